@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use modus_lib::analysis::{self, ModusSemantics};
+use modus_lib::modusfile::{Expression, Modusfile};
+use modus_lib::{imagegen, sld};
+
+// Goes past parsing: checks the Modusfile, and solves and plans the heads of its first
+// clauses, which may not pass the checks.
+fuzz_target!(|data: &str| {
+    let mf: Modusfile = match data.parse() {
+        Ok(mf) => mf,
+        Err(_) => return,
+    };
+    let kinds = mf.kinds();
+    _ = analysis::analysis_diagnostics(&kinds, &mf, None);
+    for clause in mf.0.iter().take(3) {
+        let query: Expression = match clause.head.to_string().parse() {
+            Ok(query) => query,
+            Err(_) => continue,
+        };
+        _ = imagegen::plan_from_modusfile(
+            mf.clone(),
+            query,
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        );
+    }
+});
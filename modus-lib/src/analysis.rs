@@ -27,7 +27,6 @@
 
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
-use std::iter;
 use std::ops::Range;
 
 use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
@@ -38,8 +37,9 @@ use petgraph::algo::find_negative_cycle;
 use crate::builtin::{select_builtin, OPERATOR_KIND_MAP};
 use crate::logic::{self, Literal, Predicate, SpannedPosition};
 use crate::modusfile::{Expression, ModusClause, Operator};
-use crate::modusfile::{ModusTerm, Modusfile};
+use crate::modusfile::{FormatStringFragment, ModusTerm, Modusfile};
 use crate::translate::translate_modusfile;
+use crate::wellformed;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Kind {
@@ -131,6 +131,7 @@ impl ModusSemantics for Modusfile {
             }
 
             Diagnostic::error()
+                .with_code("kind-mismatch")
                 .with_message(message)
                 .with_labels(labels)
         }
@@ -143,12 +144,16 @@ impl ModusSemantics for Modusfile {
             } else {
                 Vec::new()
             };
-            Diagnostic::note().with_message(message).with_labels(labels)
+            Diagnostic::note()
+                .with_code("kind-info")
+                .with_message(message)
+                .with_labels(labels)
         }
 
         fn generate_unknown_operator_diag(op: &Operator) -> Diagnostic<()> {
-            let diag =
-                Diagnostic::error().with_message(format!("Unknown operator: {}", op.predicate));
+            let diag = Diagnostic::error()
+                .with_code("unknown-operator")
+                .with_message(format!("Unknown operator: {}", op.predicate));
             if let Some(pos) = &op.position {
                 diag.with_labels(vec![Label::primary(
                     (),
@@ -164,7 +169,9 @@ impl ModusSemantics for Modusfile {
             expected: &Kind,
             actual: &Kind,
         ) -> Diagnostic<()> {
-            let diag = Diagnostic::error().with_message(format!("Expected kind: {expected:?}"));
+            let diag = Diagnostic::error()
+                .with_code("unexpected-kind")
+                .with_message(format!("Expected kind: {expected:?}"));
             let mut labels = Vec::new();
 
             if let Some(pos) = expr.get_spanned_position() {
@@ -230,6 +237,7 @@ impl ModusSemantics for Modusfile {
                                 // ````
                                 // Since both will 'defer' to each other. However, this isn't a sensible program on it's own anyway.
                                 Err(Diagnostic::warning()
+                                    .with_code("undetermined-kind")
                                     .with_message(format!("{} not determined yet.", lit.predicate)))
                             } else {
                                 Ok(Kind::Logic)
@@ -267,7 +275,7 @@ impl ModusSemantics for Modusfile {
                             }
                         }
                         if let Some(p) = head_pred {
-                            if !pred_kind.contains_key(&p) {
+                            if !pred_kind.contains_key(p) {
                                 pred_kind.insert(p.clone(), out_kind);
                             } else {
                                 // could enforce existing is equal to assumption here
@@ -303,7 +311,7 @@ impl ModusSemantics for Modusfile {
                         let possibility = possibilities[0];
 
                         if let Some(p) = head_pred {
-                            if !pred_kind.contains_key(&p) {
+                            if !pred_kind.contains_key(p) {
                                 pred_kind.insert(p.clone(), possibility.2);
                             } else {
                                 // could enforce existing is equal to assumption here
@@ -350,7 +358,7 @@ impl ModusSemantics for Modusfile {
                         let possibility = possibilities[0];
 
                         if let Some(p) = head_pred {
-                            if !pred_kind.contains_key(&p) {
+                            if !pred_kind.contains_key(p) {
                                 pred_kind.insert(p.clone(), possibility.2);
                             } else {
                                 // could enforce existing is equal to assumption here
@@ -393,7 +401,8 @@ impl ModusSemantics for Modusfile {
         let from_pred = Predicate("from".into());
         let run_pred = Predicate("run".into());
         let copy_pred = Predicate("copy".into());
-        // This initializes the map with the kinds of from/run/copy.
+        let git_checkout_pred = Predicate("git_checkout".into());
+        // This initializes the map with the kinds of from/run/copy/git_checkout.
         let mut pred_kind: HashMap<Predicate, Kind> = vec![
             (
                 from_pred.clone(),
@@ -401,7 +410,7 @@ impl ModusSemantics for Modusfile {
                     positive: true,
                     position: None,
                     predicate: from_pred,
-                    args: vec![logic::IRTerm::Constant("".to_string())],
+                    args: vec![logic::IRTerm::Constant("".into())],
                 })
                 .1
                 .unwrap()
@@ -413,7 +422,7 @@ impl ModusSemantics for Modusfile {
                     positive: true,
                     position: None,
                     predicate: run_pred,
-                    args: vec![logic::IRTerm::Constant("".to_string())],
+                    args: vec![logic::IRTerm::Constant("".into())],
                 })
                 .1
                 .unwrap()
@@ -426,14 +435,26 @@ impl ModusSemantics for Modusfile {
                     position: None,
                     predicate: copy_pred,
                     args: vec![
-                        logic::IRTerm::Constant("".to_string()),
-                        logic::IRTerm::Constant("".to_string()),
+                        logic::IRTerm::Constant("".into()),
+                        logic::IRTerm::Constant("".into()),
                     ],
                 })
                 .1
                 .unwrap()
                 .kind(),
             ),
+            (
+                git_checkout_pred.clone(),
+                select_builtin(&Literal {
+                    positive: true,
+                    position: None,
+                    predicate: git_checkout_pred,
+                    args: vec![logic::IRTerm::Constant("".into()); 3],
+                })
+                .1
+                .unwrap()
+                .kind(),
+            ),
         ]
         .into_iter()
         .collect();
@@ -463,7 +484,7 @@ impl ModusSemantics for Modusfile {
                     Some(&c.head.predicate),
                 );
                 if let Ok(k) = k_res {
-                    if maybe_existing_kind == None {
+                    if maybe_existing_kind.is_none() {
                         new_pred = true;
                         pred_kind.insert(c.head.predicate.clone(), k);
                     }
@@ -516,6 +537,7 @@ impl ModusSemantics for Modusfile {
                             };
                             errs.push(
                                 Diagnostic::error()
+                                    .with_code("inconsistent-kind")
                                     .with_message(
                                         "A rule with matching head predicate has a different kind.",
                                     )
@@ -568,7 +590,12 @@ impl PredicateDependency for Modusfile {
     ) {
         fn get_predicate_positivity(expr: &Expression) -> Vec<(&str, bool)> {
             match expr {
-                Expression::Literal(lit) => vec![(&lit.predicate.0, lit.positive)],
+                // The solutions of the goal of a findall must all be known, like for a negation.
+                Expression::Literal(lit) => {
+                    std::iter::once((lit.predicate.0.as_str(), lit.positive))
+                        .chain(lit.findall_goal().map(|goal| (goal, false)))
+                        .collect()
+                }
                 Expression::OperatorApplication(_, expr, _) => get_predicate_positivity(expr),
                 Expression::And(_, _, e1, e2) => {
                     let mut pred1 = get_predicate_positivity(e1);
@@ -658,17 +685,17 @@ fn check_negated_logic_kind(
         for lit in &c.body {
             let k = pred_kind.get(&lit.predicate);
             // We move on if we don't know what kind this pred is.
-            if k.is_some() && k != Some(&Kind::Logic) && !lit.positive {
+            if let Some(k) = k.filter(|k| **k != Kind::Logic && !lit.positive) {
                 // We disallow negating non-logical predicate to encourage better written
                 // Modusfiles.
                 // Also, maybe SLDNF should be considered an implementation detail and
                 // so this would make it easier to switch to different negation semantics.
                 let mut diag = Diagnostic::error()
+                    .with_code("negated-non-logic")
                     .with_message("Negating a non-logical predicate is disallowed.")
                     .with_notes(vec![format!(
                         "{} was found to be of kind {:?}.",
-                        lit.predicate,
-                        k.unwrap()
+                        lit.predicate, k
                     )]);
                 if let Some(s) = &lit.position {
                     diag =
@@ -689,6 +716,7 @@ fn check_negated_logic_kind(
 fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
     fn generate_f_string_diag(pos: &SpannedPosition) -> Diagnostic<()> {
         Diagnostic::error()
+            .with_code("format-string-in-head")
             .with_message("A format string was found in a head literal.")
             .with_labels(vec![Label::primary(
                 (),
@@ -698,6 +726,7 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
 
     fn generate_list_diag(pos: &SpannedPosition) -> Diagnostic<()> {
         Diagnostic::error()
+            .with_code("unsupported-list")
             .with_message("A list was found here. This is not supported currently.")
             .with_labels(vec![Label::primary(
                 (),
@@ -726,31 +755,14 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
                 ModusTerm::FormatString { position, .. } => {
                     vec![generate_f_string_diag(position)]
                 }
-                ModusTerm::List(position, ts) => {
-                    let mut v = vec![generate_list_diag(position)];
-                    if let Err(d) = check_no_f_string(ts) {
-                        v.push(d);
-                    }
-                    v
-                }
+                ModusTerm::List(_, ts) => check_no_f_string(ts).err().into_iter().collect(),
                 _ => Vec::new(),
             })
             .collect()
     }
 
-    fn body_term_check(body_lit: &Literal<ModusTerm>) -> Vec<Diagnostic<()>> {
-        body_lit
-            .args
-            .iter()
-            .filter_map(|arg| match arg {
-                ModusTerm::List(position, _) => Some(generate_list_diag(position)),
-                _ => None,
-            })
-            .collect()
-    }
-
     fn op_term_check(op: &Operator) -> Vec<Diagnostic<()>> {
-        let allowed_list_ops = vec![
+        let allowed_list_ops = [
             Predicate("set_entrypoint".into()),
             Predicate("set_cmd".into()),
         ];
@@ -768,25 +780,107 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
         }
     }
 
+    /// Variables of the terms outside of format strings, i.e. those that something other
+    /// than an interpolation may bind.
+    fn bound_variables<'a>(terms: &'a [ModusTerm], vars: &mut HashSet<&'a str>) {
+        for t in terms {
+            match t {
+                ModusTerm::UserVariable(v) => {
+                    vars.insert(v);
+                }
+                ModusTerm::List(_, ts) => bound_variables(ts, vars),
+                _ => (),
+            }
+        }
+    }
+
+    /// Interpolated variables of the format strings among the terms, with their spans.
+    fn interpolated_variables<'a>(
+        terms: &'a [ModusTerm],
+        vars: &mut Vec<(&'a str, &'a SpannedPosition)>,
+    ) {
+        for t in terms {
+            match t {
+                ModusTerm::FormatString { fragments, .. } => {
+                    vars.extend(fragments.iter().filter_map(|f| match f {
+                        FormatStringFragment::InterpolatedVariable(span, v) => {
+                            Some((v.as_str(), span))
+                        }
+                        _ => None,
+                    }))
+                }
+                ModusTerm::List(_, ts) => interpolated_variables(ts, vars),
+                _ => (),
+            }
+        }
+    }
+
+    /// Format strings in the head are reported on their own, so this only checks those
+    /// of the body, whose variables must be bound elsewhere in the clause.
+    fn interpolation_check(modus_clause: &ModusClause) -> Vec<Diagnostic<()>> {
+        let body = match &modus_clause.body {
+            Some(body) => body,
+            None => return Vec::new(),
+        };
+        let literals = body.literals();
+        let operators = body.operators();
+        let body_args = literals
+            .iter()
+            .map(|lit| &lit.args[..])
+            .chain(operators.iter().map(|op| &op.args[..]));
+
+        let mut bound = HashSet::new();
+        bound_variables(&modus_clause.head.args, &mut bound);
+        let mut interpolated = Vec::new();
+        for args in body_args {
+            bound_variables(args, &mut bound);
+            interpolated_variables(args, &mut interpolated);
+        }
+
+        let mut unbound: Vec<(&str, Vec<&SpannedPosition>)> = Vec::new();
+        for (v, span) in interpolated.into_iter().filter(|(v, _)| !bound.contains(v)) {
+            match unbound.iter_mut().find(|(u, _)| *u == v) {
+                Some((_, spans)) => spans.push(span),
+                None => unbound.push((v, vec![span])),
+            }
+        }
+        unbound.sort_by_key(|(_, spans)| spans.iter().map(|s| s.offset).min());
+        unbound
+            .into_iter()
+            .map(|(v, mut spans)| {
+                spans.sort_by_key(|s| s.offset);
+                Diagnostic::error()
+                    .with_code("unbound-interpolated-variable")
+                    .with_message(format!(
+                        "The variable {} is interpolated in a format string, but is not bound anywhere in the clause.",
+                        v
+                    ))
+                    .with_labels(
+                        spans
+                            .into_iter()
+                            .map(|s| Label::primary((), s.offset..s.offset + s.length))
+                            .collect(),
+                    )
+                    .with_notes(vec![format!(
+                        "{} must also appear outside of a format string, for example as an argument of the head or of a literal that grounds it.",
+                        v
+                    )])
+            })
+            .collect()
+    }
+
     let mut diags = Vec::new();
 
     for modus_clause in &mf.0 {
         diags.extend(head_term_check(&modus_clause.head));
-        for lit in modus_clause
-            .body
-            .as_ref()
-            .map(|b| b.literals())
-            .unwrap_or_default()
-        {
-            diags.extend(body_term_check(&lit));
-        }
+        diags.extend(interpolation_check(modus_clause));
         for op in modus_clause
             .body
             .as_ref()
             .map(|b| b.operators())
             .unwrap_or_default()
         {
-            diags.extend(op_term_check(&op));
+            diags.extend(op_term_check(op));
         }
     }
 
@@ -797,6 +891,139 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
     }
 }
 
+/// Runs all the checks on the Modusfile (and goal, if any), returning the errors
+/// and warnings found.
+pub fn analysis_diagnostics(
+    kind_res: &KindResult,
+    mf: &Modusfile,
+    goal: Option<&Expression>,
+) -> Vec<Diagnostic<()>> {
+    // perform analysis including the goal
+    let mut mf = mf.clone();
+    if let Some(e) = goal {
+        mf.add_goal(e.clone());
+    }
+    let term_check_res = term_check(&mf);
+    let term_errors = term_check_res.err().unwrap_or_default();
+
+    let can_translate = term_errors.is_empty();
+
+    let ir_clauses = can_translate.then(|| translate_modusfile(&mf));
+    let negation_errors = ir_clauses
+        .as_ref()
+        .and_then(|ir_clauses| check_negated_logic_kind(ir_clauses, &kind_res.pred_kind).err())
+        .unwrap_or_default();
+
+    let mut diags = kind_res
+        .errs
+        .iter()
+        .cloned()
+        .chain(negation_errors)
+        .chain(term_errors)
+        .collect::<Vec<_>>();
+
+    // The translated clauses also have the negations of expressions, e.g. `!(a, b)`, as
+    // negated literals, so prefer checking them when possible.
+    let is_stratifiable = match &ir_clauses {
+        Some(ir_clauses) => wellformed::check_stratification(ir_clauses).map_err(|path| {
+            path.into_iter()
+                .filter(|p| !p.is_negation())
+                .map(|p| p.unmangle().to_string())
+                .collect()
+        }),
+        None => mf
+            .stratifiable()
+            .map_err(|path| path.into_iter().map(str::to_owned).collect::<Vec<_>>()),
+    };
+    if let Err(path) = is_stratifiable {
+        // The clauses of the predicates on the cycle that depend on another one of them.
+        let labels =
+            mf.0.iter()
+                .filter(|c| path.iter().any(|p| **p == *c.head.predicate.0))
+                .filter(|c| {
+                    c.body.iter().flat_map(|b| b.literals()).any(|l| {
+                        std::iter::once(&*l.predicate.0)
+                            .chain(l.findall_goal())
+                            .any(|pred| path.iter().any(|p| **p == *pred))
+                    })
+                })
+                .filter_map(|c| c.position.as_ref())
+                .map(|pos| Label::secondary((), Range::from(pos)).with_message("part of the cycle"))
+                .collect();
+        let path_string = path.join(" -> ");
+        let path_string = "Cycle: ... -> ".to_string() + &path_string + " -> ...";
+        diags.push(
+            Diagnostic::error()
+                .with_code("not-stratifiable")
+                .with_message(
+                    "Program is not stratifiable. Recursive dependency on negation found.",
+                )
+                .with_labels(labels)
+                .with_notes(vec![path_string]),
+        );
+    }
+
+    for found in ir_clauses
+        .as_deref()
+        .map(wellformed::check_termination)
+        .unwrap_or_default()
+    {
+        match found {
+            wellformed::NonTermination::SameArguments(l) => {
+                // Literals of included Modusfiles have no position to report.
+                if let Some(pos) = &l.position {
+                    diags.push(
+                        Diagnostic::warning()
+                            .with_code("non-termination")
+                            .with_message(format!(
+                                "{} calls itself with the same arguments, so resolving it does not terminate",
+                                l.predicate.clone().unmangle()
+                            ))
+                            .with_labels(vec![Label::primary((), Range::from(pos))
+                                .with_message("same arguments as the head")]),
+                    );
+                }
+            }
+            wellformed::NonTermination::NoBaseCase(signatures) => {
+                let labels =
+                    mf.0.iter()
+                        .filter(|c| {
+                            signatures.iter().any(|logic::Signature(p, arity)| {
+                                *p == c.head.predicate && *arity as usize == c.head.args.len()
+                            })
+                        })
+                        .filter_map(|c| c.position.as_ref())
+                        .map(|pos| {
+                            Label::secondary((), Range::from(pos))
+                                .with_message("depends on the recursion")
+                        })
+                        .collect::<Vec<_>>();
+                if !labels.is_empty() {
+                    let names = signatures
+                        .iter()
+                        .filter(|s| !s.0 .0.starts_with('_'))
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>();
+                    diags.push(
+                        Diagnostic::warning()
+                            .with_code("non-termination")
+                            .with_message(format!(
+                                "recursion through {} has no base case, so resolving it does not terminate",
+                                names.join(", ")
+                            ))
+                            .with_labels(labels)
+                            .with_notes(vec![
+                                "each of their rules depends on one of them".to_string()
+                            ]),
+                    );
+                }
+            }
+        }
+    }
+
+    diags
+}
+
 /// Returns true if the results of the check were satisfactory; we don't need to terminate.
 pub fn check_and_output_analysis<
     'files,
@@ -813,54 +1040,15 @@ pub fn check_and_output_analysis<
 ) -> bool {
     if verbose {
         for msg in &kind_res.messages {
-            term::emit(out, config, file, &msg).expect("Error when writing to stderr.");
+            term::emit(out, config, file, msg).expect("Error when writing to stderr.");
         }
     }
 
-    // perform analysis including the goal
-    let mut mf = mf.clone();
-    if let Some(e) = goal {
-        mf.add_goal(e.clone());
-    }
-    let term_check_res = term_check(&mf);
-    let term_errors = term_check_res.err().unwrap_or_default();
-
-    let can_translate = term_errors.is_empty();
-
-    let negation_errors = if can_translate {
-        let ir_clauses = translate_modusfile(&mf);
-        check_negated_logic_kind(&ir_clauses, &kind_res.pred_kind)
-            .err()
-            .unwrap_or_default()
-    } else {
-        Vec::new()
-    };
-
-    let errs = kind_res
-        .errs
-        .iter()
-        .chain(&negation_errors)
-        .chain(&term_errors)
-        .collect::<Vec<_>>();
+    let errs = analysis_diagnostics(kind_res, mf, goal);
     for err in &errs {
         term::emit(out, config, file, err).expect("Error when writing to stderr.");
     }
 
-    let is_stratifiable = mf.stratifiable();
-    if let Err(path) = is_stratifiable {
-        let path_string = path
-            .iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<_>>()
-            .join(" -> ");
-        let path_string = "Cycle: ... -> ".to_string() + &path_string + " -> ...";
-        let diag = Diagnostic::error()
-            .with_message("Program is not stratifiable. Recursive dependency on negation found.")
-            .with_notes(vec![path_string]);
-        term::emit(out, config, file, &diag).expect("Error when writing to stderr.");
-        return false;
-    }
-
     errs.iter().all(|err| err.severity != Severity::Error)
 }
 
@@ -872,7 +1060,7 @@ mod tests {
 
     #[test]
     fn acyclic_dependency() {
-        let clauses = vec!["foo(X) :- bar(X).", "bar(X) :- baz(X)."];
+        let clauses = ["foo(X) :- bar(X).", "bar(X) :- baz(X)."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let mut expected_dep = petgraph::Graph::<&str, &str>::new();
@@ -888,7 +1076,7 @@ mod tests {
 
     #[test]
     fn cyclic_dependency() {
-        let clauses = vec![
+        let clauses = [
             "foo(X) :- from(\"ubuntu\"), run(\"apt-get update\"), bar(X).",
             "bar(X) :- foo(X).",
         ];
@@ -908,7 +1096,7 @@ mod tests {
 
     #[test]
     fn stratifiable_program() {
-        let clauses = vec![
+        let clauses = [
             "foo(X) :- from(\"ubuntu\"), run(\"apt-get update\"), bar(X).",
             "bar(X) :- foo(X).",
         ];
@@ -918,7 +1106,7 @@ mod tests {
 
     #[test]
     fn unstratifiable_program() {
-        let clauses = vec![
+        let clauses = [
             "foo(X) :- from(\"ubuntu\"), run(\"apt-get update\"), bar(X).",
             "bar(X) :- !foo(X).",
         ];
@@ -926,9 +1114,48 @@ mod tests {
         assert!(mf.stratifiable().is_err());
     }
 
+    #[test]
+    fn unstratifiable_through_negated_expression() {
+        let clauses = ["p :- !(q, r).", "q :- p.", "r."];
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+        let kind_res = mf.kinds();
+        let diags = analysis_diagnostics(&kind_res, &mf, None);
+        let diag = diags
+            .iter()
+            .find(|d| d.code.as_deref() == Some("not-stratifiable"))
+            .unwrap();
+        assert!(!diag.notes[0].contains("_negate_"));
+        // the first two clauses, but not the fact
+        assert_eq!(diag.labels.len(), 2);
+        assert_eq!(diag.labels[0].range, 0..13);
+    }
+
+    #[test]
+    fn non_terminating_recursion() {
+        let clauses = [
+            "a(X) :- b(X), a(X).",
+            "a(X) :- b(X).",
+            "b(\"x\").",
+            "c :- d.",
+            "d :- c.",
+        ];
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+        let kind_res = mf.kinds();
+        let diags = analysis_diagnostics(&kind_res, &mf, None)
+            .into_iter()
+            .filter(|d| d.code.as_deref() == Some("non-termination"))
+            .collect::<Vec<_>>();
+        assert_eq!(diags.len(), 2);
+        assert!(diags.iter().all(|d| d.severity == Severity::Warning));
+        // the recursive call of the first clause
+        assert_eq!(diags[0].labels[0].range, 14..18);
+        assert!(diags[1].message.contains("c/0, d/0"));
+        assert_eq!(diags[1].labels.len(), 2);
+    }
+
     #[test]
     fn simple_image_predicate_kind() {
-        let clauses = vec!["a :- from(\"ubuntu\"), run(\"apt-get update\"), run(\"echo hello\")."];
+        let clauses = ["a :- from(\"ubuntu\"), run(\"apt-get update\"), run(\"echo hello\")."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -940,7 +1167,7 @@ mod tests {
 
     #[test]
     fn layer_predicate_kind() {
-        let clauses = vec![
+        let clauses = [
             "a :- from(\"ubuntu\"), run(\"apt-get update\").",
             "b :- a::copy(\".\", \".\").",
         ];
@@ -956,7 +1183,7 @@ mod tests {
 
     #[test]
     fn simple_logic_predicate_kind() {
-        let clauses = vec!["b(X) :- X = \"test\"."];
+        let clauses = ["b(X) :- X = \"test\"."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -969,7 +1196,7 @@ mod tests {
 
     #[test]
     fn simple_indirect_kind() {
-        let clauses = vec![
+        let clauses = [
             "a(X) :- from(X), run(\"apt-get update\").",
             "b(X) :- a(X).",
             "c(X) :- b(X).",
@@ -996,7 +1223,7 @@ mod tests {
     fn handles_indirect_recursion() {
         // Since we can evaluate foo in the third clause, we should be able to evaluate
         // `bar`.
-        let clauses = vec![
+        let clauses = [
             "foo(X) :- bar(X).",
             "bar(X) :- foo(X).",
             "foo(X) :- from(X), run(\"apt-get update\").",
@@ -1017,13 +1244,11 @@ mod tests {
 
     #[test]
     fn handles_recursion_through_presumptions() {
-        let clauses = vec![
-            "foo(mode) :- ( \
+        let clauses = ["foo(mode) :- ( \
                mode=\"prod\",from(\"alpine\"),foo(\"dev\")::copy(\".\", \".\") \
              ; \
                mode=\"dev\",from(\"gcc\"),run(\"echo hello\") \
-             ).",
-        ];
+             )."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -1036,13 +1261,11 @@ mod tests {
 
     #[test]
     fn handles_disjunct_recursion_through_presumptions() {
-        let clauses = vec![
-            "foo(mode) :- ( \
+        let clauses = ["foo(mode) :- ( \
                mode=\"prod\",foo(\"dev\"),run(\"make prod\") \
              ; \
                mode=\"dev\",copy(\".\", \"/app\"),run(\"echo hello\") \
-             ).",
-        ];
+             )."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -1055,7 +1278,7 @@ mod tests {
 
     #[test]
     fn warns_recursion() {
-        let clauses = vec![
+        let clauses = [
             "recurse :- recurse.",
             "a(X) :- from(X), run(\"apt-get update\").",
         ];
@@ -1072,7 +1295,7 @@ mod tests {
 
     #[test]
     fn warns_indirect_recursion() {
-        let clauses = vec!["foo(X) :- bar(X).", "bar(X) :- foo(X)."];
+        let clauses = ["foo(X) :- bar(X).", "bar(X) :- foo(X)."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -1083,7 +1306,7 @@ mod tests {
 
     #[test]
     fn errors_conflicting_predicate_kind() {
-        let clauses = vec!["foo :- \"1\" = \"1\".", "foo(X) :- from(X)."];
+        let clauses = ["foo :- \"1\" = \"1\".", "foo(X) :- from(X)."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -1093,7 +1316,7 @@ mod tests {
 
     #[test]
     fn errors_f_string_present_in_head() {
-        let clauses = vec![
+        let clauses = [
             "fact_f(f\"foo ${X}\", f\"${Y}\").",
             "fact_f(f\"foo\").",
             "fact_f(f\"foo ${X}\") :- bar, lar.",
@@ -1107,18 +1330,37 @@ mod tests {
 
     #[test]
     fn errors_f_string_present_in_head_list() {
-        let clauses = vec!["fact_f([X, f\"foo ${X}\"], f\"${Y}\")."];
+        let clauses = ["fact_f([X, f\"foo ${X}\"], f\"${Y}\")."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let res = term_check(&mf);
         assert!(res.is_err());
         assert_eq!(res.as_ref().err().unwrap()[0].severity, Severity::Error);
-        assert_eq!(1 + 2, res.err().unwrap().len());
+        // The list itself is allowed, but not the format string in it.
+        assert_eq!(2, res.err().unwrap().len());
+    }
+
+    #[test]
+    fn errors_unbound_interpolated_variable() {
+        let clauses = [
+            "a(X) :- from(f\"alpine:${X}\").",
+            "b :- c(X), from(f\"alpine:${X}\")::set_workdir(f\"/${Y}/${Y}\").",
+            "d :- from(\"alpine\"), run(f\"echo ${Z} ${_}\"), run(f\"echo ${Z}\").",
+        ];
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+
+        let diags = term_check(&mf).err().unwrap();
+        assert_eq!(2, diags.len()); // one for each of Y and Z
+        assert!(diags.iter().all(|d| d.severity == Severity::Error));
+        assert!(diags[0].message.contains("Y"));
+        assert_eq!(2, diags[0].labels.len());
+        assert!(diags[1].message.contains("Z"));
+        assert_eq!(2, diags[1].labels.len());
     }
 
     #[test]
     fn kind_errors_with_unknown_operator() {
-        let clauses = vec!["head :- bar::foobar(X, Y), lar.", "lar."];
+        let clauses = ["head :- bar::foobar(X, Y), lar.", "lar."];
         let mf: Modusfile = clauses.join("\n").parse().unwrap();
 
         let kind_res = mf.kinds();
@@ -1128,7 +1370,7 @@ mod tests {
 
     #[test]
     fn kind_errors_with_incorrect_operator_inp() {
-        let clauses = vec![
+        let clauses = [
             "run_alias :- run(\"echo foobar\").",
             "head :- run_alias::set_entrypoint(\"bash\").",
         ];
@@ -16,10 +16,7 @@
 
 use std::collections::HashMap;
 
-use crate::{
-    analysis::Kind,
-    logic::{Clause, IRTerm, Literal, Predicate},
-};
+use crate::{analysis::Kind, logic::Literal};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SelectBuiltinResult {
@@ -30,10 +27,7 @@ pub enum SelectBuiltinResult {
 
 impl SelectBuiltinResult {
     pub fn is_match(&self) -> bool {
-        match self {
-            SelectBuiltinResult::Match => true,
-            _ => false,
-        }
+        matches!(self, SelectBuiltinResult::Match)
     }
 }
 
@@ -53,15 +47,16 @@ pub trait BuiltinPredicate {
             ref args,
             ..
         } = lit;
-        if &predicate.0 != self.name() {
+        if predicate.0 != self.name() {
             return SelectBuiltinResult::NoMatch;
         }
         if args.len() == self.arg_groundness().len()
-            && args.iter().zip(self.arg_groundness().into_iter()).all(
-                |(term, allows_ungrounded)| {
+            && args
+                .iter()
+                .zip(self.arg_groundness())
+                .all(|(term, allows_ungrounded)| {
                     *allows_ungrounded || term.is_constant_or_compound_constant()
-                },
-            )
+                })
         {
             SelectBuiltinResult::Match
         } else {
@@ -83,6 +78,12 @@ pub trait BuiltinPredicate {
     /// they must all be either auxillary or some existing variables from the
     /// input.
     fn apply(&self, lit: &Literal) -> Option<Literal>;
+
+    /// Like `apply`, but for builtins that may hold in several ways, such as `member`,
+    /// returns a literal for each of them. Each one is tried as a separate resolvent.
+    fn apply_all(&self, lit: &Literal) -> Vec<Literal> {
+        self.apply(lit).into_iter().collect()
+    }
 }
 
 mod string_concat {
@@ -98,11 +99,11 @@ mod string_concat {
         Some(Literal {
             positive: true,
             position: pos.clone(),
-            predicate: Predicate("string_concat".to_owned()),
+            predicate: Predicate("string_concat".into()),
             args: vec![
-                IRTerm::Constant(a.to_owned()),
-                IRTerm::Constant(b.to_owned()),
-                IRTerm::Constant(c.to_owned()),
+                IRTerm::Constant(a.into()),
+                IRTerm::Constant(b.into()),
+                IRTerm::Constant(c.into()),
             ],
         })
     }
@@ -171,7 +172,7 @@ mod string_concat {
         fn apply(&self, lit: &Literal) -> Option<Literal> {
             let a = lit.args[0].as_constant()?;
             let c = lit.args[2].as_constant()?;
-            if let Some(b) = c.strip_prefix(&a) {
+            if let Some(b) = c.strip_prefix(a) {
                 string_concat_result(a, b, c, &lit.position)
             } else {
                 None
@@ -204,11 +205,8 @@ mod equality {
             Some(Literal {
                 positive: true,
                 position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
-                args: vec![
-                    IRTerm::Constant(a.to_owned()),
-                    IRTerm::Constant(a.to_owned()),
-                ],
+                predicate: Predicate("string_eq".into()),
+                args: vec![IRTerm::Constant(a.into()), IRTerm::Constant(a.into())],
             })
         }
     }
@@ -232,18 +230,18 @@ mod equality {
             Some(Literal {
                 positive: true,
                 position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
-                args: vec![
-                    IRTerm::Constant(b.to_owned()),
-                    IRTerm::Constant(b.to_owned()),
-                ],
+                predicate: Predicate("string_eq".into()),
+                args: vec![IRTerm::Constant(b.into()), IRTerm::Constant(b.into())],
             })
         }
     }
 }
 
 mod number {
+    use std::collections::HashMap;
+
     use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal};
 
     macro_rules! define_number_comparison {
         ($name:ident, $cond:expr) => {
@@ -281,11 +279,482 @@ mod number {
     define_number_comparison!(number_lt, |a, b| a < b);
     define_number_comparison!(number_geq, |a, b| a >= b);
     define_number_comparison!(number_leq, |a, b| a <= b);
+
+    /// A number of an arithmetic builtin. Integers are kept exact, so that e.g. ports and
+    /// offsets don't pick up a fractional part.
+    #[derive(Clone, Copy)]
+    enum Number {
+        Integer(i64),
+        Float(f64),
+    }
+
+    impl Number {
+        /// Reads a constant as a number by its value, so that e.g. `"01"` and `"+1"` are
+        /// `1`. Integers that don't fit in an `i64` are not numbers, rather than losing
+        /// precision as floats, and neither are infinities and NaN.
+        fn parse(term: &IRTerm) -> Option<Number> {
+            let s = term.as_constant()?;
+            let digits = s.strip_prefix(&['-', '+'][..]).unwrap_or(s);
+            if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+                s.parse().ok().map(Number::Integer)
+            } else {
+                s.parse()
+                    .ok()
+                    .filter(|f: &f64| f.is_finite())
+                    .map(Number::Float)
+            }
+        }
+
+        fn as_float(self) -> f64 {
+            match self {
+                Number::Integer(i) => i as f64,
+                Number::Float(f) => f,
+            }
+        }
+
+        /// Compares integers exactly, and as floats otherwise.
+        fn equals(self, other: Number) -> bool {
+            match (self, other) {
+                (Number::Integer(a), Number::Integer(b)) => a == b,
+                (a, b) => a.as_float() == b.as_float(),
+            }
+        }
+
+        /// The normalized text of the number, e.g. `0` rather than `-0`.
+        fn to_constant(self) -> IRTerm {
+            let s = match self {
+                Number::Integer(i) => i.to_string(),
+                // Adding zero turns `-0` into `0`.
+                Number::Float(f) => (f + 0.0).to_string(),
+            };
+            IRTerm::Constant(s.into())
+        }
+    }
+
+    /// Applies the operation exactly to integers, and to floats otherwise, failing if the
+    /// result overflows or is not finite.
+    fn arithmetic(
+        a: &IRTerm,
+        b: &IRTerm,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Option<Number> {
+        match (Number::parse(a)?, Number::parse(b)?) {
+            (Number::Integer(a), Number::Integer(b)) => int_op(a, b).map(Number::Integer),
+            (a, b) => Some(float_op(a.as_float(), b.as_float()))
+                .filter(|c| c.is_finite())
+                .map(Number::Float),
+        }
+    }
+
+    /// The literal with the argument at `i` replaced by the computed number, unless it is
+    /// already a constant, which must then be equal to it.
+    fn with_result(lit: &Literal, i: usize, result: Number) -> Option<Literal> {
+        if lit.args[i].is_constant() {
+            let expected = Number::parse(&lit.args[i])?;
+            return expected.equals(result).then(|| lit.clone());
+        }
+        let mut lit = lit.clone();
+        lit.args[i] = result.to_constant();
+        Some(lit)
+    }
+
+    /// Defines an arithmetic builtin, one struct for each argument it can solve for given
+    /// the other two, like `string_concat`.
+    macro_rules! define_number_arithmetic {
+        ($name:ident, $struct:ident, $groundness:expr, $solve:expr) => {
+            pub struct $struct;
+            impl BuiltinPredicate for $struct {
+                fn name(&self) -> &'static str {
+                    stringify!($name)
+                }
+
+                fn kind(&self) -> crate::analysis::Kind {
+                    crate::analysis::Kind::Logic
+                }
+
+                fn arg_groundness(&self) -> &'static [bool] {
+                    $groundness
+                }
+
+                fn apply(&self, lit: &Literal) -> Option<Literal> {
+                    let i = self.arg_groundness().iter().position(|&g| g).unwrap();
+                    let result = $solve(&lit.args[0], &lit.args[1], &lit.args[2])?;
+                    with_result(lit, i, result)
+                }
+            }
+        };
+    }
+
+    // number_add(A, B, C) holds if A + B = C.
+    define_number_arithmetic!(number_add, NumberAdd1, &[false, false, true], |a, b, _| {
+        arithmetic(a, b, i64::checked_add, |a, b| a + b)
+    });
+    define_number_arithmetic!(number_add, NumberAdd2, &[true, false, false], |_, b, c| {
+        arithmetic(c, b, i64::checked_sub, |c, b| c - b)
+    });
+    define_number_arithmetic!(number_add, NumberAdd3, &[false, true, false], |a, _, c| {
+        arithmetic(c, a, i64::checked_sub, |c, a| c - a)
+    });
+
+    // number_sub(A, B, C) holds if A - B = C.
+    define_number_arithmetic!(number_sub, NumberSub1, &[false, false, true], |a, b, _| {
+        arithmetic(a, b, i64::checked_sub, |a, b| a - b)
+    });
+    define_number_arithmetic!(number_sub, NumberSub2, &[true, false, false], |_, b, c| {
+        arithmetic(c, b, i64::checked_add, |c, b| c + b)
+    });
+    define_number_arithmetic!(number_sub, NumberSub3, &[false, true, false], |a, _, c| {
+        arithmetic(a, c, i64::checked_sub, |a, c| a - c)
+    });
+
+    // number_mul(A, B, C) holds if A * B = C. It does not solve for A or B, since division
+    // may not be exact.
+    define_number_arithmetic!(number_mul, NumberMul, &[false, false, true], |a, b, _| {
+        arithmetic(a, b, i64::checked_mul, |a, b| a * b)
+    });
+
+    /// A bound of an interval, and whether it is strict (excluded from the interval).
+    type Bound = Option<(f64, bool)>;
+
+    /// The values a variable may take given the comparisons suspended on it.
+    #[derive(Default)]
+    struct Interval {
+        lower: Bound,
+        upper: Bound,
+    }
+
+    impl Interval {
+        fn restrict_lower(&mut self, value: f64, strict: bool) {
+            match self.lower {
+                Some((v, s)) if v > value || (v == value && s) => {}
+                _ => self.lower = Some((value, strict)),
+            }
+        }
+
+        fn restrict_upper(&mut self, value: f64, strict: bool) {
+            match self.upper {
+                Some((v, s)) if v < value || (v == value && s) => {}
+                _ => self.upper = Some((value, strict)),
+            }
+        }
+
+        fn is_empty(&self) -> bool {
+            match (self.lower, self.upper) {
+                (Some((lo, lo_strict)), Some((hi, hi_strict))) => {
+                    lo > hi || (lo == hi && (lo_strict || hi_strict))
+                }
+                _ => false,
+            }
+        }
+    }
+
+    /// Returns the first of the given number comparisons between a variable and a constant
+    /// that cannot hold together with the comparisons before it, whatever the variable is
+    /// later bound to.
+    ///
+    /// This lets resolution prune a branch as soon as its suspended comparisons are
+    /// inconsistent, e.g. `number_gt(X, "5"), number_lt(X, "3")`, rather than when `X`
+    /// becomes ground.
+    pub fn inconsistent_comparison<'a>(
+        literals: impl IntoIterator<Item = &'a Literal>,
+    ) -> Option<&'a Literal> {
+        let mut intervals: HashMap<&IRTerm, Interval> = HashMap::new();
+        for lit in literals {
+            if !lit.positive || lit.args.len() != 2 {
+                continue;
+            }
+            // Normalize to a comparison `X op c`, flipping it if the constant comes first.
+            let (var, c, flipped) = match (&lit.args[0], &lit.args[1]) {
+                (IRTerm::Constant(c), v) if !v.is_constant_or_compound_constant() => (v, c, true),
+                (v, IRTerm::Constant(c)) if !v.is_constant_or_compound_constant() => (v, c, false),
+                _ => continue,
+            };
+            let c: f64 = match c.parse() {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let interval = intervals.entry(var).or_default();
+            match (lit.predicate.0.as_str(), flipped) {
+                ("number_eq", _) => {
+                    interval.restrict_lower(c, false);
+                    interval.restrict_upper(c, false);
+                }
+                ("number_gt", false) | ("number_lt", true) => interval.restrict_lower(c, true),
+                ("number_geq", false) | ("number_leq", true) => interval.restrict_lower(c, false),
+                ("number_lt", false) | ("number_gt", true) => interval.restrict_upper(c, true),
+                ("number_leq", false) | ("number_geq", true) => interval.restrict_upper(c, false),
+                _ => continue,
+            }
+            // No number compares with NaN.
+            if c.is_nan() || interval.is_empty() {
+                return Some(lit);
+            }
+        }
+        None
+    }
+}
+
+mod boolean {
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal};
+    use crate::modusfile::tokens::{FALSE, TRUE};
+
+    fn parse_bool(t: &IRTerm) -> Option<bool> {
+        match t.as_constant()? {
+            TRUE => Some(true),
+            FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    fn bool_constant(b: bool) -> IRTerm {
+        IRTerm::Constant(if b { TRUE } else { FALSE }.into())
+    }
+
+    macro_rules! define_bool_operation {
+        ($name:ident, $op:expr) => {
+            #[allow(non_camel_case_types)]
+            pub struct $name;
+            impl BuiltinPredicate for $name {
+                fn name(&self) -> &'static str {
+                    stringify!($name)
+                }
+
+                fn kind(&self) -> crate::analysis::Kind {
+                    crate::analysis::Kind::Logic
+                }
+
+                fn arg_groundness(&self) -> &'static [bool] {
+                    &[false, false, true]
+                }
+
+                /// Computes the third argument from the first two booleans.
+                fn apply(&self, lit: &Literal) -> Option<Literal> {
+                    let a = parse_bool(&lit.args[0])?;
+                    let b = parse_bool(&lit.args[1])?;
+                    let mut res = lit.clone();
+                    res.args[2] = bool_constant($op(a, b));
+                    Some(res)
+                }
+            }
+        };
+    }
+
+    define_bool_operation!(bool_and, |a, b| a && b);
+    define_bool_operation!(bool_or, |a, b| a || b);
+
+    pub struct BoolNot1;
+    impl BuiltinPredicate for BoolNot1 {
+        fn name(&self) -> &'static str {
+            "bool_not"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let a = parse_bool(&lit.args[0])?;
+            let mut res = lit.clone();
+            res.args[1] = bool_constant(!a);
+            Some(res)
+        }
+    }
+
+    pub struct BoolNot2;
+    impl BuiltinPredicate for BoolNot2 {
+        fn name(&self) -> &'static str {
+            "bool_not"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let b = parse_bool(&lit.args[1])?;
+            let mut res = lit.clone();
+            res.args[0] = bool_constant(!b);
+            Some(res)
+        }
+    }
+}
+
+mod list {
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal};
+    use crate::sld::Auxiliary;
+
+    fn elements(term: &IRTerm) -> Option<&[IRTerm]> {
+        match term {
+            IRTerm::List(ts) => Some(ts),
+            _ => None,
+        }
+    }
+
+    fn with_args(lit: &Literal, args: Vec<IRTerm>) -> Literal {
+        Literal {
+            args,
+            ..lit.clone()
+        }
+    }
+
+    /// `member(X, List)` holds for each element X of the list.
+    pub struct Member;
+    impl BuiltinPredicate for Member {
+        fn name(&self) -> &'static str {
+            "member"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            self.apply_all(lit).into_iter().next()
+        }
+
+        fn apply_all(&self, lit: &Literal) -> Vec<Literal> {
+            let ts = elements(&lit.args[1]).unwrap_or_default();
+            let mut seen = Vec::new();
+            ts.iter()
+                .filter(|&t| {
+                    let new = !seen.contains(&t);
+                    seen.push(t);
+                    new
+                })
+                .map(|t| with_args(lit, vec![t.clone(), lit.args[1].clone()]))
+                .collect()
+        }
+    }
+
+    /// `length(List, N)` holds if the list has N elements.
+    pub struct Length1;
+    impl BuiltinPredicate for Length1 {
+        fn name(&self) -> &'static str {
+            "length"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let n = elements(&lit.args[0])?.len();
+            Some(with_args(
+                lit,
+                vec![lit.args[0].clone(), IRTerm::Constant(n.to_string().into())],
+            ))
+        }
+    }
+
+    /// Creates a list of N new variables, which later literals may bind.
+    pub struct Length2;
+    impl BuiltinPredicate for Length2 {
+        fn name(&self) -> &'static str {
+            "length"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let n: usize = lit.args[1].as_constant()?.parse().ok()?;
+            let list = IRTerm::List((0..n).map(|_| IRTerm::aux(false)).collect());
+            Some(with_args(lit, vec![list, lit.args[1].clone()]))
+        }
+    }
+
+    /// `append(A, B, C)` holds if C is the elements of A followed by those of B.
+    pub struct Append1;
+    impl BuiltinPredicate for Append1 {
+        fn name(&self) -> &'static str {
+            "append"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let a = elements(&lit.args[0])?;
+            let b = elements(&lit.args[1])?;
+            let c = IRTerm::List(a.iter().chain(b).cloned().collect());
+            Some(with_args(
+                lit,
+                vec![lit.args[0].clone(), lit.args[1].clone(), c],
+            ))
+        }
+    }
+
+    /// Splits C in each way into A and B, so that e.g. `append([H], T, List)` gives the
+    /// head and tail of a list.
+    pub struct Append2;
+    impl BuiltinPredicate for Append2 {
+        fn name(&self) -> &'static str {
+            "append"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true, true, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            self.apply_all(lit).into_iter().next()
+        }
+
+        fn apply_all(&self, lit: &Literal) -> Vec<Literal> {
+            let c = elements(&lit.args[2]).unwrap_or_default();
+            (0..=c.len())
+                .map(|i| {
+                    let (a, b) = c.split_at(i);
+                    with_args(
+                        lit,
+                        vec![
+                            IRTerm::List(a.to_vec()),
+                            IRTerm::List(b.to_vec()),
+                            lit.args[2].clone(),
+                        ],
+                    )
+                })
+                .collect()
+        }
+    }
 }
 
 mod semver {
     use super::BuiltinPredicate;
-    use semver::{Comparator, Op, Version};
+    use semver::{Comparator, Version};
 
     fn parse_partial_version(s: &str) -> Option<Version> {
         if let Ok(v) = Version::parse(s) {
@@ -300,7 +769,7 @@ mod semver {
         if let Ok(v) = Version::parse(&s) {
             return Some(v);
         }
-        return None;
+        None
     }
 
     macro_rules! define_semver_comparison {
@@ -345,13 +814,67 @@ mod semver {
     define_semver_comparison!(semver_leq, "<=");
 }
 
+mod changes {
+    use std::sync::OnceLock;
+
+    use super::BuiltinPredicate;
+    use crate::logic::Literal;
+
+    /// Tells whether anything under the path changed since the git revision, e.g.
+    /// `origin/main`, including changes that are not committed yet.
+    pub type ChangeDetector = fn(&str, &str) -> Result<bool, String>;
+
+    static CHANGE_DETECTOR: OnceLock<ChangeDetector> = OnceLock::new();
+
+    /// Sets how `changed_since` finds changes. Without a detector, or if it fails,
+    /// every path counts as changed, so that nothing is skipped by mistake. Only the
+    /// first detector that is set is used.
+    pub fn set_change_detector(detector: ChangeDetector) {
+        let _ = CHANGE_DETECTOR.set(detector);
+    }
+
+    /// `changed_since(Rev, Path)`, which holds if the path changed since the revision.
+    pub struct ChangedSince;
+    impl BuiltinPredicate for ChangedSince {
+        fn name(&self) -> &'static str {
+            "changed_since"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let rev = lit.args[0].as_constant()?;
+            let path = lit.args[1].as_constant()?;
+            let changed = match CHANGE_DETECTOR.get() {
+                Some(detector) => detector(rev, path).unwrap_or(true),
+                None => true,
+            };
+            if changed {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
 macro_rules! intrinsic_predicate {
     ($name:ident, $kind:expr, $($arg_groundness:expr),*) => {
+        intrinsic_predicate!($name = stringify!($name), $kind, $($arg_groundness),*);
+    };
+    // A predicate with another arity than the one named `$predicate`.
+    ($name:ident = $predicate:expr, $kind:expr, $($arg_groundness:expr),*) => {
         #[allow(non_camel_case_types)]
         pub struct $name;
         impl BuiltinPredicate for $name {
             fn name(&self) -> &'static str {
-                stringify!($name)
+                $predicate
             }
 
             fn kind(&self) -> Kind {
@@ -499,77 +1022,234 @@ intrinsic_predicate!(
     false,
     false
 );
+intrinsic_predicate!(
+    _operator_tag_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_tag_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_assert_file_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_assert_file_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_assert_cmd_succeeds_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_assert_cmd_succeeds_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_retry_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_retry_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_retry_with_delay_begin = "_operator_retry_begin",
+    crate::analysis::Kind::Layer,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_retry_with_delay_end = "_operator_retry_end",
+    crate::analysis::Kind::Layer,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_timeout_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_timeout_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_insecure_begin,
+    crate::analysis::Kind::Layer,
+    false
+);
+intrinsic_predicate!(_operator_insecure_end, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(
+    _operator_cache_mount_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_cache_mount_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_secret_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_secret_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
 intrinsic_predicate!(copy, crate::analysis::Kind::Layer, false, false);
+intrinsic_predicate!(
+    git_checkout,
+    crate::analysis::Kind::Layer,
+    false,
+    false,
+    false
+);
 intrinsic_predicate!(_operator_merge_begin, crate::analysis::Kind::Layer, false);
 intrinsic_predicate!(_operator_merge_end, crate::analysis::Kind::Layer, false);
 
-/// Convenience macro that returns Some(b) for the first b that can be selected.
-macro_rules! select_builtins {
-    ( $lit:expr, $( $x:expr ),+, ) => {{
-        let mut has_ground_mismatch = false;
-        $(
-            match $x.select($lit) {
-                SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(&$x)),
-                SelectBuiltinResult::GroundnessMismatch => {
-                    has_ground_mismatch = true;
-                },
-                _ => {}
-            }
-        );+
-        if has_ground_mismatch {
-            return (SelectBuiltinResult::GroundnessMismatch, None);
-        } else {
-            return (SelectBuiltinResult::NoMatch, None);
-        }
-    }};
-}
-
+/// All builtin predicates, in the order they are tried by `select_builtin`.
+const BUILTINS: &[&dyn BuiltinPredicate] = &[
+    &string_concat::StringConcat1,
+    &string_concat::StringConcat2,
+    &string_concat::StringConcat3,
+    &run,
+    &from,
+    &_operator_copy_begin,
+    &_operator_copy_end,
+    &_operator_in_workdir_begin,
+    &_operator_in_workdir_end,
+    &_operator_set_workdir_begin,
+    &_operator_set_workdir_end,
+    &_operator_set_entrypoint_begin,
+    &_operator_set_entrypoint_end,
+    &_operator_set_cmd_begin,
+    &_operator_set_cmd_end,
+    &_operator_set_label_begin,
+    &_operator_set_label_end,
+    &_operator_set_env_begin,
+    &_operator_set_env_end,
+    &_operator_in_env_begin,
+    &_operator_in_env_end,
+    &_operator_append_path_begin,
+    &_operator_append_path_end,
+    &_operator_set_user_begin,
+    &_operator_set_user_end,
+    &_operator_tag_begin,
+    &_operator_tag_end,
+    &_operator_assert_file_begin,
+    &_operator_assert_file_end,
+    &_operator_assert_cmd_succeeds_begin,
+    &_operator_assert_cmd_succeeds_end,
+    &_operator_retry_begin,
+    &_operator_retry_end,
+    &_operator_retry_with_delay_begin,
+    &_operator_retry_with_delay_end,
+    &_operator_timeout_begin,
+    &_operator_timeout_end,
+    &_operator_insecure_begin,
+    &_operator_insecure_end,
+    &_operator_cache_mount_begin,
+    &_operator_cache_mount_end,
+    &_operator_secret_begin,
+    &_operator_secret_end,
+    &copy,
+    &git_checkout,
+    &equality::StringEq1,
+    &equality::StringEq2,
+    &_operator_merge_begin,
+    &_operator_merge_end,
+    &number::number_eq,
+    &number::number_gt,
+    &number::number_lt,
+    &number::number_geq,
+    &number::number_leq,
+    &number::NumberAdd1,
+    &number::NumberAdd2,
+    &number::NumberAdd3,
+    &number::NumberSub1,
+    &number::NumberSub2,
+    &number::NumberSub3,
+    &number::NumberMul,
+    &list::Member,
+    &list::Length1,
+    &list::Length2,
+    &list::Append1,
+    &list::Append2,
+    &boolean::bool_and,
+    &boolean::bool_or,
+    &boolean::BoolNot1,
+    &boolean::BoolNot2,
+    &semver::semver_exact,
+    &semver::semver_gt,
+    &semver::semver_lt,
+    &semver::semver_geq,
+    &semver::semver_leq,
+    &changes::ChangedSince,
+];
+
+pub use changes::{set_change_detector, ChangeDetector};
+pub use number::inconsistent_comparison;
+
+/// Returns the first builtin that can be selected for the literal.
 pub fn select_builtin<'a>(
     lit: &Literal,
 ) -> (SelectBuiltinResult, Option<&'a dyn BuiltinPredicate>) {
-    select_builtins!(
-        lit,
-        string_concat::StringConcat1,
-        string_concat::StringConcat2,
-        string_concat::StringConcat3,
-        run,
-        from,
-        _operator_copy_begin,
-        _operator_copy_end,
-        _operator_in_workdir_begin,
-        _operator_in_workdir_end,
-        _operator_set_workdir_begin,
-        _operator_set_workdir_end,
-        _operator_set_entrypoint_begin,
-        _operator_set_entrypoint_end,
-        _operator_set_cmd_begin,
-        _operator_set_cmd_end,
-        _operator_set_label_begin,
-        _operator_set_label_end,
-        _operator_set_env_begin,
-        _operator_set_env_end,
-        _operator_in_env_begin,
-        _operator_in_env_end,
-        _operator_append_path_begin,
-        _operator_append_path_end,
-        _operator_set_user_begin,
-        _operator_set_user_end,
-        copy,
-        equality::StringEq1,
-        equality::StringEq2,
-        _operator_merge_begin,
-        _operator_merge_end,
-        number::number_eq,
-        number::number_gt,
-        number::number_lt,
-        number::number_geq,
-        number::number_leq,
-        semver::semver_exact,
-        semver::semver_gt,
-        semver::semver_lt,
-        semver::semver_geq,
-        semver::semver_leq,
-    )
+    let mut has_ground_mismatch = false;
+    for &b in BUILTINS {
+        match b.select(lit) {
+            SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(b)),
+            SelectBuiltinResult::GroundnessMismatch => has_ground_mismatch = true,
+            SelectBuiltinResult::NoMatch => {}
+        }
+    }
+    if has_ground_mismatch {
+        (SelectBuiltinResult::GroundnessMismatch, None)
+    } else {
+        (SelectBuiltinResult::NoMatch, None)
+    }
+}
+
+/// Names of the builtin predicates that can be used in a Modusfile, excluding
+/// the internal predicates that operators are translated to.
+pub fn builtin_predicate_names() -> Vec<&'static str> {
+    let mut names = BUILTINS
+        .iter()
+        .map(|b| b.name())
+        .filter(|name| !name.starts_with("_operator_"))
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names.dedup();
+    names
 }
 
 lazy_static! {
@@ -584,8 +1264,16 @@ lazy_static! {
         m.insert("set_label", (Kind::Image, Kind::Image));
         m.insert("set_user", (Kind::Image, Kind::Image));
         m.insert("append_path", (Kind::Image, Kind::Image));
+        m.insert("tag", (Kind::Image, Kind::Image));
+        m.insert("assert_file", (Kind::Image, Kind::Image));
+        m.insert("assert_cmd_succeeds", (Kind::Image, Kind::Image));
         m.insert("in_workdir", (Kind::Layer, Kind::Layer));
         m.insert("in_env", (Kind::Layer, Kind::Layer));
+        m.insert("retry", (Kind::Layer, Kind::Layer));
+        m.insert("timeout", (Kind::Layer, Kind::Layer));
+        m.insert("insecure", (Kind::Layer, Kind::Layer));
+        m.insert("cache_mount", (Kind::Layer, Kind::Layer));
+        m.insert("secret", (Kind::Layer, Kind::Layer));
         m.insert("merge", (Kind::Layer, Kind::Layer));
         m
     };
@@ -602,8 +1290,8 @@ mod test {
         let lit = Literal {
             positive: true,
             position: None,
-            predicate: Predicate("run".to_owned()),
-            args: vec![IRTerm::Constant("hello".to_owned())],
+            predicate: Predicate("run".into()),
+            args: vec![IRTerm::Constant("hello".into())],
         };
         let b = super::select_builtin(&lit);
         assert!(b.0.is_match());
@@ -615,11 +1303,11 @@ mod test {
         let lit = Literal {
             positive: true,
             position: None,
-            predicate: Predicate("string_concat".to_owned()),
+            predicate: Predicate("string_concat".into()),
             args: vec![
-                IRTerm::Constant("hello".to_owned()),
-                IRTerm::Constant("world".to_owned()),
-                IRTerm::UserVariable("X".to_owned()),
+                IRTerm::Constant("hello".into()),
+                IRTerm::Constant("world".into()),
+                IRTerm::UserVariable("X".into()),
             ],
         };
         let b = super::select_builtin(&lit);
@@ -632,11 +1320,11 @@ mod test {
             Some(Literal {
                 positive: true,
                 position: None,
-                predicate: Predicate("string_concat".to_owned()),
+                predicate: Predicate("string_concat".into()),
                 args: vec![
-                    IRTerm::Constant("hello".to_owned()),
-                    IRTerm::Constant("world".to_owned()),
-                    IRTerm::Constant("helloworld".to_owned()),
+                    IRTerm::Constant("hello".into()),
+                    IRTerm::Constant("world".into()),
+                    IRTerm::Constant("helloworld".into()),
                 ]
             })
         );
@@ -644,8 +1332,8 @@ mod test {
         let lit = Literal {
             positive: true,
             position: None,
-            predicate: Predicate("xxx".to_owned()),
-            args: vec![IRTerm::Constant("hello".to_owned())],
+            predicate: Predicate("xxx".into()),
+            args: vec![IRTerm::Constant("hello".into())],
         };
         let b = super::select_builtin(&lit);
         assert_eq!(b.0, SelectBuiltinResult::NoMatch);
@@ -659,28 +1347,28 @@ mod test {
             head: Literal {
                 positive: true,
                 position: None,
-                predicate: Predicate("a".to_owned()),
+                predicate: Predicate("a".into()),
                 args: vec![],
             },
             body: vec![
                 Literal {
                     positive: true,
                     position: None,
-                    predicate: Predicate("from".to_owned()),
-                    args: vec![IRTerm::Constant("ubuntu".to_owned())],
+                    predicate: Predicate("from".into()),
+                    args: vec![IRTerm::Constant("ubuntu".into())],
                 },
                 Literal {
                     positive: true,
                     position: None,
-                    predicate: Predicate("run".to_owned()),
-                    args: vec![IRTerm::Constant("rm -rf /".to_owned())],
+                    predicate: Predicate("run".into()),
+                    args: vec![IRTerm::Constant("rm -rf /".into())],
                 },
             ],
         }];
         let goals = vec![Literal {
             positive: true,
             position: None,
-            predicate: Predicate("a".to_owned()),
+            predicate: Predicate("a".into()),
             args: vec![],
         }];
         let tree = crate::sld::sld(&rules, &goals, 100, true).tree;
@@ -868,10 +1556,10 @@ mod test {
                 let lit = Literal {
                     positive: true,
                     position: None,
-                    predicate: Predicate(name.to_owned()),
+                    predicate: Predicate(name.into()),
                     args: vec![
-                        IRTerm::Constant(left.to_owned()),
-                        IRTerm::Constant(right.to_owned()),
+                        IRTerm::Constant(left.into()),
+                        IRTerm::Constant(right.into()),
                     ],
                 };
                 let b = super::select_builtin(&lit);
@@ -887,10 +1575,10 @@ mod test {
                 let lit = Literal {
                     positive: true,
                     position: None,
-                    predicate: Predicate(name.to_owned()),
+                    predicate: Predicate(name.into()),
                     args: vec![
-                        IRTerm::Constant(left.to_owned()),
-                        IRTerm::Constant(right.to_owned()),
+                        IRTerm::Constant(left.into()),
+                        IRTerm::Constant(right.into()),
                     ],
                 };
                 let b = super::select_builtin(&lit);
@@ -898,10 +1586,233 @@ mod test {
                 let b = b.1.unwrap();
                 assert_eq!(b.name(), name);
                 assert_eq!(b.kind(), Kind::Logic);
-                if b.apply(&lit) != None {
+                if b.apply(&lit).is_some() {
                     panic!("Expected {} to fail (but resolved)", lit);
                 }
             }
         }
     }
+
+    #[test]
+    pub fn test_number_arithmetic() {
+        use crate::logic::Literal;
+
+        let tests = vec![
+            (
+                "number_add(\"1\", \"2\", X)",
+                Some("number_add(\"1\", \"2\", \"3\")"),
+            ),
+            (
+                "number_add(X, \"2\", \"3\")",
+                Some("number_add(\"1\", \"2\", \"3\")"),
+            ),
+            (
+                "number_add(\"1\", X, \"3\")",
+                Some("number_add(\"1\", \"2\", \"3\")"),
+            ),
+            (
+                "number_add(\"1\", \"2\", \"3.0\")",
+                Some("number_add(\"1\", \"2\", \"3.0\")"),
+            ),
+            ("number_add(\"1\", \"2\", \"4\")", None),
+            (
+                "number_add(\"0.5\", \"1\", X)",
+                Some("number_add(\"0.5\", \"1\", \"1.5\")"),
+            ),
+            ("number_add(\"9223372036854775807\", \"1\", X)", None),
+            ("number_add(\"a\", \"1\", X)", None),
+            (
+                "number_sub(\"8080\", \"80\", X)",
+                Some("number_sub(\"8080\", \"80\", \"8000\")"),
+            ),
+            (
+                "number_sub(X, \"80\", \"8000\")",
+                Some("number_sub(\"8080\", \"80\", \"8000\")"),
+            ),
+            (
+                "number_sub(\"8080\", X, \"8000\")",
+                Some("number_sub(\"8080\", \"80\", \"8000\")"),
+            ),
+            (
+                "number_mul(\"3\", \"-4\", X)",
+                Some("number_mul(\"3\", \"-4\", \"-12\")"),
+            ),
+            ("number_mul(\"1e308\", \"10\", X)", None),
+            // Numerals are read by their value, and results are normalized.
+            (
+                "number_add(\"01\", \"1\", X)",
+                Some("number_add(\"01\", \"1\", \"2\")"),
+            ),
+            (
+                "number_add(\"+1\", \"-01\", X)",
+                Some("number_add(\"+1\", \"-01\", \"0\")"),
+            ),
+            (
+                "number_add(\"1\", \"2\", \"003\")",
+                Some("number_add(\"1\", \"2\", \"003\")"),
+            ),
+            (
+                "number_mul(\"-0.5\", \"0\", X)",
+                Some("number_mul(\"-0.5\", \"0\", \"0\")"),
+            ),
+            (
+                "number_sub(\"1.50\", \"0.5\", X)",
+                Some("number_sub(\"1.50\", \"0.5\", \"1\")"),
+            ),
+            ("number_add(\"--1\", \"1\", X)", None),
+            ("number_add(\"inf\", \"1\", X)", None),
+            ("number_add(\"NaN\", \"1\", X)", None),
+            // Integers out of range are not rounded to floats.
+            ("number_add(\"9223372036854775808\", \"0\", X)", None),
+            (
+                "number_add(\"9007199254740993\", \"0\", \"9007199254740992\")",
+                None,
+            ),
+        ];
+
+        for (lit, expected) in tests {
+            let lit: Literal = lit.parse().unwrap();
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match(), "{} should be selected", lit);
+            let b = b.1.unwrap();
+            assert_eq!(b.kind(), Kind::Logic);
+            let expected: Option<Literal> = expected.map(|e| e.parse().unwrap());
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args),
+                expected.map(|l| l.args),
+                "{}",
+                lit
+            );
+        }
+
+        let lit: Literal = "number_mul(X, \"2\", \"6\")".parse().unwrap();
+        assert_eq!(
+            super::select_builtin(&lit).0,
+            SelectBuiltinResult::GroundnessMismatch
+        );
+    }
+
+    #[test]
+    pub fn test_list_operations() {
+        use crate::logic::Literal;
+
+        let apply_all = |lit: &str| -> Vec<Vec<IRTerm>> {
+            let lit: Literal = lit.parse().unwrap();
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match(), "{} should be selected", lit);
+            b.1.unwrap()
+                .apply_all(&lit)
+                .into_iter()
+                .map(|l| l.args)
+                .collect()
+        };
+        let args = |lit: &str| -> Vec<IRTerm> { lit.parse::<Literal>().unwrap().args };
+
+        assert_eq!(
+            apply_all("member(X, [\"a\", \"b\", \"a\"])"),
+            vec![
+                args("member(\"a\", [\"a\", \"b\", \"a\"])"),
+                args("member(\"b\", [\"a\", \"b\", \"a\"])"),
+            ]
+        );
+        assert!(apply_all("member(X, \"a\")").is_empty());
+        assert_eq!(
+            apply_all("length([\"a\", \"b\"], N)"),
+            vec![args("length([\"a\", \"b\"], \"2\")")]
+        );
+        let lists = apply_all("length(L, \"2\")");
+        assert!(matches!(&lists[0][0], IRTerm::List(ts) if ts.len() == 2 && !ts[0].is_constant()));
+        assert_eq!(
+            apply_all("append([\"a\"], [\"b\"], C)"),
+            vec![args("append([\"a\"], [\"b\"], [\"a\", \"b\"])")]
+        );
+        assert_eq!(
+            apply_all("append(A, B, [\"a\", \"b\"])"),
+            vec![
+                args("append([], [\"a\", \"b\"], [\"a\", \"b\"])"),
+                args("append([\"a\"], [\"b\"], [\"a\", \"b\"])"),
+                args("append([\"a\", \"b\"], [], [\"a\", \"b\"])"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_bool_operations() {
+        use crate::logic::{Literal, Predicate};
+
+        let lit = |name: &str, args: &[&str]| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate(name.into()),
+            args: args
+                .iter()
+                .map(|a| match *a {
+                    "X" => IRTerm::UserVariable("X".into()),
+                    c => IRTerm::Constant(c.into()),
+                })
+                .collect(),
+        };
+        let tests = [
+            (
+                "bool_and",
+                vec!["true", "false", "X"],
+                Some(vec!["true", "false", "false"]),
+            ),
+            (
+                "bool_and",
+                vec!["true", "true", "X"],
+                Some(vec!["true", "true", "true"]),
+            ),
+            (
+                "bool_or",
+                vec!["false", "true", "X"],
+                Some(vec!["false", "true", "true"]),
+            ),
+            ("bool_or", vec!["false", "no", "X"], None),
+            ("bool_not", vec!["true", "X"], Some(vec!["true", "false"])),
+            ("bool_not", vec!["X", "true"], Some(vec!["false", "true"])),
+        ];
+        for (name, args, expected) in tests {
+            let input = lit(name, &args);
+            let b = super::select_builtin(&input);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.kind(), Kind::Logic);
+            assert_eq!(b.apply(&input), expected.map(|e| lit(name, &e)));
+        }
+    }
+
+    #[test]
+    pub fn test_changed_since() {
+        use crate::logic::{Literal, Predicate};
+
+        fn detect(rev: &str, path: &str) -> Result<bool, String> {
+            match rev {
+                "main" => Ok(path.starts_with("app/")),
+                _ => Err(format!("unknown revision {}", rev)),
+            }
+        }
+        super::set_change_detector(detect);
+
+        let lit = |rev: IRTerm, path: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("changed_since".into()),
+            args: vec![rev, IRTerm::Constant(path.into())],
+        };
+        let main = || IRTerm::Constant("main".into());
+        let apply = |lit: &Literal| {
+            let b = super::select_builtin(lit);
+            assert!(b.0.is_match());
+            b.1.unwrap().apply(lit)
+        };
+        assert!(apply(&lit(main(), "app/src")).is_some());
+        assert!(apply(&lit(main(), "lib/src")).is_none());
+        // Paths that cannot be checked count as changed.
+        assert!(apply(&lit(IRTerm::Constant("missing".into()), "lib/src")).is_some());
+        assert_eq!(
+            super::select_builtin(&lit(IRTerm::UserVariable("R".into()), "app")).0,
+            SelectBuiltinResult::GroundnessMismatch
+        );
+    }
 }
@@ -0,0 +1,208 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Bottom-up evaluation of the pure Datalog predicates of a program, e.g. facts about
+//! versions or package lists, as an alternative to top-down SLD resolution.
+//!
+//! A predicate is pure Datalog if each of its rules is range restricted, i.e. each
+//! variable of the head occurs in the body, has no lists, and only has positive body
+//! literals of pure Datalog predicates, so no builtin, operator, negation or `findall`.
+//! Its facts are then finite and ground, and are computed once with semi-naive
+//! evaluation: each round only joins the rules with the facts derived in the previous
+//! one.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::builtin::builtin_predicate_names;
+use crate::logic::{Clause, IRTerm, Literal, Signature};
+use crate::unification::{Substitute, Substitution};
+
+/// The facts of the pure Datalog predicates of a program, see the [module docs](self).
+#[derive(Clone, Debug, Default)]
+pub struct Model {
+    facts: HashMap<Signature, Vec<Literal>>,
+}
+
+impl Model {
+    /// Evaluates the pure Datalog predicates of the rules bottom-up.
+    pub fn new(rules: &[Clause]) -> Model {
+        let pure = pure_predicates(rules);
+        let rules = rules
+            .iter()
+            .filter(|r| pure.contains(&r.head.signature()))
+            .collect::<Vec<_>>();
+
+        let mut facts: HashMap<Signature, Vec<Literal>> =
+            pure.into_iter().map(|s| (s, Vec::new())).collect();
+        let mut known = HashSet::new();
+        let mut delta: HashMap<Signature, Vec<Literal>> = HashMap::new();
+        for r in rules.iter().filter(|r| r.body.is_empty()) {
+            let fact = ground_fact(&r.head, &Substitution::new());
+            if known.insert(fact.clone()) {
+                delta.entry(fact.signature()).or_default().push(fact);
+            }
+        }
+
+        while !delta.is_empty() {
+            for (signature, new) in &delta {
+                facts
+                    .get_mut(signature)
+                    .unwrap()
+                    .extend(new.iter().cloned());
+            }
+            let mut next: HashMap<Signature, Vec<Literal>> = HashMap::new();
+            for r in rules.iter().filter(|r| !r.body.is_empty()) {
+                // Each derivation that uses a fact of the last round is found by joining
+                // that fact with one of the body literals, and all facts with the others.
+                for i in 0..r.body.len() {
+                    if !delta.contains_key(&r.body[i].signature()) {
+                        continue;
+                    }
+                    for s in join(&r.body, i, &facts, &delta) {
+                        let fact = ground_fact(&r.head, &s);
+                        if known.insert(fact.clone()) {
+                            next.entry(fact.signature()).or_default().push(fact);
+                        }
+                    }
+                }
+            }
+            delta = next;
+        }
+        Model { facts }
+    }
+
+    /// The facts of the literal's predicate, if it is evaluated bottom-up.
+    pub fn facts(&self, literal: &Literal) -> Option<&[Literal]> {
+        if !literal.positive {
+            return None;
+        }
+        self.facts.get(&literal.signature()).map(Vec::as_slice)
+    }
+}
+
+/// The substitutions that satisfy the body, taking the facts of the literal at
+/// `from_delta` from `delta` and those of the other literals from `facts`.
+fn join(
+    body: &[Literal],
+    from_delta: usize,
+    facts: &HashMap<Signature, Vec<Literal>>,
+    delta: &HashMap<Signature, Vec<Literal>>,
+) -> Vec<Substitution> {
+    let mut substitutions = vec![Substitution::new()];
+    for (i, literal) in body.iter().enumerate() {
+        let source = if i == from_delta { delta } else { facts };
+        let candidates = source
+            .get(&literal.signature())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        substitutions = substitutions
+            .into_iter()
+            .flat_map(|s| {
+                let literal = literal.substitute(&s);
+                candidates
+                    .iter()
+                    .filter_map(move |fact| literal.unify(fact).map(|mgu| s.clone().union(mgu)))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        if substitutions.is_empty() {
+            break;
+        }
+    }
+    substitutions
+}
+
+fn ground_fact(head: &Literal, s: &Substitution) -> Literal {
+    Literal {
+        position: None,
+        ..head.substitute(s)
+    }
+}
+
+/// The signatures whose rules only depend on pure Datalog predicates, see the
+/// [module docs](self).
+fn pure_predicates(rules: &[Clause]) -> HashSet<Signature> {
+    let builtins = builtin_predicate_names();
+    let is_simple = |r: &Clause| {
+        let body_variables = r
+            .body
+            .iter()
+            .flat_map(|l| l.variables(true))
+            .collect::<HashSet<_>>();
+        r.head.variables(true).is_subset(&body_variables)
+            && r.body.iter().chain([&r.head]).all(|l| {
+                l.positive
+                    && !builtins.contains(&l.predicate.0.as_str())
+                    && !l.predicate.is_operator()
+                    && l.findall_parts().is_none()
+                    && l.args.iter().all(|t| !matches!(t, IRTerm::List(_)))
+            })
+    };
+
+    let mut pure = rules
+        .iter()
+        .map(|r| r.head.signature())
+        .collect::<HashSet<_>>();
+    for r in rules.iter().filter(|r| !is_simple(r)) {
+        pure.remove(&r.head.signature());
+    }
+    // Removes the predicates that depend on removed or undefined ones, until none does.
+    loop {
+        let impure = rules
+            .iter()
+            .filter(|r| pure.contains(&r.head.signature()))
+            .filter(|r| r.body.iter().any(|l| !pure.contains(&l.signature())))
+            .map(|r| r.head.signature())
+            .collect::<HashSet<_>>();
+        if impure.is_empty() {
+            break pure;
+        }
+        pure.retain(|s| !impure.contains(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn semi_naive_evaluation() {
+        let rules: Vec<Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "edge(\"c\", \"a\").".parse().unwrap(),
+            "path(X, Y) :- path(X, Z), edge(Z, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "reachable(X) :- path(\"a\", X), string_eq(X, X)."
+                .parse()
+                .unwrap(),
+            "any(X) :- edge(X, _).".parse().unwrap(),
+        ];
+        let model = Model::new(&rules);
+
+        let paths = model.facts(&"path(X, Y)".parse().unwrap()).unwrap();
+        assert_eq!(paths.len(), 9);
+        assert!(paths.iter().any(|p| p.to_string() == "path(\"b\", \"a\")"));
+        assert_eq!(
+            model.facts(&"edge(X, Y)".parse().unwrap()).unwrap().len(),
+            3
+        );
+        // Not pure Datalog, since it uses a builtin.
+        assert!(model.facts(&"reachable(X)".parse().unwrap()).is_none());
+        assert!(model.facts(&"any(X)".parse().unwrap()).is_some());
+        assert!(model.facts(&"!edge(X, Y)".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,234 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Warnings about deprecated syntax and builtins, with fixes that rewrite the source of
+//! the Modusfile to the syntax that replaces them.
+
+use std::ops::Range;
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::logic;
+use crate::modusfile::{tokens::*, ModusTerm, Modusfile};
+
+type Literal = logic::Literal<ModusTerm>;
+
+/// A rewrite of part of the source of a Modusfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fix {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+/// A use of deprecated syntax, and the fix for it, if it can be rewritten automatically.
+#[derive(Debug, Clone)]
+pub struct Deprecated {
+    pub diagnostic: Diagnostic<()>,
+    pub fix: Option<Fix>,
+}
+
+/// How a use of a deprecated builtin is rewritten.
+#[derive(Debug, Clone, Copy)]
+enum Rewrite {
+    /// To the infix operator that is parsed into the builtin, e.g. `number_gt(X, "3")`
+    /// to `X > "3"`.
+    Infix(&'static str),
+}
+
+impl Rewrite {
+    fn apply(&self, lit: &Literal) -> Option<String> {
+        // Terms are displayed without escaping, so only simple ones are rewritten.
+        let simple = |t: &ModusTerm| match t {
+            ModusTerm::UserVariable(_) => true,
+            ModusTerm::Constant(c) => !c.contains(['"', '\\', '\n']),
+            _ => false,
+        };
+        match (self, &lit.args[..]) {
+            // A constant that is not a number would make the infix operator compare versions.
+            (Rewrite::Infix(_), [t1, t2])
+                if t1.is_non_numeric_constant() || t2.is_non_numeric_constant() =>
+            {
+                None
+            }
+            (Rewrite::Infix(operator), [t1, t2]) if simple(t1) && simple(t2) => {
+                Some(format!("{} {} {}", t1, operator, t2))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A deprecated builtin predicate.
+struct Deprecation {
+    predicate: &'static str,
+    message: &'static str,
+    rewrite: Rewrite,
+}
+
+const COMPARISON_MESSAGE: &str =
+    "number comparison builtins are deprecated, use `>`, `<`, `>=` or `<=`";
+
+const DEPRECATIONS: &[Deprecation] = &[
+    Deprecation {
+        predicate: "number_gt",
+        message: COMPARISON_MESSAGE,
+        rewrite: Rewrite::Infix(GREATER),
+    },
+    Deprecation {
+        predicate: "number_lt",
+        message: COMPARISON_MESSAGE,
+        rewrite: Rewrite::Infix(LESS),
+    },
+    Deprecation {
+        predicate: "number_geq",
+        message: COMPARISON_MESSAGE,
+        rewrite: Rewrite::Infix(GREATER_EQUALS),
+    },
+    Deprecation {
+        predicate: "number_leq",
+        message: COMPARISON_MESSAGE,
+        rewrite: Rewrite::Infix(LESS_EQUALS),
+    },
+];
+
+/// True if the literal was written as a call of its predicate, rather than parsed from
+/// syntax that is translated into it, such as `X > "3"`.
+fn is_call(lit: &Literal, text: &str) -> bool {
+    text.trim_start_matches(|c: char| c == NEGATION || c.is_whitespace())
+        .strip_prefix(lit.predicate.0.as_str())
+        .is_some_and(|rest| rest.trim_start().starts_with(ARGS_START))
+}
+
+/// Finds the uses of deprecated syntax in the Modusfile, which was parsed from `source`,
+/// in the order they appear in it. Clauses without positions, such as the ones added
+/// from libraries, are skipped.
+pub fn find_deprecated(mf: &Modusfile, source: &str) -> Vec<Deprecated> {
+    let mut literals =
+        mf.0.iter()
+            .filter_map(|c| c.body.as_ref())
+            .flat_map(|body| body.literals())
+            .filter_map(|lit| {
+                let pos = lit.position.clone()?;
+                let text = source.get(pos.offset..pos.offset + pos.length)?;
+                let deprecation = DEPRECATIONS
+                    .iter()
+                    .find(|d| lit.predicate.0 == d.predicate && is_call(&lit, text))?;
+                Some((pos.offset..pos.offset + pos.length, lit, deprecation))
+            })
+            .collect::<Vec<_>>();
+    literals.sort_by_key(|(range, ..)| (range.start, range.end));
+
+    literals
+        .into_iter()
+        .map(|(range, lit, deprecation)| {
+            let fix = deprecation
+                .rewrite
+                .apply(&lit)
+                .map(|replacement| match lit.positive {
+                    true => replacement,
+                    false => format!("{}{}{}{}", NEGATION, ARGS_START, replacement, ARGS_END),
+                })
+                .map(|replacement| Fix {
+                    range: range.clone(),
+                    replacement,
+                });
+            let label = Label::primary((), range).with_message(match &fix {
+                Some(fix) => format!("replace with `{}`", fix.replacement),
+                None => "this can not be rewritten automatically".to_owned(),
+            });
+            Deprecated {
+                diagnostic: Diagnostic::warning()
+                    .with_code("deprecated")
+                    .with_message(deprecation.message)
+                    .with_labels(vec![label]),
+                fix,
+            }
+        })
+        .collect()
+}
+
+/// Applies the fixes to the source. A fix that overlaps one before it is skipped, so the
+/// number of applied fixes is returned too.
+pub fn apply_fixes<'a>(source: &str, fixes: impl IntoIterator<Item = &'a Fix>) -> (String, usize) {
+    let mut fixes = fixes.into_iter().collect::<Vec<_>>();
+    fixes.sort_by_key(|f| (f.range.start, f.range.end));
+    let mut fixed = String::with_capacity(source.len());
+    let mut end = 0;
+    let mut applied = 0;
+    for fix in fixes {
+        if fix.range.start < end {
+            continue;
+        }
+        fixed.push_str(&source[end..fix.range.start]);
+        fixed.push_str(&fix.replacement);
+        end = fix.range.end;
+        applied += 1;
+    }
+    fixed.push_str(&source[end..]);
+    (fixed, applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_comparison_builtins() {
+        let source = "a(X) :- number_gt(X, \"3\"), X < \"9\", number_leq (\"1\", X).\n\
+                      b(X) :- !number_geq(X, \"3\"), number_lt(X, f\"${X}\").";
+        let mf: Modusfile = source.parse().unwrap();
+        let deprecated = find_deprecated(&mf, source);
+        assert_eq!(deprecated.len(), 4);
+        assert!(deprecated
+            .iter()
+            .all(|d| d.diagnostic.code.as_deref() == Some("deprecated")));
+        assert_eq!(
+            deprecated.iter().filter(|d| d.fix.is_some()).count(),
+            3,
+            "Expected the f-string not to be fixed"
+        );
+
+        let (fixed, applied) =
+            apply_fixes(source, deprecated.iter().filter_map(|d| d.fix.as_ref()));
+        assert_eq!(applied, 3);
+        assert_eq!(
+            fixed,
+            "a(X) :- X > \"3\", X < \"9\", \"1\" <= X.\n\
+             b(X) :- !(X >= \"3\"), number_lt(X, f\"${X}\")."
+        );
+        let mf: Modusfile = fixed.parse().unwrap();
+        assert_eq!(find_deprecated(&mf, &fixed).len(), 1);
+    }
+
+    #[test]
+    fn non_numeric_comparisons_are_not_fixed() {
+        let source = "a(X) :- number_gt(X, \"1.2.0\").";
+        let mf: Modusfile = source.parse().unwrap();
+        let deprecated = find_deprecated(&mf, source);
+        assert_eq!(deprecated.len(), 1);
+        assert!(deprecated[0].fix.is_none());
+    }
+
+    #[test]
+    fn overlapping_fixes() {
+        let fix = |range: Range<usize>, replacement: &str| Fix {
+            range,
+            replacement: replacement.to_owned(),
+        };
+        let fixes = [fix(4..6, "x"), fix(0..2, "ab"), fix(1..5, "y")];
+        assert_eq!(apply_fixes("012345678", &fixes), ("ab23x678".to_owned(), 2));
+    }
+}
@@ -38,6 +38,8 @@ pub struct UnresolvedParent(String);
 pub struct From<P> {
     pub parent: P,
     pub alias: Option<String>,
+    /// The platform given by `--platform`, e.g. `linux/arm64`.
+    pub platform: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -59,6 +61,8 @@ pub struct Workdir(pub String);
 pub enum Instruction<P> {
     From(From<P>),
     Run(Run),
+    /// `RUN` with flags, such as `--mount=type=cache,target=/var/cache/apk`.
+    RunWithFlags(Vec<String>, Run),
     Cmd(String),
     Label(String, String),
     // Maintainer(String),
@@ -71,6 +75,8 @@ pub enum Instruction<P> {
     // User(String),
     Workdir(Workdir),
     Arg(Arg),
+    /// A comment line, which is not parsed back.
+    Comment(String),
     // Onbuild(String),
     // Stopsignal(String),
     // Healthcheck(String),
@@ -127,6 +133,9 @@ where
     P: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(platform) = &self.platform {
+            write!(f, "--platform={} ", platform)?;
+        }
         match &self.alias {
             Some(a) => write!(f, "{} AS {}", self.parent, a),
             None => write!(f, "{}", self.parent),
@@ -149,9 +158,86 @@ impl fmt::Display for UnresolvedParent {
     }
 }
 
+/// Frontend syntax required for heredocs, see `Run::is_multiline`, and `RUN --mount`.
+const HEREDOC_SYNTAX: &str = "docker/dockerfile:1.4";
+/// The flag of `RUN` that needs the labs syntax, see `LABS_SYNTAX`.
+pub const INSECURE_FLAG: &str = "--security=insecure";
+/// Frontend syntax required for `RUN --security=insecure`, which also supports heredocs.
+const LABS_SYNTAX: &str = "docker/dockerfile:1.4-labs";
+
+/// Words that start or end a line of a compound shell command, e.g. an `if`, which can't
+/// be joined with the next line by `&&`.
+const COMPOUND_KEYWORDS: [&str; 14] = [
+    "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case", "esac", "{",
+    "}",
+];
+
+/// Endings of a line that continue the command on the next one.
+const CONTINUATIONS: [&str; 5] = ["\\", "|", "&&", "&", "("];
+
+impl Run {
+    /// Multi-line commands are emitted as heredocs, since a line break would otherwise end the instruction.
+    pub fn is_multiline(&self) -> bool {
+        self.0.contains('\n')
+    }
+
+    /// The command with its lines joined by `&&`, which runs them in order and stops at the
+    /// first one that fails, so that it needs no heredoc. Empty lines and comments are left
+    /// out. Returns `None` if a line might not be a whole command, e.g. if it continues on
+    /// the next line, is part of an `if`, or has a quote that is closed on another line.
+    pub fn joined(&self) -> Option<Run> {
+        let is_whole_command = |line: &str| {
+            let first = line.split_whitespace().next().unwrap_or_default();
+            let last = line.split_whitespace().last().unwrap_or_default();
+            !CONTINUATIONS.iter().any(|c| line.ends_with(c))
+                && ![first, last.trim_end_matches(';')]
+                    .iter()
+                    .any(|w| COMPOUND_KEYWORDS.contains(w))
+                && !line.contains("<<")
+                && !line.contains(" #")
+                && line.matches('\'').count().is_multiple_of(2)
+                && line
+                    .replace("\\\"", "")
+                    .matches('"')
+                    .count()
+                    .is_multiple_of(2)
+        };
+        let commands = self
+            .0
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect::<Vec<_>>();
+        if commands.is_empty() || !commands.iter().all(|l| is_whole_command(l)) {
+            return None;
+        }
+        Some(Run(commands.join(" && ")))
+    }
+
+    /// Returns a heredoc delimiter that does not occur as a line of the command. Lines are
+    /// compared without their surrounding whitespace, so that a delimiter indented with
+    /// tabs, which `<<-` strips, counts as well.
+    fn heredoc_delimiter(&self) -> String {
+        let mut delimiter = "EOF".to_string();
+        let mut n = 0;
+        while self.0.lines().any(|l| l.trim() == delimiter) {
+            n += 1;
+            delimiter = format!("EOF_{}", n);
+        }
+        delimiter
+    }
+}
+
 impl fmt::Display for Run {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        if self.is_multiline() {
+            let delimiter = self.heredoc_delimiter();
+            // The line of the delimiter ends the last line of the command.
+            let body = self.0.strip_suffix('\n').unwrap_or(&self.0);
+            write!(f, "<<{}\n{}\n{}", delimiter, body, delimiter)
+        } else {
+            write!(f, "{}", self.0)
+        }
     }
 }
 
@@ -179,6 +265,32 @@ impl fmt::Display for Workdir {
     }
 }
 
+impl<P> Dockerfile<P> {
+    /// Joins the lines of multi-line commands with `&&` where possible, see [`Run::joined`],
+    /// so that the Dockerfile does not need a frontend with heredocs.
+    pub fn with_joined_runs(self) -> Self {
+        let join = |r: Run| {
+            if r.is_multiline() {
+                r.joined().unwrap_or(r)
+            } else {
+                r
+            }
+        };
+        Dockerfile(
+            self.0
+                .into_iter()
+                .map(|i| match i {
+                    Instruction::Run(r) => Instruction::Run(join(r)),
+                    Instruction::RunWithFlags(flags, r) => {
+                        Instruction::RunWithFlags(flags, join(r))
+                    }
+                    i => i,
+                })
+                .collect(),
+        )
+    }
+}
+
 impl str::FromStr for Dockerfile<UnresolvedParent> {
     type Err = String;
 
@@ -195,17 +307,30 @@ where
     T: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.iter().any(
+            |i| matches!(i, Instruction::RunWithFlags(flags, _) if flags.iter().any(|f| f == INSECURE_FLAG)),
+        ) {
+            writeln!(f, "# syntax={}", LABS_SYNTAX)?;
+        } else if self.0.iter().any(|i| match i {
+            Instruction::Run(r) => r.is_multiline(),
+            Instruction::RunWithFlags(..) => true,
+            _ => false,
+        }) {
+            writeln!(f, "# syntax={}", HEREDOC_SYNTAX)?;
+        }
         for i in self.0.iter() {
             match i {
                 Instruction::Arg(s) => writeln!(f, "ARG {}", s),
                 Instruction::Copy(s) => writeln!(f, "COPY {}", s),
                 Instruction::From(image) => writeln!(f, "\nFROM {}", image),
                 Instruction::Run(s) => writeln!(f, "RUN {}", s),
+                Instruction::RunWithFlags(flags, s) => writeln!(f, "RUN {} {}", flags.join(" "), s),
                 Instruction::Env(s) => writeln!(f, "ENV {}", s),
                 Instruction::Workdir(s) => writeln!(f, "WORKDIR {}", s),
                 Instruction::Entrypoint(s) => writeln!(f, "ENTRYPOINT {}", s),
                 Instruction::Cmd(s) => writeln!(f, "CMD {}", s),
                 Instruction::Label(k, v) => writeln!(f, "LABEL {:?}={:?}", k, v),
+                Instruction::Comment(s) => writeln!(f, "# {}", s),
             }?;
         }
         Ok(())
@@ -340,9 +465,17 @@ pub mod parser {
         map(recognize(image), |s| UnresolvedParent(s.into()))(i)
     }
 
+    fn platform(i: &str) -> IResult<&str, String> {
+        map(
+            delimited(tag("--platform="), is_not(" \t\n\r"), space),
+            String::from,
+        )(i)
+    }
+
     fn from_content(i: &str) -> IResult<&str, From<UnresolvedParent>> {
         map(
-            pair(
+            tuple((
+                opt(platform),
                 parent,
                 opt(map(
                     preceded(
@@ -351,8 +484,12 @@ pub mod parser {
                     ),
                     String::from,
                 )),
-            ),
-            |(parent, alias)| From { parent, alias },
+            )),
+            |(platform, parent, alias)| From {
+                parent,
+                alias,
+                platform,
+            },
         )(i)
     }
 
@@ -484,6 +621,7 @@ mod tests {
         From {
             parent: UnresolvedParent("ubuntu".into()),
             alias: None,
+            platform: None,
         }
     }
 
@@ -491,6 +629,7 @@ mod tests {
         From {
             parent: UnresolvedParent("ubuntu:20.04".into()),
             alias: None,
+            platform: None,
         }
     }
 
@@ -508,6 +647,22 @@ mod tests {
         assert_eq!(Ok(e), "FROM ubuntu:20.04\n".parse());
     }
 
+    #[test]
+    fn from_with_platform() {
+        let f = Instruction::From(From {
+            platform: Some("linux/arm64".into()),
+            ..from_ubuntu_latest()
+        });
+        assert_eq!(
+            "FROM --platform=linux/arm64 ubuntu\n",
+            Dockerfile(vec![f.clone()]).to_string().trim_start()
+        );
+        assert_eq!(
+            Ok(Dockerfile(vec![f])),
+            "FROM --platform=linux/arm64 ubuntu\n".parse()
+        );
+    }
+
     #[test]
     fn two_instructions() {
         let f = Instruction::From(from_ubuntu_latest());
@@ -516,6 +671,79 @@ mod tests {
         assert_eq!(Ok(e), "FROM ubuntu\nRUN ls\n".parse());
     }
 
+    #[test]
+    fn multiline_run_is_heredoc() {
+        let f = Instruction::From(from_ubuntu_latest());
+        let r = Instruction::Run(Run("echo \"a\"\nEOF\necho b".into()));
+        let e: Dockerfile<UnresolvedParent> = Dockerfile(vec![f, r]);
+        assert_eq!(
+            "# syntax=docker/dockerfile:1.4\n\nFROM ubuntu\nRUN <<EOF_1\necho \"a\"\nEOF\necho b\nEOF_1\n",
+            e.to_string()
+        );
+
+        assert_eq!(
+            Run("echo a\necho b\n".into()).to_string(),
+            "<<EOF\necho a\necho b\nEOF"
+        );
+        assert_eq!(Run("echo a\n\n".into()).to_string(), "<<EOF\necho a\n\nEOF");
+        assert_eq!(
+            Run("cat <<-EOF\n\ta\n\tEOF\n".into()).to_string(),
+            "<<EOF_1\ncat <<-EOF\n\ta\n\tEOF\nEOF_1"
+        );
+    }
+
+    #[test]
+    fn joined_runs() {
+        let joined = |s: &str| Run(s.into()).joined().map(|r| r.0);
+        assert_eq!(
+            joined("cd \"/app\" || exit 1; apk add make\n\n# build it\nmake \"a b\"\n"),
+            Some("cd \"/app\" || exit 1; apk add make && make \"a b\"".to_owned())
+        );
+        assert_eq!(joined("if [ -f x ]; then\n  rm x\nfi"), None);
+        assert_eq!(joined("apk add \\\n  make"), None);
+        assert_eq!(joined("echo 'a\nb'"), None);
+        assert_eq!(joined("cat <<X\na\nX"), None);
+
+        let f = Instruction::From(from_ubuntu_latest());
+        let r = Instruction::Run(Run("echo a\necho b".into()));
+        let i = Instruction::Run(Run("for f in *; do\necho $f\ndone".into()));
+        let e: Dockerfile<UnresolvedParent> = Dockerfile(vec![f.clone(), r]);
+        assert_eq!(
+            "\nFROM ubuntu\nRUN echo a && echo b\n",
+            e.with_joined_runs().to_string()
+        );
+        let e: Dockerfile<UnresolvedParent> = Dockerfile(vec![f, i]);
+        assert!(e.with_joined_runs().to_string().contains("RUN <<EOF\n"));
+    }
+
+    #[test]
+    fn insecure_run_needs_labs_syntax() {
+        let f = Instruction::From(from_ubuntu_latest());
+        let r = Instruction::Run(Run("echo a\necho b".into()));
+        let i = Instruction::RunWithFlags(vec![INSECURE_FLAG.to_owned()], Run("modprobe x".into()));
+        let e: Dockerfile<UnresolvedParent> = Dockerfile(vec![f, r, i]);
+        assert_eq!(
+            "# syntax=docker/dockerfile:1.4-labs\n\nFROM ubuntu\nRUN <<EOF\necho a\necho b\nEOF\n\
+             RUN --security=insecure modprobe x\n",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn cache_mount_needs_heredoc_syntax() {
+        let f = Instruction::From(from_ubuntu_latest());
+        let r = Instruction::RunWithFlags(
+            vec!["--mount=type=cache,target=/var/cache/apt".to_owned()],
+            Run("apt-get update".into()),
+        );
+        let e: Dockerfile<UnresolvedParent> = Dockerfile(vec![f, r]);
+        assert_eq!(
+            "# syntax=docker/dockerfile:1.4\n\nFROM ubuntu\n\
+             RUN --mount=type=cache,target=/var/cache/apt apt-get update\n",
+            e.to_string()
+        );
+    }
+
     #[test]
     fn no_newline() {
         let f = Instruction::From(from_ubuntu_latest());
@@ -0,0 +1,136 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The errors of the stages of building an image from a Modusfile, from parsing it to
+//! running the build.
+
+use std::fmt;
+
+use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
+
+use crate::imagegen::PlanError;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An error of one of the stages of building a Modusfile. The errors found in the
+/// Modusfile carry diagnostics, which point to where they are in it.
+#[derive(Debug, thiserror::Error)]
+pub enum ModusError {
+    /// The Modusfile or the query could not be parsed.
+    #[error("{}", Summary(.0))]
+    Parse(Vec<Diagnostic<()>>),
+    /// The Modusfile or the query is not well-formed, e.g. it has a kind error.
+    #[error("{}", Summary(.0))]
+    Wellformedness(Vec<Diagnostic<()>>),
+    /// The query could not be solved, e.g. it has no solution or the solver exceeded
+    /// the maximum depth.
+    #[error("{}", Summary(.0))]
+    Solving(Vec<Diagnostic<()>>),
+    /// The proofs of the query could not be turned into a build plan.
+    #[error("{}", Summary(.0))]
+    Imagegen(Vec<Diagnostic<()>>),
+    /// A base image could not be resolved from its registry, or its signature was invalid.
+    #[error("{0}")]
+    Registry(#[source] BoxedError),
+    /// The build of the plan failed.
+    #[error("{0}")]
+    Build(#[source] BoxedError),
+    /// Solving or building was cancelled, e.g. by Ctrl-C.
+    #[error("Cancelled.")]
+    Cancelled,
+}
+
+/// Displays the first error of the diagnostics, and how many more there are.
+struct Summary<'a>(&'a [Diagnostic<()>]);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut errors = self.0.iter().filter(|d| d.severity >= Severity::Error);
+        match errors.next().or_else(|| self.0.first()) {
+            Some(d) => write!(f, "{}", d.message)?,
+            None => write!(f, "unknown error")?,
+        }
+        match errors.count() {
+            0 => Ok(()),
+            1 => write!(f, " (and 1 more error)"),
+            n => write!(f, " (and {} more errors)", n),
+        }
+    }
+}
+
+impl From<PlanError> for ModusError {
+    fn from(e: PlanError) -> Self {
+        let mut diagnostic = Diagnostic::error()
+            .with_code("invalid-build-plan")
+            .with_message(e.message);
+        if let Some(position) = e.position {
+            diagnostic = diagnostic.with_labels(vec![Label::primary((), &position)]);
+        }
+        ModusError::Imagegen(vec![diagnostic])
+    }
+}
+
+impl ModusError {
+    /// The diagnostics of the error, for reporting it with the source of the Modusfile.
+    /// Errors of the build, which are not in the Modusfile, are a single diagnostic
+    /// without labels.
+    pub fn diagnostics(&self) -> Vec<Diagnostic<()>> {
+        match self {
+            ModusError::Parse(diags)
+            | ModusError::Wellformedness(diags)
+            | ModusError::Solving(diags)
+            | ModusError::Imagegen(diags) => diags.clone(),
+            ModusError::Registry(e) | ModusError::Build(e) => {
+                vec![Diagnostic::error().with_message(e.to_string())]
+            }
+            ModusError::Cancelled => vec![Diagnostic::error().with_message(self.to_string())],
+        }
+    }
+
+    /// Adds the diagnostics of another error to this one, which keeps its stage. Used to
+    /// report the errors of several queries together.
+    pub fn merge(mut self, other: ModusError) -> ModusError {
+        match &mut self {
+            ModusError::Parse(diags)
+            | ModusError::Wellformedness(diags)
+            | ModusError::Solving(diags)
+            | ModusError::Imagegen(diags) => diags.extend(other.diagnostics()),
+            ModusError::Registry(_) | ModusError::Build(_) | ModusError::Cancelled => {}
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_first_error() {
+        let e = ModusError::Solving(vec![
+            Diagnostic::warning().with_message("maximum depth exceeded"),
+            Diagnostic::error().with_message("no solution"),
+            Diagnostic::error().with_message("unknown predicate"),
+        ]);
+        assert_eq!(e.to_string(), "no solution (and 1 more error)");
+
+        let merged = e.merge(ModusError::Imagegen(vec![
+            Diagnostic::error().with_message("No base layer yet.")
+        ]));
+        assert!(matches!(&merged, ModusError::Solving(diags) if diags.len() == 4));
+        assert_eq!(merged.to_string(), "no solution (and 2 more errors)");
+    }
+}
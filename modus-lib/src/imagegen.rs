@@ -14,14 +14,17 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::{HashMap, HashSet};
-use std::iter::{self, FromIterator};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::iter::FromIterator;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::analysis::{Kind, ModusSemantics};
-use crate::logic::{Clause, IRTerm, Literal, Predicate};
+use crate::error::ModusError;
+use crate::logic::{Clause, IRTerm, Literal, Predicate, SpannedPosition};
 use crate::modusfile::{self, Modusfile};
-use crate::sld::{self, ClauseId, Proof, ResolutionError};
+use crate::sld::{self, ClauseId, Failure, Proof};
 use crate::translate::translate_modusfile;
 use crate::unification::Substitute;
 
@@ -36,6 +39,22 @@ pub struct BuildPlan {
     pub nodes: Vec<BuildNode>,
     pub dependencies: Vec<Vec<NodeId>>,
     pub outputs: Vec<Output>,
+    /// The head of the rule whose body produced each node, if any, e.g. to
+    /// trace a Dockerfile instruction back to the Modusfile.
+    #[serde(skip)]
+    pub sources: Vec<Option<Literal>>,
+    /// The platform that images are built for, e.g. `linux/arm64`, if one was
+    /// requested. Otherwise, images are built for the platform of the builder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// The tags given to images with the `::tag` operator, as the tagged node and the
+    /// tag. The tags of the outputs are also recorded in the outputs.
+    #[serde(skip)]
+    pub tags: Vec<(NodeId, String)>,
+    /// The checks given to images with the `::assert_file` and `::assert_cmd_succeeds`
+    /// operators, as the checked node and the check.
+    #[serde(skip)]
+    pub assertions: Vec<(NodeId, Assertion)>,
 }
 
 impl BuildPlan {
@@ -44,21 +63,327 @@ impl BuildPlan {
             nodes: Vec::new(),
             dependencies: Vec::new(),
             outputs: Vec::new(),
+            sources: Vec::new(),
+            platform: None,
+            tags: Vec::new(),
+            assertions: Vec::new(),
         }
     }
 
     pub fn new_node(&mut self, node: BuildNode, deps: Vec<NodeId>) -> NodeId {
         let id = self.nodes.len();
         self.nodes.push(node);
-        self.dependencies.push(
-            HashSet::<_>::from_iter(deps.into_iter())
-                .into_iter()
-                .collect(),
-        );
+        self.dependencies
+            .push(HashSet::<_>::from_iter(deps).into_iter().collect());
+        self.sources.push(None);
         debug_assert_eq!(self.nodes.len(), self.dependencies.len());
         id
     }
 
+    /// Sets the source of the nodes created since `first_node` that do not have one yet.
+    fn attribute_nodes(&mut self, first_node: NodeId, source: &Literal) {
+        for s in self.sources.iter_mut().skip(first_node) {
+            s.get_or_insert_with(|| source.clone());
+        }
+    }
+
+    /// The source of the node, see [`BuildPlan::sources`].
+    pub fn source(&self, node: NodeId) -> Option<&Literal> {
+        self.sources.get(node).and_then(Option::as_ref)
+    }
+
+    /// The ids of the secrets that commands mount with `::secret`, with the nodes that
+    /// run the commands.
+    pub fn secret_nodes(&self) -> BTreeMap<&str, BTreeSet<NodeId>> {
+        let mut res = BTreeMap::<&str, BTreeSet<NodeId>>::new();
+        for (node_id, node) in self.nodes.iter().enumerate() {
+            let secrets: Vec<&String> = match node {
+                BuildNode::Run { secrets, .. } => secrets.iter().collect(),
+                BuildNode::Merge(merge) => merge
+                    .operations
+                    .iter()
+                    .flat_map(|op| match op {
+                        MergeOperation::Run { secrets, .. } => secrets.iter().collect(),
+                        _ => Vec::new(),
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            for id in secrets {
+                res.entry(id).or_default().insert(node_id);
+            }
+        }
+        res
+    }
+
+    /// The nodes that run a command with `::insecure`.
+    pub fn insecure_nodes(&self) -> Vec<NodeId> {
+        (0..self.nodes.len())
+            .filter(|&node| match &self.nodes[node] {
+                BuildNode::Run { insecure, .. } => *insecure,
+                BuildNode::Merge(merge) => merge
+                    .operations
+                    .iter()
+                    .any(|op| matches!(op, MergeOperation::Run { insecure: true, .. })),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Returns a copy of the plan with only the nodes that some output depends on, which
+    /// are renumbered in their original order.
+    pub fn without_unreachable_nodes(&self) -> BuildPlan {
+        let mut reachable = self.topological_order();
+        reachable.sort_unstable();
+        let mut new_ids = vec![None; self.nodes.len()];
+        for (new_id, &node) in reachable.iter().enumerate() {
+            new_ids[node] = Some(new_id);
+        }
+        let new_id = |node: NodeId| {
+            new_ids[node].expect("Expected dependencies of a reachable node to be reachable")
+        };
+        BuildPlan {
+            nodes: reachable
+                .iter()
+                .map(|&node| self.nodes[node].clone().map_node_ids(new_id))
+                .collect(),
+            dependencies: reachable
+                .iter()
+                .map(|&node| self.dependencies[node].iter().map(|&d| new_id(d)).collect())
+                .collect(),
+            outputs: self
+                .outputs
+                .iter()
+                .map(|o| Output {
+                    node: new_id(o.node),
+                    ..o.clone()
+                })
+                .collect(),
+            sources: reachable
+                .iter()
+                .map(|&node| self.source(node).cloned())
+                .collect(),
+            platform: self.platform.clone(),
+            tags: self
+                .tags
+                .iter()
+                .filter_map(|(node, tag)| Some((new_ids[*node]?, tag.clone())))
+                .collect(),
+            assertions: self
+                .assertions
+                .iter()
+                .filter_map(|(node, assertion)| Some((new_ids[*node]?, assertion.clone())))
+                .collect(),
+        }
+    }
+
+    /// Returns a copy of the plan in which identical nodes with identical dependencies
+    /// are shared, e.g. the leading instructions that several outputs have in common, so
+    /// that they are only built once. Nodes that are no longer used are removed.
+    pub fn with_shared_nodes(&self) -> BuildPlan {
+        let mut shared_ids = (0..self.nodes.len()).collect::<Vec<NodeId>>();
+        let mut nodes = self.nodes.clone();
+        let mut dependencies = self.dependencies.clone();
+        let mut candidates: HashMap<Vec<NodeId>, Vec<NodeId>> = HashMap::new();
+        // Dependencies come first, so the nodes they refer to are already shared.
+        for node in self.topological_order() {
+            let shared_node = self.nodes[node].clone().map_node_ids(|d| shared_ids[d]);
+            let mut deps = self.dependencies[node]
+                .iter()
+                .map(|&d| shared_ids[d])
+                .collect::<Vec<_>>();
+            deps.sort_unstable();
+            deps.dedup();
+            let same_deps = candidates.entry(deps.clone()).or_default();
+            match same_deps.iter().find(|&&c| nodes[c] == shared_node) {
+                Some(&existing) => shared_ids[node] = existing,
+                None => same_deps.push(node),
+            }
+            nodes[node] = shared_node;
+            dependencies[node] = deps;
+        }
+        BuildPlan {
+            nodes,
+            dependencies,
+            outputs: self
+                .outputs
+                .iter()
+                .map(|o| Output {
+                    node: shared_ids[o.node],
+                    ..o.clone()
+                })
+                .collect(),
+            sources: self.sources.clone(),
+            platform: self.platform.clone(),
+            tags: self
+                .tags
+                .iter()
+                .map(|(node, tag)| (shared_ids[*node], tag.clone()))
+                .collect(),
+            assertions: self
+                .assertions
+                .iter()
+                .map(|(node, assertion)| (shared_ids[*node], assertion.clone()))
+                .collect(),
+        }
+        .without_unreachable_nodes()
+    }
+
+    /// Summarizes what building the plan involves, without building it.
+    pub fn stats(&self) -> PlanStats {
+        let mut stats = PlanStats::default();
+        for node in self.topological_order() {
+            *stats.nodes.entry(self.nodes[node].name()).or_default() += 1;
+            match &self.nodes[node] {
+                BuildNode::From { image_ref, .. } => {
+                    stats.base_images.insert(image_ref.to_owned());
+                    stats.downloads.insert(image_ref.to_owned());
+                }
+                BuildNode::FromScratch { .. } => {
+                    stats.base_images.insert("scratch".to_owned());
+                }
+                BuildNode::FromContext { name } => {
+                    stats.base_images.insert(format!(
+                        "{}(\"{}\")",
+                        modusfile::tokens::NAMED_CONTEXT,
+                        name
+                    ));
+                }
+                node @ (BuildNode::FromOciLayout { .. } | BuildNode::FromDockerTar { .. }) => {
+                    stats.base_images.extend(node.local_image());
+                }
+                _ => {}
+            }
+        }
+        stats.layers = self
+            .outputs
+            .iter()
+            .map(|o| {
+                let mut layers = 0;
+                let mut node = Some(o.node);
+                while let Some(n) = node {
+                    layers += self.nodes[n].adds_layer() as usize;
+                    node = self.nodes[n].parent();
+                }
+                OutputLayers {
+                    output: o
+                        .source_literal
+                        .as_ref()
+                        .map_or_else(|| format!("n_{}", o.node), |l| l.to_string()),
+                    layers,
+                }
+            })
+            .collect();
+        stats
+    }
+
+    /// Appends the nodes and outputs of `other` to this plan.
+    pub fn merge(&mut self, other: BuildPlan) {
+        let offset = self.nodes.len();
+        self.nodes.extend(
+            other
+                .nodes
+                .into_iter()
+                .map(|n| n.map_node_ids(|id| id + offset)),
+        );
+        self.dependencies.extend(
+            other
+                .dependencies
+                .into_iter()
+                .map(|deps| deps.into_iter().map(|d| d + offset).collect()),
+        );
+        self.sources.extend(other.sources);
+        self.platform = self.platform.take().or(other.platform);
+        self.tags.extend(
+            other
+                .tags
+                .into_iter()
+                .map(|(node, tag)| (node + offset, tag)),
+        );
+        self.assertions.extend(
+            other
+                .assertions
+                .into_iter()
+                .map(|(node, assertion)| (node + offset, assertion)),
+        );
+        self.outputs
+            .extend(other.outputs.into_iter().map(|o| Output {
+                node: o.node + offset,
+                ..o
+            }));
+    }
+
+    /// Checks that the plan is well-formed, e.g. after it was deserialized: each node only
+    /// depends on nodes before it, which include the ones it refers to, and each output is
+    /// a node of the plan. The other methods may panic or not terminate otherwise.
+    pub fn check_structure(&self) -> Result<(), String> {
+        if self.dependencies.len() != self.nodes.len() {
+            return Err(format!(
+                "The plan has {} nodes but dependencies for {}.",
+                self.nodes.len(),
+                self.dependencies.len()
+            ));
+        }
+        for (id, (node, deps)) in self.nodes.iter().zip(self.dependencies.iter()).enumerate() {
+            if let Some(&dep) = deps.iter().find(|&&d| d >= id) {
+                return Err(format!(
+                    "n_{} depends on n_{}, which is not before it.",
+                    id, dep
+                ));
+            }
+            let missing = std::cell::Cell::new(None);
+            node.clone().map_node_ids(|n| {
+                if !deps.contains(&n) {
+                    missing.set(Some(n));
+                }
+                n
+            });
+            if let Some(n) = missing.get() {
+                return Err(format!(
+                    "n_{} refers to n_{}, which is not one of its dependencies.",
+                    id, n
+                ));
+            }
+        }
+        if let Some(o) = self.outputs.iter().find(|o| o.node >= self.nodes.len()) {
+            return Err(format!(
+                "The output n_{} is not a node of the plan.",
+                o.node
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that no tag is given to two outputs, which are different images even if they
+    /// are built from the same tagged image, since the literal they were built for is
+    /// recorded in a label.
+    pub fn check_tags(&self) -> Result<(), PlanError> {
+        let mut tagged: HashMap<&str, &Output> = HashMap::new();
+        for output in self.outputs.iter() {
+            for tag in output.tags.iter() {
+                match tagged.get(tag.as_str()) {
+                    Some(other) if other.source_literal != output.source_literal => {
+                        let describe = |o: &Output| {
+                            o.source_literal
+                                .as_ref()
+                                .map_or_else(|| format!("n_{}", o.node), ToString::to_string)
+                        };
+                        return Err(PlanError::new(format!(
+                            "The tag {} is given to both {} and {}.",
+                            tag,
+                            describe(other),
+                            describe(output)
+                        )));
+                    }
+                    _ => {
+                        tagged.insert(tag, output);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Return an ordering of nodes in which dependencies of a node comes before
     /// the node itself.
     pub fn topological_order(&self) -> Vec<NodeId> {
@@ -80,32 +405,84 @@ impl BuildPlan {
             seen[node] = true;
         }
         for output in self.outputs.iter() {
-            dfs(&self, output.node, &mut topological_order, &mut seen);
+            dfs(self, output.node, &mut topological_order, &mut seen);
         }
         topological_order
     }
 }
 
+impl Default for BuildPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 struct State {
     current_node: Option<NodeId>,
     cwd: String,
     current_merge: Option<MergeNode>,
     additional_envs: HashMap<String, String>,
+    retry: Option<Retry>,
+    timeout: Option<Timeout>,
+    insecure: bool,
+    cache_mounts: Vec<String>,
+    secrets: Vec<String>,
 }
 
 impl State {
-    fn with_new_cwd<F: FnOnce(&mut Self)>(&mut self, new_cwd: String, f: F) {
+    fn with_new_cwd<T, F: FnOnce(&mut Self) -> T>(&mut self, new_cwd: String, f: F) -> T {
         let old_cwd = std::mem::replace(&mut self.cwd, new_cwd);
-        f(self);
+        let res = f(self);
         self.cwd = old_cwd;
+        res
+    }
+
+    fn with_retry<T, F: FnOnce(&mut Self) -> T>(&mut self, retry: Retry, f: F) -> T {
+        let old_retry = self.retry.replace(retry);
+        let res = f(self);
+        self.retry = old_retry;
+        res
+    }
+
+    fn with_timeout<T, F: FnOnce(&mut Self) -> T>(&mut self, timeout: Timeout, f: F) -> T {
+        let old_timeout = self.timeout.replace(timeout);
+        let res = f(self);
+        self.timeout = old_timeout;
+        res
+    }
+
+    fn with_insecure<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+        let old_insecure = std::mem::replace(&mut self.insecure, true);
+        let res = f(self);
+        self.insecure = old_insecure;
+        res
+    }
+
+    fn with_cache_mount<T, F: FnOnce(&mut Self) -> T>(&mut self, path: String, f: F) -> T {
+        self.cache_mounts.push(path);
+        let res = f(self);
+        self.cache_mounts.pop();
+        res
     }
 
-    fn with_new_merge<F: FnOnce(&mut Self)>(&mut self, new_merge: MergeNode, f: F) -> MergeNode {
+    fn with_secret<T, F: FnOnce(&mut Self) -> T>(&mut self, id: String, f: F) -> T {
+        self.secrets.push(id);
+        let res = f(self);
+        self.secrets.pop();
+        res
+    }
+
+    fn with_new_merge<F: FnOnce(&mut Self) -> Result<(), PlanError>>(
+        &mut self,
+        new_merge: MergeNode,
+        f: F,
+    ) -> Result<MergeNode, PlanError> {
         debug_assert!(self.current_merge.is_none());
         self.current_merge = Some(new_merge);
-        f(self);
-        self.current_merge.take().unwrap()
+        let res = f(self);
+        let merge = self.current_merge.take().unwrap();
+        res.map(|_| merge)
     }
 
     fn has_base(&self) -> bool {
@@ -117,15 +494,20 @@ impl State {
         self.current_node = Some(node);
     }
 
-    fn with_additional_envs<E: IntoIterator<Item = (String, String)>, F: FnOnce(&mut Self)>(
+    fn with_additional_envs<
+        T,
+        E: IntoIterator<Item = (String, String)>,
+        F: FnOnce(&mut Self) -> T,
+    >(
         &mut self,
         envs: E,
         f: F,
-    ) {
+    ) -> T {
         let old_envs = self.additional_envs.clone();
         self.additional_envs.extend(envs);
-        f(self);
+        let res = f(self);
         self.additional_envs = old_envs;
+        res
     }
 }
 
@@ -147,7 +529,7 @@ pub type NodeId = usize;
 /// respectively.
 ///
 /// TODO: add caching control
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BuildNode {
     From {
         /// The actual image reference to use. Probably a resolved hash.
@@ -159,11 +541,48 @@ pub enum BuildNode {
         /// A hack, inserted by buildkit.rs See buildkit_frontend.rs for documentation
         scratch_ref: Option<String>,
     },
+    /// A named build context, given to docker build with `--build-context name=...`.
+    /// It can be either a local directory or an image.
+    FromContext {
+        name: String,
+    },
+    /// An image in an OCI image layout directory, relative to the build context.
+    FromOciLayout {
+        path: String,
+        /// The image that the layout was loaded as, inserted by buildkit.rs.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image_ref: Option<String>,
+    },
+    /// An image saved with `docker save` to a tarball, relative to the build context.
+    FromDockerTar {
+        path: String,
+        /// The image that the tarball was loaded as, inserted by buildkit.rs.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image_ref: Option<String>,
+    },
     Run {
         parent: NodeId,
         command: String,
         cwd: String,
         additional_envs: HashMap<String, String>,
+        /// Set by the `::retry` operator.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+        /// Set by the `::timeout` operator.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<Timeout>,
+        /// Set by the `::insecure` operator, runs the command with BuildKit's
+        /// `security=insecure`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        insecure: bool,
+        /// The directories that are cache mounts while the command runs, set by the
+        /// `::cache_mount` operator.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        cache_mounts: Vec<String>,
+        /// The ids of the secrets that are mounted in `/run/secrets` while the command
+        /// runs, set by the `::secret` operator.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        secrets: Vec<String>,
     },
     CopyFromImage {
         parent: NodeId,
@@ -176,6 +595,14 @@ pub enum BuildNode {
         src_path: String,
         dst_path: String,
     },
+    /// `git_checkout(Url, Rev, Dest)`, the repository checked out at a revision, which
+    /// is a branch, a tag or a commit.
+    GitCheckout {
+        parent: NodeId,
+        url: String,
+        rev: String,
+        dst_path: String,
+    },
     SetWorkdir {
         parent: NodeId,
         new_workdir: String,
@@ -210,18 +637,303 @@ pub enum BuildNode {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BuildNode {
+    /// The name of the operation, e.g. `run`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildNode::From { .. } => "from",
+            BuildNode::FromScratch { .. } => "from_scratch",
+            BuildNode::FromContext { .. } => "from_context",
+            BuildNode::FromOciLayout { .. } => "from_oci_layout",
+            BuildNode::FromDockerTar { .. } => "from_docker_tar",
+            BuildNode::Run { .. } => "run",
+            BuildNode::CopyFromImage { .. } => "copy_from_image",
+            BuildNode::CopyFromLocal { .. } => "copy",
+            BuildNode::GitCheckout { .. } => "git_checkout",
+            BuildNode::SetWorkdir { .. } => "set_workdir",
+            BuildNode::SetEntrypoint { .. } => "set_entrypoint",
+            BuildNode::SetCmd { .. } => "set_cmd",
+            BuildNode::SetLabel { .. } => "set_label",
+            BuildNode::Merge(_) => "merge",
+            BuildNode::SetEnv { .. } => "set_env",
+            BuildNode::AppendEnvValue { .. } => "append_env_value",
+            BuildNode::SetUser { .. } => "set_user",
+        }
+    }
+
+    /// The image this node builds on, unless it starts a new one.
+    pub fn parent(&self) -> Option<NodeId> {
+        match self {
+            BuildNode::From { .. }
+            | BuildNode::FromScratch { .. }
+            | BuildNode::FromContext { .. }
+            | BuildNode::FromOciLayout { .. }
+            | BuildNode::FromDockerTar { .. } => None,
+            BuildNode::Run { parent, .. }
+            | BuildNode::CopyFromImage { parent, .. }
+            | BuildNode::CopyFromLocal { parent, .. }
+            | BuildNode::GitCheckout { parent, .. }
+            | BuildNode::SetWorkdir { parent, .. }
+            | BuildNode::SetEntrypoint { parent, .. }
+            | BuildNode::SetCmd { parent, .. }
+            | BuildNode::SetLabel { parent, .. }
+            | BuildNode::Merge(MergeNode { parent, .. })
+            | BuildNode::SetEnv { parent, .. }
+            | BuildNode::AppendEnvValue { parent, .. }
+            | BuildNode::SetUser { parent, .. } => Some(*parent),
+        }
+    }
+
+    /// The expression of a node that starts from an image in a local file, e.g.
+    /// `oci_layout("./base-oci")`.
+    pub fn local_image(&self) -> Option<String> {
+        let (keyword, path) = match self {
+            BuildNode::FromOciLayout { path, .. } => (modusfile::tokens::OCI_LAYOUT, path),
+            BuildNode::FromDockerTar { path, .. } => (modusfile::tokens::DOCKER_TAR, path),
+            _ => return None,
+        };
+        Some(format!("{}({:?})", keyword, path))
+    }
+
+    /// Whether this node adds a layer to its parent, rather than only changing its config.
+    pub fn adds_layer(&self) -> bool {
+        matches!(
+            self,
+            BuildNode::Run { .. }
+                | BuildNode::CopyFromImage { .. }
+                | BuildNode::CopyFromLocal { .. }
+                | BuildNode::GitCheckout { .. }
+                | BuildNode::Merge(_)
+        )
+    }
+
+    /// Replaces the IDs of the nodes this node refers to by their image under `f`.
+    pub fn map_node_ids<F: Fn(NodeId) -> NodeId>(self, f: F) -> BuildNode {
+        match self {
+            BuildNode::From { .. }
+            | BuildNode::FromScratch { .. }
+            | BuildNode::FromContext { .. }
+            | BuildNode::FromOciLayout { .. }
+            | BuildNode::FromDockerTar { .. } => self,
+            BuildNode::Run {
+                parent,
+                command,
+                cwd,
+                additional_envs,
+                retry,
+                timeout,
+                insecure,
+                cache_mounts,
+                secrets,
+            } => BuildNode::Run {
+                parent: f(parent),
+                command,
+                cwd,
+                additional_envs,
+                retry,
+                timeout,
+                insecure,
+                cache_mounts,
+                secrets,
+            },
+            BuildNode::CopyFromImage {
+                parent,
+                src_image,
+                src_path,
+                dst_path,
+            } => BuildNode::CopyFromImage {
+                parent: f(parent),
+                src_image: f(src_image),
+                src_path,
+                dst_path,
+            },
+            BuildNode::CopyFromLocal {
+                parent,
+                src_path,
+                dst_path,
+            } => BuildNode::CopyFromLocal {
+                parent: f(parent),
+                src_path,
+                dst_path,
+            },
+            BuildNode::GitCheckout {
+                parent,
+                url,
+                rev,
+                dst_path,
+            } => BuildNode::GitCheckout {
+                parent: f(parent),
+                url,
+                rev,
+                dst_path,
+            },
+            BuildNode::SetWorkdir {
+                parent,
+                new_workdir,
+            } => BuildNode::SetWorkdir {
+                parent: f(parent),
+                new_workdir,
+            },
+            BuildNode::SetEntrypoint {
+                parent,
+                new_entrypoint,
+            } => BuildNode::SetEntrypoint {
+                parent: f(parent),
+                new_entrypoint,
+            },
+            BuildNode::SetCmd { parent, new_cmd } => BuildNode::SetCmd {
+                parent: f(parent),
+                new_cmd,
+            },
+            BuildNode::SetLabel {
+                parent,
+                label,
+                value,
+            } => BuildNode::SetLabel {
+                parent: f(parent),
+                label,
+                value,
+            },
+            BuildNode::Merge(MergeNode { parent, operations }) => BuildNode::Merge(MergeNode {
+                parent: f(parent),
+                operations: operations
+                    .into_iter()
+                    .map(|op| match op {
+                        MergeOperation::CopyFromImage {
+                            src_image,
+                            src_path,
+                            dst_path,
+                        } => MergeOperation::CopyFromImage {
+                            src_image: f(src_image),
+                            src_path,
+                            dst_path,
+                        },
+                        op => op,
+                    })
+                    .collect(),
+            }),
+            BuildNode::SetEnv { parent, key, value } => BuildNode::SetEnv {
+                parent: f(parent),
+                key,
+                value,
+            },
+            BuildNode::AppendEnvValue { parent, key, value } => BuildNode::AppendEnvValue {
+                parent: f(parent),
+                key,
+                value,
+            },
+            BuildNode::SetUser { parent, user } => BuildNode::SetUser {
+                parent: f(parent),
+                user,
+            },
+        }
+    }
+}
+
+/// How a command is retried if it fails, set with `::retry(Retries)` or
+/// `::retry(Retries, DelaySeconds)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay_secs: u32,
+}
+
+impl Retry {
+    /// Wraps the command in a shell script that runs it until it succeeds, at most
+    /// `retries` more times, and otherwise exits with its last exit code.
+    pub fn wrap_command(&self, command: &str) -> String {
+        let sleep = if self.delay_secs > 0 {
+            format!(" sleep {};", self.delay_secs)
+        } else {
+            String::new()
+        };
+        format!(
+            "n=0; until sh -c '{}'; do s=$?; if [ $n -ge {} ]; then exit $s; fi; n=$((n+1)); \
+             echo \"Exited with code $s, retrying ($n/{})\" >&2;{} done",
+            command.replace('\'', "'\\''"),
+            self.retries,
+            self.retries,
+            sleep
+        )
+    }
+}
+
+/// The time a command may take, set with `::timeout(Seconds)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeout {
+    pub secs: u32,
+    /// The head of the rule that ran the command, to report where a command timed out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl Timeout {
+    /// Wraps the command in a shell script that runs it in the background and kills it
+    /// once it has run for `secs` seconds, along with the processes it started, which are
+    /// found through `/proc`. The script exits with the command's exit code, which is that
+    /// of `SIGTERM` if it was killed. Only `sleep` and `kill` are needed, which also exist
+    /// in minimal images, unlike `timeout`.
+    pub fn wrap_command(&self, command: &str) -> String {
+        let message = match &self.source {
+            Some(source) => format!(
+                "modus: run({:?}) in {} timed out after {} seconds",
+                command, source, self.secs
+            ),
+            None => format!(
+                "modus: run({:?}) timed out after {} seconds",
+                command, self.secs
+            ),
+        };
+        let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+        format!(
+            "k() {{ kill -STOP $1 2>/dev/null; \
+             for c in $(cat /proc/$1/task/$1/children 2>/dev/null); do k $c; done; \
+             kill $1 2>/dev/null; kill -CONT $1 2>/dev/null; }}; \
+             sh -c {} & p=$!; (i=0; while [ $i -lt {} ]; do sleep 1; \
+             kill -0 $p 2>/dev/null || exit 0; i=$((i+1)); done; \
+             echo {} >&2; k $p) & w=$!; wait $p; s=$?; kill $w 2>/dev/null; (exit $s)",
+            quote(command),
+            self.secs,
+            quote(&message)
+        )
+    }
+}
+
+/// The shell script that runs a command with the given `::retry` and `::timeout`
+/// settings. The timeout applies to each attempt.
+pub fn wrap_command(command: &str, retry: Option<Retry>, timeout: Option<&Timeout>) -> String {
+    let command = match timeout {
+        Some(timeout) => timeout.wrap_command(command),
+        None => command.to_owned(),
+    };
+    match retry {
+        Some(retry) => retry.wrap_command(&command),
+        None => command,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MergeNode {
     pub parent: NodeId,
     pub operations: Vec<MergeOperation>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MergeOperation {
     Run {
         command: String,
         cwd: String,
         additional_envs: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<Timeout>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        insecure: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        cache_mounts: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        secrets: Vec<String>,
     },
     CopyFromImage {
         src_image: NodeId,
@@ -232,6 +944,59 @@ pub enum MergeOperation {
         src_path: String,
         dst_path: String,
     },
+    GitCheckout {
+        url: String,
+        rev: String,
+        dst_path: String,
+    },
+}
+
+/// A summary of a build plan, see [`BuildPlan::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PlanStats {
+    /// The number of nodes of each operation, see [`BuildNode::name`].
+    pub nodes: BTreeMap<&'static str, usize>,
+    /// The images that are built from, including `scratch` and named contexts.
+    pub base_images: BTreeSet<String>,
+    /// The estimated number of layers that are added to the base image of each output.
+    pub layers: Vec<OutputLayers>,
+    /// The images that are pulled from a registry.
+    pub downloads: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutputLayers {
+    pub output: String,
+    pub layers: usize,
+}
+
+impl fmt::Display for PlanStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nodes = self.nodes.values().sum::<usize>();
+        writeln!(
+            f,
+            "{} nodes: {}",
+            nodes,
+            self.nodes
+                .iter()
+                .map(|(name, n)| format!("{} {}", n, name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        writeln!(f, "{} distinct base images:", self.base_images.len())?;
+        for image in self.base_images.iter() {
+            writeln!(f, "  {}", image)?;
+        }
+        writeln!(f, "{} external downloads:", self.downloads.len())?;
+        for image in self.downloads.iter() {
+            writeln!(f, "  {}", image)?;
+        }
+        writeln!(f, "Estimated layers per output, on top of the base image:")?;
+        for o in self.layers.iter() {
+            writeln!(f, "  {}: {}", o.output, o.layers)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -239,6 +1004,87 @@ pub struct Output {
     pub node: NodeId,
     #[serde(skip)]
     pub source_literal: Option<Literal>,
+    /// Extra metadata to attach to the exported image, such as provenance
+    /// information. Empty unless filled in by the caller after planning.
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// The names given to the image with the `::tag` operator, e.g. `registry/app:1.0`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// A check of a built image, which `modus test` and `modus build --verify` run in a
+/// container started from it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Assertion {
+    /// `::assert_file(Path)`, the path exists in the image. A relative path is relative
+    /// to the working directory of the image.
+    File(String),
+    /// `::assert_cmd_succeeds(Command)`, the command exits with status 0 when run with
+    /// `sh -c` in the image.
+    CommandSucceeds(String),
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Assertion::File(path) => write!(f, "assert_file({:?})", path),
+            Assertion::CommandSucceeds(command) => write!(f, "assert_cmd_succeeds({:?})", command),
+        }
+    }
+}
+
+/// A proof that can not be turned into build instructions, e.g. because it runs a
+/// command before `from`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{message}")]
+pub struct PlanError {
+    pub message: String,
+    /// The position of the literal whose instruction could not be generated, if known.
+    pub position: Option<SpannedPosition>,
+}
+
+impl PlanError {
+    fn new(message: impl Into<String>) -> Self {
+        PlanError {
+            message: message.into(),
+            position: None,
+        }
+    }
+
+    /// Attributes the error to the literal, unless it was attributed to one inside it.
+    fn at(self, lit: &Literal) -> Self {
+        PlanError {
+            position: self.position.or_else(|| lit.position.clone()),
+            ..self
+        }
+    }
+}
+
+/// The argument of an intrinsic or operator, which must be a string once solved.
+fn constant_arg(lit: &Literal, index: usize) -> Result<&str, PlanError> {
+    lit.args
+        .get(index)
+        .and_then(|arg| arg.as_constant())
+        .ok_or_else(|| PlanError::new(format!("Expected the arguments of {} to be strings.", lit)))
+}
+
+/// The argument of an operator that is either a string or a list of strings.
+fn string_list_arg(lit: &Literal, index: usize) -> Result<Vec<String>, PlanError> {
+    match lit.args.get(index) {
+        Some(IRTerm::List(ts)) => ts
+            .iter()
+            .map(|t| t.as_constant().map(ToOwned::to_owned))
+            .collect::<Option<Vec<_>>>(),
+        Some(IRTerm::Constant(c)) => Some(vec![c.to_string()]),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        PlanError::new(format!(
+            "Expected the arguments of {} to be strings or lists of strings.",
+            lit
+        ))
+    })
 }
 
 /// Given a list of pairs of ground (solved) queries and their proof tree, output
@@ -246,7 +1092,7 @@ pub struct Output {
 pub fn build_dag_from_proofs(
     query_and_proofs: &[(Literal, Proof)],
     rules: &Vec<Clause<IRTerm>>,
-) -> BuildPlan {
+) -> Result<BuildPlan, PlanError> {
     let mut res = BuildPlan::new();
     let mut image_literals: HashMap<Literal, NodeId> = HashMap::new();
 
@@ -265,12 +1111,17 @@ pub fn build_dag_from_proofs(
         res: &mut BuildPlan,
         image_literals: &mut HashMap<Literal, NodeId>,
         tag_with_literal: Option<String>,
-    ) -> Option<NodeId> {
+    ) -> Result<Option<NodeId>, PlanError> {
         let mut curr_state = State {
             current_node: None,
             cwd: "".to_string(),
             current_merge: None,
             additional_envs: HashMap::new(),
+            retry: None,
+            timeout: None,
+            insecure: false,
+            cache_mounts: Vec::new(),
+            secrets: Vec::new(),
         };
 
         /* We go through the proof tree in depth-first order, since this is
@@ -290,6 +1141,9 @@ pub fn build_dag_from_proofs(
          * possible. In the case where we haven't, we can also store the node we
          * built from going into that literal into the image_literals store, to
          * be re-used later.
+         *
+         * The nodes created while processing a rule are attributed to it,
+         * unless a rule deeper in the tree already produced them.
          */
         fn process_tree(
             proof: &Proof,
@@ -297,13 +1151,29 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), PlanError> {
+            let first_node = res.nodes.len();
+            process_clause(proof, rules, res, image_literals, curr_state)?;
+            if let ClauseId::Rule(rid) = proof.clause {
+                res.attribute_nodes(first_node, &rules[rid].head);
+            }
+            Ok(())
+        }
+
+        fn process_clause(
+            proof: &Proof,
+            rules: &Vec<Clause<IRTerm>>,
+            res: &mut BuildPlan,
+            image_literals: &mut HashMap<Literal, NodeId>,
+            curr_state: &mut State,
+        ) -> Result<(), PlanError> {
             match proof.clause {
                 ClauseId::Query => {}
                 ClauseId::Builtin(ref intrinsic) => {
-                    process_intrinsic(intrinsic, res, image_literals, curr_state);
+                    process_intrinsic(intrinsic, res, image_literals, curr_state)
+                        .map_err(|e| e.at(intrinsic))?;
                     debug_assert!(proof.children.is_empty()); // Intrinsics should not have children.
-                    return;
+                    return Ok(());
                 }
                 ClauseId::Rule(rid) => {
                     let substituted_lit = rules[rid].head.substitute(&proof.valuation);
@@ -316,7 +1186,7 @@ pub fn build_dag_from_proofs(
                         // Do the optimization mentioned above.
                         if let Some(&node_id) = image_literals.get(&substituted_lit) {
                             curr_state.set_node(node_id);
-                            return; // no need to recurse to children anymore.
+                            return Ok(()); // no need to recurse to children anymore.
                         } else {
                             if let Some(node_id) = process_image(
                                 &proof.children.iter().collect::<Vec<_>>()[..],
@@ -324,12 +1194,12 @@ pub fn build_dag_from_proofs(
                                 res,
                                 image_literals,
                                 Some(substituted_lit.to_string()),
-                            ) {
+                            )? {
                                 curr_state.set_node(node_id);
                                 image_literals.insert(substituted_lit, node_id);
-                                return; // no need to recurse to children anymore, since I just built the content of this literal.
+                                return Ok(()); // no need to recurse to children anymore, since I just built the content of this literal.
                             } else {
-                                return; // the literal doesn't do any docker thing, so we can safely skip it.
+                                return Ok(()); // the literal doesn't do any docker thing, so we can safely skip it.
                             }
                         }
                     } else {
@@ -339,6 +1209,7 @@ pub fn build_dag_from_proofs(
                     }
                 }
                 ClauseId::NegationCheck(_) => {}
+                ClauseId::Tabled(_) => unreachable!("proofs contain the proofs of tabled answers"),
             }
 
             process_children(
@@ -347,7 +1218,7 @@ pub fn build_dag_from_proofs(
                 res,
                 image_literals,
                 curr_state,
-            );
+            )
         }
 
         fn process_intrinsic(
@@ -355,69 +1226,113 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), PlanError> {
             let name = &intrinsic.predicate.0[..];
             assert!(!name.starts_with("_operator_")); // operators handled separately below.
             match name {
                 "from" => {
                     if curr_state.current_merge.is_some() {
-                        panic!("You can not generate a new image inside a merge.");
+                        return Err(PlanError::new(
+                            "You can not generate a new image inside a merge.".to_owned(),
+                        ));
                     }
                     if curr_state.has_base() {
-                        panic!("from must be the first build instruction.");
+                        return Err(PlanError::new(
+                            "from must be the first build instruction.".to_owned(),
+                        ));
                     }
                     // Special sharing for the "from" intrinsic.
-                    if let Some(&existing_node) = image_literals.get(&intrinsic) {
+                    if let Some(&existing_node) = image_literals.get(intrinsic) {
                         curr_state.set_node(existing_node);
                     } else {
-                        let image_ref = intrinsic.args[0].as_constant().unwrap().to_owned();
-                        let new_node;
-                        if &image_ref == "scratch" {
-                            new_node =
-                                res.new_node(BuildNode::FromScratch { scratch_ref: None }, vec![]);
+                        let image_ref = constant_arg(intrinsic, 0)?.to_owned();
+
+                        let new_node = if &image_ref == "scratch" {
+                            res.new_node(BuildNode::FromScratch { scratch_ref: None }, vec![])
+                        } else if let Some(name) =
+                            image_ref.strip_prefix(modusfile::tokens::NAMED_CONTEXT_PREFIX)
+                        {
+                            res.new_node(
+                                BuildNode::FromContext {
+                                    name: name.to_owned(),
+                                },
+                                vec![],
+                            )
+                        } else if let Some(path) =
+                            image_ref.strip_prefix(modusfile::tokens::OCI_LAYOUT_PREFIX)
+                        {
+                            res.new_node(
+                                BuildNode::FromOciLayout {
+                                    path: path.to_owned(),
+                                    image_ref: None,
+                                },
+                                vec![],
+                            )
+                        } else if let Some(path) =
+                            image_ref.strip_prefix(modusfile::tokens::DOCKER_TAR_PREFIX)
+                        {
+                            res.new_node(
+                                BuildNode::FromDockerTar {
+                                    path: path.to_owned(),
+                                    image_ref: None,
+                                },
+                                vec![],
+                            )
                         } else {
-                            new_node = res.new_node(
+                            res.new_node(
                                 BuildNode::From {
                                     display_name: image_ref.clone(),
                                     image_ref,
                                 },
                                 vec![],
-                            );
-                        }
+                            )
+                        };
                         curr_state.set_node(new_node);
                         image_literals.insert(intrinsic.clone(), new_node);
                     }
                 }
                 "run" => {
-                    let command = intrinsic.args[0].as_constant().unwrap().to_owned();
+                    let command = constant_arg(intrinsic, 0)?.to_owned();
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
                         curr_merge.operations.push(MergeOperation::Run {
                             command,
                             cwd: curr_state.cwd.clone(),
                             additional_envs: curr_state.additional_envs.clone(),
+                            retry: curr_state.retry,
+                            timeout: curr_state.timeout.clone(),
+                            insecure: curr_state.insecure,
+                            cache_mounts: curr_state.cache_mounts.clone(),
+                            secrets: curr_state.secrets.clone(),
                         });
                     } else {
                         if !curr_state.has_base() {
-                            panic!("No base layer yet.");
+                            return Err(PlanError::new("No base layer yet.".to_owned()));
                         }
                         let parent = curr_state.current_node.unwrap();
                         curr_state.set_node(res.new_node(
                             BuildNode::Run {
-                                parent: parent,
-                                command: command,
+                                parent,
+                                command,
                                 cwd: curr_state.cwd.clone(),
                                 additional_envs: curr_state.additional_envs.clone(),
+                                retry: curr_state.retry,
+                                timeout: curr_state.timeout.clone(),
+                                insecure: curr_state.insecure,
+                                cache_mounts: curr_state.cache_mounts.clone(),
+                                secrets: curr_state.secrets.clone(),
                             },
                             vec![parent],
                         ));
                     }
                 }
                 "copy" => {
-                    let src_path = intrinsic.args[0].as_constant().unwrap().to_owned();
+                    let src_path = constant_arg(intrinsic, 0)?.to_owned();
                     if src_path.starts_with("/") {
-                        panic!("The source of a local copy can not be an absolute path.");
+                        return Err(PlanError::new(
+                            "The source of a local copy can not be an absolute path.".to_owned(),
+                        ));
                     }
-                    let dst_path = intrinsic.args[1].as_constant().unwrap();
+                    let dst_path = constant_arg(intrinsic, 1)?;
                     let dst_path = join_path(&curr_state.cwd, dst_path);
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
                         curr_merge
@@ -425,7 +1340,7 @@ pub fn build_dag_from_proofs(
                             .push(MergeOperation::CopyFromLocal { src_path, dst_path });
                     } else {
                         if !curr_state.has_base() {
-                            panic!("No base layer yet.");
+                            return Err(PlanError::new("No base layer yet.".to_owned()));
                         }
                         let parent = curr_state.current_node.unwrap();
                         curr_state.set_node(res.new_node(
@@ -438,10 +1353,36 @@ pub fn build_dag_from_proofs(
                         ));
                     }
                 }
+                "git_checkout" => {
+                    let url = constant_arg(intrinsic, 0)?.to_owned();
+                    let rev = constant_arg(intrinsic, 1)?.to_owned();
+                    let dst_path = join_path(&curr_state.cwd, constant_arg(intrinsic, 2)?);
+                    if let Some(ref mut curr_merge) = curr_state.current_merge {
+                        curr_merge.operations.push(MergeOperation::GitCheckout {
+                            url,
+                            rev,
+                            dst_path,
+                        });
+                    } else {
+                        let parent = curr_state
+                            .current_node
+                            .ok_or_else(|| PlanError::new("No base layer yet.".to_owned()))?;
+                        curr_state.set_node(res.new_node(
+                            BuildNode::GitCheckout {
+                                parent,
+                                url,
+                                rev,
+                                dst_path,
+                            },
+                            vec![parent],
+                        ));
+                    }
+                }
                 _ => {
                     // do nothing - there might be stuff like string_concat.
                 }
             }
+            Ok(())
         }
 
         fn process_operator(
@@ -452,14 +1393,18 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), PlanError> {
             match op_name {
                 // Image-to-image copy. (local copy is not an operator)
                 "copy" => {
-                    let src_image = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect("Stuff inside this copy does not build an image.");
-                    let src_path = lit.args[1].as_constant().unwrap().to_owned();
-                    let dst_path = join_path(&curr_state.cwd, lit.args[2].as_constant().unwrap());
+                    let src_image = process_image(subtree_in_op, rules, res, image_literals, None)?
+                        .ok_or_else(|| {
+                            PlanError::new(
+                                "Stuff inside this copy does not build an image.".to_owned(),
+                            )
+                        })?;
+                    let src_path = constant_arg(lit, 1)?.to_owned();
+                    let dst_path = join_path(&curr_state.cwd, constant_arg(lit, 2)?);
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
                         curr_merge.operations.push(MergeOperation::CopyFromImage {
                             src_image,
@@ -467,7 +1412,9 @@ pub fn build_dag_from_proofs(
                             dst_path,
                         });
                     } else {
-                        let parent = curr_state.current_node.expect("No base layer yet.");
+                        let parent = curr_state
+                            .current_node
+                            .ok_or_else(|| PlanError::new("No base layer yet.".to_owned()))?;
                         let node = res.new_node(
                             BuildNode::CopyFromImage {
                                 parent,
@@ -481,31 +1428,43 @@ pub fn build_dag_from_proofs(
                     }
                 }
                 "in_workdir" => {
-                    let new_p = lit.args[1].as_constant().unwrap();
+                    let new_p = constant_arg(lit, 1)?;
                     let new_cwd = join_path(&curr_state.cwd, new_p);
                     curr_state.with_new_cwd(new_cwd, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
                     // TODO: emit a warning if the tree inside attempts
                     // to build a fresh image - this is probably an incorrect usage.
                 }
-                "set_workdir" | "set_entrypoint" | "set_cmd" | "set_env" | "append_path"
-                | "set_label" | "set_user" => {
+                "set_workdir"
+                | "set_entrypoint"
+                | "set_cmd"
+                | "set_env"
+                | "append_path"
+                | "set_label"
+                | "set_user"
+                | "tag"
+                | "assert_file"
+                | "assert_cmd_succeeds" => {
                     if curr_state.current_merge.is_some() {
-                        panic!("You can not generate a new image inside a merge.");
+                        return Err(PlanError::new(
+                            "You can not generate a new image inside a merge.".to_owned(),
+                        ));
                     }
-                    let img = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect(&format!("{} should be applied to an image.", op_name));
+                    let img = process_image(subtree_in_op, rules, res, image_literals, None)?
+                        .ok_or_else(|| {
+                            PlanError::new(format!("{} should be applied to an image.", op_name))
+                        })?;
                     if curr_state.has_base() {
-                        panic!(
+                        return Err(PlanError::new(format!(
                             "{} generates a new image, so it should be the first instruction.",
                             op_name
-                        );
+                        )));
                     }
 
                     match op_name {
                         "set_workdir" => {
-                            let new_p = lit.args[1].as_constant().unwrap();
+                            let new_p = constant_arg(lit, 1)?;
                             curr_state.set_node(res.new_node(
                                 BuildNode::SetWorkdir {
                                     parent: img,
@@ -515,15 +1474,7 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "set_entrypoint" => {
-                            let arg = &lit.args[1];
-                            let entrypoint = match arg {
-                                IRTerm::Constant(c) => vec![c.to_owned()],
-                                IRTerm::List(ts) => ts
-                                    .iter()
-                                    .map(|t| t.as_constant().unwrap().to_owned())
-                                    .collect(),
-                                _ => unreachable!(),
-                            };
+                            let entrypoint = string_list_arg(lit, 1)?;
                             curr_state.set_node(res.new_node(
                                 BuildNode::SetEntrypoint {
                                     parent: img,
@@ -533,15 +1484,7 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "set_cmd" => {
-                            let arg = &lit.args[1];
-                            let cmd = match arg {
-                                IRTerm::List(ts) => ts
-                                    .iter()
-                                    .map(|t| t.as_constant().unwrap().to_owned())
-                                    .collect::<Vec<_>>(),
-                                IRTerm::Constant(c) => vec![c.to_owned()],
-                                _ => unreachable!(),
-                            };
+                            let cmd = string_list_arg(lit, 1)?;
                             curr_state.set_node(res.new_node(
                                 BuildNode::SetCmd {
                                     parent: img,
@@ -551,8 +1494,8 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "set_env" => {
-                            let env_k = lit.args[1].as_constant().unwrap().to_owned();
-                            let env_v = lit.args[2].as_constant().unwrap().to_owned();
+                            let env_k = constant_arg(lit, 1)?.to_owned();
+                            let env_v = constant_arg(lit, 2)?.to_owned();
                             curr_state.set_node(res.new_node(
                                 BuildNode::SetEnv {
                                     parent: img,
@@ -563,7 +1506,7 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "append_path" => {
-                            let append = format!(":{}", lit.args[1].as_constant().unwrap());
+                            let append = format!(":{}", constant_arg(lit, 1)?);
                             curr_state.set_node(res.new_node(
                                 BuildNode::AppendEnvValue {
                                     parent: img,
@@ -574,8 +1517,8 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "set_label" => {
-                            let label_k = lit.args[1].as_constant().unwrap().to_owned();
-                            let label_v = lit.args[2].as_constant().unwrap().to_owned();
+                            let label_k = constant_arg(lit, 1)?.to_owned();
+                            let label_v = constant_arg(lit, 2)?.to_owned();
                             curr_state.set_node(res.new_node(
                                 BuildNode::SetLabel {
                                     parent: img,
@@ -586,21 +1529,63 @@ pub fn build_dag_from_proofs(
                             ));
                         }
                         "set_user" => {
-                            let user = lit.args[1].as_constant().unwrap().to_owned();
+                            let user = constant_arg(lit, 1)?.to_owned();
                             curr_state.set_node(
                                 res.new_node(BuildNode::SetUser { parent: img, user }, vec![img]),
                             );
                         }
+                        "tag" => {
+                            let tag = constant_arg(lit, 1)?;
+                            if tag.is_empty() || tag.contains(char::is_whitespace) {
+                                return Err(PlanError::new(format!(
+                                    "{:?} is not a valid image tag.",
+                                    tag
+                                )));
+                            }
+                            if res.tags.iter().any(|(node, t)| t == tag && *node != img) {
+                                return Err(PlanError::new(format!(
+                                    "The tag {} is already given to another image.",
+                                    tag
+                                )));
+                            }
+                            // Recorded again for each output, see `output_tags`.
+                            res.tags.push((img, tag.to_owned()));
+                            // Tagging does not change the image.
+                            curr_state.set_node(img);
+                        }
+                        "assert_file" | "assert_cmd_succeeds" => {
+                            let arg = constant_arg(lit, 1)?;
+                            if arg.trim().is_empty() {
+                                return Err(PlanError::new(format!(
+                                    "{} expects a non-empty argument.",
+                                    op_name
+                                )));
+                            }
+                            let assertion = match op_name {
+                                "assert_file" => Assertion::File(arg.to_owned()),
+                                _ => Assertion::CommandSucceeds(arg.to_owned()),
+                            };
+                            res.assertions.push((img, assertion));
+                            // Like tagging, an assertion does not change the image.
+                            curr_state.set_node(img);
+                        }
                         _ => unreachable!(),
                     }
                 }
                 "merge" => {
                     if curr_state.current_merge.is_some() {
-                        process_children(subtree_in_op, rules, res, image_literals, curr_state);
-                        return;
+                        return process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            curr_state,
+                        );
                     }
                     if !curr_state.has_base() {
-                        panic!("merge requires a base layer outside.");
+                        return Err(PlanError::new(
+                            "merge requires a base layer outside.".to_owned(),
+                        ));
                     }
                     let parent = curr_state.current_node.unwrap();
                     let merge_node = MergeNode {
@@ -608,33 +1593,96 @@ pub fn build_dag_from_proofs(
                         operations: vec![],
                     };
                     let merge_node = curr_state.with_new_merge(merge_node, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
                     let mut deps: Vec<NodeId> = merge_node
                         .operations
                         .iter()
                         .filter_map(|x| match x {
                             MergeOperation::CopyFromImage { src_image, .. } => Some(*src_image),
                             // Explicitly list out all the no-dependency cases to prevent future errors.
-                            MergeOperation::CopyFromLocal { .. } | MergeOperation::Run { .. } => {
-                                None
-                            }
+                            MergeOperation::CopyFromLocal { .. }
+                            | MergeOperation::Run { .. }
+                            | MergeOperation::GitCheckout { .. } => None,
                         })
                         .collect();
                     deps.push(parent);
                     curr_state.set_node(res.new_node(BuildNode::Merge(merge_node), deps));
                 }
+                "retry" => {
+                    let number = |arg: &IRTerm| {
+                        arg.as_constant()
+                            .and_then(|c| c.parse::<u32>().ok())
+                            .ok_or_else(|| {
+                                PlanError::new(
+                                    "The arguments of retry must be non-negative integers."
+                                        .to_owned(),
+                                )
+                            })
+                    };
+                    let retry = Retry {
+                        retries: number(&lit.args[1])?,
+                        delay_secs: lit.args.get(2).map_or(Ok(0), number)?,
+                    };
+                    curr_state.with_retry(retry, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "timeout" => {
+                    let timeout = Timeout {
+                        secs: lit.args[1]
+                            .as_constant()
+                            .and_then(|c| c.parse::<u32>().ok())
+                            .filter(|&secs| secs > 0)
+                            .ok_or_else(|| {
+                                PlanError::new(
+                                    "The argument of timeout must be a positive integer."
+                                        .to_owned(),
+                                )
+                            })?,
+                        source: None,
+                    };
+                    curr_state.with_timeout(timeout, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "cache_mount" => {
+                    let path = join_path(&curr_state.cwd, constant_arg(lit, 1)?);
+                    curr_state.with_cache_mount(path, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "secret" => {
+                    let id = constant_arg(lit, 1)?;
+                    if id.is_empty()
+                        || id.contains(|c: char| c == ',' || c == '/' || c.is_whitespace())
+                    {
+                        return Err(PlanError::new(format!(
+                            "{:?} is not a valid secret id.",
+                            id
+                        )));
+                    }
+                    curr_state.with_secret(id.to_owned(), |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "insecure" => {
+                    curr_state.with_insecure(|new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
                 "in_env" => {
-                    let env_k = lit.args[1].as_constant().unwrap().to_owned();
-                    let env_v = lit.args[2].as_constant().unwrap().to_owned();
+                    let env_k = constant_arg(lit, 1)?.to_owned();
+                    let env_v = constant_arg(lit, 2)?.to_owned();
                     curr_state.with_additional_envs([(env_k, env_v)], |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
                 }
                 _ => {
-                    panic!("Unkown operator: {}", op_name);
+                    return Err(PlanError::new(format!("Unknown operator: {}", op_name)));
                 }
             }
+            Ok(())
         }
 
         fn process_children(
@@ -643,7 +1691,7 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), PlanError> {
             let mut i = 0usize;
             while i < children.len() {
                 let child = children[i];
@@ -656,11 +1704,11 @@ pub fn build_dag_from_proofs(
                         // due to the way things work, the end predicate for this is
                         // guarenteed to be in the same level.
                         let end_name = format!("_operator_{}_end", op_name);
-                        let pair_id = lit.args[0].as_constant().unwrap();
+                        let pair_id = constant_arg(lit, 0)?;
                         let mut j = i + 1;
                         while !{
                             if let ClauseId::Builtin(ref lit) = children[j].clause {
-                                lit.predicate.0 == end_name
+                                lit.predicate.0 == end_name.as_str()
                                     && lit.args[0].as_constant() == Some(pair_id)
                             } else {
                                 false
@@ -679,66 +1727,133 @@ pub fn build_dag_from_proofs(
                             res,
                             image_literals,
                             curr_state,
-                        );
+                        )
+                        .map_err(|e| e.at(lit))?;
                         i = j + 1;
                         continue;
                     }
                 }
-                process_tree(child, rules, res, image_literals, curr_state);
+                process_tree(child, rules, res, image_literals, curr_state)?;
                 i += 1;
             }
+            Ok(())
         }
 
-        process_children(subtree, rules, res, image_literals, &mut curr_state);
+        process_children(subtree, rules, res, image_literals, &mut curr_state)?;
 
         debug_assert!(curr_state.current_merge.is_none());
 
-        if curr_state.current_node.is_some() && tag_with_literal.is_some() {
-            let node = curr_state.current_node.unwrap();
+        if let (Some(node), Some(tag_with_literal)) = (curr_state.current_node, tag_with_literal) {
             let tagged_node = res.new_node(
                 BuildNode::SetLabel {
                     parent: node,
                     label: MODUS_LABEL.to_owned(),
-                    value: tag_with_literal.unwrap().to_owned(),
+                    value: tag_with_literal.to_owned(),
                 },
                 vec![node],
             );
             curr_state.set_node(tagged_node);
         }
-        curr_state.current_node
+        Ok(curr_state.current_node)
     }
 
-    for (query, proof) in query_and_proofs.into_iter() {
+    for (query, proof) in query_and_proofs.iter() {
         debug_assert!(query
             .args
             .iter()
             .all(|x| x.is_constant_or_compound_constant()));
-        if let Some(&existing_node_id) = image_literals.get(&query) {
+        if let Some(&existing_node_id) = image_literals.get(query) {
             // TODO: unreachable?
             res.outputs.push(Output {
                 node: existing_node_id,
                 source_literal: Some(query.clone()),
+                annotations: BTreeMap::new(),
+                tags: Vec::new(),
             });
             continue;
         }
+        let first_tag = res.tags.len();
         if let Some(node_id) = process_image(
             &[proof],
             rules,
             &mut res,
             &mut image_literals,
             Some(query.to_string()),
-        ) {
+        )? {
             image_literals.insert(query.clone(), node_id);
+            let tags = output_tags(&res, &res.tags[first_tag..], node_id)?;
             res.outputs.push(Output {
                 node: node_id,
                 source_literal: Some(query.clone()),
+                annotations: BTreeMap::new(),
+                tags,
             });
         } else {
-            panic!("{} does not resolve to any docker instructions.", query);
+            return Err(PlanError::new(format!(
+                "{} does not resolve to any docker instructions.",
+                query
+            ))
+            .at(query));
+        }
+    }
+
+    res.check_tags()?;
+    res.tags.sort_unstable();
+    res.tags.dedup();
+    res.assertions.sort_unstable();
+    res.assertions.dedup();
+
+    // Now that the nodes are attributed to rules, record where each timeout was set.
+    for (node, source) in res.nodes.iter_mut().zip(res.sources.iter()) {
+        let timeouts = match node {
+            BuildNode::Run { timeout, .. } => vec![timeout],
+            BuildNode::Merge(merge) => merge
+                .operations
+                .iter_mut()
+                .filter_map(|op| match op {
+                    MergeOperation::Run { timeout, .. } => Some(timeout),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        for timeout in timeouts.into_iter().flatten() {
+            timeout.source = source.as_ref().map(ToString::to_string);
         }
     }
 
-    res
+    Ok(res)
+}
+
+/// The tags of the output `node`, out of the `tags` given while building it. The image
+/// of an output is tagged before the labels recording the literals that were built are
+/// set, and the images that the output is built from can not be tagged, since they are
+/// not built on their own.
+fn output_tags(
+    plan: &BuildPlan,
+    tags: &[(NodeId, String)],
+    node: NodeId,
+) -> Result<Vec<String>, PlanError> {
+    let mut image = node;
+    while let BuildNode::SetLabel { parent, label, .. } = &plan.nodes[image] {
+        if label != MODUS_LABEL || tags.iter().any(|(n, _)| *n == image) {
+            break;
+        }
+        image = *parent;
+    }
+    let mut output_tags = Vec::new();
+    for (n, tag) in tags {
+        if *n != image {
+            return Err(PlanError::new(format!(
+                "The image tagged {} is not an output of the query, so it is not built on its own.",
+                tag
+            )));
+        }
+        if !output_tags.contains(tag) {
+            output_tags.push(tag.clone());
+        }
+    }
+    Ok(output_tags)
 }
 
 fn join_path(base: &str, path: &str) -> String {
@@ -748,10 +1863,57 @@ fn join_path(base: &str, path: &str) -> String {
     }
 }
 
+/// Values for the standard `org.opencontainers.image.*` labels, usually derived
+/// from the git metadata of the build context.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OciLabels {
+    /// URL to the source code, e.g. the git remote.
+    pub source: Option<String>,
+    /// Source control revision, e.g. the commit hash.
+    pub revision: Option<String>,
+    /// Date and time the image was built, as an RFC 3339 string.
+    pub created: Option<String>,
+}
+
+impl OciLabels {
+    fn labels(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("org.opencontainers.image.source", &self.source),
+            ("org.opencontainers.image.revision", &self.revision),
+            ("org.opencontainers.image.created", &self.created),
+        ]
+        .iter()
+        .filter_map(|(k, v)| v.as_deref().map(|v| (*k, v)))
+        .collect()
+    }
+}
+
+/// Adds the given OCI labels to every output image of the plan. This is opt-in,
+/// so that plans stay reproducible unless the user asks for it.
+pub fn add_oci_labels(plan: &mut BuildPlan, oci_labels: &OciLabels) {
+    let labels = oci_labels.labels();
+    for i in 0..plan.outputs.len() {
+        let mut node = plan.outputs[i].node;
+        for (label, value) in labels.iter() {
+            node = plan.new_node(
+                BuildNode::SetLabel {
+                    parent: node,
+                    label: label.to_string(),
+                    value: value.to_string(),
+                },
+                vec![node],
+            );
+        }
+        plan.outputs[i].node = node;
+    }
+}
+
 pub fn plan_from_modusfile(
     mf: Modusfile,
     query: modusfile::Expression,
-) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
+    strategy: sld::SearchStrategy,
+    profile: &mut sld::SolveProfile,
+) -> Result<BuildPlan, ModusError> {
     // 1. Adds a new clause based on the user's expression query to the Modusfile, `_query :- ...`.
     // 2. Translates the Modusfile to IR.
     // 3. Find proof for `_query`. We need to do this, and not just find proof of the image literal due to any
@@ -829,7 +1991,7 @@ pub fn plan_from_modusfile(
 
     let max_depth = 175;
 
-    let goal_pred = Predicate("_query".to_owned());
+    let goal_pred = Predicate("_query".into());
     let mut mf_with_query = mf.clone();
     mf_with_query.add_goal(query.clone());
     let ir_clauses: Vec<Clause> = translate_modusfile(&mf_with_query);
@@ -840,16 +2002,772 @@ pub fn plan_from_modusfile(
         .expect("should find same predicate name after translation");
     let query_goal = &q_clause.body;
 
-    let image_literal = get_image_literal(&query, &mf_with_query, q_clause)?;
+    let image_literal =
+        get_image_literal(&query, &mf_with_query, q_clause).map_err(ModusError::Wellformedness)?;
 
     // don't store full tree as this takes a lot of memory, and is probably not needed
     // when building/transpiling
-    let success_tree = Result::from(sld::sld(&ir_clauses, &query_goal, max_depth, false))?;
-    let proofs = sld::proofs(&success_tree, &ir_clauses, &query_goal);
+    let sld_result =
+        sld::sld_with_strategy(&ir_clauses, query_goal, max_depth, false, strategy, profile);
+    // Any proofs found before solving was cancelled may not be all of them.
+    if profile.cancellation.is_cancelled() {
+        return Err(ModusError::Cancelled);
+    }
+    let success_tree = Result::from(sld_result).map_err(|mut diags| {
+        diags.extend(profile.deepest_failures.iter().map(Failure::get_diagnostic));
+        ModusError::Solving(diags)
+    })?;
+    let proofs = sld::proofs(&success_tree, &ir_clauses, query_goal);
 
     let query_and_proofs = proofs
-        .into_iter()
-        .map(|(_, p)| (image_literal.substitute(&p.valuation), p))
+        .into_values()
+        .map(|p| (image_literal.substitute(&p.valuation), p))
         .collect::<Vec<_>>();
-    Ok(build_dag_from_proofs(&query_and_proofs[..], &ir_clauses))
+    let mut build_plan = build_dag_from_proofs(&query_and_proofs[..], &ir_clauses)?;
+    build_plan.platform = mf.target_platform().map(ToOwned::to_owned);
+    Ok(build_plan)
+}
+
+/// Plans several queries on a pool of threads sharing the parsed Modusfile, and
+/// merges the plans in the order of the queries.
+///
+/// The diagnostics of all the queries that failed are returned together, in the error
+/// of the first one.
+pub fn plan_from_queries(
+    mf: &Modusfile,
+    queries: &[modusfile::Expression],
+    strategy: sld::SearchStrategy,
+    profile: &mut sld::SolveProfile,
+) -> Result<BuildPlan, ModusError> {
+    if let [query] = queries {
+        return plan_from_modusfile(mf.clone(), query.clone(), strategy, profile);
+    }
+
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(queries.len());
+    let next_query = AtomicUsize::new(0);
+
+    let mut results = std::thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| {
+                std::thread::Builder::new()
+                    .stack_size(sld::SOLVE_THREAD_STACK_SIZE)
+                    .spawn_scoped(scope, || {
+                        let mut results = Vec::new();
+                        loop {
+                            let i = next_query.fetch_add(1, Ordering::SeqCst);
+                            let query = match queries.get(i) {
+                                Some(q) => q.clone(),
+                                None => break,
+                            };
+                            let mut query_profile = profile.fork();
+                            let res = plan_from_modusfile(
+                                mf.clone(),
+                                query,
+                                strategy,
+                                &mut query_profile,
+                            );
+                            results.push((i, res, query_profile));
+                        }
+                        results
+                    })
+                    .expect("failed to spawn planning thread")
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("planning thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(i, _, _)| *i);
+
+    let mut plan = BuildPlan::new();
+    let mut error: Option<ModusError> = None;
+    for (_, res, query_profile) in results {
+        profile.merge(query_profile);
+        match res {
+            Ok(p) => plan.merge(p),
+            Err(e) => {
+                error = Some(match error {
+                    Some(error) => error.merge(e),
+                    None => e,
+                })
+            }
+        }
+    }
+    match error {
+        None => {
+            plan.check_tags()?;
+            Ok(plan)
+        }
+        Some(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oci_labels_added_to_outputs() {
+        let mut plan = BuildPlan::new();
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_string(),
+                display_name: "alpine".to_string(),
+            },
+            Vec::new(),
+        );
+        plan.outputs.push(Output {
+            node: from,
+            source_literal: None,
+            annotations: BTreeMap::new(),
+            tags: Vec::new(),
+        });
+
+        let oci_labels = OciLabels {
+            source: Some("https://example.com/repo.git".to_string()),
+            revision: Some("abc123".to_string()),
+            created: None,
+        };
+        add_oci_labels(&mut plan, &oci_labels);
+
+        assert_eq!(plan.nodes.len(), 3);
+        assert_eq!(plan.topological_order(), vec![0, 1, 2]);
+        match &plan.nodes[plan.outputs[0].node] {
+            BuildNode::SetLabel { label, value, .. } => {
+                assert_eq!(label, "org.opencontainers.image.revision");
+                assert_eq!(value, "abc123");
+            }
+            n => panic!("unexpected node {:?}", n),
+        }
+    }
+    #[test]
+    fn plan_queries_in_parallel() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"echo a\").\n\
+                             b :- from(\"alpine\")::set_workdir(\"/b\")."
+            .parse()
+            .unwrap();
+        let queries = ["a", "b"]
+            .iter()
+            .map(|q| q.parse::<modusfile::Expression>().unwrap())
+            .collect::<Vec<_>>();
+        let mut profile = sld::SolveProfile::default();
+        let plan = plan_from_queries(&mf, &queries, sld::SearchStrategy::DepthFirst, &mut profile)
+            .unwrap();
+
+        assert_eq!(plan.outputs.len(), 2);
+        let outputs = plan
+            .outputs
+            .iter()
+            .map(|o| o.source_literal.as_ref().unwrap().predicate.0.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec!["a", "b"]);
+        // Node IDs of the second plan are shifted past the first.
+        let (workdir, parent) = plan
+            .nodes
+            .iter()
+            .enumerate()
+            .find_map(|(i, n)| match n {
+                BuildNode::SetWorkdir { parent, .. } => Some((i, *parent)),
+                _ => None,
+            })
+            .unwrap();
+        assert!(matches!(plan.nodes[parent], BuildNode::From { .. }));
+        assert!(parent > plan.outputs[0].node);
+        assert_eq!(plan.dependencies[workdir], vec![parent]);
+        assert!(profile.stats.nodes_explored > 0);
+
+        // Only the nodes of `b` are reachable from its output.
+        let b = BuildPlan {
+            outputs: vec![plan.outputs[1].clone()],
+            ..plan.clone()
+        }
+        .without_unreachable_nodes();
+        assert!(b.nodes.len() < plan.nodes.len());
+        assert_eq!(b.nodes.len(), b.sources.len());
+        assert_eq!(
+            b.topological_order(),
+            (0..b.nodes.len()).collect::<Vec<_>>()
+        );
+        assert!(b.nodes.iter().all(|n| !matches!(n, BuildNode::Run { .. })));
+        assert_eq!(
+            b.outputs[0]
+                .source_literal
+                .as_ref()
+                .unwrap()
+                .predicate
+                .0
+                .to_string(),
+            "b"
+        );
+
+        let bad = vec!["a".parse().unwrap(), "c".parse().unwrap()];
+        assert!(
+            plan_from_queries(&mf, &bad, sld::SearchStrategy::DepthFirst, &mut profile).is_err()
+        );
+    }
+
+    #[test]
+    fn plan_stats() {
+        let mf: Modusfile = "base :- from(\"alpine:3.15\"), run(\"apk add make\").\n\
+                             app :- base, copy(\".\", \"/src\"), (run(\"make\"), run(\"make install\"))::merge.\n\
+                             tools :- from(context(\"tools\"))::set_workdir(\"/opt\")."
+            .parse()
+            .unwrap();
+        let queries = ["app", "tools"]
+            .iter()
+            .map(|q| q.parse::<modusfile::Expression>().unwrap())
+            .collect::<Vec<_>>();
+        let plan = plan_from_queries(
+            &mf,
+            &queries,
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+
+        let stats = plan.stats();
+        assert_eq!(stats.nodes["from"], 1);
+        assert_eq!(stats.nodes["from_context"], 1);
+        assert_eq!(stats.nodes["merge"], 1);
+        assert_eq!(stats.nodes.values().sum::<usize>(), plan.nodes.len());
+        assert_eq!(
+            stats.base_images.iter().collect::<Vec<_>>(),
+            vec!["alpine:3.15", "context(\"tools\")"]
+        );
+        assert_eq!(
+            stats.downloads.iter().collect::<Vec<_>>(),
+            vec!["alpine:3.15"]
+        );
+        assert_eq!(
+            stats.layers,
+            vec![
+                OutputLayers {
+                    output: "app".to_owned(),
+                    layers: 3
+                },
+                OutputLayers {
+                    output: "tools".to_owned(),
+                    layers: 0
+                },
+            ]
+        );
+        assert!(stats.to_string().contains("  app: 3\n"));
+    }
+
+    #[test]
+    fn retry_operator() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"make\")::retry(2), \
+                             (run(\"apk add curl\"), run(\"curl x\"))::merge::retry(1, 5), run(\"true\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        let retries = plan
+            .nodes
+            .iter()
+            .filter_map(|n| match n {
+                BuildNode::Run { command, retry, .. } => Some((command.as_str(), *retry)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            retries,
+            vec![
+                (
+                    "make",
+                    Some(Retry {
+                        retries: 2,
+                        delay_secs: 0
+                    })
+                ),
+                ("true", None)
+            ]
+        );
+        let merge = plan
+            .nodes
+            .iter()
+            .find_map(|n| match n {
+                BuildNode::Merge(merge) => Some(merge),
+                _ => None,
+            })
+            .unwrap();
+        assert!(merge.operations.iter().all(|op| matches!(
+            op,
+            MergeOperation::Run {
+                retry: Some(Retry {
+                    retries: 1,
+                    delay_secs: 5
+                }),
+                ..
+            }
+        )));
+
+        // Fails twice, then succeeds.
+        let dir = std::env::temp_dir().join(format!("modus-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let flaky = "echo x >> attempts; [ $(wc -l < attempts) -ge 3 ] && echo 'done'";
+        let run = |retries| {
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(
+                    Retry {
+                        retries,
+                        delay_secs: 0,
+                    }
+                    .wrap_command(flaky),
+                )
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+        };
+        let output = run(2);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+        std::fs::remove_file(dir.join("attempts")).unwrap();
+        assert_eq!(run(1).status.code(), Some(1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn insecure_operator() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"modprobe x\")::insecure, \
+                             (run(\"ls\"), run(\"make\")::insecure)::merge, run(\"true\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        let insecure_nodes = plan.insecure_nodes();
+        assert_eq!(insecure_nodes.len(), 2);
+        assert!(matches!(
+            &plan.nodes[insecure_nodes[0]],
+            BuildNode::Run { command, insecure: true, .. } if command == "modprobe x"
+        ));
+        match &plan.nodes[insecure_nodes[1]] {
+            BuildNode::Merge(merge) => assert_eq!(
+                merge
+                    .operations
+                    .iter()
+                    .map(|op| matches!(op, MergeOperation::Run { insecure: true, .. }))
+                    .collect::<Vec<_>>(),
+                vec![false, true]
+            ),
+            node => panic!("Expected a merge node, got {:?}", node),
+        }
+    }
+
+    #[test]
+    fn cancelled_planning() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"make\").".parse().unwrap();
+        let mut profile =
+            sld::SolveProfile::default().with_cancellation(sld::CancellationToken::new());
+        profile.cancellation.cancel();
+        assert!(matches!(
+            plan_from_queries(
+                &mf,
+                &["a".parse().unwrap(), "a".parse().unwrap()],
+                sld::SearchStrategy::DepthFirst,
+                &mut profile,
+            ),
+            Err(ModusError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn malformed_plans_are_errors() {
+        let plan = |mf: &str| {
+            plan_from_modusfile(
+                mf.parse().unwrap(),
+                "a".parse().unwrap(),
+                sld::SearchStrategy::DepthFirst,
+                &mut sld::SolveProfile::default(),
+            )
+        };
+        for mf in [
+            "a :- from(\"alpine\"), run(\"make\")::retry(\"x\").",
+            "a :- from(\"alpine\"), run(\"make\")::timeout(\"0\").",
+            "a :- from(\"alpine\")::set_entrypoint([\"sh\", [\"-c\"]]).",
+        ] {
+            let diags = match plan(mf) {
+                Err(ModusError::Imagegen(diags)) => diags,
+                res => panic!("Expected {} not to be planned, got {:?}", mf, res),
+            };
+            assert_eq!(diags.len(), 1);
+            assert_eq!(diags[0].code.as_deref(), Some("invalid-build-plan"));
+            assert_eq!(diags[0].labels.len(), 1, "Expected {} to be labelled", mf);
+        }
+    }
+
+    #[test]
+    fn plan_structure() {
+        let plan = plan_from_modusfile(
+            "a :- from(\"alpine\"), run(\"make\"), copy(\".\", \"/src\")."
+                .parse()
+                .unwrap(),
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        assert_eq!(plan.check_structure(), Ok(()));
+
+        let mut cyclic = plan.clone();
+        cyclic.dependencies[0].push(1);
+        assert!(cyclic.check_structure().is_err());
+        let mut missing = plan.clone();
+        missing.dependencies[1].clear();
+        assert!(missing.check_structure().is_err());
+        let mut output = plan.clone();
+        output.outputs[0].node = plan.nodes.len();
+        assert!(output.check_structure().is_err());
+        let mut truncated = plan;
+        truncated.dependencies.pop();
+        assert!(truncated.check_structure().is_err());
+    }
+
+    #[test]
+    fn tag_operator() {
+        let plan = |mf: &str, query: &str| {
+            plan_from_modusfile(
+                mf.parse().unwrap(),
+                query.parse().unwrap(),
+                sld::SearchStrategy::DepthFirst,
+                &mut sld::SolveProfile::default(),
+            )
+        };
+        let tagged = plan(
+            "base :- from(\"alpine\").\n\
+             app(V) :- (V = \"1\" ; V = \"2\"), \
+                 (base, run(f\"make V=${V}\"))::tag(f\"registry/app:${V}\")::tag(f\"app:latest-${V}\").",
+            "app(X)",
+        )
+        .unwrap();
+        let mut tags = tagged
+            .outputs
+            .iter()
+            .map(|o| o.tags.clone())
+            .collect::<Vec<_>>();
+        tags.sort();
+        assert_eq!(
+            tags,
+            vec![
+                vec!["registry/app:1", "app:latest-1"],
+                vec!["registry/app:2", "app:latest-2"]
+            ]
+        );
+        assert_eq!(tagged.with_shared_nodes().tags.len(), 4);
+
+        // Both solutions tag the same image.
+        let shared = plan(
+            "app(V) :- (V = \"1\" ; V = \"2\"), from(\"alpine\")::tag(f\"app:${V}\").",
+            "app(X)",
+        )
+        .unwrap();
+        let mut tags = shared
+            .outputs
+            .iter()
+            .map(|o| o.tags.clone())
+            .collect::<Vec<_>>();
+        tags.sort();
+        assert_eq!(tags, vec![vec!["app:1"], vec!["app:2"]]);
+
+        for mf in [
+            // The same tag is given to both solutions.
+            "app(V) :- (V = \"1\" ; V = \"2\"), from(\"alpine\")::tag(\"app\").",
+            // The tagged image is only a base for the output.
+            "app(V) :- V = \"1\", from(\"alpine\")::tag(\"base\"), run(\"make\").",
+            "app(V) :- V = \"1\", from(\"alpine\")::tag(\"not a tag\").",
+        ] {
+            match plan(mf, "app(X)") {
+                Err(ModusError::Imagegen(diags)) => assert_eq!(diags.len(), 1),
+                res => panic!("Expected {} not to be planned, got {:?}", mf, res),
+            }
+        }
+    }
+
+    #[test]
+    fn assertion_operators() {
+        let plan = |mf: &str| {
+            plan_from_modusfile(
+                mf.parse().unwrap(),
+                "app".parse().unwrap(),
+                sld::SearchStrategy::DepthFirst,
+                &mut sld::SolveProfile::default(),
+            )
+        };
+        let checked = plan(
+            "base :- from(\"alpine\")::assert_cmd_succeeds(\"apk --version\").\n\
+             app :- (base, run(\"make\"))::assert_file(\"/usr/bin/app\")\
+                 ::assert_cmd_succeeds(\"app --version\").",
+        )
+        .unwrap();
+        assert_eq!(checked.assertions.len(), 3);
+        let make = checked
+            .nodes
+            .iter()
+            .position(|n| matches!(n, BuildNode::Run { command, .. } if command == "make"))
+            .unwrap();
+        let mut on_app = checked
+            .assertions
+            .iter()
+            .filter(|(node, _)| *node == make)
+            .map(|(_, a)| a.to_string())
+            .collect::<Vec<_>>();
+        on_app.sort();
+        assert_eq!(
+            on_app,
+            vec![
+                "assert_cmd_succeeds(\"app --version\")",
+                "assert_file(\"/usr/bin/app\")"
+            ]
+        );
+        // The base image is checked on its own.
+        assert!(checked.assertions.iter().any(|(node, a)| matches!(
+            checked.nodes[*node],
+            BuildNode::From { .. }
+        ) && *a
+            == Assertion::CommandSucceeds("apk --version".to_owned())));
+        assert_eq!(checked.with_shared_nodes().assertions.len(), 3);
+
+        assert!(plan("app :- from(\"alpine\")::assert_file(\"\").").is_err());
+    }
+
+    #[test]
+    fn cache_mount_operator() {
+        let mf: Modusfile = "a :- from(\"rust\"), \
+                             run(\"cargo build\")::cache_mount(\"target\")::in_workdir(\"/src\")\
+                                 ::cache_mount(\"/usr/local/cargo/registry\"), \
+                             run(\"ls\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        let mounts = plan
+            .nodes
+            .iter()
+            .filter_map(|n| match n {
+                BuildNode::Run {
+                    command,
+                    cache_mounts,
+                    ..
+                } => Some((command.as_str(), cache_mounts.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mounts,
+            vec![
+                (
+                    "cargo build",
+                    vec![
+                        "/usr/local/cargo/registry".to_owned(),
+                        "/src/target".to_owned()
+                    ]
+                ),
+                ("ls", vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn secret_operator() {
+        let mf: Modusfile = "a :- from(\"python\"), \
+                             run(\"pip install -r requirements.txt\")::secret(\"pypi_token\"), \
+                             (run(\"make\"), run(\"make install\"))::secret(\"foo\")::merge."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        let secrets = plan.secret_nodes();
+        assert_eq!(
+            secrets.keys().copied().collect::<Vec<_>>(),
+            ["foo", "pypi_token"]
+        );
+        let pip = *secrets["pypi_token"].iter().next().unwrap();
+        assert!(
+            matches!(&plan.nodes[pip], BuildNode::Run { secrets, .. } if secrets == &["pypi_token"])
+        );
+        assert!(matches!(
+            &plan.nodes[*secrets["foo"].iter().next().unwrap()],
+            BuildNode::Merge(_)
+        ));
+
+        let mf: Modusfile = "a :- from(\"python\"), run(\"ls\")::secret(\"a,b\")."
+            .parse()
+            .unwrap();
+        assert!(plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn timeout_operator() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"make\")::timeout(60)::retry(1)."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "a".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        let timeout = Timeout {
+            secs: 60,
+            source: Some("a".to_owned()),
+        };
+        assert!(plan.nodes.iter().any(|n| matches!(
+            n,
+            BuildNode::Run { command, timeout: Some(t), retry: Some(_), .. }
+                if command == "make" && *t == timeout
+        )));
+
+        let run = |command: &str, secs| {
+            let timeout = Timeout {
+                secs,
+                source: Some("a".to_owned()),
+            };
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(wrap_command(command, None, Some(&timeout)))
+                .output()
+                .unwrap()
+        };
+        let output = run("echo 'it''s done'; exit 3", 10);
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "its done\n");
+
+        let start = std::time::Instant::now();
+        // The command's children are killed too, so the output is not held open.
+        let output = run("sleep 30; echo no", 1);
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .starts_with("modus: run(\"sleep 30; echo no\") in a timed out after 1 seconds\n"));
+    }
+
+    #[test]
+    fn from_named_context() {
+        let mf: Modusfile = "app :- from(context(\"builder\")), run(\"make\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "app".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            &plan.nodes[0],
+            BuildNode::FromContext { name } if name == "builder"
+        ));
+    }
+
+    #[test]
+    fn from_local_images() {
+        let mf: Modusfile = "app(\"oci\") :- from(oci_layout(\"./base-oci\")), run(\"make\").\n\
+                             app(\"tar\") :- from(docker_tar(\"base.tar\")), run(\"make\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "app(X)".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        assert!(plan.nodes.iter().any(|n| matches!(
+            n,
+            BuildNode::FromOciLayout { path, image_ref: None } if path == "./base-oci"
+        )));
+        assert!(plan.nodes.iter().any(|n| matches!(
+            n,
+            BuildNode::FromDockerTar { path, image_ref: None } if path == "base.tar"
+        )));
+        assert!(plan
+            .stats()
+            .base_images
+            .contains("oci_layout(\"./base-oci\")"));
+    }
+
+    #[test]
+    fn nodes_attributed_to_rules() {
+        let mf: Modusfile = "base :- from(\"alpine\").\n\
+                             app :- base, run(\"echo a\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            "app".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+
+        let source_of = |matches: fn(&BuildNode) -> bool| {
+            let node = plan.nodes.iter().position(matches).unwrap();
+            let head = plan.source(node).unwrap();
+            (head.to_string(), head.position.as_ref().unwrap().offset)
+        };
+        assert_eq!(
+            source_of(|n| matches!(n, BuildNode::From { .. })),
+            ("base".to_string(), 0)
+        );
+        assert_eq!(
+            source_of(|n| matches!(n, BuildNode::Run { .. })),
+            ("app".to_string(), 24)
+        );
+    }
+
+    #[test]
+    fn constraints_in_query() {
+        let mf: Modusfile = "app(V) :- (V = \"3.9\" ; V = \"3.11\"), from(f\"python:${V}\")."
+            .parse()
+            .unwrap();
+        let query: modusfile::Expression = "app(X), semver_geq(X, \"3.10\")".parse().unwrap();
+        let plan = plan_from_modusfile(
+            mf,
+            query,
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+
+        assert_eq!(plan.outputs.len(), 1);
+        assert_eq!(
+            plan.outputs[0].source_literal.as_ref().unwrap().to_string(),
+            "app(\"3.11\")"
+        );
+        assert!(plan.nodes.iter().all(|n| match n {
+            BuildNode::From { image_ref, .. } => image_ref == "python:3.11",
+            _ => true,
+        }));
+    }
 }
@@ -17,8 +17,12 @@
 pub mod analysis;
 // pub mod buildkit;
 pub mod builtin;
+pub mod datalog;
+pub mod deprecation;
 pub mod dockerfile;
+pub mod error;
 pub mod imagegen;
+pub mod library;
 pub mod logic;
 pub mod modusfile;
 // pub mod reporting;
@@ -28,5 +32,8 @@ pub mod transpiler;
 pub mod unification;
 pub mod wellformed;
 
+pub use error::ModusError;
+pub use logic::Symbol;
+
 #[macro_use]
 extern crate lazy_static;
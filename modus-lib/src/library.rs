@@ -0,0 +1,1064 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Libraries of rules that are bundled with Modus, and loaded with the directive
+//! `:- use(<library>).`
+//!
+//! A library is identified by its name and version, e.g. `"stdlib@1"`, so that a new
+//! version can be bundled next to the old one without changing the rules of the
+//! Modusfiles that use it. A name without a version refers to the latest version.
+//!
+//! One library is the prelude, the standard library unless a Modusfile chooses another
+//! one with `:- prelude(<library>).` or none with `:- prelude(none).` The predicates of
+//! the prelude that a Modusfile uses without defining them are added to it.
+//!
+//! Rules can also be shared without bundling them, by including a Modusfile from a URL
+//! with `:- include("<url>", sha256="<digest>").` Its clauses are added to the Modusfile
+//! as if they were written in it. Fetching is left to the program that uses this crate,
+//! see [`set_include_fetcher`].
+//!
+//! Rules can be split across the files of a project by importing a local Modusfile with
+//! `:- import("<path>").` The path is relative to the directory of the importing file.
+//! Its clauses are added to the Modusfile as if they were written in it, and a file that
+//! imports itself, directly or through other imports, is an error. Reading the files is
+//! left to the program that uses this crate, see [`set_import_loader`]. To avoid conflicts,
+//! the predicates of a Modusfile imported with `:- import("<path>", as=<namespace>).` are
+//! prefixed with the namespace, like those of a dependency.
+//!
+//! The libraries that a project depends on are loaded with `:- use(<name>).` as well,
+//! see [`set_dependency_loader`]. The predicates they define are prefixed with the name
+//! of the dependency, e.g. `python_install`, so that dependencies do not conflict.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::logic::{self, parser::Span, Predicate};
+use crate::modusfile::{
+    better_convert_error, parser, Expression, ModusClause, ModusTerm, Modusfile, IMPORT_PREDICATE,
+    INCLUDE_PREDICATE, PRELUDE_PREDICATE, USE_PREDICATE,
+};
+
+/// The name of the standard library, which is the prelude by default.
+pub const STDLIB: &str = "stdlib";
+
+/// The argument of `:- prelude(...)` that disables the prelude.
+pub const NO_PRELUDE: &str = "none";
+
+/// Separates the name of a library from its version, as in `stdlib@1`.
+pub const VERSION_SEPARATOR: char = '@';
+
+/// A library of rules that is bundled with Modus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Library {
+    pub name: &'static str,
+    pub version: u32,
+    source: &'static str,
+}
+
+/// Bundles the library at `library/<name>/v<version>.Modusfile`.
+macro_rules! bundled_library {
+    ($name:literal, $version:literal) => {
+        Library {
+            name: $name,
+            version: $version,
+            source: include_str!(concat!(
+                "library/",
+                $name,
+                "/v",
+                stringify!($version),
+                ".Modusfile"
+            )),
+        }
+    };
+}
+
+/// The bundled libraries.
+pub const LIBRARIES: &[Library] = &[bundled_library!("stdlib", 1), bundled_library!("rust", 1)];
+
+impl fmt::Display for Library {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.name, VERSION_SEPARATOR, self.version)
+    }
+}
+
+impl Library {
+    /// Finds the bundled library that `reference` refers to, either `<name>` for its
+    /// latest version, or `<name>@<version>`.
+    pub fn find(reference: &str) -> Result<&'static Library, String> {
+        let (name, version) = match reference.split_once(VERSION_SEPARATOR) {
+            Some((name, version)) => (
+                name,
+                Some(version.parse::<u32>().map_err(|_| {
+                    format!("Expected a version number after {}", VERSION_SEPARATOR)
+                })?),
+            ),
+            None => (reference, None),
+        };
+        let versions = LIBRARIES.iter().filter(|l| l.name == name);
+        match version {
+            Some(version) => versions
+                .clone()
+                .find(|l| l.version == version)
+                .ok_or_else(
+                    || match versions.map(|l| l.to_string()).collect::<Vec<_>>() {
+                        bundled if bundled.is_empty() => format!("Unknown library: {}", name),
+                        bundled => format!(
+                            "Unknown version of {}, the bundled versions are: {}",
+                            name,
+                            bundled.join(", ")
+                        ),
+                    },
+                ),
+            None => versions
+                .max_by_key(|l| l.version)
+                .ok_or_else(|| format!("Unknown library: {}", name)),
+        }
+    }
+
+    /// The clauses of the library. Their positions are removed, since they do not refer
+    /// to the Modusfile that uses the library.
+    pub fn clauses(&self) -> Vec<ModusClause> {
+        let (_, Modusfile(clauses)) = parser::modusfile(Span::new(self.source))
+            .unwrap_or_else(|e| panic!("Expected the library {} to parse: {:?}", self, e));
+        without_positions(clauses)
+    }
+}
+
+fn without_positions(clauses: Vec<ModusClause>) -> Vec<ModusClause> {
+    clauses
+        .into_iter()
+        .map(|mut c| {
+            c.head.position = None;
+            c.body = c.body.map(|b| b.without_position());
+            c.position = None;
+            c
+        })
+        .collect()
+}
+
+/// A Modusfile included with `:- include("<url>", sha256="<digest>").`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Include {
+    pub url: String,
+    /// The SHA-256 digest of the included Modusfile, as 64 lowercase hex digits.
+    pub sha256: String,
+}
+
+/// Fetches the source of an included Modusfile, and checks that it has the digest that
+/// the include is pinned to.
+pub type IncludeFetcher = fn(&Include) -> Result<String, String>;
+
+static INCLUDE_FETCHER: OnceLock<IncludeFetcher> = OnceLock::new();
+
+/// Sets how included Modusfiles are fetched when a Modusfile is parsed. Without a
+/// fetcher, includes are an error. Only the first fetcher that is set is used.
+pub fn set_include_fetcher(fetcher: IncludeFetcher) {
+    let _ = INCLUDE_FETCHER.set(fetcher);
+}
+
+fn no_include_fetcher(include: &Include) -> Result<String, String> {
+    Err(format!(
+        "Unable to include {}, remote includes are not supported here",
+        include.url
+    ))
+}
+
+/// Reads a Modusfile imported with `:- import("<path>").` from the file that imports it,
+/// or from the Modusfile being parsed if that is `None`. Returns the path of the imported
+/// file, which identifies it, e.g. its canonical path, along with its source.
+pub type ImportLoader = fn(Option<&Path>, &str) -> Imported;
+
+/// The path and source of an imported Modusfile, or why it could not be read.
+type Imported = Result<(PathBuf, String), String>;
+
+static IMPORT_LOADER: OnceLock<ImportLoader> = OnceLock::new();
+
+/// Sets how imported Modusfiles are read when a Modusfile is parsed. Without a loader,
+/// imports are an error. Only the first loader that is set is used.
+pub fn set_import_loader(loader: ImportLoader) {
+    let _ = IMPORT_LOADER.set(loader);
+}
+
+fn no_import_loader(_: Option<&Path>, path: &str) -> Imported {
+    Err(format!(
+        "Unable to import {}, local imports are not supported here",
+        path
+    ))
+}
+
+fn is_digest(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Loads the source of a dependency of the project by its name, or returns `None` if the
+/// project has no such dependency.
+pub type DependencyLoader = fn(&str) -> Option<Result<String, String>>;
+
+static DEPENDENCY_LOADER: OnceLock<DependencyLoader> = OnceLock::new();
+
+/// Sets how the dependencies that `:- use(<name>).` may refer to, besides the bundled
+/// libraries, are loaded. Only the first loader that is set is used.
+pub fn set_dependency_loader(loader: DependencyLoader) {
+    let _ = DEPENDENCY_LOADER.set(loader);
+}
+
+fn no_dependencies(_: &str) -> Option<Result<String, String>> {
+    None
+}
+
+/// Separates the name of a dependency from the names of the predicates it defines, e.g.
+/// the predicate `install` of the dependency `python` is used as `python_install`.
+pub const NAMESPACE_SEPARATOR: char = '_';
+
+fn is_directive_predicate(predicate: &Predicate) -> bool {
+    [
+        USE_PREDICATE,
+        PRELUDE_PREDICATE,
+        INCLUDE_PREDICATE,
+        IMPORT_PREDICATE,
+    ]
+    .contains(&predicate.0.as_str())
+}
+
+/// Renames the literals of the expression whose predicate `rename` maps to a new one.
+fn rename_literals(e: &Expression, rename: &dyn Fn(&Predicate) -> Option<Predicate>) -> Expression {
+    match e {
+        Expression::Literal(lit) => {
+            let mut args = lit.args.clone();
+            if let Some(goal) = lit
+                .findall_goal()
+                .and_then(|g| rename(&Predicate(g.into())))
+            {
+                args[2] = ModusTerm::Constant(goal.0.to_string());
+            }
+            Expression::Literal(logic::Literal {
+                predicate: rename(&lit.predicate).unwrap_or_else(|| lit.predicate.clone()),
+                args,
+                ..lit.clone()
+            })
+        }
+        Expression::OperatorApplication(pos, e, op) => Expression::OperatorApplication(
+            pos.clone(),
+            Box::new(rename_literals(e, rename)),
+            op.clone(),
+        ),
+        Expression::And(pos, positive, e1, e2) => Expression::And(
+            pos.clone(),
+            *positive,
+            Box::new(rename_literals(e1, rename)),
+            Box::new(rename_literals(e2, rename)),
+        ),
+        Expression::Or(pos, positive, e1, e2) => Expression::Or(
+            pos.clone(),
+            *positive,
+            Box::new(rename_literals(e1, rename)),
+            Box::new(rename_literals(e2, rename)),
+        ),
+    }
+}
+
+/// Prefixes the predicates that the clauses of a dependency define with its name, where
+/// they are defined and where they are used.
+fn namespaced(name: &str, clauses: Vec<ModusClause>) -> Vec<ModusClause> {
+    let defined = clauses
+        .iter()
+        .map(|c| c.head.predicate.clone())
+        .filter(|p| !is_directive_predicate(p))
+        .collect::<HashSet<_>>();
+    let rename = |p: &Predicate| {
+        defined
+            .contains(p)
+            .then(|| Predicate(format!("{}{}{}", name, NAMESPACE_SEPARATOR, p.0).into()))
+    };
+    clauses
+        .into_iter()
+        .map(|c| ModusClause {
+            head: logic::Literal {
+                predicate: rename(&c.head.predicate).unwrap_or(c.head.predicate),
+                ..c.head
+            },
+            body: c.body.map(|b| rename_literals(&b, &rename)),
+            position: c.position,
+        })
+        .collect()
+}
+
+/// Parses the source of a Modusfile that the directive loads, with positions removed.
+/// Parse errors are reported on the directive, since they do not refer to the Modusfile
+/// that it is in.
+fn parse_loaded(
+    directive: &ModusClause,
+    source: &str,
+    description: &str,
+    errors: &mut Vec<Diagnostic<()>>,
+) -> Vec<ModusClause> {
+    match parser::modusfile(Span::new(source)) {
+        Ok((_, Modusfile(clauses))) => without_positions(clauses),
+        Err(e) => {
+            let parse_errors = match e {
+                nom::Err::Error(e) | nom::Err::Failure(e) => better_convert_error(e),
+                nom::Err::Incomplete(_) => Vec::new(),
+            };
+            let notes = parse_errors
+                .iter()
+                .map(|d| match d.labels.first() {
+                    Some(label) => format!(
+                        "line {}: {}",
+                        source[..label.range.start].matches('\n').count() + 1,
+                        d.message
+                    ),
+                    None => d.message.clone(),
+                })
+                .collect();
+            errors.push(
+                directive_error(
+                    directive,
+                    "include-failed",
+                    format!("Did not parse {}", description),
+                )
+                .with_notes(notes),
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// The clauses of the Modusfile that the include directive includes, unless it was
+/// included already.
+fn included_clauses(
+    directive: &ModusClause,
+    fetch: &dyn Fn(&Include) -> Result<String, String>,
+    included: &mut HashSet<Include>,
+    errors: &mut Vec<Diagnostic<()>>,
+) -> Vec<ModusClause> {
+    let args = directive
+        .head
+        .args
+        .iter()
+        .map(|arg| match arg {
+            ModusTerm::Constant(c) => c.clone(),
+            _ => unreachable!("Expected the include directive to have constant arguments"),
+        })
+        .collect::<Vec<_>>();
+    let include = match &args[..] {
+        [url, sha256] if is_digest(sha256) => Include {
+            url: url.clone(),
+            sha256: sha256.clone(),
+        },
+        [_, _] => {
+            errors.push(directive_error(
+                directive,
+                "invalid-include-digest",
+                "Expected the sha256 of an include to be 64 lowercase hex digits".to_owned(),
+            ));
+            return Vec::new();
+        }
+        _ => {
+            errors.push(
+                directive_error(
+                    directive,
+                    "include-not-pinned",
+                    format!("The include of {} is not pinned to a digest", args[0]),
+                )
+                .with_notes(vec![format!(
+                    "write it as :- include({:?}, sha256=\"<digest>\").",
+                    args[0]
+                )]),
+            );
+            return Vec::new();
+        }
+    };
+    if !included.insert(include.clone()) {
+        return Vec::new();
+    }
+    match fetch(&include) {
+        Ok(source) => parse_loaded(
+            directive,
+            &source,
+            &format!("the Modusfile included from {}", include.url),
+            errors,
+        ),
+        Err(message) => {
+            errors.push(directive_error(directive, "include-failed", message));
+            Vec::new()
+        }
+    }
+}
+
+fn is_import_directive(clause: &ModusClause) -> bool {
+    clause.body.is_none()
+        && clause.head.predicate.0 == IMPORT_PREDICATE
+        && (1..=2).contains(&clause.head.args.len())
+}
+
+/// The clauses of the Modusfile that the import directive in `from` imports, with its own
+/// imports replaced by the clauses they import, unless it was imported already into the
+/// same namespace. `importing` is the chain of files whose imports are being loaded, to
+/// find cycles.
+fn imported_clauses(
+    directive: &ModusClause,
+    from: Option<&Path>,
+    import: &dyn Fn(Option<&Path>, &str) -> Imported,
+    imported: &mut HashSet<(PathBuf, Option<String>)>,
+    importing: &mut Vec<PathBuf>,
+    errors: &mut Vec<Diagnostic<()>>,
+) -> Vec<ModusClause> {
+    let args = directive
+        .head
+        .args
+        .iter()
+        .map(|arg| match arg {
+            ModusTerm::Constant(c) => c.as_str(),
+            _ => unreachable!("Expected the import directive to have constant arguments"),
+        })
+        .collect::<Vec<_>>();
+    let (path, namespace) = (args[0], args.get(1).copied());
+    let (file, source) = match import(from, path) {
+        Ok(loaded) => loaded,
+        Err(message) => {
+            errors.push(directive_error(directive, "import-failed", message));
+            return Vec::new();
+        }
+    };
+    if let Some(i) = importing.iter().position(|f| *f == file) {
+        let cycle = importing[i..]
+            .iter()
+            .chain(std::iter::once(&file))
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        errors.push(
+            directive_error(
+                directive,
+                "import-cycle",
+                format!("{} is imported by a file that it imports", file.display()),
+            )
+            .with_notes(vec![format!("Cycle: {}", cycle)]),
+        );
+        return Vec::new();
+    }
+    if !imported.insert((file.clone(), namespace.map(str::to_owned))) {
+        return Vec::new();
+    }
+
+    let clauses = parse_loaded(
+        directive,
+        &source,
+        &format!("the Modusfile imported from {}", file.display()),
+        errors,
+    );
+    // The clauses of a namespace are renamed, so they do not stand in for the same files
+    // imported elsewhere.
+    let mut namespace_imported = HashSet::new();
+    let imported = match namespace {
+        Some(_) => &mut namespace_imported,
+        None => imported,
+    };
+    importing.push(file);
+    let clauses = clauses
+        .into_iter()
+        .flat_map(|c| {
+            if is_import_directive(&c) {
+                let from = importing.last().cloned();
+                imported_clauses(&c, from.as_deref(), import, imported, importing, errors)
+            } else {
+                vec![c]
+            }
+        })
+        .collect();
+    importing.pop();
+    match namespace {
+        Some(namespace) => namespaced(namespace, clauses),
+        None => clauses,
+    }
+}
+
+/// Replaces the include directives of the clauses, and the `use` directives that name a
+/// dependency rather than a bundled library, and the import directives, by the clauses
+/// they load. This includes the directives of the loaded clauses. A Modusfile that is
+/// loaded more than once is only added once.
+fn expand_sources(
+    mut clauses: Vec<ModusClause>,
+    fetch: &dyn Fn(&Include) -> Result<String, String>,
+    load_dependency: &dyn Fn(&str) -> Option<Result<String, String>>,
+    import: &dyn Fn(Option<&Path>, &str) -> Imported,
+    errors: &mut Vec<Diagnostic<()>>,
+) -> Vec<ModusClause> {
+    let mut included = HashSet::new();
+    let mut imported = HashSet::new();
+    let mut dependencies = HashSet::new();
+    let mut i = 0;
+    while i < clauses.len() {
+        let directive = clauses[i].clone();
+        let is_directive = |predicate: &str, arities: &[usize]| {
+            directive.body.is_none()
+                && directive.head.predicate.0 == predicate
+                && arities.contains(&directive.head.args.len())
+        };
+        let loaded = if is_directive(INCLUDE_PREDICATE, &[1, 2]) {
+            included_clauses(&directive, fetch, &mut included, errors)
+        } else if is_import_directive(&directive) {
+            imported_clauses(
+                &directive,
+                None,
+                import,
+                &mut imported,
+                &mut Vec::new(),
+                errors,
+            )
+        } else if is_directive(USE_PREDICATE, &[1]) {
+            let name = match &directive.head.args[0] {
+                ModusTerm::Constant(name) => name.as_str(),
+                _ => unreachable!("Expected the directive to name a library"),
+            };
+            match load_dependency(name) {
+                // A bundled library, which is loaded later.
+                None => {
+                    i += 1;
+                    continue;
+                }
+                Some(_) if dependencies.contains(name) => Vec::new(),
+                Some(Ok(source)) => {
+                    dependencies.insert(name.to_owned());
+                    let clauses = parse_loaded(
+                        &directive,
+                        &source,
+                        &format!("the dependency {}", name),
+                        errors,
+                    );
+                    namespaced(name, clauses)
+                }
+                Some(Err(message)) => {
+                    errors.push(directive_error(&directive, "dependency-failed", message));
+                    Vec::new()
+                }
+            }
+        } else {
+            i += 1;
+            continue;
+        };
+        clauses.splice(i..i + 1, loaded);
+    }
+    clauses
+}
+
+/// The predicates that the clause uses in its body.
+fn used_predicates(clause: &ModusClause) -> Vec<Predicate> {
+    clause
+        .body
+        .iter()
+        .flat_map(|b| b.literals())
+        .flat_map(|l| {
+            let goal = l.findall_goal().map(|g| Predicate(g.into()));
+            std::iter::once(l.predicate).chain(goal)
+        })
+        .collect()
+}
+
+fn directive_error(directive: &ModusClause, code: &str, message: String) -> Diagnostic<()> {
+    let diag = Diagnostic::error().with_code(code).with_message(message);
+    match &directive.head.position {
+        Some(pos) => diag.with_labels(vec![Label::primary(
+            (),
+            pos.offset..pos.offset + pos.length,
+        )]),
+        None => diag,
+    }
+}
+
+/// Replaces the directives of the Modusfile with the libraries they name, and adds the
+/// predicates of the prelude that it uses. Predicates that the Modusfile defines itself
+/// are not added, so a library rule can be overridden. Included Modusfiles, dependencies
+/// and imported Modusfiles are loaded with the functions set by [`set_include_fetcher`],
+/// [`set_dependency_loader`] and [`set_import_loader`].
+pub fn load_libraries(mf: Modusfile) -> Result<Modusfile, Vec<Diagnostic<()>>> {
+    let fetcher = INCLUDE_FETCHER.get().copied().unwrap_or(no_include_fetcher);
+    let loader = DEPENDENCY_LOADER.get().copied().unwrap_or(no_dependencies);
+    let importer = IMPORT_LOADER.get().copied().unwrap_or(no_import_loader);
+    load_libraries_with(mf, &fetcher, &loader, &importer)
+}
+
+/// Like [`load_libraries`], but fetches included Modusfiles with `fetch`, loads
+/// dependencies with `load_dependency` and reads imported Modusfiles with `import`.
+pub fn load_libraries_with(
+    mf: Modusfile,
+    fetch: &dyn Fn(&Include) -> Result<String, String>,
+    load_dependency: &dyn Fn(&str) -> Option<Result<String, String>>,
+    import: &dyn Fn(Option<&Path>, &str) -> Imported,
+) -> Result<Modusfile, Vec<Diagnostic<()>>> {
+    let mut errors = Vec::new();
+    let mf = Modusfile(expand_sources(
+        mf.0,
+        fetch,
+        load_dependency,
+        import,
+        &mut errors,
+    ));
+    let is_directive = |c: &ModusClause| {
+        c.body.is_none()
+            && (c.head.predicate.0 == USE_PREDICATE || c.head.predicate.0 == PRELUDE_PREDICATE)
+            && c.head.args.len() == 1
+    };
+    let (directives, mut clauses): (Vec<_>, Vec<_>) = mf.0.into_iter().partition(is_directive);
+    let defined = clauses
+        .iter()
+        .map(|c| c.head.predicate.clone())
+        .collect::<HashSet<_>>();
+
+    let mut used: Vec<(&Library, &ModusClause)> = Vec::new();
+    let mut prelude: Option<Option<&Library>> = None;
+    for directive in directives.iter() {
+        let reference = match &directive.head.args[0] {
+            ModusTerm::Constant(reference) => reference.as_str(),
+            _ => unreachable!("Expected the directive to name a library"),
+        };
+        let is_prelude = directive.head.predicate.0 == PRELUDE_PREDICATE;
+        let library = if is_prelude && reference == NO_PRELUDE {
+            None
+        } else {
+            match Library::find(reference) {
+                Ok(library) => Some(library),
+                Err(message) => {
+                    let known = LIBRARIES
+                        .iter()
+                        .map(|l| l.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    errors.push(
+                        directive_error(directive, "unknown-library", message)
+                            .with_notes(vec![format!("The bundled libraries are: {}", known)]),
+                    );
+                    continue;
+                }
+            }
+        };
+        if is_prelude {
+            if prelude.is_some() {
+                errors.push(directive_error(
+                    directive,
+                    "prelude-redefined",
+                    "The prelude is chosen more than once".to_owned(),
+                ));
+            }
+            prelude = Some(library);
+            continue;
+        }
+        let library = library.unwrap();
+        match used.iter().find(|(l, _)| l.name == library.name) {
+            Some((l, _)) if l.version == library.version => {}
+            Some((l, _)) => errors.push(directive_error(
+                directive,
+                "library-version-conflict",
+                format!("{} is used, but so is {}", library, l),
+            )),
+            None => used.push((library, directive)),
+        }
+    }
+    let prelude = prelude.unwrap_or_else(|| Some(Library::find(STDLIB).unwrap()));
+    if let Some(prelude) = prelude {
+        if let Some((l, directive)) = used
+            .iter()
+            .find(|(l, _)| l.name == prelude.name && l.version != prelude.version)
+        {
+            errors.push(directive_error(
+                directive,
+                "library-version-conflict",
+                format!("{} is used, but the prelude is {}", l, prelude),
+            ));
+        }
+    }
+
+    // The library that each added predicate comes from, since two libraries must not
+    // both define one.
+    let mut provided: HashMap<Predicate, &Library> = HashMap::new();
+    for &(library, directive) in used.iter() {
+        for clause in library.clauses() {
+            if defined.contains(&clause.head.predicate) {
+                continue;
+            }
+            match provided.get(&clause.head.predicate) {
+                Some(other) if *other != library => {
+                    errors.push(directive_error(
+                        directive,
+                        "library-conflict",
+                        format!(
+                            "{} is defined by both {} and {}",
+                            clause.head.predicate, other, library
+                        ),
+                    ));
+                    continue;
+                }
+                _ => {}
+            }
+            provided.insert(clause.head.predicate.clone(), library);
+            clauses.push(clause);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if let Some(prelude) = prelude.filter(|p| !used.iter().any(|(l, _)| l == p)) {
+        // Only what is used, so that the prelude does not take predicate names away from
+        // a Modusfile, or show up in its plan.
+        let prelude = prelude
+            .clauses()
+            .into_iter()
+            .filter(|c| !defined.contains(&c.head.predicate))
+            .filter(|c| !provided.contains_key(&c.head.predicate))
+            .collect::<Vec<_>>();
+        let mut used = clauses
+            .iter()
+            .flat_map(used_predicates)
+            .collect::<HashSet<_>>();
+        let mut loaded = HashSet::new();
+        while let Some((i, clause)) = prelude
+            .iter()
+            .enumerate()
+            .find(|(i, c)| !loaded.contains(i) && used.contains(&c.head.predicate))
+        {
+            loaded.insert(i);
+            used.extend(used_predicates(clause));
+            clauses.push(clause.clone());
+        }
+    }
+
+    Ok(Modusfile(clauses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::{self, BuildNode};
+    use crate::sld;
+
+    fn defines(mf: &Modusfile, predicate: &str) -> usize {
+        mf.0.iter()
+            .filter(|c| c.head.predicate.0 == predicate)
+            .count()
+    }
+
+    #[test]
+    fn libraries_parse() {
+        for library in LIBRARIES {
+            assert!(!library.clauses().is_empty());
+        }
+        let stdlib = Library::find(STDLIB).unwrap().clauses();
+        for predicate in ["apk_install", "apt_install", "pip_install"] {
+            assert!(stdlib.iter().any(|c| c.head.predicate.0 == predicate));
+        }
+    }
+
+    #[test]
+    fn find_versions() {
+        assert_eq!(Library::find("stdlib").unwrap().to_string(), "stdlib@1");
+        assert_eq!(Library::find("rust@1").unwrap().to_string(), "rust@1");
+        assert_eq!(
+            Library::find("rust@7"),
+            Err("Unknown version of rust, the bundled versions are: rust@1".to_owned())
+        );
+        assert!(Library::find("rust@latest").is_err());
+        assert!(Library::find("missing").is_err());
+        assert!(Library::find("missing@1").is_err());
+    }
+
+    #[test]
+    fn stdlib_is_loaded_implicitly() {
+        let mf: Modusfile = "app :- from(\"alpine\"), apk_install(\"make gcc\")."
+            .parse()
+            .unwrap();
+        assert_eq!(defines(&mf, "apk_install"), 1);
+        assert_eq!(defines(&mf, "apt_install"), 0);
+        assert_eq!(defines(&mf, USE_PREDICATE), 0);
+
+        let plan = imagegen::plan_from_modusfile(
+            mf,
+            "app".parse().unwrap(),
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+        assert!(plan.nodes.iter().any(|n| matches!(
+            n,
+            BuildNode::Run { command, cache_mounts, .. }
+                if command.ends_with("make gcc") && cache_mounts == &["/var/cache/apk"]
+        )));
+    }
+
+    #[test]
+    fn use_directive() {
+        let mf: Modusfile = ":- use(stdlib).\n\
+                             :- use(\"stdlib\").\n\
+                             app :- from(\"alpine\")."
+            .parse()
+            .unwrap();
+        assert_eq!(defines(&mf, "apk_install"), 1);
+        assert_eq!(defines(&mf, "apt_install"), 1);
+        assert_eq!(defines(&mf, "pip_install"), 1);
+        assert_eq!(defines(&mf, USE_PREDICATE), 0);
+    }
+
+    #[test]
+    fn user_rules_override_library() {
+        let mf: Modusfile = ":- use(stdlib).\n\
+                             pip_install(P) :- run(f\"pip install --user ${P}\").\n\
+                             app :- from(\"python\"), pip_install(\"flask\")."
+            .parse()
+            .unwrap();
+        assert_eq!(defines(&mf, "pip_install"), 1);
+        assert!(mf.0.iter().any(|c| c.head.predicate.0 == "pip_install"
+            && c.body.as_ref().unwrap().to_string().contains("--user")));
+    }
+
+    #[test]
+    fn prelude_directive() {
+        let source = "app :- from(\"alpine\"), apk_install(\"make\"), cargo_build(\"\").";
+        let mf: Modusfile = source.parse().unwrap();
+        assert_eq!(defines(&mf, "apk_install"), 1);
+        assert_eq!(defines(&mf, "cargo_build"), 0);
+
+        let mf: Modusfile = format!(":- prelude(none).\n{}", source).parse().unwrap();
+        assert_eq!(defines(&mf, "apk_install"), 0);
+        assert_eq!(defines(&mf, PRELUDE_PREDICATE), 0);
+
+        let mf: Modusfile = format!(":- prelude(\"rust@1\").\n{}", source)
+            .parse()
+            .unwrap();
+        assert_eq!(defines(&mf, "apk_install"), 0);
+        assert_eq!(defines(&mf, "cargo_build"), 1);
+        assert_eq!(defines(&mf, "cargo_install"), 0);
+
+        let errors = format!(":- prelude(none).\n:- prelude(stdlib).\n{}", source)
+            .parse::<Modusfile>()
+            .unwrap_err();
+        assert_eq!(errors[0].code.as_deref(), Some("prelude-redefined"));
+    }
+
+    #[test]
+    fn version_references() {
+        let mf: Modusfile = ":- prelude(\"stdlib@1\").\n:- use(rust).\n:- use(\"rust@1\")."
+            .parse()
+            .unwrap();
+        assert_eq!(defines(&mf, "cargo_build"), 1);
+        let errors = ":- use(\"stdlib@1\").\n:- use(\"stdlib@2\")."
+            .parse::<Modusfile>()
+            .unwrap_err();
+        assert_eq!(errors[0].code.as_deref(), Some("unknown-library"));
+    }
+
+    #[test]
+    fn unknown_library() {
+        let source = "app :- from(\"alpine\").\n:- use(haskell).";
+        let errors = source.parse::<Modusfile>().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code.as_deref(), Some("unknown-library"));
+        let range = errors[0].labels[0].range.clone();
+        assert_eq!(&source[range], ":- use(haskell)");
+    }
+
+    #[test]
+    fn include_directive() {
+        let digest = |c: char| c.to_string().repeat(64);
+        let source = format!(
+            ":- include(\"https://example.com/python.Modusfile\", sha256=\"{}\").\n\
+             app :- python(\"3.11\"), apk_install(\"make\").",
+            digest('a')
+        );
+        let (_, mf) = parser::modusfile(Span::new(&source)).unwrap();
+        let fetch = |include: &Include| match include.url.as_str() {
+            "https://example.com/python.Modusfile" => Ok(format!(
+                ":- include(\"https://example.com/base.Modusfile\", sha256 = \"{}\").\n\
+                 python(V) :- base(f\"python:${{V}}-alpine\").",
+                digest('b')
+            )),
+            "https://example.com/base.Modusfile" => Ok(format!(
+                ":- include(\"https://example.com/python.Modusfile\", sha256=\"{}\").\n\
+                 base(I) :- from(I).",
+                digest('a')
+            )),
+            url => Err(format!("Unexpected fetch of {}", url)),
+        };
+        let mf = load_libraries_with(mf, &fetch, &|_| None, &no_import_loader).unwrap();
+        assert_eq!(defines(&mf, "python"), 1);
+        assert_eq!(defines(&mf, "base"), 1);
+        assert_eq!(defines(&mf, "apk_install"), 1);
+        assert_eq!(defines(&mf, INCLUDE_PREDICATE), 0);
+        assert!(mf
+            .0
+            .iter()
+            .filter(|c| c.head.predicate.0 != "app")
+            .all(|c| c.head.position.is_none()));
+
+        let errors = |source: &str| {
+            let (_, mf) = parser::modusfile(Span::new(source)).unwrap();
+            load_libraries_with(
+                mf,
+                &|_| Ok("not a Modusfile".to_owned()),
+                &|_| None,
+                &no_import_loader,
+            )
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.code.unwrap())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors(":- include(\"https://example.com/a.Modusfile\")."),
+            vec!["include-not-pinned"]
+        );
+        assert_eq!(
+            errors(":- include(\"https://example.com/a.Modusfile\", sha256=\"ABC\")."),
+            vec!["invalid-include-digest"]
+        );
+        assert_eq!(
+            errors(&format!(
+                ":- include(\"https://example.com/a.Modusfile\", sha256=\"{}\").",
+                digest('c')
+            )),
+            vec!["include-failed"]
+        );
+
+        // No fetcher is set in tests.
+        let source = format!(
+            "app :- from(\"alpine\").\n:- include(\"https://example.com/a.Modusfile\", sha256=\"{}\").",
+            digest('d')
+        );
+        let errors = source.parse::<Modusfile>().unwrap_err();
+        assert_eq!(errors[0].code.as_deref(), Some("include-failed"));
+        let range = errors[0].labels[0].range.clone();
+        assert!(source[range].starts_with(":- include("));
+    }
+
+    #[test]
+    fn dependencies_are_namespaced() {
+        let source = ":- use(python).\n\
+                      :- use(\"python\").\n\
+                      :- use(rust).\n\
+                      install(P) :- run(P).\n\
+                      app :- python_image(\"3.11\"), python_install(\"flask\"), install(\"make\")."
+            .to_owned();
+        let (_, mf) = parser::modusfile(Span::new(&source)).unwrap();
+        let load = |name: &str| match name {
+            "python" => Some(Ok(":- use(stdlib).\n\
+                                 image(V) :- from(f\"python:${V}\").\n\
+                                 install(P) :- pip_install(P)."
+                .to_owned())),
+            "broken" => Some(Err("broken has not been fetched".to_owned())),
+            _ => None,
+        };
+        let mf = load_libraries_with(
+            mf,
+            &|_| Err("Unexpected include".to_owned()),
+            &load,
+            &no_import_loader,
+        )
+        .unwrap();
+        assert_eq!(defines(&mf, "python_image"), 1);
+        assert_eq!(defines(&mf, "python_install"), 1);
+        assert_eq!(defines(&mf, "install"), 1);
+        assert_eq!(defines(&mf, "image"), 0);
+        assert_eq!(defines(&mf, "pip_install"), 1);
+        assert_eq!(defines(&mf, "cargo_build"), 1);
+        let python_install =
+            mf.0.iter()
+                .find(|c| c.head.predicate.0 == "python_install")
+                .unwrap();
+        assert_eq!(
+            python_install.body.as_ref().unwrap().to_string(),
+            "pip_install(P)"
+        );
+
+        let (_, mf) = parser::modusfile(Span::new(":- use(broken).")).unwrap();
+        let errors =
+            load_libraries_with(mf, &|_| Err(String::new()), &load, &no_import_loader).unwrap_err();
+        assert_eq!(errors[0].code.as_deref(), Some("dependency-failed"));
+    }
+
+    #[test]
+    fn imports_are_relative_and_acyclic() {
+        let files: HashMap<&str, &str> = vec![
+            (
+                "lib/base.Modusfile",
+                ":- import(\"tools.Modusfile\").\nbase :- from(\"alpine\").",
+            ),
+            ("lib/tools.Modusfile", "tool :- run(\"make\")."),
+            ("a.Modusfile", ":- import(\"lib/../b.Modusfile\").\na."),
+            ("b.Modusfile", ":- import(\"a.Modusfile\").\nb."),
+        ]
+        .into_iter()
+        .collect();
+        let import = |from: Option<&Path>, path: &str| {
+            let file = match from.and_then(Path::parent) {
+                Some(dir) => dir.join(path),
+                None => PathBuf::from(path),
+            };
+            // Resolves `..` as canonicalizing would.
+            let file = PathBuf::from(file.to_str().unwrap().replace("lib/../", ""));
+            files
+                .get(file.to_str().unwrap())
+                .map(|source| (file.clone(), source.to_string()))
+                .ok_or_else(|| format!("{} does not exist", file.display()))
+        };
+        let load = |source: &str| {
+            let (_, mf) = parser::modusfile(Span::new(source)).unwrap();
+            load_libraries_with(mf, &|_| Err(String::new()), &|_| None, &import)
+        };
+
+        let mf = load(
+            ":- import(\"lib/base.Modusfile\").\n\
+             :- import(\"lib/tools.Modusfile\").\n\
+             app :- base, tool.",
+        )
+        .unwrap();
+        assert_eq!(defines(&mf, "base"), 1);
+        assert_eq!(defines(&mf, "tool"), 1);
+        assert_eq!(defines(&mf, IMPORT_PREDICATE), 0);
+
+        let mf = load(
+            ":- import(\"lib/base.Modusfile\", as=base).\n\
+             :- import(\"lib/tools.Modusfile\").\n\
+             app :- base_base, base_tool, tool.",
+        )
+        .unwrap();
+        assert_eq!(defines(&mf, "base"), 0);
+        assert_eq!(defines(&mf, "base_base"), 1);
+        assert_eq!(defines(&mf, "base_tool"), 1);
+        assert_eq!(defines(&mf, "tool"), 1);
+        let base =
+            mf.0.iter()
+                .find(|c| c.head.predicate.0 == "base_base")
+                .unwrap();
+        assert_eq!(base.body.as_ref().unwrap().to_string(), "from(\"alpine\")");
+
+        let errors = load(":- import(\"a.Modusfile\").").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code.as_deref(), Some("import-cycle"));
+        assert_eq!(
+            errors[0].notes,
+            vec!["Cycle: a.Modusfile -> b.Modusfile -> a.Modusfile"]
+        );
+
+        let source = "app.\n:- import(\"missing.Modusfile\").";
+        let errors = load(source).unwrap_err();
+        assert_eq!(errors[0].code.as_deref(), Some("import-failed"));
+        let range = errors[0].labels[0].range.clone();
+        assert!(source[range].starts_with(":- import("));
+    }
+}
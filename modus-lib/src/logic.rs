@@ -27,14 +27,129 @@ use crate::logic::parser::Span;
 use crate::sld;
 use crate::unification::Rename;
 
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Debug;
-use std::ops::Range;
+use std::ops::{Deref, Range};
 use std::str;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
 use std::{collections::HashSet, hash::Hash};
 
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, u32>,
+    strings: Vec<&'static str>,
+}
+
+lazy_static! {
+    static ref INTERNER: RwLock<Interner> = RwLock::new(Interner::default());
+}
+
+/// An interned string, used for constants, variable names and predicate names.
+///
+/// Symbols are compared and hashed by their ID, so they are cheap to copy and compare.
+/// Interned strings live for the rest of the program.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    pub fn intern(s: &str) -> Symbol {
+        if let Some(&id) = INTERNER.read().unwrap().ids.get(s) {
+            return Symbol(id);
+        }
+        let mut interner = INTERNER.write().unwrap();
+        // Another thread may have interned it between the two locks.
+        if let Some(&id) = interner.ids.get(s) {
+            return Symbol(id);
+        }
+        let s: &'static str = Box::leak(s.to_owned().into_boxed_str());
+        let id = interner.strings.len() as u32;
+        interner.strings.push(s);
+        interner.ids.insert(s, id);
+        Symbol(id)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        INTERNER.read().unwrap().strings[self.0 as usize]
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+/// Symbols are ordered by their string, not by the order in which they were interned.
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        if self == other {
+            CmpOrdering::Equal
+        } else {
+            self.as_str().cmp(other.as_str())
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(s: &str) -> Self {
+        Symbol::intern(s)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(s: String) -> Self {
+        Symbol::intern(&s)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(s: &String) -> Self {
+        Symbol::intern(s)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
 impl fmt::Display for IRTerm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -84,7 +199,7 @@ impl sld::Auxiliary for IRTerm {
 
 /// A predicate symbol
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
-pub struct Predicate(pub String);
+pub struct Predicate(pub Symbol);
 
 impl Predicate {
     /// True if this predicate symbol represents an operator.
@@ -92,6 +207,16 @@ impl Predicate {
         self.0.starts_with("_operator_")
     }
 
+    /// True if this predicate symbol was introduced by the translation of a negated expression.
+    pub fn is_negation(&self) -> bool {
+        self.0.starts_with("_negate_")
+    }
+
+    /// True if this predicate symbol is the head of a query directive, `?- <goal>.`
+    pub fn is_assertion(&self) -> bool {
+        self.0.starts_with("_assertion_")
+    }
+
     /// Unmangles the name if it's an operator.
     pub fn unmangle(self) -> Predicate {
         if self.is_operator() {
@@ -100,7 +225,7 @@ impl Predicate {
                     .trim_start_matches("_operator_")
                     .trim_end_matches("_begin")
                     .trim_end_matches("_end")
-                    .to_string(),
+                    .into(),
             )
         } else {
             self
@@ -112,7 +237,7 @@ impl Predicate {
     pub fn naive_predicate_kind(&self) -> Kind {
         match self.0.as_str() {
             "from" => Kind::Image,
-            "run" | "copy" => Kind::Layer,
+            "run" | "copy" | "git_checkout" => Kind::Layer,
             _ => Kind::Logic,
         }
     }
@@ -120,14 +245,20 @@ impl Predicate {
 
 impl From<String> for Predicate {
     fn from(s: String) -> Self {
-        Predicate(s)
+        Predicate(s.into())
+    }
+}
+
+impl From<&str> for Predicate {
+    fn from(s: &str) -> Self {
+        Predicate(s.into())
     }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
 pub enum IRTerm {
-    Constant(String),
-    UserVariable(String),
+    Constant(Symbol),
+    UserVariable(Symbol),
     List(Vec<IRTerm>),
 
     /// Primarily used to establish f-string constraints.
@@ -315,6 +446,27 @@ impl Literal {
         }
     }
 
+    /// Splits a `findall` literal, `_findall(<template>, <list>, "<goal predicate>", <goal args>...)`,
+    /// into its template, list and goal.
+    pub fn findall_parts(&self) -> Option<(&IRTerm, &IRTerm, Literal)> {
+        match (self.predicate.0.as_str(), &self.args[..]) {
+            (
+                crate::modusfile::FINDALL_PREDICATE,
+                [template, list, IRTerm::Constant(goal), args @ ..],
+            ) => Some((
+                template,
+                list,
+                Literal {
+                    positive: true,
+                    position: self.position.clone(),
+                    predicate: Predicate(*goal),
+                    args: args.to_vec(),
+                },
+            )),
+            _ => None,
+        }
+    }
+
     pub fn negated(&self) -> Literal {
         Literal {
             positive: !self.positive,
@@ -385,11 +537,10 @@ impl fmt::Display for Predicate {
 }
 
 fn display_sep<T: fmt::Display>(seq: &[T], sep: &str) -> String {
-    return seq
-        .iter()
+    seq.iter()
         .map(|t| t.to_string())
         .collect::<Vec<String>>()
-        .join(sep);
+        .join(sep)
 }
 
 impl fmt::Display for Literal {
@@ -472,7 +623,7 @@ pub mod parser {
         P: FnMut(Span<'a>) -> IResult<Span<'a>, T>,
     {
         move |i| {
-            let original_i = i.clone();
+            let original_i = i;
 
             let (i, o) = inner(i)?;
 
@@ -484,9 +635,9 @@ pub mod parser {
         }
     }
 
-    fn ws<'a, F: 'a, O>(inner: F) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, O>
+    fn ws<'a, F, O>(inner: F) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, O>
     where
-        F: FnMut(Span<'a>) -> IResult<Span<'a>, O>,
+        F: FnMut(Span<'a>) -> IResult<Span<'a>, O> + 'a,
     {
         delimited(multispace0, inner, multispace0)
     }
@@ -510,9 +661,11 @@ pub mod parser {
     pub fn term(i: Span) -> IResult<Span, IRTerm> {
         alt((
             map(list_term, IRTerm::List),
-            map(constant, |s| IRTerm::Constant(s.fragment().to_string())),
+            map(constant, |s| IRTerm::Constant((*s.fragment()).into())),
             map(is_a("_"), |_| sld::Auxiliary::aux(true)),
-            map(variable, |s| IRTerm::UserVariable(s.fragment().to_string())),
+            map(variable, |s| {
+                IRTerm::UserVariable(Symbol::intern(s.fragment()))
+            }),
         ))(i)
     }
 
@@ -525,12 +678,12 @@ pub mod parser {
     }
 
     /// Parses a literal with a generic term type.
-    pub fn literal<'a, FT: 'a, T, S, Any>(
+    pub fn literal<'a, FT, T, S, Any>(
         term: FT,
         space0: S,
     ) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Literal<T>>
     where
-        FT: FnMut(Span<'a>) -> IResult<Span<'a>, T> + Clone,
+        FT: FnMut(Span<'a>) -> IResult<Span<'a>, T> + Clone + 'a,
         S: FnMut(Span<'a>) -> IResult<Span<'a>, Any> + Clone,
     {
         move |i| {
@@ -556,19 +709,16 @@ pub mod parser {
                 Literal {
                     positive: neg_count % 2 == 0,
                     position: Some(spanned_pos),
-                    predicate: Predicate(name.fragment().to_string()),
-                    args: match args {
-                        Some(args) => args,
-                        None => Vec::new(),
-                    },
+                    predicate: Predicate((*name.fragment()).into()),
+                    args: args.unwrap_or_default(),
                 },
             ))
         }
     }
 
-    pub fn clause<'a, FT: 'a, T>(term: FT) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Clause<T>>
+    pub fn clause<'a, FT, T>(term: FT) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Clause<T>>
     where
-        FT: FnMut(Span) -> IResult<Span, T> + Clone,
+        FT: FnMut(Span) -> IResult<Span, T> + Clone + 'a,
     {
         map(
             pair(
@@ -590,6 +740,17 @@ pub mod parser {
 mod tests {
     use super::*;
 
+    #[test]
+    fn symbols_are_interned() {
+        let a = Symbol::intern("alpine");
+        assert_eq!(a, Symbol::from("alpine".to_owned()));
+        assert_ne!(a, Symbol::intern("ubuntu"));
+        assert_eq!(a.to_string(), "alpine");
+        assert_eq!(format!("{:?}", a), "\"alpine\"");
+        // Ordering follows the strings, not the interning order.
+        assert!(Symbol::intern("zz") > Symbol::intern("aa"));
+    }
+
     #[test]
     fn simple_term() {
         let inp = "\"\"";
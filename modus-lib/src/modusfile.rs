@@ -26,6 +26,7 @@ use std::collections::HashSet;
 use std::fmt;
 use std::ops::Range;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::logic;
 use crate::logic::parser::Span;
@@ -66,7 +67,7 @@ impl Expression {
     #[cfg(test)]
     fn eq_ignoring_position(&self, other: &Expression) -> bool {
         match (self, other) {
-            (Expression::Literal(l), Expression::Literal(r)) => l.eq_ignoring_position(&r),
+            (Expression::Literal(l), Expression::Literal(r)) => l.eq_ignoring_position(r),
             (
                 Expression::OperatorApplication(_, e1, op1),
                 Expression::OperatorApplication(_, e2, op2),
@@ -82,9 +83,9 @@ impl Expression {
     pub fn get_spanned_position(&self) -> &Option<SpannedPosition> {
         match self {
             Expression::Literal(lit) => &lit.position,
-            Expression::OperatorApplication(s, ..) => &s,
-            Expression::And(s, ..) => &s,
-            Expression::Or(s, ..) => &s,
+            Expression::OperatorApplication(s, ..) => s,
+            Expression::And(s, ..) => s,
+            Expression::Or(s, ..) => s,
         }
     }
 
@@ -101,13 +102,13 @@ impl Expression {
             ),
             Expression::And(_, positive, e1, e2) => Expression::And(
                 None,
-                positive.clone(),
+                *positive,
                 Box::new(e1.without_position()),
                 Box::new(e2.without_position()),
             ),
             Expression::Or(_, positive, e1, e2) => Expression::Or(
                 None,
-                positive.clone(),
+                *positive,
                 Box::new(e1.without_position()),
                 Box::new(e2.without_position()),
             ),
@@ -118,22 +119,18 @@ impl Expression {
         match self {
             Expression::Literal(lit) => vec![lit.clone()].into_iter().collect(),
             Expression::OperatorApplication(_, e, _) => e.literals(),
-            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => e1
-                .literals()
-                .into_iter()
-                .chain(e2.literals().into_iter())
-                .collect(),
+            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => {
+                e1.literals().into_iter().chain(e2.literals()).collect()
+            }
         }
     }
 
     pub fn operators(&self) -> HashSet<&Operator> {
         match self {
             Expression::OperatorApplication(_, _, op) => vec![op].into_iter().collect(),
-            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => e1
-                .operators()
-                .into_iter()
-                .chain(e2.operators().into_iter())
-                .collect(),
+            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => {
+                e1.operators().into_iter().chain(e2.operators()).collect()
+            }
             _ => HashSet::new(),
         }
     }
@@ -186,13 +183,38 @@ pub struct ModusClause {
     pub head: Literal,
     // If None, this clause is a fact.
     pub body: Option<Expression>,
+    /// The span of the whole clause, including the `.` that ends it.
+    pub position: Option<SpannedPosition>,
+}
+
+impl ModusClause {
+    /// The documentation of the clause, from the doc comments on the lines right before
+    /// it. `source` is the text the clause was parsed from.
+    pub fn doc(&self, source: &str) -> Option<String> {
+        let position = self.position.as_ref()?;
+        let (before, line_start) = source[..position.offset].rsplit_once('\n')?;
+        if !line_start.trim().is_empty() {
+            return None;
+        }
+        let mut lines = before
+            .lines()
+            .rev()
+            .map_while(|l| l.trim().strip_prefix(tokens::DOC_COMMENT))
+            .map(|l| l.strip_prefix(' ').unwrap_or(l))
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            return None;
+        }
+        lines.reverse();
+        Some(lines.join("\n"))
+    }
 }
 
 #[cfg(test)]
 impl ModusClause {
     fn eq_ignoring_position(&self, other: &ModusClause) -> bool {
         if let (Some(expr1), Some(expr2)) = (&self.body, &other.body) {
-            self.head.eq_ignoring_position(&other.head) && expr1.eq_ignoring_position(&expr2)
+            self.head.eq_ignoring_position(&other.head) && expr1.eq_ignoring_position(expr2)
         } else {
             self.head.eq_ignoring_position(&other.head) && self.body.eq(&other.body)
         }
@@ -235,10 +257,10 @@ pub enum ModusTerm {
 
 impl ModusTerm {
     pub fn is_variable(&self) -> bool {
-        match self {
-            ModusTerm::FormatString { .. } | ModusTerm::UserVariable(_) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            ModusTerm::FormatString { .. } | ModusTerm::UserVariable(_)
+        )
     }
 
     /// Excludes anonymous variables.
@@ -258,6 +280,12 @@ impl ModusTerm {
         }
     }
 
+    /// Returns `true` if the modus term is a constant that is not a number, such as a
+    /// version `"1.2.0"`, so that an infix comparison with it compares versions.
+    pub fn is_non_numeric_constant(&self) -> bool {
+        matches!(self, ModusTerm::Constant(c) if c.parse::<f64>().is_err())
+    }
+
     /// Returns `true` if the modus term is [`FormatString`].
     ///
     /// [`FormatString`]: ModusTerm::FormatString
@@ -296,6 +324,12 @@ impl fmt::Display for ModusTerm {
     }
 }
 
+/// The parsers are complete, so this is only returned if one of them asks for more
+/// input anyway.
+fn incomplete_input() -> Vec<Diagnostic<()>> {
+    vec![Diagnostic::error().with_message("Unexpected end of input.")]
+}
+
 impl str::FromStr for ModusTerm {
     type Err = Vec<Diagnostic<()>>;
 
@@ -304,7 +338,7 @@ impl str::FromStr for ModusTerm {
         match parser::modus_term(span) {
             Result::Ok((_, o)) => Ok(o),
             Result::Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+            _ => Err(incomplete_input()),
         }
     }
 }
@@ -312,13 +346,19 @@ impl str::FromStr for ModusTerm {
 impl From<ModusTerm> for logic::IRTerm {
     fn from(modus_term: ModusTerm) -> Self {
         match modus_term {
-            ModusTerm::Constant(c) => logic::IRTerm::Constant(process_raw_string(&c)),
+            ModusTerm::Constant(c) => logic::IRTerm::Constant(
+                process_raw_string(&c)
+                    .expect("BUG: the parser should've rejected invalid escapes.")
+                    .into(),
+            ),
             ModusTerm::FormatString { .. } => {
                 unreachable!("BUG: analysis should've handled this case.")
             }
-            ModusTerm::UserVariable(v) => logic::IRTerm::UserVariable(v),
+            ModusTerm::UserVariable(v) => logic::IRTerm::UserVariable(v.into()),
             ModusTerm::AnonymousVariable => sld::Auxiliary::aux(true),
-            ModusTerm::List(_, _) => unimplemented!(),
+            ModusTerm::List(_, ts) => {
+                logic::IRTerm::List(ts.into_iter().map(logic::IRTerm::from).collect())
+            }
         }
     }
 }
@@ -416,9 +456,86 @@ impl Modusfile {
                 args: Vec::new(),
             },
             body: Some(goal),
+            position: None,
+        });
+        self
+    }
+
+    /// Adds the fact `target_platform("<platform>")`, so that rules can depend on
+    /// the platform that is being built for.
+    pub fn add_target_platform(&mut self, platform: &str) -> &mut Self {
+        self.0.push(ModusClause {
+            head: Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(TARGET_PLATFORM_PREDICATE.into()),
+                args: vec![ModusTerm::Constant(platform.to_owned())],
+            },
+            body: None,
+            position: None,
         });
         self
     }
+
+    /// The platform of the first `target_platform` fact, if any.
+    pub fn target_platform(&self) -> Option<&str> {
+        self.0.iter().find_map(|c| match (&c.head, &c.body) {
+            (
+                Literal {
+                    positive: true,
+                    predicate,
+                    args,
+                    ..
+                },
+                None,
+            ) if predicate.0 == TARGET_PLATFORM_PREDICATE => match &args[..] {
+                [ModusTerm::Constant(platform)] => Some(platform.as_str()),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
+/// The predicate of the fact that holds the platform given by `--platform`.
+pub const TARGET_PLATFORM_PREDICATE: &str = "target_platform";
+
+/// The predicate of the fact that the directive `:- use(<library>).` is parsed into,
+/// see [`crate::library`].
+pub const USE_PREDICATE: &str = "_use";
+
+/// The predicate of the fact that the directive `:- prelude(<library>).` is parsed into,
+/// see [`crate::library`].
+pub const PRELUDE_PREDICATE: &str = "_prelude";
+
+/// The predicate of the fact that the directive `:- include(<url>, sha256=<digest>).` is
+/// parsed into, see [`crate::library`].
+pub const INCLUDE_PREDICATE: &str = "_include";
+
+/// The predicate of the fact that the directive `:- import(<path>).` or
+/// `:- import(<path>, as=<namespace>).` is parsed into, see [`crate::library`].
+pub const IMPORT_PREDICATE: &str = "_import";
+
+/// Used to generate the unique predicate names of the heads that query directives,
+/// `?- <goal>.`, are parsed into, `_assertion_<n> :- <goal>.`.
+static ASSERTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn fetch_add_assertion_id() -> usize {
+    ASSERTION_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// The predicate of the literal that `findall(<template>, <goal>, <list>)` is parsed into,
+/// `_findall(<template>, <list>, "<goal predicate>", <goal args>...)`, see [`crate::sld`].
+pub const FINDALL_PREDICATE: &str = "_findall";
+
+impl Literal {
+    /// The predicate of the goal of a `findall`.
+    pub fn findall_goal(&self) -> Option<&str> {
+        match (self.predicate.0.as_str(), self.args.get(2)) {
+            (FINDALL_PREDICATE, Some(ModusTerm::Constant(goal))) => Some(goal),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -430,9 +547,76 @@ pub struct Version {
     build: String,
 }
 
+/// The diagnostic code of all syntax errors.
+pub const PARSE_ERROR_CODE: &str = "parse-error";
+
 /// Combines nom_supreme's error tree type, codespan's reporting and some custom logic
 /// that selects only a subset of a span to produce better error messages.
-fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
+pub(crate) fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
+    /// Whether the bracket that `rest` starts with is closed later on, skipping strings.
+    fn is_closed(rest: &str, open: char, close: char) -> bool {
+        let mut depth = 0;
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// A hint on how to fix the mistakes that are most often made, given the input that
+    /// could not be parsed.
+    fn hint(rest: &str, kind: &BaseErrorKind) -> Option<&'static str> {
+        use nom_supreme::error::Expectation;
+        use tokens::*;
+
+        match kind {
+            // The clause may be over, or a bracket that can't be parsed is unbalanced.
+            BaseErrorKind::Expected(Expectation::Char(CLAUSE_END)) => match rest.chars().next() {
+                Some('(') if !is_closed(rest, '(', ')') => Some("the `(` is not closed by a `)`"),
+                Some('[') if !is_closed(rest, '[', ']') => Some("the `[` is not closed by a `]`"),
+                Some(')') => Some("the `)` closes no `(`"),
+                Some(']') => Some("the `]` closes no `[`"),
+                _ => Some("expected `.` to end the clause"),
+            },
+            BaseErrorKind::Expected(Expectation::Tag(ARGS_END)) => {
+                Some("expected `)` to close the parenthesis")
+            }
+            BaseErrorKind::Expected(Expectation::Tag(LIST_END)) => {
+                Some("expected `]` to end the list")
+            }
+            BaseErrorKind::Expected(Expectation::Tag(INTERPOLATION_END)) => {
+                Some("expected `}` to end the interpolation")
+            }
+            BaseErrorKind::Expected(Expectation::Tag(STRING_DELIMITER | RAW_STRING_DELIMITER)) => {
+                Some("the string is not terminated")
+            }
+            BaseErrorKind::Expected(Expectation::Eof) => {
+                Some("expected the start of a clause, e.g. `a.` or `a :- b.`")
+            }
+            _ => None,
+        }
+    }
+
     fn generate_base_label(span: &Span, kind: &BaseErrorKind) -> Label<()> {
         let length = if let BaseErrorKind::Expected(nom_supreme::error::Expectation::Tag(t)) = kind
         {
@@ -442,7 +626,11 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
             // (Displaying the full span could be the entire rest of the source file.)
             1
         };
-        Label::primary((), span.location_offset()..span.location_offset() + length)
+        let label = Label::primary((), span.location_offset()..span.location_offset() + length);
+        match hint(span.fragment(), kind) {
+            Some(hint) => label.with_message(hint),
+            None => label,
+        }
     }
 
     /// Generate labels for diagnostic messages using the given stack context.
@@ -480,6 +668,7 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
                     .chain(generate_labels(contexts, base_range))
                     .collect();
                 let diag = Diagnostic::error()
+                    .with_code(PARSE_ERROR_CODE)
                     .with_message(kind.to_string())
                     .with_labels(labels);
                 diags.push(diag);
@@ -496,7 +685,9 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
                     ErrorTree::Base { location, kind } => {
                         labels.push(generate_base_label(&location, &kind));
                         base_range = labels[0].range.clone();
-                        diag = Diagnostic::error().with_message(kind.to_string());
+                        diag = Diagnostic::error()
+                            .with_code(PARSE_ERROR_CODE)
+                            .with_message(kind.to_string());
                     }
                     ErrorTree::Stack { .. } => panic!("base of an error stack was a stack"),
                     ErrorTree::Alt(alts) => {
@@ -540,10 +731,11 @@ impl str::FromStr for Modusfile {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let span = Span::new(s);
-        match parser::modusfile(span) {
-            Result::Ok((_, o)) => Ok(o),
-            Result::Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+        let (mf, errors) = parser::modusfile_with_recovery(span);
+        if errors.is_empty() {
+            crate::library::load_libraries(mf)
+        } else {
+            Err(errors.into_iter().flat_map(better_convert_error).collect())
         }
     }
 }
@@ -556,7 +748,7 @@ impl str::FromStr for Expression {
         match parser::body(span) {
             Ok((_, o)) => Ok(o),
             Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+            _ => Err(incomplete_input()),
         }
     }
 }
@@ -565,9 +757,9 @@ impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expression::OperatorApplication(_, expr, op) => {
-                write!(f, "({})::{}", expr.to_string(), op)
+                write!(f, "({})::{}", expr, op)
             }
-            Expression::Literal(l) => write!(f, "{}", l.to_string()),
+            Expression::Literal(l) => write!(f, "{}", l),
             Expression::And(_, positive, expr1, expr2) => {
                 // Explicit parenthesization when printing to output, looks a bit
                 // verbose but shouldn't affect user code.
@@ -613,8 +805,10 @@ impl str::FromStr for ModusClause {
 
 impl fmt::Display for ModusClause {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(e) = &self.body {
-            write!(f, "{} :- {}.", self.head, e.to_string(),)
+        if let (true, Some(e)) = (self.head.predicate.is_assertion(), &self.body) {
+            write!(f, "{} {}.", tokens::QUERY_NECK, e)
+        } else if let Some(e) = &self.body {
+            write!(f, "{} :- {}.", self.head, e,)
         } else {
             write!(f, "{}.", self.head)
         }
@@ -633,29 +827,127 @@ impl str::FromStr for Literal {
     }
 }
 
+/// The tokens of the Modusfile syntax. The parser is written in terms of these, and
+/// so are the editor grammars generated by `modus grammar`, which keeps them in sync.
+pub mod tokens {
+    pub const COMMENT: &str = "#";
+    /// Starts a comment that documents the clause on the next line, as in
+    /// `## Installs the package with apt.`
+    pub const DOC_COMMENT: &str = "##";
+    pub const RULE_NECK: &str = ":-";
+    /// Starts a query directive, which `modus check` and `modus proof` solve, as in
+    /// `?- base_image(\"alpine\").`
+    pub const QUERY_NECK: &str = "?-";
+    /// The directive that loads a library, as in `:- use(stdlib).`
+    pub const USE: &str = "use";
+    /// The directive that replaces the prelude, as in `:- prelude(none).`
+    pub const PRELUDE: &str = "prelude";
+    /// The directive that includes a remote Modusfile, as in
+    /// `:- include("https://example.com/python.Modusfile", sha256="...").`
+    pub const INCLUDE: &str = "include";
+    /// Names the digest that an included Modusfile is pinned to.
+    pub const INCLUDE_DIGEST: &str = "sha256";
+    /// The directive that imports a local Modusfile, as in `:- import("lib/base.Modusfile").`
+    pub const IMPORT: &str = "import";
+    /// Names the prefix of the predicates of an imported Modusfile, as in
+    /// `:- import("lib/base.Modusfile", as=base).`
+    pub const IMPORT_NAMESPACE: &str = "as";
+    pub const CLAUSE_END: char = '.';
+    pub const AND: &str = ",";
+    pub const OR: &str = ";";
+    /// Separates the condition of an if-then-else from its branches, as in
+    /// `(is_debian -> run("apt-get install -y make") ; run("apk add make"))`.
+    pub const IF_THEN: &str = "->";
+    /// Collects the solutions of a goal into a list, as in `findall(P, needs_pkg(P), Ps)`.
+    pub const FINDALL: &str = "findall";
+    pub const NEGATION: char = '!';
+    pub const OPERATOR_APPLICATION: &str = "::";
+    pub const EQUALS: &str = "=";
+    pub const NOT_EQUALS: &str = "!=";
+    pub const GREATER_EQUALS: &str = ">=";
+    pub const LESS_EQUALS: &str = "<=";
+    pub const GREATER: &str = ">";
+    pub const LESS: &str = "<";
+    pub const ARGS_START: &str = "(";
+    pub const ARGS_END: &str = ")";
+    pub const ARG_SEPARATOR: &str = ",";
+    pub const LIST_START: &str = "[";
+    pub const LIST_END: &str = "]";
+
+    pub const STRING_DELIMITER: &str = "\"";
+    /// Delimits raw strings, which may span multiple lines and are not escaped.
+    pub const RAW_STRING_DELIMITER: &str = "\"\"\"";
+    /// Starts a single-quoted raw string, as in `r"C:\path"`, which is not escaped either.
+    pub const RAW_STRING_PREFIX: &str = "r";
+    /// Any number of these can be put between the prefix and the quote of a raw string,
+    /// and closes it after the quote, as in `r#"echo "hi""#`, so that it can contain quotes.
+    pub const RAW_STRING_HASH: char = '#';
+    pub const FORMAT_STRING_START: &str = "f\"";
+    pub const INTERPOLATION_START: &str = "${";
+    pub const INTERPOLATION_END: &str = "}";
+    pub const ESCAPE: char = '\\';
+    /// Characters that may follow the escape character in a string.
+    pub const STRING_ESCAPE_CHARS: &str = "\"\\nrt0\n";
+    /// Characters that may follow the escape character in a format string.
+    pub const FORMAT_STRING_ESCAPE_CHARS: &str = "$\"\\nrt0\n";
+    /// Starts an ASCII escape with two hex digits, such as `\x7f`.
+    pub const HEX_ESCAPE: char = 'x';
+    /// Starts a unicode escape with up to six hex digits, such as `\u{1F600}`.
+    pub const UNICODE_ESCAPE_START: &str = "u{";
+    pub const UNICODE_ESCAPE_END: &str = "}";
+    /// A regular expression matching what may follow the escape character in a hex or unicode escape.
+    pub const NUMERIC_ESCAPE_REGEX: &str = "x[0-9a-fA-F]{2}|u\\{[0-9a-fA-F]{1,6}\\}";
+
+    pub const ANONYMOUS_VARIABLE: &str = "_";
+    pub const TRUE: &str = "true";
+    pub const FALSE: &str = "false";
+    /// Starts a reference to a named build context, as in `from(context("builder"))`.
+    pub const NAMED_CONTEXT: &str = "context";
+    /// The prefix of the constant that a named context reference is parsed into. It
+    /// cannot be the start of an image reference.
+    pub const NAMED_CONTEXT_PREFIX: &str = "context://";
+    /// Starts a reference to an OCI image layout directory, as in
+    /// `from(oci_layout("./base-oci"))`.
+    pub const OCI_LAYOUT: &str = "oci_layout";
+    /// The prefix of the constant that an OCI layout reference is parsed into.
+    pub const OCI_LAYOUT_PREFIX: &str = "oci-layout://";
+    /// Starts a reference to an image saved with `docker save`, as in
+    /// `from(docker_tar("./base.tar"))`.
+    pub const DOCKER_TAR: &str = "docker_tar";
+    /// The prefix of the constant that a docker tarball reference is parsed into.
+    pub const DOCKER_TAR_PREFIX: &str = "docker-tar://";
+    /// A regular expression matching unquoted numbers, equivalent to `parser::modus_number`.
+    pub const NUMBER_REGEX: &str = "-?[0-9]+(\\.[0-9]+)?";
+    /// A regular expression matching the identifiers of predicates, operators
+    /// and variables, equivalent to `logic::parser::literal_identifier`.
+    pub const IDENTIFIER_REGEX: &str = "[A-Za-z_][A-Za-z0-9_-]*";
+}
+
 pub mod parser {
     use crate::logic::parser::{literal, literal_identifier, recognized_span, IResult};
     use crate::logic::Predicate;
 
+    use super::tokens::*;
     use super::*;
 
-    use nom::bytes::complete::{escaped, is_a};
-    use nom::character::complete::{multispace0, none_of, one_of};
-    use nom::combinator::{cut, opt, recognize};
+    use nom::bytes::complete::{escaped, is_a, take_until, take_while_m_n};
+    use nom::character::complete::{char, digit1, multispace0, none_of, one_of, satisfy};
+    use nom::combinator::{cut, not, opt, recognize, value, verify};
     use nom::error::context;
-    use nom::multi::{many0_count, many1, separated_list0, separated_list1};
+    use nom::multi::{count, many0_count, many1, separated_list0, separated_list1};
     use nom::sequence::{pair, tuple};
     use nom::{
         branch::alt,
         combinator::{eof, map},
         multi::many0,
         sequence::{delimited, preceded, separated_pair, terminated},
+        Slice,
     };
     use nom_supreme::tag::complete::tag;
 
     fn comment(s: Span) -> IResult<Span, Span> {
         recognize(delimited(
-            tag("#"),
+            tag(COMMENT),
             opt(not_line_ending),
             alt((line_ending, eof)),
         ))(s)
@@ -726,18 +1018,87 @@ pub mod parser {
         map(
             recognized_span(tuple((
                 modus_term,
-                delimited(token_sep0, alt((tag("!="), tag("="))), token_sep0),
+                delimited(token_sep0, alt((tag(NOT_EQUALS), tag(EQUALS))), token_sep0),
                 cut(modus_term),
             ))),
             |(spanned_pos, (t1, op, t2))| Literal {
                 positive: op.fragment().len() == 1,
                 position: Some(spanned_pos),
-                predicate: Predicate("string_eq".to_owned()),
+                predicate: Predicate("string_eq".into()),
                 args: vec![t1, t2],
             },
         )(i)
     }
 
+    /// Parses `findall(<template>, <goal>, <list>)` into the literal
+    /// `_findall(<template>, <list>, "<goal predicate>", <goal args>...)`.
+    fn findall_sugar(i: Span) -> IResult<Span, Literal> {
+        let arg_sep = |i| delimited(token_sep0, tag(ARG_SEPARATOR), token_sep0)(i);
+        map(
+            recognized_span(preceded(
+                terminated(tag(FINDALL), token_sep0),
+                delimited(
+                    terminated(tag(ARGS_START), token_sep0),
+                    tuple((
+                        terminated(modus_term, arg_sep),
+                        terminated(
+                            verify(literal(modus_term, token_sep0), |l: &Literal| l.positive),
+                            arg_sep,
+                        ),
+                        cut(modus_term),
+                    )),
+                    cut(preceded(token_sep0, tag(ARGS_END))),
+                ),
+            )),
+            |(spanned_pos, (template, goal, list))| Literal {
+                positive: true,
+                position: Some(spanned_pos),
+                predicate: Predicate(FINDALL_PREDICATE.into()),
+                args: vec![
+                    template,
+                    list,
+                    ModusTerm::Constant(goal.predicate.0.to_string()),
+                ]
+                .into_iter()
+                .chain(goal.args)
+                .collect(),
+            },
+        )(i)
+    }
+
+    /// Parses `<term1> > <term2>` into a builtin call, `number_gt(term1, term2)`, and
+    /// similarly for `<`, `>=` and `<=`.
+    /// If either term is a constant that is not a number, e.g. `Version >= "1.2.0"`, the
+    /// terms are compared as versions instead, with `semver_geq(term1, term2)`.
+    fn comparison_sugar(i: Span) -> IResult<Span, Literal> {
+        let comparison = alt((
+            map(tag(GREATER_EQUALS), |_| ("number_geq", "semver_geq")),
+            map(tag(LESS_EQUALS), |_| ("number_leq", "semver_leq")),
+            map(tag(GREATER), |_| ("number_gt", "semver_gt")),
+            map(tag(LESS), |_| ("number_lt", "semver_lt")),
+        ));
+        map(
+            recognized_span(tuple((
+                modus_term,
+                delimited(token_sep0, comparison, token_sep0),
+                cut(modus_term),
+            ))),
+            |(spanned_pos, (t1, (number, semver), t2))| {
+                let predicate = if t1.is_non_numeric_constant() || t2.is_non_numeric_constant() {
+                    semver
+                } else {
+                    number
+                };
+                Literal {
+                    positive: true,
+                    position: Some(spanned_pos),
+                    predicate: Predicate(predicate.into()),
+                    args: vec![t1, t2],
+                }
+            },
+        )(i)
+    }
+
     fn modus_literal(i: Span) -> IResult<Span, Expression> {
         context(
             stringify!(modus_literal),
@@ -745,15 +1106,70 @@ pub mod parser {
         )(i)
     }
 
+    /// Parses `cond -> then ; else` into `(cond, then ; !cond, else)`, so the else
+    /// branch is only taken if the condition can't be proven. Without an else branch, it
+    /// is `(cond, then)`. The else branch may be another if-then-else, as in
+    /// `c1 -> t1 ; c2 -> t2 ; e`.
+    ///
+    /// Unlike in Prolog, the condition is not committed to: like any negation, `!cond` is
+    /// checked once the variables of the condition are ground, which may be after the
+    /// else branch binds them. So `(p(X) -> a ; X = "z")` holds with `X = "z"` unless
+    /// `p("z")` does, whether `p("x")` holds or not. Variables that only the condition
+    /// uses are never ground there, and make the else branch an insufficient groundness
+    /// error; they should be anonymous, as in `(p(_) -> a ; b)`.
+    fn if_then_else(i: Span) -> IResult<Span, Expression> {
+        let if_then_else = map(
+            recognized_span(tuple((
+                // an operator application can't be negated
+                verify(conjunction, |e| {
+                    !matches!(e, Expression::OperatorApplication(..))
+                }),
+                delimited(token_sep0, tag(IF_THEN), token_sep0),
+                cut(conjunction),
+                opt(preceded(
+                    delimited(token_sep0, tag(OR), token_sep0),
+                    cut(alt((if_then_else, body))),
+                )),
+            ))),
+            |(span, (cond, _, then, otherwise))| {
+                let span = Some(span);
+                let then =
+                    Expression::And(span.clone(), true, Box::new(cond.clone()), Box::new(then));
+                match otherwise {
+                    Some(otherwise) => Expression::Or(
+                        span.clone(),
+                        true,
+                        Box::new(then),
+                        Box::new(Expression::And(
+                            span,
+                            true,
+                            Box::new(cond.negate_current()),
+                            Box::new(otherwise),
+                        )),
+                    ),
+                    None => then,
+                }
+            },
+        );
+        preceded(token_sep0, if_then_else)(i)
+    }
+
     /// Parses a parenthesized expression, taking into account any preceding negation.
     fn parenthesized_expr(i: Span) -> IResult<Span, Expression> {
-        let l_paren_with_comments = |i| terminated(tag("("), comments)(i);
-        let r_paren_with_comments = |i| preceded(comments, cut(tag(")")))(i);
+        let l_paren_with_comments = |i| terminated(tag(ARGS_START), comments)(i);
+        let r_paren_with_comments = |i| preceded(comments, cut(tag(ARGS_END)))(i);
 
         map(
             pair(
-                many0_count(terminated(nom::character::complete::char('!'), token_sep0)),
-                delimited(l_paren_with_comments, cut(body), r_paren_with_comments),
+                many0_count(terminated(
+                    nom::character::complete::char(NEGATION),
+                    token_sep0,
+                )),
+                delimited(
+                    l_paren_with_comments,
+                    cut(alt((if_then_else, body))),
+                    r_paren_with_comments,
+                ),
             ),
             |(neg_count, expr)| {
                 if neg_count % 2 == 0 {
@@ -772,17 +1188,17 @@ pub mod parser {
             recognized_span(pair(
                 terminated(literal_identifier, token_sep0),
                 opt(delimited(
-                    terminated(tag("("), token_sep0),
+                    terminated(tag(ARGS_START), token_sep0),
                     separated_list1(
-                        terminated(tag(","), token_sep0),
+                        terminated(tag(ARG_SEPARATOR), token_sep0),
                         terminated(modus_term, token_sep0),
                     ),
-                    cut(terminated(tag(")"), token_sep0)),
+                    cut(terminated(tag(ARGS_END), token_sep0)),
                 )),
             )),
             |(spanned_pos, (name, args))| Operator {
                 position: Some(spanned_pos),
-                predicate: Predicate(name.fragment().to_string()),
+                predicate: Predicate((*name.fragment()).into()),
                 args: args.unwrap_or(Vec::new()),
             },
         )(i)
@@ -790,13 +1206,14 @@ pub mod parser {
 
     fn expression_inner(i: Span) -> IResult<Span, Expression> {
         let unification_expr_parser = map(unification_sugar, Expression::Literal);
+        let comparison_expr_parser = map(comparison_sugar, Expression::Literal);
         // These inner expression parsers can fully recurse.
         let op_application_parser = map(
             pair(
                 alt((modus_literal, parenthesized_expr)),
                 // :: separated list of operators
                 many1(recognized_span(preceded(
-                    delimited(token_sep0, tag("::"), token_sep0),
+                    delimited(token_sep0, tag(OPERATOR_APPLICATION), token_sep0),
                     cut(operator),
                 ))),
             ),
@@ -815,17 +1232,20 @@ pub mod parser {
             },
         );
         alt((
+            map(findall_sugar, Expression::Literal),
             context("unification", unification_expr_parser),
+            context("comparison", comparison_expr_parser),
             context("op_application", op_application_parser),
             modus_literal,
             parenthesized_expr,
         ))(i)
     }
 
-    pub fn body(i: Span) -> IResult<Span, Expression> {
-        let comma_separated_exprs = map(
+    /// Parses a comma separated list of inner expressions.
+    fn conjunction(i: Span) -> IResult<Span, Expression> {
+        map(
             separated_list1(
-                delimited(token_sep0, tag(","), token_sep0),
+                delimited(token_sep0, tag(AND), token_sep0),
                 expression_inner,
             ),
             |es| {
@@ -844,12 +1264,12 @@ pub mod parser {
                     })
                     .expect("Converting list to expression pairs.")
             },
-        );
+        )(i)
+    }
+
+    pub fn body(i: Span) -> IResult<Span, Expression> {
         let semi_separated_exprs = map(
-            separated_list1(
-                delimited(token_sep0, tag(";"), token_sep0),
-                comma_separated_exprs,
-            ),
+            separated_list1(delimited(token_sep0, tag(OR), token_sep0), conjunction),
             |es| {
                 es.into_iter()
                     .reduce(|e1, e2| {
@@ -881,11 +1301,12 @@ pub mod parser {
                     // NOTE: this is a failure ('cut') assuming the rule parser failed,
                     // however if this is tried *before* the rule parser, this shouldn't be a
                     // failure. This is just one of the subtleties of a parser combinator.
-                    cut(terminated(nom::character::complete::char('.'), token_sep0)),
+                    cut(nom::character::complete::char(CLAUSE_END)),
                 ),
                 |h| ModusClause {
                     head: h,
                     body: None,
+                    position: None,
                 },
             ),
         )(i)
@@ -897,34 +1318,69 @@ pub mod parser {
             map(
                 separated_pair(
                     head,
-                    delimited(token_sep0, tag(":-"), token_sep0),
+                    delimited(token_sep0, tag(RULE_NECK), token_sep0),
                     cut(context(
                         "rule_body",
-                        terminated(
-                            body,
-                            cut(terminated(nom::character::complete::char('.'), token_sep0)),
-                        ),
+                        terminated(body, cut(nom::character::complete::char(CLAUSE_END))),
                     )),
                 ),
                 |(head, body)| ModusClause {
                     head,
                     body: Some(body),
+                    position: None,
                 },
             ),
         )(i)
     }
 
+    /// The character of a hex escape, which like in Rust is limited to ASCII.
+    fn hex_escape_char(digits: &str) -> Option<char> {
+        if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        u8::from_str_radix(digits, 16)
+            .ok()
+            .filter(u8::is_ascii)
+            .map(char::from)
+    }
+
+    /// The character of a unicode escape, which must be a unicode scalar value.
+    fn unicode_escape_char(digits: &str) -> Option<char> {
+        if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+    }
+
+    /// Escapes the given string so that `process_raw_string` turns it back into the same string,
+    /// e.g. to construct a `ModusTerm::Constant` from arbitrary content.
+    pub fn escape_constant(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if c == ESCAPE || STRING_DELIMITER.starts_with(c) {
+                escaped.push(ESCAPE);
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
     /// Processes the given string, converting escape substrings into the proper characters.
     ///
     /// This also supports string continuation, This allows users to write strings like: "Hello, \
     ///                                                                                   World!"
     /// which is actually just "Hello, World!".
-    pub fn process_raw_string(s: &str) -> String {
+    ///
+    /// Returns an error if the string ends with an escape character or contains a malformed
+    /// hex or unicode escape.
+    pub fn process_raw_string(s: &str) -> Result<String, String> {
         let mut processed = String::new();
 
         let mut chars = s.chars().peekable();
         while let Some(c) = chars.next() {
-            if c == '\\' {
+            if c == ESCAPE {
                 match chars.next() {
                     Some('"') => processed.push('"'),
                     Some('\\') => processed.push('\\'),
@@ -932,6 +1388,39 @@ pub mod parser {
                     Some('r') => processed.push('\r'),
                     Some('t') => processed.push('\t'),
                     Some('0') => processed.push('\0'),
+                    Some(HEX_ESCAPE) => {
+                        let digits: String = chars.by_ref().take(2).collect();
+                        let c = hex_escape_char(&digits).ok_or_else(|| {
+                            format!(
+                                "invalid hex escape `{}{}{}`, expected two hex digits of at most 7f",
+                                ESCAPE, HEX_ESCAPE, digits
+                            )
+                        })?;
+                        processed.push(c);
+                    }
+                    Some(c) if UNICODE_ESCAPE_START.starts_with(c) => {
+                        let mut escape = c.to_string();
+                        let mut terminated = false;
+                        for c in chars.by_ref() {
+                            escape.push(c);
+                            if escape.ends_with(UNICODE_ESCAPE_END) {
+                                terminated = true;
+                                break;
+                            }
+                        }
+                        let c = escape
+                            .strip_prefix(UNICODE_ESCAPE_START)
+                            .and_then(|e| e.strip_suffix(UNICODE_ESCAPE_END))
+                            .filter(|_| terminated)
+                            .and_then(unicode_escape_char)
+                            .ok_or_else(|| {
+                                format!(
+                                    "invalid unicode escape `{}{}`, expected up to six hex digits of a unicode scalar value",
+                                    ESCAPE, escape
+                                )
+                            })?;
+                        processed.push(c);
+                    }
                     Some('\n') => {
                         // string continuation so we'll ignore whitespace till we get to a non-whitespace.
                         while let Some(c) = chars.peek() {
@@ -946,22 +1435,50 @@ pub mod parser {
                         processed.push('\\');
                         processed.push(c);
                     }
-                    None => panic!("given string ends with an escape character"),
+                    None => return Err("string ends with an escape character".to_string()),
                 }
             } else {
                 processed.push(c);
             }
         }
-        processed
+        Ok(processed)
     }
 
-    const STRING_ESCAPE_CHARS: &str = "\"\\nrt0\n";
-    const FORMAT_STRING_ESCAPE_CHARS: &str = "$\"\\nrt0\n";
+    /// Parses what follows the escape character in a string, which is either one of the given
+    /// characters or a hex or unicode escape.
+    fn escape_sequence<'a>(
+        escape_chars: &'static str,
+    ) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Span<'a>> {
+        let hex_digits = |min, max| take_while_m_n(min, max, |c: char| c.is_ascii_hexdigit());
+        context(
+            stringify!(escape_sequence),
+            alt((
+                recognize(one_of(escape_chars)),
+                recognize(preceded(
+                    char(HEX_ESCAPE),
+                    verify(hex_digits(2, 2), |s: &Span| {
+                        hex_escape_char(s.fragment()).is_some()
+                    }),
+                )),
+                recognize(delimited(
+                    tag(UNICODE_ESCAPE_START),
+                    verify(hex_digits(1, 6), |s: &Span| {
+                        unicode_escape_char(s.fragment()).is_some()
+                    }),
+                    tag(UNICODE_ESCAPE_END),
+                )),
+            )),
+        )
+    }
 
     /// Parses a string that possibly contains escaped characters, but doesn't actually
     /// convert the escape characters.
     fn string_content(i: Span) -> IResult<Span, String> {
-        let escape_parser = escaped(none_of("\\\""), '\\', one_of(STRING_ESCAPE_CHARS));
+        let escape_parser = escaped(
+            none_of("\\\""),
+            ESCAPE,
+            cut(escape_sequence(STRING_ESCAPE_CHARS)),
+        );
         let (i, o) = opt(escape_parser)(i)?;
         let parsed_str: &str = o.map(|span| *span.fragment()).unwrap_or("");
         Ok((i, parsed_str.to_owned()))
@@ -975,17 +1492,59 @@ pub mod parser {
         // ideally `one_of` would just have a better error type.
         let (i, o) = escaped(
             none_of("\\\"$"),
-            '\\',
-            cut(one_of(FORMAT_STRING_ESCAPE_CHARS)),
+            ESCAPE,
+            cut(escape_sequence(FORMAT_STRING_ESCAPE_CHARS)),
         )(i)?;
         let parsed_str: &str = o.fragment();
         Ok((i, parsed_str.to_owned()))
     }
 
+    /// Parses the content of a raw string, escaping it so that it is left unchanged by
+    /// `process_raw_string`, like the content of other constants.
+    ///
+    /// A line break directly after the opening delimiter is not part of the string.
+    fn raw_string_content(i: Span) -> IResult<Span, String> {
+        let (i, o) = take_until(RAW_STRING_DELIMITER)(i)?;
+        let content = o.fragment();
+        let content = content
+            .strip_prefix("\r\n")
+            .or_else(|| content.strip_prefix('\n'))
+            .unwrap_or(content);
+        Ok((i, escape_constant(content)))
+    }
+
+    /// Parses a raw string with a prefix, `r"<content>"` or `r#"<content>"#` with any
+    /// number of `#`, escaping its content like `raw_string_content`.
+    fn prefixed_raw_string(i: Span) -> IResult<Span, String> {
+        let (i, hashes) = delimited(
+            tag(RAW_STRING_PREFIX),
+            recognize(many0_count(char(RAW_STRING_HASH))),
+            tag(STRING_DELIMITER),
+        )(i)?;
+        let end = format!("{}{}", STRING_DELIMITER, hashes.fragment());
+        let (i, content) = cut(take_until(end.as_str()))(i)?;
+        let (i, _) = preceded(
+            tag(STRING_DELIMITER),
+            count(char(RAW_STRING_HASH), hashes.fragment().len()),
+        )(i)?;
+        Ok((i, escape_constant(content.fragment())))
+    }
+
     pub fn modus_const(i: Span) -> IResult<Span, String> {
         context(
             stringify!(modus_const),
-            delimited(tag("\""), string_content, cut(tag("\""))),
+            alt((
+                prefixed_raw_string,
+                preceded(
+                    tag(RAW_STRING_DELIMITER),
+                    cut(terminated(raw_string_content, tag(RAW_STRING_DELIMITER))),
+                ),
+                delimited(
+                    tag(STRING_DELIMITER),
+                    string_content,
+                    cut(tag(STRING_DELIMITER)),
+                ),
+            )),
         )(i)
     }
 
@@ -1018,9 +1577,9 @@ pub mod parser {
         context(
             stringify!(modus_format_string),
             recognized_span(delimited(
-                tag("f\""),
+                tag(FORMAT_STRING_START),
                 cut(many0(format_string_fragment)),
-                cut(tag("\"")),
+                cut(tag(STRING_DELIMITER)),
             )),
         )(i)
     }
@@ -1035,17 +1594,93 @@ pub mod parser {
 
     pub fn string_interpolation(i: Span) -> IResult<Span, Span> {
         delimited(
-            terminated(tag("${"), token_sep0),
+            terminated(tag(INTERPOLATION_START), token_sep0),
             cut(modus_var),
-            cut(preceded(token_sep0, tag("}"))),
+            cut(preceded(token_sep0, tag(INTERPOLATION_END))),
         )(i)
     }
 
     fn modus_list_term(i: Span) -> IResult<Span, Vec<ModusTerm>> {
         delimited(
-            terminated(tag("["), token_sep0),
-            separated_list0(delimited(token_sep0, tag(","), token_sep0), modus_term),
-            cut(preceded(token_sep0, tag("]"))),
+            terminated(tag(LIST_START), token_sep0),
+            separated_list0(
+                delimited(token_sep0, tag(ARG_SEPARATOR), token_sep0),
+                modus_term,
+            ),
+            cut(preceded(token_sep0, tag(LIST_END))),
+        )(i)
+    }
+
+    /// Parses an unquoted integer or decimal number. Numbers are not a kind of term of their
+    /// own: an unquoted number is the constant of its text, so `expose(8080)` is the same
+    /// literal as `expose("8080")` and `3.10` differs from `3.1`, as versions should. The
+    /// number builtins read such constants by their value.
+    pub fn modus_number(i: Span) -> IResult<Span, String> {
+        context(
+            stringify!(modus_number),
+            map(
+                recognize(tuple((
+                    opt(char('-')),
+                    digit1,
+                    opt(pair(char('.'), digit1)),
+                ))),
+                |s: Span| s.fragment().to_string(),
+            ),
+        )(i)
+    }
+
+    /// Parses a boolean constant, as long as it is not the prefix of a variable name.
+    pub fn modus_boolean(i: Span) -> IResult<Span, String> {
+        context(
+            stringify!(modus_boolean),
+            map(
+                terminated(
+                    alt((tag(TRUE), tag(FALSE))),
+                    not(satisfy(|c| {
+                        c.is_ascii_alphanumeric() || c == '_' || c == '-'
+                    })),
+                ),
+                |s: Span| s.fragment().to_string(),
+            ),
+        )(i)
+    }
+
+    /// Parses `<keyword>("<value>")` into the constant `"<prefix><value>"`.
+    fn prefixed_reference<'a>(
+        keyword: &'static str,
+        prefix: &'static str,
+    ) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, String> {
+        map(
+            preceded(
+                pair(tag(keyword), tag(ARGS_START)),
+                cut(terminated(
+                    delimited(token_sep0, modus_const, token_sep0),
+                    tag(ARGS_END),
+                )),
+            ),
+            move |value| format!("{}{}", prefix, value),
+        )
+    }
+
+    /// Parses a reference to a named build context, e.g. `context("builder")`, into the
+    /// constant `"context://builder"`.
+    pub fn modus_named_context(i: Span) -> IResult<Span, String> {
+        context(
+            stringify!(modus_named_context),
+            prefixed_reference(NAMED_CONTEXT, NAMED_CONTEXT_PREFIX),
+        )(i)
+    }
+
+    /// Parses a reference to an image in a local file, e.g. `oci_layout("./base-oci")` or
+    /// `docker_tar("./base.tar")`, into the constant `"oci-layout://./base-oci"` or
+    /// `"docker-tar://./base.tar"`.
+    pub fn modus_local_image(i: Span) -> IResult<Span, String> {
+        context(
+            stringify!(modus_local_image),
+            alt((
+                prefixed_reference(OCI_LAYOUT, OCI_LAYOUT_PREFIX),
+                prefixed_reference(DOCKER_TAR, DOCKER_TAR_PREFIX),
+            )),
         )(i)
     }
 
@@ -1054,6 +1689,9 @@ pub mod parser {
             stringify!(modus_term),
             alt((
                 map(modus_const, ModusTerm::Constant),
+                map(modus_number, ModusTerm::Constant),
+                map(modus_named_context, ModusTerm::Constant),
+                map(modus_local_image, ModusTerm::Constant),
                 map(recognized_span(modus_list_term), |(span, terms)| {
                     ModusTerm::List(span, terms)
                 }),
@@ -1063,7 +1701,8 @@ pub mod parser {
                         fragments,
                     }
                 }),
-                map(is_a("_"), |_| ModusTerm::AnonymousVariable),
+                map(is_a(ANONYMOUS_VARIABLE), |_| ModusTerm::AnonymousVariable),
+                map(modus_boolean, ModusTerm::Constant),
                 map(modus_var, |s| {
                     ModusTerm::UserVariable(s.fragment().to_string())
                 }),
@@ -1071,8 +1710,198 @@ pub mod parser {
         )(i)
     }
 
+    /// Parses the directives `:- use(<library>).` and `:- prelude(<library>).` into the
+    /// facts `_use("<library>")` and `_prelude("<library>")`. The library is named by a
+    /// string or an identifier, as in `:- use(stdlib).` or `:- use("stdlib@1").`
+    fn directive(i: Span) -> IResult<Span, ModusClause> {
+        context(
+            stringify!(directive),
+            map(
+                terminated(
+                    recognized_span(preceded(
+                        terminated(tag(RULE_NECK), token_sep0),
+                        pair(
+                            alt((
+                                value(USE_PREDICATE, tag(USE)),
+                                value(PRELUDE_PREDICATE, tag(PRELUDE)),
+                            )),
+                            cut(delimited(
+                                delimited(token_sep0, tag(ARGS_START), token_sep0),
+                                alt((
+                                    modus_const,
+                                    map(literal_identifier, |s| s.fragment().to_string()),
+                                )),
+                                preceded(token_sep0, tag(ARGS_END)),
+                            )),
+                        ),
+                    )),
+                    cut(nom::character::complete::char(CLAUSE_END)),
+                ),
+                |(position, (predicate, library))| ModusClause {
+                    head: Literal {
+                        positive: true,
+                        position: Some(position),
+                        predicate: Predicate(predicate.into()),
+                        args: vec![ModusTerm::Constant(library)],
+                    },
+                    body: None,
+                    position: None,
+                },
+            ),
+        )(i)
+    }
+
+    /// Parses the directive `:- include("<url>", sha256="<digest>").` into the fact
+    /// `_include("<url>", "<digest>")`. Without a digest, it is parsed into
+    /// `_include("<url>")`, which is rejected when the Modusfile is loaded.
+    fn include_directive(i: Span) -> IResult<Span, ModusClause> {
+        context(
+            stringify!(include_directive),
+            map(
+                terminated(
+                    recognized_span(preceded(
+                        terminated(tag(RULE_NECK), token_sep0),
+                        preceded(
+                            tag(INCLUDE),
+                            cut(delimited(
+                                delimited(token_sep0, tag(ARGS_START), token_sep0),
+                                pair(
+                                    modus_const,
+                                    opt(preceded(
+                                        tuple((
+                                            token_sep0,
+                                            tag(ARG_SEPARATOR),
+                                            token_sep0,
+                                            tag(INCLUDE_DIGEST),
+                                            token_sep0,
+                                            tag(EQUALS),
+                                            token_sep0,
+                                        )),
+                                        modus_const,
+                                    )),
+                                ),
+                                preceded(token_sep0, tag(ARGS_END)),
+                            )),
+                        ),
+                    )),
+                    cut(nom::character::complete::char(CLAUSE_END)),
+                ),
+                |(position, (url, digest))| ModusClause {
+                    head: Literal {
+                        positive: true,
+                        position: Some(position),
+                        predicate: Predicate(INCLUDE_PREDICATE.into()),
+                        args: std::iter::once(url)
+                            .chain(digest)
+                            .map(ModusTerm::Constant)
+                            .collect(),
+                    },
+                    body: None,
+                    position: None,
+                },
+            ),
+        )(i)
+    }
+
+    /// Parses the directive `:- import("<path>", as=<namespace>).` into the fact
+    /// `_import("<path>", "<namespace>")`, or `_import("<path>")` without a namespace.
+    fn import_directive(i: Span) -> IResult<Span, ModusClause> {
+        context(
+            stringify!(import_directive),
+            map(
+                terminated(
+                    recognized_span(preceded(
+                        terminated(tag(RULE_NECK), token_sep0),
+                        preceded(
+                            tag(IMPORT),
+                            cut(delimited(
+                                delimited(token_sep0, tag(ARGS_START), token_sep0),
+                                pair(
+                                    modus_const,
+                                    opt(preceded(
+                                        tuple((
+                                            token_sep0,
+                                            tag(ARG_SEPARATOR),
+                                            token_sep0,
+                                            tag(IMPORT_NAMESPACE),
+                                            token_sep0,
+                                            tag(EQUALS),
+                                            token_sep0,
+                                        )),
+                                        map(literal_identifier, |s| s.fragment().to_string()),
+                                    )),
+                                ),
+                                preceded(token_sep0, tag(ARGS_END)),
+                            )),
+                        ),
+                    )),
+                    cut(nom::character::complete::char(CLAUSE_END)),
+                ),
+                |(position, (path, namespace))| ModusClause {
+                    head: Literal {
+                        positive: true,
+                        position: Some(position),
+                        predicate: Predicate(IMPORT_PREDICATE.into()),
+                        args: std::iter::once(path)
+                            .chain(namespace)
+                            .map(ModusTerm::Constant)
+                            .collect(),
+                    },
+                    body: None,
+                    position: None,
+                },
+            ),
+        )(i)
+    }
+
+    /// Parses a clause, with the span of the whole clause up to and including the `.`.
+    /// Parses the query directive `?- <goal>.` into the rule `_assertion_<n> :- <goal>.`,
+    /// where `n` is unique, so that each one can be solved on its own.
+    fn query_directive(i: Span) -> IResult<Span, ModusClause> {
+        context(
+            stringify!(query_directive),
+            map(
+                terminated(
+                    recognized_span(preceded(
+                        terminated(tag(QUERY_NECK), token_sep0),
+                        cut(context("query_body", body)),
+                    )),
+                    cut(nom::character::complete::char(CLAUSE_END)),
+                ),
+                |(position, goal)| ModusClause {
+                    head: Literal {
+                        positive: true,
+                        position: Some(position),
+                        predicate: Predicate(
+                            format!("_assertion_{}", fetch_add_assertion_id()).into(),
+                        ),
+                        args: Vec::new(),
+                    },
+                    body: Some(goal),
+                    position: None,
+                },
+            ),
+        )(i)
+    }
+
     pub fn modus_clause(i: Span) -> IResult<Span, ModusClause> {
-        alt((rule, fact))(i)
+        map(
+            terminated(
+                recognized_span(alt((
+                    directive,
+                    include_directive,
+                    import_directive,
+                    query_directive,
+                    rule,
+                    fact,
+                ))),
+                token_sep0,
+            ),
+            |(position, clause)| ModusClause {
+                position: Some(position),
+                ..clause
+            },
+        )(i)
     }
 
     pub fn modusfile(i: Span) -> IResult<Span, Modusfile> {
@@ -1084,6 +1913,58 @@ pub mod parser {
             Modusfile,
         )(i)
     }
+
+    /// Parses a Modusfile like [`modusfile`], but recovers from a syntax error by
+    /// skipping to the end of the clause, so that the errors of all clauses are
+    /// returned, along with the clauses that parsed.
+    pub fn modusfile_with_recovery(mut i: Span) -> (Modusfile, Vec<ErrorTree<Span>>) {
+        let mut clauses = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            if let Ok((rest, _)) = token_sep0(i) {
+                i = rest;
+            }
+            if i.fragment().is_empty() {
+                break;
+            }
+            match modus_clause(i) {
+                Ok((rest, clause)) => {
+                    clauses.push(clause);
+                    i = rest;
+                }
+                Err(nom::Err::Failure(e)) => {
+                    errors.push(e);
+                    i = skip_clause(i);
+                }
+                // No clause starts here, which `modusfile` reports as not reaching the end.
+                Err(_) => {
+                    errors.push(ErrorTree::Base {
+                        location: i,
+                        kind: BaseErrorKind::Expected(nom_supreme::error::Expectation::Eof),
+                    });
+                    i = skip_clause(i);
+                }
+            }
+        }
+        (Modusfile(clauses), errors)
+    }
+
+    /// Skips to after the next `.` that is followed by whitespace, a comment or the
+    /// end of the input, which is most likely the end of the clause.
+    fn skip_clause(i: Span) -> Span {
+        let s = i.fragment();
+        let end = s
+            .char_indices()
+            .find(|&(n, c)| {
+                c == CLAUSE_END
+                    && s[n + 1..]
+                        .chars()
+                        .next()
+                        .is_none_or(|next| next.is_whitespace() || COMMENT.starts_with(next))
+            })
+            .map_or(s.len(), |(n, _)| n + 1);
+        i.slice(end..)
+    }
 }
 
 #[cfg(test)]
@@ -1108,6 +1989,7 @@ mod tests {
         let c = ModusClause {
             head: l1,
             body: None,
+            position: None,
         };
 
         assert_eq!("l1.", c.to_string());
@@ -1116,6 +1998,24 @@ mod tests {
         assert!(c.eq_ignoring_position(&actual));
     }
 
+    #[test]
+    fn clause_positions() {
+        let mf: Modusfile = "a. # a fact\n  b :- a,\n  a.\nc(\"x\").\n".parse().unwrap();
+        let spans: Vec<_> =
+            mf.0.iter()
+                .map(|c| Range::from(c.position.as_ref().unwrap()))
+                .collect();
+        assert_eq!(spans, vec![0..2, 14..26, 27..34]);
+    }
+
+    #[test]
+    fn doc_comments() {
+        let source = "## Unrelated.\n\n# Not documentation.\n## The base image.\n##   Alpine.\nbase :- from(\"alpine\").\napp :- base.\n";
+        let mf: Modusfile = source.parse().unwrap();
+        assert_eq!(mf.0[0].doc(source).unwrap(), "The base image.\n  Alpine.");
+        assert_eq!(mf.0[1].doc(source), None);
+    }
+
     #[test]
     fn rule() {
         let l1 = Literal {
@@ -1139,6 +2039,7 @@ mod tests {
         let c = Rule {
             head: l1,
             body: Expression::And(None, true, Box::new(l2.into()), Box::new(l3.into())).into(),
+            position: None,
         };
 
         assert_eq!("l1 :- (l2, l3).", c.to_string());
@@ -1156,6 +2057,7 @@ mod tests {
         let c = Rule {
             head: "foo".parse().unwrap(),
             body: Expression::Or(None, true, Box::new(l1.into()), Box::new(l2.into())).into(),
+            position: None,
         };
 
         assert_eq!("foo :- (l1; l2).", c.to_string());
@@ -1197,11 +2099,13 @@ mod tests {
                 merge.clone(),
             )
             .into(),
+            position: None,
         };
         let r2 = Rule {
             head: foo,
             body: Expression::OperatorApplication(None, Box::new(Expression::Literal(a)), merge)
                 .into(),
+            position: None,
         };
 
         assert_eq!("foo :- ((a, b))::merge.", r1.to_string());
@@ -1252,6 +2156,7 @@ mod tests {
                 merge,
             )
             .into(),
+            position: None,
         };
         assert_eq!("foo :- ((a, b))::merge.", r.to_string());
 
@@ -1290,6 +2195,7 @@ mod tests {
                 merge,
             )
             .into(),
+            position: None,
         };
         let r2 = Rule {
             head: foo.clone(),
@@ -1310,6 +2216,7 @@ mod tests {
                 )),
             )
             .into(),
+            position: None,
         };
         assert_eq!("foo :- ((a; b))::merge.", r1.to_string());
         assert_eq!("foo :- (a, (b, (a; b))).", r2.to_string());
@@ -1338,9 +2245,9 @@ mod tests {
         let inp2 = r#""Tabs\tare\tbetter\tthan\tspaces""#;
         let inp3 = r#""Testing \
                        multiline.""#;
-        let (_, s1) = parser::modus_const(Span::new(inp1.into())).unwrap();
-        let (_, s2) = parser::modus_const(Span::new(inp2.into())).unwrap();
-        let (_, s3) = parser::modus_const(Span::new(inp3.into())).unwrap();
+        let (_, s1) = parser::modus_const(Span::new(inp1)).unwrap();
+        let (_, s2) = parser::modus_const(Span::new(inp2)).unwrap();
+        let (_, s3) = parser::modus_const(Span::new(inp3)).unwrap();
 
         assert_eq!(s1, r#"Hello\nWorld"#);
         assert_eq!(s2, r#"Tabs\tare\tbetter\tthan\tspaces"#);
@@ -1351,6 +2258,123 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unquoted_numbers() {
+        let clause: ModusClause = "a :- expose(8080), retries(-3), f(0.5).".parse().unwrap();
+        let quoted: ModusClause = "a :- expose(\"8080\"), retries(\"-3\"), f(\"0.5\")."
+            .parse()
+            .unwrap();
+        let c1: Vec<logic::Clause> = (&clause).into();
+        let c2: Vec<logic::Clause> = (&quoted).into();
+        assert_eq!(c2[0].to_string(), c1[0].to_string());
+
+        // The text is kept, rather than the value.
+        let version: ModusClause = "a :- python(3.10).".parse().unwrap();
+        let c: Vec<logic::Clause> = (&version).into();
+        assert_eq!(c[0].body[0].args[0], logic::IRTerm::Constant("3.10".into()));
+
+        // A trailing dot ends the clause rather than starting a fraction.
+        let (rest, n) = parser::modus_number(Span::new("3.")).unwrap();
+        assert_eq!("3", n);
+        assert_eq!(".", *rest.fragment());
+    }
+
+    #[test]
+    fn boolean_constants() {
+        let (_, t) = modus_term(Span::new("true")).unwrap();
+        assert_eq!(ModusTerm::Constant("true".to_string()), t);
+        let (_, t) = modus_term(Span::new("false")).unwrap();
+        assert_eq!(ModusTerm::Constant("false".to_string()), t);
+        let (_, t) = modus_term(Span::new("true_value")).unwrap();
+        assert_eq!(ModusTerm::UserVariable("true_value".to_string()), t);
+    }
+
+    #[test]
+    fn named_contexts() {
+        let (_, t) = modus_term(Span::new("context(\"builder\")")).unwrap();
+        assert_eq!(ModusTerm::Constant("context://builder".to_string()), t);
+        let (_, t) = modus_term(Span::new("context")).unwrap();
+        assert_eq!(ModusTerm::UserVariable("context".to_string()), t);
+        assert!(modus_term(Span::new("context(X)")).is_err());
+    }
+
+    #[test]
+    fn local_images() {
+        let (_, t) = modus_term(Span::new("oci_layout(\"./base-oci\")")).unwrap();
+        assert_eq!(
+            ModusTerm::Constant("oci-layout://./base-oci".to_string()),
+            t
+        );
+        let (_, t) = modus_term(Span::new("docker_tar( \"base.tar\" )")).unwrap();
+        assert_eq!(ModusTerm::Constant("docker-tar://base.tar".to_string()), t);
+        let (_, t) = modus_term(Span::new("oci_layout")).unwrap();
+        assert_eq!(ModusTerm::UserVariable("oci_layout".to_string()), t);
+        assert!(modus_term(Span::new("docker_tar(X)")).is_err());
+    }
+
+    #[test]
+    fn hex_and_unicode_escapes() {
+        let inp = r#""tab\x09caf\u{e9} \u{1F600}""#;
+        let (_, s) = parser::modus_const(Span::new(inp)).unwrap();
+        assert_eq!(process_raw_string(&s).unwrap(), "tab\tcafé 😀");
+
+        for invalid in [
+            r#""\x8f""#,
+            r#""\x4""#,
+            r#""\u{}""#,
+            r#""\u{D800}""#,
+            r#""\u{1234567}""#,
+            r#""\u{41""#,
+        ] {
+            assert!(
+                parser::modus_const(Span::new(invalid)).is_err(),
+                "{}",
+                invalid
+            );
+        }
+
+        assert!(process_raw_string("trailing \\").is_err());
+        assert!(process_raw_string("\\u{41").is_err());
+        assert!(process_raw_string("\\xg0").is_err());
+    }
+
+    #[test]
+    fn raw_string_constant() {
+        let inp = "\"\"\"\nif [ \"$x\" = \\\"y\\\" ]; then\n  echo \"\"\n\nfi\"\"\"";
+        let (rest, s) = parser::modus_const(Span::new(inp)).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            process_raw_string(&s).unwrap(),
+            "if [ \"$x\" = \\\"y\\\" ]; then\n  echo \"\"\n\nfi"
+        );
+
+        let t = ModusTerm::Constant(s);
+        let (_, reparsed) = parser::modus_term(Span::new(&t.to_string())).unwrap();
+        assert_eq!(t, reparsed);
+
+        assert!(parser::modus_const(Span::new("\"\"\"unterminated\"")).is_err());
+    }
+
+    #[test]
+    fn prefixed_raw_string_constant() {
+        let cases = [
+            ("r\"C:\\new\\path\"", "C:\\new\\path"),
+            ("r\"\"", ""),
+            ("r#\"echo \"hi\"\"#", "echo \"hi\""),
+            ("r##\"a \"# b\"##", "a \"# b"),
+        ];
+        for (case, expected) in cases {
+            let (rest, s) = parser::modus_const(Span::new(case)).unwrap();
+            assert!(rest.is_empty(), "{}", case);
+            assert_eq!(process_raw_string(&s).unwrap(), expected);
+        }
+
+        // a variable named `r` is not the start of a raw string
+        let (_, t) = parser::modus_term(Span::new("r")).unwrap();
+        assert_eq!(t, ModusTerm::UserVariable("r".into()));
+        assert!(parser::modus_const(Span::new("r#\"unterminated\"")).is_err());
+    }
+
     #[test]
     fn anonymous_variables() {
         let expected = Literal {
@@ -1430,6 +2454,41 @@ mod tests {
         assert!(expected_body.eq_ignoring_position(&modus_clause.body.unwrap()));
     }
 
+    #[test]
+    fn modus_comparison() {
+        let modus_clause: ModusClause = "foo(X, Y) :- X > 3, Y <= X.".parse().unwrap();
+        let expected_body = Expression::And(
+            None,
+            true,
+            Box::new(Expression::Literal("number_gt(X, \"3\")".parse().unwrap())),
+            Box::new(Expression::Literal("number_leq(Y, X)".parse().unwrap())),
+        );
+        assert!(expected_body.eq_ignoring_position(&modus_clause.body.unwrap()));
+    }
+
+    #[test]
+    fn modus_version_comparison() {
+        let modus_clause: ModusClause = "foo(V) :- V >= \"1.2.0\", \"2\" > V, V < \"2.0-rc\"."
+            .parse()
+            .unwrap();
+        let expected_body = Expression::And(
+            None,
+            true,
+            Box::new(Expression::And(
+                None,
+                true,
+                Box::new(Expression::Literal(
+                    "semver_geq(V, \"1.2.0\")".parse().unwrap(),
+                )),
+                Box::new(Expression::Literal("number_gt(\"2\", V)".parse().unwrap())),
+            )),
+            Box::new(Expression::Literal(
+                "semver_lt(V, \"2.0-rc\")".parse().unwrap(),
+            )),
+        );
+        assert!(expected_body.eq_ignoring_position(&modus_clause.body.unwrap()));
+    }
+
     #[test]
     fn modus_negated_unification() {
         let inp = "foo(X, Y) :- X != Y.";
@@ -1462,7 +2521,7 @@ mod tests {
             head: logic::Literal {
                 positive: true,
                 position: None,
-                predicate: logic::Predicate("a".to_owned()),
+                predicate: logic::Predicate("a".into()),
                 args: vec![],
             },
             body: Some(Expression::And(
@@ -1498,6 +2557,7 @@ mod tests {
                     },
                 )),
             )),
+            position: None,
         };
 
         let actual: Rule = r#"a:-(foo(x),bar)::setenv("a","foobar"), (baz)::setenv("a", "baz")."#
@@ -1516,7 +2576,7 @@ mod tests {
             head: logic::Literal {
                 positive: true,
                 position: None,
-                predicate: logic::Predicate("a".to_owned()),
+                predicate: logic::Predicate("a".into()),
                 args: vec![],
             },
             body: Some(Expression::OperatorApplication(
@@ -1541,6 +2601,7 @@ mod tests {
                     args: Vec::new(),
                 },
             )),
+            position: None,
         };
 
         assert!(expected.eq_ignoring_position(&r1));
@@ -1674,6 +2735,106 @@ mod tests {
         assert!(diags[0].labels[2].message.contains("rule"));
     }
 
+    #[test]
+    fn reports_errors_of_all_clauses() {
+        let diags = "a :- b(X.\nb :- run(\"x. y\").\nc :- [X.\nd. )\ne."
+            .parse::<Modusfile>()
+            .unwrap_err();
+        let offsets: Vec<_> = diags.iter().map(|d| d.labels[0].range.start).collect();
+        assert_eq!(offsets, vec![8, 35, 40]);
+    }
+
+    #[test]
+    fn if_then_else() {
+        let cases = [
+            ("(a -> b ; c)", "(a, b ; !a, c)"),
+            ("(a, b -> c)", "(a, b, c)"),
+            ("(a -> b ; c -> d ; e)", "(a, b ; !a, (c, d ; !c, e))"),
+            ("(a -> b ; c ; d)", "(a, b ; !a, (c ; d))"),
+        ];
+        for (case, desugared) in cases {
+            let actual: Expression = case.parse().unwrap();
+            let expected: Expression = desugared.parse().unwrap();
+            assert!(actual.eq_ignoring_position(&expected), "{}", case);
+        }
+        assert!("(a::merge -> b)".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn query_directives() {
+        let mf: Modusfile = "a(\"x\").\n?- a(X), X = \"x\".\n?- a(\"y\")."
+            .parse()
+            .unwrap();
+        let assertions =
+            mf.0.iter()
+                .filter(|c| c.head.predicate.is_assertion())
+                .collect::<Vec<_>>();
+        assert_eq!(assertions.len(), 2);
+        assert_ne!(assertions[0].head.predicate, assertions[1].head.predicate);
+        assert!(assertions[0].head.args.is_empty());
+        let expected: Expression = "a(X), X = \"x\"".parse().unwrap();
+        assert!(assertions[0]
+            .body
+            .as_ref()
+            .unwrap()
+            .eq_ignoring_position(&expected));
+        assert_eq!(assertions[1].to_string(), "?- a(\"y\").");
+        assert_eq!(
+            assertions
+                .iter()
+                .map(|c| c.position.as_ref().map(Range::from))
+                .collect::<Vec<_>>(),
+            vec![Some(8..25), Some(26..36)]
+        );
+
+        assert!("?- a".parse::<Modusfile>().is_err());
+    }
+
+    #[test]
+    fn findall() {
+        let actual: Expression = "findall(X, p(X, \"a\"), L)".parse().unwrap();
+        let expected: Expression = "_findall(X, L, \"p\", X, \"a\")".parse().unwrap();
+        assert!(actual.eq_ignoring_position(&expected));
+
+        // without a goal, it's a literal of a user-defined `findall`
+        let actual: Expression = "findall(X, \"a\", L)".parse().unwrap();
+        let expected = Expression::Literal(Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("findall".into()),
+            args: vec![
+                ModusTerm::UserVariable("X".into()),
+                ModusTerm::Constant("a".into()),
+                ModusTerm::UserVariable("L".into()),
+            ],
+        });
+        assert!(actual.eq_ignoring_position(&expected));
+        assert!("findall(X, p(X), )".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn parse_errors_have_hints() {
+        let cases = [
+            ("a :- b", "expected `.` to end the clause"),
+            ("e( :- f.", "the `(` is not closed by a `)`"),
+            ("e( :- f(\")\").", "the `(` is not closed by a `)`"),
+            ("a :- b).", "the `)` closes no `(`"),
+            ("a :- b, c].", "the `]` closes no `[`"),
+            ("a :- b(X.", "expected `)` to close the parenthesis"),
+            ("a :- [X, b.", "expected `]` to end the list"),
+            ("a :- b. )", "expected the start of a clause"),
+        ];
+        for (case, hint) in cases {
+            let diags = case.parse::<Modusfile>().unwrap_err();
+            assert!(
+                diags[0].labels[0].message.starts_with(hint),
+                "{}: {:?}",
+                case,
+                diags[0].labels[0]
+            );
+        }
+    }
+
     #[test]
     fn format_string() {
         let case = "f\"foo ${ X }\"";
@@ -1741,7 +2902,7 @@ mod tests {
         let expected: ModusTerm = ModusTerm::FormatString {
             position: SpannedPosition {
                 offset: 0,
-                length: 0 + 3,
+                length: 3,
             },
             fragments: vec![],
         };
@@ -15,14 +15,21 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{hash_map, BTreeMap, BinaryHeap, HashMap, HashSet},
     fmt::{self, Debug},
     hash::Hash,
-    io, iter,
+    io,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    analysis, builtin,
+    analysis, builtin, datalog,
     logic::Predicate,
     modusfile::{self, Modusfile},
     translate::translate_modusfile,
@@ -30,7 +37,7 @@ use crate::{
 };
 use crate::{builtin::SelectBuiltinResult, unification::RenameWithSubstitution};
 use crate::{
-    logic::{self, Signature},
+    logic::{self, Ground, Signature, Symbol},
     unification::Substitute,
     wellformed,
 };
@@ -39,13 +46,13 @@ use colored::Colorize;
 use itertools::Itertools;
 use logic::{Clause, IRTerm, Literal};
 use ptree::{item::StringItem, print_tree, TreeBuilder, TreeItem};
+use serde::Serialize;
 
 pub trait Auxiliary: Rename<Self> + Sized {
     fn aux(anonymous: bool) -> Self;
 }
 
 type RuleId = usize;
-type GoalId = usize;
 type TreeLevel = usize;
 pub(crate) type Goal<T = IRTerm> = Vec<Literal<T>>;
 
@@ -55,6 +62,9 @@ pub enum ClauseId {
     Rule(RuleId),
     Query,
     Builtin(Literal<IRTerm>),
+    /// An answer of the subgoal of a tabled literal. Only found in SLD trees, as the
+    /// proofs built from them contain the proof of the answer instead.
+    Tabled(TabledAnswer),
 
     /// Stores the literal which we attempted to prove.
     /// So it should be a positive literal.
@@ -70,6 +80,29 @@ impl ClauseId {
     }
 }
 
+/// An answer of a tabled subgoal with the proof it was first found with, see
+/// [`SolveProfile::with_tabling`]. Answers are compared by their literal only.
+#[derive(Clone, Debug)]
+pub struct TabledAnswer {
+    pub answer: Literal<IRTerm>,
+    /// The proof of the answer, with the variables of the answer.
+    pub proof: Arc<Proof>,
+}
+
+impl PartialEq for TabledAnswer {
+    fn eq(&self, other: &Self) -> bool {
+        self.answer == other.answer
+    }
+}
+
+impl Eq for TabledAnswer {}
+
+impl Hash for TabledAnswer {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.answer.hash(state);
+    }
+}
+
 /// A literal origin can be uniquely identified through its source clause and its index in the clause body
 #[derive(Clone, PartialEq, Debug)]
 pub struct LiteralOrigin {
@@ -87,7 +120,11 @@ struct LiteralWithHistory {
     introduction: TreeLevel,
     origin: LiteralOrigin,
 }
-type GoalWithHistory = Vec<LiteralWithHistory>;
+
+/// Literals are shared between the goals of a node and its descendants, so
+/// building a resolvent only allocates the literals that actually change. They are
+/// shared atomically, so that branches can be explored on other threads.
+type GoalWithHistory = Vec<Arc<LiteralWithHistory>>;
 
 /// An SLD tree consists of
 /// - a goal with its dependencies (at which level and from which part of body each literal was introduced)
@@ -129,8 +166,7 @@ impl Tree {
 
     fn resolvents(
         &self,
-    ) -> HashMap<&(usize, ClauseId), &(HashMap<IRTerm, IRTerm>, HashMap<IRTerm, IRTerm>, Tree)>
-    {
+    ) -> HashMap<&(LiteralGoalId, ClauseId), &(Substitution, Substitution, Tree)> {
         self.success_resolvents
             .iter()
             .chain(&self.fail_resolvents)
@@ -208,6 +244,7 @@ impl Tree {
                         ClauseId::Rule(rid) => rules[*rid].head.to_string(),
                         ClauseId::Query => "query".to_string(),
                         ClauseId::Builtin(lit) => lit.to_string(),
+                        ClauseId::Tabled(t) => t.answer.to_string(),
                         ClauseId::NegationCheck(lit) => format!("Check {lit}?"),
                     };
                     edges.push((curr_index, new_index, edge_label));
@@ -222,6 +259,34 @@ impl Tree {
         Graph { name, nodes, edges }
     }
 
+    /// Converts this tree to JSON, for external tools. Each node has its goal, its error if
+    /// resolution failed there, and its resolvents, each with the index of the selected
+    /// literal in the goal, the clause applied, the most general unifier and the subtree.
+    pub fn to_json(&self, rules: &[Clause]) -> serde_json::Value {
+        let mut resolvents = self.resolvents().into_iter().collect::<Vec<_>>();
+        resolvents.sort_by_key(|((lid, cid), _)| (*lid, describe_clause(cid, rules)));
+        serde_json::json!({
+            "goal": self.goal.iter().map(|l| l.literal.to_string()).collect::<Vec<_>>(),
+            "level": self.level,
+            "success": self.is_success(),
+            "error": self.error.as_ref().map(|e| e.to_string()),
+            "resolvents": resolvents
+                .into_iter()
+                .map(|((lid, cid), (mgu, _, tree))| {
+                    serde_json::json!({
+                        "literal": lid,
+                        "clause": describe_clause(cid, rules),
+                        "substitution": mgu
+                            .iter()
+                            .map(|(v, t)| (v.get_original().to_string(), t.to_string()))
+                            .collect::<BTreeMap<_, _>>(),
+                        "tree": tree.to_json(rules),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
     /// Takes string_concat literals.
     ///
     /// Relies on the ordering of f-strings to represent some of the string_concats as f-strings
@@ -240,7 +305,7 @@ impl Tree {
             {
                 prev_string += if concat.args[1].is_constant_or_compound_constant() {
                     let as_string = concat.args[1].to_string();
-                    format!("{}", &as_string[1..as_string.len() - 1])
+                    as_string[1..as_string.len() - 1].to_string()
                 } else {
                     format!("${{{}}}", concat.args[1])
                 }
@@ -254,13 +319,13 @@ impl Tree {
                     "{}{}",
                     if concat.args[0].is_constant_or_compound_constant() {
                         let as_string = concat.args[0].to_string();
-                        format!("{}", &as_string[1..as_string.len() - 1])
+                        as_string[1..as_string.len() - 1].to_string()
                     } else {
                         format!("${{{}}}", concat.args[0])
                     },
                     if concat.args[1].is_constant_or_compound_constant() {
                         let as_string = concat.args[1].to_string();
-                        format!("{}", &as_string[1..as_string.len() - 1])
+                        as_string[1..as_string.len() - 1].to_string()
                     } else {
                         format!("${{{}}}", concat.args[1])
                     }
@@ -293,8 +358,8 @@ impl Tree {
                     if let Some(xs) = maybe_data {
                         ":\n".to_owned()
                             + &" ".repeat(depth * 3)
-                            + &"- "
-                            + &xs.join(&("\n".to_owned() + &" ".repeat(depth * 3) + &"- "))
+                            + "- "
+                            + &xs.join(&("\n".to_owned() + &" ".repeat(depth * 3) + "- "))
                     } else {
                         "".to_string()
                     }
@@ -307,7 +372,7 @@ impl Tree {
                 for (k, v) in &resolvent_pairs {
                     let (goal_id, cid) = k;
 
-                    let substitution_map: HashMap<_, _> =
+                    let substitution_map: Substitution =
                         v.0.iter()
                             .map(|(t1, t2)| (t1.get_original().clone(), t2.clone()))
                             .collect();
@@ -334,6 +399,7 @@ impl Tree {
                             .join(&("\n".to_owned() + &" ".repeat(depth * 3) + "- ")),
                         ClauseId::Query => unimplemented!(),
                         ClauseId::Builtin(lit) => lit.substitute(&v.0).to_string(),
+                        ClauseId::Tabled(t) => t.answer.substitute(&v.0).to_string(),
                         ClauseId::NegationCheck(lit) => {
                             format!("{} to have no proof", lit)
                         }
@@ -349,10 +415,11 @@ impl Tree {
                         }
                     );
 
-                    let new_child = if v.2.error.is_some() && !v.2.resolvents().is_empty() {
-                        format!("{}", v.2.error.as_ref().unwrap().to_string().bright_red())
-                    } else {
-                        curr_attempt
+                    let new_child = match &v.2.error {
+                        Some(e) if !v.2.resolvents().is_empty() => {
+                            format!("{}", e.to_string().bright_red())
+                        }
+                        _ => curr_attempt,
                     };
 
                     // If we've resolved with a builtin - it means it was successful, so I don't think it adds
@@ -379,6 +446,160 @@ impl Tree {
         dfs(self, rules, 1, &mut builder);
         builder.build()
     }
+
+    /// Returns the resolution steps recorded in this tree, in the order in which
+    /// they were taken.
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        fn clause_order(cid: &ClauseId) -> (usize, usize) {
+            match cid {
+                ClauseId::Query => (0, 0),
+                ClauseId::Builtin(_) | ClauseId::Tabled(_) => (1, 0),
+                ClauseId::Rule(rid) => (2, *rid),
+                ClauseId::NegationCheck(_) => (3, 0),
+            }
+        }
+
+        fn dfs(t: &Tree, events: &mut Vec<TraceEvent>) {
+            if t.goal.is_empty() {
+                events.push(TraceEvent::Success { level: t.level });
+                return;
+            }
+            if let Some(error) = &t.error {
+                events.push(TraceEvent::Fail {
+                    level: t.level,
+                    goal: t.goal.iter().map(|l| l.literal.clone()).collect(),
+                    error: error.clone(),
+                });
+            }
+            let mut resolvents = t.resolvents().into_iter().collect::<Vec<_>>();
+            resolvents.sort_by_key(|(k, _)| (k.0, clause_order(&k.1)));
+            for ((lid, cid), (_, _, subtree)) in resolvents {
+                events.push(TraceEvent::Resolve {
+                    level: t.level,
+                    literal: t.goal[*lid].literal.clone(),
+                    clause: cid.clone(),
+                });
+                dfs(subtree, events);
+            }
+        }
+
+        let mut events = Vec::new();
+        dfs(self, &mut events);
+        events
+    }
+}
+
+/// A step of SLD resolution.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// The selected literal was resolved with a clause.
+    Resolve {
+        level: usize,
+        literal: Literal,
+        clause: ClauseId,
+    },
+    /// The goal could not be resolved further.
+    Fail {
+        level: usize,
+        goal: Vec<Literal>,
+        error: ResolutionError,
+    },
+    /// The goal was fully resolved.
+    Success { level: usize },
+}
+
+impl TraceEvent {
+    pub fn level(&self) -> usize {
+        match self {
+            TraceEvent::Resolve { level, .. }
+            | TraceEvent::Fail { level, .. }
+            | TraceEvent::Success { level } => *level,
+        }
+    }
+
+    /// The literals involved in this event.
+    pub fn literals(&self) -> Vec<&Literal> {
+        match self {
+            TraceEvent::Resolve { literal, .. } => vec![literal],
+            TraceEvent::Fail { goal, error, .. } => match error {
+                ResolutionError::UnknownPredicate(l)
+                | ResolutionError::BuiltinFailure(l, _)
+                | ResolutionError::InsufficientRules(l)
+                | ResolutionError::NegationProof(l) => vec![l],
+                _ => goal.iter().collect(),
+            },
+            TraceEvent::Success { .. } => Vec::new(),
+        }
+    }
+
+    pub fn describe(&self, rules: &[Clause]) -> String {
+        match self {
+            TraceEvent::Resolve {
+                literal, clause, ..
+            } => match clause {
+                ClauseId::Rule(rid) if rules[*rid].body.is_empty() => {
+                    format!("{} resolved with fact {}", literal, rules[*rid].head)
+                }
+                ClauseId::Rule(rid) => format!("{} resolved with rule {}", literal, rules[*rid]),
+                ClauseId::Builtin(lit) => {
+                    format!("{} resolved with builtin {}", literal, lit.predicate)
+                }
+                ClauseId::Tabled(t) => {
+                    format!("{} resolved with tabled answer {}", literal, t.answer)
+                }
+                ClauseId::NegationCheck(lit) => {
+                    format!("{} requires {} to have no proof", literal, lit)
+                }
+                ClauseId::Query => format!("{} resolved with the query", literal),
+            },
+            TraceEvent::Fail { error, .. } => format!("failed: {}", error),
+            TraceEvent::Success { .. } => "success".to_owned(),
+        }
+    }
+}
+
+/// Selects the trace events that involve some predicates. A predicate is given
+/// by its signature, e.g. `install/2`, or just by its name to match any arity.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceFilter(Vec<(Predicate, Option<u32>)>);
+
+impl std::str::FromStr for TraceFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|p| {
+                let p = p.trim();
+                let (name, arity) = match p.rsplit_once('/') {
+                    Some((name, arity)) => (
+                        name,
+                        Some(
+                            arity
+                                .parse::<u32>()
+                                .map_err(|_| format!("invalid arity in predicate {}", p))?,
+                        ),
+                    ),
+                    None => (p, None),
+                };
+                if name.is_empty() {
+                    return Err(format!("missing predicate name in {:?}", p));
+                }
+                Ok((Predicate(name.into()), arity))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(TraceFilter)
+    }
+}
+
+impl TraceFilter {
+    pub fn matches(&self, event: &TraceEvent) -> bool {
+        event.literals().iter().any(|lit| {
+            let Signature(name, arity) = lit.signature();
+            self.0
+                .iter()
+                .any(|(p, a)| *p == name && a.is_none_or(|a| a == arity))
+        })
+    }
 }
 
 type Nd<'a> = (usize, &'a str);
@@ -413,7 +634,7 @@ impl<'a> dot::GraphWalk<'a, Nd<'a>, Ed<'a>> for Graph {
         self.nodes.iter().map(|n| &n[..]).enumerate().collect()
     }
 
-    fn edges(&'a self) -> dot::Edges<'a, Ed> {
+    fn edges(&'a self) -> dot::Edges<'a, Ed<'a>> {
         self.edges
             .iter()
             .map(|(i, j, label)| {
@@ -426,11 +647,11 @@ impl<'a> dot::GraphWalk<'a, Nd<'a>, Ed<'a>> for Graph {
             .collect()
     }
 
-    fn source(&'a self, edge: &Ed<'a>) -> Nd {
+    fn source(&'a self, edge: &Ed<'a>) -> Nd<'a> {
         edge.0
     }
 
-    fn target(&'a self, edge: &Ed<'a>) -> Nd {
+    fn target(&'a self, edge: &Ed<'a>) -> Nd<'a> {
         edge.1
     }
 }
@@ -447,6 +668,15 @@ pub struct Proof {
 }
 
 impl Proof {
+    /// This proof with the values of the variables of every step substituted.
+    fn instantiate(&self, s: &Substitution) -> Proof {
+        Proof {
+            clause: self.clause.clone(),
+            valuation: compose_no_extend(&self.valuation, s),
+            children: self.children.iter().map(|c| c.instantiate(s)).collect(),
+        }
+    }
+
     /// Returns the height of this proof tree, where a leaf node has height 0.
     fn height(&self) -> usize {
         self.children
@@ -467,13 +697,13 @@ impl Proof {
             start_index: usize,
             children: &[Proof],
         ) -> Option<usize> {
-            for i in start_index + 1..children.len() {
-                let child = &children[i];
+            for (i, child) in children.iter().enumerate().skip(start_index + 1) {
                 if let ClauseId::Builtin(b) = &child.clause {
                     if b.predicate.naive_predicate_kind().is_logic()
                         && b.predicate.is_operator()
                         && b.predicate.0.ends_with("_end")
-                        && b.predicate.0.replace("_end", "_begin") == operator_start.predicate.0
+                        && b.predicate.0.replace("_end", "_begin")
+                            == operator_start.predicate.0.as_str()
                         && b.args.last() == operator_start.args.last()
                     {
                         return Some(i);
@@ -510,7 +740,7 @@ impl Proof {
                                 },
                             ));
                         }
-                        dfs(&child, clauses, builder, pred_kind, compact);
+                        dfs(child, clauses, builder, pred_kind, compact);
                         if !compact {
                             builder.end_child();
                         }
@@ -526,10 +756,7 @@ impl Proof {
                             ));
                         }
                         crate::analysis::Kind::Layer => {
-                            builder.add_empty_child(format!(
-                                "{}",
-                                b.substitute(&child.valuation).to_string()
-                            ));
+                            builder.add_empty_child(format!("{}", b.substitute(&child.valuation)));
                         }
                         crate::analysis::Kind::Logic => {
                             if b.predicate.is_operator() {
@@ -559,13 +786,16 @@ impl Proof {
                                     builder.add_empty_child(format!(
                                         "{}::{}",
                                         if dont_close.contains(&i) { " " } else { ")" },
-                                        b.substitute(&child.valuation).unmangle().to_string()
+                                        b.substitute(&child.valuation).unmangle()
                                     ));
                                 }
                             }
                         }
                     },
                     ClauseId::NegationCheck(_) => {} // negation checks are omitted from the proof tree
+                    ClauseId::Tabled(_) => {
+                        unreachable!("proofs contain the proofs of tabled answers")
+                    }
                 }
             }
         }
@@ -584,6 +814,123 @@ impl Proof {
     ) -> io::Result<()> {
         print_tree(&self.get_tree(clauses, pred_kind, compact))
     }
+
+    /// A tree of every step of this proof of `goal`, labelled with the literal each step
+    /// proves. Literals proven by facts are green, by rules blue, and by builtins yellow;
+    /// negated literals that were checked to have no proof are dimmed.
+    pub fn tree(&self, goal: &Goal, clauses: &[Clause]) -> StringItem {
+        fn label(p: &Proof, clauses: &[Clause]) -> String {
+            match &p.clause {
+                ClauseId::Rule(rid) => {
+                    let head = clauses[*rid].head.substitute(&p.valuation).to_string();
+                    if clauses[*rid].body.is_empty() {
+                        head.green().to_string()
+                    } else {
+                        head.blue().to_string()
+                    }
+                }
+                ClauseId::Builtin(b) => {
+                    let lit = b.substitute(&p.valuation);
+                    if lit.predicate.is_operator() {
+                        let end = if lit.predicate.0.ends_with("_begin") {
+                            "begin"
+                        } else {
+                            "end"
+                        };
+                        format!("::{} {}", lit.unmangle(), end).yellow().to_string()
+                    } else {
+                        lit.to_string().yellow().to_string()
+                    }
+                }
+                ClauseId::NegationCheck(l) => l
+                    .negated()
+                    .substitute(&p.valuation)
+                    .to_string()
+                    .dimmed()
+                    .to_string(),
+                ClauseId::Query => "query".to_string(),
+                ClauseId::Tabled(_) => unreachable!("proofs contain the proofs of tabled answers"),
+            }
+        }
+
+        fn dfs(p: &Proof, clauses: &[Clause], builder: &mut TreeBuilder) {
+            for child in &p.children {
+                if child.children.is_empty() {
+                    builder.add_empty_child(label(child, clauses));
+                } else {
+                    builder.begin_child(label(child, clauses));
+                    dfs(child, clauses, builder);
+                    builder.end_child();
+                }
+            }
+        }
+
+        let mut builder = TreeBuilder::new(goal.substitute(&self.valuation).iter().join(", "));
+        dfs(self, clauses, &mut builder);
+        builder.build()
+    }
+
+    /// Converts this proof of `goal` to a directed graph, whose nodes show the clause
+    /// applied at each step along with the values of its variables.
+    pub fn to_graph(&self, goal: &Goal, clauses: &[Clause]) -> Graph {
+        fn label(p: &Proof, clauses: &[Clause]) -> String {
+            let (text, variables) = match &p.clause {
+                ClauseId::Rule(rid) => {
+                    (clause_text(&clauses[*rid]), clauses[*rid].variables(false))
+                }
+                ClauseId::Builtin(lit) => {
+                    (lit.substitute(&p.valuation).to_string(), HashSet::new())
+                }
+                ClauseId::NegationCheck(lit) => (
+                    lit.negated().substitute(&p.valuation).to_string(),
+                    HashSet::new(),
+                ),
+                ClauseId::Query => ("query".to_string(), HashSet::new()),
+                ClauseId::Tabled(_) => unreachable!("proofs contain the proofs of tabled answers"),
+            };
+            let valuation = variables
+                .iter()
+                .filter(|v| p.valuation.contains_key(v))
+                .map(|v| format!("{} = {}", v, v.substitute(&p.valuation)))
+                .sorted()
+                .join(", ");
+            if valuation.is_empty() {
+                text
+            } else {
+                format!("{}\n{}", text, valuation)
+            }
+        }
+
+        fn convert(
+            p: &Proof,
+            clauses: &[Clause],
+            nodes: &mut Vec<String>,
+            edges: &mut Vec<(usize, usize, String)>,
+        ) {
+            let index = nodes.len() - 1;
+            for child in &p.children {
+                nodes.push(label(child, clauses));
+                edges.push((index, nodes.len() - 1, String::new()));
+                convert(child, clauses, nodes, edges);
+            }
+        }
+
+        let mut nodes = vec![goal.substitute(&self.valuation).iter().join(",\n")];
+        let mut edges = Vec::new();
+        convert(self, clauses, &mut nodes, &mut edges);
+        Graph {
+            name: "Proof",
+            nodes,
+            edges,
+        }
+    }
+
+    /// Renders this proof of `goal` as a DOT graph, see [`Proof::to_graph`].
+    pub fn to_dot(&self, goal: &Goal, clauses: &[Clause]) -> String {
+        let mut out = Vec::new();
+        dot::render(&self.to_graph(goal, clauses), &mut out).unwrap();
+        String::from_utf8(out).expect("DOT output is UTF-8")
+    }
 }
 
 impl PartialOrd for Proof {
@@ -592,21 +939,27 @@ impl PartialOrd for Proof {
     }
 }
 
+/// Whether applying `s` to `term` would change it.
+fn is_substituted(term: &IRTerm, s: &Substitution<IRTerm>) -> bool {
+    s.contains_key(term)
+        || matches!(term, IRTerm::List(ts) if ts.iter().any(|t| is_substituted(t, s)))
+}
+
 impl Substitute<IRTerm> for GoalWithHistory {
     type Output = GoalWithHistory;
     fn substitute(&self, s: &Substitution<IRTerm>) -> Self::Output {
         self.iter()
-            .map(
-                |LiteralWithHistory {
-                     literal,
-                     introduction,
-                     origin,
-                 }| LiteralWithHistory {
-                    literal: literal.substitute(s),
-                    introduction: *introduction,
-                    origin: origin.clone(),
-                },
-            )
+            .map(|l| {
+                if l.literal.args.iter().any(|t| is_substituted(t, s)) {
+                    Arc::new(LiteralWithHistory {
+                        literal: l.literal.substitute(s),
+                        introduction: l.introduction,
+                        origin: l.origin.clone(),
+                    })
+                } else {
+                    Arc::clone(l)
+                }
+            })
             .collect()
     }
 }
@@ -627,6 +980,124 @@ pub enum ResolutionError {
     InconsistentGroundnessSignature(Vec<Signature>),
     /// Proof of a negated literal was found.
     NegationProof(Literal),
+    /// Solving was cancelled through the [`CancellationToken`] of the profile.
+    Cancelled,
+    /// Contains the literal whose unification with a clause head would have bound the
+    /// variable to a list containing it, with the occurs check enabled.
+    OccursCheck(Literal, IRTerm),
+    /// Contains the limit of the profile that was exceeded, and the goal being resolved.
+    ResourceLimitExceeded(ResourceLimit, Vec<Literal>),
+}
+
+/// A limit on the resources used by solving, see [`ResourceLimits`].
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum ResourceLimit {
+    Timeout(Duration),
+    Nodes(usize),
+    SubstitutionSize(usize),
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceLimit::Timeout(t) => write!(f, "timeout of {}s", t.as_secs_f64()),
+            ResourceLimit::Nodes(n) => write!(f, "{} nodes explored", n),
+            ResourceLimit::SubstitutionSize(n) => write!(f, "substitution size of {}", n),
+        }
+    }
+}
+
+/// The resources a solve may use, see [`SolveProfile::with_limits`]. Solving stops with a
+/// [`ResolutionError::ResourceLimitExceeded`] error once one is exceeded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The wall-clock time a solve may take.
+    pub timeout: Option<Duration>,
+    /// The number of nodes of the SLD tree a solve may explore.
+    pub max_nodes: Option<usize>,
+    /// The size of the most general unifier of a resolution step, counting each
+    /// constant, variable and list in the terms it binds.
+    pub max_substitution_size: Option<usize>,
+}
+
+pub const NO_PROOF_CODE: &str = "no-proof";
+
+/// A clause as it would be written in a Modusfile, without the `:-` of a fact.
+fn clause_text(c: &Clause) -> String {
+    if c.body.is_empty() {
+        format!("{}.", c.head)
+    } else {
+        format!("{}.", c)
+    }
+}
+
+/// Describes how a literal was resolved, e.g. with the text of the rule applied.
+fn describe_clause(cid: &ClauseId, rules: &[Clause]) -> String {
+    match cid {
+        ClauseId::Rule(rid) => clause_text(&rules[*rid]),
+        ClauseId::Query => "query".to_string(),
+        ClauseId::Builtin(lit) => lit.to_string(),
+        ClauseId::Tabled(t) => t.answer.to_string(),
+        ClauseId::NegationCheck(lit) => lit.negated().to_string(),
+    }
+}
+
+/// A literal selected on one of the deepest failed branches of a solve, that neither a
+/// builtin nor a rule could resolve, see [`SolveProfile::deepest_failures`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    /// The level of the SLD tree at which the literal was selected.
+    pub level: usize,
+    pub literal: Literal,
+    /// The name of the builtin that was tried, if the literal selects one.
+    pub builtin: Option<&'static str>,
+    /// The rules of the literal's predicate, none of whose heads unify with it.
+    pub candidates: Vec<Clause>,
+}
+
+impl Failure {
+    /// A note explaining which literal could not be satisfied, to accompany the errors
+    /// of a solve without proofs.
+    pub fn get_diagnostic(&self) -> Diagnostic<()> {
+        let mut notes = Vec::new();
+        if self.literal.position.is_none() {
+            notes.push(self.literal.to_string());
+        }
+        if let Some(builtin) = self.builtin {
+            notes.push(format!("the builtin {} does not apply to it", builtin));
+        }
+        if self.candidates.is_empty() {
+            if self.builtin.is_none() {
+                notes.push(format!(
+                    "there are no rules for {}",
+                    self.literal.signature()
+                ));
+            }
+        } else {
+            notes.push(format!(
+                "tried {} rule(s), whose heads do not unify with it:\n{}",
+                self.candidates.len(),
+                self.candidates
+                    .iter()
+                    .map(|c| format!("  {}", clause_text(c)))
+                    .join("\n")
+            ));
+        }
+        Diagnostic::note()
+            .with_code(NO_PROOF_CODE)
+            .with_message(format!(
+                "no proof found; a deepest failed branch, at depth {}, could not satisfy {}",
+                self.level, self.literal
+            ))
+            .with_labels(
+                self.literal
+                    .position
+                    .iter()
+                    .map(|pos| Label::primary((), pos.offset..(pos.offset + pos.length)))
+                    .collect(),
+            )
+            .with_notes(notes)
+    }
 }
 
 impl fmt::Display for ResolutionError {
@@ -642,8 +1113,17 @@ impl fmt::Display for ResolutionError {
             ResolutionError::InsufficientGroundness(literals) => {
                 write!(f, "insufficient groundness for {} goal(s)", literals.len())
             }
-            ResolutionError::MaximumDepthExceeded(_, max_depth) => {
-                write!(f, "exceeded maximum depth of {}", max_depth)
+            ResolutionError::MaximumDepthExceeded(goal, max_depth) => {
+                write!(
+                    f,
+                    "depth limit of {} reached while expanding goal {}",
+                    max_depth,
+                    goal.iter().take(3).join(", ")
+                )?;
+                if goal.len() > 3 {
+                    write!(f, ", ...")?;
+                }
+                Ok(())
             }
             ResolutionError::BuiltinFailure(l, builtin_name) => {
                 write!(f, "builtin {builtin_name} failed to apply or unify: {l}")
@@ -661,10 +1141,39 @@ impl fmt::Display for ResolutionError {
             ResolutionError::NegationProof(lit) => {
                 write!(f, "A proof was found for {}", lit.negated())
             }
+            ResolutionError::Cancelled => write!(f, "solving was cancelled"),
+            ResolutionError::OccursCheck(lit, var) => write!(
+                f,
+                "resolving {} would bind {} to a list that contains it",
+                lit, var
+            ),
+            ResolutionError::ResourceLimitExceeded(limit, _) => {
+                write!(f, "resource limit exceeded: {}", limit)
+            }
         }
     }
 }
 
+pub const UNKNOWN_PREDICATE_CODE: &str = "unknown-predicate";
+pub const MAXIMUM_DEPTH_EXCEEDED_CODE: &str = "maximum-depth-exceeded";
+pub const CANCELLED_CODE: &str = "cancelled";
+pub const OCCURS_CHECK_CODE: &str = "occurs-check";
+pub const RESOURCE_LIMIT_EXCEEDED_CODE: &str = "resource-limit-exceeded";
+
+/// The diagnostic codes of all resolution errors.
+pub const RESOLUTION_ERROR_CODES: &[&str] = &[
+    UNKNOWN_PREDICATE_CODE,
+    "insufficient-groundness",
+    MAXIMUM_DEPTH_EXCEEDED_CODE,
+    "builtin-failure",
+    "insufficient-rules",
+    "inconsistent-groundness",
+    "negation-proof",
+    CANCELLED_CODE,
+    OCCURS_CHECK_CODE,
+    RESOURCE_LIMIT_EXCEEDED_CODE,
+];
+
 impl ResolutionError {
     fn to_short_string(&self) -> String {
         match self {
@@ -675,24 +1184,43 @@ impl ResolutionError {
                     format!("unknown predicate: {}", literal.predicate)
                 }
             }
-            ResolutionError::InsufficientGroundness(literals) => {
-                format!("insufficient groundness")
+            ResolutionError::InsufficientGroundness(_literals) => {
+                "insufficient groundness".to_string()
             }
             ResolutionError::MaximumDepthExceeded(_, max_depth) => {
                 format!("exceeded depth of {}", max_depth)
             }
-            ResolutionError::BuiltinFailure(l, builtin_name) => {
+            ResolutionError::BuiltinFailure(_l, builtin_name) => {
                 format!("{builtin_name} failed")
             }
             ResolutionError::InsufficientRules(literal) => {
                 format!("failed to resolve: {}", literal)
             }
             ResolutionError::InconsistentGroundnessSignature(_) => {
-                format!("clauses with inconsistent signatures",)
+                "clauses with inconsistent signatures".to_string()
             }
             ResolutionError::NegationProof(lit) => {
                 format!("proof found for {}", lit.negated())
             }
+            ResolutionError::Cancelled => "cancelled".to_string(),
+            ResolutionError::OccursCheck(_, var) => format!("occurs check failed for {}", var),
+            ResolutionError::ResourceLimitExceeded(limit, _) => format!("exceeded {}", limit),
+        }
+    }
+
+    /// A stable identifier for the kind of error, used as the diagnostic code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ResolutionError::UnknownPredicate(_) => UNKNOWN_PREDICATE_CODE,
+            ResolutionError::InsufficientGroundness(_) => "insufficient-groundness",
+            ResolutionError::MaximumDepthExceeded(_, _) => MAXIMUM_DEPTH_EXCEEDED_CODE,
+            ResolutionError::BuiltinFailure(_, _) => "builtin-failure",
+            ResolutionError::InsufficientRules(_) => "insufficient-rules",
+            ResolutionError::InconsistentGroundnessSignature(_) => "inconsistent-groundness",
+            ResolutionError::NegationProof(_) => "negation-proof",
+            ResolutionError::Cancelled => CANCELLED_CODE,
+            ResolutionError::OccursCheck(_, _) => OCCURS_CHECK_CODE,
+            ResolutionError::ResourceLimitExceeded(_, _) => RESOURCE_LIMIT_EXCEEDED_CODE,
         }
     }
 
@@ -705,6 +1233,9 @@ impl ResolutionError {
             ResolutionError::InsufficientRules(_) => Severity::Warning,
             ResolutionError::InconsistentGroundnessSignature(_) => Severity::Error,
             ResolutionError::NegationProof(_) => Severity::Warning,
+            ResolutionError::Cancelled => Severity::Error,
+            ResolutionError::OccursCheck(_, _) => Severity::Error,
+            ResolutionError::ResourceLimitExceeded(_, _) => Severity::Error,
         }
     }
 
@@ -722,9 +1253,12 @@ impl ResolutionError {
             ResolutionError::BuiltinFailure(_, _) => None,
             ResolutionError::InsufficientRules(_) => None,
             ResolutionError::InconsistentGroundnessSignature(sigs) => {
-                Some(sigs.into_iter().map(|x| x.to_string()).collect())
+                Some(sigs.iter().map(|x| x.to_string()).collect())
             }
             ResolutionError::NegationProof(_) => None,
+            ResolutionError::Cancelled => None,
+            ResolutionError::OccursCheck(_, _) => None,
+            ResolutionError::ResourceLimitExceeded(_, _) => None,
         }
     }
 
@@ -754,33 +1288,42 @@ impl ResolutionError {
         let message = self.to_string();
         let (labels, notes) = match &self {
             ResolutionError::UnknownPredicate(literal) => (
-                get_position_labels(&[literal.clone()]),
-                get_notes(&[literal.clone()]),
+                get_position_labels(std::slice::from_ref(literal)),
+                get_notes(std::slice::from_ref(literal)),
             ),
             ResolutionError::InsufficientGroundness(literals) => {
-                (get_position_labels(&literals), get_notes(&literals))
+                (get_position_labels(literals), get_notes(literals))
             }
             ResolutionError::MaximumDepthExceeded(literals, _) => {
-                (get_position_labels(&literals), get_notes(&literals))
+                (get_position_labels(literals), get_notes(literals))
             }
             ResolutionError::BuiltinFailure(literal, _) => (
-                get_position_labels(&[literal.clone()]),
-                get_notes(&[literal.clone()]),
+                get_position_labels(std::slice::from_ref(literal)),
+                get_notes(std::slice::from_ref(literal)),
             ),
             ResolutionError::InsufficientRules(literal) => (
-                get_position_labels(&[literal.clone()]),
-                get_notes(&[literal.clone()]),
+                get_position_labels(std::slice::from_ref(literal)),
+                get_notes(std::slice::from_ref(literal)),
             ),
             ResolutionError::InconsistentGroundnessSignature(sigs) => {
                 (Vec::new(), sigs.iter().map(|sig| sig.to_string()).collect())
             }
             ResolutionError::NegationProof(lit) => (
-                get_position_labels(&[lit.clone()]),
-                get_notes(&[lit.clone()]),
+                get_position_labels(std::slice::from_ref(lit)),
+                get_notes(std::slice::from_ref(lit)),
             ),
+            ResolutionError::Cancelled => (Vec::new(), Vec::new()),
+            ResolutionError::OccursCheck(lit, _) => (
+                get_position_labels(std::slice::from_ref(lit)),
+                get_notes(std::slice::from_ref(lit)),
+            ),
+            ResolutionError::ResourceLimitExceeded(_, literals) => {
+                (get_position_labels(literals), get_notes(literals))
+            }
         };
 
         Diagnostic::new(self.severity())
+            .with_code(self.code())
             .with_message(message)
             .with_labels(labels)
             .with_notes(notes)
@@ -813,108 +1356,965 @@ impl ResolutionError {
             ResolutionError::NegationProof(l) => {
                 ResolutionError::NegationProof(l.normalized_terms())
             }
+            ResolutionError::Cancelled => ResolutionError::Cancelled,
+            ResolutionError::OccursCheck(l, v) => {
+                ResolutionError::OccursCheck(l.normalized_terms(), v)
+            }
+            ResolutionError::ResourceLimitExceeded(limit, ls) => {
+                ResolutionError::ResourceLimitExceeded(
+                    limit,
+                    ls.into_iter().map(|x| x.normalized_terms()).collect(),
+                )
+            }
         }
     }
 }
 
-/// Result of building the SLD tree.
-///
-/// Uses a custom result type in resolution since we often have some information about
-/// either state, an 'Ok' and 'Err' state is too disjoint for our purposes.
-pub struct SLDResult {
-    pub tree: Tree,
-    pub errors: HashSet<ResolutionError>,
+/// Resolution statistics of the literals of a single predicate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PredicateProfile {
+    /// Time spent resolving the literals, excluding the time spent on the
+    /// subgoals they introduced.
+    pub time: Duration,
+    /// Number of times a literal of this predicate was selected.
+    pub selections: usize,
+    /// Number of clauses (or builtins) the literals were resolved with.
+    pub resolutions: usize,
+    /// Number of these resolutions that did not lead to a solution.
+    pub backtracks: usize,
 }
 
-impl From<SLDResult> for Result<Tree, Vec<Diagnostic<()>>> {
-    fn from(sld_result: SLDResult) -> Self {
-        if sld_result.tree.is_success() {
-            Ok(sld_result.tree)
-        } else {
-            Err(sld_result
-                .errors
-                .into_iter()
-                .map(ResolutionError::normalize)
-                .unique()
-                .map(ResolutionError::get_diagnostic)
-                .collect::<Vec<_>>())
-        }
+/// Overall counts of the work done during SLD resolution.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SolverStats {
+    /// Number of nodes of the SLD tree that were explored.
+    pub nodes_explored: usize,
+    /// The deepest level of the SLD tree that was reached.
+    pub max_depth: usize,
+    /// Number of attempts to unify a literal with a clause head or builtin.
+    pub unifications: usize,
+    /// Number of times a builtin predicate was applied.
+    pub builtin_invocations: usize,
+    /// Number of times a ground subgoal failed immediately, as it was already known to
+    /// have no proof earlier in the same solve.
+    pub known_failures: usize,
+}
+
+impl fmt::Display for SolverStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes explored, maximum depth {}, {} unifications attempted, {} builtin invocations, \
+             {} known failures reused",
+            self.nodes_explored,
+            self.max_depth,
+            self.unifications,
+            self.builtin_invocations,
+            self.known_failures
+        )
     }
 }
 
-/// Returns a tree that contains both successful and failed paths, also, any resolution errors.
-/// To save on memory usage, can avoid storing the failed paths by passing false to `store_full_tree`.
-pub fn sld(
-    rules: &[Clause<IRTerm>],
-    goal: &Goal,
-    maxdepth: TreeLevel,
-    store_full_tree: bool,
-) -> SLDResult {
-    /// Select leftmost literal with compatible groundness.
-    fn select(
-        goal: &GoalWithHistory,
-        grounded: &HashMap<Signature, Vec<bool>>,
-    ) -> Result<(LiteralGoalId, LiteralWithHistory), ResolutionError> {
-        for (id, lit) in goal.iter().enumerate() {
-            // TODO: could rewrite this to enumerate the different cases more explicitly.
-
-            let literal = &lit.literal;
-
-            // A negated literal must have only constants or anonymous variables (which represent
-            // variables that will not equal any other).
-            // Otherwise, something like !string_eq("constant", X) would be pointless, the
-            // user very likely means X to be bound through some other literal.
-            // An alternative approach would be to check other variables in the goal.
-            let positive_or_grounded_negation = literal.positive
-                || literal
-                    .args
-                    .iter()
-                    .all(|arg| arg.is_constant() || arg.is_underlying_anonymous_variable());
+/// Cancels solving, e.g. from a signal handler or another thread. Clones share the same
+/// state, so cancelling one cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
 
-            let select_builtin_res = builtin::select_builtin(literal);
-            if select_builtin_res.0.is_match() && positive_or_grounded_negation {
-                return Ok((id, lit.clone()));
-            }
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        Default::default()
+    }
 
-            // For any user-defined atom, we can get its groundness requirement
-            // (computed outside), and if a particular argument can not be
-            // ungrounded (grounded[arg_index] == false), variables will not be
-            // allowed there.
-            let lit_grounded = grounded.get(&literal.signature());
-            if let Some(lit_grounded) = lit_grounded {
-                debug_assert_eq!(lit_grounded.len(), literal.args.len());
-                if positive_or_grounded_negation
-                    && literal.args.iter().zip(lit_grounded.iter()).all(
-                        |(term, allows_ungrounded)| {
-                            *allows_ungrounded || term.is_constant_or_compound_constant()
-                        },
-                    )
-                {
-                    return Ok((id, lit.clone()));
-                } else {
-                    continue;
-                }
-            } else if select_builtin_res.0 == SelectBuiltinResult::GroundnessMismatch
-                || (select_builtin_res.0 == SelectBuiltinResult::Match
-                    && !positive_or_grounded_negation)
-            {
-                continue;
-            }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 
-            return Err(ResolutionError::UnknownPredicate(literal.clone()));
+    /// The flag that is set when the token is cancelled, for APIs that set a flag, such
+    /// as signal handlers.
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.0
+    }
+}
+
+/// Aggregates resolution statistics per predicate signature during SLD resolution.
+///
+/// The default profile only counts the overall [`SolverStats`], which is cheap,
+/// and does not record anything per predicate.
+#[derive(Clone, Debug, Default)]
+pub struct SolveProfile {
+    enabled: bool,
+    pub stats: SolverStats,
+    pub predicates: HashMap<Signature, PredicateProfile>,
+    /// The goal at the deepest level of the SLD tree that was reached.
+    pub deepest_goal: Vec<Literal>,
+    /// The distinct literals that could not be resolved at the deepest level of the
+    /// current solve where resolution failed, which explain why a query has no proof.
+    pub deepest_failures: Vec<Failure>,
+    /// Resolution stops at the next step once this is cancelled, with a
+    /// [`ResolutionError::Cancelled`] error.
+    pub cancellation: CancellationToken,
+    /// Whether unification fails, with a [`ResolutionError::OccursCheck`] error, instead
+    /// of binding a variable to a list that contains it.
+    pub occurs_check: bool,
+    /// Whether subgoals of logic predicates are tabled, see [`SolveProfile::with_tabling`].
+    pub tabling: bool,
+    /// Whether alternative resolvents are explored on several threads, see
+    /// [`SolveProfile::with_parallelism`].
+    pub parallel: bool,
+    /// Whether pure Datalog predicates are evaluated bottom-up, see
+    /// [`SolveProfile::with_bottom_up`].
+    pub bottom_up: bool,
+    /// Which literal of a goal is resolved next.
+    pub selection: SelectionRule,
+    /// The resources each solve may use, see [`SolveProfile::with_limits`].
+    pub limits: ResourceLimits,
+    /// When the timeout of the current solve expires.
+    deadline: Option<Instant>,
+    /// The number of nodes explored by the current solve, shared with the profiles forked
+    /// from this one so that parallel workers draw from the same budget.
+    solve_nodes: Option<Arc<AtomicUsize>>,
+    /// The literals being resolved: their signature, the time resolution
+    /// started, and the time spent on subgoals so far.
+    stack: Vec<(Signature, Instant, Duration)>,
+}
+
+impl SolveProfile {
+    pub fn new() -> SolveProfile {
+        SolveProfile {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// An empty profile recording the same information as this one, e.g. to
+    /// profile a solve running on another thread.
+    pub fn fork(&self) -> SolveProfile {
+        SolveProfile {
+            enabled: self.enabled,
+            cancellation: self.cancellation.clone(),
+            occurs_check: self.occurs_check,
+            tabling: self.tabling,
+            parallel: self.parallel,
+            bottom_up: self.bottom_up,
+            selection: self.selection,
+            limits: self.limits,
+            deadline: self.deadline,
+            solve_nodes: self.solve_nodes.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Solving with this profile stops when the token is cancelled.
+    pub fn with_cancellation(self, cancellation: CancellationToken) -> SolveProfile {
+        SolveProfile {
+            cancellation,
+            ..self
         }
+    }
+
+    /// Solving with this profile performs the occurs check when unifying.
+    pub fn with_occurs_check(self, occurs_check: bool) -> SolveProfile {
+        SolveProfile {
+            occurs_check,
+            ..self
+        }
+    }
 
-        Err(ResolutionError::InsufficientGroundness(
-            goal.iter().map(|lit| lit.literal.clone()).collect(),
+    /// Solving with this profile tables the subgoals of the predicates that cannot produce
+    /// an image: each variant of a subgoal is solved once, and recursive predicates such as
+    /// a transitive closure terminate even when they are left recursive.
+    ///
+    /// A tabled literal is resolved with the answers of its subgoal, so the SLD tree does
+    /// not contain the proofs of these answers, but the proofs built from it contain the
+    /// first proof found of each answer.
+    pub fn with_tabling(self, tabling: bool) -> SolveProfile {
+        SolveProfile { tabling, ..self }
+    }
+
+    /// Solving with this profile explores the alternative resolvents of the first goal
+    /// that has several on a pool of threads. The solutions and errors are the same as
+    /// when solving on a single thread.
+    pub fn with_parallelism(self, parallel: bool) -> SolveProfile {
+        SolveProfile { parallel, ..self }
+    }
+
+    /// Solving with this profile evaluates the pure Datalog predicates, e.g. facts about
+    /// versions, bottom-up before the search, see [`datalog`]. Their literals are then
+    /// resolved with the facts computed, like builtins, so the tree does not contain the
+    /// proofs of these facts.
+    pub fn with_bottom_up(self, bottom_up: bool) -> SolveProfile {
+        SolveProfile { bottom_up, ..self }
+    }
+
+    /// Solving with this profile selects literals with the rule. The solutions are the
+    /// same with any rule, but the cheapest-first rule prunes failing branches sooner.
+    pub fn with_selection(self, selection: SelectionRule) -> SolveProfile {
+        SolveProfile { selection, ..self }
+    }
+
+    /// Solving with this profile stops once it exceeds one of the limits. The timeout and
+    /// the number of nodes apply to each solve, e.g. each query, and the threads of a
+    /// parallel solve share them.
+    pub fn with_limits(self, limits: ResourceLimits) -> SolveProfile {
+        SolveProfile { limits, ..self }
+    }
+
+    /// Starts counting the resources used by a solve against the limits.
+    fn start_solve(&mut self) {
+        self.deadline = self.limits.timeout.map(|t| Instant::now() + t);
+        self.solve_nodes = self.limits.max_nodes.map(|_| Arc::new(AtomicUsize::new(0)));
+        self.deepest_failures.clear();
+    }
+
+    /// The error that stops solving the goal, if solving was cancelled or exceeded a
+    /// limit.
+    fn interruption(&self, goal: &GoalWithHistory) -> Option<ResolutionError> {
+        let limit = if self.cancellation.is_cancelled() {
+            return Some(ResolutionError::Cancelled);
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            ResourceLimit::Timeout(self.limits.timeout?)
+        } else if self
+            .solve_nodes
+            .as_ref()
+            .zip(self.limits.max_nodes)
+            .is_some_and(|(explored, max)| explored.load(Ordering::Relaxed) > max)
+        {
+            ResourceLimit::Nodes(self.limits.max_nodes?)
+        } else {
+            return None;
+        };
+        Some(ResolutionError::ResourceLimitExceeded(
+            limit,
+            goal.iter().map(|l| l.literal.clone()).collect(),
         ))
     }
 
+    /// Adds the statistics recorded in `other` to this profile.
+    pub fn merge(&mut self, other: SolveProfile) {
+        if other.stats.max_depth > self.stats.max_depth || self.deepest_goal.is_empty() {
+            self.deepest_goal = other.deepest_goal;
+        }
+        for failure in other.deepest_failures {
+            self.fail(failure.level, &failure.literal.clone(), || failure);
+        }
+        self.stats.nodes_explored += other.stats.nodes_explored;
+        self.stats.max_depth = self.stats.max_depth.max(other.stats.max_depth);
+        self.stats.unifications += other.stats.unifications;
+        self.stats.builtin_invocations += other.stats.builtin_invocations;
+        self.stats.known_failures += other.stats.known_failures;
+        for (signature, p) in other.predicates {
+            let existing = self.predicates.entry(signature).or_default();
+            existing.time += p.time;
+            existing.selections += p.selections;
+            existing.resolutions += p.resolutions;
+            existing.backtracks += p.backtracks;
+        }
+    }
+
+    /// Records that resolution reached the goal at the given level of the SLD tree.
+    fn reach(&mut self, goal: &GoalWithHistory, level: TreeLevel) {
+        self.stats.nodes_explored += 1;
+        if let Some(explored) = &self.solve_nodes {
+            explored.fetch_add(1, Ordering::Relaxed);
+        }
+        if level > self.stats.max_depth || self.deepest_goal.is_empty() {
+            self.stats.max_depth = self.stats.max_depth.max(level);
+            // An empty goal is a proof, which is not worth reporting as the deepest goal.
+            if !goal.is_empty() {
+                self.deepest_goal = goal.iter().map(|l| l.literal.clone()).collect();
+            }
+        }
+    }
+
+    /// Records a literal that could not be resolved at the given level, unless resolution
+    /// failed deeper elsewhere.
+    fn fail(&mut self, level: TreeLevel, literal: &Literal, failure: impl FnOnce() -> Failure) {
+        let deepest = self.deepest_failures.first().map_or(0, |f| f.level);
+        if level > deepest {
+            self.deepest_failures.clear();
+        }
+        if level >= deepest && !self.deepest_failures.iter().any(|f| &f.literal == literal) {
+            self.deepest_failures.push(failure());
+        }
+    }
+
+    fn enter(&mut self, literal: &Literal) {
+        if !self.enabled {
+            return;
+        }
+        let signature = literal.signature();
+        self.predicates
+            .entry(signature.clone())
+            .or_default()
+            .selections += 1;
+        self.stack
+            .push((signature, Instant::now(), Duration::default()));
+    }
+
+    fn exit(&mut self) {
+        if let Some((signature, start, subgoals)) = self.stack.pop() {
+            let elapsed = start.elapsed();
+            self.predicates.get_mut(&signature).unwrap().time += elapsed.saturating_sub(subgoals);
+            if let Some(parent) = self.stack.last_mut() {
+                parent.2 += elapsed;
+            }
+        }
+    }
+
+    /// Records time spent on the subgoals of the literal currently being resolved,
+    /// outside of this profile.
+    fn spent_on_subgoals(&mut self, time: Duration) {
+        if let Some(current) = self.stack.last_mut() {
+            current.2 += time;
+        }
+    }
+
+    /// Records a resolution of the literal currently being resolved.
+    fn record_resolution(&mut self, success: bool) {
+        if let Some((signature, _, _)) = self.stack.last() {
+            let p = self.predicates.get_mut(signature).unwrap();
+            p.resolutions += 1;
+            if !success {
+                p.backtracks += 1;
+            }
+        }
+    }
+
+    /// The profiled predicates, the most time consuming first.
+    pub fn sorted(&self) -> Vec<(&Signature, &PredicateProfile)> {
+        let mut res = self.predicates.iter().collect::<Vec<_>>();
+        res.sort_by(|(s1, p1), (s2, p2)| {
+            p2.time
+                .cmp(&p1.time)
+                .then(p2.selections.cmp(&p1.selections))
+                .then_with(|| s1.to_string().cmp(&s2.to_string()))
+        });
+        res
+    }
+}
+
+/// The rules of a single signature, see [`RuleIndex`].
+#[derive(Debug, Default)]
+struct SignatureRules {
+    all: Vec<RuleId>,
+    /// Rules whose head has a constant first argument, by that constant.
+    by_first_arg: HashMap<Symbol, Vec<RuleId>>,
+    /// Rules whose head has some other first argument, e.g. a variable, which
+    /// may unify with any first argument.
+    other_first_arg: Vec<RuleId>,
+}
+
+/// Indexes rules by the signature of their head and the constant in its first
+/// argument, so that resolution only tries rules whose head could unify.
+///
+/// The literals of predicates evaluated bottom-up are resolved with the facts of the
+/// model instead.
+#[derive(Debug, Default)]
+struct RuleIndex(HashMap<Signature, SignatureRules>, Option<datalog::Model>);
+
+impl RuleIndex {
+    /// Indexes the rules, and evaluates their pure Datalog predicates if `bottom_up`.
+    fn with_model(rules: &[Clause], bottom_up: bool) -> RuleIndex {
+        RuleIndex(
+            RuleIndex::new(rules).0,
+            bottom_up.then(|| datalog::Model::new(rules)),
+        )
+    }
+
+    /// The facts of the literal's predicate, if it is evaluated bottom-up.
+    fn facts(&self, literal: &Literal) -> Option<&[Literal]> {
+        self.1.as_ref().and_then(|m| m.facts(literal))
+    }
+
+    fn new(rules: &[Clause]) -> RuleIndex {
+        let mut index = HashMap::<Signature, SignatureRules>::new();
+        for (rid, rule) in rules.iter().enumerate() {
+            let entry = index.entry(rule.head.signature()).or_default();
+            entry.all.push(rid);
+            match rule.head.args.first() {
+                Some(IRTerm::Constant(c)) => entry.by_first_arg.entry(*c).or_default().push(rid),
+                _ => entry.other_first_arg.push(rid),
+            }
+        }
+        RuleIndex(index, None)
+    }
+
+    /// The number of rules that could resolve the literal, see [`RuleIndex::candidates`].
+    fn count_candidates(&self, literal: &Literal) -> usize {
+        let entry = match self.0.get(&literal.signature()) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        match literal.args.first() {
+            Some(IRTerm::Constant(c)) => {
+                entry.by_first_arg.get(c).map_or(0, Vec::len) + entry.other_first_arg.len()
+            }
+            _ => entry.all.len(),
+        }
+    }
+
+    /// The rules that could resolve the literal, in the order they were given.
+    fn candidates(&self, literal: &Literal) -> Vec<RuleId> {
+        let entry = match self.0.get(&literal.signature()) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        match literal.args.first() {
+            Some(IRTerm::Constant(c)) => {
+                let mut res = entry
+                    .by_first_arg
+                    .get(c)
+                    .map(|rids| rids.iter().chain(&entry.other_first_arg).copied().collect())
+                    .unwrap_or_else(|| entry.other_first_arg.clone());
+                res.sort_unstable();
+                res
+            }
+            _ => entry.all.clone(),
+        }
+    }
+}
+
+/// Ground subgoals that are known to have no proof, so that they fail immediately when
+/// they are selected again on another branch of the same solve, instead of being searched
+/// again.
+///
+/// A ground literal binds no variable of the rest of the goal, so whether it has a proof
+/// does not depend on the branch it is selected on. A branch that selected it may have
+/// failed because of the rest of the goal though, so when a subgoal that failed before is
+/// selected again, it is first resolved on its own. Only failures that did not reach the
+/// maximum depth, or any error, count.
+#[derive(Clone, Debug, Default)]
+struct FailedSubgoals(HashMap<Literal, SubgoalStatus>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SubgoalStatus {
+    /// A branch that selected the subgoal failed.
+    FailedOnBranch,
+    /// The subgoal is being, or was, resolved on its own, and may have a proof.
+    Checked,
+    HasNoProof,
+}
+
+impl FailedSubgoals {
+    /// The literal, without its position, if it is a ground subgoal resolved with rules.
+    fn key(literal: &Literal) -> Option<Literal> {
+        let cacheable = literal.positive
+            && literal
+                .args
+                .iter()
+                .all(IRTerm::is_constant_or_compound_constant)
+            && !builtin::select_builtin(literal).0.is_match();
+        cacheable.then(|| Literal {
+            position: None,
+            ..literal.clone()
+        })
+    }
+
+    fn status(&self, key: &Literal) -> Option<SubgoalStatus> {
+        self.0.get(key).copied()
+    }
+
+    fn set(&mut self, key: Literal, status: SubgoalStatus) {
+        self.0.insert(key, status);
+    }
+}
+
+/// The answer tables of the subgoals of tabled predicates, see
+/// [`SolveProfile::with_tabling`].
+///
+/// Subgoals are keyed by variant, i.e. up to a renaming of their variables. A subgoal is
+/// solved on its own once, and the literal is resolved with each of its answers instead
+/// of with the rules. A subgoal selected again while it is being solved, e.g. through
+/// left recursion, only consumes the answers found so far, so the first subgoal of such
+/// a cycle is solved again until none of the tables gains an answer.
+#[derive(Clone, Debug, Default)]
+struct Tables {
+    /// The signatures of the predicates whose subgoals are tabled.
+    tabled: HashSet<Signature>,
+    entries: HashMap<Literal, AnswerTable>,
+    /// The subgoals being solved, innermost last.
+    stack: Vec<TableFrame>,
+    /// Whether a table gained an answer during the current iteration.
+    changed: bool,
+    /// Whether the next tabled literal selected is resolved with the rules, as it is the
+    /// subgoal being solved.
+    resolve_with_rules: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+struct AnswerTable {
+    /// The answers, each with the proof it was first found with.
+    answers: HashMap<Literal, Arc<Proof>>,
+    /// Whether the answers are all the answers of the subgoal.
+    complete: bool,
+}
+
+#[derive(Clone, Debug)]
+struct TableFrame {
+    key: Literal,
+    /// The lowest frame whose incomplete table was consumed while solving this subgoal.
+    lowest_dependency: Option<usize>,
+    /// The subgoals solved while solving this one that depend on a frame below theirs,
+    /// which are complete once the frame they depend on is.
+    dependents: Vec<Literal>,
+}
+
+impl Tables {
+    /// Tables the predicates of `rules` that cannot produce an image, if `enabled`, as
+    /// only the first proof of each answer is kept, which need not be the cheapest to
+    /// build.
+    fn new(rules: &[Clause], enabled: bool) -> Tables {
+        if !enabled {
+            return Tables::default();
+        }
+        let mut untabled: HashSet<Signature> = HashSet::new();
+        loop {
+            let before = untabled.len();
+            for c in rules {
+                if c.body.iter().any(|l| {
+                    is_image_producing(l)
+                        || l.predicate.is_operator()
+                        || untabled.contains(&l.signature())
+                }) {
+                    untabled.insert(c.head.signature());
+                }
+            }
+            if untabled.len() == before {
+                break;
+            }
+        }
+        let tabled = rules
+            .iter()
+            .map(|c| c.head.signature())
+            .filter(|sig| !untabled.contains(sig) && !sig.0 .0.starts_with('_'))
+            .collect();
+        Tables {
+            tabled,
+            ..Default::default()
+        }
+    }
+
+    fn is_tabled(&self, literal: &Literal) -> bool {
+        literal.positive && self.tabled.contains(&literal.signature())
+    }
+
+    /// The literal with its variables renamed in order of appearance, and without its
+    /// position, so that variants of a literal are equal.
+    fn variant(literal: &Literal) -> Literal {
+        Tables::variant_with_renaming(literal).0
+    }
+
+    /// Like [`Tables::variant`], along with the renaming of the variables.
+    fn variant_with_renaming(literal: &Literal) -> (Literal, Substitution) {
+        fn rename(term: &IRTerm, renaming: &mut HashMap<IRTerm, u32>) -> IRTerm {
+            match term {
+                IRTerm::Constant(_) => term.clone(),
+                IRTerm::List(ts) => IRTerm::List(ts.iter().map(|t| rename(t, renaming)).collect()),
+                _ => {
+                    let next = renaming.len() as u32;
+                    IRTerm::AuxiliaryVariable(*renaming.entry(term.clone()).or_insert(next))
+                }
+            }
+        }
+
+        let mut renaming = HashMap::new();
+        let variant = Literal {
+            position: None,
+            args: literal
+                .args
+                .iter()
+                .map(|t| rename(t, &mut renaming))
+                .collect(),
+            ..literal.clone()
+        };
+        let renaming = renaming
+            .into_iter()
+            .map(|(v, i)| (v, IRTerm::AuxiliaryVariable(i)))
+            .collect();
+        (variant, renaming)
+    }
+}
+
+/// Whether a failed search can be relied on, i.e. it did not stop at the maximum depth
+/// or because of an error.
+fn failed_cleanly(errors: &HashSet<ResolutionError>) -> bool {
+    errors.iter().all(|e| {
+        e.severity() != Severity::Error && !matches!(e, ResolutionError::MaximumDepthExceeded(..))
+    })
+}
+
+/// Result of building the SLD tree.
+///
+/// Uses a custom result type in resolution since we often have some information about
+/// either state, an 'Ok' and 'Err' state is too disjoint for our purposes.
+pub struct SLDResult {
+    pub tree: Tree,
+    pub errors: HashSet<ResolutionError>,
+}
+
+impl From<SLDResult> for Result<Tree, Vec<Diagnostic<()>>> {
+    fn from(sld_result: SLDResult) -> Self {
+        if sld_result.tree.is_success() {
+            Ok(sld_result.tree)
+        } else {
+            Err(sld_result
+                .errors
+                .into_iter()
+                .map(ResolutionError::normalize)
+                .unique()
+                .map(ResolutionError::get_diagnostic)
+                .collect::<Vec<_>>())
+        }
+    }
+}
+
+/// How the SLD tree is searched for proofs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SearchStrategy {
+    /// Explores the whole tree depth-first, finding every proof.
+    #[default]
+    DepthFirst,
+    /// Explores the branches with the fewest image-producing literals first, and stops
+    /// at the first proof found, which is the one with the fewest build steps.
+    BestFirst,
+}
+
+impl std::str::FromStr for SearchStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "depth-first" => Ok(SearchStrategy::DepthFirst),
+            "best-first" => Ok(SearchStrategy::BestFirst),
+            _ => Err(format!("unknown search strategy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SearchStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchStrategy::DepthFirst => write!(f, "depth-first"),
+            SearchStrategy::BestFirst => write!(f, "best-first"),
+        }
+    }
+}
+
+/// Which literal of a goal is resolved next, see [`SolveProfile::with_selection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SelectionRule {
+    /// Selects the leftmost literal that can be selected.
+    Leftmost,
+    /// Selects the literal that can be selected with the fewest alternatives to resolve
+    /// it with, preferring ground literals, such as checks, which bind no variable. Ties
+    /// are broken by selecting the leftmost.
+    #[default]
+    CheapestFirst,
+}
+
+impl std::str::FromStr for SelectionRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leftmost" => Ok(SelectionRule::Leftmost),
+            "cheapest-first" => Ok(SelectionRule::CheapestFirst),
+            _ => Err(format!("unknown selection rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SelectionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectionRule::Leftmost => write!(f, "leftmost"),
+            SelectionRule::CheapestFirst => write!(f, "cheapest-first"),
+        }
+    }
+}
+
+/// Whether resolving this literal adds a step to the build.
+fn is_image_producing(literal: &Literal) -> bool {
+    literal.positive && literal.predicate.naive_predicate_kind() != analysis::Kind::Logic
+}
+
+/// Returns a tree that contains both successful and failed paths, also, any resolution errors.
+/// To save on memory usage, can avoid storing the failed paths by passing false to `store_full_tree`.
+/// The search is deepened iteratively up to `maxdepth`, see [`sld_with_strategy`].
+///
+/// Unification does not perform the occurs check; use [`sld_profiled`] with
+/// [`SolveProfile::with_occurs_check`] for that.
+pub fn sld(
+    rules: &[Clause<IRTerm>],
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    store_full_tree: bool,
+) -> SLDResult {
+    sld_profiled(
+        rules,
+        goal,
+        maxdepth,
+        store_full_tree,
+        &mut SolveProfile::default(),
+    )
+}
+
+/// Like [`sld`], but records where resolution spent its time in `profile`.
+pub fn sld_profiled(
+    rules: &[Clause<IRTerm>],
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    store_full_tree: bool,
+    profile: &mut SolveProfile,
+) -> SLDResult {
+    sld_with_strategy(
+        rules,
+        goal,
+        maxdepth,
+        store_full_tree,
+        SearchStrategy::DepthFirst,
+        profile,
+    )
+}
+
+/// The stack size of the threads that solve, as resolution recurses at each level of the
+/// SLD tree, rather than the smaller default.
+pub(crate) const SOLVE_THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The depth of the first search of [`SearchStrategy::DepthFirst`].
+const INITIAL_DEPTH: TreeLevel = 16;
+
+/// Like [`sld_profiled`], but searching the tree with the given strategy.
+///
+/// [`SearchStrategy::DepthFirst`] deepens iteratively: it first searches up to a shallow
+/// depth, and doubles it while some branch was cut at the depth, up to `maxdepth`. A
+/// search that no branch reached the depth of is the same as the search up to `maxdepth`.
+///
+/// With [`SearchStrategy::BestFirst`], the tree only contains the path to the first
+/// proof found, regardless of `store_full_tree`.
+pub fn sld_with_strategy(
+    rules: &[Clause<IRTerm>],
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    store_full_tree: bool,
+    strategy: SearchStrategy,
+    profile: &mut SolveProfile,
+) -> SLDResult {
+    solve(
+        rules,
+        goal,
+        maxdepth,
+        store_full_tree,
+        strategy,
+        profile,
+        None,
+    )
+}
+
+/// Builds the tree like [`sld_with_strategy`], unless `on_proof` is given, in which case
+/// the proofs are searched for in the order of the strategy and passed to it, as trees
+/// that only contain the path to the proof, until it returns false. The returned tree
+/// is then only the root.
+fn solve(
+    rules: &[Clause<IRTerm>],
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    store_full_tree: bool,
+    strategy: SearchStrategy,
+    profile: &mut SolveProfile,
+    on_proof: Option<&mut dyn FnMut(Tree) -> bool>,
+) -> SLDResult {
+    /// Returns whether the literal can be selected given the groundness of its
+    /// arguments, or `None` if its predicate is unknown.
+    fn can_select(literal: &Literal, grounded: &HashMap<Signature, Vec<bool>>) -> Option<bool> {
+        // A negated literal must have only constants or anonymous variables (which represent
+        // variables that will not equal any other).
+        // Otherwise, something like !string_eq("constant", X) would be pointless, the
+        // user very likely means X to be bound through some other literal.
+        // An alternative approach would be to check other variables in the goal.
+        let positive_or_grounded_negation = literal.positive
+            || literal
+                .args
+                .iter()
+                .all(|arg| arg.is_constant() || arg.is_underlying_anonymous_variable());
+
+        // The variables of the goal of a findall that are not collected through the template
+        // must be bound before the goal is solved, or be anonymous.
+        if let Some((template, _, goal)) = literal.findall_parts() {
+            let collected = template.variables(false);
+            return Some(
+                literal.positive
+                    && goal
+                        .variables(false)
+                        .iter()
+                        .all(|v| collected.contains(v) || v.is_underlying_anonymous_variable()),
+            );
+        }
+
+        let select_builtin_res = builtin::select_builtin(literal);
+        if select_builtin_res.0.is_match() && positive_or_grounded_negation {
+            return Some(true);
+        }
+
+        // For any user-defined atom, we can get its groundness requirement
+        // (computed outside), and if a particular argument can not be
+        // ungrounded (grounded[arg_index] == false), variables will not be
+        // allowed there.
+        if let Some(lit_grounded) = grounded.get(&literal.signature()) {
+            debug_assert_eq!(lit_grounded.len(), literal.args.len());
+            Some(
+                positive_or_grounded_negation
+                    && literal.args.iter().zip(lit_grounded.iter()).all(
+                        |(term, allows_ungrounded)| {
+                            *allows_ungrounded || term.is_constant_or_compound_constant()
+                        },
+                    ),
+            )
+        } else if select_builtin_res.0 == SelectBuiltinResult::GroundnessMismatch
+            || select_builtin_res.0 == SelectBuiltinResult::Match
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// An estimate of the cost of selecting the literal: whether it binds variables, and
+    /// the number of alternatives to resolve it with. Negations, findalls and builtins
+    /// count as a single alternative.
+    fn selection_cost(literal: &Literal, index: &RuleIndex) -> (bool, usize) {
+        let alternatives = if !literal.positive
+            || literal.findall_parts().is_some()
+            || builtin::select_builtin(literal).0.is_match()
+        {
+            1
+        } else if let Some(facts) = index.facts(literal) {
+            facts.len()
+        } else {
+            index.count_candidates(literal)
+        };
+        (!literal.is_ground(), alternatives)
+    }
+
+    /// Selects a literal with compatible groundness by the selection rule: the leftmost
+    /// one, or the one with the lowest `selection_cost`, the leftmost of those if several.
+    ///
+    /// Number comparisons that are not ground yet stay suspended in the goal until their
+    /// variables are bound, but fail the goal early if they cannot all hold.
+    fn select(
+        goal: &GoalWithHistory,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        rule: SelectionRule,
+        index: &RuleIndex,
+    ) -> Result<(LiteralGoalId, Arc<LiteralWithHistory>), ResolutionError> {
+        if let Some(l) = builtin::inconsistent_comparison(goal.iter().map(|l| &l.literal)) {
+            return Err(ResolutionError::BuiltinFailure(
+                l.clone(),
+                l.predicate.0.as_str(),
+            ));
+        }
+        let mut cheapest: Option<((bool, usize), LiteralGoalId)> = None;
+        for (id, lit) in goal.iter().enumerate() {
+            match can_select(&lit.literal, grounded) {
+                Some(true) if rule == SelectionRule::Leftmost => return Ok((id, lit.clone())),
+                Some(true) => {
+                    let cost = selection_cost(&lit.literal, index);
+                    if cheapest.is_none_or(|(c, _)| cost < c) {
+                        cheapest = Some((cost, id));
+                    }
+                    // No literal is cheaper than a ground one with a single alternative.
+                    if cost <= (false, 1) {
+                        break;
+                    }
+                }
+                Some(false) => continue,
+                // The leftmost rule would stop at an unknown predicate as well, unless it
+                // selected a literal before it.
+                None if cheapest.is_some() => break,
+                None => return Err(ResolutionError::UnknownPredicate(lit.literal.clone())),
+            }
+        }
+
+        match cheapest {
+            Some((_, id)) => Ok((id, goal[id].clone())),
+            None => Err(ResolutionError::InsufficientGroundness(
+                goal.iter().map(|lit| lit.literal.clone()).collect(),
+            )),
+        }
+    }
+
+    /// Reorders the literals of a resolved rule body so that, within each operator
+    /// scope, literals come after the literals binding the variables they need to
+    /// be ground, and literals that are already ground (typically checks) come first,
+    /// which prunes failing branches early.
+    ///
+    /// This only changes the order in which literals are selected; proofs are still
+    /// constructed in the order of the body, using the origin of each literal.
+    fn reorder_by_groundness(
+        body: &mut [Arc<LiteralWithHistory>],
+        grounded: &HashMap<Signature, Vec<bool>>,
+    ) {
+        // Marks the variables in `bound` as ground, so that they are treated as
+        // such by `can_select`.
+        let placeholder = IRTerm::Constant("".into());
+        let assume_bound = |literal: &Literal, bound: &HashSet<IRTerm>| {
+            let sub: Substitution = literal
+                .variables(false)
+                .into_iter()
+                .filter(|v| bound.contains(v))
+                .map(|v| (v, placeholder.clone()))
+                .collect();
+            literal.substitute(&sub)
+        };
+
+        let mut start = 0;
+        while start < body.len() {
+            let end = body[start..]
+                .iter()
+                .position(|l| l.literal.predicate.is_operator())
+                .map_or(body.len(), |i| start + i);
+            let scope = &mut body[start..end];
+
+            let mut bound = HashSet::new();
+            for i in 0..scope.len() {
+                let ready = (i..scope.len())
+                    .filter(|&j| {
+                        can_select(&assume_bound(&scope[j].literal, &bound), grounded)
+                            .unwrap_or(true)
+                    })
+                    .collect::<Vec<_>>();
+                let is_ground = |j: &&usize| {
+                    scope[**j]
+                        .literal
+                        .variables(false)
+                        .iter()
+                        .all(|v| bound.contains(v))
+                };
+                match ready.iter().find(is_ground).or_else(|| ready.first()) {
+                    Some(&j) => {
+                        scope[i..=j].rotate_right(1);
+                        bound.extend(scope[i].literal.variables(false));
+                    }
+                    // Keep the rest as is, selection will report the error.
+                    None => break,
+                }
+            }
+
+            start = end + 1;
+        }
+    }
+
     /// NOTE: the new goals are added *first*, so the behaviour of SLD changes (but not the
     /// semantics, I think), making it more 'eager' to resolve.
     /// This makes it possible to get significant performance boosts by placing ground facts first
     /// in the body of some expression, in your Modusfile(s).
     /// For example, `fact(c), expensive_goal(c)`, may waste a lot of time and memory if `fact(c)` is
     /// not true. With the 'eager' approach, SLD will quickly terminate if `fact(c)` is false.
+    /// Literals of rule bodies that are ground are also moved first by `reorder_by_groundness`.
     fn resolve(
         lid: LiteralGoalId,
         rid: ClauseId,
@@ -928,16 +2328,16 @@ pub fn sld(
                 clause: rid.clone(),
                 body_index: id,
             };
-            LiteralWithHistory {
+            Arc::new(LiteralWithHistory {
                 literal: l.clone(),
                 introduction: level,
                 origin,
-            }
+            })
         });
         let g = new_goals
-            .chain(goal.into_iter().enumerate().filter_map(|(i, v)| {
+            .chain(goal.iter().enumerate().filter_map(|(i, v)| {
                 if i != lid {
-                    Some(v.clone())
+                    Some(Arc::clone(v))
                 } else {
                     None
                 }
@@ -946,24 +2346,32 @@ pub fn sld(
         g.substitute(mgu)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_negated_literal(
         lid: LiteralGoalId,
-        l: LiteralWithHistory,
+        l: Arc<LiteralWithHistory>,
         goal: &GoalWithHistory,
         rules: &[Clause<IRTerm>],
         maxdepth: TreeLevel,
         level: TreeLevel,
         grounded: &HashMap<Signature, Vec<bool>>,
         store_full_tree: bool,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
     ) -> SLDResult {
         let mut errs: HashSet<ResolutionError> = HashSet::new();
 
-        let singleton_goal = vec![LiteralWithHistory {
+        let singleton_goal = vec![Arc::new(LiteralWithHistory {
             literal: l.literal.negated(),
-            ..l
-        }];
+            introduction: l.introduction,
+            origin: l.origin.clone(),
+        })];
 
-        // Perform SLD resolution with this goal and check if it succeeds or not.
+        // Perform SLD resolution with this goal and check if it succeeds or not. The
+        // literals it fails to resolve do not explain the failure of the outer goal.
+        let outer_failures = std::mem::take(&mut profile.deepest_failures);
         let sld_res = inner(
             rules,
             &singleton_goal,
@@ -972,11 +2380,16 @@ pub fn sld(
             0,
             grounded,
             store_full_tree,
+            profile,
+            index,
+            failed,
+            tables,
         );
+        profile.deepest_failures = outer_failures;
 
         let rid = ClauseId::NegationCheck(l.literal.negated());
-        let mgu = HashMap::new();
-        let renaming = HashMap::new();
+        let mgu = Substitution::new();
+        let renaming = Substitution::new();
 
         let mut success_resolvents = HashMap::new();
         let mut fail_resolvents = HashMap::new();
@@ -988,7 +2401,7 @@ pub fn sld(
                 fail_resolvents.insert((lid, rid), (mgu, renaming, sld_res.tree));
             }
 
-            let err = ResolutionError::NegationProof(l.literal);
+            let err = ResolutionError::NegationProof(l.literal.clone());
             if !subtree_error {
                 errs.insert(err.clone());
             }
@@ -1000,7 +2413,7 @@ pub fn sld(
                 error: if subtree_error { None } else { Some(err) },
             };
 
-            return SLDResult { tree, errors: errs };
+            SLDResult { tree, errors: errs }
         } else {
             let resolvent = resolve(
                 lid,
@@ -1008,7 +2421,7 @@ pub fn sld(
                 goal,
                 &mgu,
                 &Clause {
-                    head: l.literal,
+                    head: l.literal.clone(),
                     body: Vec::new(),
                 },
                 level + 1,
@@ -1020,8 +2433,13 @@ pub fn sld(
                 level + 1,
                 grounded,
                 store_full_tree,
+                profile,
+                index,
+                failed,
+                tables,
             );
 
+            profile.record_resolution(tree.is_success());
             if tree.is_success() {
                 success_resolvents.insert((lid, rid), (mgu, renaming, tree));
             } else if store_full_tree {
@@ -1037,19 +2455,494 @@ pub fn sld(
                 error: None,
             };
 
-            return SLDResult { tree, errors: errs };
+            SLDResult { tree, errors: errs }
         }
     }
 
-    fn inner(
-        rules: &[Clause<IRTerm>],
-        goal: &GoalWithHistory,
-        maxdepth: TreeLevel,
-        level: TreeLevel,
-        grounded: &HashMap<Signature, Vec<bool>>,
-        store_full_tree: bool,
+    /// A resolvent along with the clause, mgu and renaming that produced it.
+    type Resolvent = (ClauseId, Substitution, Substitution, GoalWithHistory);
+
+    /// Unifies `head` with the selected literal, performing the occurs check if the
+    /// profile asks for it, in which case a failure is recorded in `errors`.
+    fn unify_selected(
+        head: &Literal,
+        selected: &Literal,
+        profile: &mut SolveProfile,
+        errors: &mut Vec<ResolutionError>,
+    ) -> Option<Substitution> {
+        fn size(term: &IRTerm) -> usize {
+            match term {
+                IRTerm::List(ts) => 1 + ts.iter().map(size).sum::<usize>(),
+                _ => 1,
+            }
+        }
+
+        profile.stats.unifications += 1;
+        let mgu = if profile.occurs_check {
+            head.unify_with_occurs_check(selected)
+                .unwrap_or_else(|failure| {
+                    errors.push(ResolutionError::OccursCheck(
+                        selected.clone(),
+                        failure.variable,
+                    ));
+                    None
+                })
+        } else {
+            head.unify(selected)
+        }?;
+        match profile.limits.max_substitution_size {
+            Some(max) if mgu.values().map(size).sum::<usize>() > max => {
+                errors.push(ResolutionError::ResourceLimitExceeded(
+                    ResourceLimit::SubstitutionSize(max),
+                    vec![selected.clone()],
+                ));
+                None
+            }
+            _ => Some(mgu),
+        }
+    }
+
+    /// Resolves the selected `findall` literal by solving its goal on its own, like a
+    /// negated literal, and unifying its list with the sorted and deduplicated values
+    /// of the template in each solution.
+    #[allow(clippy::too_many_arguments)]
+    fn findall_resolvents(
+        lid: LiteralGoalId,
+        l: &LiteralWithHistory,
+        goal: &GoalWithHistory,
+        rules: &[Clause<IRTerm>],
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
+    ) -> (Vec<Resolvent>, Vec<ResolutionError>) {
+        let (template, _, subgoal) = l
+            .literal
+            .findall_parts()
+            .expect("should only be called on findall literals");
+        profile.stats.builtin_invocations += 1;
+
+        let singleton_goal = vec![Arc::new(LiteralWithHistory {
+            literal: subgoal.clone(),
+            introduction: l.introduction,
+            origin: l.origin.clone(),
+        })];
+        let outer_failures = std::mem::take(&mut profile.deepest_failures);
+        let sld_res = inner(
+            rules,
+            &singleton_goal,
+            // The stratifiability check should make it safe to use the same maxdepth.
+            maxdepth,
+            0,
+            grounded,
+            false,
+            profile,
+            index,
+            failed,
+            tables,
+        );
+        profile.deepest_failures = outer_failures;
+
+        // An incomplete search would give an incomplete list.
+        let incomplete = sld_res
+            .errors
+            .iter()
+            .filter(|e| {
+                e.severity() == Severity::Error
+                    || matches!(e, ResolutionError::MaximumDepthExceeded(..))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if !incomplete.is_empty() {
+            return (Vec::new(), incomplete);
+        }
+
+        let mut values = Vec::new();
+        for solution in solutions(&sld_res.tree) {
+            let value = subgoal
+                .unify(&solution[0])
+                .map(|mgu| template.substitute(&mgu))
+                .expect("a solution should be an instance of the goal");
+            if !value.is_constant_or_compound_constant() {
+                return (
+                    Vec::new(),
+                    vec![ResolutionError::InsufficientGroundness(vec![l
+                        .literal
+                        .clone()])],
+                );
+            }
+            values.push(value);
+        }
+        values.sort();
+        values.dedup();
+
+        let mut head = l.literal.clone();
+        head.args[1] = IRTerm::List(values);
+        profile.stats.unifications += 1;
+        match head.unify(&l.literal) {
+            Some(mgu) => {
+                let rid = ClauseId::Builtin(head.clone());
+                let resolvent = resolve(
+                    lid,
+                    rid.clone(),
+                    goal,
+                    &mgu,
+                    &Clause {
+                        head,
+                        body: Vec::new(),
+                    },
+                    level + 1,
+                );
+                (vec![(rid, mgu, Substitution::new(), resolvent)], Vec::new())
+            }
+            None => (
+                Vec::new(),
+                vec![ResolutionError::BuiltinFailure(
+                    l.literal.clone(),
+                    "findall",
+                )],
+            ),
+        }
+    }
+
+    /// The answers of the subgoal of the selected tabled literal, solving it unless
+    /// its table is complete or it is already being solved, along with the errors of
+    /// solving it.
+    #[allow(clippy::too_many_arguments)]
+    fn tabled_answers(
+        l: &LiteralWithHistory,
+        rules: &[Clause<IRTerm>],
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
+    ) -> (Vec<TabledAnswer>, Vec<ResolutionError>) {
+        fn answers(table: &AnswerTable) -> Vec<TabledAnswer> {
+            table
+                .answers
+                .iter()
+                .map(|(answer, proof)| TabledAnswer {
+                    answer: answer.clone(),
+                    proof: proof.clone(),
+                })
+                .collect()
+        }
+
+        let key = Tables::variant(&l.literal);
+        if let Some(table) = tables.entries.get(&key) {
+            if table.complete {
+                return (answers(table), Vec::new());
+            }
+            if let Some(i) = tables.stack.iter().position(|f| f.key == key) {
+                let top = tables
+                    .stack
+                    .last_mut()
+                    .expect("the subgoal is on the stack");
+                top.lowest_dependency = Some(top.lowest_dependency.map_or(i, |d| d.min(i)));
+                return (answers(table), Vec::new());
+            }
+        }
+
+        let own = tables.stack.len();
+        tables.entries.entry(key.clone()).or_default();
+        tables.stack.push(TableFrame {
+            key: key.clone(),
+            lowest_dependency: None,
+            dependents: Vec::new(),
+        });
+        // The subgoal is the query of its own tree, so that the proofs of its answers can
+        // be built from the tree.
+        let subgoal = vec![l.literal.clone()];
+        let singleton_goal = vec![Arc::new(LiteralWithHistory {
+            literal: l.literal.clone(),
+            introduction: level,
+            origin: LiteralOrigin {
+                clause: ClauseId::Query,
+                body_index: 0,
+            },
+        })];
+        let outer_changed = tables.changed;
+        let mut changed = false;
+        let errors = loop {
+            tables.changed = false;
+            tables.resolve_with_rules = true;
+            // Starts at the current level, so that solving subgoals that are always new
+            // still stops at the maximum depth.
+            let sld_res = inner(
+                rules,
+                &singleton_goal,
+                maxdepth,
+                level,
+                grounded,
+                false,
+                profile,
+                index,
+                failed,
+                tables,
+            );
+            // Left set if the search stopped before selecting the subgoal.
+            tables.resolve_with_rules = false;
+            for (solution, proof) in
+                proofs_iter(&sld_res.tree, rules, &subgoal).first_per_solution()
+            {
+                let (answer, renaming) = Tables::variant_with_renaming(&solution[0]);
+                let table = tables.entries.get_mut(&key).expect("the table was created");
+                if let hash_map::Entry::Vacant(e) = table.answers.entry(answer) {
+                    e.insert(Arc::new(proof.children[0].instantiate(&renaming)));
+                    tables.changed = true;
+                }
+            }
+            changed |= tables.changed;
+            let leads_cycle = tables.stack[own].lowest_dependency == Some(own);
+            if !leads_cycle || !tables.changed || !failed_cleanly(&sld_res.errors) {
+                break sld_res.errors;
+            }
+        };
+        tables.changed = outer_changed || changed;
+
+        let frame = tables.stack.pop().expect("the frame was pushed");
+        match frame.lowest_dependency.filter(|&d| d < own) {
+            Some(d) => {
+                let below = tables.stack.last_mut().expect("a frame is below this one");
+                below.lowest_dependency = Some(below.lowest_dependency.map_or(d, |b| b.min(d)));
+                below.dependents.push(frame.key);
+                below.dependents.extend(frame.dependents);
+            }
+            None if failed_cleanly(&errors) => {
+                for k in frame.dependents.iter().chain([&frame.key]) {
+                    if let Some(table) = tables.entries.get_mut(k) {
+                        table.complete = true;
+                    }
+                }
+            }
+            None => {}
+        }
+
+        (answers(&tables.entries[&key]), errors.into_iter().collect())
+    }
+
+    /// Resolves the selected tabled literal with the answers of its subgoal.
+    #[allow(clippy::too_many_arguments)]
+    fn tabled_resolvents(
+        lid: LiteralGoalId,
+        l: &LiteralWithHistory,
+        goal: &GoalWithHistory,
+        rules: &[Clause<IRTerm>],
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
+    ) -> (Vec<Resolvent>, Vec<ResolutionError>) {
+        let (answers, mut errors) = tabled_answers(
+            l, rules, maxdepth, level, grounded, profile, index, failed, tables,
+        );
+        let resolvents = answers
+            .into_iter()
+            .filter_map(|tabled| {
+                let (answer, renaming) = tabled.answer.rename_with_sub();
+                unify_selected(&answer, &l.literal, profile, &mut errors).map(|mgu| {
+                    let rid = ClauseId::Tabled(tabled);
+                    let resolvent = resolve(
+                        lid,
+                        rid.clone(),
+                        goal,
+                        &mgu,
+                        &Clause {
+                            head: answer,
+                            body: Vec::new(),
+                        },
+                        level + 1,
+                    );
+                    (rid, mgu, renaming, resolvent)
+                })
+            })
+            .collect();
+        (resolvents, errors)
+    }
+
+    /// The resolvents of the goal obtained by resolving the selected positive literal
+    /// with a builtin or with the user's rules, along with the errors explaining why
+    /// the literal could not be resolved, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn resolvents(
+        lid: LiteralGoalId,
+        l: &LiteralWithHistory,
+        goal: &GoalWithHistory,
+        rules: &[Clause<IRTerm>],
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
+    ) -> (Vec<Resolvent>, Vec<ResolutionError>) {
+        if l.literal.findall_parts().is_some() {
+            return findall_resolvents(
+                lid, l, goal, rules, maxdepth, level, grounded, profile, index, failed, tables,
+            );
+        }
+
+        if index.facts(&l.literal).is_none()
+            && tables.is_tabled(&l.literal)
+            && !std::mem::take(&mut tables.resolve_with_rules)
+        {
+            return tabled_resolvents(
+                lid, l, goal, rules, maxdepth, level, grounded, profile, index, failed, tables,
+            );
+        }
+
+        let mut errors = Vec::new();
+
+        let selected_builtin = builtin::select_builtin(&l.literal);
+        let builtin_candidates = match selected_builtin {
+            (SelectBuiltinResult::Match, Some(pred)) => {
+                profile.stats.builtin_invocations += 1;
+                pred.apply_all(&l.literal)
+            }
+            _ => Vec::new(),
+        };
+        let builtin_resolves = builtin_candidates
+            .into_iter()
+            .filter_map(|unify_cand| {
+                unify_selected(&unify_cand, &l.literal, profile, &mut errors).map(|mgu| {
+                    (
+                        ClauseId::Builtin(unify_cand.clone()),
+                        mgu.clone(),
+                        Substitution::<IRTerm>::new(),
+                        resolve(
+                            lid,
+                            ClauseId::Builtin(unify_cand.clone()),
+                            goal,
+                            &mgu,
+                            &Clause {
+                                head: unify_cand,
+                                body: Vec::new(), // TODO: allow builtin rules to return more conditions?
+                            },
+                            level + 1,
+                        ),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if selected_builtin.0.is_match() && builtin_resolves.is_empty() {
+            errors.push(ResolutionError::BuiltinFailure(
+                l.literal.clone(),
+                selected_builtin
+                    .1
+                    .expect("match should provide builtin")
+                    .name(),
+            ));
+        }
+
+        let user_rules_resolves: Vec<Resolvent> = if let Some(facts) = index.facts(&l.literal) {
+            // The facts are ground, so they need no renaming.
+            facts
+                .iter()
+                .filter_map(|fact| {
+                    unify_selected(fact, &l.literal, profile, &mut errors).map(|mgu| {
+                        let rid = ClauseId::Builtin(fact.clone());
+                        let resolvent = resolve(
+                            lid,
+                            rid.clone(),
+                            goal,
+                            &mgu,
+                            &Clause {
+                                head: fact.clone(),
+                                body: Vec::new(),
+                            },
+                            level + 1,
+                        );
+                        (rid, mgu, Substitution::new(), resolvent)
+                    })
+                })
+                .collect()
+        } else {
+            index
+                .candidates(&l.literal)
+                .into_iter()
+                .map(|rid| (ClauseId::Rule(rid), rules[rid].rename_with_sub()))
+                .filter_map(|(rid, (c, renaming))| {
+                    unify_selected(&c.head, &l.literal, profile, &mut errors).map(|mgu| {
+                        let mut resolvent = resolve(lid, rid.clone(), goal, &mgu, &c, level + 1);
+                        reorder_by_groundness(&mut resolvent[..c.body.len()], grounded);
+                        (rid, mgu, renaming, resolvent)
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        // A failed occurs check or an exceeded limit already explains why no rule applied.
+        if !selected_builtin.0.is_match()
+            && user_rules_resolves.is_empty()
+            && !errors.iter().any(|e| {
+                matches!(
+                    e,
+                    ResolutionError::OccursCheck(..) | ResolutionError::ResourceLimitExceeded(..)
+                )
+            })
+        {
+            errors.push(ResolutionError::InsufficientRules(l.literal.clone()));
+        }
+        if builtin_resolves.is_empty() && user_rules_resolves.is_empty() {
+            let literal = l.literal.clone().normalized_terms();
+            profile.fail(level, &literal, || Failure {
+                level,
+                literal: literal.clone(),
+                builtin: selected_builtin.1.map(|b| b.name()),
+                candidates: rules
+                    .iter()
+                    .filter(|c| c.head.signature() == l.literal.signature())
+                    .cloned()
+                    .collect(),
+            });
+        }
+
+        (
+            builtin_resolves
+                .into_iter()
+                .chain(user_rules_resolves)
+                .collect(),
+            errors,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn inner(
+        rules: &[Clause<IRTerm>],
+        goal: &GoalWithHistory,
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        store_full_tree: bool,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
     ) -> SLDResult {
-        if goal.is_empty() {
+        profile.reach(goal, level);
+        if let Some(error) = profile.interruption(goal) {
+            let t = Tree {
+                goal: goal.to_owned(),
+                level,
+                success_resolvents: HashMap::new(),
+                fail_resolvents: HashMap::new(),
+                error: Some(error.clone()),
+            };
+            SLDResult {
+                tree: t,
+                errors: vec![error].into_iter().collect(),
+            }
+        } else if goal.is_empty() {
             let t = Tree {
                 goal: goal.to_owned(),
                 level,
@@ -1078,7 +2971,7 @@ pub fn sld(
             let errors = vec![error].into_iter().collect();
             SLDResult { tree: t, errors }
         } else {
-            let selection_res = select(goal, grounded);
+            let selection_res = select(goal, grounded, profile.selection, index);
             if let Err(e) = selection_res {
                 let t = Tree {
                     goal: goal.to_owned(),
@@ -1094,8 +2987,9 @@ pub fn sld(
             }
             let (lid, l) = selection_res.unwrap();
 
+            profile.enter(&l.literal);
             if !l.literal.positive {
-                return handle_negated_literal(
+                let res = handle_negated_literal(
                     lid,
                     l,
                     goal,
@@ -1104,72 +2998,66 @@ pub fn sld(
                     level,
                     grounded,
                     store_full_tree,
+                    profile,
+                    index,
+                    failed,
+                    tables,
                 );
+                profile.exit();
+                return res;
             }
 
-            let mut errs: HashSet<ResolutionError> = HashSet::new();
-
-            let selected_builtin = builtin::select_builtin(&l.literal);
-            let builtin_resolves = match selected_builtin {
-                (SelectBuiltinResult::Match, lit) => lit,
-                _ => None,
-            }
-            .and_then(|pred| pred.apply(&l.literal))
-            .and_then(|unify_cand| {
-                unify_cand.unify(&l.literal).map(|mgu| {
-                    (
-                        ClauseId::Builtin(unify_cand.clone()),
-                        mgu.clone(),
-                        Substitution::<IRTerm>::new(),
-                        resolve(
-                            lid,
-                            ClauseId::Builtin(unify_cand.clone()),
-                            goal,
-                            &mgu,
-                            &Clause {
-                                head: unify_cand,
-                                body: Vec::new(), // TODO: allow builtin rules to return more conditions?
-                            },
-                            level + 1,
-                        ),
-                    )
-                })
-            });
-
-            let mut leaf_error = None;
-            if selected_builtin.0.is_match() && builtin_resolves.is_none() {
-                let err = ResolutionError::BuiltinFailure(
-                    l.literal.clone(),
-                    selected_builtin
-                        .1
-                        .expect("match should provide builtin")
-                        .name(),
-                );
-                errs.insert(err.clone());
-                leaf_error = Some(err);
+            // A tabled subgoal may fail while the tables of its cycle are incomplete, and
+            // its table already records whether it has a proof.
+            let subgoal = FailedSubgoals::key(&l.literal).filter(|_| !tables.is_tabled(&l.literal));
+            let status = subgoal.as_ref().and_then(|k| failed.status(k));
+            let has_no_proof = match (status, &subgoal) {
+                (Some(SubgoalStatus::HasNoProof), _) => true,
+                (Some(SubgoalStatus::FailedOnBranch), Some(subgoal)) => {
+                    // Marked first, so that resolving it on its own does not check it again.
+                    failed.set(subgoal.clone(), SubgoalStatus::Checked);
+                    let alone = inner(
+                        rules,
+                        &vec![l.clone()],
+                        maxdepth,
+                        0,
+                        grounded,
+                        false,
+                        profile,
+                        index,
+                        failed,
+                        tables,
+                    );
+                    let fails = !alone.tree.is_success() && failed_cleanly(&alone.errors);
+                    if fails {
+                        failed.set(subgoal.clone(), SubgoalStatus::HasNoProof);
+                    }
+                    fails
+                }
+                _ => false,
+            };
+            if has_no_proof {
+                profile.stats.known_failures += 1;
+                profile.record_resolution(false);
+                profile.exit();
+                let tree = Tree {
+                    goal: goal.to_owned(),
+                    level,
+                    success_resolvents: HashMap::new(),
+                    fail_resolvents: HashMap::new(),
+                    error: None,
+                };
+                return SLDResult {
+                    tree,
+                    errors: HashSet::new(),
+                };
             }
 
-            let user_rules_resolves = rules
-                .iter()
-                .enumerate()
-                .filter(|(_, c)| c.head.signature() == l.literal.signature())
-                .map(|(rid, c)| (ClauseId::Rule(rid), c.rename_with_sub()))
-                .filter_map(|(rid, (c, renaming))| {
-                    c.head.unify(&l.literal).map(|mgu| {
-                        (
-                            rid.clone(),
-                            mgu.clone(),
-                            renaming,
-                            resolve(lid, rid, goal, &mgu, &c, level + 1),
-                        )
-                    })
-                })
-                .collect::<Vec<_>>();
-            if !selected_builtin.0.is_match() && user_rules_resolves.is_empty() {
-                let err = ResolutionError::InsufficientRules(l.literal.clone());
-                errs.insert(err.clone());
-                leaf_error = leaf_error.or(Some(err));
-            }
+            let (resolvents, errors) = resolvents(
+                lid, &l, goal, rules, maxdepth, level, grounded, profile, index, failed, tables,
+            );
+            let leaf_error = errors.first().cloned();
+            let mut errs: HashSet<ResolutionError> = errors.into_iter().collect();
 
             let mut success_resolvents: HashMap<
                 (LiteralGoalId, ClauseId),
@@ -1179,17 +3067,42 @@ pub fn sld(
                 (LiteralGoalId, ClauseId),
                 (Substitution, Substitution, Tree),
             > = HashMap::new();
-            for (rid, mgu, renaming, resolvent) in
-                builtin_resolves.into_iter().chain(user_rules_resolves)
-            {
-                let SLDResult { tree, errors } = inner(
-                    rules,
-                    &resolvent,
-                    maxdepth,
-                    level + 1,
-                    grounded,
-                    store_full_tree,
-                );
+            // Only the first node with alternatives is explored in parallel, outside of
+            // the solving of a tabled subgoal, whose cycle must be solved on one thread.
+            let mut explored =
+                (profile.parallel && resolvents.len() > 1 && tables.stack.is_empty()).then(|| {
+                    let goals = resolvents.iter().map(|r| &r.3).collect::<Vec<_>>();
+                    explore_in_parallel(
+                        rules,
+                        &goals,
+                        maxdepth,
+                        level + 1,
+                        grounded,
+                        store_full_tree,
+                        profile,
+                        index,
+                        failed,
+                        tables,
+                    )
+                    .into_iter()
+                });
+            for (rid, mgu, renaming, resolvent) in resolvents {
+                let SLDResult { tree, errors } = match explored.as_mut() {
+                    Some(results) => results.next().expect("a result per resolvent"),
+                    None => inner(
+                        rules,
+                        &resolvent,
+                        maxdepth,
+                        level + 1,
+                        grounded,
+                        store_full_tree,
+                        profile,
+                        index,
+                        failed,
+                        tables,
+                    ),
+                };
+                profile.record_resolution(tree.is_success());
                 if tree.is_success() {
                     success_resolvents.insert((lid, rid), (mgu, renaming, tree));
                 } else if store_full_tree {
@@ -1205,11 +3118,300 @@ pub fn sld(
                 fail_resolvents,
                 error: leaf_error,
             };
+            profile.exit();
+
+            if let Some(subgoal) = subgoal {
+                if status.is_none() && !tree.is_success() && failed_cleanly(&errs) {
+                    failed.set(subgoal, SubgoalStatus::FailedOnBranch);
+                }
+            }
 
             SLDResult { tree, errors: errs }
         }
     }
 
+    /// Builds the trees of the goals on a pool of threads, each with its own copy of the
+    /// known failures and tables, and returns them in the order of the goals.
+    #[allow(clippy::too_many_arguments)]
+    fn explore_in_parallel(
+        rules: &[Clause<IRTerm>],
+        goals: &[&GoalWithHistory],
+        maxdepth: TreeLevel,
+        level: TreeLevel,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        store_full_tree: bool,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &FailedSubgoals,
+        tables: &Tables,
+    ) -> Vec<SLDResult> {
+        let workers = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(goals.len());
+        let next_goal = AtomicUsize::new(0);
+        let start = Instant::now();
+
+        let shared_profile = &*profile;
+        let worker_results = std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    std::thread::Builder::new()
+                        .stack_size(SOLVE_THREAD_STACK_SIZE)
+                        .spawn_scoped(scope, || {
+                            // The branches below are explored on this thread.
+                            let mut worker_profile = shared_profile.fork().with_parallelism(false);
+                            let mut worker_failed = failed.clone();
+                            let mut worker_tables = tables.clone();
+                            let mut results = Vec::new();
+                            loop {
+                                let i = next_goal.fetch_add(1, Ordering::SeqCst);
+                                let goal = match goals.get(i) {
+                                    Some(g) => g,
+                                    None => break,
+                                };
+                                let res = inner(
+                                    rules,
+                                    goal,
+                                    maxdepth,
+                                    level,
+                                    grounded,
+                                    store_full_tree,
+                                    &mut worker_profile,
+                                    index,
+                                    &mut worker_failed,
+                                    &mut worker_tables,
+                                );
+                                results.push((i, res));
+                            }
+                            (results, worker_profile)
+                        })
+                        .expect("failed to spawn solving thread")
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("solving thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut results = Vec::new();
+        for (worker_results, worker_profile) in worker_results {
+            profile.merge(worker_profile);
+            results.extend(worker_results);
+        }
+        profile.spent_on_subgoals(start.elapsed());
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, res)| res).collect()
+    }
+
+    /// Explores the goals of a frontier in the order of the strategy, passing each proof
+    /// found to `on_proof`, as a tree that only contains the path to it, until it returns
+    /// false. Returns the errors found.
+    ///
+    /// Depth-first explores the deepest goal first. Best-first explores the goals with
+    /// the lowest estimated build cost first. The cost of a goal is the number of
+    /// image-producing literals resolved to reach it plus the number left in it. Each of
+    /// those is resolved by exactly one builtin, so the estimate never exceeds the actual
+    /// cost, and the proofs are found from the cheapest.
+    #[allow(clippy::too_many_arguments)]
+    fn frontier_search(
+        rules: &[Clause<IRTerm>],
+        goal: &GoalWithHistory,
+        maxdepth: TreeLevel,
+        strategy: SearchStrategy,
+        grounded: &HashMap<Signature, Vec<bool>>,
+        profile: &mut SolveProfile,
+        index: &RuleIndex,
+        failed: &mut FailedSubgoals,
+        tables: &mut Tables,
+        on_proof: &mut dyn FnMut(Tree) -> bool,
+    ) -> HashSet<ResolutionError> {
+        /// A resolution step on the path from the root to a goal in the frontier.
+        struct Step {
+            parent: Option<Rc<Step>>,
+            goal: GoalWithHistory,
+            level: TreeLevel,
+            lid: LiteralGoalId,
+            rid: ClauseId,
+            mgu: Substitution,
+            renaming: Substitution,
+        }
+
+        struct Frontier {
+            goal: GoalWithHistory,
+            level: TreeLevel,
+            /// Image-producing literals resolved so far.
+            cost: usize,
+            path: Option<Rc<Step>>,
+        }
+
+        let remaining = |goal: &GoalWithHistory| match strategy {
+            SearchStrategy::DepthFirst => 0,
+            SearchStrategy::BestFirst => goal
+                .iter()
+                .filter(|l| is_image_producing(&l.literal))
+                .count(),
+        };
+
+        let mut errors = HashSet::new();
+        // Ordered by estimated cost, then the deepest goal first to find a proof quickly,
+        // then insertion order.
+        let mut queue = BinaryHeap::new();
+        let mut frontier = HashMap::new();
+        queue.push(Reverse((remaining(goal), Reverse(0), 0)));
+        frontier.insert(
+            0,
+            Frontier {
+                goal: goal.to_owned(),
+                level: 0,
+                cost: 0,
+                path: None,
+            },
+        );
+
+        let mut next_id = 1;
+        while let Some(Reverse((_, _, id))) = queue.pop() {
+            let Frontier {
+                goal: current,
+                level,
+                cost,
+                path,
+            } = frontier.remove(&id).unwrap();
+            profile.reach(&current, level);
+            if let Some(error) = profile.interruption(&current) {
+                errors.insert(error);
+                break;
+            }
+
+            if current.is_empty() {
+                let mut tree = Tree {
+                    goal: current,
+                    level,
+                    success_resolvents: HashMap::new(),
+                    fail_resolvents: HashMap::new(),
+                    error: None,
+                };
+                let mut step = path.as_deref();
+                while let Some(s) = step {
+                    let mut success_resolvents = HashMap::new();
+                    success_resolvents.insert(
+                        (s.lid, s.rid.clone()),
+                        (s.mgu.clone(), s.renaming.clone(), tree),
+                    );
+                    tree = Tree {
+                        goal: s.goal.clone(),
+                        level: s.level,
+                        success_resolvents,
+                        fail_resolvents: HashMap::new(),
+                        error: None,
+                    };
+                    step = s.parent.as_deref();
+                }
+                if !on_proof(tree) {
+                    break;
+                }
+                continue;
+            }
+
+            if level >= maxdepth {
+                errors.insert(ResolutionError::MaximumDepthExceeded(
+                    current.iter().map(|l| l.literal.clone()).collect(),
+                    maxdepth,
+                ));
+                continue;
+            }
+
+            let (lid, l) = match select(&current, grounded, profile.selection, index) {
+                Ok(selected) => selected,
+                Err(e) => {
+                    errors.insert(e);
+                    continue;
+                }
+            };
+
+            profile.enter(&l.literal);
+            let children = if l.literal.positive {
+                let (children, errs) = resolvents(
+                    lid, &l, &current, rules, maxdepth, level, grounded, profile, index, failed,
+                    tables,
+                );
+                errors.extend(errs);
+                children
+            } else {
+                let negated_goal = vec![Arc::new(LiteralWithHistory {
+                    literal: l.literal.negated(),
+                    introduction: l.introduction,
+                    origin: l.origin.clone(),
+                })];
+                let negated = inner(
+                    rules,
+                    &negated_goal,
+                    maxdepth,
+                    0,
+                    grounded,
+                    false,
+                    profile,
+                    index,
+                    failed,
+                    tables,
+                )
+                .tree;
+                let subtree_error = negated.contains_error_severity();
+                if negated.is_success() || subtree_error {
+                    if !subtree_error {
+                        errors.insert(ResolutionError::NegationProof(l.literal.clone()));
+                    }
+                    profile.record_resolution(false);
+                    Vec::new()
+                } else {
+                    let rid = ClauseId::NegationCheck(l.literal.negated());
+                    let mgu = Substitution::new();
+                    let resolvent = resolve(
+                        lid,
+                        rid.clone(),
+                        &current,
+                        &mgu,
+                        &Clause {
+                            head: l.literal.clone(),
+                            body: Vec::new(),
+                        },
+                        level + 1,
+                    );
+                    vec![(rid, mgu, Substitution::new(), resolvent)]
+                }
+            };
+
+            let cost = cost + usize::from(is_image_producing(&l.literal));
+            for (rid, mgu, renaming, resolvent) in children {
+                profile.record_resolution(true);
+                let step = Rc::new(Step {
+                    parent: path.clone(),
+                    goal: current.clone(),
+                    level,
+                    lid,
+                    rid,
+                    mgu,
+                    renaming,
+                });
+                let estimate = cost + remaining(&resolvent);
+                queue.push(Reverse((estimate, Reverse(level + 1), next_id)));
+                frontier.insert(
+                    next_id,
+                    Frontier {
+                        goal: resolvent,
+                        level: level + 1,
+                        cost,
+                        path: Some(step),
+                    },
+                );
+                next_id += 1;
+            }
+            profile.exit();
+        }
+        errors
+    }
+
+    profile.start_solve();
     let grounded_result = wellformed::check_grounded_variables(rules);
     let goal_with_history = goal
         .iter()
@@ -1219,22 +3421,96 @@ pub fn sld(
                 clause: ClauseId::Query,
                 body_index: id,
             };
-            LiteralWithHistory {
+            Arc::new(LiteralWithHistory {
                 literal: l.clone(),
                 introduction: 0,
                 origin,
-            }
+            })
         })
         .collect();
+    let root = |goal: GoalWithHistory| Tree {
+        goal,
+        level: 0,
+        success_resolvents: HashMap::new(),
+        fail_resolvents: HashMap::new(),
+        error: None,
+    };
     match grounded_result {
-        Ok(grounded) => inner(
-            rules,
-            &goal_with_history,
-            maxdepth,
-            0,
-            &grounded,
-            store_full_tree,
-        ),
+        Ok(grounded) => match (strategy, on_proof) {
+            (_, Some(on_proof)) => {
+                let errors = frontier_search(
+                    rules,
+                    &goal_with_history,
+                    maxdepth,
+                    strategy,
+                    &grounded,
+                    profile,
+                    &RuleIndex::with_model(rules, profile.bottom_up),
+                    &mut FailedSubgoals::default(),
+                    &mut Tables::new(rules, profile.tabling),
+                    on_proof,
+                );
+                SLDResult {
+                    tree: root(goal_with_history),
+                    errors,
+                }
+            }
+            (SearchStrategy::DepthFirst, None) => {
+                let index = RuleIndex::with_model(rules, profile.bottom_up);
+                // Known failures and complete tables do not depend on the depth, so they
+                // are kept between iterations.
+                let mut failed = FailedSubgoals::default();
+                let mut tables = Tables::new(rules, profile.tabling);
+                let mut depth = maxdepth.min(INITIAL_DEPTH);
+                loop {
+                    let res = inner(
+                        rules,
+                        &goal_with_history,
+                        depth,
+                        0,
+                        &grounded,
+                        store_full_tree,
+                        profile,
+                        &index,
+                        &mut failed,
+                        &mut tables,
+                    );
+                    let limited = res
+                        .errors
+                        .iter()
+                        .any(|e| matches!(e, ResolutionError::MaximumDepthExceeded(..)));
+                    if !limited
+                        || depth >= maxdepth
+                        || profile.interruption(&goal_with_history).is_some()
+                    {
+                        break res;
+                    }
+                    depth = (depth * 2).min(maxdepth);
+                }
+            }
+            (SearchStrategy::BestFirst, None) => {
+                let mut first = None;
+                let errors = frontier_search(
+                    rules,
+                    &goal_with_history,
+                    maxdepth,
+                    strategy,
+                    &grounded,
+                    profile,
+                    &RuleIndex::with_model(rules, profile.bottom_up),
+                    &mut FailedSubgoals::default(),
+                    &mut Tables::new(rules, profile.tabling),
+                    &mut |tree| {
+                        first = Some(tree);
+                        false
+                    },
+                );
+                SLDResult {
+                    tree: first.unwrap_or_else(|| root(goal_with_history)),
+                    errors,
+                }
+            }
+        },
         Err(e) => SLDResult {
             tree: Tree {
                 goal: goal_with_history,
@@ -1254,6 +3530,103 @@ pub fn sld(
     }
 }
 
+/// The proofs of a goal, found one at a time by a search running on another thread, see
+/// [`solve_iter`].
+///
+/// Dropping the iterator stops the search.
+pub struct SolutionIter {
+    proofs: Option<mpsc::Receiver<(Goal, Proof)>>,
+    cancellation: CancellationToken,
+    search: Option<std::thread::JoinHandle<(HashSet<ResolutionError>, SolveProfile)>>,
+}
+
+impl Iterator for SolutionIter {
+    type Item = (Goal, Proof);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.proofs.as_ref()?.recv().ok()
+    }
+}
+
+impl SolutionIter {
+    /// Stops the search, and returns the errors it found, along with its profile, which
+    /// can be merged into the profile given to [`solve_iter`].
+    pub fn finish(mut self) -> (HashSet<ResolutionError>, SolveProfile) {
+        let stopped = self.stop();
+        let (mut errors, profile) = self
+            .search
+            .take()
+            .expect("the search is only joined once")
+            .join()
+            .expect("solving thread panicked");
+        if stopped {
+            errors.remove(&ResolutionError::Cancelled);
+        }
+        (errors, profile)
+    }
+
+    /// Stops the search, returning whether it was still running.
+    fn stop(&mut self) -> bool {
+        let running = self.search.as_ref().is_some_and(|s| !s.is_finished());
+        self.cancellation.cancel();
+        // Unblocks the search if it is waiting for a proof to be taken.
+        self.proofs = None;
+        running
+    }
+}
+
+impl Drop for SolutionIter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Searches for the proofs of the goal on another thread, in the order of the strategy,
+/// and yields them one at a time, without building the SLD tree. Only the path to the
+/// goal being resolved is kept, so this scales to search spaces whose tree would not fit
+/// in memory, and the search can be stopped after the first solutions.
+///
+/// A proof is found at most one ahead of the one that is taken. The search uses the
+/// options of `profile`, but has its own cancellation token.
+pub fn solve_iter(
+    rules: &[Clause<IRTerm>],
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    strategy: SearchStrategy,
+    profile: &SolveProfile,
+) -> SolutionIter {
+    let rules = rules.to_vec();
+    let goal = goal.clone();
+    let cancellation = CancellationToken::new();
+    let mut profile = profile.fork().with_cancellation(cancellation.clone());
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let search = std::thread::Builder::new()
+        .stack_size(SOLVE_THREAD_STACK_SIZE)
+        .spawn(move || {
+            let res = solve(
+                &rules,
+                &goal,
+                maxdepth,
+                false,
+                strategy,
+                &mut profile,
+                Some(&mut |tree| {
+                    let proof = proofs_iter(&tree, &rules, &goal)
+                        .next()
+                        .expect("the tree is the path to a proof");
+                    sender.send(proof).is_ok()
+                }),
+            );
+            (res.errors, profile)
+        })
+        .expect("failed to spawn solving thread");
+    SolutionIter {
+        proofs: Some(receiver),
+        cancellation,
+        search: Some(search),
+    }
+}
+
 pub fn solutions(tree: &Tree) -> HashSet<Goal> {
     fn inner(tree: &Tree) -> Vec<Substitution> {
         if tree.goal.is_empty() {
@@ -1263,28 +3636,16 @@ pub fn solutions(tree: &Tree) -> HashSet<Goal> {
         tree.success_resolvents
             .iter()
             .map(|(_, (mgu, _, subtree))| (mgu, inner(subtree)))
-            .map(|(mgu, sub)| {
+            .flat_map(|(mgu, sub)| {
                 sub.iter()
                     .map(|s| compose_extend(mgu, s))
                     .collect::<Vec<Substitution<IRTerm>>>()
             })
-            .flatten()
             .collect()
     }
     inner(tree)
         .iter()
-        .map(|s| {
-            tree.goal
-                .iter()
-                .map(
-                    |LiteralWithHistory {
-                         literal,
-                         introduction: _,
-                         origin: _,
-                     }| literal.substitute(s),
-                )
-                .collect()
-        })
+        .map(|s| tree.goal.iter().map(|l| l.literal.substitute(s)).collect())
         .collect()
 }
 
@@ -1296,126 +3657,291 @@ struct PathNode {
     renaming: Substitution,
 }
 
-// sequence of nodes and global mgu
-type Path = (Vec<PathNode>, Substitution);
+/// Reconstructs the proof rooted at the given level of a success path, using the
+/// mgu composed along the whole path. The path starts at the level `base` of the tree.
+fn proof_for_level(
+    path: &[PathNode],
+    mgu: &Substitution,
+    rules: &[Clause],
+    base: TreeLevel,
+    level: TreeLevel,
+) -> Proof {
+    if let ClauseId::Tabled(t) = &path[level].applied {
+        return t
+            .proof
+            .instantiate(&compose_no_extend(&path[level].renaming, mgu));
+    }
 
-pub fn proofs(tree: &Tree, rules: &[Clause], goal: &Goal) -> HashMap<Goal, Proof> {
-    fn flatten_compose(
-        lid: &LiteralGoalId,
-        cid: &ClauseId,
-        mgu: &Substitution,
-        renaming: &Substitution,
-        tree: &Tree,
-    ) -> Vec<Path> {
-        if tree.goal.is_empty() {
-            return vec![(
-                vec![PathNode {
-                    resolvent: tree.goal.clone(),
-                    applied: cid.clone(),
-                    selected: *lid,
-                    renaming: renaming.clone(),
-                }],
-                mgu.clone(),
-            )];
+    let mut sublevels_map: HashMap<usize, TreeLevel> = HashMap::new();
+    for l in 0..path.len() {
+        if !path[l].resolvent.is_empty() {
+            let resolved_child = &path[l].resolvent[path[l + 1].selected];
+            if resolved_child.introduction == base + level {
+                sublevels_map.insert(resolved_child.origin.body_index, l + 1);
+            }
         }
-        tree.success_resolvents
+    }
+    let children_length = sublevels_map.len();
+    match path[level].applied {
+        ClauseId::Query => assert_eq!(children_length, path[0].resolvent.len()),
+        ClauseId::Rule(rid) => assert_eq!(children_length, rules[rid].body.len()),
+        ClauseId::Builtin(_) => assert_eq!(children_length, 0),
+        // There shouldn't be a subtree here since the tree is currently only stored
+        // if the negation check failed (i.e. we found a proof).
+        ClauseId::NegationCheck(_) => assert_eq!(children_length, 0),
+        ClauseId::Tabled(_) => unreachable!("the proof of the answer is used"),
+    };
+
+    let mut sublevels = Vec::<TreeLevel>::with_capacity(sublevels_map.len());
+    for k in sublevels_map.keys() {
+        assert!(*k < children_length);
+    }
+    for i in 0..children_length {
+        sublevels.push(*sublevels_map.get(&i).unwrap());
+    }
+    Proof {
+        clause: path[level].applied.clone(),
+        valuation: compose_no_extend(&path[level].renaming, mgu),
+        children: sublevels
             .iter()
-            .map(|((sub_lid, sub_cid), (sub_mgu, sub_renaming, sub_tree))| {
-                flatten_compose(sub_lid, sub_cid, sub_mgu, sub_renaming, sub_tree)
-                    .iter()
-                    .map(|(sub_path, sub_val)| {
-                        let mut nodes = vec![PathNode {
-                            resolvent: tree.goal.clone(),
-                            applied: cid.clone(),
-                            selected: *lid,
-                            renaming: renaming.clone(),
-                        }];
-                        let val = compose_extend(mgu, sub_val);
-                        nodes.extend(sub_path.clone());
-                        (nodes, val)
-                    })
-                    .collect::<Vec<Path>>()
-            })
-            .flatten()
-            .collect()
+            .map(|l| proof_for_level(path, mgu, rules, base, *l))
+            .collect(),
     }
-    // reconstruct proof for a given tree level
-    fn proof_for_level(
-        path: &[PathNode],
-        mgu: &Substitution,
-        rules: &[Clause],
-        level: TreeLevel,
-    ) -> Proof {
-        let mut sublevels_map: HashMap<usize, TreeLevel> = HashMap::new();
-        for l in 0..path.len() {
-            if !path[l].resolvent.is_empty() {
-                let resolved_child = path[l].resolvent[path[l + 1].selected].clone();
-                if resolved_child.introduction == level {
-                    sublevels_map.insert(resolved_child.origin.body_index, l + 1);
+}
+
+/// An edge of the tree still to be explored: the depth of the node it leads to,
+/// the selected literal, the applied clause, the mgu, the renaming and the subtree.
+/// Substitutions are persistent maps, so they are cheap to clone.
+type PendingEdge<'a> = (
+    usize,
+    LiteralGoalId,
+    &'a ClauseId,
+    Substitution,
+    Substitution,
+    &'a Tree,
+);
+
+/// Enumerates the proofs of a tree one success path at a time, together with the
+/// solution of the goal each proves.
+///
+/// Only the current path and the edges left to explore are kept in memory, so
+/// consumers can stop early or deduplicate proofs as they come.
+pub struct ProofIter<'a> {
+    rules: &'a [Clause],
+    goal: &'a Goal,
+    /// The level of the root of the tree.
+    base: TreeLevel,
+    /// Nodes of the current path, along with the mgu composed from the root.
+    path: Vec<(PathNode, Substitution)>,
+    pending: Vec<PendingEdge<'a>>,
+    /// Solutions already produced, if only the first proof of each is wanted.
+    seen: Option<HashSet<Goal>>,
+}
+
+impl<'a> ProofIter<'a> {
+    /// Skips proofs of solutions that were already produced, without reconstructing them.
+    pub fn first_per_solution(mut self) -> Self {
+        self.seen = Some(HashSet::new());
+        self
+    }
+}
+
+impl Iterator for ProofIter<'_> {
+    type Item = (Goal, Proof);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((depth, lid, cid, mgu, renaming, tree)) = self.pending.pop() {
+            self.path.truncate(depth);
+            let composed = match self.path.last() {
+                Some((_, parent_mgu)) => compose_extend(parent_mgu, &mgu),
+                None => mgu,
+            };
+            self.path.push((
+                PathNode {
+                    resolvent: tree.goal.clone(),
+                    applied: cid.clone(),
+                    selected: lid,
+                    renaming,
+                },
+                composed,
+            ));
+
+            if !tree.goal.is_empty() {
+                self.pending.extend(tree.success_resolvents.iter().map(
+                    |((sub_lid, sub_cid), (sub_mgu, sub_renaming, sub_tree))| {
+                        (
+                            depth + 1,
+                            *sub_lid,
+                            sub_cid,
+                            sub_mgu.clone(),
+                            sub_renaming.clone(),
+                            sub_tree,
+                        )
+                    },
+                ));
+                continue;
+            }
+
+            let mgu = &self.path[depth].1;
+            let valuation = compose_no_extend(&self.path[0].0.renaming, mgu);
+            let solution: Goal = self.goal.substitute(&valuation);
+            if let Some(seen) = &mut self.seen {
+                if !seen.insert(solution.clone()) {
+                    continue;
                 }
             }
+            let path = self.path.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+            return Some((
+                solution,
+                proof_for_level(&path, mgu, self.rules, self.base, 0),
+            ));
         }
-        let children_length = sublevels_map.len();
-        match path[level].applied {
-            ClauseId::Query => assert_eq!(children_length, path[0].resolvent.len()),
-            ClauseId::Rule(rid) => assert_eq!(children_length, rules[rid].body.len()),
-            ClauseId::Builtin(_) => assert_eq!(children_length, 0),
-            // There shouldn't be a subtree here since the tree is currently only stored
-            // if the negation check failed (i.e. we found a proof).
-            ClauseId::NegationCheck(_) => assert_eq!(children_length, 0),
-        };
+        None
+    }
+}
+
+/// Lazily enumerates all proofs in the tree. See [`ProofIter`].
+pub fn proofs_iter<'a>(tree: &'a Tree, rules: &'a [Clause], goal: &'a Goal) -> ProofIter<'a> {
+    let goal_id_renaming: Substitution = goal
+        .iter()
+        .flat_map(|l| l.variables(true))
+        .map(|v| (v.clone(), v))
+        .collect();
+    // assume lid of root is 0, as if it came from a clause "true :- goal" for query "true", but this is not used anyway
+    ProofIter {
+        rules,
+        goal,
+        base: tree.level,
+        path: Vec::new(),
+        pending: vec![(
+            0,
+            0,
+            &ClauseId::Query,
+            Substitution::new(),
+            goal_id_renaming,
+            tree,
+        )],
+        seen: None,
+    }
+}
+
+/// Estimates the cost of a proof, to choose the cheapest proof of each solution, see
+/// [`proofs_with_cost`].
+pub trait CostModel {
+    fn cost(&self, proof: &Proof) -> usize;
+}
+
+impl<F: Fn(&Proof) -> usize> CostModel for F {
+    fn cost(&self, proof: &Proof) -> usize {
+        self(proof)
+    }
+}
+
+/// The cost models of proofs that Modus provides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ProofCost {
+    /// The height of the proof tree.
+    Height,
+    /// The number of image-producing literals, e.g. `from`, `run` or `copy`, each of
+    /// which is a step of the build.
+    #[default]
+    BuildSteps,
+    /// An estimate of the number of layers of the image: each `run` or `copy` adds a
+    /// layer, except within a `merge`, which adds a single layer.
+    Layers,
+}
 
-        let mut sublevels = Vec::<TreeLevel>::with_capacity(sublevels_map.len());
-        for k in sublevels_map.keys() {
-            assert!(*k < children_length);
+impl CostModel for ProofCost {
+    fn cost(&self, proof: &Proof) -> usize {
+        fn builtins<'a>(p: &'a Proof, out: &mut Vec<&'a Literal>) {
+            if let ClauseId::Builtin(b) = &p.clause {
+                out.push(b);
+            }
+            for child in &p.children {
+                builtins(child, out);
+            }
         }
-        for i in 0..children_length {
-            sublevels.push(*sublevels_map.get(&i).unwrap());
+
+        let mut steps = Vec::new();
+        builtins(proof, &mut steps);
+        match self {
+            ProofCost::Height => proof.height(),
+            ProofCost::BuildSteps => steps.iter().filter(|b| is_image_producing(b)).count(),
+            ProofCost::Layers => {
+                let mut layers = 0;
+                let mut merges = 0;
+                for b in steps {
+                    match b.predicate.0.as_str() {
+                        "_operator_merge_begin" => {
+                            layers += usize::from(merges == 0);
+                            merges += 1;
+                        }
+                        "_operator_merge_end" => merges -= 1,
+                        _ => {
+                            layers += usize::from(
+                                merges == 0
+                                    && b.predicate.naive_predicate_kind() == analysis::Kind::Layer,
+                            )
+                        }
+                    }
+                }
+                layers
+            }
         }
-        Proof {
-            clause: path[level].applied.clone(),
-            valuation: compose_no_extend(&path[level].renaming, mgu),
-            children: sublevels
-                .iter()
-                .map(|l| proof_for_level(path, mgu, rules, *l))
-                .collect(),
+    }
+}
+
+impl std::str::FromStr for ProofCost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "height" => Ok(ProofCost::Height),
+            "build-steps" => Ok(ProofCost::BuildSteps),
+            "layers" => Ok(ProofCost::Layers),
+            _ => Err(format!("unknown proof cost: {}", s)),
         }
     }
-    // assume lid of root is 0, as if it came from a clause "true :- goal" for query "true", but this is not used anyway
-    let goal_vars = goal
-        .iter()
-        .map(|l| l.variables(true))
-        .reduce(|mut l, r| {
-            l.extend(r);
-            l
+}
+
+impl fmt::Display for ProofCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProofCost::Height => "height",
+            ProofCost::BuildSteps => "build-steps",
+            ProofCost::Layers => "layers",
         })
-        .unwrap_or_default();
-    let goal_id_renaming: Substitution<IRTerm> =
-        goal_vars.iter().map(|v| (v.clone(), v.clone())).collect();
-    let paths = flatten_compose(
-        &0,
-        &ClauseId::Query,
-        &Substitution::new(),
-        &goal_id_renaming,
-        tree,
-    );
-    let all_proofs: Vec<Proof> = paths
-        .iter()
-        .map(|(path, mgu)| proof_for_level(path, mgu, rules, 0))
-        .collect();
+    }
+}
+
+/// The cheapest proof of each solution of the tree, by the default [`ProofCost`], so
+/// that the build of each solution has as few steps as possible.
+pub fn proofs(tree: &Tree, rules: &[Clause], goal: &Goal) -> HashMap<Goal, Proof> {
+    proofs_with_cost(tree, rules, goal, &ProofCost::default())
+}
 
-    let mut solution_to_proof_tree: HashMap<Goal, Proof> = HashMap::new();
-    for p in all_proofs {
-        let solution: Goal = goal.substitute(&p.valuation);
-        // keeps the minimal proof tree
-        if let Some(existing_proof) = solution_to_proof_tree.get(&solution) {
-            if existing_proof <= &p {
+/// The cheapest proof of each solution of the tree by the cost model. Of proofs with the
+/// same cost, the one with the smallest height is kept, and then the first one found.
+pub fn proofs_with_cost(
+    tree: &Tree,
+    rules: &[Clause],
+    goal: &Goal,
+    model: &dyn CostModel,
+) -> HashMap<Goal, Proof> {
+    let mut solution_to_proof_tree: HashMap<Goal, ((usize, usize), Proof)> = HashMap::new();
+    for (solution, p) in proofs_iter(tree, rules, goal) {
+        let cost = (model.cost(&p), p.height());
+        if let Some((existing_cost, _)) = solution_to_proof_tree.get(&solution) {
+            if *existing_cost <= cost {
                 continue;
             }
         }
-        solution_to_proof_tree.insert(solution, p);
+        solution_to_proof_tree.insert(solution, (cost, p));
     }
     solution_to_proof_tree
+        .into_iter()
+        .map(|(solution, (_, p))| (solution, p))
+        .collect()
 }
 
 pub fn tree_from_modusfile(
@@ -1423,12 +3949,13 @@ pub fn tree_from_modusfile(
     query: modusfile::Expression,
     max_depth: usize,
     full_tree: bool,
+    profile: &mut SolveProfile,
 ) -> (Goal, Vec<Clause>, SLDResult) {
     // 1. Create a new clause with a nullary goal '_query', with a body of the user's query.
     // 2. Translate this and other clauses.
     // 3. Use the body of the IR clause with the '_query' head predicate as the goal.
 
-    let goal_pred = Predicate("_query".to_owned());
+    let goal_pred = Predicate("_query".into());
     let mut mf = mf.clone();
     mf.add_goal(query);
     let clauses: Vec<Clause> = translate_modusfile(&mf);
@@ -1442,16 +3969,51 @@ pub fn tree_from_modusfile(
     (
         goal.clone(),
         clauses.clone(),
-        sld(&clauses, &goal, max_depth, full_tree),
+        sld_profiled(&clauses, goal, max_depth, full_tree, profile),
     )
 }
 
+/// Solves each query directive, `?- <goal>.`, of the Modusfile, and returns an error for
+/// each one without a proof, followed by the errors explaining why. Query directives of
+/// included Modusfiles, which have no position, are not solved.
+pub fn check_assertions(
+    mf: &Modusfile,
+    max_depth: usize,
+    profile: &mut SolveProfile,
+) -> Vec<Diagnostic<()>> {
+    let mut diags = Vec::new();
+    for clause in mf.0.iter() {
+        let (Some(position), Some(goal)) = (&clause.position, &clause.body) else {
+            continue;
+        };
+        if !clause.head.predicate.is_assertion() {
+            continue;
+        }
+        let query = modusfile::Expression::Literal(Literal {
+            position: None,
+            ..clause.head.clone()
+        });
+        let (_, _, sld_result) = tree_from_modusfile(mf.clone(), query, max_depth, false, profile);
+        if let Err(errors) = Result::from(sld_result) {
+            diags.push(
+                Diagnostic::error()
+                    .with_code("assertion-failed")
+                    .with_message(format!("query directive has no proof: {}", goal))
+                    .with_labels(vec![Label::primary(
+                        (),
+                        position.offset..position.offset + position.length,
+                    )
+                    .with_message("this query has no proof")]),
+            );
+            diags.extend(errors);
+        }
+    }
+    diags
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{
-        logic::SpannedPosition,
-        modusfile::{Expression, FormatStringFragment, ModusTerm},
-    };
+    use crate::modusfile::Expression;
 
     use super::*;
     use serial_test::serial;
@@ -1467,7 +4029,7 @@ mod tests {
                 && curr_lits
                     .iter()
                     .zip(lits)
-                    .all(|(l1, l2)| l1.eq_ignoring_position(&l2))
+                    .all(|(l1, l2)| l1.eq_ignoring_position(l2))
         })
     }
 
@@ -1540,6 +4102,235 @@ mod tests {
         ));
     }
 
+    #[test]
+    #[serial]
+    fn trace_filtered_by_predicate() {
+        let goal: Goal<logic::IRTerm> = vec!["a(\"c\")".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X), c(X).".parse().unwrap(),
+            "b(\"c\").".parse().unwrap(),
+            "c(\"d\").".parse().unwrap(),
+        ];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+        let trace = tree.trace();
+        assert_eq!(trace.len(), 3);
+        assert!(matches!(trace.last(), Some(TraceEvent::Fail { .. })));
+
+        let filter: TraceFilter = "c/1".parse().unwrap();
+        let filtered = trace
+            .iter()
+            .filter(|e| filter.matches(e))
+            .collect::<Vec<_>>();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(
+            filtered[0].describe(&clauses),
+            "failed: could not find a rule to resolve with literal c(\"c\")"
+        );
+
+        assert!("a/1,b".parse::<TraceFilter>().is_ok());
+        assert!("a/x".parse::<TraceFilter>().is_err());
+        assert!("/1".parse::<TraceFilter>().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn profile_counts_per_predicate() {
+        let goal: Goal<logic::IRTerm> = vec!["a(\"c\")".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X), !c(X).".parse().unwrap(),
+            "b(\"d\").".parse().unwrap(),
+            "b(X) :- string_eq(X, \"c\").".parse().unwrap(),
+            "c(\"d\").".parse().unwrap(),
+        ];
+        let mut profile = SolveProfile::new();
+        let tree = sld_profiled(&clauses, &goal, 10, true, &mut profile).tree;
+        assert!(tree.is_success());
+
+        let b = &profile.predicates[&Signature(Predicate("b".into()), 1)];
+        assert_eq!(b.selections, 1);
+        assert_eq!(b.resolutions, 1);
+        assert_eq!(b.backtracks, 0);
+        let c = &profile.predicates[&Signature(Predicate("c".into()), 1)];
+        // once negated, and once in the proof attempt of the negation
+        assert_eq!(c.selections, 2);
+        assert_eq!(c.resolutions, 1);
+        assert_eq!(c.backtracks, 0);
+        assert_eq!(profile.sorted().len(), profile.predicates.len());
+
+        let mut disabled = SolveProfile::default();
+        sld_profiled(&clauses, &goal, 10, true, &mut disabled);
+        assert!(disabled.predicates.is_empty());
+        assert_eq!(disabled.stats, profile.stats);
+    }
+
+    #[test]
+    #[serial]
+    fn solver_stats() {
+        let goal: Goal<logic::IRTerm> = vec!["a(\"c\")".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X).".parse().unwrap(),
+            "b(\"d\").".parse().unwrap(),
+            "b(X) :- string_eq(X, \"c\").".parse().unwrap(),
+        ];
+        let mut profile = SolveProfile::default();
+        sld_profiled(&clauses, &goal, 10, true, &mut profile);
+        assert_eq!(
+            profile.stats,
+            SolverStats {
+                // a(c), b(c), string_eq(c, c) and the empty goal
+                nodes_explored: 4,
+                max_depth: 3,
+                // a, the b rule (b("d") is not tried thanks to indexing), and string_eq
+                unifications: 3,
+                builtin_invocations: 1,
+                known_failures: 0,
+            }
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn known_failures_are_not_searched_again() {
+        let goal: Goal<logic::IRTerm> = vec!["a".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "a :- path(X), finish(X).".parse().unwrap(),
+            "path(\"1\").".parse().unwrap(),
+            "path(\"2\").".parse().unwrap(),
+            "path(\"3\").".parse().unwrap(),
+            "path(\"4\").".parse().unwrap(),
+            "path(\"5\").".parse().unwrap(),
+            "finish(X) :- string_eq(X, X), dead_end(\"z\")."
+                .parse()
+                .unwrap(),
+            "dead_end(X) :- step(X, \"1\"), step(X, \"2\"), string_eq(X, \"y\")."
+                .parse()
+                .unwrap(),
+            "step(X, Y) :- string_eq(X, X), string_eq(Y, Y)."
+                .parse()
+                .unwrap(),
+        ];
+        // Selecting leftmost, so that dead_end("z") is selected after the string_eq.
+        let mut profile = SolveProfile::default().with_selection(SelectionRule::Leftmost);
+        let res = sld_profiled(&clauses, &goal, 20, true, &mut profile);
+        assert!(!res.tree.is_success());
+        // dead_end("z") is searched on the first path, and on its own on the second one.
+        // It fails at once on the second path and on the three others.
+        assert_eq!(profile.stats.known_failures, 4);
+        assert!(res.errors.iter().any(
+            |e| matches!(e, ResolutionError::BuiltinFailure(l, _) if l.predicate.0 == "string_eq")
+        ));
+
+        // ok("x") is on failed branches, but only because of the rest of their goal.
+        let clauses: Vec<logic::Clause> = vec![
+            "a :- path(X), finish(X).".parse().unwrap(),
+            "path(\"1\").".parse().unwrap(),
+            "path(\"2\").".parse().unwrap(),
+            "path(\"3\").".parse().unwrap(),
+            "finish(X) :- ok(\"x\"), string_eq(X, \"3\")."
+                .parse()
+                .unwrap(),
+            "ok(\"x\").".parse().unwrap(),
+        ];
+        let mut profile = SolveProfile::default();
+        let res = sld_profiled(&clauses, &goal, 20, true, &mut profile);
+        assert!(res.tree.is_success());
+        assert_eq!(profile.stats.known_failures, 0);
+    }
+
+    #[test]
+    #[serial]
+    fn deepest_goal_and_cancellation() {
+        let goal: Goal<logic::IRTerm> = vec!["a(\"c\")".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X).".parse().unwrap(),
+            "b(X) :- c(X), d(X).".parse().unwrap(),
+            "c(\"c\").".parse().unwrap(),
+        ];
+        let mut profile = SolveProfile::default();
+        sld_profiled(&clauses, &goal, 10, true, &mut profile);
+        assert_eq!(
+            profile
+                .deepest_goal
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["d(\"c\")"]
+        );
+
+        let cancellation = CancellationToken::new();
+        let mut profile = SolveProfile::default().with_cancellation(cancellation.clone());
+        cancellation.cancel();
+        let result = sld_profiled(&clauses, &goal, 10, true, &mut profile);
+        assert_eq!(profile.stats.nodes_explored, 1);
+        assert_eq!(
+            result.errors,
+            vec![ResolutionError::Cancelled].into_iter().collect()
+        );
+        for strategy in [SearchStrategy::DepthFirst, SearchStrategy::BestFirst] {
+            let result =
+                sld_with_strategy(&clauses, &goal, 10, false, strategy, &mut profile.fork());
+            assert!(!result.tree.is_success());
+            assert!(result.errors.contains(&ResolutionError::Cancelled));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn ground_checks_selected_first() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- big(Y), check(X).".parse().unwrap(),
+            "big(\"1\").".parse().unwrap(),
+            "big(\"2\").".parse().unwrap(),
+            "big(\"3\").".parse().unwrap(),
+            "check(\"yes\").".parse().unwrap(),
+        ];
+
+        let goal: Goal<logic::IRTerm> = vec!["a(\"no\")".parse().unwrap()];
+        let mut profile = SolveProfile::default();
+        let tree = sld_profiled(&clauses, &goal, 10, true, &mut profile).tree;
+        assert!(!tree.is_success());
+        // check("no") fails before any of the big(Y) branches are explored
+        assert_eq!(profile.stats.nodes_explored, 2);
+
+        // proofs still follow the order of the rule body
+        let goal: Goal<logic::IRTerm> = vec!["a(\"yes\")".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+        let proofs = proofs(&tree, &clauses, &goal);
+        assert_eq!(proofs.len(), 1);
+        let proof = proofs.values().next().unwrap();
+        assert!(matches!(
+            proof.children[0].children[0].clause,
+            ClauseId::Rule(1..=3)
+        ));
+        assert_eq!(proof.children[0].children[1].clause, ClauseId::Rule(4));
+    }
+
+    #[test]
+    #[serial]
+    fn first_argument_indexing() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a(\"x\", \"1\").".parse().unwrap(),
+            "a(X, \"2\") :- string_eq(X, \"y\").".parse().unwrap(),
+            "a(\"y\", \"3\").".parse().unwrap(),
+            "a(\"x\", \"4\").".parse().unwrap(),
+        ];
+        let index = RuleIndex::new(&clauses);
+        assert_eq!(
+            index.candidates(&"a(\"x\", Y)".parse().unwrap()),
+            vec![0, 1, 3]
+        );
+        assert_eq!(index.candidates(&"a(\"z\", Y)".parse().unwrap()), vec![1]);
+        assert_eq!(
+            index.candidates(&"a(X, Y)".parse().unwrap()),
+            vec![0, 1, 2, 3]
+        );
+        assert!(index.candidates(&"a(X)".parse().unwrap()).is_empty());
+
+        let goal: Goal<logic::IRTerm> = vec!["a(\"y\", Y)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+        assert_eq!(solutions(&tree).len(), 2);
+    }
+
     #[test]
     #[serial]
     fn simple_nongrounded() {
@@ -1753,12 +4544,42 @@ mod tests {
     fn leaf_height_is_zero() {
         let proof = Proof {
             clause: ClauseId::Rule(0),
-            valuation: HashMap::default(),
+            valuation: Substitution::default(),
             children: Vec::new(),
         };
         assert_eq!(proof.height(), 0);
     }
 
+    #[test]
+    fn goal_substitution_shares_unchanged_literals() {
+        let goal: GoalWithHistory = ["a(X)", "b(\"c\")"]
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                Arc::new(LiteralWithHistory {
+                    literal: l.parse().unwrap(),
+                    introduction: 0,
+                    origin: LiteralOrigin {
+                        clause: ClauseId::Query,
+                        body_index: i,
+                    },
+                })
+            })
+            .collect();
+        let mut s = Substitution::new();
+        s.insert(
+            IRTerm::UserVariable("X".into()),
+            IRTerm::Constant("c".into()),
+        );
+        let substituted = goal.substitute(&s);
+        assert_eq!(
+            substituted[0].literal.args,
+            vec![IRTerm::Constant("c".into())]
+        );
+        assert!(!Arc::ptr_eq(&goal[0], &substituted[0]));
+        assert!(Arc::ptr_eq(&goal[1], &substituted[1]));
+    }
+
     #[test]
     #[serial]
     fn proof_minimality() {
@@ -1768,15 +4589,82 @@ mod tests {
             "bar(\"test\").".parse().unwrap(),
             "foo(\"test\").".parse().unwrap(),
         ];
-        let tree = sld(&clauses, &goal, 15, true).tree;
-        let sld_proofs = proofs(&tree, &clauses, &goal);
-        assert_eq!(sld_proofs.len(), 1);
-        assert_eq!(
-            sld_proofs.values().next().unwrap().height(),
-            1,
-            "{:?}",
-            sld_proofs[&goal]
+        let tree = sld(&clauses, &goal, 15, true).tree;
+        let sld_proofs = proofs(&tree, &clauses, &goal);
+        assert_eq!(sld_proofs.len(), 1);
+        assert_eq!(
+            sld_proofs.values().next().unwrap().height(),
+            1,
+            "{:?}",
+            sld_proofs[&goal]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn proofs_are_enumerated_lazily() {
+        let goal: Goal<logic::IRTerm> = vec!["foo(X)".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "foo(X) :- bar(X).".parse().unwrap(),
+            "foo(X) :- baz(X).".parse().unwrap(),
+            "bar(\"a\").".parse().unwrap(),
+            "baz(\"a\").".parse().unwrap(),
+            "baz(\"b\").".parse().unwrap(),
+        ];
+        let tree = sld(&clauses, &goal, 15, true).tree;
+
+        assert_eq!(proofs_iter(&tree, &clauses, &goal).count(), 3);
+        let first = proofs_iter(&tree, &clauses, &goal)
+            .first_per_solution()
+            .map(|(solution, _)| solution)
+            .collect::<Vec<_>>();
+        assert_eq!(first.len(), 2);
+        assert_eq!(
+            first.into_iter().collect::<HashSet<_>>(),
+            proofs(&tree, &clauses, &goal).into_keys().collect()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn best_first_finds_cheapest_proof() {
+        let goal: Goal<logic::IRTerm> = vec!["img(X)".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "img(\"heavy\") :- from(\"alpine\"), run(\"a\"), run(\"b\")."
+                .parse()
+                .unwrap(),
+            "img(\"light\") :- from(\"alpine\"), run(\"a\")."
+                .parse()
+                .unwrap(),
+        ];
+        let depth_first = sld(&clauses, &goal, 15, false).tree;
+        assert_eq!(proofs(&depth_first, &clauses, &goal).len(), 2);
+
+        let res = sld_with_strategy(
+            &clauses,
+            &goal,
+            15,
+            false,
+            SearchStrategy::BestFirst,
+            &mut SolveProfile::default(),
+        );
+        assert!(res.tree.is_success());
+        let best = proofs(&res.tree, &clauses, &goal);
+        assert_eq!(best.len(), 1);
+        let solution = best.keys().next().unwrap();
+        assert_eq!(solution[0].args, vec![IRTerm::Constant("light".into())]);
+
+        let unsatisfiable: Goal<logic::IRTerm> = vec!["img(\"none\")".parse().unwrap()];
+        let res = sld_with_strategy(
+            &clauses,
+            &unsatisfiable,
+            15,
+            false,
+            SearchStrategy::BestFirst,
+            &mut SolveProfile::default(),
         );
+        assert!(!res.tree.is_success());
+        assert!(!res.errors.is_empty());
     }
 
     #[test]
@@ -1792,7 +4680,8 @@ mod tests {
             args: vec!["f\"alpine${X}\"".parse().unwrap()],
         });
 
-        let (_, _, sld_res) = tree_from_modusfile(mf, query, 20, true);
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf, query, 20, true, &mut SolveProfile::default());
         assert!(sld_res.tree.is_success());
     }
 
@@ -1872,6 +4761,747 @@ mod tests {
         ));
     }
 
+    #[test]
+    #[serial]
+    fn format_strings_and_lists_in_heads() {
+        let mf: Modusfile = "v(\"3\"). v(\"4\").\n\
+                             app(f\"python:${V}\") :- v(V).\n\
+                             cmd([\"sh\", \"-c\"])."
+            .parse()
+            .unwrap();
+        let query: Expression = "app(\"python:4\"), cmd([\"sh\", X])".parse().unwrap();
+
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf, query, 20, true, &mut SolveProfile::default());
+        assert!(sld_res.tree.is_success());
+        assert_eq!(solutions(&sld_res.tree).len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn if_then_else() {
+        let mf: Modusfile = "os(\"debian\"). os(\"alpine\").\n\
+                             debian_like(\"debian\").\n\
+                             pm(OS, P) :- os(OS), (debian_like(OS) -> P = \"apt\" ; P = \"apk\")."
+            .parse()
+            .unwrap();
+        let query: Expression = "pm(OS, P)".parse().unwrap();
+
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf, query, 20, true, &mut SolveProfile::default());
+        let pms = solutions(&sld_res.tree)
+            .into_iter()
+            .map(|g| g[0].args.clone())
+            .collect::<HashSet<_>>();
+        let expected = [("debian", "apt"), ("alpine", "apk")]
+            .iter()
+            .map(|(os, p)| {
+                vec![
+                    IRTerm::Constant((*os).into()),
+                    IRTerm::Constant((*p).into()),
+                ]
+            })
+            .collect();
+        assert_eq!(pms, expected);
+
+        // The else branch binds the variable of the condition before it is checked.
+        let mf: Modusfile = "p(\"x\"). a. b.\n\
+                             later(X) :- (p(X) -> a ; X = \"z\").\n\
+                             local :- (p(X) -> a ; b).\n\
+                             anonymous :- (p(_) -> a ; b)."
+            .parse()
+            .unwrap();
+        let solve = |query: &str| {
+            tree_from_modusfile(
+                mf.clone(),
+                query.parse().unwrap(),
+                20,
+                true,
+                &mut SolveProfile::default(),
+            )
+            .2
+        };
+        let later = solutions(&solve("later(X)").tree)
+            .into_iter()
+            .map(|g| g[0].args[0].clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            later,
+            HashSet::from([IRTerm::Constant("x".into()), IRTerm::Constant("z".into())])
+        );
+
+        // A variable only in the condition is never ground in the else branch.
+        let local = solve("local");
+        assert!(local
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::InsufficientGroundness(_))));
+        let anonymous = solve("anonymous");
+        assert!(anonymous.tree.is_success());
+        assert!(!anonymous
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::InsufficientGroundness(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn check_assertions() {
+        let mf: Modusfile = "a(\"x\").\n\
+                             ?- a(\"x\").\n\
+                             ?- a(\"y\")."
+            .parse()
+            .unwrap();
+        let diags = super::check_assertions(&mf, 20, &mut SolveProfile::default());
+        assert_eq!(diags[0].code.as_deref(), Some("assertion-failed"));
+        assert_eq!(diags[0].labels[0].range, 19..29);
+        assert!(diags[1..]
+            .iter()
+            .all(|d| d.code.as_deref() != Some("assertion-failed")));
+    }
+
+    #[test]
+    #[serial]
+    fn findall() {
+        let mf: Modusfile = "needs_pkg(\"git\"). needs_pkg(\"curl\"). needs_pkg(\"git\").\n\
+                             pkgs(Ps) :- findall(P, needs_pkg(P), Ps).\n\
+                             open(Ps) :- findall(P, needs_pkg(Q), Ps)."
+            .parse()
+            .unwrap();
+
+        let query: Expression = "pkgs(Ps)".parse().unwrap();
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf.clone(), query, 20, true, &mut SolveProfile::default());
+        let pkgs = solutions(&sld_res.tree)
+            .into_iter()
+            .map(|g| g[0].args.clone())
+            .collect::<Vec<_>>();
+        let expected = IRTerm::List(vec![
+            IRTerm::Constant("curl".into()),
+            IRTerm::Constant("git".into()),
+        ]);
+        assert_eq!(pkgs, vec![vec![expected]]);
+
+        let query: Expression = "pkgs([\"git\"])".parse().unwrap();
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf.clone(), query, 20, true, &mut SolveProfile::default());
+        assert!(solutions(&sld_res.tree).is_empty());
+
+        // The variables of the goal must be ground or collected.
+        let query: Expression = "open(Ps)".parse().unwrap();
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf, query, 20, true, &mut SolveProfile::default());
+        assert!(sld_res
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::InsufficientGroundness(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn occurs_check() {
+        let clauses: Vec<logic::Clause> = vec![
+            "item(\"a\").".parse().unwrap(),
+            "wrap(X, [X]) :- item(X).".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["wrap(Y, Y)".parse().unwrap()];
+
+        let result = sld(&clauses, &goal, 10, true);
+        assert!(!result
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::OccursCheck(..))));
+
+        let mut profile = SolveProfile::default().with_occurs_check(true);
+        let result = sld_profiled(&clauses, &goal, 10, true, &mut profile);
+        assert!(solutions(&result.tree).is_empty());
+        assert!(result
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::OccursCheck(l, _) if l.predicate.0 == "wrap")));
+    }
+
+    #[test]
+    #[serial]
+    fn resource_limits() {
+        let clauses: Vec<logic::Clause> = vec![
+            "loop(X) :- loop(X).".parse().unwrap(),
+            "wrap(X, Y) :- string_eq(Y, Y), wrap([X, X], Y)."
+                .parse()
+                .unwrap(),
+        ];
+        let exceeded = |goal: &str, limits: ResourceLimits| {
+            let goal: Goal = vec![goal.parse().unwrap()];
+            let mut profile = SolveProfile::default().with_limits(limits);
+            sld_profiled(&clauses, &goal, 1000, false, &mut profile)
+                .errors
+                .into_iter()
+                .filter_map(|e| match e {
+                    ResolutionError::ResourceLimitExceeded(limit, _) => Some(limit),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let limits = ResourceLimits {
+            max_nodes: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            exceeded("loop(\"a\")", limits),
+            vec![ResourceLimit::Nodes(50)]
+        );
+
+        let limits = ResourceLimits {
+            timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(
+            exceeded("loop(\"a\")", limits),
+            vec![ResourceLimit::Timeout(Duration::ZERO)]
+        );
+
+        let limits = ResourceLimits {
+            max_substitution_size: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            exceeded("wrap(\"a\", \"b\")", limits),
+            vec![ResourceLimit::SubstitutionSize(100)]
+        );
+    }
+
+    #[test]
+    fn deepest_failures() {
+        let clauses: Vec<logic::Clause> = vec![
+            "dep(X) :- candidate(X), supported(X, \"amd64\")."
+                .parse()
+                .unwrap(),
+            "dep(X) :- string_eq(X, \"z\").".parse().unwrap(),
+            "candidate(\"a\").".parse().unwrap(),
+            "candidate(\"b\").".parse().unwrap(),
+            "supported(\"c\", \"amd64\").".parse().unwrap(),
+            "supported(X, \"arm\") :- candidate(X).".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["dep(\"a\")".parse().unwrap()];
+        let mut profile = SolveProfile::default();
+        let result = sld_profiled(&clauses, &goal, 20, false, &mut profile);
+        assert!(!result.tree.is_success());
+
+        let failures = &profile.deepest_failures;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].level, 2);
+        assert_eq!(
+            failures[0].literal.to_string(),
+            "supported(\"a\", \"amd64\")"
+        );
+        assert_eq!(failures[0].builtin, None);
+        assert_eq!(failures[0].candidates, clauses[4..]);
+        assert!(profile.deepest_failures[0]
+            .get_diagnostic()
+            .notes
+            .iter()
+            .any(|n| n.contains("supported(X, \"arm\") :- candidate(X).")));
+    }
+
+    #[test]
+    #[serial]
+    fn proof_tree() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X), string_eq(X, \"x\").".parse().unwrap(),
+            "b(\"x\").".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["a(X)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+        let proofs = proofs(&tree, &clauses, &goal);
+        let proof = proofs.values().next().unwrap();
+
+        colored::control::set_override(false);
+        let mut out = Vec::new();
+        ptree::write_tree(&proof.tree(&goal, &clauses), &mut out).unwrap();
+        colored::control::unset_override();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a(\"x\")\n\
+             └─ a(\"x\")\n   \
+                ├─ b(\"x\")\n   \
+                └─ string_eq(\"x\", \"x\")\n"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn dot_and_json_export() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- b(X), string_eq(X, \"x\").".parse().unwrap(),
+            "b(\"x\").".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["a(X)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+
+        let proofs = proofs(&tree, &clauses, &goal);
+        let dot = proofs.values().next().unwrap().to_dot(&goal, &clauses);
+        assert!(dot.starts_with("digraph Proof {"));
+        assert!(dot.contains(r#"[label="a(X) :- b(X), string_eq(X, \"x\").\nX = \"x\""]"#));
+        assert!(dot.contains(r#"[label="b(\"x\")."]"#));
+
+        let json = tree.to_json(&clauses);
+        assert_eq!(json["goal"], serde_json::json!(["a(X)"]));
+        assert_eq!(json["success"], true);
+        let resolvent = &json["resolvents"][0];
+        assert_eq!(resolvent["literal"], 0);
+        assert_eq!(resolvent["clause"], "a(X) :- b(X), string_eq(X, \"x\").");
+        assert_eq!(
+            resolvent["tree"]["resolvents"][0]["substitution"]["X"],
+            "\"x\""
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn proof_cost_models() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a :- from(\"x\"), run(\"1\"), run(\"2\").".parse().unwrap(),
+            "a :- b.".parse().unwrap(),
+            "b :- c.".parse().unwrap(),
+            "c :- from(\"x\").".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["a".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, true).tree;
+        let cheapest = |model: &dyn CostModel| {
+            let proofs = proofs_with_cost(&tree, &clauses, &goal, model);
+            assert_eq!(proofs.len(), 1);
+            proofs.into_values().next().unwrap().children[0]
+                .clause
+                .clone()
+        };
+        assert_eq!(cheapest(&ProofCost::Height), ClauseId::Rule(0));
+        assert_eq!(cheapest(&ProofCost::BuildSteps), ClauseId::Rule(1));
+        assert_eq!(cheapest(&ProofCost::Layers), ClauseId::Rule(1));
+        assert_eq!(cheapest(&|p: &Proof| p.children.len()), ClauseId::Rule(0));
+
+        let step = |lit: &str| Proof {
+            clause: ClauseId::Builtin(lit.parse().unwrap()),
+            valuation: Substitution::new(),
+            children: Vec::new(),
+        };
+        let merged = Proof {
+            clause: ClauseId::Query,
+            valuation: Substitution::new(),
+            children: vec![
+                step("from(\"x\")"),
+                step("run(\"1\")"),
+                step("_operator_merge_begin(\"1\")"),
+                step("run(\"2\")"),
+                step("copy(\"a\", \"b\")"),
+                step("_operator_merge_end(\"1\")"),
+            ],
+        };
+        assert_eq!(ProofCost::Layers.cost(&merged), 2);
+        assert_eq!(ProofCost::BuildSteps.cost(&merged), 4);
+    }
+
+    #[test]
+    #[serial]
+    fn streamed_solutions() {
+        let clauses: Vec<logic::Clause> = (0..10)
+            .map(|i| format!("v(\"{}\").", i).parse().unwrap())
+            .chain(std::iter::once(
+                "pair(X, Y) :- v(X), v(Y).".parse().unwrap(),
+            ))
+            .collect();
+        let goal: Goal = vec!["pair(X, Y)".parse().unwrap()];
+
+        let all = solve_iter(
+            &clauses,
+            &goal,
+            20,
+            SearchStrategy::DepthFirst,
+            &SolveProfile::default(),
+        )
+        .map(|(solution, _)| solution)
+        .collect::<HashSet<_>>();
+        assert_eq!(all, solutions(&sld(&clauses, &goal, 20, false).tree));
+
+        let mut first = solve_iter(
+            &clauses,
+            &goal,
+            20,
+            SearchStrategy::DepthFirst,
+            &SolveProfile::default(),
+        );
+        let taken = first.by_ref().take(3).collect::<Vec<_>>();
+        assert_eq!(taken.len(), 3);
+        let (errors, profile) = first.finish();
+        assert!(errors.is_empty());
+        // The search stopped at most one proof ahead.
+        assert!(profile.stats.nodes_explored < 20);
+    }
+
+    #[test]
+    #[serial]
+    fn parallel_exploration() {
+        let clauses: Vec<logic::Clause> = (0..20)
+            .map(|i| format!("v(\"{}\").", i).parse().unwrap())
+            .chain(vec![
+                "pair(X, Y) :- v(X), v(Y), string_eq(X, Y)."
+                    .parse()
+                    .unwrap(),
+                "pair(X, Y) :- missing(X, Y).".parse().unwrap(),
+            ])
+            .collect();
+        let goal: Goal = vec!["pair(X, Y)".parse().unwrap()];
+
+        let sequential = sld(&clauses, &goal, 20, true);
+        let mut profile = SolveProfile::new().with_parallelism(true);
+        let parallel = sld_profiled(&clauses, &goal, 20, true, &mut profile);
+        assert_eq!(solutions(&parallel.tree), solutions(&sequential.tree));
+        assert_eq!(solutions(&parallel.tree).len(), 20);
+        assert_eq!(parallel.errors, sequential.errors);
+        assert_eq!(profile.predicates[&Signature("v".into(), 1)].selections, 21);
+    }
+
+    #[test]
+    fn parallel_exploration_shares_node_limit() {
+        let clauses: Vec<logic::Clause> = (0..20)
+            .map(|i| format!("v(\"{}\").", i).parse().unwrap())
+            .chain(vec![
+                "p(X) :- v(X), loop(X).".parse().unwrap(),
+                "loop(X) :- loop(X).".parse().unwrap(),
+            ])
+            .collect();
+        let goal: Goal = vec!["p(X)".parse().unwrap()];
+        let limits = ResourceLimits {
+            max_nodes: Some(200),
+            ..Default::default()
+        };
+        let mut profile = SolveProfile::new()
+            .with_parallelism(true)
+            .with_limits(limits);
+        let result = sld_profiled(&clauses, &goal, 1000, false, &mut profile);
+        assert!(result.errors.iter().any(|e| matches!(
+            e,
+            ResolutionError::ResourceLimitExceeded(ResourceLimit::Nodes(200), _)
+        )));
+        // Each worker may explore one node past the limit before it notices.
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        assert!(profile.stats.nodes_explored <= 200 + workers);
+
+        // The workers draw from the budget of the solve they were forked from.
+        let mut profile = SolveProfile::new().with_limits(limits);
+        profile.start_solve();
+        let mut workers = [profile.fork(), profile.fork()];
+        for _ in 0..101 {
+            for worker in workers.iter_mut() {
+                worker.reach(&vec![], 0);
+            }
+        }
+        assert!(profile.interruption(&vec![]).is_some());
+        assert!(workers.iter().all(|w| w.interruption(&vec![]).is_some()));
+    }
+
+    #[test]
+    #[serial]
+    fn iterative_deepening() {
+        let clauses: Vec<logic::Clause> = std::iter::once("p0.".parse().unwrap())
+            .chain((1..30).map(|i| format!("p{} :- p{}.", i, i - 1).parse().unwrap()))
+            .collect();
+        let goal: Goal = vec!["p29".parse().unwrap()];
+
+        // Deeper than the first search.
+        let result = sld(&clauses, &goal, 50, false);
+        assert!(result.errors.is_empty());
+        assert_eq!(solutions(&result.tree).len(), 1);
+
+        let result = sld(&clauses, &goal, 20, false);
+        assert!(solutions(&result.tree).is_empty());
+        let messages = result
+            .errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec!["depth limit of 20 reached while expanding goal p9"]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn tabling() {
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "edge(\"c\", \"a\").".parse().unwrap(),
+            "edge(\"d\", \"a\").".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "path(X, Y) :- path(X, Z), edge(Z, Y).".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["path(\"a\", Y)".parse().unwrap()];
+
+        // Left recursion does not terminate without tabling.
+        let result = sld(&clauses, &goal, 50, false);
+        assert!(result
+            .errors
+            .iter()
+            .any(|e| matches!(e, ResolutionError::MaximumDepthExceeded(..))));
+
+        let mut profile = SolveProfile::default().with_tabling(true);
+        let result = sld_profiled(&clauses, &goal, 50, false, &mut profile);
+        assert!(result.errors.is_empty());
+        let mut reached = solutions(&result.tree)
+            .into_iter()
+            .map(|g| g[0].args[1].clone())
+            .collect::<Vec<_>>();
+        reached.sort();
+        assert_eq!(
+            reached,
+            vec![
+                IRTerm::Constant("a".into()),
+                IRTerm::Constant("b".into()),
+                IRTerm::Constant("c".into()),
+            ]
+        );
+
+        let goal: Goal = vec!["path(\"a\", \"d\")".parse().unwrap()];
+        let mut profile = SolveProfile::default().with_tabling(true);
+        let result = sld_profiled(&clauses, &goal, 50, false, &mut profile);
+        assert!(failed_cleanly(&result.errors));
+        assert!(solutions(&result.tree).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn tabled_proofs() {
+        fn steps(p: &Proof, clauses: &[Clause]) -> String {
+            let step = match &p.clause {
+                ClauseId::Rule(rid) => clauses[*rid].head.substitute(&p.valuation).to_string(),
+                ClauseId::Builtin(b) => b.substitute(&p.valuation).to_string(),
+                c => format!("{:?}", c),
+            };
+            let children = p.children.iter().map(|c| steps(c, clauses)).join(", ");
+            format!("{}[{}]", step, children)
+        }
+        let proofs_of = |clauses: &[Clause], goal: &Goal, tabling: bool| {
+            let mut profile = SolveProfile::default().with_tabling(tabling);
+            let result = sld_profiled(clauses, goal, 50, false, &mut profile);
+            proofs(&result.tree, clauses, goal)
+                .into_iter()
+                .map(|(solution, proof)| (solution.iter().join(", "), steps(&proof, clauses)))
+                .sorted()
+                .collect::<Vec<_>>()
+        };
+
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "edge(\"c\", \"d\").".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Z), path(Z, Y).".parse().unwrap(),
+        ];
+        for goal in ["path(\"a\", Y)", "path(X, \"d\")", "path(\"a\", \"d\")"] {
+            let goal: Goal = vec![goal.parse().unwrap()];
+            let untabled = proofs_of(&clauses, &goal, false);
+            assert!(!untabled.is_empty());
+            assert_eq!(proofs_of(&clauses, &goal, true), untabled);
+        }
+
+        // With left recursion, which only terminates with tabling.
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "path(X, Y) :- path(X, Z), edge(Z, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["path(\"a\", Y)".parse().unwrap()];
+        assert_eq!(
+            proofs_of(&clauses, &goal, true)
+                .into_iter()
+                .map(|(_, steps)| steps)
+                .collect::<Vec<_>>(),
+            vec![
+                "path(\"a\", \"b\")[edge(\"a\", \"b\")[]]",
+                "path(\"a\", \"c\")[path(\"a\", \"b\")[edge(\"a\", \"b\")[]], edge(\"b\", \"c\")[]]",
+            ]
+            .into_iter()
+            .map(|s| format!("Query[{}]", s))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn cheapest_first_selection() {
+        let clauses: Vec<logic::Clause> = (0..10)
+            .map(|i| format!("v(\"{}\").", i).parse().unwrap())
+            .chain([
+                "check(\"no\").".parse().unwrap(),
+                "a(X, Y) :- v(X), v(Y), check(X).".parse().unwrap(),
+            ])
+            .collect();
+        let goal: Goal = vec!["a(X, Y)".parse().unwrap()];
+
+        let explored = |rule: SelectionRule| {
+            let mut profile = SolveProfile::default().with_selection(rule);
+            let res = sld_profiled(&clauses, &goal, 20, false, &mut profile);
+            assert!(!res.tree.is_success());
+            profile.stats.nodes_explored
+        };
+        // check(X) has a single rule, which binds X to a value that fails at once, instead
+        // of being checked on a branch for each value of X.
+        assert_eq!(explored(SelectionRule::CheapestFirst), 3);
+        assert_eq!(explored(SelectionRule::Leftmost), 12);
+
+        let clauses: Vec<logic::Clause> = vec![
+            "a(X) :- v(X), w(X).".parse().unwrap(),
+            "v(\"1\").".parse().unwrap(),
+            "v(\"2\").".parse().unwrap(),
+            "w(\"2\").".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["a(X)".parse().unwrap()];
+        let mut profile = SolveProfile::default();
+        let res = sld_profiled(&clauses, &goal, 20, true, &mut profile);
+        // w(X) has a single rule, so it is selected first, but the proof follows the body.
+        let proofs = proofs(&res.tree, &clauses, &goal);
+        let proof = proofs.values().next().unwrap();
+        assert_eq!(proofs.len(), 1);
+        assert_eq!(proof.children[0].children[0].clause, ClauseId::Rule(2));
+        assert_eq!(proof.children[0].children[1].clause, ClauseId::Rule(3));
+        assert_eq!(profile.stats.nodes_explored, 4);
+    }
+
+    #[test]
+    #[serial]
+    fn bottom_up_evaluation() {
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "edge(\"c\", \"a\").".parse().unwrap(),
+            "path(X, Y) :- path(X, Z), edge(Z, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "app(X) :- path(\"a\", X), from(X).".parse().unwrap(),
+        ];
+        let goal: Goal = vec!["app(X)".parse().unwrap()];
+
+        let mut profile = SolveProfile::default().with_bottom_up(true);
+        let result = sld_profiled(&clauses, &goal, 50, true, &mut profile);
+        assert!(result.errors.is_empty());
+        let proofs = proofs(&result.tree, &clauses, &goal);
+        assert_eq!(proofs.len(), 3);
+        // The path is resolved with a fact, and the image with the rule.
+        for proof in proofs.values() {
+            assert_eq!(proof.children[0].clause, ClauseId::Rule(5));
+            assert!(proof.children[0].children[0].clause.is_builtin());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn suspended_comparisons() {
+        let clauses: Vec<logic::Clause> = vec![
+            "v(\"2\").".parse().unwrap(),
+            "v(\"4\").".parse().unwrap(),
+            "v(\"7\").".parse().unwrap(),
+        ];
+
+        let goal: Goal<logic::IRTerm> = vec![
+            "number_gt(X, \"3\")".parse().unwrap(),
+            "number_lt(X, \"5\")".parse().unwrap(),
+            "v(X)".parse().unwrap(),
+        ];
+        let tree = sld(&clauses, &goal, 10, false).tree;
+        let solutions = proofs(&tree, &clauses, &goal);
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(
+            solutions.keys().next().unwrap()[2].args,
+            vec![IRTerm::Constant("4".into())]
+        );
+
+        // The comparisons cannot both hold, so the goal fails before resolving `v(X)`.
+        let goal: Goal<logic::IRTerm> = vec![
+            "number_gt(X, \"5\")".parse().unwrap(),
+            "number_geq(\"3\", X)".parse().unwrap(),
+            "v(X)".parse().unwrap(),
+        ];
+        let mut profile = SolveProfile::default();
+        let res = sld_profiled(&clauses, &goal, 10, true, &mut profile);
+        assert!(!res.tree.is_success());
+        assert_eq!(profile.stats.nodes_explored, 1);
+        assert!(matches!(
+            res.errors.iter().next(),
+            Some(ResolutionError::BuiltinFailure(_, "number_geq"))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn number_arithmetic() {
+        let clauses: Vec<logic::Clause> = vec![
+            "base_port(\"8000\").".parse().unwrap(),
+            "port(I, P) :- number_add(B, I, P), base_port(B)."
+                .parse()
+                .unwrap(),
+        ];
+
+        // `number_add` waits for `base_port` to ground its first argument.
+        let goal: Goal<logic::IRTerm> = vec!["port(\"80\", P)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, false).tree;
+        let sols = solutions(&tree);
+        assert_eq!(sols.len(), 1);
+        assert_eq!(
+            sols.iter().next().unwrap()[0].args[1],
+            IRTerm::Constant("8080".into())
+        );
+
+        // The offset is solved for given the port.
+        let goal: Goal<logic::IRTerm> = vec!["port(I, \"8443\")".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, false).tree;
+        let sols = solutions(&tree);
+        assert_eq!(
+            sols.iter().next().unwrap()[0].args[0],
+            IRTerm::Constant("443".into())
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn list_builtins() {
+        let clauses: Vec<logic::Clause> = vec![
+            "packages([\"curl\", \"git\"]).".parse().unwrap(),
+            "installed(P) :- packages(Ps), member(P, Ps)."
+                .parse()
+                .unwrap(),
+            "first(F) :- packages(Ps), append([F], _, Ps)."
+                .parse()
+                .unwrap(),
+        ];
+
+        let goal: Goal<logic::IRTerm> = vec!["installed(P)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, false).tree;
+        let installed = solutions(&tree)
+            .into_iter()
+            .map(|g| g[0].args[0].clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            installed,
+            vec![
+                IRTerm::Constant("curl".into()),
+                IRTerm::Constant("git".into())
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        let goal: Goal<logic::IRTerm> = vec!["first(F)".parse().unwrap()];
+        let tree = sld(&clauses, &goal, 10, false).tree;
+        let sols = solutions(&tree);
+        assert_eq!(sols.len(), 1);
+        assert_eq!(
+            sols.iter().next().unwrap()[0].args[0],
+            IRTerm::Constant("curl".into())
+        );
+    }
+
     #[test]
     #[serial]
     fn lists_error_when_ungrounded() {
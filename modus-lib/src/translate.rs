@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{collections::HashSet, sync::atomic::AtomicUsize};
+use std::sync::atomic::AtomicUsize;
 
 use itertools::Itertools;
 
@@ -30,9 +30,9 @@ use crate::{
 /// needed to make this equivalent.
 fn convert_format_string(
     spanned_position: &SpannedPosition,
-    fragments: &Vec<FormatStringFragment>,
+    fragments: &[FormatStringFragment],
 ) -> (Vec<logic::Literal>, IRTerm) {
-    let concat_predicate = logic::Predicate("string_concat".to_string());
+    let concat_predicate = logic::Predicate("string_concat".into());
     let mut prev_variable: IRTerm = Auxiliary::aux(false);
     let mut new_literals = vec![];
 
@@ -47,8 +47,13 @@ fn convert_format_string(
             }),
             predicate: concat_predicate.clone(),
             args: vec![
-                IRTerm::Constant("".to_owned()),
-                IRTerm::Constant(process_raw_string(&s).replace("\\$", "$")),
+                IRTerm::Constant("".into()),
+                IRTerm::Constant(
+                    process_raw_string(s)
+                        .expect("BUG: the parser should've rejected invalid escapes.")
+                        .replace("\\$", "$")
+                        .into(),
+                ),
                 prev_variable.clone(),
             ],
         }),
@@ -61,8 +66,8 @@ fn convert_format_string(
                 }),
                 predicate: concat_predicate.clone(),
                 args: vec![
-                    IRTerm::Constant("".to_owned()),
-                    IRTerm::UserVariable(v.to_string()),
+                    IRTerm::Constant("".into()),
+                    IRTerm::UserVariable(v.into()),
                     prev_variable.clone(),
                 ],
             })
@@ -76,7 +81,7 @@ fn convert_format_string(
                 }),
                 predicate: concat_predicate.clone(),
                 args: vec![
-                    IRTerm::Constant("".to_owned()),
+                    IRTerm::Constant("".into()),
                     sld::Auxiliary::aux(true),
                     prev_variable.clone(),
                 ],
@@ -93,10 +98,15 @@ fn convert_format_string(
             let (span, new_term) = match fragment {
                 FormatStringFragment::StringContent(span, s) => (
                     span,
-                    IRTerm::Constant(process_raw_string(&s).replace("\\$", "$")),
+                    IRTerm::Constant(
+                        process_raw_string(s)
+                            .expect("BUG: the parser should've rejected invalid escapes.")
+                            .replace("\\$", "$")
+                            .into(),
+                    ),
                 ),
                 FormatStringFragment::InterpolatedVariable(span, v) => {
-                    (span, IRTerm::UserVariable(v.to_string()))
+                    (span, IRTerm::UserVariable(v.into()))
                 }
                 FormatStringFragment::InterpolatedAnonymousVariable(span) => {
                     (span, sld::Auxiliary::aux(true))
@@ -122,7 +132,7 @@ fn convert_format_string(
     (
         new_literals,
         // special case of empty f-string is converted to constant
-        if fragments.len() == 0 {
+        if fragments.is_empty() {
             IRTerm::Constant("".into())
         } else {
             prev_variable
@@ -156,7 +166,14 @@ pub(crate) fn reset_negation_literal_id() {
 /// alongside whatever predicate is using this term.
 fn translate_term(t: &ModusTerm) -> (IRTerm, Vec<logic::Literal>) {
     match t {
-        ModusTerm::Constant(c) => (IRTerm::Constant(process_raw_string(c)), Vec::new()),
+        ModusTerm::Constant(c) => (
+            IRTerm::Constant(
+                process_raw_string(c)
+                    .expect("BUG: the parser should've rejected invalid escapes.")
+                    .into(),
+            ),
+            Vec::new(),
+        ),
         ModusTerm::FormatString {
             position,
             fragments,
@@ -164,7 +181,7 @@ fn translate_term(t: &ModusTerm) -> (IRTerm, Vec<logic::Literal>) {
             let (new_literals, new_var) = convert_format_string(position, fragments);
             (new_var, new_literals)
         }
-        ModusTerm::UserVariable(v) => (IRTerm::UserVariable(v.to_owned()), Vec::new()),
+        ModusTerm::UserVariable(v) => (IRTerm::UserVariable(v.into()), Vec::new()),
         ModusTerm::AnonymousVariable => (sld::Auxiliary::aux(true), Vec::new()),
         ModusTerm::List(_, ts) => {
             let mut new_terms = Vec::new();
@@ -181,11 +198,14 @@ fn translate_term(t: &ModusTerm) -> (IRTerm, Vec<logic::Literal>) {
 
 /// Replaces negation on expressions with literals and new clauses.
 fn handle_negation(modus_clause: &modusfile::ModusClause) -> Vec<modusfile::ModusClause> {
-    fn new_head_literal_for_negation(args: Vec<ModusTerm>) -> logic::Literal<ModusTerm> {
+    fn new_head_literal_for_negation(
+        args: Vec<ModusTerm>,
+        position: Option<SpannedPosition>,
+    ) -> logic::Literal<ModusTerm> {
         logic::Literal {
             positive: true,
-            position: None,
-            predicate: Predicate(format!("_negate_{}", fetch_add_negation_literal_id())),
+            position,
+            predicate: Predicate(format!("_negate_{}", fetch_add_negation_literal_id()).into()),
             args: args.into_iter().unique().collect(),
         }
     }
@@ -204,10 +224,12 @@ fn handle_negation(modus_clause: &modusfile::ModusClause) -> Vec<modusfile::Modu
                             .iter()
                             .map(|s| ModusTerm::UserVariable(s.to_string()))
                             .collect(),
+                        l.position.clone(),
                     );
                     let new_clause = modusfile::ModusClause {
                         head: new_negate_literal.clone(),
                         body: Some(expr.negate_current()),
+                        position: l.position.clone(),
                     };
 
                     clauses.extend(handle_negation(&new_clause));
@@ -242,10 +264,12 @@ fn handle_negation(modus_clause: &modusfile::ModusClause) -> Vec<modusfile::Modu
                         .iter()
                         .map(|s| ModusTerm::UserVariable(s.to_string()))
                         .collect(),
+                    s.clone(),
                 );
                 let new_clause = modusfile::ModusClause {
                     head: new_negate_literal.clone(),
                     body: Some(expr.negate_current()),
+                    position: s.clone(),
                 };
 
                 clauses.extend(handle_negation(&new_clause));
@@ -265,11 +289,61 @@ fn handle_negation(modus_clause: &modusfile::ModusClause) -> Vec<modusfile::Modu
             .body
             .as_ref()
             .map(|e| handle_expression(e, &mut clauses)),
+        position: modus_clause.position.clone(),
     };
     clauses.push(new_clause);
     clauses
 }
 
+/// Replaces the format strings in the head of the clause with new variables, which are
+/// unified with the format strings at the end of the body. Analysis rejects such heads,
+/// but they are still translated, so that solving a Modusfile that was not checked does
+/// not panic.
+fn handle_head_format_strings(modus_clause: &ModusClause) -> ModusClause {
+    fn replace(t: &ModusTerm, unifications: &mut Vec<Expression>) -> ModusTerm {
+        match t {
+            ModusTerm::FormatString { position, .. } => {
+                // Not a valid identifier, so it can't clash with the variables of the clause.
+                let var = ModusTerm::UserVariable(format!("$head{}", unifications.len()));
+                unifications.push(Expression::Literal(logic::Literal {
+                    positive: true,
+                    position: Some(position.clone()),
+                    predicate: Predicate("string_eq".into()),
+                    args: vec![var.clone(), t.clone()],
+                }));
+                var
+            }
+            ModusTerm::List(position, ts) => ModusTerm::List(
+                position.clone(),
+                ts.iter().map(|t| replace(t, unifications)).collect(),
+            ),
+            t => t.clone(),
+        }
+    }
+
+    let mut unifications = Vec::new();
+    let args = modus_clause
+        .head
+        .args
+        .iter()
+        .map(|t| replace(t, &mut unifications))
+        .collect();
+    let body = modus_clause
+        .body
+        .iter()
+        .cloned()
+        .chain(unifications)
+        .reduce(|e1, e2| Expression::And(None, true, Box::new(e1), Box::new(e2)));
+    ModusClause {
+        head: logic::Literal {
+            args,
+            ..modus_clause.head.clone()
+        },
+        body,
+        position: modus_clause.position.clone(),
+    }
+}
+
 impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
     /// Convert a ModusClause into one supported by the IR.
     /// It converts logical or/; into multiple rules, which should be equivalent.
@@ -301,13 +375,14 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                 Some(Expression::OperatorApplication(_, expr, op)) => handle_clause(&ModusClause {
                     head: modus_clause.head.clone(),
                     body: Some(*expr.clone()),
+                    position: modus_clause.position.clone(),
                 })
                 .into_iter()
                 .map(|c| {
                     let mut body = Vec::with_capacity(c.body.len() + 2);
                     let mut op_args = Vec::with_capacity(op.args.len() + 1);
                     let id = OPERATOR_PAIR_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                    op_args.push(IRTerm::Constant(id.to_string()));
+                    op_args.push(IRTerm::Constant(id.to_string().into()));
                     op_args.extend(op.args.iter().map(|t| {
                         let (t, nl) = translate_term(t);
                         body.extend_from_slice(&nl);
@@ -316,14 +391,14 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                     body.push(logic::Literal {
                         positive: true,
                         position: op.position.clone(),
-                        predicate: Predicate(format!("_operator_{}_begin", &op.predicate.0)),
+                        predicate: Predicate(format!("_operator_{}_begin", op.predicate.0).into()),
                         args: op_args.clone(),
                     });
                     body.extend_from_slice(&c.body);
                     body.push(logic::Literal {
                         positive: true,
                         position: op.position.clone(),
-                        predicate: Predicate(format!("_operator_{}_end", &op.predicate.0)),
+                        predicate: Predicate(format!("_operator_{}_end", op.predicate.0).into()),
                         args: op_args,
                     });
                     logic::Clause {
@@ -337,10 +412,12 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                     let c1 = handle_clause(&ModusClause {
                         head: modus_clause.head.clone(),
                         body: Some(*expr1.clone()),
+                        position: modus_clause.position.clone(),
                     });
                     let c2 = handle_clause(&ModusClause {
                         head: modus_clause.head.clone(),
                         body: Some(*expr2.clone()),
+                        position: modus_clause.position.clone(),
                     });
 
                     let mut clauses = Vec::new();
@@ -354,7 +431,7 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                                     .body
                                     .clone()
                                     .into_iter()
-                                    .chain(clause2.body.clone().into_iter())
+                                    .chain(clause2.body.clone())
                                     .collect(),
                             })
                         }
@@ -366,10 +443,12 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                     let mut c1 = handle_clause(&ModusClause {
                         head: modus_clause.head.clone(),
                         body: Some(*expr1.clone()),
+                        position: modus_clause.position.clone(),
                     });
                     let mut c2 = handle_clause(&ModusClause {
                         head: modus_clause.head.clone(),
                         body: Some(*expr2.clone()),
+                        position: modus_clause.position.clone(),
                     });
 
                     c1.append(&mut c2);
@@ -389,7 +468,7 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
         }
 
         // convert negated expressions into negated literals, then perform translation as normal
-        let without_expr_negation = handle_negation(modus_clause);
+        let without_expr_negation = handle_negation(&handle_head_format_strings(modus_clause));
         let ir_clauses: Vec<logic::Clause> = without_expr_negation
             .iter()
             .flat_map(handle_clause)
@@ -420,7 +499,7 @@ mod tests {
     fn translate_constant_term() {
         let inp1 = r#"Hello\nWorld"#;
         let modus_term1 = ModusTerm::Constant(inp1.to_owned());
-        let ir_term = IRTerm::Constant("Hello\nWorld".to_owned());
+        let ir_term = IRTerm::Constant("Hello\nWorld".into());
 
         assert_eq!(ir_term, translate_term(&modus_term1).0)
     }
@@ -435,7 +514,7 @@ mod tests {
         let lits = vec![];
 
         assert_eq!(
-            (lits, IRTerm::Constant("".to_string())),
+            (lits, IRTerm::Constant("".into())),
             convert_format_string(
                 &SpannedPosition {
                     offset: 0,
@@ -469,10 +548,10 @@ mod tests {
                     offset: 2,
                     length: 16,
                 }),
-                predicate: logic::Predicate("string_concat".to_owned()),
+                predicate: logic::Predicate("string_concat".into()),
                 args: vec![
                     IRTerm::Constant("".into()),
-                    IRTerm::UserVariable("target_folder".to_owned()),
+                    IRTerm::UserVariable("target_folder".into()),
                     IRTerm::AuxiliaryVariable(0),
                 ],
             },
@@ -482,10 +561,10 @@ mod tests {
                     offset: 2,
                     length: 36,
                 }),
-                predicate: logic::Predicate("string_concat".to_owned()),
+                predicate: logic::Predicate("string_concat".into()),
                 args: vec![
                     IRTerm::AuxiliaryVariable(0),
-                    IRTerm::Constant("/buildkit-frontend".to_owned()),
+                    IRTerm::Constant("/buildkit-frontend".into()),
                     IRTerm::AuxiliaryVariable(1),
                 ],
             },
@@ -523,10 +602,10 @@ mod tests {
                     offset: 2,
                     length: 6,
                 }),
-                predicate: logic::Predicate("string_concat".to_owned()),
+                predicate: logic::Predicate("string_concat".into()),
                 args: vec![
                     IRTerm::Constant("".into()),
-                    IRTerm::Constant("use \"".to_owned()),
+                    IRTerm::Constant("use \"".into()),
                     IRTerm::AuxiliaryVariable(0),
                 ],
             },
@@ -536,10 +615,10 @@ mod tests {
                     offset: 2,
                     length: 15,
                 }),
-                predicate: logic::Predicate("string_concat".to_owned()),
+                predicate: logic::Predicate("string_concat".into()),
                 args: vec![
                     IRTerm::AuxiliaryVariable(0),
-                    IRTerm::UserVariable("feature".to_owned()),
+                    IRTerm::UserVariable("feature".into()),
                     IRTerm::AuxiliaryVariable(1),
                 ],
             },
@@ -549,10 +628,10 @@ mod tests {
                     offset: 2,
                     length: 80,
                 }),
-                predicate: logic::Predicate("string_concat".to_owned()),
+                predicate: logic::Predicate("string_concat".into()),
                 args: vec![
                     IRTerm::AuxiliaryVariable(1),
-                    IRTerm::Constant("\" like this ${...} foobar".to_owned()),
+                    IRTerm::Constant("\" like this ${...} foobar".into()),
                     IRTerm::AuxiliaryVariable(2),
                 ],
             },
@@ -725,10 +804,7 @@ mod tests {
                 positive: true,
                 position: None,
                 predicate: Predicate("foo".into()),
-                args: vec![
-                    IRTerm::Constant("bar".to_string()),
-                    IRTerm::AnonymousVariable(0),
-                ],
+                args: vec![IRTerm::Constant("bar".into()), IRTerm::AnonymousVariable(0)],
             },
             body: vec![],
         }];
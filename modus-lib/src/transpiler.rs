@@ -14,49 +14,199 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{io::Write, str::FromStr};
+use std::{collections::HashSet, fmt, io::Write, str::FromStr};
 
-use codespan_reporting::diagnostic::Diagnostic;
+use codespan_reporting::{files::Files, files::SimpleFile};
+use itertools::Itertools;
 
 use crate::{
-    dockerfile::{Dockerfile, Image, Instruction, ResolvedDockerfile, ResolvedParent, Run},
-    imagegen::{self, BuildPlan, MergeNode, NodeId},
-    logic::{self, Clause, IRTerm, Literal, Predicate},
+    dockerfile::{Dockerfile, Instruction, ResolvedDockerfile, ResolvedParent, Run, INSECURE_FLAG},
+    error::ModusError,
+    imagegen::{self, wrap_command, BuildPlan, MergeNode},
+    logic::{Clause, Literal},
     modusfile::{self, Modusfile},
-    sld::{self, ClauseId, ResolutionError, SLDResult, Tree},
+    sld::{SLDResult, SearchStrategy, SolveProfile},
 };
 
 use crate::imagegen::BuildNode;
 
 /// Renders the entire SLD tree as a DOT graph, to the writer.
-pub fn render_tree<W: Write>(clauses: &Vec<Clause>, sld_result: SLDResult, output: &mut W) {
+pub fn render_tree<W: Write>(clauses: &[Clause], sld_result: SLDResult, output: &mut W) {
     // TODO: we could figure out the corresponding error for each failed path
     let g = sld_result.tree.to_graph(clauses);
     dot::render(&g, output).unwrap()
 }
 
-pub fn transpile(
+/// Transpiles the query into a Dockerfile. `file` is the source of the Modusfile, which
+/// each stage refers to in a comment naming the clause that produced it.
+pub fn transpile<N: fmt::Display + Clone, S: AsRef<str>>(
+    file: &SimpleFile<N, S>,
     mf: Modusfile,
     query: modusfile::Expression,
-) -> Result<Dockerfile<ResolvedParent>, Vec<Diagnostic<()>>> {
-    let build_plan = imagegen::plan_from_modusfile(mf, query)?;
-    Ok(plan_to_docker(&build_plan))
+    strategy: SearchStrategy,
+    profile: &mut SolveProfile,
+) -> Result<Dockerfile<ResolvedParent>, ModusError> {
+    let build_plan = imagegen::plan_from_modusfile(mf, query, strategy, profile)?;
+    Ok(plan_to_docker(&build_plan, file))
 }
 
-fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
+/// Transpiles several queries into a single Dockerfile, planning them in parallel.
+pub fn transpile_queries<N: fmt::Display + Clone, S: AsRef<str>>(
+    file: &SimpleFile<N, S>,
+    mf: &Modusfile,
+    queries: &[modusfile::Expression],
+    strategy: SearchStrategy,
+    profile: &mut SolveProfile,
+) -> Result<Dockerfile<ResolvedParent>, ModusError> {
+    let build_plan = imagegen::plan_from_queries(mf, queries, strategy, profile)?;
+    Ok(plan_to_docker(&build_plan, file))
+}
+
+/// Transpiles several queries into a separate Dockerfile for each solved output, along
+/// with a file name for it derived from the output's literal, e.g. `app-3.11.Dockerfile`.
+pub fn transpile_queries_per_output<N: fmt::Display + Clone, S: AsRef<str>>(
+    file: &SimpleFile<N, S>,
+    mf: &Modusfile,
+    queries: &[modusfile::Expression],
+    strategy: SearchStrategy,
+    profile: &mut SolveProfile,
+) -> Result<Vec<(String, ResolvedDockerfile)>, ModusError> {
+    let build_plan = imagegen::plan_from_queries(mf, queries, strategy, profile)?;
+    let mut names = HashSet::new();
+    Ok(build_plan
+        .outputs
+        .iter()
+        .map(|o| {
+            let literal = o
+                .source_literal
+                .as_ref()
+                .expect("Expected source_literal to present in build plan");
+            let mut name = dockerfile_name(literal);
+            let mut n = 1;
+            while !names.insert(name.clone()) {
+                n += 1;
+                name = format!("{}-{}", dockerfile_name(literal), n);
+            }
+            // Only the stages this output depends on are emitted, see `plan_to_docker`.
+            let plan = BuildPlan {
+                outputs: vec![o.clone()],
+                ..build_plan.clone()
+            };
+            (format!("{}.Dockerfile", name), plan_to_docker(&plan, file))
+        })
+        .collect())
+}
+
+/// A file name stem for the output built for the literal, from its predicate and arguments.
+fn dockerfile_name(literal: &Literal) -> String {
+    std::iter::once(literal.predicate.to_string())
+        .chain(literal.args.iter().map(|arg| match arg.as_constant() {
+            Some(c) => c.to_owned(),
+            None => arg.to_string(),
+        }))
+        .join("-")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Describes the clause a node was produced by, as `file:line: head`.
+fn source_comment<N: fmt::Display + Clone, S: AsRef<str>>(
+    head: &Literal,
+    file: &SimpleFile<N, S>,
+) -> String {
+    match head
+        .position
+        .as_ref()
+        .and_then(|p| file.line_index((), p.offset).ok())
+    {
+        Some(line) => format!("{}:{}: {}", file.name(), line + 1, head),
+        None => format!("{}: {}", file.name(), head),
+    }
+}
+
+/// Dockerfiles have no stable way of fetching a git repository, so `git_checkout` is
+/// cloned with git, which needs to be installed in the image.
+fn git_clone_command(url: &str, rev: &str, dst_path: &str) -> String {
+    format!(
+        "git clone --quiet {:?} {:?} && git -C {:?} checkout --quiet {:?}",
+        url, dst_path, dst_path, rev
+    )
+}
+
+/// The `RUN` instruction of a command, which is run with `--security=insecure` if it
+/// was marked `::insecure`, and with its `::cache_mount`s and `::secret`s.
+fn run_instructions<P>(
+    run: Run,
+    insecure: bool,
+    cache_mounts: &[String],
+    secrets: &[String],
+) -> Vec<Instruction<P>> {
+    let mut instructions = Vec::new();
+    let mut flags = cache_mounts
+        .iter()
+        .map(|path| format!("--mount=type=cache,target={}", path))
+        .chain(
+            secrets
+                .iter()
+                .map(|id| format!("--mount=type=secret,id={}", id)),
+        )
+        .collect::<Vec<_>>();
+    if !secrets.is_empty() {
+        let sources = secrets
+            .iter()
+            .map(|id| format!("--secret id={},src=<FILE>", id))
+            .collect::<Vec<_>>();
+        instructions.push(Instruction::Comment(format!(
+            "::secret, build with `docker buildx build {}`",
+            sources.join(" ")
+        )));
+    }
+    if insecure {
+        instructions.push(Instruction::Comment(
+            "::insecure, build with `docker buildx build --allow security.insecure` \
+             on a builder that allows the security.insecure entitlement"
+                .to_owned(),
+        ));
+        flags.push(INSECURE_FLAG.to_owned());
+    }
+    instructions.push(if flags.is_empty() {
+        Instruction::Run(run)
+    } else {
+        Instruction::RunWithFlags(flags, run)
+    });
+    instructions
+}
+
+fn plan_to_docker<N: fmt::Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+) -> ResolvedDockerfile {
+    // Stages that no output depends on, e.g. those of the other outputs of a plan, are
+    // not emitted, and the remaining ones are numbered without gaps. Outputs that start
+    // with the same instructions build from a single stage for them, so that it is only
+    // built, and cached, once.
+    let plan = &plan.with_shared_nodes();
     let topological_order = plan.topological_order();
 
     let mut instructions = topological_order
         .into_iter()
-        .map(|node_id| {
+        .flat_map(|node_id| {
             use crate::dockerfile::*;
             let node = &plan.nodes[node_id];
             let str_id = format!("n_{}", node_id);
-            match node {
+            let mut node_instructions = match node {
                 BuildNode::FromScratch { .. } => {
                     vec![Instruction::From(From {
                         parent: ResolvedParent::Image(Image::from_str("scratch").unwrap()),
                         alias: Some(str_id),
+                        platform: plan.platform.clone(),
                     })]
                 }
                 BuildNode::From {
@@ -65,25 +215,74 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                 } => vec![Instruction::From(From {
                     parent: ResolvedParent::Image(Image::from_str(image_ref).unwrap()),
                     alias: Some(str_id),
+                    platform: plan.platform.clone(),
                 })],
+                // Resolved by docker build from its --build-context with the same name.
+                BuildNode::FromContext { name } => vec![Instruction::From(From {
+                    parent: ResolvedParent::Stage(name.to_owned()),
+                    alias: Some(str_id),
+                    platform: None,
+                })],
+                // Dockerfiles cannot read local images themselves, so they are passed in as
+                // named contexts too.
+                BuildNode::FromOciLayout { path, .. } => {
+                    let name = format!("{}_oci_layout", str_id);
+                    vec![
+                        Instruction::Comment(format!(
+                            "Build with --build-context {}=oci-layout://<absolute path of {}>",
+                            name, path
+                        )),
+                        Instruction::From(From {
+                            parent: ResolvedParent::Stage(name),
+                            alias: Some(str_id),
+                            platform: None,
+                        }),
+                    ]
+                }
+                BuildNode::FromDockerTar { path, .. } => {
+                    let name = format!("{}_docker_tar", str_id);
+                    vec![
+                        Instruction::Comment(format!(
+                            "Load {} with docker load, and build with --build-context {}=docker-image://<loaded image>",
+                            path, name
+                        )),
+                        Instruction::From(From {
+                            parent: ResolvedParent::Stage(name),
+                            alias: Some(str_id),
+                            platform: None,
+                        }),
+                    ]
+                }
                 BuildNode::Run {
                     parent,
                     command,
                     cwd,
                     additional_envs,
+                    retry,
+                    timeout,
+                    insecure,
+                    cache_mounts,
+                    secrets,
                 } => {
+                    let command = wrap_command(command, *retry, timeout.as_ref());
                     let mut instructions = vec![Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     })];
                     for (k, v) in additional_envs.iter() {
                         instructions.push(Instruction::Env(Env(format!("{}={}", k, v))));
                     }
-                    instructions.push(Instruction::Run(Run(if cwd.is_empty() {
-                        command.to_owned()
-                    } else {
-                        format!("cd {:?} || exit 1; {}", cwd, command)
-                    })));
+                    instructions.extend(run_instructions(
+                        Run(if cwd.is_empty() {
+                            command.to_owned()
+                        } else {
+                            format!("cd {:?} || exit 1; {}", cwd, command)
+                        }),
+                        *insecure,
+                        cache_mounts,
+                        secrets,
+                    ));
                     instructions
                 }
                 BuildNode::CopyFromImage {
@@ -95,12 +294,26 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Copy(Copy(format!(
                         "--from=n_{} {:?} {:?}", // TODO: is this really correct?
                         src_image, src_path, dst_path
                     ))),
                 ],
+                BuildNode::GitCheckout {
+                    parent,
+                    url,
+                    rev,
+                    dst_path,
+                } => vec![
+                    Instruction::From(From {
+                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
+                        alias: Some(str_id),
+                        platform: None,
+                    }),
+                    Instruction::Run(Run(git_clone_command(url, rev, dst_path))),
+                ],
                 BuildNode::CopyFromLocal {
                     parent,
                     src_path,
@@ -109,6 +322,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Copy(Copy(format!("{:?} {:?}", src_path, dst_path))),
                 ],
@@ -119,6 +333,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Workdir(Workdir(new_workdir.to_string())),
                 ],
@@ -129,6 +344,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Entrypoint(format!("{:?}", new_entrypoint)),
                 ],
@@ -136,6 +352,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Cmd(format!("{:?}", new_cmd)),
                 ],
@@ -147,6 +364,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Label(label.to_owned(), value.to_owned()),
                 ],
@@ -155,6 +373,7 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     insts.push(Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }));
                     for op in operations {
                         use imagegen::MergeOperation;
@@ -163,15 +382,31 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                                 command,
                                 cwd,
                                 additional_envs,
+                                retry,
+                                timeout,
+                                insecure,
+                                cache_mounts,
+                                secrets,
                             } => {
+                                let command = wrap_command(command, *retry, timeout.as_ref());
                                 for (k, v) in additional_envs.iter() {
                                     insts.push(Instruction::Env(Env(format!("{}={}", k, v))));
                                 }
-                                insts.push(Instruction::Run(Run(if cwd.is_empty() {
-                                    command.to_owned()
-                                } else {
-                                    format!("cd {:?} || exit 1; {}", cwd, command)
-                                })));
+                                insts.extend(run_instructions(
+                                    Run(if cwd.is_empty() {
+                                        command.to_owned()
+                                    } else {
+                                        format!("cd {:?} || exit 1; {}", cwd, command)
+                                    }),
+                                    *insecure,
+                                    cache_mounts,
+                                    secrets,
+                                ));
+                            }
+                            MergeOperation::GitCheckout { url, rev, dst_path } => {
+                                insts.push(Instruction::Run(Run(git_clone_command(
+                                    url, rev, dst_path,
+                                ))));
                             }
                             MergeOperation::CopyFromLocal { src_path, dst_path } => {
                                 insts.push(Instruction::Copy(Copy(format!(
@@ -197,23 +432,33 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
                     Instruction::From(From {
                         parent: ResolvedParent::Stage(format!("n_{}", parent)),
                         alias: Some(str_id),
+                        platform: None,
                     }),
                     Instruction::Env(Env(format!("{}={}", key, value))),
                 ],
-                BuildNode::AppendEnvValue { parent, key, value } => {
+                BuildNode::AppendEnvValue {
+                    parent: _,
+                    key: _,
+                    value: _,
+                } => {
                     todo!()
                 }
                 BuildNode::SetUser { .. } => todo!(),
+            };
+            if let Some(head) = plan.source(node_id) {
+                // Every stage starts with its FROM line, so comment the rest of it.
+                node_instructions.insert(1, Instruction::Comment(source_comment(head, file)));
             }
+            node_instructions
         })
-        .flatten()
         .collect::<Vec<_>>();
 
     if plan.outputs.len() > 1 {
-        use crate::dockerfile::{From, Run};
+        use crate::dockerfile::From;
         instructions.push(Instruction::From(From {
             parent: ResolvedParent::Stage("busybox".to_owned()),
             alias: Some("force_multioutput".to_owned()),
+            platform: None,
         }));
 
         for o in plan.outputs.iter() {
@@ -227,3 +472,110 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
 
     Dockerfile(instructions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dockerfile_per_output() {
+        let source = "app(V) :- (V = \"3.9\" ; V = \"3.11\"), from(f\"python:${V}\").";
+        let file = SimpleFile::new("Modusfile", source);
+        let mf: Modusfile = source.parse().unwrap();
+        let queries = vec!["app(X)".parse().unwrap()];
+        let dockerfiles = transpile_queries_per_output(
+            &file,
+            &mf,
+            &queries,
+            SearchStrategy::DepthFirst,
+            &mut SolveProfile::default(),
+        )
+        .unwrap();
+
+        let mut names = dockerfiles
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, vec!["app-3.11.Dockerfile", "app-3.9.Dockerfile"]);
+        for (name, df) in dockerfiles.iter() {
+            let df = df.to_string();
+            assert_eq!(df.matches("FROM python:").count(), 1, "{}", name);
+            assert!(df.contains(" AS n_0\n"), "{}", name);
+            assert!(df.contains("# Modusfile:1: app(V)"));
+        }
+
+        let literal: Literal = "app(\"a/b c\", X)".parse().unwrap();
+        assert_eq!(dockerfile_name(&literal), "app-a_b_c-X");
+    }
+
+    #[test]
+    fn shared_base_stage() {
+        let source = "base :- from(\"alpine\"), run(\"apk add make\").\n\
+                      a :- base, run(\"make a\").\n\
+                      b :- base, run(\"make b\").";
+        let file = SimpleFile::new("Modusfile", source);
+        let mf: Modusfile = source.parse().unwrap();
+        let queries = vec!["a".parse().unwrap(), "b".parse().unwrap()];
+        let df = transpile_queries(
+            &file,
+            &mf,
+            &queries,
+            SearchStrategy::DepthFirst,
+            &mut SolveProfile::default(),
+        )
+        .unwrap()
+        .to_string();
+
+        assert_eq!(df.matches("FROM alpine:latest").count(), 1);
+        assert_eq!(df.matches("RUN apk add make").count(), 1);
+        assert_eq!(df.matches("RUN make a").count(), 1);
+        assert_eq!(df.matches("RUN make b").count(), 1);
+        assert_eq!(df.matches("FROM n_2 AS").count(), 2, "{}", df);
+    }
+
+    #[test]
+    fn target_platform() {
+        let source = "app :- target_platform(\"linux/arm64\"), from(\"alpine\").\n\
+                      app :- target_platform(\"linux/amd64\"), from(\"ubuntu\").";
+        let file = SimpleFile::new("Modusfile", source);
+        let mut mf: Modusfile = source.parse().unwrap();
+        mf.add_target_platform("linux/arm64");
+        let queries = vec!["app".parse().unwrap()];
+        let df = transpile_queries(
+            &file,
+            &mf,
+            &queries,
+            SearchStrategy::DepthFirst,
+            &mut SolveProfile::default(),
+        )
+        .unwrap()
+        .to_string();
+
+        assert!(df.contains("FROM --platform=linux/arm64 alpine:latest AS n_0"));
+        assert!(!df.contains("ubuntu"));
+    }
+
+    #[test]
+    fn git_checkout_is_cloned() {
+        let source = "app :- from(\"alpine/git\"), \
+                      git_checkout(\"https://example.com/a.git\", \"v1\", \"src\")::in_workdir(\"/app\").";
+        let file = SimpleFile::new("Modusfile", source);
+        let mf: Modusfile = source.parse().unwrap();
+        let queries = vec!["app".parse().unwrap()];
+        let df = transpile_queries(
+            &file,
+            &mf,
+            &queries,
+            SearchStrategy::DepthFirst,
+            &mut SolveProfile::default(),
+        )
+        .unwrap()
+        .to_string();
+
+        assert!(df.contains(
+            "RUN git clone --quiet \"https://example.com/a.git\" \"/app/src\" \
+             && git -C \"/app/src\" checkout --quiet \"v1\"\n"
+        ));
+    }
+}
@@ -14,12 +14,16 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use crate::logic;
-use logic::{Clause, Ground, IRTerm, Literal, Predicate};
+use logic::{Clause, Ground, IRTerm, Literal};
 
-pub type Substitution<T = IRTerm> = HashMap<T, T>;
+/// A mapping from variables to terms.
+///
+/// This is a persistent map, so cloning it, which happens for every node of the
+/// SLD tree, is cheap and the clones share their structure.
+pub type Substitution<T = IRTerm> = im::HashMap<T, T>;
 
 impl Ground for Substitution {
     fn is_ground(&self) -> bool {
@@ -61,20 +65,14 @@ impl Substitute<IRTerm> for IRTerm {
 impl RenameWithSubstitution<IRTerm> for IRTerm {
     type Output = IRTerm;
     fn rename_with_sub(&self) -> (Self::Output, Substitution<IRTerm>) {
-        let s: Substitution<IRTerm> = self
+        let s: Substitution = self
             .variables(true)
-            .iter()
+            .into_iter()
             .map(|r| {
-                [(r.clone(), r.rename())]
-                    .iter()
-                    .cloned()
-                    .collect::<Substitution<IRTerm>>()
-            })
-            .reduce(|mut l, r| {
-                l.extend(r);
-                l
+                let renamed = r.rename();
+                (r, renamed)
             })
-            .unwrap();
+            .collect();
         (self.substitute(&s), s)
     }
 }
@@ -96,18 +94,12 @@ impl RenameWithSubstitution<IRTerm> for Literal<IRTerm> {
     fn rename_with_sub(&self) -> (Self::Output, Substitution<IRTerm>) {
         let s: Substitution = self
             .variables(true)
-            .iter()
+            .into_iter()
             .map(|r| {
-                [(r.clone(), r.rename())]
-                    .iter()
-                    .cloned()
-                    .collect::<Substitution>()
-            })
-            .reduce(|mut l, r| {
-                l.extend(r);
-                l
+                let renamed = r.rename();
+                (r, renamed)
             })
-            .unwrap();
+            .collect();
         (self.substitute(&s), s)
     }
 }
@@ -122,20 +114,16 @@ impl Substitute<IRTerm> for Vec<Literal<IRTerm>> {
 impl RenameWithSubstitution<IRTerm> for Vec<Literal<IRTerm>> {
     type Output = Vec<Literal<IRTerm>>;
     fn rename_with_sub(&self) -> (Self::Output, Substitution<IRTerm>) {
-        let s: Substitution<IRTerm> = self
+        let s: Substitution = self
             .iter()
             .flat_map(|e| e.variables(true))
+            .collect::<HashSet<_>>()
+            .into_iter()
             .map(|r| {
-                [(r.clone(), r.rename())]
-                    .iter()
-                    .cloned()
-                    .collect::<Substitution<IRTerm>>()
+                let renamed = r.rename();
+                (r, renamed)
             })
-            .reduce(|mut l, r| {
-                l.extend(r);
-                l
-            })
-            .unwrap();
+            .collect();
         (self.substitute(&s), s)
     }
 }
@@ -153,20 +141,14 @@ impl Substitute<IRTerm> for Clause<IRTerm> {
 impl RenameWithSubstitution<IRTerm> for Clause<IRTerm> {
     type Output = Clause<IRTerm>;
     fn rename_with_sub(&self) -> (Self::Output, Substitution<IRTerm>) {
-        let s: Substitution<IRTerm> = self
+        let s: Substitution = self
             .variables(true)
-            .iter()
+            .into_iter()
             .map(|r| {
-                [(r.clone(), r.rename())]
-                    .iter()
-                    .cloned()
-                    .collect::<Substitution<IRTerm>>()
+                let renamed = r.rename();
+                (r, renamed)
             })
-            .reduce(|mut l, r| {
-                l.extend(r);
-                l
-            })
-            .unwrap_or_default();
+            .collect();
         (self.substitute(&s), s)
     }
 }
@@ -175,22 +157,55 @@ pub fn compose_no_extend(
     l: &Substitution<IRTerm>,
     r: &Substitution<IRTerm>,
 ) -> Substitution<IRTerm> {
-    let mut result = Substitution::<IRTerm>::new();
-    for (k, v) in l {
-        result.insert(k.clone(), v.substitute(r));
+    if r.is_empty() {
+        return l.clone();
     }
-    result
+    l.iter()
+        .map(|(k, v)| (k.clone(), v.substitute(r)))
+        .collect()
 }
 
 pub fn compose_extend(l: &Substitution<IRTerm>, r: &Substitution<IRTerm>) -> Substitution<IRTerm> {
-    let mut result = compose_no_extend(l, r);
-    result.extend(r.clone());
-    result
+    if l.is_empty() {
+        return r.clone();
+    }
+    // The bindings of `r` take precedence, `union` keeps those of the map it is called on.
+    r.clone().union(compose_no_extend(l, r))
+}
+
+/// A variable that would be bound to a list containing it, e.g. `X` to `[X]`, which would
+/// make the list cyclic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OccursCheckFailure {
+    pub variable: IRTerm,
+    pub list: IRTerm,
 }
 
 impl Literal<IRTerm> {
     pub fn unify(&self, other: &Literal<IRTerm>) -> Option<Substitution<IRTerm>> {
-        fn unify_arglist(current: &[IRTerm], other: &[IRTerm]) -> Option<Substitution<IRTerm>> {
+        self.unify_checked(other, false)
+            .expect("unification without the occurs check should not fail it")
+    }
+
+    /// Like [`Literal::unify`], but fails if a variable would be bound to a list that
+    /// contains it.
+    pub fn unify_with_occurs_check(
+        &self,
+        other: &Literal<IRTerm>,
+    ) -> Result<Option<Substitution<IRTerm>>, OccursCheckFailure> {
+        self.unify_checked(other, true)
+    }
+
+    fn unify_checked(
+        &self,
+        other: &Literal<IRTerm>,
+        occurs_check: bool,
+    ) -> Result<Option<Substitution<IRTerm>>, OccursCheckFailure> {
+        fn unify_arglist(
+            current: &[IRTerm],
+            other: &[IRTerm],
+            occurs_check: bool,
+        ) -> Result<Option<Substitution<IRTerm>>, OccursCheckFailure> {
             let mut s = Substitution::<IRTerm>::new();
             for (self_term, other_term) in current.iter().zip(other) {
                 let self_term_subs = self_term.substitute(&s);
@@ -198,16 +213,27 @@ impl Literal<IRTerm> {
                 if self_term_subs != other_term_subs {
                     match (self_term_subs.clone(), other_term_subs.clone()) {
                         // cannot unify if they are both different constants
-                        (IRTerm::Constant(_), IRTerm::Constant(_)) => return None,
-
-                        (IRTerm::List(_), IRTerm::List(_)) => {
-                            unimplemented!("TODO: borrow unification from Prolog.")
+                        (IRTerm::Constant(_), IRTerm::Constant(_)) => return Ok(None),
+
+                        (IRTerm::List(ts1), IRTerm::List(ts2)) => {
+                            if ts1.len() != ts2.len() {
+                                return Ok(None);
+                            }
+                            let upd = match unify_arglist(&ts1, &ts2, occurs_check)? {
+                                Some(upd) => upd,
+                                None => return Ok(None),
+                            };
+                            s = compose_extend(&s, &upd);
                         }
                         (IRTerm::List(_), IRTerm::Constant(_))
-                        | (IRTerm::Constant(_), IRTerm::List(_)) => return None,
+                        | (IRTerm::Constant(_), IRTerm::List(_)) => return Ok(None),
                         (IRTerm::List(ts), v) | (v, IRTerm::List(ts)) => {
+                            let list = IRTerm::List(ts);
+                            if occurs_check && list.variables(true).contains(&v) {
+                                return Err(OccursCheckFailure { variable: v, list });
+                            }
                             let mut upd = Substitution::<IRTerm>::new();
-                            upd.insert(v.clone(), IRTerm::List(ts));
+                            upd.insert(v.clone(), list);
                             s = compose_extend(&s, &upd);
                         }
 
@@ -224,13 +250,13 @@ impl Literal<IRTerm> {
                     }
                 }
             }
-            Some(s)
+            Ok(Some(s))
         }
 
         if self.signature() != other.signature() {
-            return None;
+            return Ok(None);
         }
-        unify_arglist(&self.args, &other.args)
+        unify_arglist(&self.args, &other.args, occurs_check)
     }
 }
 
@@ -240,6 +266,25 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn compose_extend_prefers_right() {
+        let var = |v: &str| IRTerm::UserVariable(v.into());
+        let constant = |c: &str| IRTerm::Constant(c.into());
+        let l: Substitution = [(var("X"), var("Y")), (var("Z"), var("Y"))]
+            .iter()
+            .cloned()
+            .collect();
+        let r: Substitution = [(var("X"), constant("a")), (var("Y"), constant("b"))]
+            .iter()
+            .cloned()
+            .collect();
+        let composed = compose_extend(&l, &r);
+        assert_eq!(composed.get(&var("X")), Some(&constant("a")));
+        assert_eq!(composed.get(&var("Y")), Some(&constant("b")));
+        assert_eq!(composed.get(&var("Z")), Some(&constant("b")));
+        assert_eq!(composed.len(), 3);
+    }
+
     #[test]
     fn simple_unifier() {
         let l: logic::Literal = "a(X, \"c\")".parse().unwrap();
@@ -297,6 +342,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn list_list_unifier() {
+        let l: logic::Literal = "p([\"a\", X], X)".parse().unwrap();
+        let m: logic::Literal = "p([Y, \"b\"], Z)".parse().unwrap();
+        let mgu = l.unify(&m).unwrap();
+        assert!(l.substitute(&mgu).eq_ignoring_position(&m.substitute(&mgu)));
+        assert_eq!(
+            mgu.get(&logic::IRTerm::UserVariable("Y".into())),
+            Some(&logic::IRTerm::Constant("a".into()))
+        );
+
+        let n: logic::Literal = "p([\"a\"], \"b\")".parse().unwrap();
+        assert!(l.unify(&n).is_none());
+    }
+
+    #[test]
+    fn occurs_check() {
+        let l: logic::Literal = "p(X, X)".parse().unwrap();
+        let m: logic::Literal = "p(Y, [\"a\", Y])".parse().unwrap();
+        assert!(l.unify(&m).is_some());
+
+        let failure = l.unify_with_occurs_check(&m).unwrap_err();
+        assert_eq!(
+            failure.list.variables(true),
+            vec![failure.variable.clone()].into_iter().collect()
+        );
+
+        let n: logic::Literal = "p(Y, [\"a\", Z])".parse().unwrap();
+        assert!(l.unify_with_occurs_check(&n).unwrap().is_some());
+    }
+
+    #[test]
+    fn composition() {
+        let x = IRTerm::UserVariable("X".into());
+        let y = IRTerm::UserVariable("Y".into());
+        let a = IRTerm::Constant("a".into());
+        let l: Substitution = vec![(x.clone(), y.clone())].into_iter().collect();
+        let r: Substitution = vec![(y.clone(), a.clone())].into_iter().collect();
+
+        let composed = compose_extend(&l, &r);
+        assert_eq!(composed.get(&x), Some(&a));
+        assert_eq!(composed.get(&y), Some(&a));
+        assert_eq!(compose_no_extend(&l, &r).get(&y), None);
+        // the inputs are left untouched
+        assert_eq!(l.get(&x), Some(&y));
+        assert_eq!(compose_extend(&Substitution::new(), &r), r);
+    }
+
     #[test]
     fn simple_non_unifiable() {
         let l: logic::Literal = "a(X, \"b\")".parse().unwrap();
@@ -16,12 +16,14 @@
 
 use std::collections::{HashMap, HashSet};
 
-use crate::logic::{Clause, IRTerm, Signature};
+use petgraph::algo::{find_negative_cycle, tarjan_scc};
+
+use crate::logic::{Clause, IRTerm, Literal, Predicate, Signature};
 
 /// infer image predicates, i.e. those that transitively depend on image/1
 /// check that image predicates depend on image/1 in each disjunct
 pub fn check_image_predicates(
-    clauses: &Vec<Clause<IRTerm>>,
+    _clauses: &Vec<Clause<IRTerm>>,
 ) -> Result<HashSet<Signature>, HashSet<Signature>> {
     todo!()
 }
@@ -45,7 +47,7 @@ fn combine_groundness(g1: &[bool], g2: &[bool]) -> Vec<bool> {
 pub fn check_grounded_variables(
     clauses: &[Clause<IRTerm>],
 ) -> Result<HashMap<Signature, Vec<bool>>, HashSet<Signature>> {
-    let mut errors: HashSet<Signature> = HashSet::new();
+    let errors: HashSet<Signature> = HashSet::new();
     let mut result: HashMap<Signature, Vec<bool>> = HashMap::new();
 
     fn infer(c: &Clause<IRTerm>) -> Vec<bool> {
@@ -74,14 +76,14 @@ pub fn check_grounded_variables(
             .collect()
     }
 
-    let signatures: HashSet<Signature> = clauses.iter().map(|c| c.head.signature()).collect();
+    let _signatures: HashSet<Signature> = clauses.iter().map(|c| c.head.signature()).collect();
 
     for c in clauses {
         let sig = c.head.signature();
         let grounded = infer(c);
 
         let new_groundness = if let Some(prev_groundness) = result.get(&sig) {
-            combine_groundness(&prev_groundness, &grounded)
+            combine_groundness(prev_groundness, &grounded)
         } else {
             grounded
         };
@@ -95,6 +97,105 @@ pub fn check_grounded_variables(
     }
 }
 
+/// Checks that the program is stratified, i.e. that no predicate depends on itself through a
+/// negated literal, so that negation-as-failure gives sound results.
+/// Returns the predicates of a cycle through a negation otherwise.
+pub fn check_stratification(clauses: &[Clause<IRTerm>]) -> Result<(), Vec<Predicate>> {
+    fn index(
+        g: &mut petgraph::Graph<Predicate, f32>,
+        indices: &mut HashMap<Predicate, petgraph::graph::NodeIndex>,
+        p: Predicate,
+    ) -> petgraph::graph::NodeIndex {
+        *indices.entry(p.clone()).or_insert_with(|| g.add_node(p))
+    }
+
+    let mut g = petgraph::Graph::<Predicate, f32>::new();
+    let mut indices = HashMap::new();
+    for c in clauses {
+        let head = index(&mut g, &mut indices, c.head.predicate.clone());
+        for lit in &c.body {
+            let dependency = index(&mut g, &mut indices, lit.predicate.clone());
+            // A negative weight for negated literals allows us to find a cycle with a
+            // negative edge using an algorithm for negative cycles.
+            g.add_edge(head, dependency, if lit.positive { 0.0 } else { -1.0 });
+            // All the solutions of the goal of a findall must be known, as for a negation.
+            if let Some((_, _, goal)) = lit.findall_parts() {
+                let dependency = index(&mut g, &mut indices, goal.predicate);
+                g.add_edge(head, dependency, -1.0);
+            }
+        }
+    }
+
+    for id in g.node_indices() {
+        if let Some(ids) = find_negative_cycle(&g, id) {
+            return Err(ids.into_iter().map(|id| g[id].clone()).collect());
+        }
+    }
+    Ok(())
+}
+
+/// A reason why resolving some literals obviously never terminates, see
+/// [`check_termination`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum NonTermination {
+    /// A literal of a clause body that has the same predicate and arguments as the head, so
+    /// resolving it with the clause gives the same goal again.
+    SameArguments(Literal),
+    /// Mutually recursive predicates, each of whose clauses depends on one of them, so that
+    /// none has a base case to end the recursion.
+    NoBaseCase(Vec<Signature>),
+}
+
+/// Finds obviously non-terminating recursion: clauses that call themselves with the same
+/// arguments, and recursive predicates without a base case.
+pub fn check_termination(clauses: &[Clause<IRTerm>]) -> Vec<NonTermination> {
+    let mut found = clauses
+        .iter()
+        .flat_map(|c| {
+            c.body
+                .iter()
+                .filter(move |l| {
+                    l.positive && l.predicate == c.head.predicate && l.args == c.head.args
+                })
+                .map(|l| NonTermination::SameArguments(l.clone()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut g = petgraph::Graph::<Signature, ()>::new();
+    let mut indices = HashMap::new();
+    let mut index = |g: &mut petgraph::Graph<Signature, ()>, s: Signature| {
+        *indices.entry(s.clone()).or_insert_with(|| g.add_node(s))
+    };
+    for c in clauses {
+        let head = index(&mut g, c.head.signature());
+        for lit in c.body.iter().filter(|l| l.positive) {
+            let dependency = index(&mut g, lit.signature());
+            g.update_edge(head, dependency, ());
+        }
+    }
+    for component in tarjan_scc(&g) {
+        let signatures = component
+            .iter()
+            .map(|&id| g[id].clone())
+            .collect::<HashSet<_>>();
+        let is_recursive = component.len() > 1 || g.contains_edge(component[0], component[0]);
+        let has_base_case = clauses
+            .iter()
+            .filter(|c| signatures.contains(&c.head.signature()))
+            .any(|c| {
+                c.body
+                    .iter()
+                    .all(|l| !l.positive || !signatures.contains(&l.signature()))
+            });
+        if is_recursive && !has_base_case {
+            let mut signatures = signatures.into_iter().collect::<Vec<_>>();
+            signatures.sort_by_key(|s| s.to_string());
+            found.push(NonTermination::NoBaseCase(signatures));
+        }
+    }
+    found
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,4 +239,68 @@ mod tests {
         let foo_grounded = result.unwrap().get(&foo_sig).unwrap().clone();
         assert!(!foo_grounded[0]);
     }
+
+    #[test]
+    fn stratification() {
+        let clauses: Vec<Clause> = vec![
+            "a(X) :- b(X), !c(X).".parse().unwrap(),
+            "c(X) :- d(X).".parse().unwrap(),
+            "d(X) :- c(X).".parse().unwrap(),
+        ];
+        assert!(check_stratification(&clauses).is_ok());
+
+        let clauses: Vec<Clause> = vec![
+            "a(X) :- b(X), !c(X).".parse().unwrap(),
+            "c(X) :- d(X).".parse().unwrap(),
+            "d(X) :- a(X).".parse().unwrap(),
+        ];
+        let cycle = check_stratification(&clauses).unwrap_err();
+        assert_eq!(3, cycle.len());
+        assert!(cycle.contains(&Predicate("a".into())));
+    }
+
+    #[test]
+    fn termination() {
+        let clauses: Vec<Clause> = vec![
+            "nat(\"0\").".parse().unwrap(),
+            "nat(X) :- nat(Y), number_gt(X, Y).".parse().unwrap(),
+            "loop(X, Y) :- nat(Y), loop(X, Y).".parse().unwrap(),
+            "loop(X, Y) :- nat(X).".parse().unwrap(),
+            "even(X) :- odd(Y), number_gt(X, Y).".parse().unwrap(),
+            "odd(X) :- even(Y), number_gt(X, Y).".parse().unwrap(),
+        ];
+        let found = check_termination(&clauses);
+        assert_eq!(found.len(), 2);
+        assert!(
+            matches!(&found[0], NonTermination::SameArguments(l) if l.to_string() == "loop(X, Y)")
+        );
+        assert_eq!(
+            found[1],
+            NonTermination::NoBaseCase(vec![
+                Signature(Predicate("even".into()), 1),
+                Signature(Predicate("odd".into()), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn stratification_through_findall() {
+        let modus_clauses: Vec<modusfile::ModusClause> = vec![
+            "p(L) :- findall(X, q(X), L).".parse().unwrap(),
+            "q(X) :- p(X).".parse().unwrap(),
+        ];
+        let clauses: Vec<Clause> = modus_clauses.iter().flat_map(Vec::<Clause>::from).collect();
+        let cycle = check_stratification(&clauses).unwrap_err();
+        assert!(cycle.contains(&Predicate("q".into())));
+    }
+
+    #[test]
+    fn stratification_after_translation() {
+        let modus_clauses: Vec<modusfile::ModusClause> =
+            vec!["p :- !(q, r).".parse().unwrap(), "q :- p.".parse().unwrap()];
+        let clauses: Vec<Clause> = modus_clauses.iter().flat_map(Vec::<Clause>::from).collect();
+        let cycle = check_stratification(&clauses).unwrap_err();
+        assert!(cycle.iter().any(Predicate::is_negation));
+        assert!(cycle.contains(&Predicate("p".into())));
+    }
 }
@@ -42,7 +42,7 @@ fn try_get_sha() -> Result<String, String> {
                 .stdin(Stdio::null())
                 .stderr(Stdio::inherit())
                 .stdout(Stdio::piped())
-                .args(&["rev-parse", "HEAD"])
+                .args(["rev-parse", "HEAD"])
                 .output()
                 .map_err(|e| format!("Unable to run git: {}", e))?;
             if !cmd.status.success() {
@@ -53,9 +53,7 @@ fn try_get_sha() -> Result<String, String> {
                 .trim();
             Ok(sha.to_owned())
         }
-        Err(e) => {
-            return Err(format!("Failed to read .cargo_vcs_info.json: {}", e));
-        }
+        Err(e) => Err(format!("Failed to read .cargo_vcs_info.json: {}", e)),
     }
 }
 
@@ -0,0 +1,224 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Finds what changed in the git repository of the build context since a revision, for
+//! the `changed_since(Rev, Path)` builtin and `modus build --affected-only`.
+//!
+//! Paths are relative to the build context, as in `copy`. Changes that are not committed
+//! yet, including new files that git does not ignore, count as changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+use colored::Colorize;
+use modus_lib::imagegen::{BuildNode, BuildPlan, MergeOperation, Output};
+use serde_json::json;
+
+use crate::logging;
+
+/// The build context, whose repository changes are looked for in.
+static CONTEXT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the build context that paths are relative to. The current directory is used
+/// if none is set.
+pub fn set_context_dir(dir: &Path) {
+    let _ = CONTEXT.set(dir.to_path_buf());
+}
+
+fn git(args: &[&str]) -> Result<std::process::Output, String> {
+    let mut cmd = Command::new("git");
+    if let Some(dir) = CONTEXT.get() {
+        cmd.arg("-C").arg(dir);
+    }
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Unable to run git: {}", e))
+}
+
+/// Whether anything under the path changed since the revision, e.g. `origin/main`.
+pub fn changed_since(rev: &str, path: &str) -> Result<bool, String> {
+    let diff = git(&["diff", "--quiet", rev, "--", path])?;
+    match diff.status.code() {
+        Some(0) => {}
+        Some(1) => return Ok(true),
+        _ => {
+            return Err(format!(
+                "git diff {} failed: {}",
+                rev,
+                String::from_utf8_lossy(&diff.stderr).trim()
+            ))
+        }
+    }
+    let untracked = git(&["ls-files", "--others", "--exclude-standard", "--", path])?;
+    if !untracked.status.success() {
+        return Err(format!(
+            "git ls-files failed: {}",
+            String::from_utf8_lossy(&untracked.stderr).trim()
+        ));
+    }
+    Ok(!untracked.stdout.is_empty())
+}
+
+/// [`changed_since`] for the builtin, which is asked about the same paths once per proof
+/// that uses them, so the answers are remembered. Failures are reported once, and the
+/// builtin then counts the path as changed.
+/// See [`modus_lib::builtin::ChangeDetector`].
+pub fn detect(rev: &str, path: &str) -> Result<bool, String> {
+    type Answers = HashMap<(String, String), Result<bool, String>>;
+    static ANSWERS: OnceLock<Mutex<Answers>> = OnceLock::new();
+    let mut answers = ANSWERS.get_or_init(Default::default).lock().unwrap();
+    answers
+        .entry((rev.to_owned(), path.to_owned()))
+        .or_insert_with(|| {
+            let answer = changed_since(rev, path);
+            if let Err(e) = &answer {
+                logging::progress(
+                    "changed_since_failed",
+                    json!({ "revision": rev, "path": path, "message": e }),
+                    || {
+                        format!(
+                            "{} {}, so {} counts as changed.",
+                            "Warning:".yellow(),
+                            e,
+                            path
+                        )
+                    },
+                );
+            }
+            answer
+        })
+        .clone()
+}
+
+/// The local paths that the output copies from the build context, including the files
+/// of the local images that it starts from.
+pub fn copied_paths(plan: &BuildPlan, output: &Output) -> Vec<String> {
+    let output_plan = BuildPlan {
+        outputs: vec![output.clone()],
+        ..plan.clone()
+    };
+    let mut paths = output_plan
+        .topological_order()
+        .into_iter()
+        .flat_map(|node| match &plan.nodes[node] {
+            BuildNode::CopyFromLocal { src_path, .. }
+            | BuildNode::FromOciLayout { path: src_path, .. }
+            | BuildNode::FromDockerTar { path: src_path, .. } => vec![src_path.clone()],
+            BuildNode::Merge(merge) => merge
+                .operations
+                .iter()
+                .filter_map(|op| match op {
+                    MergeOperation::CopyFromLocal { src_path, .. } => Some(src_path.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    paths.sort_unstable();
+    paths.dedup();
+    paths
+}
+
+/// Returns the plan without the outputs that are not affected by a change, i.e. whose
+/// copied paths did not change and neither did any of `project_files`, the files that
+/// every output depends on such as the Modusfile. Includes and dependencies are pinned
+/// by their digest in these files, so changing them changes the files. Also returns the
+/// outputs that were left out.
+pub fn affected_outputs<F>(
+    plan: &BuildPlan,
+    project_files: &[String],
+    mut changed: F,
+) -> Result<(BuildPlan, Vec<Output>), String>
+where
+    F: FnMut(&str) -> Result<bool, String>,
+{
+    for file in project_files {
+        if changed(file)? {
+            return Ok((plan.clone(), Vec::new()));
+        }
+    }
+    let mut affected = Vec::new();
+    let mut unaffected = Vec::new();
+    for output in plan.outputs.iter() {
+        let mut is_affected = false;
+        for path in copied_paths(plan, output) {
+            if changed(&path)? {
+                is_affected = true;
+                break;
+            }
+        }
+        if is_affected {
+            affected.push(output.clone());
+        } else {
+            unaffected.push(output.clone());
+        }
+    }
+    let plan = BuildPlan {
+        outputs: affected,
+        ..plan.clone()
+    }
+    .without_unreachable_nodes();
+    Ok((plan, unaffected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn affected_outputs_follow_copies() {
+        let plan = testing::plan(
+            "base :- from(\"alpine\"), copy(\"common\", \"/common\").\n\
+             app(\"a\") :- base, copy(\"a/src\", \"/src\").\n\
+             app(\"b\") :- base, (copy(\"b/src\", \"/src\"), run(\"make\"))::merge.\n\
+             app(\"c\") :- from(\"alpine\").",
+            "app(X)",
+        );
+        let name = |o: &Output| o.source_literal.as_ref().unwrap().to_string();
+        let b = plan
+            .outputs
+            .iter()
+            .find(|o| name(o) == "app(\"b\")")
+            .unwrap();
+        assert_eq!(copied_paths(&plan, b), vec!["b/src", "common"]);
+
+        let affected = |changes: &[&str]| {
+            let (plan, _) = affected_outputs(&plan, &["Modusfile".to_owned()], |path| {
+                Ok(changes.contains(&path))
+            })
+            .unwrap();
+            let mut names = plan.outputs.iter().map(name).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert!(affected(&[]).is_empty());
+        assert_eq!(affected(&["a/src"]), vec!["app(\"a\")"]);
+        assert_eq!(affected(&["common"]), vec!["app(\"a\")", "app(\"b\")"]);
+        assert_eq!(affected(&["Modusfile"]).len(), 3);
+
+        let (plan, unaffected) = affected_outputs(&plan, &[], |path| Ok(path == "a/src")).unwrap();
+        assert_eq!(unaffected.len(), 2);
+        assert!(plan.nodes.iter().all(|n| !matches!(n, BuildNode::Merge(_))));
+        assert!(affected_outputs(&plan, &[], |_| Err("no git".to_owned())).is_err());
+    }
+}
@@ -28,25 +28,25 @@
 //! the following things will happen in order:
 //!
 //! 1. The outer modus program generates all required proofs and build plan for
-//! the user's query, then encodes the build plan to json.
+//!    the user's query, then encodes the build plan to json.
 //!
 //! 2. The outer program writes the json along with a special declaration line
-//! `#syntax=...` to a temporary Dockerfile, and invokes `docker build`. The
-//! `#syntax` declaration tells Docker to use our frontend instead of treating
-//! it as a normal Dockerfile.
+//!    `#syntax=...` to a temporary Dockerfile, and invokes `docker build`. The
+//!    `#syntax` declaration tells Docker to use our frontend instead of treating
+//!    it as a normal Dockerfile.
 //!
 //! 3. Our frontend (which runs inside a separate container created by docker
-//! build) turns the json back into the build plan and generate all the required
-//! buildkit calls to build all output images. If there are more than one output
-//! images, it also generate a final empty image which depeneds on all the
-//! output images user wants. This will effectively force buildkit to build
-//! multiple output images in parallel, if that's what the user wants.
+//!    build) turns the json back into the build plan and generate all the required
+//!    buildkit calls to build all output images. If there are more than one output
+//!    images, it also generate a final empty image which depeneds on all the
+//!    output images user wants. This will effectively force buildkit to build
+//!    multiple output images in parallel, if that's what the user wants.
 //!
 //! 4. If there are multiple output images, invoke docker build again for each
-//! output image, and instead of building the empty image, just build the
-//! required output image, and also ignore all "no_cache". This should be
-//! instant since everything is cached. We can also tell docker build to tag the
-//! image with a name so that it can be referenced by the user.
+//!    output image, and instead of building the empty image, just build the
+//!    required output image, and also ignore all "no_cache". This should be
+//!    instant since everything is cached. We can also tell docker build to tag the
+//!    image with a name so that it can be referenced by the user.
 //!
 //! The easiest way to create this inner frontend is to build a separate binary.
 //! Check out `buildkit_frontend.rs` for the main function of this inner
@@ -55,9 +55,10 @@
 // TODO: check isatty before printing \x1b
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryInto,
     fs::OpenOptions,
+    io::IsTerminal,
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
     time::Instant,
@@ -65,13 +66,15 @@ use std::{
 
 use spawn_wait::{ProcessSet, SignalHandler};
 
-use modus_lib::imagegen::{BuildNode, BuildPlan, Output};
+use modus_lib::imagegen::{BuildNode, BuildPlan, NodeId, Output};
+use modus_lib::ModusError;
 
 use colored::Colorize;
 use rand::{
     distributions::{Distribution, Uniform},
     Rng,
 };
+use serde_json::json;
 use std::io::Write;
 
 use thiserror::Error;
@@ -91,10 +94,13 @@ pub enum BuildError {
     UnableToCreateTempFile(#[source] std::io::Error),
     #[error("Unable to run docker build: {0}")]
     UnableToRunDockerBuild(#[source] spawn_wait::Error),
+    /// docker build failed, at the node if it is known.
     #[error("docker build exited with code {0}.")]
-    DockerBuildFailed(ExitStatus),
+    DockerBuildFailed(ExitStatus, Option<NodeId>),
     #[error("docker tag {0} {1} exited with code {2}.")]
     DockerTagFailed(String, String, ExitStatus),
+    #[error("docker push {0} exited with code {1}.")]
+    DockerPushFailed(String, ExitStatus),
     #[error("{0} contains invalid utf-8.")]
     FileHasInvalidUtf8(String),
     #[error("Unable to create temporary directory: {0}")]
@@ -105,18 +111,41 @@ pub enum BuildError {
     UnableToReadTmpFile(String, #[source] std::io::Error),
     #[error("Could not resolve {0}: docker build returned {1}")]
     CouldNotResolveImage(String, ExitStatus),
+    #[error("Unable to run {0}: {1}")]
+    UnableToRunVerifier(&'static str, #[source] std::io::Error),
+    #[error("The signature of {0} could not be verified: {1} exited with code {2}.")]
+    ImageSignatureInvalid(String, &'static str, ExitStatus),
+    #[error("Unable to run git: {0}")]
+    UnableToRunGit(#[source] std::io::Error),
+    #[error("git clone {0} exited with code {1}.")]
+    GitCloneFailed(String, ExitStatus),
     #[error("{0}")]
     IOError(
         #[from]
         #[source]
         std::io::Error,
     ),
+    #[error("Unable to load {0}: {1}")]
+    LoadImageFailed(String, String),
     #[error("Interrupted by user.")]
     Interrupted,
 }
 
+impl From<BuildError> for ModusError {
+    fn from(e: BuildError) -> Self {
+        match e {
+            CouldNotResolveImage(..) | ImageSignatureInvalid(..) | DockerPushFailed(..) => {
+                ModusError::Registry(Box::new(e))
+            }
+            Interrupted => ModusError::Cancelled,
+            e => ModusError::Build(Box::new(e)),
+        }
+    }
+}
+
 use BuildError::*;
 
+use crate::logging;
 use crate::reporting::Profiling;
 
 #[derive(Debug, Clone, Default)]
@@ -124,6 +153,15 @@ pub struct DockerBuildOptions {
     pub verbose: bool,
     pub quiet: bool,
     pub no_cache: bool,
+    /// Passed to `docker build --platform`, which sets the target platform of our frontend.
+    pub platform: Option<String>,
+    /// Named contexts in the form `name=value`, passed to `docker build --build-context`.
+    pub build_contexts: Vec<String>,
+    /// Passed as `docker build --load`, which exports the images into the local image
+    /// store even if the builder does not by default, e.g. with the docker-container driver.
+    pub load: bool,
+    /// The sources of the secrets mounted with `::secret`, passed to `docker build --secret`.
+    pub secrets: Vec<SecretSource>,
     pub additional_args: Vec<String>,
 }
 
@@ -133,22 +171,203 @@ pub struct BuildOptions {
     pub resolve_concurrency: u32,
     pub export_concurrency: u32,
     pub docker_build_options: DockerBuildOptions,
+    /// If set, every base image must be signed before it is resolved.
+    pub signature_policy: Option<SignaturePolicy>,
+}
+
+/// Where BuildKit reads a secret from. Written as `id=<ID>,src=<FILE>` or `id=<ID>,env=<VAR>`,
+/// as for `docker build --secret`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretSource {
+    pub id: String,
+    pub value: SecretValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretValue {
+    File(PathBuf),
+    Env(String),
+}
+
+impl std::str::FromStr for SecretSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut id = None;
+        let mut value = None;
+        for field in s.split(',') {
+            match field.split_once('=') {
+                Some(("id", v)) if !v.is_empty() => id = Some(v.to_owned()),
+                Some(("src" | "source", v)) if !v.is_empty() && value.is_none() => {
+                    value = Some(SecretValue::File(PathBuf::from(v)))
+                }
+                Some(("env", v)) if !v.is_empty() && value.is_none() => {
+                    value = Some(SecretValue::Env(v.to_owned()))
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid secret {:?}, expected id=<ID>,src=<FILE> or id=<ID>,env=<VAR>",
+                        s
+                    ))
+                }
+            }
+        }
+        match (id, value) {
+            (Some(id), Some(value)) => Ok(SecretSource { id, value }),
+            (None, _) => Err(format!("the secret {:?} has no id", s)),
+            (Some(id), None) => Err(format!(
+                "the secret {} has no source, add src=<FILE> or env=<VAR>",
+                id
+            )),
+        }
+    }
+}
+
+impl SecretSource {
+    /// Checks that the secret can be read, and makes its file absolute, as the build runs
+    /// in the context directory.
+    pub fn resolve(self) -> Result<SecretSource, String> {
+        let SecretSource { id, value } = self;
+        let value = match value {
+            SecretValue::File(path) => {
+                SecretValue::File(std::fs::canonicalize(&path).map_err(|e| {
+                    format!(
+                        "Unable to read the secret {} from {}: {}",
+                        id,
+                        path.display(),
+                        e
+                    )
+                })?)
+            }
+            SecretValue::Env(var) if std::env::var_os(&var).is_none() => {
+                return Err(format!(
+                    "The secret {} is read from ${}, which is not set.",
+                    id, var
+                ))
+            }
+            value => value,
+        };
+        Ok(SecretSource { id, value })
+    }
+
+    fn arg(&self) -> String {
+        match &self.value {
+            SecretValue::File(path) => format!("id={},src={}", self.id, path.display()),
+            SecretValue::Env(var) => format!("id={},env={}", self.id, var),
+        }
+    }
+}
+
+/// The signature that is required of base images, checked with cosign or notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Signed with the given cosign key, a path or a KMS URI. Written as `cosign=<key>`.
+    CosignKey(String),
+    /// Signed keylessly by the given identity, as issued by the given OIDC
+    /// issuer. Written as `cosign-identity=<identity>@<issuer>`.
+    CosignIdentity { identity: String, issuer: String },
+    /// Trusted according to the trust policy configured for notation. Written as `notation`.
+    Notation,
+}
+
+impl std::str::FromStr for SignaturePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("cosign", key)) if !key.is_empty() => Ok(SignaturePolicy::CosignKey(key.to_owned())),
+            Some(("cosign-identity", identity_issuer)) => match identity_issuer.rsplit_once('@') {
+                Some((identity, issuer)) if !identity.is_empty() && !issuer.is_empty() => {
+                    Ok(SignaturePolicy::CosignIdentity {
+                        identity: identity.to_owned(),
+                        issuer: issuer.to_owned(),
+                    })
+                }
+                _ => Err("expected cosign-identity=<identity>@<issuer>".to_owned()),
+            },
+            None if s == "notation" => Ok(SignaturePolicy::Notation),
+            _ => Err(format!(
+                "unknown signature policy {:?}, expected cosign=<key>, cosign-identity=<identity>@<issuer> or notation",
+                s
+            )),
+        }
+    }
+}
+
+impl SignaturePolicy {
+    fn program(&self) -> &'static str {
+        match self {
+            SignaturePolicy::CosignKey(_) | SignaturePolicy::CosignIdentity { .. } => "cosign",
+            SignaturePolicy::Notation => "notation",
+        }
+    }
+
+    fn verify_args(&self, image_ref: &str) -> Vec<String> {
+        let mut args = vec!["verify".to_string()];
+        match self {
+            SignaturePolicy::CosignKey(key) => {
+                args.extend(["--key".to_string(), key.to_owned()]);
+            }
+            SignaturePolicy::CosignIdentity { identity, issuer } => {
+                args.extend([
+                    "--certificate-identity".to_string(),
+                    identity.to_owned(),
+                    "--certificate-oidc-issuer".to_string(),
+                    issuer.to_owned(),
+                ]);
+            }
+            SignaturePolicy::Notation => {}
+        }
+        args.push(image_ref.to_owned());
+        args
+    }
+
+    /// Checks the signature of the image in its registry, before it is pulled.
+    fn verify(&self, image_ref: &str) -> Result<(), BuildError> {
+        let program = self.program();
+        let status = logging::status(
+            Command::new(program)
+                .args(self.verify_args(image_ref))
+                .stdin(Stdio::null())
+                .stdout(Stdio::null()),
+            program,
+        )
+        .map_err(|e| UnableToRunVerifier(program, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(ImageSignatureInvalid(image_ref.to_owned(), program, status))
+        }
+    }
+}
+
+/// The outputs of a build plan that our frontend builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuildTarget {
+    /// The only output of the plan.
+    Only,
+    /// Every output of a plan with several, so that building each one afterwards only
+    /// exports it. The image of the first one is returned.
+    All,
+    /// The output at the index, as with `--target`.
+    Output(usize),
 }
 
 fn make_buildkit_command(
     dockerfile: &str,
     tag: Option<String>,
-    target: Option<String>,
+    target: BuildTarget,
     has_dockerignore: bool,
     iidfile: Option<&str>,
     options: &DockerBuildOptions,
     cwd: Option<&Path>,
 ) -> Command {
-    let mut args = Vec::new();
-    args.push("build".to_string());
-    args.push(".".to_string());
-    args.push("-f".to_string());
-    args.push(dockerfile.to_owned());
+    let mut args = vec![
+        "build".to_string(),
+        ".".to_string(),
+        "-f".to_string(),
+        dockerfile.to_owned(),
+    ];
     if let Some(tag) = tag {
         args.push("-t".to_string());
         args.push(tag);
@@ -163,13 +382,32 @@ fn make_buildkit_command(
         args.push("--build-arg".to_string());
         args.push("no_cache=false".to_string());
     }
-    if let Some(target) = target {
-        args.push("--target".to_string());
-        args.push(target);
+    match target {
+        BuildTarget::Only => {}
+        BuildTarget::All => {
+            args.push("--build-arg".to_string());
+            args.push("all_outputs=true".to_string());
+        }
+        BuildTarget::Output(i) => {
+            args.push("--target".to_string());
+            args.push(i.to_string());
+        }
     }
     if options.quiet {
         args.push("--quiet".to_string());
     }
+    if let Some(platform) = &options.platform {
+        args.push("--platform".to_string());
+        args.push(platform.to_owned());
+    }
+    for build_context in options.build_contexts.iter() {
+        args.push("--build-context".to_string());
+        args.push(build_context.to_owned());
+    }
+    for secret in options.secrets.iter() {
+        args.push("--secret".to_string());
+        args.push(secret.arg());
+    }
     args.push("--build-arg".to_string());
     if has_dockerignore {
         args.push("has_dockerignore=true".to_string());
@@ -183,6 +421,9 @@ fn make_buildkit_command(
     if options.verbose {
         args.push("--progress=plain".to_string());
     }
+    if options.load {
+        args.push("--load".to_string());
+    }
     args.extend_from_slice(&options.additional_args);
     let mut cmd = Command::new("docker");
     cmd.args(args);
@@ -200,11 +441,106 @@ fn make_buildkit_command(
     cmd
 }
 
+/// The name of the buildkit vertex that builds a node, e.g. `[n_3] run("make")`. It starts
+/// with the node so that a failure reported by docker build can be traced back to the
+/// Modusfile.
+#[allow(dead_code)] // only used by the frontend binary
+pub fn vertex_name(node: NodeId, description: &str) -> String {
+    format!("[n_{}] {}", node, description)
+}
+
+/// The node of a vertex named with [`vertex_name`].
+fn vertex_node(name: &str) -> Option<NodeId> {
+    name.strip_prefix("[n_")?.split_once(']')?.0.parse().ok()
+}
+
+/// A node of the build plan that buildkit finished building, as reported in the plain
+/// progress output of docker build by `#8 DONE 1.2s`, or `#7 CACHED` if it was cached.
+#[derive(Debug, Clone, PartialEq)]
+struct FinishedNode {
+    node: NodeId,
+    cached: bool,
+    duration_secs: Option<f64>,
+}
+
+/// Follows the nodes in the plain progress output of docker build. The node that failed
+/// is the first vertex that reports an error, as in `#8 ERROR: ...`, or else the vertex
+/// whose logs are shown in the error summary, as in ` > [n_3] run("make"):`.
+#[derive(Debug, Default)]
+struct NodeProgress {
+    vertices: HashMap<String, NodeId>,
+    failed: Option<NodeId>,
+    summarized: Option<NodeId>,
+}
+
+impl NodeProgress {
+    /// Reads a line of output, returning the node it reports as finished, if any.
+    fn line(&mut self, line: &str) -> Option<FinishedNode> {
+        if let Some((vertex, rest)) = line.strip_prefix('#').and_then(|l| l.split_once(' ')) {
+            if let Some(node) = vertex_node(rest) {
+                self.vertices.insert(vertex.to_owned(), node);
+            } else if rest.starts_with("ERROR") && self.failed.is_none() {
+                self.failed = self.vertices.get(vertex).copied();
+            } else if let Some(duration) = rest.strip_prefix("DONE") {
+                return Some(FinishedNode {
+                    node: *self.vertices.get(vertex)?,
+                    cached: false,
+                    duration_secs: duration.trim().strip_suffix('s')?.parse().ok(),
+                });
+            } else if rest == "CACHED" {
+                return Some(FinishedNode {
+                    node: *self.vertices.get(vertex)?,
+                    cached: true,
+                    duration_secs: None,
+                });
+            }
+        } else if let Some(rest) = line.trim_start().strip_prefix("> ") {
+            if let Some(node) = vertex_node(rest) {
+                self.summarized = Some(node);
+            }
+        }
+        None
+    }
+
+    fn failed_node(&self) -> Option<NodeId> {
+        self.failed.or(self.summarized)
+    }
+}
+
+/// Passes the stderr of the command through to ours with [`logging::output`] while
+/// reporting the nodes that finish, and looking for the node that failed. This is only done when docker build prints its
+/// progress as plain text anyway, since it would not draw its progress on the terminal
+/// otherwise.
+fn watch_node_progress(
+    cmd: &mut Command,
+) -> std::io::Result<std::thread::JoinHandle<Option<NodeId>>> {
+    let (reader, writer) = std::io::pipe()?;
+    cmd.stderr(writer);
+    Ok(std::thread::spawn(move || {
+        let mut progress = NodeProgress::default();
+        logging::for_each_line(reader, |line| {
+            logging::output("docker build", line);
+            if let Some(finished) = progress.line(String::from_utf8_lossy(line).trim_end()) {
+                logging::event(
+                    "node_finished",
+                    json!({
+                        "node": finished.node,
+                        "cached": finished.cached,
+                        "duration_secs": finished.duration_secs,
+                    }),
+                );
+            }
+        });
+        progress.failed_node()
+    }))
+}
+
 /// A holder for a file name that deletes the file when dropped.
 struct AutoDeleteTmpFilename(String);
 /// A holder for a directory in std::env::temp_dir() that deletes the directory when dropped.
-struct AutoRmTmpDir(PathBuf);
+pub(crate) struct AutoRmTmpDir(PathBuf);
 pub const TMP_PREFIX: &str = "modus_temp_";
+#[allow(dead_code)] // only used by the frontend binary
 pub const TMP_PREFIX_IGNORE_PATTERN: &str = "modus_temp_*";
 
 pub fn gen_tmp_filename() -> String {
@@ -252,13 +588,13 @@ impl Drop for AutoDeleteTmpFilename {
 }
 
 impl AutoRmTmpDir {
-    fn new_empty() -> std::io::Result<Self> {
+    pub(crate) fn new_empty() -> std::io::Result<Self> {
         let mut name = std::env::temp_dir();
-        name.push(&gen_tmp_filename());
+        name.push(gen_tmp_filename());
         std::fs::create_dir(&name)?;
         Ok(Self(name))
     }
-    fn path(&self) -> &Path {
+    pub(crate) fn path(&self) -> &Path {
         &self.0
     }
 }
@@ -277,6 +613,66 @@ impl Drop for AutoRmTmpDir {
     }
 }
 
+/// A build context in a remote git repository, given as `<url>#<ref>`, e.g.
+/// `https://github.com/org/repo.git#branch`. The reference is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitContext {
+    pub url: String,
+    pub reference: Option<String>,
+}
+
+impl GitContext {
+    /// Returns None if `context` is not a git URL, in which case it should be a local directory.
+    pub fn parse(context: &str) -> Option<GitContext> {
+        let (url, reference) = match context.split_once('#') {
+            Some((url, reference)) => (url, Some(reference).filter(|r| !r.is_empty())),
+            None => (context, None),
+        };
+        let is_git = url.starts_with("git://")
+            || url.starts_with("git@")
+            || ((url.starts_with("https://") || url.starts_with("http://"))
+                && url.ends_with(".git"));
+        if is_git {
+            Some(GitContext {
+                url: url.to_owned(),
+                reference: reference.map(ToOwned::to_owned),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// A shallow clone of a [`GitContext`], which is deleted when dropped.
+pub struct ClonedGitContext(AutoRmTmpDir);
+
+impl ClonedGitContext {
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+/// Clone the given reference of a git context, without its history, into a temporary directory.
+pub fn shallow_clone(git_context: &GitContext) -> Result<ClonedGitContext, BuildError> {
+    let dir = AutoRmTmpDir::new_empty().map_err(UnableToCreateTempDir)?;
+    let mut cmd = Command::new("git");
+    cmd.args(["clone", "--quiet", "--depth", "1"]);
+    if let Some(reference) = &git_context.reference {
+        cmd.args(["--branch", reference]);
+    }
+    let status = logging::status(
+        cmd.arg(&git_context.url)
+            .arg(dir.path())
+            .stdin(Stdio::null()),
+        "git",
+    )
+    .map_err(UnableToRunGit)?;
+    if !status.success() {
+        return Err(GitCloneFailed(git_context.url.clone(), status));
+    }
+    Ok(ClonedGitContext(dir))
+}
+
 fn write_tmp_dockerfile(content: &str) -> Result<AutoDeleteTmpFilename, std::io::Error> {
     let tmp_file = AutoDeleteTmpFilename::gen(".Dockerfile");
     let mut f = OpenOptions::new()
@@ -321,6 +717,153 @@ pub fn image_ref_is_hash(s: &str) -> bool {
     len == EXPECTED_LEN
 }
 
+#[test]
+fn test_signature_policy() {
+    let policy: SignaturePolicy = "cosign=cosign.pub".parse().unwrap();
+    assert_eq!(
+        policy.verify_args("alpine"),
+        vec!["verify", "--key", "cosign.pub", "alpine"]
+    );
+    let policy: SignaturePolicy = "cosign-identity=me@example.com@https://accounts.example.com"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        policy,
+        SignaturePolicy::CosignIdentity {
+            identity: "me@example.com".to_owned(),
+            issuer: "https://accounts.example.com".to_owned(),
+        }
+    );
+    assert_eq!("notation".parse(), Ok(SignaturePolicy::Notation));
+    assert!("cosign=".parse::<SignaturePolicy>().is_err());
+    assert!("gpg".parse::<SignaturePolicy>().is_err());
+}
+
+#[test]
+fn test_secret_source() {
+    let secret: SecretSource = "id=pypi_token,src=./token.txt".parse().unwrap();
+    assert_eq!(
+        secret.value,
+        SecretValue::File(PathBuf::from("./token.txt"))
+    );
+    assert!(secret.resolve().is_err());
+    let secret: SecretSource = "id=foo,env=PATH".parse().unwrap();
+    assert_eq!(secret.clone().resolve(), Ok(secret.clone()));
+    assert_eq!(secret.arg(), "id=foo,env=PATH");
+    assert!("src=token.txt".parse::<SecretSource>().is_err());
+    assert!("id=foo".parse::<SecretSource>().is_err());
+    assert!("id=foo,src=a,env=B".parse::<SecretSource>().is_err());
+}
+
+#[test]
+fn test_load() {
+    let args = |load| {
+        make_buildkit_command(
+            "Dockerfile",
+            None,
+            BuildTarget::Only,
+            false,
+            None,
+            &DockerBuildOptions {
+                load,
+                additional_args: vec!["--pull".to_owned()],
+                ..Default::default()
+            },
+            None,
+        )
+        .get_args()
+        .map(|a| a.to_str().unwrap().to_owned())
+        .collect::<Vec<_>>()
+    };
+    assert!(!args(false).contains(&"--load".to_owned()));
+    let loading = args(true);
+    // Additional arguments come last, so that they can override the others.
+    assert_eq!(&loading[loading.len() - 2..], ["--load", "--pull"]);
+}
+
+#[test]
+fn test_failed_node() {
+    let failed_node = |output: &str| {
+        let mut progress = NodeProgress::default();
+        output.lines().for_each(|l| {
+            progress.line(l);
+        });
+        progress.failed_node()
+    };
+    assert_eq!(vertex_node(&vertex_name(12, "run(\"make\")")), Some(12));
+    assert_eq!(
+        failed_node(
+            "#7 [n_2] run(\"apk add make\")\n\
+             #7 CACHED\n\
+             #8 [n_3] run(\"make\")\n\
+             #8 0.215 make: *** No targets specified and no makefile found.  Stop.\n\
+             #8 ERROR: process \"/bin/sh -c make\" did not complete successfully: exit code: 2\n\
+             ------\n \
+             > [n_3] run(\"make\"):\n\
+             ------"
+        ),
+        Some(3)
+    );
+    assert_eq!(
+        failed_node("------\n > [n_5] copy(\"a\", \"b\"):\n------"),
+        Some(5)
+    );
+    assert_eq!(
+        failed_node("#1 [internal] load build definition\n#1 ERROR: not found"),
+        None
+    );
+}
+
+#[test]
+fn test_finished_nodes() {
+    let mut progress = NodeProgress::default();
+    let finished = "#1 [internal] load build definition\n\
+                    #1 DONE 0.0s\n\
+                    #7 [n_2] run(\"apk add make\")\n\
+                    #7 CACHED\n\
+                    #8 [n_3] run(\"make\")\n\
+                    #8 0.215 building\n\
+                    #8 DONE 1.5s"
+        .lines()
+        .filter_map(|l| progress.line(l))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        finished,
+        vec![
+            FinishedNode {
+                node: 2,
+                cached: true,
+                duration_secs: None
+            },
+            FinishedNode {
+                node: 3,
+                cached: false,
+                duration_secs: Some(1.5)
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_git_context() {
+    assert_eq!(
+        GitContext::parse("https://github.com/org/repo.git#branch"),
+        Some(GitContext {
+            url: "https://github.com/org/repo.git".to_owned(),
+            reference: Some("branch".to_owned()),
+        })
+    );
+    assert_eq!(
+        GitContext::parse("git@github.com:org/repo.git"),
+        Some(GitContext {
+            url: "git@github.com:org/repo.git".to_owned(),
+            reference: None,
+        })
+    );
+    assert_eq!(GitContext::parse("."), None);
+    assert_eq!(GitContext::parse("https://example.com/context.tar"), None);
+}
+
 #[test]
 fn test_image_ref_is_hash() {
     assert!(image_ref_is_hash("sha256:a"));
@@ -352,12 +895,14 @@ fn test_image_ref_is_hash() {
     ));
 }
 
+/// Resolves the base images of the plan, and returns the ID that each image reference
+/// was resolved to.
 fn resolve_froms(
     build_plan: &mut BuildPlan,
     build_options: &BuildOptions,
     sh: &mut SignalHandler,
     image_cleanup: &mut DockerImageRmOnDrop,
-) -> Result<(), BuildError> {
+) -> Result<BTreeMap<String, String>, BuildError> {
     #[derive(Debug, Clone, PartialEq, Eq, Hash)]
     enum ImageToResolve {
         Ref(String),
@@ -381,7 +926,17 @@ fn resolve_froms(
         .into_iter()
         .collect::<Vec<_>>();
     if queue.is_empty() {
-        return Ok(());
+        return Ok(BTreeMap::new());
+    }
+    if let Some(policy) = &build_options.signature_policy {
+        for to_resolve in queue.iter() {
+            if let ImageToResolve::Ref(image_ref) = to_resolve {
+                if sh.termination_pending() {
+                    return Err(Interrupted);
+                }
+                policy.verify(image_ref)?;
+            }
+        }
     }
 
     let _ctx = AutoRmTmpDir::new_empty().map_err(BuildError::UnableToCreateTempDir)?;
@@ -401,7 +956,7 @@ fn resolve_froms(
         if sh.termination_pending() {
             return Err(Interrupted);
         }
-        std::fs::create_dir(&ctx).map_err(|e| UnableToCreateTempDir(e))?;
+        std::fs::create_dir(&ctx).map_err(UnableToCreateTempDir)?;
         let dockerfile = ctx.join(gen_tmp_filename());
         let iidfile = ctx.join(gen_tmp_filename());
         /* Note:
@@ -417,7 +972,10 @@ fn resolve_froms(
 
         let content = match to_resolve {
             ImageToResolve::Ref(image_ref) => {
-                let mut tmp_plan = BuildPlan::new();
+                let mut tmp_plan = BuildPlan {
+                    platform: build_plan.platform.clone(),
+                    ..BuildPlan::new()
+                };
                 let out = tmp_plan.new_node(
                     BuildNode::From {
                         image_ref: image_ref.clone(),
@@ -428,6 +986,8 @@ fn resolve_froms(
                 tmp_plan.outputs.push(Output {
                     node: out,
                     source_literal: None,
+                    annotations: Default::default(),
+                    tags: Vec::new(),
                 });
 
                 let mut content = String::new();
@@ -447,19 +1007,21 @@ fn resolve_froms(
         }
         std::fs::write(&dockerfile, content.as_bytes())
             .map_err(|e| BuildError::UnableToWriteTmpFile(dockerfile.display().to_string(), e))?;
-        let cmd = make_buildkit_command(
+        let mut cmd = make_buildkit_command(
             dockerfile.to_str().expect("path to be utf-8"),
             None,
-            None,
+            BuildTarget::Only,
             false,
             Some(iidfile.to_str().expect("path to be utf-8")),
             &DockerBuildOptions {
                 quiet: true,
                 verbose: false,
+                build_contexts: Vec::new(),
                 ..build_options.docker_build_options.clone()
             },
             Some(&ctx),
         );
+        logging::forward_output(&mut cmd, "docker build")?;
         let t = Task {
             to_resolve: to_resolve.clone(),
             iidfile,
@@ -468,11 +1030,14 @@ fn resolve_froms(
     }
 
     let mut nb_done = 0usize;
-    eprintln!(
-        "{}",
-        format!("Resolving {} base images...", queue.len()).blue()
-    );
+    logging::progress("resolve_start", json!({ "images": queue.len() }), || {
+        format!(
+            "{}",
+            format!("Resolving {} base images...", queue.len()).blue()
+        )
+    });
     let mut orig_to_resolved_tag = HashMap::with_capacity(queue.len());
+    let mut resolved_ids = BTreeMap::new();
     loop {
         use spawn_wait::WaitAnyResult::*;
         match procs.wait_any(sh) {
@@ -493,28 +1058,43 @@ fn resolve_froms(
                 nb_done += 1;
                 let resolved = std::fs::read_to_string(&t.iidfile)
                     .map_err(|e| UnableToReadTmpFile(t.iidfile.display().to_string(), e))?;
-                let tmp_tag = format!("modus_tmp_tag_{}", &resolved);
+                let tmp_tag = format!("modus_tmp_tag_{}", resolved);
                 // tmp_tag is going to be something like modus_tmp_tag_sha256:1234....
                 // This is very much intentional.
-                let st = Command::new("docker")
-                    .args(&["tag", &resolved, &tmp_tag])
-                    .status()?;
+                let st = logging::status(
+                    Command::new("docker").args(["tag", &resolved, &tmp_tag]),
+                    "docker tag",
+                )?;
                 if !st.success() {
                     return Err(BuildError::DockerTagFailed(resolved, tmp_tag, st));
                 }
                 image_cleanup.add(tmp_tag.clone());
+                if let ImageToResolve::Ref(image_ref) = &t.to_resolve {
+                    resolved_ids.insert(image_ref.clone(), resolved.clone());
+                }
 
                 debug_assert!(!orig_to_resolved_tag.contains_key(&t.to_resolve));
                 orig_to_resolved_tag.insert(t.to_resolve.clone(), tmp_tag);
-                eprintln!(
-                    "\x1b[2K\r{}\x1b[0m",
-                    format!(
-                        "[{}/{}] Resolved from({:?})...",
-                        nb_done,
-                        queue.len(),
-                        orig_str_repr
-                    )
-                    .blue()
+                logging::progress(
+                    "image_resolved",
+                    json!({
+                        "image": orig_str_repr,
+                        "resolved": resolved,
+                        "done": nb_done,
+                        "total": queue.len(),
+                    }),
+                    || {
+                        format!(
+                            "\x1b[2K\r{}\x1b[0m",
+                            format!(
+                                "[{}/{}] Resolved from({:?})...",
+                                nb_done,
+                                queue.len(),
+                                orig_str_repr
+                            )
+                            .blue()
+                        )
+                    },
                 );
             }
             ReceivedTerminationSignal(_) => {
@@ -553,7 +1133,137 @@ fn resolve_froms(
         }
     }
 
-    Ok(())
+    Ok(resolved_ids)
+}
+
+/// Loads an image from an OCI image layout directory or a `docker save` tarball into the
+/// local image store, and returns its ID.
+fn load_image(path: &Path, is_oci_layout: bool) -> Result<String, String> {
+    let mut load = Command::new("docker");
+    load.arg("load")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = if is_oci_layout {
+        if !path.join("index.json").is_file() {
+            return Err(format!(
+                "{} is not an OCI image layout, it has no index.json",
+                path.display()
+            ));
+        }
+        // docker load reads OCI layouts as tarballs.
+        let mut tar = Command::new("tar")
+            .arg("-C")
+            .arg(path)
+            .args(["-cf", "-", "."])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run tar: {}", e))?;
+        let output = load
+            .stdin(tar.stdout.take().unwrap())
+            .output()
+            .map_err(|e| format!("unable to run docker load: {}", e))?;
+        let tar_status = tar.wait().map_err(|e| e.to_string())?;
+        if !tar_status.success() {
+            return Err(format!("tar exited with code {}", tar_status));
+        }
+        output
+    } else {
+        if !path.is_file() {
+            return Err(format!("{} is not a file", path.display()));
+        }
+        load.arg("-i")
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("unable to run docker load: {}", e))?
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "docker load exited with code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let mut ids = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let id = if let Some(id) = line.strip_prefix("Loaded image ID: ") {
+            id.trim().to_owned()
+        } else if let Some(image) = line.strip_prefix("Loaded image: ") {
+            let inspect = Command::new("docker")
+                .args(["image", "inspect", "--format", "{{.Id}}", image.trim()])
+                .stdin(Stdio::null())
+                .output()
+                .map_err(|e| format!("unable to run docker image inspect: {}", e))?;
+            if !inspect.status.success() {
+                return Err(format!(
+                    "unable to inspect the loaded image {}",
+                    image.trim()
+                ));
+            }
+            String::from_utf8_lossy(&inspect.stdout).trim().to_owned()
+        } else {
+            continue;
+        };
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    match ids.len() {
+        1 => Ok(ids.pop().unwrap()),
+        0 => Err("docker load did not load an image".to_owned()),
+        n => Err(format!("it contains {} images, expected one", n)),
+    }
+}
+
+/// Loads the images in local files that the plan starts from, e.g. with
+/// `from(oci_layout("./base-oci"))`, into the local image store, from where the frontend
+/// reads them as it does resolved base images. The paths are relative to the context.
+/// Returns the ID that each was loaded as, by its expression.
+fn load_local_images(
+    build_plan: &mut BuildPlan,
+    context: &Path,
+    sh: &mut SignalHandler,
+    image_cleanup: &mut DockerImageRmOnDrop,
+) -> Result<BTreeMap<String, String>, BuildError> {
+    let mut tags: HashMap<String, String> = HashMap::new();
+    let mut loaded_ids = BTreeMap::new();
+    for node in build_plan.nodes.iter_mut() {
+        let expression = match node.local_image() {
+            Some(expression) => expression,
+            None => continue,
+        };
+        let (path, image_ref, is_oci_layout) = match node {
+            BuildNode::FromOciLayout { path, image_ref } => (path, image_ref, true),
+            BuildNode::FromDockerTar { path, image_ref } => (path, image_ref, false),
+            _ => unreachable!(),
+        };
+        if let Some(tag) = tags.get(&expression) {
+            *image_ref = Some(tag.clone());
+            continue;
+        }
+        if sh.termination_pending() {
+            return Err(Interrupted);
+        }
+        logging::progress("image_loading", json!({ "image": expression }), || {
+            format!("{}", format!("Loading {}...", expression).blue())
+        });
+        let id = load_image(&context.join(&path), is_oci_layout)
+            .map_err(|e| LoadImageFailed(expression.clone(), e))?;
+        let tag = format!("modus_tmp_tag_{}", id);
+        let st = logging::status(
+            Command::new("docker").args(["tag", &id, &tag]),
+            "docker tag",
+        )?;
+        if !st.success() {
+            return Err(BuildError::DockerTagFailed(id, tag, st));
+        }
+        image_cleanup.add(tag.clone());
+        *image_ref = Some(tag.clone());
+        tags.insert(expression.clone(), tag);
+        loaded_ids.insert(expression, id);
+    }
+    Ok(loaded_ids)
 }
 
 #[derive(Debug, Default)]
@@ -567,30 +1277,49 @@ impl DockerImageRmOnDrop {
 
 impl Drop for DockerImageRmOnDrop {
     fn drop(&mut self) {
-        eprintln!("Cleaning up {} temporary images and tags...", self.0.len());
+        logging::progress("cleanup", json!({ "images": self.0.len() }), || {
+            format!("Cleaning up {} temporary images and tags...", self.0.len())
+        });
         for img in self.0.iter() {
-            let _ = Command::new("docker")
-                .args(&["image", "rm", img])
-                .stdout(Stdio::null())
-                .status();
+            let _ = logging::status(
+                Command::new("docker")
+                    .args(["image", "rm", img])
+                    .stdout(Stdio::null()),
+                "docker image rm",
+            );
         }
     }
 }
 
-/// Returns the image IDs on success, following the order in build_plan.outputs.
+/// The images produced by a build.
+#[derive(Debug, Clone)]
+pub struct BuiltImages {
+    /// The image IDs, following the order in build_plan.outputs.
+    pub image_ids: Vec<String>,
+    /// The ID of the image that each base image reference was resolved to.
+    pub base_images: BTreeMap<String, String>,
+}
+
 pub fn build<P: AsRef<Path>>(
     mut build_plan: BuildPlan,
     context: P,
     build_options: &BuildOptions,
     profiling: &mut Profiling,
-) -> Result<Vec<String>, BuildError> {
+) -> Result<BuiltImages, BuildError> {
     let mut sh = SignalHandler::default();
     let context = context.as_ref().canonicalize().map_err(CwdError)?;
     let previous_cwd = PathBuf::from(".").canonicalize().map_err(CwdError)?;
     let _restore_cwd = RestoreCwd(previous_cwd);
     let mut image_cleanup = DockerImageRmOnDrop::default();
     let resolving_start = Instant::now();
-    resolve_froms(&mut build_plan, build_options, &mut sh, &mut image_cleanup)?;
+    let mut base_images =
+        resolve_froms(&mut build_plan, build_options, &mut sh, &mut image_cleanup)?;
+    base_images.extend(load_local_images(
+        &mut build_plan,
+        &context,
+        &mut sh,
+        &mut image_cleanup,
+    )?);
     profiling.resolving_total = resolving_start.elapsed().as_secs_f32();
     std::env::set_current_dir(&context).map_err(EnterContextDir)?;
     let has_dockerignore = check_dockerignore()?;
@@ -604,29 +1333,57 @@ pub fn build<P: AsRef<Path>>(
     }
     let dockerfile = write_tmp_dockerfile(&content).map_err(UnableToCreateTempFile)?;
     use spawn_wait::WaitAnyResult::*;
-    eprintln!("{}", "Running docker build...".blue());
+    logging::progress(
+        "plan_submitted",
+        json!({
+            "nodes": build_plan.nodes.len(),
+            "outputs": build_plan.outputs.len(),
+        }),
+        || format!("{}", "Running docker build...".blue()),
+    );
     let main_img_iidfile = AutoDeleteTmpFilename::gen(".iid");
     let mut procs = ProcessSet::new();
     let build_start = Instant::now();
-    procs.add_command(
-        (),
-        make_buildkit_command(
-            dockerfile.name(),
-            None,
-            None,
-            has_dockerignore,
-            Some(main_img_iidfile.name()),
-            &build_options.docker_build_options,
-            None,
-        ),
+    let mut cmd = make_buildkit_command(
+        dockerfile.name(),
+        None,
+        match build_plan.outputs.len() {
+            1 => BuildTarget::Only,
+            _ => BuildTarget::All,
+        },
+        has_dockerignore,
+        Some(main_img_iidfile.name()),
+        &DockerBuildOptions {
+            // With several outputs, the images are loaded when they are exported.
+            load: build_options.docker_build_options.load && build_plan.outputs.len() == 1,
+            ..build_options.docker_build_options.clone()
+        },
+        None,
     );
+    // The JSON log reports the nodes as they finish, which needs the plain progress.
+    let plain_progress = build_options.docker_build_options.verbose
+        || logging::is_json()
+        || !std::io::stderr().is_terminal();
+    let progress_watcher = match plain_progress && !build_options.docker_build_options.quiet {
+        true => Some(watch_node_progress(&mut cmd)?),
+        false => {
+            logging::forward_output(&mut cmd, "docker build")?;
+            None
+        }
+    };
+    procs.add_command((), cmd);
     match procs.wait_any(&mut sh) {
         Subprocess(_, res) => {
-            let (_, exit_status) = res.map_err(|e| UnableToRunDockerBuild(e))?;
+            let (_, exit_status) = res.map_err(UnableToRunDockerBuild)?;
             profiling.building = build_start.elapsed().as_secs_f32();
+            let failed_node = progress_watcher.and_then(|w| w.join().ok().flatten());
             if !exit_status.success() {
-                return Err(DockerBuildFailed(exit_status));
+                return Err(DockerBuildFailed(exit_status, failed_node));
             }
+            logging::event(
+                "build_finished",
+                json!({ "duration_secs": profiling.building }),
+            );
         }
         ReceivedTerminationSignal(_) => {
             let _ = procs.sigint_all_and_wait(&mut sh);
@@ -638,24 +1395,39 @@ pub fn build<P: AsRef<Path>>(
         .map_err(|e| UnableToReadTmpFile(main_img_iidfile.name().to_owned(), e))?;
     match build_plan.outputs.len() {
         0 => unreachable!(), // not possible because if there is no solution to the initial query, there will be an SLD failure.
-        1 => Ok(vec![main_img_iid]),
+        1 => {
+            logging::event(
+                "output_exported",
+                json!({
+                    "literal": build_plan.outputs[0].source_literal.as_ref().map(|l| l.to_string()),
+                    "digest": main_img_iid,
+                    "done": 1,
+                    "total": 1,
+                }),
+            );
+            Ok(BuiltImages {
+                image_ids: vec![main_img_iid],
+                base_images,
+            })
+        }
         nb_outputs => {
             image_cleanup.add(main_img_iid.clone());
             let mut procs = ProcessSet::with_concurrency_limit(
                 build_options.export_concurrency.try_into().unwrap(),
             );
             let mut res = vec![None; nb_outputs];
-            // Overwrite the last line printed by buildkit.
-            eprintln!("\x1b[1A\x1b[2K\r=== Build success, exporting individual images ===");
+            logging::progress("export_start", json!({ "outputs": nb_outputs }), || {
+                // Overwrite the last line printed by buildkit.
+                "\x1b[1A\x1b[2K\r=== Build success, exporting individual images ===".to_owned()
+            });
             let mut iidfiles = Vec::with_capacity(nb_outputs);
             let exporting_start = Instant::now();
             for i in 0..nb_outputs {
-                let target_str = format!("{}", i);
                 let iidfile = AutoDeleteTmpFilename::gen(".iid");
-                let cmd = make_buildkit_command(
+                let mut cmd = make_buildkit_command(
                     dockerfile.name(),
                     None,
-                    Some(target_str),
+                    BuildTarget::Output(i),
                     has_dockerignore,
                     Some(iidfile.name()),
                     &DockerBuildOptions {
@@ -666,6 +1438,7 @@ pub fn build<P: AsRef<Path>>(
                     },
                     None,
                 );
+                logging::forward_output(&mut cmd, "docker build")?;
                 iidfiles.push(iidfile);
                 procs.add_command(i, cmd);
             }
@@ -684,32 +1457,52 @@ pub fn build<P: AsRef<Path>>(
                         }
                         let exit_status = r.unwrap().1;
                         if !exit_status.success() {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "Exporting {} failed with exit code {}",
-                                    literal_str,
-                                    exit_status.code().unwrap_or(-1)
-                                )
-                                .red()
+                            logging::progress(
+                                "export_failed",
+                                json!({
+                                    "literal": literal_str,
+                                    "exit_code": exit_status.code(),
+                                }),
+                                || {
+                                    format!(
+                                        "{}",
+                                        format!(
+                                            "Exporting {} failed with exit code {}",
+                                            literal_str,
+                                            exit_status.code().unwrap_or(-1)
+                                        )
+                                        .red()
+                                    )
+                                },
                             );
                             let _ = procs.sigint_all_and_wait(&mut sh);
-                            return Err(DockerBuildFailed(exit_status));
+                            return Err(DockerBuildFailed(exit_status, None));
                         }
                         let iid = std::fs::read_to_string(iidfiles[i].name())
                             .map_err(|e| UnableToReadTmpFile(iidfiles[i].name().to_owned(), e))?;
                         res[i] = Some(iid);
                         nb_done += 1;
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "Exported {}/{}: {} -> {}",
-                                nb_done,
-                                nb_outputs,
-                                literal_str,
-                                res[i].as_ref().unwrap()
-                            )
-                            .blue()
+                        logging::progress(
+                            "output_exported",
+                            json!({
+                                "literal": literal_str,
+                                "digest": res[i],
+                                "done": nb_done,
+                                "total": nb_outputs,
+                            }),
+                            || {
+                                format!(
+                                    "{}",
+                                    format!(
+                                        "Exported {}/{}: {} -> {}",
+                                        nb_done,
+                                        nb_outputs,
+                                        literal_str,
+                                        res[i].as_ref().unwrap()
+                                    )
+                                    .blue()
+                                )
+                            },
                         );
                     }
                     ReceivedTerminationSignal(_) => {
@@ -723,11 +1516,61 @@ pub fn build<P: AsRef<Path>>(
             }
             profiling.exporting_total = exporting_start.elapsed().as_secs_f32();
             debug_assert_eq!(nb_done, nb_outputs);
-            Ok(res.into_iter().map(|x| x.unwrap()).collect())
+            Ok(BuiltImages {
+                image_ids: res.into_iter().map(|x| x.unwrap()).collect(),
+                base_images,
+            })
         }
     }
 }
 
+/// Tags the built images with the tags given to their outputs with `::tag`, and pushes
+/// the tags to their registries if `push` is set. The image IDs follow the order in
+/// build_plan.outputs, as returned by [`build`].
+pub fn tag_outputs(
+    build_plan: &BuildPlan,
+    image_ids: &[String],
+    push: bool,
+) -> Result<(), BuildError> {
+    debug_assert_eq!(build_plan.outputs.len(), image_ids.len());
+    for (output, image_id) in build_plan.outputs.iter().zip(image_ids) {
+        for tag in output.tags.iter() {
+            let status = logging::status(
+                Command::new("docker").args(["tag", image_id, tag]),
+                "docker tag",
+            )?;
+            if !status.success() {
+                return Err(DockerTagFailed(image_id.clone(), tag.clone(), status));
+            }
+            logging::event("image_tagged", json!({ "digest": image_id, "tag": tag }));
+        }
+    }
+    if !push {
+        return Ok(());
+    }
+    for tag in build_plan.outputs.iter().flat_map(|o| o.tags.iter()) {
+        logging::progress("push_start", json!({ "tag": tag }), || {
+            format!("{}", format!("Pushing {}...", tag).blue())
+        });
+        // Keep stdout for the results of the build, e.g. with --format. The JSON log
+        // reports the push with its own events instead of the progress of docker push.
+        let status = logging::status(
+            Command::new("docker")
+                .args(["push", tag])
+                .stdout(match logging::is_json() {
+                    true => Stdio::null(),
+                    false => std::io::stderr().into(),
+                }),
+            "docker push",
+        )?;
+        if !status.success() {
+            return Err(DockerPushFailed(tag.clone(), status));
+        }
+        logging::event("image_pushed", json!({ "tag": tag }));
+    }
+    Ok(())
+}
+
 pub fn check_dockerignore() -> Result<bool, BuildError> {
     match std::fs::read(".dockerignore") {
         Ok(content) => {
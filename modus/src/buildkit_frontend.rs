@@ -19,7 +19,10 @@
 // (otherwise there will be a lot of warnings for functions that are only used in the main binary.)
 
 mod buildkit;
+mod logging;
 mod reporting;
+#[cfg(test)]
+mod testing;
 
 use modus_lib::*;
 
@@ -27,7 +30,7 @@ mod buildkit_llb_types;
 use buildkit_llb_types::OwnedOutput;
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     ffi::{OsStr, OsString},
     path::PathBuf,
     sync::Arc,
@@ -35,13 +38,19 @@ use std::{
 
 use buildkit_frontend::{
     oci::{ImageConfig, ImageSpecification},
-    run_frontend, Bridge, Frontend, FrontendOutput,
+    run_frontend, Bridge, ErrorWithCauses, Frontend, FrontendOutput,
 };
 use buildkit_llb::prelude::*;
 
 use async_trait::async_trait;
 
-use imagegen::{BuildNode, BuildPlan};
+use codespan_reporting::{
+    diagnostic::Diagnostic,
+    files::SimpleFile,
+    term::{self, termcolor::NoColor},
+};
+use imagegen::{wrap_command, BuildNode, BuildPlan};
+use modusfile::Modusfile;
 
 use crate::imagegen::{MergeNode, MergeOperation};
 
@@ -56,12 +65,76 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 struct TheFrontend;
 
+/// An error returned to BuildKit instead of a panic, so that `docker build` shows it.
+#[derive(thiserror::Error, Debug)]
+enum FrontendError {
+    #[error("Unable to read {0} from the context: {1}")]
+    ReadContextFile(String, String),
+    #[error("{0} contains invalid utf-8.")]
+    InvalidUtf8(String),
+    #[error("Invalid build plan: {0}")]
+    InvalidPlan(String),
+    #[error("{0}")]
+    InvalidInlineInput(String),
+    #[error("{0}")]
+    InvalidTarget(String),
+    /// The frontend API returns a single image to BuildKit.
+    #[error(
+        "The build plan has {0} outputs, but a single image can be built at once. Select one \
+         with --target, given as the index of the output, one of its tags or its literal, \
+         or build the Modusfile with `modus build`."
+    )]
+    TargetRequired(usize),
+    /// buildkit-llb always runs commands with `security=sandbox`, so `::insecure` is only
+    /// supported by the Dockerfile backend.
+    #[error(
+        "run({0:?})::insecure is not supported by the modus frontend. Transpile the Modusfile \
+         with `modus transpile` and build it with `docker buildx build --allow security.insecure`."
+    )]
+    Insecure(String),
+    /// The LLB that the frontend builds has no way to mount secrets yet, unlike the
+    /// Dockerfile backend.
+    #[error(
+        "run({0:?})::secret({1:?}) is not supported by the modus frontend yet. Transpile the \
+         Modusfile with `modus transpile` and build it with `docker buildx build --secret id={1},...`."
+    )]
+    Secret(String, String),
+    #[error("The named context {0:?} was not given, use --build-context {0}=...")]
+    MissingNamedContext(String),
+    #[error("Unsupported named context {0}={1}")]
+    UnsupportedNamedContext(String, String),
+    #[error("Unable to resolve the image config of {0}: {1}")]
+    ResolveImageConfig(String, String),
+}
+
+/// A Modusfile and query given in the frontend options rather than in the context, e.g.
+/// `--build-arg 'inline={"modusfile": "app :- from(\"alpine\").", "query": "app"}'`.
+///
+/// It is given as JSON because other option values are split at commas.
+#[derive(Deserialize, Debug, PartialEq)]
+struct InlineInput {
+    modusfile: String,
+    query: String,
+}
+
 #[derive(Deserialize)]
 struct FrontendOptions {
     filename: String,
+    /// The output to return, see [`select_target`]. It is required if the plan has
+    /// several outputs, unless `all_outputs` is set.
     target: Option<String>,
+    /// Set by `modus build` to build every output of a plan with several at once, so
+    /// that building each target afterwards only exports it. The first one is returned.
+    #[serde(default)]
+    all_outputs: bool,
     has_dockerignore: bool,
     no_cache: bool,
+    #[serde(default)]
+    inline: Option<InlineInput>,
+    /// A git repository to copy from instead of the local context, e.g.
+    /// `https://github.com/org/repo.git#branch`.
+    #[serde(default)]
+    git_context: Option<String>,
     #[serde(flatten)]
     others: HashMap<String, serde_json::Value>,
 }
@@ -73,50 +146,19 @@ impl Frontend<FrontendOptions> for TheFrontend {
         bridge: Bridge,
         options: FrontendOptions,
     ) -> Result<FrontendOutput, failure::Error> {
-        let build_plan = fetch_input(&bridge, &options).await;
-        let mut outputs = handle_build_plan(&bridge, &options, &build_plan).await;
-        let final_output;
-        if outputs.len() == 1 {
-            final_output = outputs.into_iter().next().unwrap();
-        } else if options.target.is_some() && !options.target.as_ref().unwrap().is_empty() {
-            let target_idx: usize = options
-                .target
-                .as_ref()
-                .unwrap()
-                .parse()
-                .expect("Expected target to be an usize");
-            final_output = outputs.swap_remove(target_idx);
-        } else {
-            let alpine = Source::image("alpine")
-                .custom_name("Getting an alpine image as a stub for the final image")
-                .ref_counted();
-            let (_, alpine_config) = bridge
-                .resolve_image_config(&alpine, Some("alpine (stub) :: resolve"))
-                .await?;
-            let mut command = Command::run("true").cwd("/").mount(Mount::Layer(
-                OutputIdx(0),
-                SingleOwnedOutput::output(&alpine),
-                "/",
-            ));
-            let mut idx = 1usize;
-            for o in &outputs {
-                command = command.mount(Mount::Layer(
-                    OutputIdx(idx as u32),
-                    o.0.output(),
-                    format!("/_{}", idx),
-                ));
-                idx += 1;
+        let build_plan = fetch_input(&bridge, &options).await?;
+        let target = output_to_return(&build_plan, &options)?;
+        let mut outputs = handle_build_plan(&bridge, &options, &build_plan).await?;
+
+        if options.all_outputs {
+            for o in outputs.iter().skip(1) {
+                bridge.solve(Terminal::with(o.0.output())).await?;
             }
-            command = command.custom_name("Finishing multiple output images");
-            final_output = (
-                OwnedOutput::from_command(command.ref_counted(), 0),
-                Arc::new(alpine_config),
-            );
         }
+        let final_output = outputs.swap_remove(target);
         let solved = bridge
             .solve(Terminal::with(final_output.0.output()))
-            .await
-            .expect("Unable to solve");
+            .await?;
         Ok(FrontendOutput::with_spec_and_ref(
             (*final_output.1).clone(),
             solved,
@@ -124,35 +166,128 @@ impl Frontend<FrontendOptions> for TheFrontend {
     }
 }
 
-async fn read_local_file(bridge: &Bridge, filename: &str) -> Vec<u8> {
+/// The index of the output whose image is returned to BuildKit: the one selected with
+/// `--target`, or else the first one, which has to be the only one unless every output
+/// is built.
+fn output_to_return(plan: &BuildPlan, options: &FrontendOptions) -> Result<usize, FrontendError> {
+    match options.target.as_deref() {
+        Some(target) if !target.is_empty() => {
+            select_target(plan, target).map_err(FrontendError::InvalidTarget)
+        }
+        _ if plan.outputs.len() > 1 && !options.all_outputs => {
+            Err(FrontendError::TargetRequired(plan.outputs.len()))
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Finds the output selected with `--target`, either by its index in the plan, by one of
+/// the tags given to it with `::tag`, or by its literal, e.g. `app("3.11")`, if the
+/// plan records it.
+fn select_target(plan: &BuildPlan, target: &str) -> Result<usize, String> {
+    if let Ok(index) = target.parse::<usize>() {
+        return match index < plan.outputs.len() {
+            true => Ok(index),
+            false => Err(format!(
+                "The target {} is out of range, the plan has {} outputs.",
+                index,
+                plan.outputs.len()
+            )),
+        };
+    }
+    plan.outputs
+        .iter()
+        .position(|o| {
+            o.tags.iter().any(|t| t == target)
+                || o.source_literal
+                    .as_ref()
+                    .is_some_and(|l| l.to_string() == target)
+        })
+        .ok_or_else(|| {
+            format!(
+                "No output of the plan is tagged or built for {}, expected an output index \
+                 from 0 to {}.",
+                target,
+                plan.outputs.len().saturating_sub(1)
+            )
+        })
+}
+
+async fn read_local_file(bridge: &Bridge, filename: &str) -> Result<Vec<u8>, FrontendError> {
+    let error = |e| {
+        FrontendError::ReadContextFile(
+            filename.to_owned(),
+            ErrorWithCauses::single_line(e).to_string(),
+        )
+    };
     let mut local_source = Source::local("context").custom_name(format!("Reading {}", filename));
     local_source = local_source.add_include_pattern(filename);
     let local_output = local_source.output();
     let local_ref = bridge
         .solve(Terminal::with(local_output))
         .await
-        .expect("Failed to get local context");
-    let input = bridge
+        .map_err(error)?;
+    bridge
         .read_file(&local_ref, filename, None)
         .await
-        .expect("Failed to read local file");
-    input
+        .map_err(error)
+}
+
+async fn fetch_input(
+    bridge: &Bridge,
+    options: &FrontendOptions,
+) -> Result<BuildPlan, FrontendError> {
+    let plan = match &options.inline {
+        Some(inline) => plan_inline_input(inline)?,
+        None => parse_plan(&read_local_file(bridge, &options.filename).await?)?,
+    };
+    plan.check_structure().map_err(FrontendError::InvalidPlan)?;
+    Ok(plan)
+}
+
+/// Parses the build plan written by `modus build`, which follows the `#syntax=` line.
+fn parse_plan(input: &[u8]) -> Result<BuildPlan, FrontendError> {
+    let start = input
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| FrontendError::InvalidPlan("expected a #syntax line".to_owned()))?
+        + 1;
+    serde_json::from_slice(&input[start..]).map_err(|e| FrontendError::InvalidPlan(e.to_string()))
 }
 
-async fn fetch_input(bridge: &Bridge, options: &FrontendOptions) -> BuildPlan {
-    let input_filename = &options.filename;
-    let input_file_bytes = read_local_file(bridge, input_filename).await;
-    let input_file_content =
-        std::str::from_utf8(&input_file_bytes[..]).expect("Expected input to be UTF8");
-    let start = input_file_content.find('\n').expect("Invalid input") + 1;
-    serde_json::from_slice(&input_file_bytes[start..]).expect("Invalid input")
+fn plan_inline_input(inline: &InlineInput) -> Result<BuildPlan, FrontendError> {
+    /// Renders the diagnostics as `modus` prints them, without colors.
+    fn rendered(name: &str, source: &str, diagnostics: &[Diagnostic<()>]) -> FrontendError {
+        let file = SimpleFile::new(name, source);
+        let mut out = NoColor::new(Vec::new());
+        for diagnostic in diagnostics {
+            // Writing to a Vec only fails if the labels are out of the source.
+            let _ = term::emit(&mut out, &term::Config::default(), &file, diagnostic);
+        }
+        FrontendError::InvalidInlineInput(String::from_utf8_lossy(&out.into_inner()).into_owned())
+    }
+    let mf: Modusfile = inline
+        .modusfile
+        .parse()
+        .map_err(|e: Vec<_>| rendered("Modusfile", &inline.modusfile, &e))?;
+    let query: modusfile::Expression = inline
+        .query
+        .parse()
+        .map_err(|e: Vec<_>| rendered("query", &inline.query, &e))?;
+    imagegen::plan_from_modusfile(
+        mf,
+        query.without_position(),
+        sld::SearchStrategy::DepthFirst,
+        &mut sld::SolveProfile::default(),
+    )
+    .map_err(|e| rendered("Modusfile", &inline.modusfile, &e.diagnostics()))
 }
 
 async fn handle_build_plan(
     bridge: &Bridge,
     options: &FrontendOptions,
     build_plan: &BuildPlan,
-) -> Vec<(OwnedOutput, Arc<ImageSpecification>)> {
+) -> Result<Vec<(OwnedOutput, Arc<ImageSpecification>)>, FrontendError> {
     let mut translated_nodes: Vec<Option<(OwnedOutput, Arc<ImageSpecification>)>> =
         Vec::with_capacity(build_plan.nodes.len());
     for _ in 0..build_plan.nodes.len() {
@@ -202,21 +337,44 @@ async fn handle_build_plan(
     async fn get_local_source_for_copy(
         bridge: &Bridge,
         should_read_ignore_file: bool,
-    ) -> OperationOutput<'static> {
+        git_context: Option<&str>,
+    ) -> Result<OperationOutput<'static>, FrontendError> {
+        if let Some(git_context) = git_context.and_then(buildkit::GitContext::parse) {
+            let mut source = Source::git(&git_context.url)
+                .custom_name(format!("Fetching git context {}", git_context.url));
+            if let Some(reference) = git_context.reference {
+                source = source.with_reference(reference);
+            }
+            return Ok(source.ref_counted().output());
+        }
         let mut source = Source::local("context").custom_name("Sending local context for copy");
         if should_read_ignore_file {
-            let dockerignore_bytes = read_local_file(bridge, ".dockerignore").await;
+            let dockerignore_bytes = read_local_file(bridge, ".dockerignore").await?;
             let dockerignore = std::str::from_utf8(&dockerignore_bytes)
-                .expect("Expected .dockerignore to contain valid utf-8 content.");
+                .map_err(|_| FrontendError::InvalidUtf8(".dockerignore".to_owned()))?;
             for line in dockerignore.lines() {
                 source = source.add_exclude_pattern(line);
             }
         }
         source = source.add_exclude_pattern(buildkit::TMP_PREFIX_IGNORE_PATTERN);
-        source.ref_counted().output()
+        Ok(source.ref_counted().output())
+    }
+
+    /// The repository at the revision, fetched by BuildKit, which caches it by commit.
+    fn git_source(url: &str, rev: &str) -> OperationOutput<'static> {
+        Source::git(url)
+            .with_reference(rev)
+            .custom_name(format!("Fetching {}#{}", url, rev))
+            .ref_counted()
+            .output()
     }
 
-    let local_context = get_local_source_for_copy(bridge, options.has_dockerignore).await;
+    let local_context = get_local_source_for_copy(
+        bridge,
+        options.has_dockerignore,
+        options.git_context.as_deref(),
+    )
+    .await?;
 
     for node_id in build_plan.topological_order().into_iter() {
         let node = &build_plan.nodes[node_id];
@@ -233,7 +391,7 @@ async fn handle_build_plan(
                 .config
                 .as_ref()
                 .and_then(|x| x.user.as_ref().map(|x| &x[..]));
-            cmd = cmd.cwd(get_cwd_from_image_spec(&imgspec).join(this_cwd));
+            cmd = cmd.cwd(get_cwd_from_image_spec(imgspec).join(this_cwd));
             if let Some(user) = user {
                 cmd = cmd.user(user);
             } else {
@@ -275,24 +433,89 @@ async fn handle_build_plan(
                 its spec.
             */
             FromScratch { scratch_ref } => {
-                let img_s =
-                    Source::image(scratch_ref.as_ref().unwrap()).custom_name("from(\"scratch\")");
+                let scratch_ref = scratch_ref.as_ref().ok_or_else(|| {
+                    FrontendError::InvalidPlan(
+                        "from(\"scratch\") was not resolved to an empty image.".to_owned(),
+                    )
+                })?;
+                let img_s = Source::image(scratch_ref).custom_name("from(\"scratch\")");
                 (img_s.ref_counted().into(), Arc::new(scratch_spec()))
             }
+            FromContext { name } => {
+                // docker build passes `--build-context name=value` to us as `context:name`.
+                let value = options
+                    .others
+                    .get(&format!("context:{}", name))
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| FrontendError::MissingNamedContext(name.clone()))?;
+                if let Some(image_ref) = value.strip_prefix("docker-image://") {
+                    let img_s =
+                        Source::image(image_ref).custom_name(format!("context({:?})", name));
+                    let log_name = format!("context({:?}) :: resolve image config", name);
+                    let resolved_config = bridge
+                        .resolve_image_config(&img_s, Some(&log_name))
+                        .await
+                        .map_err(|e| {
+                            FrontendError::ResolveImageConfig(
+                                image_ref.to_owned(),
+                                ErrorWithCauses::single_line(e).to_string(),
+                            )
+                        })?
+                        .1;
+                    (img_s.ref_counted().into(), Arc::new(resolved_config))
+                } else if let Some(local_name) = value.strip_prefix("local:") {
+                    let local_s =
+                        Source::local(local_name).custom_name(format!("context({:?})", name));
+                    (local_s.ref_counted().into(), Arc::new(scratch_spec()))
+                } else {
+                    return Err(FrontendError::UnsupportedNamedContext(
+                        name.clone(),
+                        value.to_owned(),
+                    ));
+                }
+            }
             From {
                 image_ref,
                 display_name,
             } => {
-                let img_s =
-                    Source::image(image_ref).custom_name(format!("from({:?})", display_name));
+                let img_s = Source::image(image_ref).custom_name(buildkit::vertex_name(
+                    node_id,
+                    &format!("from({:?})", display_name),
+                ));
                 let log_name = format!("from({:?}) :: resolve image config", display_name);
-                let resolved_config =
-                    match bridge.resolve_image_config(&img_s, Some(&log_name)).await {
-                        Ok((_, x)) => x,
-                        Err(e) => {
-                            panic!("Failed to resolve image config: {:?}", e); // unreachable
-                        }
-                    };
+                let resolved_config = bridge
+                    .resolve_image_config(&img_s, Some(&log_name))
+                    .await
+                    .map_err(|e| {
+                        FrontendError::ResolveImageConfig(
+                            image_ref.to_owned(),
+                            ErrorWithCauses::single_line(e).to_string(),
+                        )
+                    })?
+                    .1;
+                (img_s.ref_counted().into(), Arc::new(resolved_config))
+            }
+            FromOciLayout { image_ref, .. } | FromDockerTar { image_ref, .. } => {
+                // The file is loaded into the local image store by modus, as for a from.
+                let expression = node.local_image().unwrap();
+                let image_ref = image_ref.as_ref().ok_or_else(|| {
+                    FrontendError::InvalidPlan(format!("{} was not loaded.", expression))
+                })?;
+                let img_s = Source::image(image_ref).custom_name(buildkit::vertex_name(
+                    node_id,
+                    &format!("from({})", expression),
+                ));
+                let log_name = format!("from({}) :: resolve image config", expression);
+                let resolved_config = bridge
+                    .resolve_image_config(&img_s, Some(&log_name))
+                    .await
+                    .map_err(|e| {
+                        FrontendError::ResolveImageConfig(
+                            expression.clone(),
+                            ErrorWithCauses::single_line(e).to_string(),
+                        )
+                    })?
+                    .1;
                 (img_s.ref_counted().into(), Arc::new(resolved_config))
             }
             Run {
@@ -300,15 +523,34 @@ async fn handle_build_plan(
                 command,
                 cwd,
                 additional_envs,
+                retry,
+                timeout,
+                insecure,
+                cache_mounts,
+                secrets,
             } => {
+                if *insecure {
+                    return Err(FrontendError::Insecure(command.clone()));
+                }
+                if let Some(id) = secrets.first() {
+                    return Err(FrontendError::Secret(command.clone(), id.clone()));
+                }
                 let parent = translated_nodes[*parent]
                     .as_ref()
                     .expect("Expected dependencies to already be built");
                 let parent_config = parent.1.clone();
-                let mut cmd = new_cmd(&*parent_config, &cwd[..], &parent.0, &options)
-                    .args(&["-c", &command[..]])
-                    .custom_name(format!("run({:?})", command));
+                let script = wrap_command(command, *retry, timeout.as_ref());
+                let mut cmd = new_cmd(&parent_config, &cwd[..], &parent.0, options)
+                    .args(["-c", &script[..]])
+                    .custom_name(buildkit::vertex_name(
+                        node_id,
+                        &format!("run({:?})", command),
+                    ));
                 cmd = add_envs(cmd, additional_envs);
+                let image_cwd = get_cwd_from_image_spec(&parent_config);
+                for path in cache_mounts {
+                    cmd = cmd.mount(Mount::SharedCache(image_cwd.join(path)));
+                }
                 let o = OwnedOutput::from_command(cmd.ref_counted(), 0);
                 (o, parent_config)
             }
@@ -329,9 +571,9 @@ async fn handle_build_plan(
                     .create_path(true)
                     .recursive(true)
                     .into_operation()
-                    .custom_name(format!(
-                        "...::copy({:?}, {:?})",
-                        &raw_src_path, &raw_dst_path
+                    .custom_name(buildkit::vertex_name(
+                        node_id,
+                        &format!("...::copy({:?}, {:?})", raw_src_path, raw_dst_path),
                     ))
                     .ref_counted();
                 (o.into(), parent.1.clone())
@@ -349,7 +591,31 @@ async fn handle_build_plan(
                     .create_path(true)
                     .recursive(true)
                     .into_operation()
-                    .custom_name(format!("copy({:?}, {:?})", &src_path, &raw_dst_path))
+                    .custom_name(buildkit::vertex_name(
+                        node_id,
+                        &format!("copy({:?}, {:?})", src_path, raw_dst_path),
+                    ))
+                    .ref_counted();
+                (o.into(), parent.1.clone())
+            }
+            GitCheckout {
+                parent,
+                url,
+                rev,
+                dst_path: raw_dst_path,
+            } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
+                let o = FileSystem::copy()
+                    .from(LayerPath::Other(git_source(url, rev), "/"))
+                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), dst_path))
+                    .create_path(true)
+                    .recursive(true)
+                    .into_operation()
+                    .custom_name(buildkit::vertex_name(
+                        node_id,
+                        &format!("git_checkout({:?}, {:?}, {:?})", url, rev, raw_dst_path),
+                    ))
                     .ref_counted();
                 (o.into(), parent.1.clone())
             }
@@ -405,13 +671,15 @@ async fn handle_build_plan(
             }
             Merge(MergeNode { parent, operations }) => {
                 let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
-                let mut cmd = new_cmd(&*p_conf, "", &p_out, &options);
+                let mut cmd = new_cmd(&p_conf, "", &p_out, options);
                 let mut name = Vec::new();
                 let mut script = Vec::new();
-                let image_cwd = get_cwd_from_image_spec(&*p_conf);
+                let image_cwd = get_cwd_from_image_spec(&p_conf);
                 debug_assert!(image_cwd.is_absolute());
                 use shell_escape::escape;
                 let mut mount_id = 0usize;
+                // The cache mounts of the commands are mounted while all of them run.
+                let mut cache_mounts = BTreeSet::new();
                 fn mkdir_pf(path: &str, script: &mut Vec<String>) {
                     script.push(format!("(mkdir -p {} || true)", escape(path.into())));
                 }
@@ -436,7 +704,20 @@ async fn handle_build_plan(
                             command,
                             cwd,
                             additional_envs,
+                            retry,
+                            timeout,
+                            insecure,
+                            cache_mounts: command_cache_mounts,
+                            secrets,
                         } => {
+                            if *insecure {
+                                return Err(FrontendError::Insecure(command.clone()));
+                            }
+                            if let Some(id) = secrets.first() {
+                                return Err(FrontendError::Secret(command.clone(), id.clone()));
+                            }
+                            cache_mounts
+                                .extend(command_cache_mounts.iter().map(|p| image_cwd.join(p)));
                             let resolved_cwd = image_cwd.join(cwd);
                             let resolved_cwd = resolved_cwd.to_str().unwrap(); // TODO: report error if image cwd is not valid utf8.
                             cd(resolved_cwd, &mut script);
@@ -448,8 +729,10 @@ async fn handle_build_plan(
                                 ));
                             }
                             script.push(format!(
-                                "echo {cmd} && sh -c {cmd}",
-                                cmd = escape(command.into())
+                                "echo {cmd} && sh -c {script}",
+                                cmd = escape(command.into()),
+                                script =
+                                    escape(wrap_command(command, *retry, timeout.as_ref()).into())
                             ));
                             name.push(format!("run({:?})::in_workdir({:?})", command, cwd));
                         }
@@ -482,6 +765,28 @@ async fn handle_build_plan(
                             cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
                             name.push(format!("...::copy({:?}, {:?})", src_path, dst_path));
                         }
+                        MergeOperation::GitCheckout { url, rev, dst_path } => {
+                            let mut mount_dir = OsString::from("/__buildkit_merge_mount_");
+                            mount_dir.push(OsStr::new(&mount_id.to_string()));
+                            mount_id += 1;
+                            let dst_path = image_cwd.join(dst_path);
+                            debug_assert!(dst_path.is_absolute());
+                            let mount_dir = PathBuf::from(mount_dir);
+                            cmd = cmd.mount(Mount::ReadOnlySelector(
+                                git_source(url, rev),
+                                mount_dir.clone(),
+                                PathBuf::from("/"),
+                            ));
+
+                            if let Some(par) = dst_path.parent() {
+                                mkdir_pf(par.to_str().unwrap(), &mut script);
+                            }
+                            cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
+                            name.push(format!(
+                                "git_checkout({:?}, {:?}, {:?})",
+                                url, rev, dst_path
+                            ));
+                        }
                         MergeOperation::CopyFromLocal { src_path, dst_path } => {
                             let mut mount_dir = OsString::from("/__buildkit_merge_mount_");
                             mount_dir.push(OsStr::new(&mount_id.to_string()));
@@ -506,8 +811,14 @@ async fn handle_build_plan(
                         }
                     }
                 }
-                cmd = cmd.args(&["-c", &script.join(" && ")]);
-                cmd = cmd.custom_name(format!("merge: {}", name.join(" + ")));
+                for path in cache_mounts {
+                    cmd = cmd.mount(Mount::SharedCache(path));
+                }
+                cmd = cmd.args(["-c", &script.join(" && ")]);
+                cmd = cmd.custom_name(buildkit::vertex_name(
+                    node_id,
+                    &format!("merge: {}", name.join(" + ")),
+                ));
 
                 (OwnedOutput::from_command(cmd.ref_counted(), 0), p_conf)
             }
@@ -532,7 +843,7 @@ async fn handle_build_plan(
                     .get_or_insert_with(BTreeMap::new)
                     .entry(key.to_owned())
                     .or_insert_with(String::new)
-                    .push_str(&value);
+                    .push_str(value);
                 (p_out, Arc::new(p_conf))
             }
             SetUser { parent, user } => {
@@ -546,11 +857,140 @@ async fn handle_build_plan(
     }
     let mut outputs: Vec<(OwnedOutput, Arc<ImageSpecification>)> = Vec::new();
     for o in &build_plan.outputs {
-        outputs.push(
-            translated_nodes[o.node]
-                .clone()
-                .expect("Expected output to be built"),
+        let (out, mut spec) = translated_nodes[o.node]
+            .clone()
+            .expect("Expected output to be built");
+        if !o.annotations.is_empty() {
+            // The frontend API only gives us access to the image config, so
+            // annotations are recorded there as labels.
+            let mut new_spec = (*spec).clone();
+            new_spec
+                .config
+                .get_or_insert_with(empty_image_config)
+                .labels
+                .get_or_insert_with(BTreeMap::new)
+                .extend(o.annotations.clone());
+            spec = Arc::new(new_spec);
+        }
+        outputs.push((out, spec));
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_input_option() {
+        let options: FrontendOptions = buildkit_frontend::options::from_env(
+            [
+                "filename=Dockerfile",
+                "build-arg:no_cache=false",
+                "build-arg:has_dockerignore=false",
+                r#"build-arg:inline={"modusfile": "app(X) :- from(X), X = \"alpine\".", "query": "app(X)"}"#,
+            ]
+            .iter()
+            .enumerate()
+            .map(|(i, o)| (format!("BUILDKIT_FRONTEND_OPT_{}", i), o.to_string())),
+        )
+        .unwrap();
+        let inline = options.inline.unwrap();
+        assert_eq!(inline.query, "app(X)");
+
+        let plan = plan_inline_input(&inline).unwrap();
+        assert_eq!(plan.outputs.len(), 1);
+        assert!(plan
+            .nodes
+            .iter()
+            .any(|n| matches!(n, BuildNode::From { image_ref, .. } if image_ref == "alpine")));
+    }
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        assert!(matches!(
+            parse_plan(b"no newline"),
+            Err(FrontendError::InvalidPlan(_))
+        ));
+        assert!(matches!(
+            parse_plan(b"#syntax=modus\n{\"nodes\": 1}"),
+            Err(FrontendError::InvalidPlan(_))
+        ));
+        let plan = parse_plan(
+            b"#syntax=modus\n{\"nodes\": [], \"dependencies\": [], \"outputs\": [{\"node\": 0}]}",
+        )
+        .unwrap();
+        assert!(plan.check_structure().is_err());
+
+        let error = plan_inline_input(&InlineInput {
+            modusfile: "app :- from(\"alpine\"), run(\"make\").".to_owned(),
+            query: "missing".to_owned(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(
+            error.starts_with("error: There must be exactly one image predicate"),
+            "{}",
+            error
+        );
+        assert!(plan_inline_input(&InlineInput {
+            modusfile: "app :- from(".to_owned(),
+            query: "app".to_owned(),
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn target_selection() {
+        let plan = plan_inline_input(&InlineInput {
+            modusfile: "app(V) :- (V = \"1\" ; V = \"2\"), from(\"alpine\")::tag(f\"app:${V}\")."
+                .to_owned(),
+            query: "app(X)".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(plan.outputs.len(), 2);
+        for (i, o) in plan.outputs.iter().enumerate() {
+            assert_eq!(select_target(&plan, &i.to_string()), Ok(i));
+            assert_eq!(select_target(&plan, &o.tags[0]), Ok(i));
+            let literal = o.source_literal.as_ref().unwrap().to_string();
+            assert_eq!(select_target(&plan, &literal), Ok(i));
+        }
+        assert!(select_target(&plan, "2").is_err());
+        assert!(select_target(&plan, "app:3").is_err());
+
+        let options = |args: &[&str]| -> FrontendOptions {
+            buildkit_frontend::options::from_env(
+                [
+                    "filename=Dockerfile",
+                    "build-arg:no_cache=false",
+                    "build-arg:has_dockerignore=false",
+                ]
+                .iter()
+                .chain(args)
+                .enumerate()
+                .map(|(i, o)| (format!("BUILDKIT_FRONTEND_OPT_{}", i), o.to_string())),
+            )
+            .unwrap()
+        };
+        assert!(matches!(
+            output_to_return(&plan, &options(&[])),
+            Err(FrontendError::TargetRequired(2))
+        ));
+        assert_eq!(
+            output_to_return(&plan, &options(&["build-arg:all_outputs=true"])).unwrap(),
+            0
+        );
+        assert_eq!(
+            output_to_return(
+                &plan,
+                &options(&[&format!("target={}", plan.outputs[1].tags[0])])
+            )
+            .unwrap(),
+            1
         );
+        assert!(matches!(
+            output_to_return(&plan, &options(&["target=3"])),
+            Err(FrontendError::InvalidTarget(_))
+        ));
     }
-    outputs
 }
@@ -0,0 +1,300 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Management of the state that builds leave behind on the host.
+//!
+//! Modus itself does not keep a cache between runs, but a build creates
+//! temporary directories (prefixed with [`TMP_PREFIX`]) and temporary docker tags
+//! for resolved base images, which are normally removed at the end of the build.
+//! Interrupted builds can leave these behind. On top of that, buildkit keeps
+//! its own build cache, which grows with every build. This module can report on
+//! and prune all of these.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use thiserror::Error;
+
+use crate::buildkit::TMP_PREFIX;
+
+const TMP_TAG_REFERENCE: &str = "modus_tmp_tag_*";
+
+/// Age under which `prune` keeps temporary directories if no age is given, as they
+/// may belong to a build that is still running.
+pub const MIN_TMP_DIR_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("Unable to read temporary directory {0}: {1}")]
+    UnableToReadTmpDir(String, #[source] std::io::Error),
+    #[error("Unable to remove {0}: {1}")]
+    UnableToRemove(String, #[source] std::io::Error),
+    #[error("Unable to run docker: {0}")]
+    UnableToRunDocker(#[source] std::io::Error),
+    #[error("`docker {0}` exited with code {1}.")]
+    DockerFailed(String, std::process::ExitStatus),
+    #[error("Invalid duration {0:?}, expected a number followed by one of s, m, h, d.")]
+    InvalidDuration(String),
+    #[error("No prune policy given, use --older-than or --keep-storage, or `modus cache clear` to remove everything.")]
+    NoPrunePolicy,
+}
+
+use CacheError::*;
+
+/// A leftover temporary directory.
+#[derive(Debug, Clone)]
+pub struct TmpDir {
+    pub path: PathBuf,
+    pub size: u64,
+    pub age: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    pub tmp_dirs: Vec<TmpDir>,
+    pub tmp_tags: Vec<String>,
+}
+
+impl CacheStats {
+    pub fn tmp_dirs_size(&self) -> u64 {
+        self.tmp_dirs.iter().map(|d| d.size).sum()
+    }
+}
+
+/// Controls what `prune` removes. At least one of the limits has to be given.
+#[derive(Debug, Clone, Default)]
+pub struct PrunePolicy {
+    /// Only remove entries older than this.
+    pub older_than: Option<Duration>,
+    /// Amount of buildkit cache to keep, in a format understood by docker, e.g. "10GB".
+    pub keep_storage: Option<String>,
+}
+
+/// Parses durations like `30s`, `15m`, `12h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration, CacheError> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.len().saturating_sub(1));
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(InvalidDuration(s.to_owned())),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| InvalidDuration(s.to_owned()))
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| dir_size(&e.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn find_tmp_dirs() -> Result<Vec<TmpDir>, CacheError> {
+    let tmp = std::env::temp_dir();
+    let entries =
+        std::fs::read_dir(&tmp).map_err(|e| UnableToReadTmpDir(tmp.display().to_string(), e))?;
+    let now = SystemTime::now();
+    let mut res = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+            continue;
+        }
+        let path = entry.path();
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| now.duration_since(t).ok())
+            .unwrap_or_default();
+        res.push(TmpDir {
+            size: dir_size(&path),
+            path,
+            age,
+        });
+    }
+    Ok(res)
+}
+
+fn docker(args: &[&str], capture: bool) -> Result<String, CacheError> {
+    let output = Command::new("docker")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(if capture {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(UnableToRunDocker)?;
+    if !output.status.success() {
+        return Err(DockerFailed(args.join(" "), output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn find_tmp_tags() -> Result<Vec<String>, CacheError> {
+    let out = docker(
+        &[
+            "image",
+            "ls",
+            "--format",
+            "{{.Repository}}:{{.Tag}}",
+            "--filter",
+            &format!("reference={}", TMP_TAG_REFERENCE),
+        ],
+        true,
+    )?;
+    Ok(out.lines().map(|l| l.trim().to_owned()).collect())
+}
+
+/// Returns the images among `image_refs` that are already in the local image store,
+/// which a build would not have to pull.
+pub fn local_images<'a, I: IntoIterator<Item = &'a str>>(
+    image_refs: I,
+) -> Result<BTreeSet<String>, CacheError> {
+    let mut present = BTreeSet::new();
+    for image_ref in image_refs {
+        let output = Command::new("docker")
+            .args(["image", "inspect", image_ref])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(UnableToRunDocker)?;
+        if output.status.success() {
+            present.insert(image_ref.to_owned());
+        } else if !String::from_utf8_lossy(&output.stderr).contains("No such image") {
+            // E.g. the daemon is not running, so it is unknown whether the image is present.
+            return Err(DockerFailed(
+                format!("image inspect {}", image_ref),
+                output.status,
+            ));
+        }
+    }
+    Ok(present)
+}
+
+pub fn stats() -> Result<CacheStats, CacheError> {
+    Ok(CacheStats {
+        tmp_dirs: find_tmp_dirs()?,
+        tmp_tags: find_tmp_tags()?,
+    })
+}
+
+/// Prints the buildkit cache usage as reported by docker.
+pub fn print_buildkit_usage() -> Result<(), CacheError> {
+    docker(&["system", "df"], false).map(|_| ())
+}
+
+fn remove_tmp_dirs(min_age: Duration) -> Result<(), CacheError> {
+    for dir in find_tmp_dirs()? {
+        if dir.age >= min_age {
+            std::fs::remove_dir_all(&dir.path)
+                .map_err(|e| UnableToRemove(dir.path.display().to_string(), e))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_tmp_tags() -> Result<(), CacheError> {
+    let tags = find_tmp_tags()?;
+    if !tags.is_empty() {
+        let mut args = vec!["image", "rm"];
+        args.extend(tags.iter().map(|t| &t[..]));
+        docker(&args, true)?;
+    }
+    Ok(())
+}
+
+/// Removes leftover temporary directories and tags, and prunes the buildkit
+/// cache according to the policy. Temporary directories younger than
+/// [`MIN_TMP_DIR_AGE`] are kept unless `older_than` is given.
+pub fn prune(policy: &PrunePolicy) -> Result<(), CacheError> {
+    if policy.older_than.is_none() && policy.keep_storage.is_none() {
+        return Err(NoPrunePolicy);
+    }
+    remove_tmp_dirs(policy.older_than.unwrap_or(MIN_TMP_DIR_AGE))?;
+    // Temporary tags are only needed during a build, so they are always safe
+    // to remove.
+    remove_tmp_tags()?;
+
+    let until;
+    let keep_storage;
+    let mut args = vec!["builder", "prune", "--force"];
+    if let Some(older_than) = policy.older_than {
+        until = format!("until={}s", older_than.as_secs());
+        args.push("--filter");
+        args.push(&until);
+    }
+    if let Some(storage) = &policy.keep_storage {
+        keep_storage = format!("--keep-storage={}", storage);
+        args.push(&keep_storage);
+    }
+    docker(&args, false).map(|_| ())
+}
+
+/// Removes everything, including the whole buildkit cache.
+pub fn clear() -> Result<(), CacheError> {
+    remove_tmp_dirs(Duration::ZERO)?;
+    remove_tmp_tags()?;
+    docker(&["builder", "prune", "--force", "--all"], false).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 86400)
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("-1h").is_err());
+    }
+
+    #[test]
+    fn prune_needs_a_policy() {
+        assert!(matches!(prune(&PrunePolicy::default()), Err(NoPrunePolicy)));
+    }
+}
@@ -0,0 +1,358 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus build --cache-report`, which tells for each node of the build plan whether it
+//! could be taken from the build cache, and if not, why.
+//!
+//! BuildKit does not tell which of the steps it ran were cached, so the nodes are
+//! compared with the previous build of the same query in the same context instead, by
+//! their cache key (see [`node_cache_keys`]) and the contents of the files they copy
+//! from the context. A node that the previous build had is a hit, unless BuildKit has
+//! pruned it from its cache since.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use modus_lib::imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::include;
+use crate::reporting::node_cache_keys;
+
+/// Why a node could not be taken from the cache.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissReason {
+    /// The build was run with `--no-cache`.
+    NoCache,
+    /// The base image was resolved to another image.
+    ChangedBaseImage,
+    /// The instruction, e.g. the command that is run, is not one the previous build ran
+    /// on the same image.
+    ChangedInstruction,
+    /// An image that the node builds on or copies from was rebuilt.
+    ChangedInput,
+    /// Files that the node copies from the context changed.
+    ChangedFiles,
+}
+
+impl fmt::Display for MissReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MissReason::NoCache => "--no-cache was given",
+            MissReason::ChangedBaseImage => "the base image changed",
+            MissReason::ChangedInstruction => "the instruction changed",
+            MissReason::ChangedInput => "an image it builds on was rebuilt",
+            MissReason::ChangedFiles => "the files it copies changed",
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "status", content = "reason", rename_all = "kebab-case")]
+pub enum CacheStatus {
+    Hit,
+    Miss(MissReason),
+    /// There is no previous build to compare with.
+    Unknown,
+}
+
+/// The cache status of a node of the build plan.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeCacheReport {
+    pub node: NodeId,
+    pub instruction: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_literal: Option<String>,
+    #[serde(flatten)]
+    pub status: CacheStatus,
+}
+
+/// What is remembered of a build to compare the next one with: the cache key of each
+/// node, with the digest of the files it copies from the context, if any.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildRecord {
+    pub nodes: BTreeMap<String, Option<String>>,
+}
+
+/// The paths of the context that the node copies.
+fn local_copies(node: &BuildNode) -> Vec<&str> {
+    match node {
+        BuildNode::CopyFromLocal { src_path, .. } => vec![src_path],
+        BuildNode::Merge(merge) => merge
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                MergeOperation::CopyFromLocal { src_path, .. } => Some(src_path.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Hashes the names and contents of the files under the path, in a fixed order.
+fn hash_path(hasher: &mut Sha256, root: &Path, path: &Path) {
+    let name = path.strip_prefix(root).unwrap_or(path).to_string_lossy();
+    hasher.update(name.as_bytes());
+    hasher.update(b"\0");
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return hasher.update(b"missing\0"),
+    };
+    if metadata.file_type().is_symlink() {
+        if let Ok(target) = std::fs::read_link(path) {
+            hasher.update(target.to_string_lossy().as_bytes());
+        }
+    } else if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        entries.sort();
+        for entry in entries {
+            hash_path(hasher, root, &entry);
+        }
+    } else if let Ok(content) = std::fs::read(path) {
+        hasher.update(&content);
+    }
+    hasher.update(b"\0");
+}
+
+/// The digest of the files that the node copies from the context, if it copies any.
+fn files_digest(node: &BuildNode, context: &Path) -> Option<String> {
+    let paths = local_copies(node);
+    if paths.is_empty() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for path in paths {
+        hash_path(&mut hasher, context, &context.join(path));
+    }
+    Some(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Records the build of the plan, see [`BuildRecord`].
+pub fn record(
+    plan: &BuildPlan,
+    base_images: &BTreeMap<String, String>,
+    context: &Path,
+) -> BuildRecord {
+    BuildRecord {
+        nodes: node_cache_keys(plan, base_images)
+            .into_iter()
+            .zip(plan.nodes.iter())
+            .map(|(key, node)| (key, files_digest(node, context)))
+            .collect(),
+    }
+}
+
+/// Compares the build of the plan, recorded as `current`, with the previous one, and
+/// tells which nodes could be taken from the cache.
+pub fn report(
+    plan: &BuildPlan,
+    current: &BuildRecord,
+    previous: Option<&BuildRecord>,
+    no_cache: bool,
+    base_images: &BTreeMap<String, String>,
+) -> Vec<NodeCacheReport> {
+    let keys = node_cache_keys(plan, base_images);
+    let mut statuses: Vec<CacheStatus> = Vec::with_capacity(plan.nodes.len());
+    // Dependencies come before the nodes that use them, so their status is already known.
+    for (node, key) in keys.iter().enumerate() {
+        let status = match previous {
+            _ if no_cache => CacheStatus::Miss(MissReason::NoCache),
+            None => CacheStatus::Unknown,
+            Some(previous) => {
+                let deps = &plan.dependencies[node];
+                if deps
+                    .iter()
+                    .any(|&d| matches!(statuses[d], CacheStatus::Miss(_)))
+                {
+                    CacheStatus::Miss(MissReason::ChangedInput)
+                } else {
+                    match previous.nodes.get(key) {
+                        None if matches!(plan.nodes[node], BuildNode::From { .. })
+                            || plan.nodes[node].local_image().is_some() =>
+                        {
+                            CacheStatus::Miss(MissReason::ChangedBaseImage)
+                        }
+                        None => CacheStatus::Miss(MissReason::ChangedInstruction),
+                        Some(files) if *files != current.nodes[key] => {
+                            CacheStatus::Miss(MissReason::ChangedFiles)
+                        }
+                        Some(_) => CacheStatus::Hit,
+                    }
+                }
+            }
+        };
+        statuses.push(status);
+    }
+    statuses
+        .into_iter()
+        .enumerate()
+        .map(|(node, status)| NodeCacheReport {
+            node,
+            instruction: plan.nodes[node].name(),
+            source_literal: plan.source(node).map(ToString::to_string),
+            status,
+        })
+        .collect()
+}
+
+/// Where the record of the builds of the query in the context is kept.
+pub fn record_path(context: &Path, query: &str) -> Option<PathBuf> {
+    let mut hasher = Sha256::new();
+    hasher.update(context.to_string_lossy().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(query.as_bytes());
+    include::cache_home().map(|home| {
+        home.join("builds")
+            .join(format!("{:x}.json", hasher.finalize()))
+    })
+}
+
+/// Reads the record of the previous build, if there is one.
+pub fn read_record(path: &Path) -> Option<BuildRecord> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+pub fn write_record(path: &Path, record: &BuildRecord) -> Result<(), String> {
+    let content = serde_json::to_vec(record).expect("Expected the record to be serializable");
+    std::fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| std::fs::write(path, content))
+        .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Summarizes the report for the terminal, listing the nodes that were not cached.
+pub fn summary(reports: &[NodeCacheReport]) -> String {
+    let count = |f: fn(&CacheStatus) -> bool| reports.iter().filter(|r| f(&r.status)).count();
+    let hits = count(|s| *s == CacheStatus::Hit);
+    let misses = count(|s| matches!(s, CacheStatus::Miss(_)));
+    let mut out = format!(
+        "Cache report: {} of {} nodes cached, {} rebuilt",
+        hits,
+        reports.len(),
+        misses
+    );
+    if count(|s| *s == CacheStatus::Unknown) > 0 {
+        out.push_str(", there is no previous build of this query to compare with");
+    }
+    out.push('\n');
+    for r in reports.iter() {
+        if let CacheStatus::Miss(reason) = r.status {
+            // The nodes that are only rebuilt because an image they build on was are
+            // left out, the first rebuilt node of each chain tells why.
+            if reason == MissReason::ChangedInput {
+                continue;
+            }
+            out.push_str(&format!("  n_{} {}: {}", r.node, r.instruction, reason));
+            if let Some(source) = &r.source_literal {
+                out.push_str(&format!(", in {}", source));
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn plan(command: &str) -> BuildPlan {
+        testing::plan(
+            &format!(
+                "base :- from(\"alpine\"), run(\"apk add make\").\n\
+                 app :- base, copy(\"src\", \"/src\"), run(\"{}\").",
+                command
+            ),
+            "app",
+        )
+    }
+
+    fn statuses(plan: &BuildPlan, current: &BuildRecord, previous: &BuildRecord) -> Vec<String> {
+        report(plan, current, Some(previous), false, &BTreeMap::new())
+            .into_iter()
+            .map(|r| {
+                format!(
+                    "{} {}",
+                    r.instruction,
+                    serde_json::to_value(r.status).unwrap()
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cache_report() {
+        let context = crate::buildkit::AutoRmTmpDir::new_empty().unwrap();
+        std::fs::create_dir(context.path().join("src")).unwrap();
+        std::fs::write(context.path().join("src/main.c"), "int main;").unwrap();
+        let base_images = BTreeMap::new();
+
+        let first = plan("make");
+        let first_record = record(&first, &base_images, context.path());
+        let reports = report(&first, &first_record, None, false, &base_images);
+        assert!(reports.iter().all(|r| r.status == CacheStatus::Unknown));
+        assert!(report(&first, &first_record, None, true, &base_images)
+            .iter()
+            .all(|r| r.status == CacheStatus::Miss(MissReason::NoCache)));
+        assert!(statuses(&first, &first_record, &first_record)
+            .iter()
+            .all(|s| s.ends_with("{\"status\":\"hit\"}")));
+
+        // Only the changed command, and what builds on it, is rebuilt.
+        let second = plan("make all");
+        let second_record = record(&second, &base_images, context.path());
+        let second_reports = report(
+            &second,
+            &second_record,
+            Some(&first_record),
+            false,
+            &base_images,
+        );
+        let changed = second_reports
+            .iter()
+            .find(|r| r.status == CacheStatus::Miss(MissReason::ChangedInstruction))
+            .unwrap();
+        assert_eq!(changed.instruction, "run");
+        assert!(second_reports
+            .iter()
+            .filter(|r| r.node < changed.node)
+            .all(|r| r.status == CacheStatus::Hit));
+        assert!(summary(&second_reports).contains("the instruction changed"));
+
+        std::fs::write(context.path().join("src/main.c"), "int main();").unwrap();
+        let third_record = record(&first, &base_images, context.path());
+        assert_eq!(
+            statuses(&first, &third_record, &first_record)
+                .into_iter()
+                .filter(|s| s.contains("miss"))
+                .collect::<Vec<_>>()[0],
+            "copy {\"reason\":\"changed-files\",\"status\":\"miss\"}"
+        );
+    }
+}
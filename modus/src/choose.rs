@@ -0,0 +1,136 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus build --choose`, which asks which of the solutions of a query to build.
+
+use std::io::{BufRead, Write};
+
+use modus_lib::imagegen::{BuildPlan, Output};
+use modus_lib::logic::Literal;
+use modus_lib::modusfile::{Expression, ModusTerm};
+
+/// The values that the variables of the query are bound to in the output literal, e.g.
+/// `X = "3.11"` for the query `app(X)` and the output `app("3.11")`. Only queries that
+/// are a single literal of the same predicate are matched against the output.
+fn bindings(query: &Expression, output: &Literal) -> Vec<String> {
+    match query {
+        Expression::Literal(q)
+            if q.predicate == output.predicate && q.args.len() == output.args.len() =>
+        {
+            q.args
+                .iter()
+                .zip(output.args.iter())
+                .filter_map(|(q_arg, arg)| match q_arg {
+                    ModusTerm::UserVariable(v) => Some(format!("{} = {}", v, arg)),
+                    _ => None,
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn describe(query: &Expression, output: &Output) -> String {
+    match &output.source_literal {
+        Some(literal) => {
+            let bindings = bindings(query, literal);
+            if bindings.is_empty() {
+                literal.to_string()
+            } else {
+                format!("{}  ({})", literal, bindings.join(", "))
+            }
+        }
+        None => format!("n_{}", output.node),
+    }
+}
+
+/// Asks which output of the plan to build, reading the answer from `input`, and returns
+/// a plan with only that output. A plan with a single output is returned as is.
+pub fn choose_output<R: BufRead, W: Write>(
+    plan: &BuildPlan,
+    query: &Expression,
+    mut input: R,
+    mut prompt: W,
+) -> Result<BuildPlan, String> {
+    if plan.outputs.len() <= 1 {
+        return Ok(plan.clone());
+    }
+    let write_err = |e: std::io::Error| format!("Unable to write the prompt: {}", e);
+    writeln!(prompt, "The query has {} solutions:", plan.outputs.len()).map_err(write_err)?;
+    for (i, output) in plan.outputs.iter().enumerate() {
+        writeln!(prompt, "  {}) {}", i + 1, describe(query, output)).map_err(write_err)?;
+    }
+    loop {
+        write!(
+            prompt,
+            "Choose the image to build [1-{}]: ",
+            plan.outputs.len()
+        )
+        .map_err(write_err)?;
+        prompt.flush().map_err(write_err)?;
+        let mut answer = String::new();
+        if input
+            .read_line(&mut answer)
+            .map_err(|e| format!("Unable to read the choice: {}", e))?
+            == 0
+        {
+            return Err("No image was chosen.".to_owned());
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=plan.outputs.len()).contains(&n) => {
+                return Ok(BuildPlan {
+                    outputs: vec![plan.outputs[n - 1].clone()],
+                    ..plan.clone()
+                }
+                .without_unreachable_nodes());
+            }
+            _ => writeln!(
+                prompt,
+                "Expected a number from 1 to {}.",
+                plan.outputs.len()
+            )
+            .map_err(write_err)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn choose_one_solution() {
+        let plan = testing::plan(
+            "app(V) :- (V = \"3.9\" ; V = \"3.11\"), from(f\"python:${V}\"), run(\"make\").",
+            "app(X)",
+        );
+        let query: Expression = "app(X)".parse().unwrap();
+        assert_eq!(plan.outputs.len(), 2);
+
+        let mut prompt = Vec::new();
+        let chosen = choose_output(&plan, &query, "3\n2\n".as_bytes(), &mut prompt).unwrap();
+        let prompt = String::from_utf8(prompt).unwrap();
+        let second = plan.outputs[1].source_literal.as_ref().unwrap();
+        assert!(prompt.contains(&format!("  2) {}  (X = {})\n", second, second.args[0])));
+        assert!(prompt.contains("Expected a number from 1 to 2."));
+        assert_eq!(chosen.outputs.len(), 1);
+        assert_eq!(chosen.outputs[0].source_literal.as_ref(), Some(second));
+        assert!(chosen.nodes.len() < plan.nodes.len());
+
+        assert!(choose_output(&plan, &query, "".as_bytes(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus debug`, which builds the image up to a node of the build plan and starts an
+//! interactive container from it.
+
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    process::{Command, ExitStatus},
+    str::FromStr,
+};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use modus_lib::imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId, Output};
+
+use crate::buildkit::{self, BuildError, BuildOptions, DockerBuildOptions};
+use crate::reporting::Profiling;
+
+/// The repository that the state a failed command ran in is tagged in, as `n_<node>`.
+pub const FAILED_STATE_REPOSITORY: &str = "modus-debug";
+
+/// Selects a node of the build plan, either by its index, e.g. `3` or `n_3` as in
+/// `modus plan` and the transpiled Dockerfile, or by the head of the rule that produced
+/// it, e.g. `base` or `app("3.11")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeSelector {
+    Index(NodeId),
+    Source(String),
+}
+
+impl FromStr for NodeSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Expected a node index or a rule head.".to_owned());
+        }
+        Ok(match s.strip_prefix("n_").unwrap_or(s).parse() {
+            Ok(index) => NodeSelector::Index(index),
+            Err(_) => NodeSelector::Source(s.to_owned()),
+        })
+    }
+}
+
+impl NodeSelector {
+    /// Finds the selected node. An output is selected by its literal, e.g. `app("3.11")`.
+    /// Otherwise, of the nodes produced by a rule, the last one is selected, which is the
+    /// image at the end of the rule's body, if the rule was only used once.
+    pub fn select(&self, plan: &BuildPlan) -> Result<NodeId, String> {
+        let source = match self {
+            NodeSelector::Index(index) if *index < plan.nodes.len() => return Ok(*index),
+            NodeSelector::Index(index) => {
+                return Err(format!(
+                    "The plan has no node {}, it has {} nodes.",
+                    index,
+                    plan.nodes.len()
+                ))
+            }
+            NodeSelector::Source(source) => source,
+        };
+        if let Some(output) = plan.outputs.iter().find(|o| {
+            o.source_literal
+                .as_ref()
+                .is_some_and(|l| l.to_string() == *source)
+        }) {
+            return Ok(output.node);
+        }
+        let matching = (0..plan.nodes.len())
+            .filter(|&node| {
+                plan.source(node).is_some_and(|head| {
+                    head.to_string() == *source || head.predicate.0 == source.as_str()
+                })
+            })
+            .collect::<BTreeSet<_>>();
+        // Drop the nodes that other matching nodes build on, leaving the ends of the rules.
+        let mut last = matching.clone();
+        for &node in matching.iter() {
+            let mut parent = plan.nodes[node].parent();
+            while let Some(p) = parent {
+                last.remove(&p);
+                parent = plan.nodes[p].parent();
+            }
+        }
+        match last.len() {
+            0 => Err(format!("No node of the plan was produced by {}.", source)),
+            1 => Ok(*last.iter().next().unwrap()),
+            _ => Err(format!(
+                "{} was used several times, choose one of the nodes {}.",
+                source,
+                last.iter()
+                    .map(|n| format!("n_{}", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+/// Returns a plan that only builds the given node.
+pub fn plan_up_to(plan: &BuildPlan, node: NodeId) -> BuildPlan {
+    BuildPlan {
+        outputs: vec![Output {
+            node,
+            source_literal: plan.source(node).cloned(),
+            annotations: Default::default(),
+            tags: Vec::new(),
+        }],
+        ..plan.clone()
+    }
+    .without_unreachable_nodes()
+}
+
+/// Starts an interactive container running `shell` from the image, which is removed
+/// when the shell exits.
+pub fn run_shell(image_id: &str, shell: &str) -> Result<ExitStatus, std::io::Error> {
+    Command::new("docker")
+        .args(["run", "--rm", "-it", "--entrypoint", shell, image_id])
+        .status()
+}
+
+/// The commands a node runs, if any.
+fn commands(node: &BuildNode) -> Vec<&str> {
+    match node {
+        BuildNode::Run { command, .. } => vec![command],
+        BuildNode::Merge(merge) => merge
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                MergeOperation::Run { command, .. } => Some(command.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The Modus expression that a node is built by, e.g. `run("make")`.
+pub fn expression(node: &BuildNode) -> String {
+    match node {
+        BuildNode::From { display_name, .. } => format!("from({:?})", display_name),
+        BuildNode::FromOciLayout { .. } | BuildNode::FromDockerTar { .. } => {
+            format!("from({})", node.local_image().unwrap())
+        }
+        BuildNode::Run { command, .. } => format!("run({:?})", command),
+        BuildNode::CopyFromImage {
+            src_path, dst_path, ..
+        } => format!("...::copy({:?}, {:?})", src_path, dst_path),
+        BuildNode::CopyFromLocal {
+            src_path, dst_path, ..
+        } => format!("copy({:?}, {:?})", src_path, dst_path),
+        BuildNode::GitCheckout {
+            url, rev, dst_path, ..
+        } => format!("git_checkout({:?}, {:?}, {:?})", url, rev, dst_path),
+        BuildNode::Merge(_) => format!(
+            "({})::merge",
+            commands(node)
+                .iter()
+                .map(|c| format!("run({:?})", c))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        _ => node.name().to_owned(),
+    }
+}
+
+/// The error reported when building a node fails, which names the expression that built
+/// it and points at the rule that it is in.
+pub fn failed_node_diagnostic(plan: &BuildPlan, node: NodeId) -> Diagnostic<()> {
+    let expression = expression(&plan.nodes[node]);
+    let source = plan.source(node);
+    let diag = Diagnostic::error().with_message(match source {
+        Some(head) => format!("{} failed, in the rule for {}", expression, head),
+        None => format!("{} failed", expression),
+    });
+    let diag = match source.and_then(|l| l.position.as_ref()) {
+        Some(pos) => diag.with_labels(vec![Label::primary(
+            (),
+            pos.offset..pos.offset + pos.length,
+        )
+        .with_message(format!("node n_{} is built by this rule", node))]),
+        None => diag,
+    };
+    match plan.nodes[node].parent() {
+        Some(parent) => diag.with_notes(vec![format!(
+            "`modus debug` with the node n_{} starts a shell in the image that it is built on",
+            parent
+        )]),
+        None => diag,
+    }
+}
+
+/// A node whose command failed, and the tag of the state that it ran in.
+#[derive(Debug, Clone)]
+pub struct FailedRun {
+    pub node: NodeId,
+    pub commands: Vec<String>,
+    pub tag: String,
+}
+
+/// Finds the node whose command made the build of the plan fail, by building the plan up
+/// to each node that runs a command in turn, and tags the state that it ran in. The steps
+/// that succeeded before are cached, so mostly the failed one is run again.
+///
+/// Returns `None` if no command failed, e.g. because a copy did.
+pub fn export_failed_state<P: AsRef<Path>>(
+    plan: &BuildPlan,
+    context: P,
+    options: &BuildOptions,
+) -> Result<Option<FailedRun>, BuildError> {
+    let options = BuildOptions {
+        docker_build_options: DockerBuildOptions {
+            quiet: true,
+            // The state a command failed in is tagged in the local image store.
+            load: true,
+            ..options.docker_build_options.clone()
+        },
+        ..options.clone()
+    };
+    let build = |node| {
+        buildkit::build(
+            plan_up_to(plan, node),
+            context.as_ref(),
+            &options,
+            &mut Profiling::default(),
+        )
+    };
+    for node in plan.topological_order() {
+        let commands = commands(&plan.nodes[node]);
+        if commands.is_empty() {
+            continue;
+        }
+        match build(node) {
+            Ok(_) => continue,
+            Err(BuildError::DockerBuildFailed(..)) => {}
+            Err(e) => return Err(e),
+        }
+        let parent = plan.nodes[node]
+            .parent()
+            .expect("Expected a node that runs commands to have a parent");
+        let image_id = match build(parent) {
+            Ok(built) => built.image_ids[0].to_owned(),
+            // The command did not run, something before it failed.
+            Err(BuildError::DockerBuildFailed(..)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let tag = format!("{}:n_{}", FAILED_STATE_REPOSITORY, node);
+        let status = Command::new("docker")
+            .args(["tag", &image_id, &tag])
+            .status()?;
+        if !status.success() {
+            return Err(BuildError::DockerTagFailed(image_id, tag, status));
+        }
+        return Ok(Some(FailedRun {
+            node,
+            commands: commands.into_iter().map(ToOwned::to_owned).collect(),
+            tag,
+        }));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use modus_lib::imagegen::BuildNode;
+
+    #[test]
+    fn select_nodes() {
+        let plan = testing::plan(
+            "base :- from(\"alpine\"), run(\"apk add make\").\n\
+             app(V) :- (V = \"1\" ; V = \"2\"), base, run(f\"make V=${V}\").",
+            "app(X)",
+        );
+
+        assert_eq!("n_2".parse(), Ok(NodeSelector::Index(2)));
+        assert_eq!(
+            "app(\"1\")".parse(),
+            Ok(NodeSelector::Source("app(\"1\")".to_owned()))
+        );
+        assert!(NodeSelector::Index(plan.nodes.len()).select(&plan).is_err());
+        assert!(NodeSelector::Source("app".to_owned())
+            .select(&plan)
+            .is_err());
+        assert!(NodeSelector::Source("missing".to_owned())
+            .select(&plan)
+            .is_err());
+
+        let base = NodeSelector::Source("base".to_owned())
+            .select(&plan)
+            .unwrap();
+        let debug_plan = plan_up_to(&plan, base);
+        assert_eq!(debug_plan.outputs.len(), 1);
+        assert!(debug_plan
+            .nodes
+            .iter()
+            .all(|n| !matches!(n, BuildNode::Run { command, .. } if command.starts_with("make"))));
+
+        let app = NodeSelector::Source("app(\"2\")".to_owned())
+            .select(&plan)
+            .unwrap();
+        assert!(plan_up_to(&plan, app)
+            .nodes
+            .iter()
+            .any(|n| matches!(n, BuildNode::Run { command, .. } if command == "make V=2")));
+    }
+
+    #[test]
+    fn failed_node_names_its_rule() {
+        let source = "base :- from(\"alpine\"), run(\"apk add make\").\n\
+                      app :- base, (run(\"make\"), copy(\"a\", \"b\"))::merge.";
+        let plan = testing::plan(source, "app");
+        let node = |f: fn(&BuildNode) -> bool| plan.nodes.iter().position(f).unwrap();
+
+        let apk = node(|n| matches!(n, BuildNode::Run { .. }));
+        let diag = failed_node_diagnostic(&plan, apk);
+        assert_eq!(
+            diag.message,
+            "run(\"apk add make\") failed, in the rule for base"
+        );
+        assert!(source[diag.labels[0].range.clone()].starts_with("base"));
+        assert_eq!(diag.notes.len(), 1);
+
+        let merge = node(|n| matches!(n, BuildNode::Merge(_)));
+        assert_eq!(
+            failed_node_diagnostic(&plan, merge).message,
+            "(run(\"make\"))::merge failed, in the rule for app"
+        );
+        let from = node(|n| matches!(n, BuildNode::From { .. }));
+        assert!(failed_node_diagnostic(&plan, from).notes.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus doc`, which lists the predicates that a Modusfile defines with their
+//! documentation.
+
+use std::fmt::Write;
+
+use modus_lib::analysis::{Kind, ModusSemantics};
+use modus_lib::logic::{Predicate, Signature};
+use modus_lib::modusfile::Modusfile;
+use modus_lib::translate::translate_modusfile;
+use modus_lib::wellformed::check_grounded_variables;
+
+/// Lists the predicates defined in `source`, in the order of their first clause, with
+/// their kind, groundness and the doc comments of their clauses. Predicates of
+/// libraries are left out.
+pub fn doc(mf: &Modusfile, source: &str) -> String {
+    let mut signatures: Vec<Signature> = Vec::new();
+    for c in
+        mf.0.iter()
+            .filter(|c| c.position.is_some() && !c.head.predicate.is_assertion())
+    {
+        let sig = Signature(c.head.predicate.clone(), c.head.args.len() as u32);
+        if !signatures.contains(&sig) {
+            signatures.push(sig);
+        }
+    }
+    let kinds = mf.kinds().pred_kind;
+    let grounded = check_grounded_variables(&translate_modusfile(mf)).unwrap_or_default();
+
+    let mut out = String::new();
+    for (i, sig) in signatures.iter().enumerate() {
+        let Signature(Predicate(name), arity) = sig;
+        if i > 0 {
+            writeln!(out).unwrap();
+        }
+        let kind = match kinds.get(&sig.0) {
+            Some(Kind::Image) => "image",
+            Some(Kind::Layer) => "layer",
+            Some(Kind::Logic) => "logic",
+            None => "unknown",
+        };
+        writeln!(out, "{}/{} ({} predicate)", name, arity, kind).unwrap();
+        if let Some(g) = grounded.get(sig) {
+            let args = g
+                .iter()
+                .map(|&g| if g { "-" } else { "+" })
+                .collect::<Vec<_>>();
+            writeln!(out, "  {}({})", name, args.join(", ")).unwrap();
+        }
+        let docs =
+            mf.0.iter()
+                .filter(|c| c.head.predicate == sig.0 && c.head.args.len() as u32 == *arity)
+                .filter_map(|c| c.doc(source))
+                .collect::<Vec<_>>();
+        for line in docs.join("\n\n").lines() {
+            writeln!(out, "  {}", line).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_predicates() {
+        let source = ":- use(stdlib).\n\
+                      ## The base image.\n\
+                      base(V) :- from(f\"alpine:${V}\").\n\
+                      version(\"3.15\").\n\
+                      ## Builds the app.\n\
+                      app :- version(V), base(V), run(\"make\").\n";
+        let mf: Modusfile = source.parse().unwrap();
+
+        assert_eq!(
+            doc(&mf, source),
+            "base/1 (image predicate)\n  base(-)\n  The base image.\n\n\
+             version/1 (logic predicate)\n  version(-)\n\n\
+             app/0 (image predicate)\n  app()\n  Builds the app.\n"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Exit codes of the modus CLI, one per class of failure, so that scripts can
+//! react to failures without parsing stderr.
+
+use codespan_reporting::diagnostic::{Diagnostic, Severity};
+use modus_lib::sld::{
+    CANCELLED_CODE, MAXIMUM_DEPTH_EXCEEDED_CODE, RESOLUTION_ERROR_CODES,
+    RESOURCE_LIMIT_EXCEEDED_CODE, UNKNOWN_PREDICATE_CODE,
+};
+use modus_lib::ModusError;
+
+use crate::buildkit::BuildError;
+use crate::lock::LockError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Any failure not covered by another code, e.g. unable to read a file.
+    Other = 1,
+    // 2 is used by clap for invalid command line arguments.
+    ParseError = 3,
+    WellformednessError = 4,
+    Unsatisfiable = 5,
+    SolverBudgetExceeded = 6,
+    BuildFailed = 7,
+    RegistryError = 8,
+    PolicyViolation = 9,
+    AssertionFailed = 10,
+    NotReproducible = 11,
+    Interrupted = 130,
+}
+
+/// A description of the exit codes, for the CLI help.
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success
+    1    Other errors
+    2    Invalid command line arguments
+    3    Parse error in the Modusfile or query
+    4    Wellformedness error, e.g. a kind or groundness error, or an undefined predicate
+    5    The query has no solution
+    6    The solver exceeded its budget, e.g. the maximum depth, --timeout or --max-nodes
+    7    The build failed
+    8    A base image could not be resolved from the registry, or its signature was invalid
+    9    The build violates a policy, see --policy and --policy-rules
+    10   An assertion does not hold, see modus test and the ?- query directives
+    11   A rebuilt image differs from the recorded one, see modus verify
+    130  Interrupted";
+
+impl ExitCode {
+    pub fn from_error(e: &ModusError) -> ExitCode {
+        match e {
+            ModusError::Parse(_) => ExitCode::ParseError,
+            ModusError::Wellformedness(_) | ModusError::Imagegen(_) => {
+                ExitCode::WellformednessError
+            }
+            ModusError::Solving(diags) => ExitCode::from_diagnostics(diags),
+            ModusError::Registry(_) => ExitCode::RegistryError,
+            ModusError::Cancelled => ExitCode::Interrupted,
+            ModusError::Build(e) => e
+                .downcast_ref::<BuildError>()
+                .map_or(ExitCode::BuildFailed, ExitCode::from_build_error),
+        }
+    }
+
+    /// Classifies errors reported during planning. Resolution errors mean the
+    /// query could not be solved, except for undefined predicates, which are
+    /// wellformedness errors like anything else.
+    pub fn from_diagnostics(diags: &[Diagnostic<()>]) -> ExitCode {
+        let errors = diags
+            .iter()
+            .filter(|d| d.severity >= Severity::Warning)
+            .collect::<Vec<_>>();
+        let has_code = |codes: &[&str]| {
+            errors
+                .iter()
+                .any(|d| d.code.as_deref().is_some_and(|c| codes.contains(&c)))
+        };
+        if has_code(&[CANCELLED_CODE]) {
+            ExitCode::Interrupted
+        } else if has_code(&[MAXIMUM_DEPTH_EXCEEDED_CODE, RESOURCE_LIMIT_EXCEEDED_CODE]) {
+            ExitCode::SolverBudgetExceeded
+        } else if has_code(&[UNKNOWN_PREDICATE_CODE]) {
+            ExitCode::WellformednessError
+        } else if has_code(RESOLUTION_ERROR_CODES) {
+            ExitCode::Unsatisfiable
+        } else {
+            ExitCode::WellformednessError
+        }
+    }
+
+    pub fn from_build_error(e: &BuildError) -> ExitCode {
+        match e {
+            BuildError::CouldNotResolveImage(..)
+            | BuildError::ImageSignatureInvalid(..)
+            | BuildError::DockerPushFailed(..) => ExitCode::RegistryError,
+            BuildError::Interrupted => ExitCode::Interrupted,
+            BuildError::DockerBuildFailed(..) | BuildError::DockerTagFailed(..) => {
+                ExitCode::BuildFailed
+            }
+            _ => ExitCode::Other,
+        }
+    }
+
+    pub fn from_lock_error(e: &LockError) -> ExitCode {
+        match e {
+            LockError::ResolveFailed(..) | LockError::InvalidOutput(..) => ExitCode::RegistryError,
+            _ => ExitCode::Other,
+        }
+    }
+
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_diagnostics() {
+        let depth = Diagnostic::warning().with_code(MAXIMUM_DEPTH_EXCEEDED_CODE);
+        let rules = Diagnostic::warning().with_code("insufficient-rules");
+        let other = Diagnostic::error().with_message("There must be exactly one image predicate");
+
+        assert_eq!(
+            ExitCode::from_diagnostics(&[rules.clone(), depth]),
+            ExitCode::SolverBudgetExceeded
+        );
+        assert_eq!(
+            ExitCode::from_diagnostics(&[
+                rules.clone(),
+                Diagnostic::error().with_code(RESOURCE_LIMIT_EXCEEDED_CODE)
+            ]),
+            ExitCode::SolverBudgetExceeded
+        );
+        assert_eq!(
+            ExitCode::from_diagnostics(std::slice::from_ref(&rules)),
+            ExitCode::Unsatisfiable
+        );
+        assert_eq!(
+            ExitCode::from_diagnostics(&[
+                rules,
+                Diagnostic::error().with_code(UNKNOWN_PREDICATE_CODE)
+            ]),
+            ExitCode::WellformednessError
+        );
+        assert_eq!(
+            ExitCode::from_diagnostics(&[other]),
+            ExitCode::WellformednessError
+        );
+    }
+
+    #[test]
+    fn classify_errors() {
+        let depth = Diagnostic::warning().with_code(MAXIMUM_DEPTH_EXCEEDED_CODE);
+        assert_eq!(
+            ExitCode::from_error(&ModusError::Solving(vec![depth])),
+            ExitCode::SolverBudgetExceeded
+        );
+        assert_eq!(
+            ExitCode::from_error(&ModusError::Parse(Vec::new())),
+            ExitCode::ParseError
+        );
+        assert_eq!(
+            ExitCode::from_error(&BuildError::Interrupted.into()),
+            ExitCode::Interrupted
+        );
+        assert_eq!(
+            ExitCode::from_error(
+                &BuildError::CouldNotResolveImage("alpine".to_owned(), Default::default()).into()
+            ),
+            ExitCode::RegistryError
+        );
+    }
+}
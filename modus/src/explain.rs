@@ -0,0 +1,165 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus explain`, which describes how a predicate is defined and used.
+
+use std::{collections::BTreeSet, fmt, fmt::Write};
+
+use codespan_reporting::files::{Files, SimpleFile};
+use modus_lib::analysis::{Kind, ModusSemantics};
+use modus_lib::builtin::builtin_predicate_names;
+use modus_lib::logic::SpannedPosition;
+use modus_lib::modusfile::Modusfile;
+use modus_lib::translate::translate_modusfile;
+use modus_lib::wellformed::check_grounded_variables;
+
+fn location<N: fmt::Display + Clone, S: AsRef<str>>(
+    file: &SimpleFile<N, S>,
+    position: Option<&SpannedPosition>,
+) -> String {
+    match position.and_then(|p| file.line_index((), p.offset).ok()) {
+        Some(line) => format!("{}:{}", file.name(), line + 1),
+        None => file.name().to_string(),
+    }
+}
+
+/// Describes the clauses that define the predicate, its kind and groundness, and the
+/// predicates that call it.
+pub fn explain<N: fmt::Display + Clone, S: AsRef<str>>(
+    mf: &Modusfile,
+    file: &SimpleFile<N, S>,
+    predicate: &str,
+) -> Result<String, String> {
+    let clauses =
+        mf.0.iter()
+            .filter(|c| c.head.predicate.0 == predicate)
+            .collect::<Vec<_>>();
+    let callers =
+        mf.0.iter()
+            .filter(|c| {
+                c.body.as_ref().is_some_and(|body| {
+                    body.literals()
+                        .iter()
+                        .any(|l| l.predicate.0 == predicate || l.findall_goal() == Some(predicate))
+                })
+            })
+            .map(|c| {
+                (
+                    location(file, c.head.position.as_ref()),
+                    format!("{}/{}", c.head.predicate, c.head.args.len()),
+                )
+            })
+            .collect::<BTreeSet<_>>();
+    let is_builtin = builtin_predicate_names().contains(&predicate);
+    if clauses.is_empty() && callers.is_empty() && !is_builtin {
+        return Err(format!(
+            "No clause defines or uses the predicate {}.",
+            predicate
+        ));
+    }
+
+    let mut out = String::new();
+    let kind =
+        mf.kinds().pred_kind.into_iter().find_map(
+            |(p, k)| {
+                if p.0 == predicate {
+                    Some(k)
+                } else {
+                    None
+                }
+            },
+        );
+    let kind = match kind {
+        Some(Kind::Image) => "image",
+        Some(Kind::Layer) => "layer",
+        Some(Kind::Logic) => "logic",
+        None => "unknown",
+    };
+    writeln!(out, "{} ({} predicate)", predicate, kind).unwrap();
+
+    if is_builtin {
+        writeln!(out, "\n{} is a builtin predicate.", predicate).unwrap();
+    }
+
+    if !clauses.is_empty() {
+        // A `+` argument has to be ground when the predicate is called, a `-` argument
+        // is ground once it has been proven.
+        let mut modes = check_grounded_variables(&translate_modusfile(mf))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(sig, _)| sig.0 .0 == predicate)
+            .map(|(_, grounded)| {
+                let args = grounded
+                    .iter()
+                    .map(|&g| if g { "-" } else { "+" })
+                    .collect::<Vec<_>>();
+                format!("{}({})", predicate, args.join(", "))
+            })
+            .collect::<Vec<_>>();
+        modes.sort();
+        writeln!(
+            out,
+            "\nGroundness (+ must be ground when called, - is ground once proven):"
+        )
+        .unwrap();
+        for mode in modes {
+            writeln!(out, "  {}", mode).unwrap();
+        }
+
+        writeln!(out, "\nDefined by:").unwrap();
+        for c in clauses {
+            writeln!(out, "  {}: {}", location(file, c.head.position.as_ref()), c).unwrap();
+        }
+    }
+
+    if !callers.is_empty() {
+        writeln!(out, "\nCalled by:").unwrap();
+        for (location, caller) in callers {
+            writeln!(out, "  {}: {}", location, caller).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explain_predicate() {
+        let source = "base(V) :- from(f\"alpine:${V}\").\n\
+                      version(\"3.15\").\n\
+                      app :- version(V), base(V), run(\"make\").\n\
+                      tagged(T) :- from(\"alpine\").";
+        let mf: Modusfile = source.parse().unwrap();
+        let file = SimpleFile::new("Modusfile", source);
+
+        let out = explain(&mf, &file, "base").unwrap();
+        assert!(out.starts_with("base (image predicate)"));
+        assert!(out.contains("  base(-)\n"));
+        assert!(out.contains("  Modusfile:1: base(V) :- from("));
+        assert!(out.contains("Called by:\n  Modusfile:3: app/0\n"));
+
+        let out = explain(&mf, &file, "version").unwrap();
+        assert!(out.contains("  version(-)\n"));
+        let out = explain(&mf, &file, "tagged").unwrap();
+        assert!(out.contains("  tagged(+)\n"));
+        assert!(!out.contains("Called by:"));
+
+        assert!(explain(&mf, &file, "run").unwrap().contains("is a builtin"));
+        assert!(explain(&mf, &file, "missing").is_err());
+    }
+}
@@ -0,0 +1,511 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Generation of grammars for editor syntax highlighting.
+//!
+//! Both grammars are built from the token definitions in
+//! [`modus_lib::modusfile::tokens`] and the builtin predicates known to the
+//! solver, so they follow the language as the parser changes.
+
+use modus_lib::{builtin, modusfile::tokens::*};
+use serde_json::{json, Value};
+
+/// Escapes a token for use in a regular expression.
+fn re(token: &str) -> String {
+    let mut res = String::new();
+    for c in token.chars() {
+        if "\\^$.|?*+()[]{}/".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+/// A regular expression character class matching any of the given characters.
+fn char_class(chars: &str) -> String {
+    let mut res = String::from("[");
+    for c in chars.chars() {
+        match c {
+            '\n' => res.push_str("\\n"),
+            '\\' | ']' | '^' | '-' => {
+                res.push('\\');
+                res.push(c);
+            }
+            _ => res.push(c),
+        }
+    }
+    res.push(']');
+    res
+}
+
+fn escape_pattern(escape_chars: &str) -> Value {
+    json!({
+        "name": "constant.character.escape.modus",
+        "match": format!(
+            "{}(?:{}|{})",
+            re(&ESCAPE.to_string()),
+            char_class(escape_chars),
+            NUMERIC_ESCAPE_REGEX
+        ),
+    })
+}
+
+fn operator_names() -> Vec<&'static str> {
+    let mut names = builtin::OPERATOR_KIND_MAP
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names
+}
+
+/// A TextMate grammar, as used by VS Code, Sublime Text and others.
+pub fn textmate_grammar() -> Value {
+    let interpolation = json!({
+        "name": "meta.interpolation.modus",
+        "begin": re(INTERPOLATION_START),
+        "end": re(INTERPOLATION_END),
+        "beginCaptures": { "0": { "name": "punctuation.section.interpolation.begin.modus" } },
+        "endCaptures": { "0": { "name": "punctuation.section.interpolation.end.modus" } },
+        "patterns": [{ "include": "#variable" }],
+    });
+
+    json!({
+        "$schema": "https://raw.githubusercontent.com/martinring/tmlanguage/master/tmlanguage.json",
+        "name": "Modus",
+        "scopeName": "source.modus",
+        "fileTypes": ["Modusfile"],
+        "patterns": [
+            // before comments, as the hashes of a raw string are not a comment
+            { "include": "#prefixed-raw-string" },
+            { "include": "#doc-comment" },
+            { "include": "#comment" },
+            { "include": "#format-string" },
+            { "include": "#raw-string" },
+            { "include": "#string" },
+            { "include": "#operator-application" },
+            { "include": "#builtin" },
+            { "include": "#predicate" },
+            { "include": "#keyword" },
+            { "include": "#boolean" },
+            { "include": "#number" },
+            { "include": "#variable" },
+        ],
+        "repository": {
+            "doc-comment": {
+                "name": "comment.line.documentation.modus",
+                "match": format!("{}.*$", re(DOC_COMMENT)),
+            },
+            "comment": {
+                "name": "comment.line.number-sign.modus",
+                "match": format!("{}.*$", re(COMMENT)),
+            },
+            "string": {
+                "name": "string.quoted.double.modus",
+                "begin": re(STRING_DELIMITER),
+                "end": re(STRING_DELIMITER),
+                "patterns": [escape_pattern(STRING_ESCAPE_CHARS)],
+            },
+            "raw-string": {
+                "name": "string.quoted.triple.modus",
+                "begin": re(RAW_STRING_DELIMITER),
+                "end": re(RAW_STRING_DELIMITER),
+            },
+            "prefixed-raw-string": {
+                "name": "string.quoted.raw.modus",
+                "begin": format!("\\b{}({}*){}", re(RAW_STRING_PREFIX), re(&RAW_STRING_HASH.to_string()), re(STRING_DELIMITER)),
+                "end": format!("{}\\1", re(STRING_DELIMITER)),
+            },
+            "format-string": {
+                "name": "string.interpolated.modus",
+                "begin": re(FORMAT_STRING_START),
+                "end": re(STRING_DELIMITER),
+                "patterns": [escape_pattern(FORMAT_STRING_ESCAPE_CHARS), interpolation],
+            },
+            "operator-application": {
+                "match": format!("({})\\s*({})", re(OPERATOR_APPLICATION), IDENTIFIER_REGEX),
+                "captures": {
+                    "1": { "name": "keyword.operator.application.modus" },
+                    "2": {
+                        "patterns": [
+                            {
+                                "name": "support.function.operator.modus",
+                                "match": format!("^({})$", operator_names().join("|")),
+                            },
+                            { "name": "entity.name.function.operator.modus", "match": ".+" },
+                        ]
+                    },
+                },
+            },
+            "builtin": {
+                "name": "support.function.builtin.modus",
+                "match": format!(
+                    "\\b({})\\b(?=\\s*{})",
+                    builtin::builtin_predicate_names()
+                        .into_iter()
+                        .chain([FINDALL])
+                        .collect::<Vec<_>>()
+                        .join("|"),
+                    re(ARGS_START)
+                ),
+            },
+            "predicate": {
+                "name": "entity.name.function.modus",
+                "match": format!("{}(?=\\s*{})", IDENTIFIER_REGEX, re(ARGS_START)),
+            },
+            "keyword": {
+                "patterns": [
+                    {
+                        "name": "keyword.control.directive.modus",
+                        "match": format!("{}\\s*({}|{}|{}|{})\\b", re(RULE_NECK), USE, PRELUDE, INCLUDE, IMPORT),
+                    },
+                    { "name": "keyword.operator.rule.modus", "match": re(RULE_NECK) },
+                    { "name": "keyword.control.query.modus", "match": re(QUERY_NECK) },
+                    {
+                        "name": "keyword.operator.comparison.modus",
+                        "match": ([NOT_EQUALS, GREATER_EQUALS, LESS_EQUALS, EQUALS, GREATER, LESS]
+                            .map(re)
+                            .join("|")),
+                    },
+                    { "name": "keyword.operator.negation.modus", "match": re(&NEGATION.to_string()) },
+                    {
+                        "name": "punctuation.separator.modus",
+                        "match": format!("{}|{}|{}", re(IF_THEN), re(AND), re(OR)),
+                    },
+                    { "name": "punctuation.terminator.modus", "match": re(&CLAUSE_END.to_string()) },
+                ]
+            },
+            "boolean": {
+                "name": "constant.language.boolean.modus",
+                "match": format!("\\b({}|{})\\b", TRUE, FALSE),
+            },
+            "number": {
+                "name": "constant.numeric.modus",
+                "match": format!("(?<![A-Za-z0-9_]){}", NUMBER_REGEX),
+            },
+            "variable": {
+                "name": "variable.other.modus",
+                "match": IDENTIFIER_REGEX,
+            },
+        },
+    })
+}
+
+/// A JavaScript string literal.
+fn js(s: &str) -> String {
+    Value::from(s).to_string()
+}
+
+/// A tree-sitter grammar, i.e. the contents of a `grammar.js`.
+pub fn tree_sitter_grammar() -> String {
+    format!(
+        r#"// Generated by `modus grammar --format tree-sitter`, do not edit.
+
+const commaSep1 = (rule) => seq(rule, repeat(seq({arg_sep}, rule)));
+
+module.exports = grammar({{
+  name: 'modus',
+
+  extras: ($) => [/\s/, $.comment],
+
+  word: ($) => $.identifier,
+
+  conflicts: ($) => [[$.literal, $.variable]],
+
+  rules: {{
+    source_file: ($) => repeat($._clause),
+
+    _clause: ($) => choice($.directive, $.query, $.fact, $.rule),
+
+    query: ($) => seq({query_neck}, field('goal', $._expression), {clause_end}),
+
+    directive: ($) =>
+      choice(
+        seq(
+          {rule_neck},
+          field('name', choice({use_}, {prelude})),
+          {args_start},
+          field('library', choice($.string, $.identifier)),
+          {args_end},
+          {clause_end},
+        ),
+        seq(
+          {rule_neck},
+          field('name', {include}),
+          {args_start},
+          field('url', $.string),
+          optional(seq({arg_sep}, {include_digest}, {equals}, field('digest', $.string))),
+          {args_end},
+          {clause_end},
+        ),
+        seq(
+          {rule_neck},
+          field('name', {import}),
+          {args_start},
+          field('path', $.string),
+          optional(seq({arg_sep}, {import_namespace}, {equals}, field('namespace', $.identifier))),
+          {args_end},
+          {clause_end},
+        ),
+      ),
+
+    fact: ($) => seq(field('head', $.literal), {clause_end}),
+
+    rule: ($) =>
+      seq(field('head', $.literal), {rule_neck}, field('body', $._expression), {clause_end}),
+
+    _expression: ($) => choice($.disjunction, $.conjunction, $._inner_expression),
+
+    disjunction: ($) => prec.left(1, seq($._expression, {or}, $._expression)),
+
+    conjunction: ($) => prec.left(2, seq($._expression, {and}, $._expression)),
+
+    _inner_expression: ($) =>
+      choice(
+        $.unification,
+        $.comparison,
+        $.operator_application,
+        $.findall,
+        $.literal,
+        $.parenthesized_expression,
+      ),
+
+    unification: ($) => seq($._term, choice({not_equals}, {equals}), $._term),
+
+    comparison: ($) =>
+      seq($._term, choice({greater_equals}, {less_equals}, {greater}, {less}), $._term),
+
+    operator_application: ($) =>
+      prec.left(
+        seq(
+          choice($.literal, $.parenthesized_expression, $.operator_application),
+          {op_app},
+          $.operator,
+        ),
+      ),
+
+    operator: ($) =>
+      seq(field('name', $.identifier), optional(seq({args_start}, commaSep1($._term), {args_end}))),
+
+    parenthesized_expression: ($) =>
+      seq(repeat({negation}), {args_start}, choice($.if_then_else, $._expression), {args_end}),
+
+    if_then_else: ($) =>
+      prec.right(
+        seq(
+          field('condition', $._expression),
+          {if_then},
+          field('then', $._expression),
+          optional(seq({or}, field('else', choice($.if_then_else, $._expression)))),
+        ),
+      ),
+
+    findall: ($) =>
+      prec(
+        1,
+        seq(
+          {findall},
+          {args_start},
+          field('template', $._term),
+          {arg_sep},
+          field('goal', $.literal),
+          {arg_sep},
+          field('list', $._term),
+          {args_end},
+        ),
+      ),
+
+    literal: ($) =>
+      seq(
+        repeat({negation}),
+        field('name', $.identifier),
+        optional(seq({args_start}, commaSep1($._term), {args_end})),
+      ),
+
+    _term: ($) =>
+      choice($.raw_string, $.string, $.format_string, $.number, $.boolean, $.named_context, $.local_image, $.list, $.variable),
+
+    list: ($) => seq({list_start}, optional(commaSep1($._term)), {list_end}),
+
+    variable: ($) => $.identifier,
+
+    string: ($) =>
+      seq(
+        {string_delimiter},
+        repeat(choice(token.immediate(prec(1, /[^"\\]+/)), $.escape_sequence)),
+        {string_delimiter},
+      ),
+
+    raw_string: ($) =>
+      choice(
+        token(seq({raw_string_delimiter}, /([^"]|"[^"]|""[^"])*/, {raw_string_delimiter})),
+        token(seq({raw_string_prefix}, {string_delimiter}, /[^"]*/, {string_delimiter})),
+        // only one hash, as the closing quote can't refer to the number of opening ones
+        token(seq({raw_string_prefix}, {raw_string_hash}, {string_delimiter}, /([^"]|"[^{raw_string_hash_char}])*/, {string_delimiter}, {raw_string_hash})),
+      ),
+
+    format_string: ($) =>
+      seq(
+        {format_string_start},
+        repeat(
+          choice(
+            token.immediate(prec(1, /[^"\\$]+/)),
+            $.interpolation,
+            token.immediate('$'),
+            $.format_escape_sequence,
+          ),
+        ),
+        {string_delimiter},
+      ),
+
+    interpolation: ($) => seq({interpolation_start}, $.variable, {interpolation_end}),
+
+    escape_sequence: ($) => token.immediate(seq({escape}, /{string_escape_chars}|{numeric_escape}/)),
+
+    format_escape_sequence: ($) =>
+      token.immediate(seq({escape}, /{format_string_escape_chars}|{numeric_escape}/)),
+
+    number: ($) => /{number}/,
+
+    boolean: ($) => choice({true_}, {false_}),
+
+    named_context: ($) => seq({named_context}, {args_start}, $.string, {args_end}),
+
+    local_image: ($) => seq(choice({oci_layout}, {docker_tar}), {args_start}, $.string, {args_end}),
+
+    identifier: ($) => /{identifier}/,
+
+    comment: ($) => token(seq({comment}, /.*/)),
+  }},
+}});
+"#,
+        arg_sep = js(ARG_SEPARATOR),
+        clause_end = js(&CLAUSE_END.to_string()),
+        rule_neck = js(RULE_NECK),
+        query_neck = js(QUERY_NECK),
+        use_ = js(USE),
+        prelude = js(PRELUDE),
+        include = js(INCLUDE),
+        include_digest = js(INCLUDE_DIGEST),
+        import = js(IMPORT),
+        import_namespace = js(IMPORT_NAMESPACE),
+        or = js(OR),
+        and = js(AND),
+        if_then = js(IF_THEN),
+        findall = js(FINDALL),
+        not_equals = js(NOT_EQUALS),
+        equals = js(EQUALS),
+        greater_equals = js(GREATER_EQUALS),
+        less_equals = js(LESS_EQUALS),
+        greater = js(GREATER),
+        less = js(LESS),
+        op_app = js(OPERATOR_APPLICATION),
+        args_start = js(ARGS_START),
+        args_end = js(ARGS_END),
+        negation = js(&NEGATION.to_string()),
+        list_start = js(LIST_START),
+        list_end = js(LIST_END),
+        string_delimiter = js(STRING_DELIMITER),
+        raw_string_delimiter = js(RAW_STRING_DELIMITER),
+        raw_string_prefix = js(RAW_STRING_PREFIX),
+        raw_string_hash = js(&RAW_STRING_HASH.to_string()),
+        raw_string_hash_char = RAW_STRING_HASH,
+        format_string_start = js(FORMAT_STRING_START),
+        interpolation_start = js(INTERPOLATION_START),
+        interpolation_end = js(INTERPOLATION_END),
+        escape = js(&ESCAPE.to_string()),
+        string_escape_chars = char_class(STRING_ESCAPE_CHARS),
+        format_string_escape_chars = char_class(FORMAT_STRING_ESCAPE_CHARS),
+        numeric_escape = NUMERIC_ESCAPE_REGEX,
+        number = NUMBER_REGEX,
+        true_ = js(TRUE),
+        false_ = js(FALSE),
+        named_context = js(NAMED_CONTEXT),
+        oci_layout = js(OCI_LAYOUT),
+        docker_tar = js(DOCKER_TAR),
+        identifier = IDENTIFIER_REGEX,
+        comment = js(COMMENT),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn textmate_grammar_uses_parser_tokens() {
+        let grammar = textmate_grammar();
+        let repo = &grammar["repository"];
+        assert_eq!(repo["comment"]["match"], "#.*$");
+        assert_eq!(repo["doc-comment"]["match"], "##.*$");
+        assert_eq!(repo["format-string"]["begin"], "f\"");
+        assert_eq!(repo["prefixed-raw-string"]["begin"], "\\br(#*)\"");
+        assert_eq!(repo["prefixed-raw-string"]["end"], "\"\\1");
+        assert_eq!(
+            repo["string"]["patterns"][0]["match"],
+            "\\\\(?:[\"\\\\nrt0\\n]|x[0-9a-fA-F]{2}|u\\{[0-9a-fA-F]{1,6}\\})"
+        );
+        let builtins = repo["builtin"]["match"].as_str().unwrap();
+        assert!(builtins.contains("|run|"));
+        assert!(builtins.contains("|findall)"));
+        assert!(!builtins.contains("_operator_"));
+        let operators = repo["operator-application"]["captures"]["2"]["patterns"][0]["match"]
+            .as_str()
+            .unwrap();
+        assert!(operators.contains("|copy|"));
+        assert_eq!(
+            repo["keyword"]["patterns"][0]["name"],
+            "keyword.control.directive.modus"
+        );
+    }
+
+    #[test]
+    fn tree_sitter_grammar_uses_parser_tokens() {
+        let grammar = tree_sitter_grammar();
+        assert!(grammar
+            .contains(r#"seq(field('head', $.literal), ":-", field('body', $._expression), ".")"#));
+        assert!(
+            grammar.contains(r#"seq("\\", /[$"\\nrt0\n]|x[0-9a-fA-F]{2}|u\{[0-9a-fA-F]{1,6}\}/)"#)
+        );
+        assert!(grammar.contains(r#"field('name', choice("use", "prelude")),"#));
+        assert!(grammar.contains(r#"field('name', "import"),"#));
+        assert!(grammar.contains(r#"query: ($) => seq("?-", field('goal', $._expression), "."),"#));
+        assert!(grammar.contains(
+            r#""->",
+          field('then', $._expression),"#
+        ));
+        assert!(grammar.contains(
+            r#""findall",
+          "(",
+          field('template', $._term),"#
+        ));
+        assert!(
+            grammar.contains(r#"optional(seq(",", "as", "=", field('namespace', $.identifier))),"#)
+        );
+        assert!(
+            grammar.contains(r#"optional(seq(",", "sha256", "=", field('digest', $.string))),"#)
+        );
+        assert!(grammar.contains("identifier: ($) => /[A-Za-z_][A-Za-z0-9_-]*/,"));
+        assert!(grammar.contains(r##"token(seq("r", "#", "\"", /([^"]|"[^#])*/, "\"", "#")),"##));
+        assert!(grammar.contains(r"number: ($) => /-?[0-9]+(\.[0-9]+)?/,"));
+        assert!(grammar.contains(r#"boolean: ($) => choice("true", "false"),"#));
+        assert!(grammar.contains(r#"named_context: ($) => seq("context", "(", $.string, ")"),"#));
+        assert!(grammar.contains(
+            r#"local_image: ($) => seq(choice("oci_layout", "docker_tar"), "(", $.string, ")"),"#
+        ));
+    }
+}
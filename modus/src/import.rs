@@ -0,0 +1,44 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Reads the local Modusfiles imported with `:- import("<path>").`, relative to the
+//! directory of the file that imports them.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static MODUSFILE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory of the Modusfile being parsed, which its imports are relative to.
+pub fn set_modusfile_dir(dir: &Path) {
+    let _ = MODUSFILE_DIR.set(dir.to_path_buf());
+}
+
+/// Reads an imported Modusfile, identified by its canonical path.
+/// See [`modus_lib::library::ImportLoader`].
+pub fn load(from: Option<&Path>, path: &str) -> Result<(PathBuf, String), String> {
+    let dir = match from {
+        Some(file) => file.parent(),
+        None => MODUSFILE_DIR.get().map(PathBuf::as_path),
+    }
+    .unwrap_or_else(|| Path::new("."));
+    let file = dir.join(path);
+    let file = std::fs::canonicalize(&file)
+        .map_err(|e| format!("Unable to import {}: {}", file.display(), e))?;
+    let source = std::fs::read_to_string(&file)
+        .map_err(|e| format!("Unable to import {}: {}", file.display(), e))?;
+    Ok((file, source))
+}
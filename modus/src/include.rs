@@ -0,0 +1,163 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fetches the Modusfiles included with `:- include("<url>", sha256="<digest>").`
+//!
+//! The URL is either an `https://` (or `http://`) URL, fetched with curl, or a file in a
+//! git repository, written `<repository>.git#<revision>:<path>`, e.g.
+//! `https://github.com/org/lib.git#v1:python.Modusfile`. Fetched Modusfiles are checked
+//! against their digest and cached by it, in `$XDG_CACHE_HOME/modus/includes`, so they
+//! are only fetched once.
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use modus_lib::library::Include;
+use sha2::{Digest, Sha256};
+
+use crate::buildkit::{AutoRmTmpDir, GitContext};
+
+/// The directory that modus keeps files in between runs, `$XDG_CACHE_HOME/modus`.
+pub(crate) fn cache_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|cache| cache.join("modus"))
+}
+
+/// The directory that included Modusfiles are cached in, named by their digest.
+fn cache_dir() -> Option<PathBuf> {
+    cache_home().map(|home| home.join("includes"))
+}
+
+/// Runs the command, and returns its stdout if it succeeds.
+fn run(cmd: &mut Command, description: &str) -> Result<Vec<u8>, String> {
+    let output = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Unable to run {}: {}", description, e))?;
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", description, output.status));
+    }
+    Ok(output.stdout)
+}
+
+/// Fetches `path` at `revision` of the repository, without its history.
+fn fetch_from_git(url: &str, revision: &str, path: &str) -> Result<Vec<u8>, String> {
+    // They are passed after `--`, so git doesn't read them as options, but neither is
+    // valid when it starts with `-`, which is reported rather than a failed fetch.
+    if let Some(arg) = [url, revision].iter().find(|a| a.starts_with('-')) {
+        return Err(format!(
+            "Expected a git repository and revision, not an option: {}",
+            arg
+        ));
+    }
+    let dir = AutoRmTmpDir::new_empty()
+        .map_err(|e| format!("Unable to create a temporary directory: {}", e))?;
+    let git = |args: &[&str]| {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(dir.path()).args(args);
+        run(&mut cmd, &format!("git {}", args[0]))
+    };
+    git(&["init", "--quiet"])?;
+    git(&["fetch", "--quiet", "--depth", "1", "--", url, revision])?;
+    git(&["show", &format!("FETCH_HEAD:{}", path)])
+}
+
+fn fetch_from_url(include: &Include) -> Result<Vec<u8>, String> {
+    if let Some(git) = GitContext::parse(&include.url) {
+        let (revision, path) = git
+            .reference
+            .as_deref()
+            .and_then(|r| r.split_once(':'))
+            .filter(|(revision, path)| !revision.is_empty() && !path.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "Expected the git URL {} to name a file, as <repository>.git#<revision>:<path>",
+                    include.url
+                )
+            })?;
+        fetch_from_git(&git.url, revision, path)
+    } else if include.url.starts_with("https://") || include.url.starts_with("http://") {
+        run(
+            Command::new("curl").args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                &include.url,
+            ]),
+            "curl",
+        )
+    } else {
+        Err(format!(
+            "Unable to include {}, expected an https:// URL or a git repository",
+            include.url
+        ))
+    }
+}
+
+fn utf8(content: Vec<u8>, include: &Include) -> Result<String, String> {
+    String::from_utf8(content).map_err(|_| format!("The Modusfile {} is not UTF-8", include.url))
+}
+
+/// Fetches an included Modusfile from the cache, or from its URL, and checks its digest.
+pub fn fetch(include: &Include) -> Result<String, String> {
+    let cached = cache_dir().map(|dir| dir.join(&include.sha256));
+    if let Some(content) = cached.as_ref().and_then(|path| std::fs::read(path).ok()) {
+        // Files in the cache are named by their digest, unless they were changed since.
+        if format!("{:x}", Sha256::digest(&content)) == include.sha256 {
+            return utf8(content, include);
+        }
+    }
+
+    let content = fetch_from_url(include)?;
+    let digest = format!("{:x}", Sha256::digest(&content));
+    if digest != include.sha256 {
+        return Err(format!(
+            "The Modusfile {} has the digest {}, but it is pinned to {}",
+            include.url, digest, include.sha256
+        ));
+    }
+    if let Some(path) = cached {
+        // Caching is best effort, the file is written to a temporary name first so that
+        // a concurrent build never reads part of it.
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let _ = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&tmp, &content))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+    }
+    utf8(content, include)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_not_fetched() {
+        for (url, revision) in [
+            ("--upload-pack=touch pwned", "v1"),
+            ("https://example.com/lib.git", "--output=pwned"),
+        ] {
+            let error = fetch_from_git(url, revision, "lib.Modusfile").unwrap_err();
+            assert!(error.contains("not an option"), "{}", error);
+        }
+    }
+}
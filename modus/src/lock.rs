@@ -0,0 +1,251 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The lockfile, `modus.lock`, which records the digest that each base image reference
+//! was resolved to, so that later builds use the same images:
+//!
+//! ```toml
+//! [images]
+//! "alpine:3.15" = "sha256:21a3deaa0d32a8057914f36584b5288d2e5ecc984380bc0118285c70fa8c9300"
+//! ```
+//!
+//! `modus lock` adds the images that a query uses and that are not locked yet, and
+//! `modus update` resolves them again. `modus build` pins the images that are locked.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+};
+
+use modus_lib::imagegen::{BuildNode, BuildPlan};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::buildkit::image_ref_is_hash;
+
+/// The name of the lockfile that is read from the context directory by default.
+pub const LOCK_FILE: &str = "modus.lock";
+
+const HEADER: &str = "# Written by `modus lock` and `modus update`, do not edit it by hand.\n";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("Unable to read {0}: {1}")]
+    UnableToRead(String, #[source] std::io::Error),
+    #[error("Invalid {0}: {1}")]
+    Invalid(String, #[source] toml::de::Error),
+    #[error("Unable to write {0}: {1}")]
+    UnableToWrite(String, #[source] std::io::Error),
+    #[error("Unable to run docker: {0}")]
+    UnableToRunDocker(#[source] std::io::Error),
+    #[error("Could not resolve the digest of {0}, docker exited with code {1}.")]
+    ResolveFailed(String, ExitStatus),
+    #[error("Unexpected output when resolving the digest of {0}: {1}")]
+    InvalidOutput(String, String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    /// The digest of the manifest that each image reference was resolved to.
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+}
+
+/// The image references that the plan starts from and that could be locked, i.e. the
+/// ones that are not already pinned to a digest.
+pub fn lockable_images(plan: &BuildPlan) -> Vec<&str> {
+    let mut images = plan
+        .nodes
+        .iter()
+        .filter_map(|node| match node {
+            BuildNode::From { image_ref, .. }
+                if !image_ref.contains('@') && !image_ref_is_hash(image_ref) =>
+            {
+                Some(image_ref.as_str())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    images.sort_unstable();
+    images.dedup();
+    images
+}
+
+/// Resolves the digest of the manifest that the image reference currently points to,
+/// which is the digest of the manifest list for multi-platform images.
+pub fn resolve_digest(image_ref: &str) -> Result<String, LockError> {
+    let output = Command::new("docker")
+        .args([
+            "buildx",
+            "imagetools",
+            "inspect",
+            "--format",
+            "{{json .Manifest}}",
+            image_ref,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(LockError::UnableToRunDocker)?;
+    if !output.status.success() {
+        return Err(LockError::ResolveFailed(
+            image_ref.to_owned(),
+            output.status,
+        ));
+    }
+    let invalid = |msg: String| LockError::InvalidOutput(image_ref.to_owned(), msg);
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| invalid(e.to_string()))?;
+    match manifest.get("digest").and_then(|d| d.as_str()) {
+        Some(digest) if image_ref_is_hash(digest) => Ok(digest.to_owned()),
+        _ => Err(invalid(format!("expected a digest in {}", manifest))),
+    }
+}
+
+impl Lockfile {
+    /// Reads the lockfile, or returns an empty one if it does not exist.
+    pub fn from_file(path: &Path) -> Result<Lockfile, LockError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Lockfile::default()),
+            Err(e) => return Err(LockError::UnableToRead(path.display().to_string(), e)),
+        };
+        toml::from_str(&content).map_err(|e| LockError::Invalid(path.display().to_string(), e))
+    }
+
+    pub fn write_to_file(&self, path: &Path) -> Result<(), LockError> {
+        let content = format!(
+            "{}{}",
+            HEADER,
+            toml::to_string(self).expect("Expected the lockfile to be serializable")
+        );
+        std::fs::write(path, content)
+            .map_err(|e| LockError::UnableToWrite(path.display().to_string(), e))
+    }
+
+    /// Resolves the images of the plan with `resolve`, and records their digests. Unless
+    /// `update` is set, images that are already locked are kept as they are. Returns the
+    /// images whose digest was added or changed.
+    pub fn lock<F>(
+        &mut self,
+        plan: &BuildPlan,
+        update: bool,
+        mut resolve: F,
+    ) -> Result<Vec<String>, LockError>
+    where
+        F: FnMut(&str) -> Result<String, LockError>,
+    {
+        let mut changed = Vec::new();
+        for image_ref in lockable_images(plan) {
+            if !update && self.images.contains_key(image_ref) {
+                continue;
+            }
+            let digest = resolve(image_ref)?;
+            if self.images.get(image_ref) != Some(&digest) {
+                self.images.insert(image_ref.to_owned(), digest);
+                changed.push(image_ref.to_owned());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Pins the images of the plan that are locked to their digest, e.g. `alpine:3.15`
+    /// to `alpine:3.15@sha256:...`. Returns the images that are not locked.
+    pub fn pin(&self, plan: &mut BuildPlan) -> Vec<String> {
+        let mut unlocked = Vec::new();
+        for node in plan.nodes.iter_mut() {
+            if let BuildNode::From { image_ref, .. } = node {
+                if image_ref.contains('@') || image_ref_is_hash(image_ref) {
+                    continue;
+                }
+                match self.images.get(image_ref.as_str()) {
+                    Some(digest) => *image_ref = format!("{}@{}", image_ref, digest),
+                    None => unlocked.push(image_ref.clone()),
+                }
+            }
+        }
+        unlocked.sort_unstable();
+        unlocked.dedup();
+        unlocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const DIGEST_A: &str =
+        "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const DIGEST_B: &str =
+        "sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    fn plan(query: &str) -> BuildPlan {
+        testing::plan(
+            "a :- from(\"alpine:3.15\"), run(\"make\").\n\
+             b :- from(\"debian\"), a::copy(\"/a\", \"/a\").\n\
+             c :- from(\"alpine@sha256:1234\").",
+            query,
+        )
+    }
+
+    #[test]
+    fn lock_and_pin() {
+        let mut lockfile = Lockfile::default();
+        let changed = lockfile
+            .lock(&plan("a"), false, |_| Ok(DIGEST_A.to_owned()))
+            .unwrap();
+        assert_eq!(changed, vec!["alpine:3.15"]);
+
+        // Locked images are only resolved again on update.
+        let changed = lockfile
+            .lock(&plan("b"), false, |image_ref| {
+                assert_eq!(image_ref, "debian");
+                Ok(DIGEST_B.to_owned())
+            })
+            .unwrap();
+        assert_eq!(changed, vec!["debian"]);
+        let changed = lockfile
+            .lock(&plan("b"), true, |_| Ok(DIGEST_B.to_owned()))
+            .unwrap();
+        assert_eq!(changed, vec!["alpine:3.15"]);
+        assert!(lockfile.lock(&plan("c"), true, |_| unreachable!()).is_ok());
+
+        let serialized = toml::to_string(&lockfile).unwrap();
+        assert_eq!(toml::from_str::<Lockfile>(&serialized).unwrap(), lockfile);
+
+        let mut pinned = plan("b");
+        assert!(lockfile.pin(&mut pinned).is_empty());
+        let refs = pinned
+            .nodes
+            .iter()
+            .filter_map(|n| match n {
+                BuildNode::From { image_ref, .. } => Some(image_ref.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(refs.contains(&format!("alpine:3.15@{}", DIGEST_B).as_str()));
+        assert!(refs.contains(&format!("debian@{}", DIGEST_B).as_str()));
+
+        let mut unlocked = plan("a");
+        assert_eq!(
+            Lockfile::default().pin(&mut unlocked),
+            vec!["alpine:3.15".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Progress reporting, either as human-oriented text or as JSON lines.
+//!
+//! In the JSON format, each event is written to stderr as a single line
+//! containing an object with at least the `event` and `timestamp` fields. The
+//! stderr of the programs we spawn, such as the progress of `docker build`, is
+//! forwarded line by line as `output` events, see [`forward_output`].
+
+use std::{
+    io::{self, BufRead, Write},
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread::JoinHandle,
+};
+
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: LogFormat) {
+    JSON_LOGS.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+pub fn format() -> LogFormat {
+    if JSON_LOGS.load(Ordering::SeqCst) {
+        LogFormat::Json
+    } else {
+        LogFormat::Human
+    }
+}
+
+pub fn is_json() -> bool {
+    format() == LogFormat::Json
+}
+
+fn event_line(event: &str, fields: Value) -> String {
+    let mut obj = Map::new();
+    obj.insert("event".to_owned(), json!(event));
+    obj.insert(
+        "timestamp".to_owned(),
+        json!(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    );
+    if let Value::Object(fields) = fields {
+        obj.extend(fields);
+    }
+    Value::Object(obj).to_string()
+}
+
+/// Reports a progress event. In the human format, the message produced by
+/// `message` is printed to stderr; in the JSON format the event and its fields are.
+pub fn progress<F: FnOnce() -> String>(event: &str, fields: Value, message: F) {
+    let line = if is_json() {
+        event_line(event, fields)
+    } else {
+        message()
+    };
+    let stderr = std::io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = writeln!(stderr, "{}", line);
+}
+
+/// Like `progress`, but only reported in the JSON format.
+pub fn event(event: &str, fields: Value) {
+    if is_json() {
+        progress(event, fields, String::new);
+    }
+}
+
+/// Writes a line that a program we spawned wrote to its stderr to ours, as an `output`
+/// event naming the program in the JSON format.
+pub fn output(command: &str, line: &[u8]) {
+    let stderr = std::io::stderr();
+    let _ = write_output(&mut stderr.lock(), is_json(), command, line);
+}
+
+fn write_output(w: &mut dyn Write, json: bool, command: &str, line: &[u8]) -> io::Result<()> {
+    if json {
+        let line = String::from_utf8_lossy(line);
+        let fields = json!({ "command": command, "line": line.trim_end_matches(['\n', '\r']) });
+        writeln!(w, "{}", event_line("output", fields))
+    } else {
+        w.write_all(line)
+    }
+}
+
+/// Calls `f` with each line read until the end of the input, including its newline.
+pub fn for_each_line(reader: impl io::Read, mut f: impl FnMut(&[u8])) {
+    let mut reader = io::BufReader::new(reader);
+    let mut line = Vec::new();
+    while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+        f(&line);
+        line.clear();
+    }
+}
+
+/// In the JSON format, redirects the stderr of the command to a thread that writes each
+/// line with [`output`], which finishes once the command exits and is dropped. In the
+/// human format, the command writes to our stderr directly.
+pub fn forward_output(
+    cmd: &mut Command,
+    command: &'static str,
+) -> io::Result<Option<JoinHandle<()>>> {
+    if !is_json() {
+        return Ok(None);
+    }
+    let (reader, writer) = io::pipe()?;
+    cmd.stderr(writer);
+    Ok(Some(std::thread::spawn(move || {
+        for_each_line(reader, |line| output(command, line))
+    })))
+}
+
+/// Runs the command to completion like [`Command::status`], with its stderr forwarded
+/// by [`forward_output`].
+pub fn status(cmd: &mut Command, command: &'static str) -> io::Result<ExitStatus> {
+    let forwarder = forward_output(cmd, command)?;
+    let status = cmd.status();
+    // Close our end of the pipe, so that the forwarder reads to its end.
+    cmd.stderr(Stdio::inherit());
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.join();
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_is_single_json_line() {
+        let line = event_line("parse", json!({ "clauses": 3, "file": "Modusfile" }));
+        assert!(!line.contains('\n'));
+        let v: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["event"], "parse");
+        assert_eq!(v["clauses"], 3);
+        assert_eq!(v["file"], "Modusfile");
+        assert!(v["timestamp"].is_string());
+    }
+
+    #[test]
+    fn output_is_wrapped_in_json_events() {
+        let progress = b"#1 [internal] load build definition\n\
+                         #1 DONE 0.0s\r\n\
+                         #2 \"quoted\" \xff\n\
+                         \n\
+                         no newline at the end";
+        let mut stderr = Vec::new();
+        for_each_line(&progress[..], |line| {
+            write_output(&mut stderr, true, "docker build", line).unwrap()
+        });
+        let lines = String::from_utf8(stderr).unwrap();
+        let events = lines
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 5);
+        assert!(events
+            .iter()
+            .all(|e| e["event"] == "output" && e["command"] == "docker build"));
+        assert_eq!(events[1]["line"], "#1 DONE 0.0s");
+        assert_eq!(events[2]["line"], "#2 \"quoted\" \u{fffd}");
+        assert_eq!(events[4]["line"], "no newline at the end");
+
+        let mut stderr = Vec::new();
+        for_each_line(&progress[..], |line| {
+            write_output(&mut stderr, false, "docker build", line).unwrap()
+        });
+        assert_eq!(stderr, progress);
+    }
+}
@@ -14,12 +14,33 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod affected;
 mod buildkit;
+mod cache;
+mod cache_report;
+mod choose;
+mod debug;
+mod doc;
+mod exit_code;
+mod explain;
+mod grammar;
+mod import;
+mod include;
+mod lock;
+mod logging;
+mod package;
+mod policy;
+mod registry;
 mod reporting;
+mod reproduce;
+mod sarif;
+#[cfg(test)]
+mod testing;
+mod verify;
 
 use clap::{arg, crate_version, Arg, Command};
 use codespan_reporting::{
-    diagnostic::Diagnostic,
+    diagnostic::{Diagnostic, Label, Severity},
     files::SimpleFile,
     term::{
         self,
@@ -28,41 +49,651 @@ use codespan_reporting::{
     },
 };
 use colored::Colorize;
+use logging::LogFormat;
 use modus_lib::transpiler::render_tree;
 use modus_lib::*;
 use modus_lib::{analysis::ModusSemantics, sld::tree_from_modusfile};
 use ptree::write_tree;
-use std::{ffi::OsStr, fs, path::Path, time::Instant};
-use std::{io::Write, path::PathBuf};
+use serde_json::json;
+use std::{ffi::OsStr, fmt, fs, path::Path, process::Stdio, time::Instant};
+use std::{
+    io::{IsTerminal, Write},
+    path::PathBuf,
+};
 
 use modus_lib::modusfile::Modusfile;
 
 use crate::buildkit::{BuildOptions, DockerBuildOptions};
+use crate::exit_code::ExitCode;
 use crate::reporting::Profiling;
 
+/// The warnings about deprecated syntax in the Modusfile that was parsed from the file.
+fn deprecation_warnings(mf: &Modusfile, file: &SimpleFile<&str, String>) -> Vec<Diagnostic<()>> {
+    deprecation::find_deprecated(mf, file.source())
+        .into_iter()
+        .map(|d| d.diagnostic)
+        .collect()
+}
+
 fn get_file_or_exit(path: &Path) -> SimpleFile<&str, String> {
-    let file_name: &str = path
-        .file_name()
-        .map(|os_str| os_str.to_str())
-        .unwrap()
-        .unwrap();
+    // A path such as `.` has no file name, reading it fails below.
+    let file_name: &str = match path.file_name().unwrap_or(path.as_os_str()).to_str() {
+        Some(file_name) => file_name,
+        None => {
+            eprintln!("The path {} is not valid UTF-8.", path.display());
+            ExitCode::Other.exit();
+        }
+    };
     let file_content: String = match fs::read_to_string(path) {
         Ok(content) => content,
         Err(err) => {
             eprintln!("Error reading {}: {}", path.display(), err);
-            std::process::exit(1);
+            ExitCode::Other.exit();
         }
     };
 
     SimpleFile::new(file_name, file_content)
 }
 
+/// Reads a Modusfile, whose dependencies are then loaded from the project it is in, and
+/// whose imports are relative to its directory.
+fn get_modusfile_or_exit(path: &Path) -> SimpleFile<&str, String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    package::set_project_dir(&dir);
+    import::set_modusfile_dir(&dir);
+    get_file_or_exit(path)
+}
+
+/// Collects the values of the standard OCI labels from the git repository
+/// containing `context_dir`. Anything that is not available, such as a missing
+/// remote, is left out.
+/// Makes the directory of a local named context absolute, since docker build is run
+/// from the context directory rather than the current one.
+fn absolute_build_context(build_context: &str) -> String {
+    match build_context.split_once('=') {
+        Some((name, value)) if !value.contains("://") => match std::fs::canonicalize(value) {
+            Ok(path) => format!("{}={}", name, path.display()),
+            Err(e) => {
+                report_failure(&format!("Unable to find build context {}: {}", value, e));
+                ExitCode::Other.exit()
+            }
+        },
+        _ => build_context.to_owned(),
+    }
+}
+
+fn git_oci_labels(context_dir: &OsStr) -> imagegen::OciLabels {
+    fn git(context_dir: &OsStr, args: &[&str]) -> Option<String> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(context_dir)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let s = String::from_utf8(output.stdout).ok()?;
+        Some(s.trim().to_owned()).filter(|s| !s.is_empty())
+    }
+
+    imagegen::OciLabels {
+        source: git(context_dir, &["remote", "get-url", "origin"]),
+        revision: git(context_dir, &["rev-parse", "HEAD"]),
+        created: Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+    }
+}
+
+/// Reports that a stage of the pipeline failed. Details should be reported
+/// separately, usually as diagnostics.
+/// Writes the named Dockerfiles to `dir`, creating it if needed, and prints their paths.
+fn write_dockerfiles<T: fmt::Display>(dir: &Path, dockerfiles: &[(String, T)]) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        eprintln!("Error creating {}: {}", dir.display(), e);
+        ExitCode::Other.exit();
+    }
+    for (name, df) in dockerfiles {
+        let path = dir.join(name);
+        if let Err(e) = fs::write(&path, df.to_string()) {
+            eprintln!("Error writing {}: {}", path.display(), e);
+            ExitCode::Other.exit();
+        }
+        println!("{}", path.display());
+    }
+}
+
+fn report_failure(message: &str) {
+    logging::progress("failure", json!({ "message": message }), || {
+        format!("❌ {}", message)
+    });
+}
+
+fn report_solver_stats(stats: &sld::SolverStats) {
+    logging::progress("solver_stats", json!(stats), || {
+        format!("Solver statistics: {}", stats)
+    });
+}
+
+/// A profile whose solving is cancelled by Ctrl-C, so that how far it got can be
+/// reported. A second Ctrl-C exits right away.
+fn interruptible_profile(profile: sld::SolveProfile) -> sld::SolveProfile {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let cancellation = sld::CancellationToken::new();
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so that it only exits if solving was already cancelled.
+        signal_hook::flag::register_conditional_shutdown(
+            signal,
+            ExitCode::Interrupted as i32,
+            cancellation.flag().clone(),
+        )
+        .and_then(|_| signal_hook::flag::register(signal, cancellation.flag().clone()))
+        .expect("Unable to handle Ctrl-C.");
+    }
+    profile.with_cancellation(cancellation)
+}
+
+/// The arguments limiting the resources used by solving, see [`solver_limits`].
+fn solver_limit_args() -> [Arg<'static>; 2] {
+    [
+        Arg::new("TIMEOUT")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("Stop solving a query after this many seconds"),
+        Arg::new("MAX_NODES")
+            .long("max-nodes")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Stop solving a query after exploring this many nodes of the proof tree"),
+    ]
+}
+
+fn solver_limits(sub: &clap::ArgMatches) -> sld::ResourceLimits {
+    sld::ResourceLimits {
+        timeout: sub
+            .is_present("TIMEOUT")
+            .then(|| std::time::Duration::from_secs_f64(sub.value_of_t_or_exit("TIMEOUT"))),
+        max_nodes: sub
+            .is_present("MAX_NODES")
+            .then(|| sub.value_of_t_or_exit("MAX_NODES")),
+        ..Default::default()
+    }
+}
+
+/// Reports how far a cancelled solve got: its statistics and the deepest goal it
+/// reached, which hints at where it was stuck.
+fn report_cancelled(profile: &sld::SolveProfile) {
+    let goal = profile
+        .deepest_goal
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    logging::progress(
+        "solve_cancelled",
+        json!({ "solver": profile.stats, "deepest_goal": goal }),
+        || {
+            format!(
+                "Solving was cancelled: {}. The deepest goal reached was:\n  {}",
+                profile.stats,
+                goal.join(",\n  ")
+            )
+        },
+    );
+}
+
+/// Prints the summary of a plan that `build --dry-run` would build, to stdout.
+fn print_dry_run(stats: &imagegen::PlanStats) {
+    let local_images = cache::local_images(stats.downloads.iter().map(String::as_str));
+    if logging::is_json() {
+        println!(
+            "{}",
+            json!({
+                "stats": stats,
+                "local_images": local_images.as_ref().ok(),
+            })
+        );
+    } else {
+        print!("{}", stats);
+        match &local_images {
+            Ok(local_images) => {
+                println!("Base images already present locally:");
+                for image in local_images.iter() {
+                    println!("  {}", image);
+                }
+                println!("Base images that would be pulled:");
+                for image in stats.downloads.difference(local_images) {
+                    println!("  {}", image);
+                }
+            }
+            Err(e) => println!("Unable to check for local base images: {}", e),
+        }
+        println!("Dry run, nothing was built.");
+    }
+}
+
+/// The `build` and `test` subcommands, which take the same arguments. `test` also checks
+/// the assertions on the built images, as `build --verify` does.
+fn build_command(name: &'static str, about: &'static str) -> Command<'static> {
+    Command::new(name)
+        .about(about)
+        .arg(
+            Arg::new("FILE")
+                .required(false)
+                .long_help("Specify the input Modusfile\n\
+                            The default is to look for a Modusfile in the context directory.")
+                .help("Specify the input Modusfile")
+                .value_name("FILE")
+                .short('f')
+                .long("modusfile")
+                .allow_invalid_utf8(true)
+        )
+        .arg(
+            Arg::new("CONTEXT")
+                .help("Specify the build context directory")
+                .long_help("Specify the build context directory\n\
+                            This may also be a git repository, e.g. https://github.com/org/repo.git#branch, \
+                            which is shallow-cloned and used as the context.")
+                .index(1)
+                .required(true)
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("QUERY")
+                .required(true)
+                .help("Specify the target query to build")
+                .long_help("Specify the target query to build\n\
+                            This may be a conjunction with logical constraints, \
+                            e.g. 'app(X), semver_geq(X, \"3.10\")'.")
+                .index(2),
+        )
+        .arg(
+            Arg::new("JSON_OUTPUT")
+                .value_name("FILE")
+                .required(false)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .long("json")
+                .help("Output build result as JSON")
+                .long_help("Output build result as JSON\n\
+                            If this flag is specified without providing a file name, output is written to stdout.")
+                .allow_invalid_utf8(true)
+        )
+        .arg(
+            Arg::new("NODE_DIGESTS")
+                .long("node-digests")
+                .requires("JSON_OUTPUT")
+                .help("Include a cache key for every node of the build plan in the JSON output")
+                .long_help("Include a cache key for every node of the build plan in the JSON output\n\
+                            The output becomes {\"images\": [...], \"nodes\": [...]}. A node's key is computed from its \
+                            instruction, the keys of the nodes it builds on and the resolved base image, so an unchanged \
+                            key across builds identifies an intermediate image that can be reused.")
+        )
+        .arg(
+            Arg::new("VERBOSE")
+                .short('v')
+                .long("verbose")
+                .help("Tell docker to print all the output"),
+        )
+        .arg(
+            Arg::new("NO_CACHE")
+                .long("--no-cache")
+                .help("Ignore all existing build cache"),
+        )
+        .arg(
+            Arg::new("CHOOSE")
+                .long("choose")
+                .help("Ask which solution to build if the query has several")
+                .long_help("Ask which solution to build if the query has several, \
+                            instead of building all of them.\n\
+                            This needs an interactive terminal."),
+        )
+        .arg(
+            Arg::new("CACHE_REPORT")
+                .long("cache-report")
+                .help("Report which nodes were cached, and why the others were rebuilt")
+                .long_help("Report which nodes of the build plan were cached, and why the others were rebuilt, \
+                            e.g. because their command or the files they copy changed.\n\
+                            Nodes are compared with the previous build of the query in the same context that \
+                            was run with this flag, since BuildKit does not report cache hits. With --log-format json, \
+                            the report is emitted as a cache_report event."),
+        )
+        .arg(
+            Arg::new("VERIFY")
+                .long("verify")
+                .help("Check the assertions on the built images")
+                .long_help("Check the assertions given with ::assert_file(Path) and \
+                            ::assert_cmd_succeeds(Command) after the images are built, by running them in a \
+                            container started from each checked image, and fail if one does not hold. \
+                            Images are only tagged and pushed if they do.\n\
+                            This is implied by `modus test`. The checks run with sh, which the images need."),
+        )
+        .arg(
+            Arg::new("EXPORT_FAILED_STATE")
+                .long("export-failed-state")
+                .help("If a command fails, tag the state it ran in for debugging")
+                .long_help("If a command fails, find it by building step by step, and tag the \
+                            state that it ran in as modus-debug:n_<node>, so that the failure can be \
+                            reproduced interactively. The rule that runs the command is reported.\n\
+                            The steps that succeeded are cached, so mostly the failed one is run again."),
+        )
+        .arg(
+            Arg::new("DRY_RUN")
+                .long("dry-run")
+                .help("Solve and check the build, and summarize it, without building anything")
+                .long_help("Solve the query and check the plan against the policies, then print a \
+                           summary of the plan and which base images are already present locally, \
+                           without running docker build.\n\
+                           Whether the other steps are cached is only known to the builder."),
+        )
+        .arg(
+            Arg::new("ADDITIONAL_OPTS")
+                .long("docker-flags")
+                .takes_value(true)
+                .multiple_values(true)
+                .required(false)
+                .help("Pass additional options to docker build")
+        )
+        .arg(
+            Arg::new("BUILD_CONTEXT")
+                .long("build-context")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("NAME=VALUE")
+                .help("Add a named context, used with from(context(\"NAME\"))")
+                .long_help("Add a named context, which can be used with from(context(\"NAME\")).\n\
+                            VALUE is either a local directory or an image, e.g. docker-image://alpine:3.15."),
+        )
+        .arg(
+            Arg::new("SECRET")
+                .long("secret")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("SECRET")
+                .help("Add a secret for ::secret, e.g. id=pypi_token,src=./token.txt")
+                .long_help("Add a secret that commands can mount with the ::secret operator, given as \
+                            id=<ID>,src=<FILE> or id=<ID>,env=<VAR>.\n\
+                            Every secret that the build plan mounts must be given a source."),
+        )
+        .arg(
+            Arg::new("VERIFY_SIGNATURES")
+                .long("verify-signatures")
+                .takes_value(true)
+                .value_name("POLICY")
+                .help("Require every base image to be signed")
+                .long_help("Require every base image to be signed before it is resolved, or fail the build.\n\
+                            POLICY is one of cosign=<key>, cosign-identity=<identity>@<issuer> (keyless) \
+                            or notation (using its configured trust policy). \
+                            The cosign or notation CLI must be installed."),
+        )
+        .arg(
+            Arg::new("POLICY")
+                .long("policy")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .help("Check base images against a policy file")
+                .long_help("Check base images against a policy file, which lists the allowed registries or \
+                            repositories and the banned tags.\n\
+                            The default is modus-policy.toml in the context directory, if it exists."),
+        )
+        .arg(
+            Arg::new("LOCKFILE")
+                .long("lockfile")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .help("Build from the base image digests recorded in a lockfile")
+                .long_help("Build from the base image digests recorded in a lockfile, see `modus lock`.\n\
+                            The default is modus.lock in the context directory, if it exists."),
+        )
+        .arg(
+            Arg::new("AFFECTED_ONLY")
+                .long("affected-only")
+                .takes_value(true)
+                .value_name("REV")
+                .help("Only build the outputs affected by changes since a git revision")
+                .long_help("Only build the outputs affected by changes since a git revision, e.g. origin/main.\n\
+                            An output is affected if a path it copies from the context changed, or the \
+                            Modusfile, modus.toml or the lockfile did, which pin the includes, the \
+                            dependencies and the base images. Changes that are not committed count. \
+                            If no output is affected, nothing is built."),
+        )
+        .arg(
+            Arg::new("POLICY_RULES")
+                .long("policy-rules")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .help("Check the build against violation(Reason) rules in a Modusfile")
+                .long_help("Check the build against the violation(Reason) rules in a Modusfile, and abort \
+                            if any violation is found.\n\
+                            The rules can refer to the facts base_image(Ref), copied_path(Src, Dst) and \
+                            run_command(Command), which describe the build."),
+        )
+        .arg(
+            Arg::new("RESOLVE_CONCURRENCY")
+                .long("image-resolve-concurrency")
+                .takes_value(true)
+                .required(false)
+                .default_value("3")
+                .value_name("NUM")
+        )
+        .arg(
+            Arg::new("EXPORT_CONCURRENCY")
+                .long("image-export-concurrency")
+                .takes_value(true)
+                .required(false)
+                .value_name("NUM")
+                .help("The number of concurrent docker instances to run in the final exporting stage.")
+                .long_help("The number of concurrent docker instances to run in the final exporting stage.\n\
+                            This is only relevant for builds with multiple output images. Most of the work done \
+                            here is computing checksums for each final image.\n\
+                            Default is the number of CPUs available.")
+        )
+        .arg(
+            Arg::new("CUSTOM_FRONTEND")
+                .long("custom-buildkit-frontend")
+                .value_name("IMAGE_REF")
+                .takes_value(true)
+                .required(false)
+                .help("Specify a custom buildkit buildkit frontend to use")
+                .long_help(concat!("Specify a custom frontend to use for buildkit. It must parse a JSON Modus build plan, and invoke relevant buildkit calls.\n\
+                            The default is to use a pre-built one hosted on ghcr.io, with commit id ", env!("GIT_SHA"), ".\n\
+                            This flag allows you to use something other than the default, for example for development on Modus itself."))
+                .default_value(buildkit::FRONTEND_IMAGE),
+        )
+        .arg(
+            Arg::new("LOAD")
+                .long("load")
+                .help("Load the built images into the local docker image store")
+                .long_help("Load the built images into the local docker image store, so that they can be run \
+                            right away, even if the builder does not do so by default, e.g. one using the \
+                            docker-container driver.\n\
+                            The names given with -t and ::tag are applied to the loaded images."),
+        )
+        .arg(
+            Arg::new("TAG")
+                .short('t')
+                .long("tag")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("NAME")
+                .help("Name the built image, as ::tag does")
+                .long_help("Name the built image, e.g. registry/app:1.0, as the ::tag operator does.\n\
+                            The query must have a single solution, use ::tag to name several images."),
+        )
+        .arg(
+            Arg::new("PUSH")
+                .long("push")
+                .help("Push the images named with ::tag after building them")
+                .long_help("Push the images named with the ::tag operator to their registries after \
+                            building and tagging them.\n\
+                            The images are tagged even without this flag. The digest each tag is pushed \
+                            with is written to the JSON output, see modus manifest."),
+        )
+        .arg(
+            Arg::new("OCI_LABELS")
+                .long("oci-labels")
+                .help("Add standard org.opencontainers.image.* labels to output images")
+                .long_help("Add standard org.opencontainers.image.* labels to output images.\n\
+                            The source, revision and created labels are derived from the git repository \
+                            of the context directory, if there is one.")
+        )
+        .arg(
+            Arg::new("PROVENANCE")
+                .long("provenance")
+                .help("Label output images with the query and Modusfile that produced them")
+                .long_help("Label each output image with the literal it was built for, the query, \
+                            a digest of the Modusfile, the modus version and the time of the solve, \
+                            under com.modus-continens.provenance.*.\n\
+                            The time of the solve makes the image digests differ between builds, \
+                            see modus verify for rebuilding with the same labels.")
+        )
+        .arg(
+            Arg::new("OUTPUT_FORMAT")
+                .long("format")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .help("Print each built image using a handlebars template")
+                .long_help("Print each built image to stdout using a handlebars template, e.g. \
+                            '{{predicate}} {{args.0}} => {{digest}}'.\n\
+                            The fields available are predicate, args, digest, annotations, tags, platform and pushed."),
+        )
+        .arg(
+            Arg::new("PROFILING")
+                .long("output-profiling")
+                .allow_invalid_utf8(true)
+                .takes_value(true)
+                .value_name("FILE")
+                .required(false)
+                .help("Output profiling information to a JSON file.")
+                .long_help("Output profiling information to a JSON file.\n\
+                            The format of the output is not specified.")
+        )
+        .arg(
+            Arg::new("SEARCH")
+                .long("search")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(["depth-first", "best-first"])
+                .default_value("depth-first")
+                .help("Set how the solver searches for proofs")
+                .long_help("Set how the solver searches for proofs.\n\
+                            depth-first finds every solution of the query. best-first explores the \
+                            proofs with the fewest image-producing literals first and stops at the first \
+                            one, so only the cheapest solution is built."),
+        )
+        .arg(arg!(--"solver-stats" "Report statistics about the work done by the solver."))
+        .args(solver_limit_args())
+        .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+        .arg(
+            Arg::new("PLATFORM")
+                .long("platform")
+                .takes_value(true)
+                .value_name("PLATFORM")
+                .help("Set the platform to build images for, e.g. linux/arm64")
+                .long_help("Set the platform to build images for, e.g. linux/arm64.\n\
+                            It is used when resolving every FROM, and rules may depend on it through \
+                            the fact target_platform(\"<PLATFORM>\")."),
+        )
+}
+
+/// The `lock` and `update` subcommands, which take the same arguments as `plan`.
+fn lock_command(name: &'static str, about: &'static str) -> Command<'static> {
+    Command::new(name)
+        .about(about)
+        .arg(
+            Arg::new("FILE")
+                .required(false)
+                .long_help(
+                    "Set the input Modusfile\n\
+                            The default is to look for a Modusfile in the context directory.",
+                )
+                .help("Set the input Modusfile")
+                .value_name("FILE")
+                .short('f')
+                .long("modusfile")
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("CONTEXT")
+                .help("Specify the directory that contains the Modusfile.")
+                .index(1)
+                .required(true)
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("QUERY")
+                .required(true)
+                .help("Specify the query whose base images are locked")
+                .index(2),
+        )
+        .arg(
+            Arg::new("LOCKFILE")
+                .long("lockfile")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .help("Set the lockfile, the default is modus.lock in the context directory"),
+        )
+        .arg(
+            Arg::new("SEARCH")
+                .long("search")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(["depth-first", "best-first"])
+                .default_value("depth-first")
+                .help("Set how the solver searches for proofs"),
+        )
+        .args(solver_limit_args())
+        .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+        .arg(
+            Arg::new("PLATFORM")
+                .long("platform")
+                .takes_value(true)
+                .value_name("PLATFORM")
+                .help("Set the platform to build images for, e.g. linux/arm64"),
+        )
+}
+
+/// The lockfile given with `--lockfile`, or the one in the context directory.
+fn lockfile_path(sub: &clap::ArgMatches, context_dir: &OsStr) -> PathBuf {
+    sub.value_of_os("LOCKFILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(context_dir).join(lock::LOCK_FILE))
+}
+
 fn main() {
+    library::set_include_fetcher(include::fetch);
+    library::set_dependency_loader(package::load);
+    library::set_import_loader(import::load);
+    builtin::set_change_detector(affected::detect);
+
     let matches = Command::new("modus")
         .version(crate_version!())
         .about("A language for building container images")
+        .after_help(exit_code::EXIT_CODES_HELP)
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("LOG_FORMAT")
+                .long("log-format")
+                .global(true)
+                .takes_value(true)
+                .possible_values(["human", "json"])
+                .default_value("human")
+                .help("Set the format of progress and error messages")
+                .long_help("Set the format of progress and error messages.\n\
+                            With json, one JSON object per event is written to stderr on its own line. \
+                            Output from docker and the other programs modus runs is wrapped in output events.")
+        )
         .subcommand(
             Command::new("transpile")
                 .hide(true)
@@ -75,27 +706,84 @@ fn main() {
                 .arg(
                     Arg::new("QUERY")
                         .required(true)
+                        .multiple_values(true)
                         .help("Specify the build target(s)")
+                        .long_help("Specify the build target(s)\n\
+                                    If several queries are given, they are solved in parallel and \
+                                    transpiled to a single Dockerfile.")
                         .index(2),
                 )
+                .arg(
+                    Arg::new("SEARCH")
+                        .long("search")
+                        .takes_value(true)
+                        .value_name("STRATEGY")
+                        .possible_values(["depth-first", "best-first"])
+                        .default_value("depth-first")
+                        .help("Set how the solver searches for proofs")
+                        .long_help("Set how the solver searches for proofs.\n\
+                                    depth-first finds every solution of the query. best-first explores the \
+                                    proofs with the fewest image-producing literals first and stops at the first \
+                                    one, so only the cheapest solution is built."),
+                )
+                .arg(arg!(--"solver-stats" "Report statistics about the work done by the solver."))
+        .args(solver_limit_args())
+        .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+                .arg(
+                    Arg::new("PLATFORM")
+                        .long("platform")
+                        .takes_value(true)
+                        .value_name("PLATFORM")
+                        .help("Set the platform to build images for, e.g. linux/arm64")
+                        .long_help("Set the platform to build images for, e.g. linux/arm64.\n\
+                                    It is used when resolving every FROM, and rules may depend on it through \
+                                    the fact target_platform(\"<PLATFORM>\")."),
+                )
+                .arg(
+                    Arg::new("OUTPUT_DIR")
+                        .long("output-dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                        .help("Write a Dockerfile for each solved output to DIR")
+                        .long_help("Write a separate Dockerfile for each solved output to DIR, \
+                                    instead of a single combined Dockerfile to stdout.\n\
+                                    Each file is named after the output literal, e.g. app-3.11.Dockerfile, \
+                                    and its path is printed."),
+                )
+                .arg(
+                    Arg::new("JOIN_RUNS")
+                        .long("join-runs")
+                        .help("Join the lines of multi-line commands with && instead of using heredocs")
+                        .long_help("Join the lines of multi-line commands, e.g. written as \"\"\"...\"\"\" strings, \
+                                    with && instead of emitting them as heredocs, which need the \
+                                    docker/dockerfile:1.4 frontend. The command then stops at the first line \
+                                    that fails. Commands whose lines are not whole commands, e.g. an if \
+                                    spanning several lines, are still emitted as heredocs."),
+                )
         )
+        .subcommand(build_command("build", "Build images."))
+        .subcommand(build_command(
+            "test",
+            "Build images and check the assertions on them.",
+        ))
         .subcommand(
-            Command::new("build")
-                .about("Build images.")
+            Command::new("plan")
+                .about("Print the build plan of a given query, without building it.")
                 .arg(
                     Arg::new("FILE")
                         .required(false)
-                        .long_help("Specify the input Modusfile\n\
+                        .long_help("Set the input Modusfile\n\
                                     The default is to look for a Modusfile in the context directory.")
-                        .help("Specify the input Modusfile")
+                        .help("Set the input Modusfile")
                         .value_name("FILE")
                         .short('f')
                         .long("modusfile")
-                        .allow_invalid_utf8(true)
+                        .allow_invalid_utf8(true),
                 )
                 .arg(
                     Arg::new("CONTEXT")
-                        .help("Specify the build context directory")
+                        .help("Specify the directory that contains the Modusfile.")
                         .index(1)
                         .required(true)
                         .allow_invalid_utf8(true),
@@ -103,83 +791,226 @@ fn main() {
                 .arg(
                     Arg::new("QUERY")
                         .required(true)
-                        .help("Specify the target query to build")
+                        .help("Specify the target query to plan")
                         .index(2),
                 )
                 .arg(
-                    Arg::new("JSON_OUTPUT")
-                        .value_name("FILE")
+                    arg!(--stats "Report statistics about the plan instead of printing it.")
+                        .long_help("Report statistics about the plan instead of printing it as JSON: \
+                                    the number of nodes of each type, the distinct base images, the images \
+                                    that are downloaded, and the estimated number of layers of each output.\n\
+                                    With --log-format json, the statistics are printed as JSON.")
+                )
+                .arg(
+                    Arg::new("SEARCH")
+                        .long("search")
+                        .takes_value(true)
+                        .value_name("STRATEGY")
+                        .possible_values(["depth-first", "best-first"])
+                        .default_value("depth-first")
+                        .help("Set how the solver searches for proofs"),
+                )
+                .args(solver_limit_args())
+                .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+                .arg(
+                    Arg::new("PLATFORM")
+                        .long("platform")
+                        .takes_value(true)
+                        .value_name("PLATFORM")
+                        .help("Set the platform to build images for, e.g. linux/arm64"),
+                )
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Start a shell in the image built up to a node of the build plan.")
+                .arg(
+                    Arg::new("FILE")
                         .required(false)
-                        .min_values(0)
-                        .max_values(1)
-                        .require_equals(true)
-                        .long("json")
-                        .help("Output build result as JSON")
-                        .long_help("Output build result as JSON\n\
-                                    If this flag is specified without providing a file name, output is written to stdout.")
-                        .allow_invalid_utf8(true)
+                        .long_help("Set the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Set the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .help("Specify the build context directory")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
                 )
                 .arg(
-                    Arg::new("VERBOSE")
-                        .short('v')
-                        .long("verbose")
-                        .help("Tell docker to print all the output"),
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the target query to build")
+                        .index(2),
                 )
                 .arg(
-                    Arg::new("NO_CACHE")
-                        .long("--no-cache")
-                        .help("Ignore all existing build cache"),
+                    Arg::new("NODE")
+                        .required(true)
+                        .help("Specify the node to build up to")
+                        .long_help("Specify the node to build up to, either by its index in the plan, \
+                                    e.g. 3 or n_3 as printed by `modus plan`, or by the head of the rule \
+                                    that produced it, e.g. base or 'app(\"3.11\")'.\n\
+                                    A rule selects the image at the end of its body.")
+                        .index(3),
                 )
                 .arg(
-                    Arg::new("ADDITIONAL_OPTS")
-                        .long("docker-flags")
+                    Arg::new("SHELL")
+                        .long("shell")
                         .takes_value(true)
-                        .multiple_values(true)
-                        .required(false)
-                        .help("Pass additional options to docker build")
+                        .default_value("sh")
+                        .help("Set the shell to run in the container"),
                 )
                 .arg(
-                    Arg::new("RESOLVE_CONCURRENCY")
-                        .long("image-resolve-concurrency")
+                    Arg::new("SEARCH")
+                        .long("search")
                         .takes_value(true)
-                        .required(false)
-                        .default_value("3")
-                        .value_name("NUM")
+                        .value_name("STRATEGY")
+                        .possible_values(["depth-first", "best-first"])
+                        .default_value("depth-first")
+                        .help("Set how the solver searches for proofs"),
                 )
+                .args(solver_limit_args())
+                .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
                 .arg(
-                    Arg::new("EXPORT_CONCURRENCY")
-                        .long("image-export-concurrency")
+                    Arg::new("PLATFORM")
+                        .long("platform")
                         .takes_value(true)
-                        .required(false)
-                        .value_name("NUM")
-                        .help("The number of concurrent docker instances to run in the final exporting stage.")
-                        .long_help("The number of concurrent docker instances to run in the final exporting stage.\n\
-                                    This is only relevant for builds with multiple output images. Most of the work done \
-                                    here is computing checksums for each final image.\n\
-                                    Default is the number of CPUs available.")
+                        .value_name("PLATFORM")
+                        .help("Set the platform to build images for, e.g. linux/arm64"),
                 )
                 .arg(
                     Arg::new("CUSTOM_FRONTEND")
                         .long("custom-buildkit-frontend")
                         .value_name("IMAGE_REF")
                         .takes_value(true)
-                        .required(false)
                         .help("Specify a custom buildkit buildkit frontend to use")
-                        .long_help(concat!("Specify a custom frontend to use for buildkit. It must parse a JSON Modus build plan, and invoke relevant buildkit calls.\n\
-                                    The default is to use a pre-built one hosted on ghcr.io, with commit id ", env!("GIT_SHA"), ".\n\
-                                    This flag allows you to use something other than the default, for example for development on Modus itself."))
                         .default_value(buildkit::FRONTEND_IMAGE),
                 )
+        )
+        .subcommand(lock_command(
+            "lock",
+            "Record the digests of the base images that a query uses in modus.lock.",
+        ).long_about("Record the digests of the base images that a query uses in modus.lock, so that \
+                      later builds use the same images. Images that are already locked are kept."))
+        .subcommand(lock_command(
+            "update",
+            "Resolve the base images that a query uses again, and update modus.lock.",
+        ))
+        .subcommand(
+            Command::new("verify")
+                .about("Rebuild the images of a build result and check that they are the same.")
+                .long_about("Rebuild the images recorded in a build result written by `modus build --json`, \
+                             and check that their digests are the same as the recorded ones.\n\
+                             The provenance labels of the recorded images are reused. If the images differ, \
+                             the nodes of the build plan where the builds started to differ are reported, \
+                             from the node digests if the result was written with --node-digests, and \
+                             otherwise from the layers of the images if the recorded ones are present locally.")
+                .arg(
+                    Arg::new("RESULT")
+                        .required(true)
+                        .help("Specify the build result, as written by modus build --json")
+                        .index(1)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Set the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Set the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .help("Specify the build context directory")
+                        .index(2)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
                 .arg(
-                    Arg::new("PROFILING")
-                        .long("output-profiling")
+                    Arg::new("QUERY")
+                        .required(false)
+                        .help("Specify the query to rebuild, the default is the recorded one")
+                        .index(3),
+                )
+                .arg(
+                    Arg::new("LOCKFILE")
+                        .long("lockfile")
+                        .takes_value(true)
+                        .value_name("FILE")
                         .allow_invalid_utf8(true)
+                        .help("Set the lockfile, the default is modus.lock in the context directory"),
+                )
+                .arg(
+                    Arg::new("OCI_LABELS")
+                        .long("oci-labels")
+                        .help("Add the org.opencontainers.image.* labels, if the result was built with them"),
+                )
+                .arg(
+                    Arg::new("SEARCH")
+                        .long("search")
                         .takes_value(true)
+                        .value_name("STRATEGY")
+                        .possible_values(["depth-first", "best-first"])
+                        .default_value("depth-first")
+                        .help("Set how the solver searches for proofs"),
+                )
+                .args(solver_limit_args())
+                .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+                .arg(
+                    Arg::new("PLATFORM")
+                        .long("platform")
+                        .takes_value(true)
+                        .value_name("PLATFORM")
+                        .help("Set the platform to build images for, e.g. linux/arm64"),
+                )
+                .arg(
+                    Arg::new("CUSTOM_FRONTEND")
+                        .long("custom-buildkit-frontend")
+                        .value_name("IMAGE_REF")
+                        .takes_value(true)
+                        .help("Specify a custom buildkit buildkit frontend to use")
+                        .default_value(buildkit::FRONTEND_IMAGE),
+                )
+        )
+        .subcommand(
+            Command::new("manifest")
+                .about("Push the images built for several platforms as a single manifest list.")
+                .long_about("Assemble the images of build results written by `modus build --platform <PLATFORM> \
+                             --push --json`, one per platform, into a manifest list and push it as TAG.\n\
+                             Each build result must hold a single image. The manifest list refers to the \
+                             digests that were recorded when the images were pushed.")
+                .arg(
+                    Arg::new("TAG")
+                        .required(true)
+                        .help("Specify the tag to push the manifest list as, e.g. registry/app:1.0")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("RESULT")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Specify the build results, as written by modus build --json")
+                        .index(2)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("JSON_OUTPUT")
                         .value_name("FILE")
                         .required(false)
-                        .help("Output profiling information to a JSON file.")
-                        .long_help("Output profiling information to a JSON file.\n\
-                                    The format of the output is not specified.")
+                        .min_values(0)
+                        .max_values(1)
+                        .require_equals(true)
+                        .long("json")
+                        .help("Output the digests of the manifest list and its images as JSON")
+                        .allow_invalid_utf8(true),
                 )
         )
         .subcommand(
@@ -209,15 +1040,87 @@ fn main() {
                     Arg::new("QUERY")
                         .required(true)
                         .help("Specify the target to prove")
+                        .long_help("Specify the target to prove\n\
+                                    This may be a conjunction of literals, \
+                                    e.g. 'app(X), semver_geq(X, \"3.10\")'.")
                         .index(2),
                 )
                 .arg(arg!(-e --explain "Prints out an explanation of the steps taken in resolution."))
                 .arg(arg!(-g --graph "Outputs a (DOT) graph that of the SLD tree traversed in resolution."))
-                .arg(arg!(--compact "Omits logical rule resolution.")),
+                .arg(arg!(--json "Outputs the SLD tree traversed in resolution as JSON."))
+                .arg(arg!(--"proof-graph" "Outputs a (DOT) graph of each proof, with the clause and valuation of each step."))
+                .arg(arg!(--compact "Omits logical rule resolution."))
+                .arg(arg!(--tree "Prints every step of each proof, colored by whether it uses a fact, a rule or a builtin."))
+                .arg(arg!(-t --trace "Prints the steps taken in resolution, in order."))
+                .arg(arg!(--profile "Prints the time spent resolving each predicate to stderr."))
+                .arg(
+                    Arg::new("OUTPUT_FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("TEMPLATE")
+                        .help("Print each solution using a handlebars template, or as csv or tsv, instead of its proof")
+                        .long_help("Print each solution to stdout using a handlebars template instead of its proof, \
+                                    e.g. '{{predicate}} {{args.0}}'.\n\
+                                    The fields available are predicate and args of the solved query literal, \
+                                    literals, the list of all solved literals, and variables, the values of \
+                                    the variables of the query.\n\
+                                    With csv or tsv, a table is printed instead, with a header row of the \
+                                    variables of the query and a row per solution."),
+                )
+                .arg(arg!(--"solver-stats" "Report statistics about the work done by the solver."))
+                .args(solver_limit_args())
+                .arg(arg!(--"bottom-up" "Evaluate predicates that only derive facts from facts bottom-up, before solving."))
+                .arg(arg!(--"occurs-check" "Fail to unify a variable with a list that contains it, instead of building a cyclic list."))
+                .arg(arg!(--tabling "Solve each variant of a logic subgoal once, so that left recursive predicates terminate."))
+                .arg(arg!(--parallel "Explore alternative rules on several threads."))
+                .arg(
+                    Arg::new("SELECTION")
+                        .long("selection")
+                        .takes_value(true)
+                        .value_name("RULE")
+                        .possible_values(["cheapest-first", "leftmost"])
+                        .default_value("cheapest-first")
+                        .help("Set which literal of a goal the solver resolves next")
+                        .long_help("Set which literal of a goal the solver resolves next.\n\
+                                    cheapest-first selects the literal with the fewest rules to resolve it \
+                                    with, preferring ground literals, and leftmost selects the leftmost one. \
+                                    The solutions are the same."),
+                )
+                .arg(
+                    Arg::new("PROOF_COST")
+                        .long("proof-cost")
+                        .takes_value(true)
+                        .value_name("MODEL")
+                        .possible_values(["build-steps", "layers", "height"])
+                        .default_value("build-steps")
+                        .help("Set how the cheapest proof of each solution is chosen")
+                        .long_help("Set how the cheapest proof of each solution is chosen.\n\
+                                    build-steps counts the image-producing literals of a proof, layers \
+                                    estimates the layers of its image, and height is the height of the \
+                                    proof tree. Ties are broken by height."),
+                )
+                .arg(
+                    Arg::new("MAX_DEPTH")
+                        .long("max-depth")
+                        .takes_value(true)
+                        .value_name("DEPTH")
+                        .default_value("175")
+                        .help("The depth of the proof tree up to which the search is deepened"),
+                )
+                .arg(
+                    Arg::new("TRACE_PRED")
+                        .long("trace-pred")
+                        .takes_value(true)
+                        .value_name("PREDICATES")
+                        .help("Only trace steps involving these predicates, e.g. install/2,from/1")
+                        .long_help("Only trace steps involving these predicates, e.g. install/2,from/1.\n\
+                                    A predicate without an arity matches any arity. Implies --trace."),
+                ),
         )
         .subcommand(
             Command::new("check")
-                .about("Analyse a Modusfile and checks the predicate kinds.")
+                .visible_alias("lint")
+                .about("Analyse a Modusfile, checks the predicate kinds and solves its ?- query directives.")
                 .arg(
                     Arg::new("FILE")
                         .required(false)
@@ -237,8 +1140,132 @@ fn main() {
                         .index(1)
                         .required(true)
                         .allow_invalid_utf8(true),
-                )
-                .arg(arg!(-v --verbose "display the evaluated kinds for all the clauses"))
+                )
+                .arg(arg!(-v --verbose "display the evaluated kinds for all the clauses"))
+                .arg(
+                    Arg::new("FIX")
+                        .long("fix")
+                        .help("Rewrite deprecated syntax in the Modusfile")
+                        .long_help("Rewrite the deprecated syntax in the Modusfile that can be rewritten automatically.\n\
+                                    The file is changed in place before it is checked."),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["human", "sarif"])
+                        .default_value("human")
+                        .help("Set the output format of the findings")
+                        .long_help("Set the output format of the findings.\n\
+                                    With sarif, a SARIF 2.1.0 log is written to stdout, for use with code scanning tools.")
+                )
+        )
+        .subcommand(
+            Command::new("explain")
+                .about("Show the clauses that define a predicate, and where it is used.")
+                .long_about("Show the clauses that define a predicate, with their locations, the kind and \
+                             groundness of the predicate, and the predicates that call it.")
+                .arg(
+                    Arg::new("PREDICATE")
+                        .required(true)
+                        .help("Specify the predicate to explain")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .help("Set the input Modusfile")
+                        .long_help("Set the input Modusfile\n\
+                                    The default is the Modusfile in the current directory.")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+        )
+        .subcommand(
+            Command::new("doc")
+                .about("List the predicates of a Modusfile with their documentation.")
+                .long_about("List the predicates that a Modusfile defines, with their arities, kinds and \
+                             groundness, and the `##` comments on the lines before their clauses.")
+                .arg(
+                    Arg::new("FILE")
+                        .help("Set the input Modusfile, the default is the one in the current directory")
+                        .index(1)
+                        .default_value("Modusfile")
+                        .allow_invalid_utf8(true),
+                )
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Inspect and clean up state left behind by builds.")
+                .long_about("Inspect and clean up state left behind by builds.\n\
+                             This covers temporary directories and image tags left by interrupted builds, \
+                             as well as the buildkit build cache.")
+                .subcommand_required(true)
+                .subcommand(Command::new("stats").about("Show the disk usage of build state."))
+                .subcommand(
+                    Command::new("prune")
+                        .about("Remove old build state.")
+                        .long_about("Remove old build state. At least one of --older-than and --keep-storage \
+                                     has to be given.\n\
+                                     Without --older-than, temporary directories less than an hour old are \
+                                     kept, as they may belong to a running build.")
+                        .arg(
+                            Arg::new("OLDER_THAN")
+                                .long("older-than")
+                                .takes_value(true)
+                                .value_name("DURATION")
+                                .help("Only remove entries older than this, e.g. 12h or 7d"),
+                        )
+                        .arg(
+                            Arg::new("KEEP_STORAGE")
+                                .long("keep-storage")
+                                .takes_value(true)
+                                .value_name("SIZE")
+                                .help("Amount of buildkit cache to keep, e.g. 10GB"),
+                        ),
+                )
+                .subcommand(Command::new("clear").about("Remove all build state, including the entire buildkit cache.")),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Fetch the rule libraries that a project depends on.")
+                .long_about("Fetch the rule libraries listed in the [dependencies] of modus.toml into .modus/libs, \
+                             checking their digests. A Modusfile of the project loads a dependency with \
+                             `:- use(<name>).`, and uses its predicates prefixed with the name, e.g. `python_install`.")
+                .arg(
+                    Arg::new("DIR")
+                        .help("Specify a directory of the project, the default is the current one")
+                        .index(1)
+                        .default_value(".")
+                        .allow_invalid_utf8(true),
+                ),
+        )
+        .subcommand(
+            Command::new("grammar")
+                .about("Generate a grammar for syntax highlighting in editors.")
+                .long_about("Generate a grammar for syntax highlighting in editors.\n\
+                             The grammar is derived from the tokens used by the Modusfile parser of this version of modus.")
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["textmate", "tree-sitter"])
+                        .default_value("textmate")
+                        .help("Set the kind of grammar to generate")
+                        .long_help("Set the kind of grammar to generate.\n\
+                                    textmate produces a JSON TextMate grammar, tree-sitter produces a grammar.js."),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Write the grammar to FILE instead of stdout")
+                        .allow_invalid_utf8(true),
+                ),
         )
         .get_matches();
 
@@ -246,6 +1273,10 @@ fn main() {
     let err_writer = StandardStream::stderr(codespan_reporting::term::termcolor::ColorChoice::Auto);
     let config = codespan_reporting::term::Config::default();
 
+    if matches.value_of("LOG_FORMAT") == Some("json") {
+        logging::set_format(LogFormat::Json);
+    }
+
     fn print_diagnostics<'files, F: codespan_reporting::files::Files<'files, FileId = ()>>(
         diags: &[Diagnostic<()>],
         writer: &mut dyn WriteColor,
@@ -253,70 +1284,143 @@ fn main() {
         files: &'files F,
     ) {
         for diagnostic in diags {
-            term::emit(writer, config, files, diagnostic).expect("Error when printing to term.")
+            if logging::is_json() {
+                logging::event(
+                    "diagnostic",
+                    json!({
+                        "severity": format!("{:?}", diagnostic.severity).to_lowercase(),
+                        "code": diagnostic.code,
+                        "message": diagnostic.message,
+                        "labels": diagnostic.labels.iter().map(|l| json!({
+                            "start": l.range.start,
+                            "end": l.range.end,
+                            "message": l.message,
+                        })).collect::<Vec<_>>(),
+                        "notes": diagnostic.notes,
+                    }),
+                );
+            } else {
+                term::emit(writer, config, files, diagnostic).expect("Error when printing to term.")
+            }
         }
     }
 
     match matches.subcommand().unwrap() {
         ("transpile", sub) => {
             let input_file = sub.value_of("FILE").unwrap();
-            let file = get_file_or_exit(Path::new(input_file));
-            let query: modusfile::Expression = match sub
-                .value_of("QUERY")
-                .map(|s| s.parse::<modusfile::Expression>())
+            let file = get_modusfile_or_exit(Path::new(input_file));
+            let queries: Vec<modusfile::Expression> = sub
+                .values_of("QUERY")
                 .unwrap()
-            {
-                Ok(e) => e.without_position(),
-                Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
-                    let temp_file =
-                        SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
-                }
-            };
+                .map(|s| match s.parse::<modusfile::Expression>() {
+                    Ok(e) => e.without_position(),
+                    Err(e) => {
+                        report_failure("Did not parse goal successfully");
+                        let temp_file = SimpleFile::new("goal", s);
+                        print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
+                        ExitCode::ParseError.exit();
+                    }
+                })
+                .collect();
 
-            let mf: Modusfile = match file.source().parse() {
+            let mut mf: Modusfile = match file.source().parse() {
                 Ok(mf) => mf,
                 Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully",);
+                    report_failure("Did not parse Modusfile successfully");
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
                 }
             };
-            let kind_res = mf.kinds();
-            if !analysis::check_and_output_analysis(
-                &kind_res,
-                &mf,
-                Some(&query),
-                false,
+            print_diagnostics(
+                &deprecation_warnings(&mf, &file),
                 &mut err_writer.lock(),
                 &config,
                 &file,
-            ) {
-                std::process::exit(1)
+            );
+            if let Some(platform) = sub.value_of("PLATFORM") {
+                mf.add_target_platform(platform);
+            }
+            let kind_res = mf.kinds();
+            if !queries.iter().all(|query| {
+                analysis::check_and_output_analysis(
+                    &kind_res,
+                    &mf,
+                    Some(query),
+                    false,
+                    &mut err_writer.lock(),
+                    &config,
+                    &file,
+                )
+            }) {
+                ExitCode::WellformednessError.exit()
             }
 
-            let df_res = transpiler::transpile(mf, query);
+            let mut profile = interruptible_profile(
+                sld::SolveProfile::default()
+                    .with_limits(solver_limits(sub))
+                    .with_bottom_up(sub.is_present("bottom-up")),
+            );
+            let strategy = sub.value_of_t_or_exit("SEARCH");
+            let join_runs = |df: dockerfile::ResolvedDockerfile| {
+                if sub.is_present("JOIN_RUNS") {
+                    df.with_joined_runs()
+                } else {
+                    df
+                }
+            };
+            let df_res = match sub.value_of_os("OUTPUT_DIR").map(Path::new) {
+                Some(dir) => transpiler::transpile_queries_per_output(
+                    &file,
+                    &mf,
+                    &queries,
+                    strategy,
+                    &mut profile,
+                )
+                .map(|dfs| {
+                    let dfs = dfs
+                        .into_iter()
+                        .map(|(name, df)| (name, join_runs(df)))
+                        .collect::<Vec<_>>();
+                    write_dockerfiles(dir, &dfs)
+                }),
+                None => transpiler::transpile_queries(&file, &mf, &queries, strategy, &mut profile)
+                    .map(|df| println!("{}", join_runs(df))),
+            };
+            if sub.is_present("solver-stats") {
+                report_solver_stats(&profile.stats);
+            }
 
             match df_res {
-                Ok(df) => println!("{}", df),
+                Ok(()) => {}
                 Err(e) => {
-                    for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                            .expect("Error when printing to stderr.")
+                    if let ModusError::Cancelled = e {
+                        report_cancelled(&profile);
                     }
-                    std::process::exit(1)
+                    print_diagnostics(&e.diagnostics(), &mut err_writer.lock(), &config, &file);
+                    ExitCode::from_error(&e).exit()
                 }
             }
         }
-        ("build", sub) => {
-            let context_dir = sub.value_of_os("CONTEXT").unwrap();
+        (subcommand @ ("build" | "test"), sub) => {
+            let cloned_context = sub
+                .value_of_os("CONTEXT")
+                .and_then(OsStr::to_str)
+                .and_then(buildkit::GitContext::parse)
+                .map(|git_context| {
+                    buildkit::shallow_clone(&git_context).unwrap_or_else(|e| {
+                        print_build_error_and_exit(&e.to_string(), ExitCode::Other, &err_writer)
+                    })
+                });
+            let context_dir = match &cloned_context {
+                Some(cloned) => cloned.path().as_os_str(),
+                None => sub.value_of_os("CONTEXT").unwrap(),
+            };
+            affected::set_context_dir(Path::new(context_dir));
             let input_file = sub
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
+            let file = get_modusfile_or_exit(input_file.as_path());
             let query: modusfile::Expression = match sub
                 .value_of("QUERY")
                 .map(|s| s.parse::<modusfile::Expression>())
@@ -324,24 +1428,41 @@ fn main() {
             {
                 Ok(e) => e.without_position(),
                 Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
+                    report_failure("Did not parse goal successfully");
                     let temp_file =
                         SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
                 }
             };
 
             let parse_start = Instant::now();
 
-            let mf: Modusfile = match file.source().parse() {
+            let mut mf: Modusfile = match file.source().parse() {
                 Ok(mf) => mf,
                 Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully.",);
+                    report_failure("Did not parse Modusfile successfully");
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
                 }
             };
+            print_diagnostics(
+                &deprecation_warnings(&mf, &file),
+                &mut err_writer.lock(),
+                &config,
+                &file,
+            );
+            if let Some(platform) = sub.value_of("PLATFORM") {
+                mf.add_target_platform(platform);
+            }
+            logging::event(
+                "parse",
+                json!({
+                    "file": input_file.display().to_string(),
+                    "clauses": mf.0.len(),
+                    "duration_secs": parse_start.elapsed().as_secs_f32(),
+                }),
+            );
             let kind_res = mf.kinds();
             if !analysis::check_and_output_analysis(
                 &kind_res,
@@ -352,22 +1473,291 @@ fn main() {
                 &config,
                 &file,
             ) {
-                std::process::exit(1)
+                ExitCode::WellformednessError.exit()
             }
 
-            let build_plan = match imagegen::plan_from_modusfile(mf, query) {
+            let output_format = sub.value_of("OUTPUT_FORMAT");
+            if let Some(template) = output_format {
+                if let Err(e) = reporting::render_template::<reporting::Image>(template, &[]) {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                }
+            }
+
+            let solve_start = Instant::now();
+            let mut profile = interruptible_profile(
+                sld::SolveProfile::default()
+                    .with_limits(solver_limits(sub))
+                    .with_bottom_up(sub.is_present("bottom-up")),
+            );
+            let strategy = sub.value_of_t_or_exit("SEARCH");
+            let plan_res = imagegen::plan_from_modusfile(mf, query.clone(), strategy, &mut profile);
+            if sub.is_present("solver-stats") {
+                report_solver_stats(&profile.stats);
+            }
+            let mut build_plan = match plan_res {
                 Ok(plan) => plan,
                 Err(e) => {
-                    for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                            .expect("Error when printing to stderr.")
+                    if let ModusError::Cancelled = e {
+                        report_cancelled(&profile);
                     }
-                    std::process::exit(1)
+                    print_diagnostics(&e.diagnostics(), &mut err_writer.lock(), &config, &file);
+                    ExitCode::from_error(&e).exit()
                 }
             };
+            logging::event(
+                "solve",
+                json!({
+                    "query": sub.value_of("QUERY"),
+                    "outputs": build_plan.outputs.iter().map(|o| o.source_literal.as_ref().map(|l| l.to_string())).collect::<Vec<_>>(),
+                    "nodes": build_plan.nodes.len(),
+                    "solver": profile.stats,
+                    "duration_secs": solve_start.elapsed().as_secs_f32(),
+                }),
+            );
+            if sub.is_present("CHOOSE") {
+                if !std::io::stdin().is_terminal() {
+                    report_failure("--choose needs an interactive terminal.");
+                    ExitCode::Other.exit()
+                }
+                build_plan = choose::choose_output(
+                    &build_plan,
+                    &query,
+                    std::io::stdin().lock(),
+                    std::io::stderr(),
+                )
+                .unwrap_or_else(|e| {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                });
+            }
+            let policy_path = sub.value_of_os("POLICY").map(PathBuf::from).or_else(|| {
+                Some(Path::new(context_dir).join(policy::POLICY_FILE)).filter(|p| p.exists())
+            });
+            if let Some(policy_path) = policy_path {
+                let policy = policy::Policy::from_file(&policy_path).unwrap_or_else(|e| {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                });
+                let violations = policy.check(&build_plan);
+                if !violations.is_empty() {
+                    print_diagnostics(&violations, &mut err_writer.lock(), &config, &file);
+                    ExitCode::PolicyViolation.exit()
+                }
+            }
+            if let Some(rules_path) = sub.value_of_os("POLICY_RULES") {
+                let rules_file = get_file_or_exit(Path::new(rules_path));
+                let rules: Modusfile = match rules_file.source().parse() {
+                    Ok(mf) => mf,
+                    Err(e) => {
+                        report_failure("Did not parse policy rules successfully");
+                        print_diagnostics(&e, &mut err_writer.lock(), &config, &rules_file);
+                        ExitCode::ParseError.exit();
+                    }
+                };
+                match policy::check_rules(&rules, &build_plan) {
+                    Ok(reasons) if reasons.is_empty() => {}
+                    Ok(reasons) => {
+                        let violations = reasons
+                            .into_iter()
+                            .map(|reason| {
+                                Diagnostic::error()
+                                    .with_code(policy::POLICY_VIOLATION_CODE)
+                                    .with_message(reason)
+                            })
+                            .collect::<Vec<_>>();
+                        print_diagnostics(
+                            &violations,
+                            &mut err_writer.lock(),
+                            &config,
+                            &rules_file,
+                        );
+                        ExitCode::PolicyViolation.exit()
+                    }
+                    Err(e) => {
+                        report_failure(&e);
+                        ExitCode::Other.exit()
+                    }
+                }
+            }
+            let insecure_nodes = build_plan.insecure_nodes();
+            if !insecure_nodes.is_empty() {
+                let diagnostics = insecure_nodes
+                    .into_iter()
+                    .map(|node| {
+                        let diag = Diagnostic::error().with_message(format!(
+                            "Node n_{} runs a command with ::insecure, which modus build does not \
+                             support as its frontend always sandboxes commands.",
+                            node
+                        ));
+                        let diag = match build_plan.source(node).and_then(|l| l.position.as_ref()) {
+                            Some(pos) => diag.with_labels(vec![Label::primary(
+                                (),
+                                pos.offset..pos.offset + pos.length,
+                            )
+                            .with_message("the command is run by this rule")]),
+                            None => diag,
+                        };
+                        diag.with_notes(vec![
+                            "transpile the Modusfile with `modus transpile` and build it with \
+                             `docker buildx build --allow security.insecure`, on a builder that \
+                             allows the security.insecure entitlement"
+                                .to_owned(),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                print_diagnostics(&diagnostics, &mut err_writer.lock(), &config, &file);
+                ExitCode::Other.exit()
+            }
+            let secrets = if sub.is_present("SECRET") {
+                sub.values_of_t_or_exit::<buildkit::SecretSource>("SECRET")
+            } else {
+                Vec::new()
+            };
+            let secrets = secrets
+                .into_iter()
+                .map(|secret| {
+                    secret.resolve().unwrap_or_else(|e| {
+                        report_failure(&e);
+                        ExitCode::Other.exit()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let missing_secrets = build_plan
+                .secret_nodes()
+                .into_iter()
+                .filter(|(id, _)| !secrets.iter().any(|s| s.id == *id))
+                .map(|(id, nodes)| {
+                    let labels = nodes
+                        .into_iter()
+                        .filter_map(|node| build_plan.source(node)?.position.as_ref())
+                        .map(|pos| {
+                            Label::primary((), pos.offset..pos.offset + pos.length)
+                                .with_message("the secret is mounted by this rule")
+                        })
+                        .collect();
+                    Diagnostic::error()
+                        .with_message(format!("The secret {} has no source.", id))
+                        .with_labels(labels)
+                        .with_notes(vec![format!(
+                            "give it with --secret id={},src=<FILE> or --secret id={},env=<VAR>",
+                            id, id
+                        )])
+                })
+                .collect::<Vec<_>>();
+            if !missing_secrets.is_empty() {
+                print_diagnostics(&missing_secrets, &mut err_writer.lock(), &config, &file);
+                ExitCode::Other.exit()
+            }
+            let lockfile_path = lockfile_path(sub, context_dir);
+            if sub.is_present("LOCKFILE") || lockfile_path.exists() {
+                let lockfile = lock::Lockfile::from_file(&lockfile_path).unwrap_or_else(|e| {
+                    report_failure(&e.to_string());
+                    ExitCode::Other.exit()
+                });
+                let unlocked = lockfile.pin(&mut build_plan);
+                if !unlocked.is_empty() {
+                    logging::progress("images_not_locked", json!({ "images": unlocked }), || {
+                        format!(
+                            "{} {} not locked in {}, run `modus lock` to lock them.",
+                            "Warning:".yellow(),
+                            unlocked.join(", "),
+                            lockfile_path.display()
+                        )
+                    });
+                }
+            }
+            if let Some(rev) = sub.value_of("AFFECTED_ONLY") {
+                let project_files = vec![
+                    Some(input_file.clone()),
+                    package::find_root(&fs::canonicalize(context_dir).unwrap_or_default())
+                        .map(|root| root.join(package::MANIFEST_FILE)),
+                    Some(lockfile_path.clone()).filter(|p| p.exists()),
+                ]
+                .into_iter()
+                .flatten()
+                .map(|p| {
+                    fs::canonicalize(&p)
+                        .unwrap_or(p)
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect::<Vec<_>>();
+                let (affected_plan, unaffected) =
+                    affected::affected_outputs(&build_plan, &project_files, |path| {
+                        affected::changed_since(rev, path)
+                    })
+                    .unwrap_or_else(|e| {
+                        report_failure(&e);
+                        ExitCode::Other.exit()
+                    });
+                if !unaffected.is_empty() {
+                    let names = unaffected
+                        .iter()
+                        .map(|o| {
+                            o.source_literal
+                                .as_ref()
+                                .map_or_else(|| format!("n_{}", o.node), |l| l.to_string())
+                        })
+                        .collect::<Vec<_>>();
+                    logging::progress(
+                        "skipped_unaffected",
+                        json!({ "revision": rev, "outputs": names }),
+                        || format!("Skipping {}, unchanged since {}.", names.join(", "), rev),
+                    );
+                }
+                build_plan = affected_plan;
+                if build_plan.outputs.is_empty() {
+                    logging::progress("nothing_affected", json!({ "revision": rev }), || {
+                        format!("No output is affected by the changes since {}.", rev)
+                    });
+                    return;
+                }
+            }
+            if sub.is_present("DRY_RUN") {
+                print_dry_run(&build_plan.stats());
+                return;
+            }
+            if let Some(tags) = sub.values_of("TAG") {
+                if build_plan.outputs.len() != 1 {
+                    report_failure(&format!(
+                        "-t names a single image, but the query has {} solutions.",
+                        build_plan.outputs.len()
+                    ));
+                    ExitCode::Other.exit()
+                }
+                for tag in tags {
+                    if tag.is_empty() || tag.contains(char::is_whitespace) {
+                        report_failure(&format!("{:?} is not a valid image tag.", tag));
+                        ExitCode::Other.exit()
+                    }
+                    if !build_plan.outputs[0].tags.iter().any(|t| t == tag) {
+                        build_plan.outputs[0].tags.push(tag.to_owned());
+                    }
+                }
+            }
+            if sub.is_present("PUSH") && build_plan.outputs.iter().all(|o| o.tags.is_empty()) {
+                print_build_error_and_exit(
+                    "--push was given, but no image is named with ::tag.",
+                    ExitCode::Other,
+                    &err_writer,
+                );
+            }
+            if sub.is_present("PROVENANCE") {
+                let provenance =
+                    reporting::Provenance::new(file.source(), sub.value_of("QUERY").unwrap());
+                reporting::annotate_outputs(&mut build_plan, &provenance);
+            }
+            if sub.is_present("OCI_LABELS") {
+                imagegen::add_oci_labels(&mut build_plan, &git_oci_labels(context_dir));
+            }
 
-            fn print_build_error_and_exit(e_str: &str, w: &StandardStream) -> ! {
+            fn print_build_error_and_exit(e_str: &str, code: ExitCode, w: &StandardStream) -> ! {
                 let mut w = w.lock();
+                if logging::is_json() {
+                    logging::event("build_error", json!({ "message": e_str }));
+                    code.exit()
+                }
                 (move || -> std::io::Result<()> {
                     w.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
                     write!(w, "build error")?;
@@ -381,7 +1771,7 @@ fn main() {
                     Ok(())
                 })()
                 .expect("Unable to write to stderr.");
-                std::process::exit(1)
+                code.exit()
             }
 
             let options = BuildOptions {
@@ -393,6 +1783,7 @@ fn main() {
                     .unwrap_or_else(|_| {
                         print_build_error_and_exit(
                             "invalid resolve concurrency - expected number",
+                            ExitCode::Other,
                             &err_writer,
                         )
                     }),
@@ -402,15 +1793,26 @@ fn main() {
                         s.parse().unwrap_or_else(|_| {
                             print_build_error_and_exit(
                                 "invalid export concurrency - expected number",
+                                ExitCode::Other,
                                 &err_writer,
                             )
                         })
                     })
                     .unwrap_or_else(|| num_cpus::get() as u32), // Cast: we're not getting 2^32 CPU computers anytime soon
+                signature_policy: sub
+                    .is_present("VERIFY_SIGNATURES")
+                    .then(|| sub.value_of_t_or_exit("VERIFY_SIGNATURES")),
                 docker_build_options: DockerBuildOptions {
                     verbose: sub.is_present("VERBOSE"),
                     no_cache: sub.is_present("NO_CACHE"),
+                    load: sub.is_present("LOAD"),
                     quiet: false,
+                    platform: build_plan.platform.clone(),
+                    build_contexts: sub
+                        .values_of("BUILD_CONTEXT")
+                        .map(|x| x.map(absolute_build_context).collect())
+                        .unwrap_or_default(),
+                    secrets,
                     additional_args: sub
                         .values_of("ADDITIONAL_OPTS")
                         .map(|x| x.map(ToOwned::to_owned).collect())
@@ -418,16 +1820,162 @@ fn main() {
                 },
             };
 
-            let mut profiling = Profiling::default();
-            profiling.planning = parse_start.elapsed().as_secs_f32();
+            let mut profiling = Profiling {
+                planning: parse_start.elapsed().as_secs_f32(),
+                ..Default::default()
+            };
 
             match buildkit::build(build_plan.clone(), context_dir, &options, &mut profiling) {
                 Err(e) => {
-                    print_build_error_and_exit(&e.to_string(), &err_writer);
+                    if let buildkit::BuildError::DockerBuildFailed(_, Some(node)) = e {
+                        print_diagnostics(
+                            &[debug::failed_node_diagnostic(&build_plan, node)],
+                            &mut err_writer.lock(),
+                            &config,
+                            &file,
+                        );
+                    }
+                    if sub.is_present("EXPORT_FAILED_STATE")
+                        && matches!(e, buildkit::BuildError::DockerBuildFailed(..))
+                    {
+                        match debug::export_failed_state(&build_plan, context_dir, &options) {
+                            Ok(Some(failed)) => {
+                                let mut diag = Diagnostic::note().with_message(format!(
+                                    "The command {} of node n_{} failed. The state it ran in is tagged {}, \
+                                     see `docker run -it --rm {} sh`.",
+                                    failed.commands.join(", "),
+                                    failed.node,
+                                    failed.tag,
+                                    failed.tag
+                                ));
+                                if let Some(pos) = build_plan
+                                    .source(failed.node)
+                                    .and_then(|l| l.position.as_ref())
+                                {
+                                    diag = diag.with_labels(vec![Label::primary(
+                                        (),
+                                        pos.offset..pos.offset + pos.length,
+                                    )
+                                    .with_message("the command is run by this rule")]);
+                                }
+                                print_diagnostics(&[diag], &mut err_writer.lock(), &config, &file);
+                            }
+                            Ok(None) => report_failure(
+                                "No command failed when building step by step, so no state was exported.",
+                            ),
+                            Err(e) => report_failure(&format!(
+                                "Unable to export the state of the failed command: {}",
+                                e
+                            )),
+                        }
+                    }
+                    print_build_error_and_exit(
+                        &e.to_string(),
+                        ExitCode::from_build_error(&e),
+                        &err_writer,
+                    );
                 }
-                Ok(image_ids) => {
+                Ok(built) => {
+                    let image_ids = &built.image_ids;
+                    if subcommand == "test" || sub.is_present("VERIFY") {
+                        let violations = verify::verify(&build_plan, context_dir, &options)
+                            .unwrap_or_else(|e| {
+                                print_build_error_and_exit(
+                                    &format!("Unable to check the assertions: {}", e),
+                                    ExitCode::from_build_error(&e),
+                                    &err_writer,
+                                )
+                            });
+                        if !violations.is_empty() {
+                            let diagnostics = violations
+                                .iter()
+                                .map(|v| {
+                                    let diag = Diagnostic::error()
+                                        .with_code(verify::ASSERTION_FAILED_CODE)
+                                        .with_message(format!(
+                                            "{} does not hold for node n_{}: {}",
+                                            v.assertion, v.node, v.output
+                                        ));
+                                    match build_plan
+                                        .source(v.node)
+                                        .and_then(|l| l.position.as_ref())
+                                    {
+                                        Some(pos) => diag.with_labels(vec![Label::primary(
+                                            (),
+                                            pos.offset..pos.offset + pos.length,
+                                        )
+                                        .with_message("the image is built by this rule")]),
+                                        None => diag,
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+                            print_diagnostics(&diagnostics, &mut err_writer.lock(), &config, &file);
+                            ExitCode::AssertionFailed.exit()
+                        }
+                        logging::progress(
+                            "assertions_checked",
+                            json!({ "assertions": build_plan.assertions.len() }),
+                            || format!("All {} assertions hold.", build_plan.assertions.len()),
+                        );
+                    }
+                    if let Err(e) =
+                        buildkit::tag_outputs(&build_plan, image_ids, sub.is_present("PUSH"))
+                    {
+                        print_build_error_and_exit(
+                            &e.to_string(),
+                            ExitCode::from_build_error(&e),
+                            &err_writer,
+                        );
+                    }
+                    let mut images = reporting::built_images(&build_plan, &image_ids[..]);
+                    if sub.is_present("PUSH") {
+                        let pushed = registry::pushed_digests(&build_plan).unwrap_or_else(|e| {
+                            print_build_error_and_exit(
+                                &e.to_string(),
+                                ExitCode::RegistryError,
+                                &err_writer,
+                            )
+                        });
+                        for (image, pushed) in images.iter_mut().zip(pushed) {
+                            image.pushed = pushed;
+                        }
+                    }
+                    if sub.is_present("CACHE_REPORT") {
+                        let context = fs::canonicalize(context_dir)
+                            .unwrap_or_else(|_| PathBuf::from(context_dir));
+                        let record =
+                            cache_report::record(&build_plan, &built.base_images, &context);
+                        let record_path =
+                            cache_report::record_path(&context, sub.value_of("QUERY").unwrap());
+                        let previous = record_path.as_deref().and_then(cache_report::read_record);
+                        let reports = cache_report::report(
+                            &build_plan,
+                            &record,
+                            previous.as_ref(),
+                            sub.is_present("NO_CACHE"),
+                            &built.base_images,
+                        );
+                        logging::progress("cache_report", json!({ "nodes": reports }), || {
+                            cache_report::summary(&reports).trim_end().to_owned()
+                        });
+                        if let Some(path) = record_path {
+                            if let Err(e) = cache_report::write_record(&path, &record) {
+                                logging::progress(
+                                    "cache_report_not_saved",
+                                    json!({ "message": e }),
+                                    || format!("{} {}", "Warning:".yellow(), e),
+                                );
+                            }
+                        }
+                    }
                     let total_dur = parse_start.elapsed();
                     profiling.total = total_dur.as_secs_f32();
+                    if let Some(template) = output_format {
+                        match reporting::render_template(template, &images) {
+                            Ok(out) => print!("{}", out),
+                            Err(e) => print_build_error_and_exit(&e, ExitCode::Other, &err_writer),
+                        }
+                    }
                     if sub.is_present("JSON_OUTPUT") {
                         let json_out_name;
                         let mut json_out_f;
@@ -444,8 +1992,9 @@ fn main() {
                                         &format!(
                                             "Unable to open {} for writing: {}.",
                                             o_path.to_string_lossy(),
-                                            &e
+                                            e
                                         ),
+                                        ExitCode::Other,
                                         &err_writer,
                                     );
                                 }
@@ -456,19 +2005,23 @@ fn main() {
                             json_out = &mut json_out_stdout;
                             json_out_name = OsStr::new("stdout");
                         }
+                        let nodes = sub
+                            .is_present("NODE_DIGESTS")
+                            .then(|| reporting::node_reports(&build_plan, &built.base_images));
                         if let Err(e) = reporting::write_build_result(
                             json_out,
-                            &json_out_name.to_string_lossy(),
-                            &build_plan,
-                            &image_ids[..],
+                            json_out_name.to_string_lossy(),
+                            &images,
+                            nodes.as_deref(),
                         ) {
-                            print_build_error_and_exit(&e, &err_writer);
+                            print_build_error_and_exit(&e, ExitCode::Other, &err_writer);
                         }
                     }
                     if let Some(out) = sub.value_of_os("PROFILING") {
                         if let Err(e) = reporting::write_profiling_result(&profiling, out) {
                             print_build_error_and_exit(
                                 &format!("Unable to write profiling JSON: {}", e),
+                                ExitCode::Other,
                                 &err_writer,
                             );
                         }
@@ -476,17 +2029,340 @@ fn main() {
                 }
             }
         }
+        (subcommand @ ("plan" | "debug" | "lock" | "update" | "verify"), sub) => {
+            let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            affected::set_context_dir(Path::new(context_dir));
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
+            let file = get_modusfile_or_exit(input_file.as_path());
+            let recorded = (subcommand == "verify").then(|| {
+                let path = Path::new(sub.value_of_os("RESULT").unwrap());
+                reproduce::BuildResult::from_file(path).unwrap_or_else(|e| {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                })
+            });
+            let query_str = sub
+                .value_of("QUERY")
+                .or_else(|| recorded.as_ref().and_then(reproduce::BuildResult::query))
+                .unwrap_or_else(|| {
+                    report_failure(
+                        "The build result does not record its query, so it must be given.",
+                    );
+                    ExitCode::Other.exit()
+                })
+                .to_owned();
+            let query: modusfile::Expression = match query_str.parse::<modusfile::Expression>() {
+                Ok(e) => e.without_position(),
+                Err(e) => {
+                    report_failure("Did not parse goal successfully");
+                    let temp_file = SimpleFile::new("goal", query_str.as_str());
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
+                    ExitCode::ParseError.exit();
+                }
+            };
+            let mut mf: Modusfile = match file.source().parse() {
+                Ok(mf) => mf,
+                Err(e) => {
+                    report_failure("Did not parse Modusfile successfully");
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    ExitCode::ParseError.exit();
+                }
+            };
+            print_diagnostics(
+                &deprecation_warnings(&mf, &file),
+                &mut err_writer.lock(),
+                &config,
+                &file,
+            );
+            if let Some(platform) = sub.value_of("PLATFORM") {
+                mf.add_target_platform(platform);
+            }
+            let kind_res = mf.kinds();
+            if !analysis::check_and_output_analysis(
+                &kind_res,
+                &mf,
+                Some(&query),
+                false,
+                &mut err_writer.lock(),
+                &config,
+                &file,
+            ) {
+                ExitCode::WellformednessError.exit()
+            }
+
+            let strategy = sub.value_of_t_or_exit("SEARCH");
+            let mut profile = interruptible_profile(
+                sld::SolveProfile::default()
+                    .with_limits(solver_limits(sub))
+                    .with_bottom_up(sub.is_present("bottom-up")),
+            );
+            let mut build_plan =
+                match imagegen::plan_from_modusfile(mf, query, strategy, &mut profile) {
+                    Ok(plan) => plan,
+                    Err(e) => {
+                        if let ModusError::Cancelled = e {
+                            report_cancelled(&profile);
+                        }
+                        print_diagnostics(&e.diagnostics(), &mut err_writer.lock(), &config, &file);
+                        ExitCode::from_error(&e).exit()
+                    }
+                };
+            if subcommand == "lock" || subcommand == "update" {
+                let path = lockfile_path(sub, context_dir);
+                let mut lockfile = lock::Lockfile::from_file(&path).unwrap_or_else(|e| {
+                    report_failure(&e.to_string());
+                    ExitCode::Other.exit()
+                });
+                let changed = lockfile
+                    .lock(&build_plan, subcommand == "update", |image_ref| {
+                        logging::progress("lock_resolve", json!({ "image": image_ref }), || {
+                            format!("Resolving {}...", image_ref)
+                        });
+                        lock::resolve_digest(image_ref)
+                    })
+                    .and_then(|changed| lockfile.write_to_file(&path).map(|_| changed))
+                    .unwrap_or_else(|e| {
+                        report_failure(&e.to_string());
+                        ExitCode::from_lock_error(&e).exit()
+                    });
+                for image_ref in changed.iter() {
+                    logging::progress(
+                        "image_locked",
+                        json!({ "image": image_ref, "digest": lockfile.images[image_ref] }),
+                        || format!("Locked {} to {}", image_ref, lockfile.images[image_ref]),
+                    );
+                }
+                if changed.is_empty() {
+                    logging::progress("lockfile_unchanged", json!({}), || {
+                        format!("{} is up to date.", path.display())
+                    });
+                }
+            } else if subcommand == "debug" {
+                let node = sub
+                    .value_of_t_or_exit::<debug::NodeSelector>("NODE")
+                    .select(&build_plan)
+                    .unwrap_or_else(|e| {
+                        report_failure(&e);
+                        ExitCode::Other.exit()
+                    });
+                let options = BuildOptions {
+                    frontend_image: sub.value_of("CUSTOM_FRONTEND").unwrap().to_owned(),
+                    resolve_concurrency: 3,
+                    export_concurrency: 1,
+                    signature_policy: None,
+                    docker_build_options: DockerBuildOptions {
+                        platform: build_plan.platform.clone(),
+                        // The shell runs in a container started by the local docker daemon.
+                        load: true,
+                        ..Default::default()
+                    },
+                };
+                let built = buildkit::build(
+                    debug::plan_up_to(&build_plan, node),
+                    context_dir,
+                    &options,
+                    &mut Profiling::default(),
+                )
+                .unwrap_or_else(|e| {
+                    report_failure(&e.to_string());
+                    ExitCode::from_build_error(&e).exit()
+                });
+                match debug::run_shell(&built.image_ids[0], sub.value_of("SHELL").unwrap()) {
+                    Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+                    Err(e) => {
+                        report_failure(&format!("Unable to run docker: {}", e));
+                        ExitCode::Other.exit()
+                    }
+                }
+            } else if let Some(recorded) = recorded {
+                let lockfile_path = lockfile_path(sub, context_dir);
+                if sub.is_present("LOCKFILE") || lockfile_path.exists() {
+                    let lockfile = lock::Lockfile::from_file(&lockfile_path).unwrap_or_else(|e| {
+                        report_failure(&e.to_string());
+                        ExitCode::Other.exit()
+                    });
+                    lockfile.pin(&mut build_plan);
+                }
+                recorded.restore_annotations(&mut build_plan);
+                if sub.is_present("OCI_LABELS") {
+                    imagegen::add_oci_labels(&mut build_plan, &git_oci_labels(context_dir));
+                }
+                let options = BuildOptions {
+                    frontend_image: sub.value_of("CUSTOM_FRONTEND").unwrap().to_owned(),
+                    resolve_concurrency: 3,
+                    export_concurrency: 1,
+                    signature_policy: None,
+                    docker_build_options: DockerBuildOptions {
+                        platform: build_plan.platform.clone(),
+                        // The layers of the images are compared by the local docker daemon.
+                        load: true,
+                        ..Default::default()
+                    },
+                };
+                let built = buildkit::build(
+                    build_plan.clone(),
+                    context_dir,
+                    &options,
+                    &mut Profiling::default(),
+                )
+                .unwrap_or_else(|e| {
+                    report_failure(&e.to_string());
+                    ExitCode::from_build_error(&e).exit()
+                });
+                let comparisons =
+                    reproduce::compare_images(&recorded, &build_plan, &built.image_ids);
+                for c in comparisons.iter() {
+                    logging::progress("image_compared", json!(c), || {
+                        match (&c.recorded, &c.rebuilt) {
+                            _ if c.is_reproduced() => {
+                                format!("{} {} is reproduced.", "ok:".green(), c.literal)
+                            }
+                            (Some(recorded), Some(rebuilt)) => format!(
+                                "{} {} was {}, but is rebuilt as {}.",
+                                "mismatch:".red(),
+                                c.literal,
+                                recorded,
+                                rebuilt
+                            ),
+                            (Some(_), None) => format!(
+                                "{} {} is not a solution of the query anymore.",
+                                "missing:".red(),
+                                c.literal
+                            ),
+                            _ => format!("{} {} was not recorded.", "new:".red(), c.literal),
+                        }
+                    });
+                }
+                if comparisons
+                    .iter()
+                    .all(reproduce::ImageComparison::is_reproduced)
+                {
+                    return;
+                }
+
+                let node_diagnostic = |node: imagegen::NodeId, message: String| {
+                    let diag = Diagnostic::error()
+                        .with_code(reproduce::NOT_REPRODUCIBLE_CODE)
+                        .with_message(message);
+                    match build_plan.source(node).and_then(|l| l.position.as_ref()) {
+                        Some(pos) => diag.with_labels(vec![Label::primary(
+                            (),
+                            pos.offset..pos.offset + pos.length,
+                        )
+                        .with_message("the node is built by this rule")]),
+                        None => diag,
+                    }
+                };
+                let nodes = reporting::node_reports(&build_plan, &built.base_images);
+                let mut diagnostics = recorded
+                    .nodes()
+                    .map(|recorded_nodes| {
+                        reproduce::diverging_nodes(&build_plan, &nodes, recorded_nodes)
+                    })
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|n| {
+                        node_diagnostic(
+                            n.node,
+                            format!(
+                                "The builds start to differ at node n_{} ({}), whose digest {} was not recorded.",
+                                n.node, n.instruction, n.cache_key
+                            ),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if diagnostics.is_empty() {
+                    // The same instructions were run on the same images, so look for the
+                    // layer that differs.
+                    for c in comparisons.iter().filter(|c| !c.is_reproduced()) {
+                        let (node, recorded_id, rebuilt_id) =
+                            match (c.node, &c.recorded, &c.rebuilt) {
+                                (Some(node), Some(recorded_id), Some(rebuilt_id)) => {
+                                    (node, recorded_id, rebuilt_id)
+                                }
+                                _ => continue,
+                            };
+                        diagnostics.push(match reproduce::compare_layers(
+                            &build_plan,
+                            node,
+                            recorded_id,
+                            rebuilt_id,
+                        ) {
+                            Some(reproduce::LayerDivergence::Node(node)) => node_diagnostic(
+                                node,
+                                format!(
+                                    "The layers of {} start to differ at node n_{} ({}), although it ran on the same image.",
+                                    c.literal,
+                                    node,
+                                    build_plan.nodes[node].name()
+                                ),
+                            ),
+                            Some(reproduce::LayerDivergence::BaseImage) => Diagnostic::error()
+                                .with_code(reproduce::NOT_REPRODUCIBLE_CODE)
+                                .with_message(format!(
+                                    "The base image of {} has different layers.",
+                                    c.literal
+                                )),
+                            Some(reproduce::LayerDivergence::Config) => Diagnostic::error()
+                                .with_code(reproduce::NOT_REPRODUCIBLE_CODE)
+                                .with_message(format!(
+                                    "The layers of {} are the same, but its config differs.",
+                                    c.literal
+                                )),
+                            None => Diagnostic::error()
+                                .with_code(reproduce::NOT_REPRODUCIBLE_CODE)
+                                .with_message(format!("{} is not reproduced.", c.literal))
+                                .with_notes(vec![format!(
+                                    "write the result with --node-digests, or pull {}, to find \
+                                     where the builds differ",
+                                    recorded_id
+                                )]),
+                        });
+                    }
+                }
+                print_diagnostics(&diagnostics, &mut err_writer.lock(), &config, &file);
+                ExitCode::NotReproducible.exit()
+            } else if !sub.is_present("stats") {
+                println!("{}", serde_json::to_string_pretty(&build_plan).unwrap());
+            } else if logging::is_json() {
+                println!("{}", json!(build_plan.stats()));
+            } else {
+                print!("{}", build_plan.stats());
+            }
+        }
         ("proof", sub) => {
             let should_output_graph = sub.is_present("graph");
+            let should_output_json = sub.is_present("json");
+            let should_output_proof_graph = sub.is_present("proof-graph");
             let should_explain = sub.is_present("explain");
             let compact = sub.is_present("compact");
+            let should_print_tree = sub.is_present("tree");
+            let trace_filter = sub.value_of("TRACE_PRED").map(|s| {
+                s.parse::<sld::TraceFilter>().unwrap_or_else(|e| {
+                    report_failure(&format!("Invalid --trace-pred: {}", e));
+                    ExitCode::Other.exit()
+                })
+            });
+            let should_trace = sub.is_present("trace") || trace_filter.is_some();
+            let output_format = sub.value_of("OUTPUT_FORMAT");
+            let table_format = output_format.and_then(reporting::TableFormat::from_name);
+            if let (Some(template), None) = (output_format, table_format) {
+                if let Err(e) = reporting::render_template::<reporting::Solution>(template, &[]) {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                }
+            }
 
             let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            affected::set_context_dir(Path::new(context_dir));
             let input_file = sub
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
+            let file = get_modusfile_or_exit(input_file.as_path());
             let query: modusfile::Expression = match sub
                 .value_of("QUERY")
                 .map(|s| s.parse::<modusfile::Expression>())
@@ -494,16 +2370,22 @@ fn main() {
             {
                 Ok(e) => e.without_position(),
                 Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
+                    report_failure("Did not parse goal successfully");
                     let temp_file =
                         SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
                 }
             };
 
             match file.source().parse::<Modusfile>() {
                 Ok(modus_f) => {
+                    print_diagnostics(
+                        &deprecation_warnings(&modus_f, &file),
+                        &mut err_writer.lock(),
+                        &config,
+                        &file,
+                    );
                     let kind_res = modus_f.kinds();
                     if !analysis::check_and_output_analysis(
                         &kind_res,
@@ -514,23 +2396,109 @@ fn main() {
                         &config,
                         &file,
                     ) {
-                        std::process::exit(1)
+                        ExitCode::WellformednessError.exit()
+                    }
+
+                    let max_depth: usize = sub.value_of_t_or_exit("MAX_DEPTH");
+                    let proof_cost: sld::ProofCost = sub.value_of_t_or_exit("PROOF_COST");
+                    let failed_assertions = sld::check_assertions(
+                        &modus_f,
+                        max_depth,
+                        &mut sld::SolveProfile::default(),
+                    );
+                    if !failed_assertions.is_empty() {
+                        print_diagnostics(
+                            &failed_assertions,
+                            &mut err_writer.lock(),
+                            &config,
+                            &file,
+                        );
+                        ExitCode::AssertionFailed.exit()
                     }
 
-                    let max_depth = 175;
+                    let mut profile = interruptible_profile(if sub.is_present("profile") {
+                        sld::SolveProfile::new()
+                    } else {
+                        sld::SolveProfile::default()
+                    })
+                    .with_occurs_check(sub.is_present("occurs-check"))
+                    .with_tabling(sub.is_present("tabling"))
+                    .with_parallelism(sub.is_present("parallel"))
+                    .with_limits(solver_limits(sub))
+                    .with_bottom_up(sub.is_present("bottom-up"))
+                    .with_selection(sub.value_of_t_or_exit("SELECTION"));
                     let (goal, clauses, sld_result) =
-                        tree_from_modusfile(modus_f, query.clone(), max_depth, true);
+                        tree_from_modusfile(modus_f, query.clone(), max_depth, true, &mut profile);
+                    if profile.cancellation.is_cancelled() {
+                        report_cancelled(&profile);
+                        ExitCode::Interrupted.exit()
+                    }
+                    if sub.is_present("profile") {
+                        reporting::write_solve_profile(&mut std::io::stderr(), &profile)
+                            .expect("Error when printing to stderr.");
+                    }
+                    if sub.is_present("solver-stats") {
+                        report_solver_stats(&profile.stats);
+                    }
 
                     if should_output_graph {
                         render_tree(&clauses, sld_result, &mut out_writer.lock());
+                    } else if should_output_json {
+                        println!("{:#}", sld_result.tree.to_json(&clauses));
+                    } else if should_trace {
+                        for event in sld_result.tree.trace() {
+                            if trace_filter.as_ref().is_some_and(|f| !f.matches(&event)) {
+                                continue;
+                            }
+                            let description = event.describe(&clauses);
+                            println!(
+                                "{}{} {}",
+                                "  ".repeat(event.level()),
+                                format!("[{}]", event.level()).dimmed(),
+                                match event {
+                                    sld::TraceEvent::Fail { .. } => description.bright_red(),
+                                    sld::TraceEvent::Success { .. } => description.green(),
+                                    sld::TraceEvent::Resolve { .. } => description.normal(),
+                                }
+                            );
+                        }
                     } else if should_explain {
                         let tree_item = sld_result.tree.explain(&clauses);
                         write_tree(&tree_item, &mut out_writer.lock())
                             .expect("Error when printing tree to stdout.");
                     } else {
-                        let proof_result =
-                            Result::from(sld_result).map(|t| sld::proofs(&t, &clauses, &goal));
+                        let proof_result = Result::from(sld_result)
+                            .map(|t| sld::proofs_with_cost(&t, &clauses, &goal, &proof_cost));
                         match proof_result {
+                            Ok(proofs) if output_format.is_some() => {
+                                let mut solutions = proofs
+                                    .values()
+                                    .map(|p| reporting::Solution::new(&goal, &p.valuation))
+                                    .collect::<Vec<_>>();
+                                solutions.sort_by_cached_key(|s| serde_json::to_string(s).unwrap());
+                                if let Some(table_format) = table_format {
+                                    print!(
+                                        "{}",
+                                        reporting::render_table(table_format, &goal, &solutions)
+                                    );
+                                } else {
+                                    match reporting::render_template(
+                                        output_format.unwrap(),
+                                        &solutions,
+                                    ) {
+                                        Ok(out) => print!("{}", out),
+                                        Err(e) => {
+                                            report_failure(&e);
+                                            ExitCode::Other.exit()
+                                        }
+                                    }
+                                }
+                            }
+                            Ok(proofs) if should_output_proof_graph => {
+                                for proof in proofs.values() {
+                                    print!("{}", proof.to_dot(&goal, &clauses));
+                                }
+                            }
                             Ok(proofs) => {
                                 println!(
                                     "{} proof(s) found for query {}",
@@ -539,44 +2507,152 @@ fn main() {
                                 );
 
                                 for (_, proof) in proofs {
-                                    proof
-                                        .pretty_print(&clauses, &kind_res.pred_kind, compact)
-                                        .expect("error when printing");
+                                    if should_print_tree {
+                                        write_tree(
+                                            &proof.tree(&goal, &clauses),
+                                            &mut out_writer.lock(),
+                                        )
+                                        .expect("Error when printing tree to stdout.");
+                                    } else {
+                                        proof
+                                            .pretty_print(&clauses, &kind_res.pred_kind, compact)
+                                            .expect("error when printing");
+                                    }
                                 }
                             }
                             Err(mut e) => {
+                                e.extend(
+                                    profile
+                                        .deepest_failures
+                                        .iter()
+                                        .map(sld::Failure::get_diagnostic),
+                                );
                                 e.sort_by(|a, b| {
                                     a.severity
                                         .partial_cmp(&b.severity)
                                         .unwrap_or(a.code.cmp(&b.code))
                                 });
-                                for diag_error in &e {
-                                    term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                                        .expect("Error when printing to stderr.")
-                                }
+                                print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                                ExitCode::from_diagnostics(&e).exit()
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully.",);
+                    report_failure("Did not parse Modusfile successfully");
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    ExitCode::ParseError.exit();
+                }
+            }
+        }
+        ("explain", sub) => {
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("Modusfile"));
+            let file = get_modusfile_or_exit(input_file.as_path());
+            let mf: Modusfile = match file.source().parse() {
+                Ok(mf) => mf,
+                Err(e) => {
+                    report_failure("Did not parse Modusfile successfully");
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
+                }
+            };
+            match explain::explain(&mf, &file, sub.value_of("PREDICATE").unwrap()) {
+                Ok(explanation) => print!("{}", explanation),
+                Err(e) => {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
                 }
             }
         }
+        ("doc", sub) => {
+            let input_file = PathBuf::from(sub.value_of_os("FILE").unwrap());
+            let file = get_modusfile_or_exit(input_file.as_path());
+            let mf: Modusfile = match file.source().parse() {
+                Ok(mf) => mf,
+                Err(e) => {
+                    report_failure("Did not parse Modusfile successfully");
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    ExitCode::ParseError.exit();
+                }
+            };
+            print!("{}", doc::doc(&mf, file.source()));
+        }
         ("check", sub) => {
             let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            affected::set_context_dir(Path::new(context_dir));
             let input_file = sub
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
+            let mut file = get_modusfile_or_exit(input_file.as_path());
+
+            if sub.is_present("FIX") {
+                if let Ok(mf) = file.source().parse::<Modusfile>() {
+                    let deprecated = deprecation::find_deprecated(&mf, file.source());
+                    let (fixed, applied) = deprecation::apply_fixes(
+                        file.source(),
+                        deprecated.iter().filter_map(|d| d.fix.as_ref()),
+                    );
+                    if applied > 0 {
+                        if let Err(e) = fs::write(&input_file, fixed) {
+                            report_failure(&format!(
+                                "Unable to write {}: {}",
+                                input_file.display(),
+                                e
+                            ));
+                            ExitCode::Other.exit()
+                        }
+                        eprintln!(
+                            "Applied {} fix{} to {}",
+                            applied,
+                            if applied == 1 { "" } else { "es" },
+                            input_file.display()
+                        );
+                        file = get_modusfile_or_exit(input_file.as_path());
+                    }
+                }
+            }
 
             let is_verbose = sub.is_present("verbose");
 
+            if sub.value_of("FORMAT") == Some("sarif") {
+                let (diags, failure_code) = match file.source().parse::<Modusfile>() {
+                    Ok(mf) => {
+                        let kind_res = mf.kinds();
+                        let mut diags = deprecation_warnings(&mf, &file);
+                        diags.extend(analysis::analysis_diagnostics(&kind_res, &mf, None));
+                        if diags.iter().any(|d| d.severity >= Severity::Error) {
+                            (diags, ExitCode::WellformednessError)
+                        } else {
+                            diags.extend(sld::check_assertions(
+                                &mf,
+                                175,
+                                &mut sld::SolveProfile::default(),
+                            ));
+                            (diags, ExitCode::AssertionFailed)
+                        }
+                    }
+                    Err(e) => (e, ExitCode::ParseError),
+                };
+                let uri = input_file.to_string_lossy().replace('\\', "/");
+                println!("{:#}", sarif::to_sarif(&diags, &file, &uri));
+                if diags.iter().any(|d| d.severity >= Severity::Error) {
+                    failure_code.exit()
+                }
+                return;
+            }
+
             match file.source().parse::<Modusfile>() {
                 Ok(mf) => {
+                    print_diagnostics(
+                        &deprecation_warnings(&mf, &file),
+                        &mut err_writer.lock(),
+                        &config,
+                        &file,
+                    );
                     let kind_res = mf.kinds();
                     if !analysis::check_and_output_analysis(
                         &kind_res,
@@ -587,14 +2663,184 @@ fn main() {
                         &config,
                         &file,
                     ) {
-                        std::process::exit(1)
+                        ExitCode::WellformednessError.exit()
+                    }
+
+                    let failed_assertions =
+                        sld::check_assertions(&mf, 175, &mut sld::SolveProfile::default());
+                    if !failed_assertions.is_empty() {
+                        print_diagnostics(
+                            &failed_assertions,
+                            &mut err_writer.lock(),
+                            &config,
+                            &file,
+                        );
+                        ExitCode::AssertionFailed.exit()
                     }
                 }
                 Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully.",);
+                    report_failure("Did not parse Modusfile successfully");
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
+                    ExitCode::ParseError.exit();
+                }
+            }
+        }
+        ("manifest", sub) => {
+            let tag = sub.value_of("TAG").unwrap();
+            let results = sub
+                .values_of_os("RESULT")
+                .unwrap()
+                .map(|path| {
+                    let path = Path::new(path);
+                    match reproduce::BuildResult::from_file(path) {
+                        Ok(result) => (path.display().to_string(), result),
+                        Err(e) => {
+                            report_failure(&e);
+                            ExitCode::Other.exit()
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            let images = registry::platform_images(&results).unwrap_or_else(|e| {
+                report_failure(&e);
+                ExitCode::Other.exit()
+            });
+            logging::progress(
+                "manifest_push_start",
+                json!({ "tag": tag, "manifests": images }),
+                || format!("{}", format!("Pushing {}...", tag).blue()),
+            );
+            let list = registry::push_manifest_list(tag, images).unwrap_or_else(|e| {
+                report_failure(&e.to_string());
+                ExitCode::RegistryError.exit()
+            });
+            logging::progress("manifest_pushed", json!(list), || {
+                format!(
+                    "Pushed {} as {}, for {}.",
+                    list.tag,
+                    list.digest,
+                    list.manifests
+                        .iter()
+                        .map(|m| m.platform.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            });
+            if sub.is_present("JSON_OUTPUT") {
+                let out =
+                    serde_json::to_vec_pretty(&list).expect("Unable to serialize manifest list");
+                let res = match sub.value_of_os("JSON_OUTPUT") {
+                    Some(path) => fs::write(path, out)
+                        .map_err(|e| format!("Unable to write {}: {}", path.to_string_lossy(), e)),
+                    None => std::io::stdout()
+                        .write_all(&out)
+                        .map_err(|e| format!("Error writing to stdout: {}", e)),
+                };
+                if let Err(e) = res {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                }
+            }
+        }
+        ("cache", sub) => {
+            fn print_cache_error_and_exit(e: cache::CacheError) -> ! {
+                eprintln!("{}: {}", "cache error".red().bold(), e);
+                ExitCode::Other.exit()
+            }
+
+            match sub.subcommand().unwrap() {
+                ("stats", _) => {
+                    let stats = cache::stats().unwrap_or_else(|e| print_cache_error_and_exit(e));
+                    println!(
+                        "{} temporary director{} ({} bytes)",
+                        stats.tmp_dirs.len(),
+                        if stats.tmp_dirs.len() == 1 {
+                            "y"
+                        } else {
+                            "ies"
+                        },
+                        stats.tmp_dirs_size()
+                    );
+                    for d in stats.tmp_dirs.iter() {
+                        println!(
+                            "  {} ({} bytes, {}s old)",
+                            d.path.display(),
+                            d.size,
+                            d.age.as_secs()
+                        );
+                    }
+                    println!("{} temporary image tag(s)", stats.tmp_tags.len());
+                    for t in stats.tmp_tags.iter() {
+                        println!("  {}", t);
+                    }
+                    println!();
+                    cache::print_buildkit_usage().unwrap_or_else(|e| print_cache_error_and_exit(e));
+                }
+                ("prune", prune_sub) => {
+                    let policy = cache::PrunePolicy {
+                        older_than: prune_sub.value_of("OLDER_THAN").map(|s| {
+                            cache::parse_duration(s)
+                                .unwrap_or_else(|e| print_cache_error_and_exit(e))
+                        }),
+                        keep_storage: prune_sub.value_of("KEEP_STORAGE").map(ToOwned::to_owned),
+                    };
+                    cache::prune(&policy).unwrap_or_else(|e| print_cache_error_and_exit(e));
+                }
+                ("clear", _) => {
+                    cache::clear().unwrap_or_else(|e| print_cache_error_and_exit(e));
+                }
+                _ => unreachable!(),
+            }
+        }
+        ("get", sub) => {
+            let dir = Path::new(sub.value_of_os("DIR").unwrap());
+            let root = fs::canonicalize(dir)
+                .ok()
+                .and_then(|dir| package::find_root(&dir))
+                .unwrap_or_else(|| {
+                    report_failure(&format!(
+                        "No {} was found in {} or its parents.",
+                        package::MANIFEST_FILE,
+                        dir.display()
+                    ));
+                    ExitCode::Other.exit()
+                });
+            match package::get(&root) {
+                Ok(names) if names.is_empty() => logging::progress(
+                    "dependencies_fetched",
+                    json!({ "dependencies": names }),
+                    || {
+                        format!(
+                            "{} has no dependencies.",
+                            root.join(package::MANIFEST_FILE).display()
+                        )
+                    },
+                ),
+                Ok(names) => logging::progress(
+                    "dependencies_fetched",
+                    json!({ "dependencies": names }),
+                    || format!("Fetched {}.", names.join(", ")),
+                ),
+                Err(e) => {
+                    report_failure(&e);
+                    ExitCode::Other.exit()
+                }
+            }
+        }
+        ("grammar", sub) => {
+            let grammar = match sub.value_of("FORMAT").unwrap() {
+                "textmate" => serde_json::to_string_pretty(&grammar::textmate_grammar()).unwrap(),
+                "tree-sitter" => grammar::tree_sitter_grammar(),
+                _ => unreachable!(),
+            };
+            match sub.value_of_os("OUTPUT") {
+                Some(path) => {
+                    if let Err(e) = fs::write(path, grammar) {
+                        eprintln!("Error writing {}: {}", Path::new(path).display(), e);
+                        ExitCode::Other.exit();
+                    }
                 }
+                None => println!("{}", grammar),
             }
         }
         _ => (),
@@ -0,0 +1,205 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The rule libraries that a project depends on, listed in its `modus.toml`:
+//!
+//! ```toml
+//! [dependencies]
+//! python = { version = "1.2.0", url = "https://example.com/python-{version}.Modusfile", sha256 = "..." }
+//! ```
+//!
+//! The URL is any URL that `:- include(...)` accepts, in which `{version}` is replaced by
+//! the version. `modus get` fetches the dependencies into the store, `.modus/libs` next
+//! to `modus.toml`, and a Modusfile of the project loads one with `:- use(python).`
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use modus_lib::library::{Include, Library};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::include;
+
+/// The name of the file that lists the dependencies of a project.
+pub const MANIFEST_FILE: &str = "modus.toml";
+
+/// The directory, relative to the manifest, that dependencies are fetched into.
+const STORE_DIR: &str = ".modus/libs";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+    #[serde(default)]
+    pub version: Option<String>,
+    pub url: String,
+    pub sha256: String,
+}
+
+impl Dependency {
+    fn include(&self) -> Include {
+        Include {
+            url: match &self.version {
+                Some(version) => self.url.replace("{version}", version),
+                None => self.url.clone(),
+            },
+            sha256: self.sha256.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Manifest, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let manifest: Manifest =
+            toml::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        manifest
+            .check()
+            .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        Ok(manifest)
+    }
+
+    /// Checks that the names of the dependencies can be used in `:- use(<name>).` and in
+    /// the names of predicates, and do not hide a bundled library.
+    fn check(&self) -> Result<(), String> {
+        for name in self.dependencies.keys() {
+            let mut chars = name.chars();
+            let is_identifier = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_identifier {
+                return Err(format!(
+                    "the dependency name {:?} is not an identifier",
+                    name
+                ));
+            }
+            if Library::find(name).is_ok() {
+                return Err(format!(
+                    "the dependency {} has the name of a bundled library",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the project that `dir` is in, i.e. the closest directory with a `modus.toml`.
+pub fn find_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|d| d.join(MANIFEST_FILE).is_file())
+        .map(Path::to_path_buf)
+}
+
+fn store_path(root: &Path, name: &str) -> PathBuf {
+    root.join(STORE_DIR).join(format!("{}.Modusfile", name))
+}
+
+/// Fetches every dependency of the project into its store, and returns their names.
+pub fn get(root: &Path) -> Result<Vec<String>, String> {
+    let manifest = Manifest::from_file(&root.join(MANIFEST_FILE))?;
+    std::fs::create_dir_all(root.join(STORE_DIR))
+        .map_err(|e| format!("Unable to create {}: {}", root.join(STORE_DIR).display(), e))?;
+    for (name, dependency) in manifest.dependencies.iter() {
+        let source = include::fetch(&dependency.include())
+            .map_err(|e| format!("Unable to get {}: {}", name, e))?;
+        let path = store_path(root, name);
+        std::fs::write(&path, source)
+            .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    }
+    Ok(manifest.dependencies.into_keys().collect())
+}
+
+/// The project whose dependencies Modusfiles are loaded with.
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the project that the Modusfile in `dir` is in, if any, as the one whose
+/// dependencies are loaded.
+pub fn set_project_dir(dir: &Path) {
+    if let Some(root) = find_root(dir) {
+        let _ = ROOT.set(root);
+    }
+}
+
+/// Loads a dependency of the project from its store, checking that it is the version
+/// that `modus.toml` asks for. See [`modus_lib::library::DependencyLoader`].
+pub fn load(name: &str) -> Option<Result<String, String>> {
+    let root = ROOT.get()?;
+    let manifest = match Manifest::from_file(&root.join(MANIFEST_FILE)) {
+        Ok(manifest) => manifest,
+        Err(e) => return Some(Err(e)),
+    };
+    let dependency = manifest.dependencies.get(name)?;
+    let path = store_path(root, name);
+    Some(match std::fs::read(&path) {
+        Ok(content) if format!("{:x}", Sha256::digest(&content)) == dependency.sha256 => {
+            String::from_utf8(content).map_err(|_| format!("The dependency {} is not UTF-8", name))
+        }
+        Ok(_) => Err(format!(
+            "The dependency {} is out of date, run `modus get` to fetch it again",
+            name
+        )),
+        Err(_) => Err(format!(
+            "The dependency {} has not been fetched, run `modus get`",
+            name
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let manifest: Manifest = toml::from_str(
+            "[dependencies]\n\
+             python = { version = \"1.2.0\", url = \"https://example.com/python-{version}.Modusfile\", sha256 = \"ab\" }\n\
+             node = { url = \"https://github.com/org/lib.git#v3:node.Modusfile\", sha256 = \"cd\" }",
+        )
+        .unwrap();
+        assert!(manifest.check().is_ok());
+        assert_eq!(
+            manifest.dependencies["python"].include().url,
+            "https://example.com/python-1.2.0.Modusfile"
+        );
+        assert_eq!(
+            manifest.dependencies["node"].include().url,
+            "https://github.com/org/lib.git#v3:node.Modusfile"
+        );
+
+        let invalid = |toml: &str| toml::from_str::<Manifest>(toml).unwrap().check().is_err();
+        assert!(invalid(
+            "[dependencies]\nstdlib = { url = \"https://example.com/a\", sha256 = \"ab\" }"
+        ));
+        assert!(invalid(
+            "[dependencies]\n\"my-lib\" = { url = \"https://example.com/a\", sha256 = \"ab\" }"
+        ));
+        assert!(toml::from_str::<Manifest>("[dependencies]\na = { url = \"x\" }").is_err());
+    }
+}
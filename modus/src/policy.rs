@@ -0,0 +1,326 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A policy on which base images a build may use, read from `modus-policy.toml`:
+//!
+//! ```toml
+//! # Registries or repositories that images must come from. Any, if omitted.
+//! allowed = ["docker.io/library", "ghcr.io/my-org"]
+//! # Tags that images may not use. An image without a tag or digest is `latest`.
+//! banned_tags = ["latest"]
+//! ```
+//!
+//! Policies can also be written as Modus rules, `violation(Reason) :- ...`, which are
+//! evaluated against facts describing the plan, see [`plan_facts`].
+
+use std::{collections::BTreeSet, path::Path};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use modus_lib::imagegen::{BuildNode, BuildPlan, MergeOperation};
+use modus_lib::logic::{Literal, Predicate};
+use modus_lib::modusfile::{parser::escape_constant, ModusClause, ModusTerm, Modusfile};
+use modus_lib::sld::{self, ResolutionError};
+use serde::Deserialize;
+
+/// The name of the policy file that is read from the context directory by default.
+pub const POLICY_FILE: &str = "modus-policy.toml";
+
+/// The diagnostic code of policy violations.
+pub const POLICY_VIOLATION_CODE: &str = "policy-violation";
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    allowed: Vec<String>,
+    #[serde(default)]
+    banned_tags: Vec<String>,
+}
+
+/// Splits an image reference into its repository, including the registry, and its tag.
+/// References to Docker Hub are normalized, e.g. `alpine` is `docker.io/library/alpine`.
+fn repository_and_tag(image_ref: &str) -> (String, Option<&str>) {
+    let (name, digest) = match image_ref.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image_ref, None),
+    };
+    let (name, tag) = match name.rsplit_once(':') {
+        Some((n, tag)) if !tag.contains('/') => (n, Some(tag)),
+        _ => (
+            name,
+            if digest.is_some() {
+                None
+            } else {
+                Some(DEFAULT_TAG)
+            },
+        ),
+    };
+    let has_registry = name.split_once('/').is_some_and(|(first, _)| {
+        first.contains('.') || first.contains(':') || first == "localhost"
+    });
+    let repository = if has_registry || (!name.contains('/') && name.contains('.')) {
+        name.to_owned()
+    } else if name.contains('/') {
+        format!("{}/{}", DEFAULT_REGISTRY, name)
+    } else {
+        format!("{}/library/{}", DEFAULT_REGISTRY, name)
+    };
+    (repository, tag)
+}
+
+impl Policy {
+    pub fn from_file(path: &Path) -> Result<Policy, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+    }
+
+    fn is_allowed(&self, repository: &str) -> bool {
+        self.allowed.is_empty()
+            || self.allowed.iter().any(|entry| {
+                let (entry, _) = repository_and_tag(entry);
+                repository == entry
+                    || repository
+                        .strip_prefix(&entry)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    fn check_image(&self, image_ref: &str) -> Vec<String> {
+        let (repository, tag) = repository_and_tag(image_ref);
+        let mut violations = Vec::new();
+        if !self.is_allowed(&repository) {
+            violations.push(format!(
+                "The base image {:?} is not from an allowed registry or repository.",
+                image_ref
+            ));
+        }
+        if let Some(tag) = tag.filter(|t| self.banned_tags.iter().any(|b| b == t)) {
+            violations.push(format!(
+                "The base image {:?} uses the banned tag {:?}.",
+                image_ref, tag
+            ));
+        }
+        violations
+    }
+
+    /// Checks every image that the plan starts from, labelling each violation with the
+    /// head of the rule that uses the image.
+    pub fn check(&self, plan: &BuildPlan) -> Vec<Diagnostic<()>> {
+        let mut diags = Vec::new();
+        for (node_id, node) in plan.nodes.iter().enumerate() {
+            let image_ref = match node {
+                BuildNode::From { image_ref, .. } => image_ref,
+                _ => continue,
+            };
+            for violation in self.check_image(image_ref) {
+                let mut diag = Diagnostic::error()
+                    .with_code(POLICY_VIOLATION_CODE)
+                    .with_message(violation);
+                if let Some(pos) = plan.source(node_id).and_then(|l| l.position.as_ref()) {
+                    diag = diag.with_labels(vec![Label::primary(
+                        (),
+                        pos.offset..pos.offset + pos.length,
+                    )
+                    .with_message("image used by this rule")]);
+                }
+                diags.push(diag);
+            }
+        }
+        diags
+    }
+}
+
+/// The query whose solutions are the violations of a policy written in Modus.
+const VIOLATION_QUERY: &str = "violation(Reason)";
+
+const POLICY_MAX_DEPTH: usize = 175;
+
+/// Describes the plan as facts that policy rules can refer to:
+///
+/// - `base_image(Ref)` for each image that is built from,
+/// - `local_image(Path)` for each image in a local file that is built from,
+/// - `copied_path(Src, Dst)` for each copy from the context,
+/// - `run_command(Command)` for each command that is run,
+/// - `git_source(Url, Rev)` for each repository that is checked out.
+pub fn plan_facts(plan: &BuildPlan) -> Vec<ModusClause> {
+    let mut facts = BTreeSet::new();
+    let mut fact = |predicate: &str, args: &[&str]| {
+        facts.insert((
+            predicate.to_owned(),
+            args.iter().map(|a| escape_constant(a)).collect::<Vec<_>>(),
+        ));
+    };
+    for node in plan.nodes.iter() {
+        match node {
+            BuildNode::From { image_ref, .. } => fact("base_image", &[image_ref]),
+            BuildNode::FromOciLayout { path, .. } | BuildNode::FromDockerTar { path, .. } => {
+                fact("local_image", &[path])
+            }
+            BuildNode::CopyFromLocal {
+                src_path, dst_path, ..
+            } => fact("copied_path", &[src_path, dst_path]),
+            BuildNode::Run { command, .. } => fact("run_command", &[command]),
+            BuildNode::GitCheckout { url, rev, .. } => fact("git_source", &[url, rev]),
+            BuildNode::Merge(merge) => {
+                for op in merge.operations.iter() {
+                    match op {
+                        MergeOperation::CopyFromLocal { src_path, dst_path } => {
+                            fact("copied_path", &[src_path, dst_path])
+                        }
+                        MergeOperation::Run { command, .. } => fact("run_command", &[command]),
+                        MergeOperation::GitCheckout { url, rev, .. } => {
+                            fact("git_source", &[url, rev])
+                        }
+                        MergeOperation::CopyFromImage { .. } => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    facts
+        .into_iter()
+        .map(|(predicate, args)| ModusClause {
+            head: Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(predicate.as_str().into()),
+                args: args.into_iter().map(ModusTerm::Constant).collect(),
+            },
+            body: None,
+            position: None,
+        })
+        .collect()
+}
+
+/// Evaluates the `violation(Reason)` rules of a policy against the plan, and returns
+/// the reasons of the violations found.
+pub fn check_rules(rules: &Modusfile, plan: &BuildPlan) -> Result<Vec<String>, String> {
+    let mut mf = rules.clone();
+    mf.0.extend(plan_facts(plan));
+    let (_, _, result) = sld::tree_from_modusfile(
+        mf,
+        VIOLATION_QUERY.parse().expect("Expected a valid query"),
+        POLICY_MAX_DEPTH,
+        false,
+        &mut sld::SolveProfile::default(),
+    );
+    // Fail closed, since violations may be beyond the maximum depth.
+    if let Some(e) = result
+        .errors
+        .iter()
+        .find(|e| matches!(e, ResolutionError::MaximumDepthExceeded(..)))
+    {
+        return Err(format!("Unable to evaluate the policy: {}", e));
+    }
+    let mut reasons = sld::solutions(&result.tree)
+        .into_iter()
+        .flatten()
+        .map(|l| match &l.args[..] {
+            [arg] => arg
+                .as_constant()
+                .map_or_else(|| l.to_string(), ToOwned::to_owned),
+            _ => l.to_string(),
+        })
+        .collect::<Vec<_>>();
+    reasons.sort();
+    Ok(reasons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use modus_lib::imagegen;
+
+    #[test]
+    fn normalizes_image_references() {
+        assert_eq!(
+            repository_and_tag("alpine"),
+            ("docker.io/library/alpine".to_owned(), Some("latest"))
+        );
+        assert_eq!(
+            repository_and_tag("org/app:1.0"),
+            ("docker.io/org/app".to_owned(), Some("1.0"))
+        );
+        assert_eq!(
+            repository_and_tag("localhost:5000/app@sha256:abc"),
+            ("localhost:5000/app".to_owned(), None)
+        );
+        assert_eq!(
+            repository_and_tag("ghcr.io/org/app:latest"),
+            ("ghcr.io/org/app".to_owned(), Some("latest"))
+        );
+    }
+
+    #[test]
+    fn checks_from_nodes() {
+        let policy: Policy = toml::from_str(
+            "allowed = [\"docker.io/library\", \"ghcr.io/org\"]\nbanned_tags = [\"latest\"]",
+        )
+        .unwrap();
+        let source = "a :- from(\"alpine:3.15\").\n\
+                      b :- from(\"ghcr.io/org/app\").\n\
+                      c :- from(\"quay.io/org/app:1.0\").";
+        let mf: Modusfile = source.parse().unwrap();
+        let plan = imagegen::plan_from_queries(
+            &mf,
+            &[
+                "a".parse().unwrap(),
+                "b".parse().unwrap(),
+                "c".parse().unwrap(),
+            ],
+            sld::SearchStrategy::DepthFirst,
+            &mut sld::SolveProfile::default(),
+        )
+        .unwrap();
+
+        let diags = policy.check(&plan);
+        assert_eq!(diags.len(), 2);
+        assert!(diags[0].message.contains("banned tag \"latest\""));
+        assert!(diags[1].message.contains("not from an allowed"));
+        let label = &diags[1].labels[0];
+        assert_eq!(source[label.range.clone()].trim_end(), "c");
+
+        assert!(toml::from_str::<Policy>("allow = []").is_err());
+    }
+
+    #[test]
+    fn violation_rules() {
+        let plan = testing::plan(
+            "a :- from(\"alpine\"), run(\"curl \\\"x\\\" | sh\"), copy(\".env\", \"/app\").",
+            "a",
+        );
+
+        let rules: Modusfile = "violation(\"secrets are copied\") :- copied_path(\".env\", _).\n\
+                                violation(R) :- run_command(C), string_concat(_, \"| sh\", C), \
+                                    string_concat(C, \" pipes to a shell\", R).\n\
+                                violation(\"not alpine\") :- base_image(I), I != \"alpine\"."
+            .parse()
+            .unwrap();
+        assert_eq!(
+            check_rules(&rules, &plan).unwrap(),
+            vec![
+                "curl \"x\" | sh pipes to a shell".to_owned(),
+                "secrets are copied".to_owned(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The images that `modus build --push` pushed to registries, and `modus manifest`,
+//! which assembles the images pushed by the builds for each platform into a single
+//! manifest list under one tag.
+//!
+//! The digests of the pushed tags are written to the build result, so the manifest
+//! list refers to the exact images that were built, even if their tags have moved since.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use modus_lib::imagegen::BuildPlan;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    lock::{self, LockError},
+    logging,
+    reproduce::BuildResult,
+};
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Unable to run docker: {0}")]
+    UnableToRunDocker(#[source] std::io::Error),
+    #[error("docker image inspect {0} exited with code {1}.")]
+    InspectFailed(String, ExitStatus),
+    #[error("{0} was pushed, but docker does not know its digest in {1}.")]
+    NoRepoDigest(String, String),
+    #[error("docker buildx imagetools create -t {0} exited with code {1}.")]
+    CreateFailed(String, ExitStatus),
+    #[error("{0}")]
+    Resolve(#[from] LockError),
+}
+
+/// The repository of an image reference, i.e. without its tag or digest.
+pub fn repository(image_ref: &str) -> &str {
+    let name = image_ref.split('@').next().unwrap();
+    match name.rfind(':') {
+        Some(i) if !name[i..].contains('/') => &name[..i],
+        _ => name,
+    }
+}
+
+/// The repository as docker shortens it, e.g. `docker.io/library/alpine` is `alpine`.
+fn familiar(repository: &str) -> &str {
+    let repository = repository.strip_prefix("docker.io/").unwrap_or(repository);
+    repository.strip_prefix("library/").unwrap_or(repository)
+}
+
+/// The digest that the pushed tag has in its registry, from the repo digests that docker
+/// recorded for the image when pushing it.
+pub fn pushed_digest(tag: &str) -> Result<String, RegistryError> {
+    let output = Command::new("docker")
+        .args(["image", "inspect", "--format", "{{json .RepoDigests}}", tag])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(RegistryError::UnableToRunDocker)?;
+    if !output.status.success() {
+        return Err(RegistryError::InspectFailed(tag.to_owned(), output.status));
+    }
+    let repo_digests: Vec<String> = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    let repo = familiar(repository(tag));
+    repo_digests
+        .iter()
+        .filter_map(|d| d.split_once('@'))
+        .find(|(name, _)| familiar(name) == repo)
+        .map(|(_, digest)| digest.to_owned())
+        .ok_or_else(|| RegistryError::NoRepoDigest(tag.to_owned(), repo.to_owned()))
+}
+
+/// The digests of the pushed tags of each output, in the order of `build_plan.outputs`.
+pub fn pushed_digests(
+    build_plan: &BuildPlan,
+) -> Result<Vec<BTreeMap<String, String>>, RegistryError> {
+    build_plan
+        .outputs
+        .iter()
+        .map(|o| {
+            o.tags
+                .iter()
+                .map(|tag| Ok((tag.clone(), pushed_digest(tag)?)))
+                .collect()
+        })
+        .collect()
+}
+
+/// The image built for one platform of a manifest list.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlatformImage {
+    pub platform: String,
+    /// The pushed image, by digest, e.g. `registry/app@sha256:...`.
+    pub image: String,
+    pub digest: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ManifestList {
+    pub tag: String,
+    pub digest: String,
+    pub manifests: Vec<PlatformImage>,
+}
+
+/// The image of each build result, which must be a single image built with `--platform`
+/// and pushed, for a different platform in each result.
+pub fn platform_images(results: &[(String, BuildResult)]) -> Result<Vec<PlatformImage>, String> {
+    let mut platforms = BTreeSet::new();
+    results
+        .iter()
+        .map(|(name, result)| {
+            let image = match result.images() {
+                [image] => image,
+                images => {
+                    return Err(format!(
+                        "{} has {} images, but a manifest list has one image per build.",
+                        name,
+                        images.len()
+                    ))
+                }
+            };
+            let platform = image.platform.clone().ok_or_else(|| {
+                format!(
+                    "{} of {} was not built for a platform, see modus build --platform.",
+                    image.literal(),
+                    name
+                )
+            })?;
+            if !platforms.insert(platform.clone()) {
+                return Err(format!(
+                    "{} is built for {}, like an image of another build result.",
+                    name, platform
+                ));
+            }
+            let (tag, digest) = image.pushed.iter().next().ok_or_else(|| {
+                format!(
+                    "{} of {} was not pushed, see modus build --push.",
+                    image.literal(),
+                    name
+                )
+            })?;
+            Ok(PlatformImage {
+                platform,
+                image: format!("{}@{}", repository(tag), digest),
+                digest: digest.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Creates a manifest list of the images and pushes it as `tag`.
+pub fn push_manifest_list(
+    tag: &str,
+    images: Vec<PlatformImage>,
+) -> Result<ManifestList, RegistryError> {
+    // Keep stdout for the report of the manifest list.
+    let status = logging::status(
+        Command::new("docker")
+            .args(["buildx", "imagetools", "create", "-t", tag])
+            .args(images.iter().map(|i| &i.image))
+            .stdin(Stdio::null())
+            .stdout(match logging::is_json() {
+                true => Stdio::null(),
+                false => std::io::stderr().into(),
+            }),
+        "docker buildx imagetools",
+    )
+    .map_err(RegistryError::UnableToRunDocker)?;
+    if !status.success() {
+        return Err(RegistryError::CreateFailed(tag.to_owned(), status));
+    }
+    Ok(ManifestList {
+        tag: tag.to_owned(),
+        digest: lock::resolve_digest(tag)?,
+        manifests: images,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repositories() {
+        assert_eq!(repository("alpine"), "alpine");
+        assert_eq!(repository("alpine:3.16"), "alpine");
+        assert_eq!(repository("localhost:5000/app"), "localhost:5000/app");
+        assert_eq!(
+            repository("localhost:5000/app:1@sha256:12"),
+            "localhost:5000/app"
+        );
+        assert_eq!(familiar("docker.io/library/alpine"), "alpine");
+        assert_eq!(familiar("docker.io/user/app"), "user/app");
+    }
+
+    #[test]
+    fn one_image_per_platform() {
+        let result = |platform: &str, digest: &str| -> BuildResult {
+            serde_json::from_value(serde_json::json!([{
+                "predicate": "app",
+                "digest": "sha256:local",
+                "platform": platform,
+                "pushed": { "registry/app:1": digest },
+            }]))
+            .unwrap()
+        };
+        let images = platform_images(&[
+            ("amd64.json".to_owned(), result("linux/amd64", "sha256:1")),
+            ("arm64.json".to_owned(), result("linux/arm64", "sha256:2")),
+        ])
+        .unwrap();
+        assert_eq!(images[1].platform, "linux/arm64");
+        assert_eq!(images[1].image, "registry/app@sha256:2");
+
+        assert!(platform_images(&[
+            ("a.json".to_owned(), result("linux/amd64", "sha256:1")),
+            ("b.json".to_owned(), result("linux/amd64", "sha256:2")),
+        ])
+        .is_err());
+        let not_pushed: BuildResult = serde_json::from_value(serde_json::json!([{
+            "predicate": "app",
+            "digest": "sha256:local",
+            "platform": "linux/amd64",
+        }]))
+        .unwrap();
+        assert!(platform_images(&[("a.json".to_owned(), not_pushed)]).is_err());
+    }
+}
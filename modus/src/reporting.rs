@@ -15,20 +15,25 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
     fmt::Display,
     io::{self, Write},
     path::Path,
 };
 
+use handlebars::{no_escape, Handlebars};
 use serde::{ser::SerializeSeq, Serialize};
+use sha2::{Digest, Sha256};
 
 use modus_lib::{
-    imagegen::BuildPlan,
+    builtin,
+    imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId},
     logic::{IRTerm, Literal},
+    sld::SolveProfile,
+    unification::{Substitute, Substitution},
 };
 
-pub type BuildResult = Vec<Image>;
-
 #[derive(Debug, Clone)]
 pub enum ConstantTerm {
     Constant(String),
@@ -62,12 +67,12 @@ pub struct ConstantLiteral {
 impl ConstantLiteral {
     pub fn from_literal(lit: Literal) -> Self {
         Self {
-            predicate: lit.predicate.0,
+            predicate: lit.predicate.0.to_string(),
             args: lit
                 .args
                 .into_iter()
                 .map(|x| match x {
-                    IRTerm::Constant(x) => ConstantTerm::Constant(x),
+                    IRTerm::Constant(x) => ConstantTerm::Constant(x.to_string()),
                     IRTerm::List(ts) => ConstantTerm::List(
                         ts.iter()
                             .map(|t| t.as_constant().unwrap().to_owned())
@@ -80,26 +85,291 @@ impl ConstantLiteral {
     }
 }
 
+impl ConstantTerm {
+    /// Like the conversion done by [`ConstantLiteral::from_literal`], but falls back to
+    /// the textual representation of terms that are not constant.
+    fn from_term(term: &IRTerm) -> Self {
+        match term {
+            IRTerm::Constant(c) => ConstantTerm::Constant(c.to_string()),
+            IRTerm::List(ts) => ConstantTerm::List(
+                ts.iter()
+                    .map(|t| match t {
+                        IRTerm::Constant(c) => c.to_string(),
+                        t => t.to_string(),
+                    })
+                    .collect(),
+            ),
+            t => ConstantTerm::Constant(t.to_string()),
+        }
+    }
+}
+
+/// A solution of a query, as exposed to `--format` templates.
+#[derive(Serialize, Debug, Clone)]
+pub struct Solution {
+    /// The solved query literal, or the first one if the query is a conjunction.
+    #[serde(flatten)]
+    pub literal: ConstantLiteral,
+    pub literals: Vec<ConstantLiteral>,
+    /// The values of the variables of the query.
+    pub variables: BTreeMap<String, ConstantTerm>,
+}
+
+impl Solution {
+    pub fn new(goal: &[Literal], valuation: &Substitution) -> Self {
+        let literal = |lit: &Literal| ConstantLiteral {
+            predicate: lit.predicate.0.to_string(),
+            args: lit
+                .substitute(valuation)
+                .args
+                .iter()
+                .map(ConstantTerm::from_term)
+                .collect(),
+        };
+        // Constraints from f-strings in the query are builtins preceding the user's literal.
+        let main = goal
+            .iter()
+            .find(|l| {
+                matches!(
+                    builtin::select_builtin(l).0,
+                    builtin::SelectBuiltinResult::NoMatch
+                )
+            })
+            .or_else(|| goal.first())
+            .expect("a query has at least one literal");
+        Self {
+            literal: literal(main),
+            literals: goal.iter().map(literal).collect(),
+            variables: valuation
+                .iter()
+                .filter_map(|(k, v)| match k {
+                    IRTerm::UserVariable(name) => {
+                        Some((name.to_string(), ConstantTerm::from_term(v)))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Renders each record with the handlebars `template`, one record per line.
+///
+/// Values are inserted as is, without HTML escaping.
+pub fn render_template<T: Serialize>(template: &str, records: &[T]) -> Result<String, String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars
+        .register_template_string("format", template)
+        .map_err(|e| format!("Invalid format template: {}", e))?;
+    let mut res = String::new();
+    for record in records {
+        res.push_str(
+            &handlebars
+                .render("format", record)
+                .map_err(|e| format!("Error rendering format template: {}", e))?,
+        );
+        res.push('\n');
+    }
+    Ok(res)
+}
+
+/// A table of solutions with a column per variable of the query, printed with
+/// `--format csv` or `--format tsv` instead of a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl TableFormat {
+    /// The table format named by the value of `--format`, if it is not a template.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(TableFormat::Csv),
+            "tsv" => Some(TableFormat::Tsv),
+            _ => None,
+        }
+    }
+
+    /// Quotes the cell as in RFC 4180 for CSV. TSV can not quote, so tabs, newlines and
+    /// backslashes are escaped with a backslash instead.
+    fn cell(self, value: &str) -> String {
+        match self {
+            TableFormat::Csv if value.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            }
+            TableFormat::Csv => value.to_owned(),
+            TableFormat::Tsv => value
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r"),
+        }
+    }
+
+    fn separator(self) -> &'static str {
+        match self {
+            TableFormat::Csv => ",",
+            TableFormat::Tsv => "\t",
+        }
+    }
+}
+
+/// Renders the solutions of the query `goal` as a table with a header row of its
+/// variables, in the order they appear in it, and a row per solution. Lists are written
+/// as JSON arrays.
+pub fn render_table(format: TableFormat, goal: &[Literal], solutions: &[Solution]) -> String {
+    fn user_variables<'a>(term: &'a IRTerm, variables: &mut Vec<&'a str>) {
+        match term {
+            IRTerm::UserVariable(v) if !variables.contains(&v.as_str()) => variables.push(v),
+            IRTerm::List(ts) => ts.iter().for_each(|t| user_variables(t, variables)),
+            _ => {}
+        }
+    }
+    let mut variables = Vec::new();
+    for arg in goal.iter().flat_map(|l| l.args.iter()) {
+        user_variables(arg, &mut variables);
+    }
+    let row = |cells: Vec<String>| {
+        let mut row = cells
+            .iter()
+            .map(|c| format.cell(c))
+            .collect::<Vec<_>>()
+            .join(format.separator());
+        row.push('\n');
+        row
+    };
+    let mut res = row(variables.iter().map(|&v| v.to_owned()).collect());
+    for solution in solutions {
+        res.push_str(&row(variables
+            .iter()
+            .map(|&v| match solution.variables.get(v) {
+                Some(ConstantTerm::Constant(c)) => c.clone(),
+                Some(list) => serde_json::to_string(list).expect("Unable to serialize list"),
+                None => String::new(),
+            })
+            .collect()));
+    }
+    res
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Image {
     #[serde(flatten)]
     pub source_literal: ConstantLiteral,
     pub digest: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// The digest that each tag was pushed with, see [`crate::registry::pushed_digests`].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub pushed: BTreeMap<String, String>,
 }
 
-pub fn write_build_result<F: Write, P: Display>(
-    mut json_out: F,
-    json_out_name: P,
-    build_plan: &BuildPlan,
-    image_ids: &[String],
-) -> Result<(), String> {
+pub const PROVENANCE_PREFIX: &str = "com.modus-continens.provenance";
+
+/// Information recorded on every output image so that it can be traced back to
+/// the rule and query that produced it.
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    pub query: String,
+    pub modusfile_digest: String,
+    pub modus_version: String,
+    pub solved_at: String,
+}
+
+impl Provenance {
+    /// Creates the provenance for a solve of `query` against the given
+    /// Modusfile content, timestamped now.
+    pub fn new(modusfile_content: &str, query: &str) -> Self {
+        Self {
+            query: query.to_owned(),
+            modusfile_digest: format!("sha256:{:x}", Sha256::digest(modusfile_content)),
+            modus_version: env!("CARGO_PKG_VERSION").to_owned(),
+            solved_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Returns the annotations to attach to the output image built for `literal`.
+    pub fn annotations_for(&self, literal: &Literal) -> BTreeMap<String, String> {
+        let mut annotations = BTreeMap::new();
+        let mut add = |key: &str, value: String| {
+            annotations.insert(format!("{}.{}", PROVENANCE_PREFIX, key), value);
+        };
+        add("literal", literal.to_string());
+        add("query", self.query.clone());
+        add("modusfile-digest", self.modusfile_digest.clone());
+        add("modus-version", self.modus_version.clone());
+        add("solved-at", self.solved_at.clone());
+        annotations
+    }
+}
+
+/// The repositories that `git_checkout` checked out for the node and the nodes it is
+/// built from, as `Url#Rev`.
+fn git_sources(build_plan: &BuildPlan, node: NodeId) -> BTreeSet<String> {
+    let mut sources = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        if !visited.insert(node) {
+            continue;
+        }
+        match &build_plan.nodes[node] {
+            BuildNode::GitCheckout { url, rev, .. } => {
+                sources.insert(format!("{}#{}", url, rev));
+            }
+            BuildNode::Merge(merge) => {
+                for op in merge.operations.iter() {
+                    if let MergeOperation::GitCheckout { url, rev, .. } = op {
+                        sources.insert(format!("{}#{}", url, rev));
+                    }
+                }
+            }
+            _ => {}
+        }
+        stack.extend(build_plan.dependencies[node].iter().copied());
+    }
+    sources
+}
+
+/// Attaches provenance annotations to every output of the build plan, including the
+/// revisions of the repositories it checked out, if any.
+pub fn annotate_outputs(build_plan: &mut BuildPlan, provenance: &Provenance) {
+    let git_sources = build_plan
+        .outputs
+        .iter()
+        .map(|o| git_sources(build_plan, o.node))
+        .collect::<Vec<_>>();
+    for (o, git_sources) in build_plan.outputs.iter_mut().zip(git_sources) {
+        let literal = o
+            .source_literal
+            .as_ref()
+            .expect("Expected source_literal to present in build plan");
+        let annotations = provenance.annotations_for(literal);
+        o.annotations.extend(annotations);
+        if !git_sources.is_empty() {
+            o.annotations.insert(
+                format!("{}.git-sources", PROVENANCE_PREFIX),
+                git_sources.into_iter().collect::<Vec<_>>().join(","),
+            );
+        }
+    }
+}
+
+/// The images built for the outputs of the build plan, given their IDs.
+pub fn built_images(build_plan: &BuildPlan, image_ids: &[String]) -> Vec<Image> {
     debug_assert_eq!(build_plan.outputs.len(), image_ids.len());
     debug_assert!(build_plan
         .outputs
         .iter()
         .all(|x| x.source_literal.is_some()));
 
-    let res = build_plan
+    build_plan
         .outputs
         .iter()
         .zip(image_ids)
@@ -108,13 +378,98 @@ pub fn write_build_result<F: Write, P: Display>(
                 o.source_literal.as_ref().unwrap().clone(),
             ),
             digest: i.clone(),
+            annotations: o.annotations.clone(),
+            tags: o.tags.clone(),
+            platform: build_plan.platform.clone(),
+            pushed: BTreeMap::new(),
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+/// A node of the build plan, with a key that identifies what it builds.
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeReport {
+    pub node: NodeId,
+    pub instruction: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_literal: Option<String>,
+    pub cache_key: String,
+}
+
+/// Computes a cache key for every node of the build plan, from the node, the keys of the
+/// nodes it refers to and, for `from`, the ID its image was resolved to, if it is in
+/// `base_images`. The keys do not depend on how the nodes are numbered, so equal keys
+/// across builds mean that the same instructions were run on the same images. Files
+/// copied from the context are not hashed, only their paths are.
+pub fn node_cache_keys(
+    build_plan: &BuildPlan,
+    base_images: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::with_capacity(build_plan.nodes.len());
+    // Dependencies come before the nodes that use them, so their keys are already known.
+    for node in build_plan.nodes.iter() {
+        let referenced = RefCell::new(Vec::new());
+        let normalized = node.clone().map_node_ids(|id| {
+            referenced.borrow_mut().push(id);
+            0
+        });
+        // Going through a Value sorts the keys of maps, such as the environment of a run.
+        let normalized =
+            serde_json::to_value(&normalized).expect("Expected build nodes to be serializable");
+        let mut hasher = Sha256::new();
+        hasher.update(normalized.to_string());
+        for id in referenced.into_inner() {
+            hasher.update(b"\0");
+            hasher.update(&keys[id]);
+        }
+        let base_image = match node {
+            BuildNode::From { image_ref, .. } => base_images.get(image_ref),
+            _ => node.local_image().and_then(|e| base_images.get(&e)),
+        };
+        if let Some(resolved) = base_image {
+            hasher.update(b"\0");
+            hasher.update(resolved);
+        }
+        keys.push(format!("sha256:{:x}", hasher.finalize()));
+    }
+    keys
+}
+
+/// The nodes of the build plan, with their cache keys.
+pub fn node_reports(
+    build_plan: &BuildPlan,
+    base_images: &BTreeMap<String, String>,
+) -> Vec<NodeReport> {
+    node_cache_keys(build_plan, base_images)
+        .into_iter()
+        .enumerate()
+        .map(|(node, cache_key)| NodeReport {
+            node,
+            instruction: build_plan.nodes[node].name(),
+            source_literal: build_plan.source(node).map(ToString::to_string),
+            cache_key,
+        })
+        .collect()
+}
+
+/// Writes the images built for the outputs as JSON. If `nodes` is given, they are
+/// written as well, as `{"images": [...], "nodes": [...]}`.
+pub fn write_build_result<F: Write, P: Display>(
+    mut json_out: F,
+    json_out_name: P,
+    images: &[Image],
+    nodes: Option<&[NodeReport]>,
+) -> Result<(), String> {
+    let res = match nodes {
+        Some(nodes) => serde_json::to_vec_pretty(&serde_json::json!({
+            "images": images,
+            "nodes": nodes,
+        })),
+        None => serde_json::to_vec_pretty(&images),
+    };
 
     json_out
-        .write_all(
-            &serde_json::to_vec_pretty(&res).map_err(|e| format!("Serialization error: {}", e))?,
-        )
+        .write_all(&res.map_err(|e| format!("Serialization error: {}", e))?)
         .map_err(|e| format!("Error writing to {}: {}", json_out_name, e))?;
 
     Ok(())
@@ -134,3 +489,203 @@ pub fn write_profiling_result(p: &Profiling, f: impl AsRef<Path>) -> io::Result<
     serde_json::to_writer(&mut f, p)?;
     Ok(())
 }
+
+/// Writes a table of the time spent resolving each predicate, the most time
+/// consuming first.
+pub fn write_solve_profile(w: &mut dyn Write, profile: &SolveProfile) -> io::Result<()> {
+    let rows = profile.sorted();
+    let width = rows
+        .iter()
+        .map(|(sig, _)| sig.to_string().len())
+        .chain(std::iter::once("predicate".len()))
+        .max()
+        .unwrap();
+    writeln!(
+        w,
+        "{:<width$}  {:>10}  {:>10}  {:>11}  {:>10}",
+        "predicate",
+        "time (ms)",
+        "selections",
+        "resolutions",
+        "backtracks",
+        width = width
+    )?;
+    for (sig, p) in rows {
+        writeln!(
+            w,
+            "{:<width$}  {:>10.3}  {:>10}  {:>11}  {:>10}",
+            sig.to_string(),
+            p.time.as_secs_f64() * 1000.0,
+            p.selections,
+            p.resolutions,
+            p.backtracks,
+            width = width
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn provenance_annotations() {
+        let provenance = Provenance::new("a :- from(\"alpine\").", "a");
+        assert_eq!(
+            provenance.modusfile_digest,
+            format!("sha256:{:x}", Sha256::digest("a :- from(\"alpine\")."))
+        );
+
+        let lit: Literal = "a".parse().unwrap();
+        let annotations = provenance.annotations_for(&lit);
+        assert_eq!(
+            annotations.get("com.modus-continens.provenance.literal"),
+            Some(&"a".to_string())
+        );
+        assert_eq!(
+            annotations.get("com.modus-continens.provenance.modus-version"),
+            Some(&env!("CARGO_PKG_VERSION").to_string())
+        );
+        assert_eq!(annotations.len(), 5);
+    }
+
+    #[test]
+    fn git_sources_in_provenance() {
+        let mut plan = testing::plan(
+            "src :- from(\"alpine/git\"), \
+                 git_checkout(\"https://example.com/a.git\", \"v1\", \"a\").\n\
+             app :- from(\"alpine\"), src::copy(\"a\", \"a\"), \
+                 (git_checkout(\"https://example.com/b.git\", \"0123abc\", \"b\"))::merge.\n\
+             img(\"app\") :- app.\n\
+             img(\"other\") :- from(\"alpine\").",
+            "img(X)",
+        );
+        annotate_outputs(&mut plan, &Provenance::new("", "img(X)"));
+        let key = "com.modus-continens.provenance.git-sources";
+        let sources = plan
+            .outputs
+            .iter()
+            .map(|o| {
+                (
+                    o.source_literal.as_ref().unwrap().to_string(),
+                    o.annotations.get(key).map(String::as_str),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            sources["img(\"app\")"],
+            Some("https://example.com/a.git#v1,https://example.com/b.git#0123abc")
+        );
+        assert_eq!(sources["img(\"other\")"], None);
+    }
+
+    #[test]
+    fn format_template() {
+        let images = vec![Image {
+            source_literal: ConstantLiteral::from_literal("app(\"a&b\")".parse().unwrap()),
+            digest: "sha256:123".to_string(),
+            annotations: BTreeMap::new(),
+            tags: vec!["registry/app:1".to_string()],
+            platform: None,
+            pushed: BTreeMap::new(),
+        }];
+        assert_eq!(
+            render_template("{{predicate}} {{args.0}} => {{digest}}", &images).unwrap(),
+            "app a&b => sha256:123\n"
+        );
+        assert_eq!(
+            render_template("{{#each tags}}{{this}}{{/each}}", &images).unwrap(),
+            "registry/app:1\n"
+        );
+        assert!(render_template::<Image>("{{#if}", &[]).is_err());
+
+        let goal: Vec<Literal> = vec!["app(X)".parse().unwrap()];
+        let mut valuation = Substitution::new();
+        valuation.insert(
+            IRTerm::UserVariable("X".into()),
+            IRTerm::Constant("b".into()),
+        );
+        let solutions = vec![Solution::new(&goal, &valuation)];
+        assert_eq!(
+            render_template("{{predicate}}({{args.0}}) X={{variables.X}}", &solutions).unwrap(),
+            "app(b) X=b\n"
+        );
+    }
+
+    #[test]
+    fn solution_tables() {
+        let goal: Vec<Literal> = vec!["app(Y, X)".parse().unwrap()];
+        let solution = |x: IRTerm, y: &str| {
+            let mut valuation = Substitution::new();
+            valuation.insert(IRTerm::UserVariable("X".into()), x);
+            valuation.insert(IRTerm::UserVariable("Y".into()), IRTerm::Constant(y.into()));
+            Solution::new(&goal, &valuation)
+        };
+        let solutions = vec![
+            solution(IRTerm::Constant("3.11".into()), "a,\"b\""),
+            solution(
+                IRTerm::List(vec![
+                    IRTerm::Constant("x".into()),
+                    IRTerm::Constant("y".into()),
+                ]),
+                "tab\there",
+            ),
+        ];
+        assert_eq!(TableFormat::from_name("{{args.0}}"), None);
+        assert_eq!(
+            render_table(TableFormat::Csv, &goal, &solutions),
+            "Y,X\n\"a,\"\"b\"\"\",3.11\ntab\there,\"[\"\"x\"\",\"\"y\"\"]\"\n"
+        );
+        assert_eq!(
+            render_table(TableFormat::Tsv, &goal, &solutions),
+            "Y\tX\na,\"b\"\t3.11\ntab\\there\t[\"x\",\"y\"]\n"
+        );
+    }
+
+    #[test]
+    fn node_cache_keys_ignore_numbering() {
+        let run = |parent| BuildNode::Run {
+            parent,
+            command: "make".to_owned(),
+            cwd: "/".to_owned(),
+            additional_envs: [("A", "1"), ("B", "2"), ("C", "3")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            retry: None,
+            timeout: None,
+            insecure: false,
+            cache_mounts: Vec::new(),
+            secrets: Vec::new(),
+        };
+        let from = |image: &str| BuildNode::From {
+            image_ref: image.to_owned(),
+            display_name: image.to_owned(),
+        };
+
+        let mut a = BuildPlan::new();
+        let alpine = a.new_node(from("alpine"), vec![]);
+        a.new_node(run(alpine), vec![alpine]);
+        let mut b = BuildPlan::new();
+        b.new_node(from("debian"), vec![]);
+        let alpine = b.new_node(from("alpine"), vec![]);
+        b.new_node(run(alpine), vec![alpine]);
+
+        let resolved = BTreeMap::from([("alpine".to_owned(), "sha256:1".to_owned())]);
+        let a_keys = node_cache_keys(&a, &resolved);
+        let b_keys = node_cache_keys(&b, &resolved);
+        assert_eq!(a_keys[..], b_keys[1..]);
+        assert_ne!(b_keys[0], b_keys[1]);
+
+        let updated = BTreeMap::from([("alpine".to_owned(), "sha256:2".to_owned())]);
+        let updated_keys = node_cache_keys(&a, &updated);
+        assert_ne!(a_keys[0], updated_keys[0]);
+        assert_ne!(a_keys[1], updated_keys[1]);
+
+        let reports = node_reports(&a, &resolved);
+        assert_eq!(reports[1].instruction, "run");
+        assert_eq!(reports[1].cache_key, a_keys[1]);
+    }
+}
@@ -0,0 +1,378 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus verify`, which rebuilds the images of a build result written with
+//! `modus build --json` and checks that they have the same digests.
+//!
+//! The outputs are given the annotations recorded in the result, so that the provenance
+//! labels of a build made with `--provenance`, e.g. the time of the solve, are the same. Where the builds differ is found
+//! from the cache keys of the nodes, if the result was written with `--node-digests`,
+//! and otherwise from the layers of the images, if the recorded one is present locally.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    process::{Command, Stdio},
+};
+
+use modus_lib::imagegen::{BuildPlan, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::reporting::{self, ConstantLiteral, NodeReport, PROVENANCE_PREFIX};
+
+/// The code of the diagnostics reporting where a rebuild differs from the recorded build.
+pub const NOT_REPRODUCIBLE_CODE: &str = "not-reproducible";
+
+/// An image of a build result, see [`reporting::Image`].
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordedImage {
+    pub predicate: String,
+    #[serde(default)]
+    pub args: Vec<Value>,
+    pub digest: String,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub pushed: BTreeMap<String, String>,
+}
+
+impl RecordedImage {
+    /// The literal the image was built for, e.g. `app("3.11")`.
+    pub fn literal(&self) -> String {
+        let args = self.args.iter().map(Value::to_string).collect::<Vec<_>>();
+        if args.is_empty() {
+            self.predicate.clone()
+        } else {
+            format!("{}({})", self.predicate, args.join(", "))
+        }
+    }
+
+    fn is_built_for(&self, literal: &ConstantLiteral) -> bool {
+        serde_json::to_value(literal).ok()
+            == Some(serde_json::json!({ "predicate": self.predicate, "args": self.args }))
+    }
+}
+
+/// A node of a build result written with `--node-digests`, see [`NodeReport`].
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordedNode {
+    pub cache_key: String,
+}
+
+/// A build result written by `modus build --json`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum BuildResult {
+    Images(Vec<RecordedImage>),
+    WithNodes {
+        images: Vec<RecordedImage>,
+        nodes: Vec<RecordedNode>,
+    },
+}
+
+impl BuildResult {
+    pub fn from_file(path: &std::path::Path) -> Result<BuildResult, String> {
+        let content =
+            std::fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&content).map_err(|e| {
+            format!(
+                "{} is not a build result written by modus build --json: {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    pub fn images(&self) -> &[RecordedImage] {
+        match self {
+            BuildResult::Images(images) | BuildResult::WithNodes { images, .. } => images,
+        }
+    }
+
+    pub fn nodes(&self) -> Option<&[RecordedNode]> {
+        match self {
+            BuildResult::Images(_) => None,
+            BuildResult::WithNodes { nodes, .. } => Some(nodes),
+        }
+    }
+
+    /// The query that was built, from the provenance of the images.
+    pub fn query(&self) -> Option<&str> {
+        let key = format!("{}.query", PROVENANCE_PREFIX);
+        self.images()
+            .iter()
+            .find_map(|i| i.annotations.get(&key))
+            .map(String::as_str)
+    }
+
+    /// Gives the outputs of the plan the annotations recorded for them.
+    pub fn restore_annotations(&self, plan: &mut BuildPlan) {
+        for output in plan.outputs.iter_mut() {
+            let literal = ConstantLiteral::from_literal(
+                output
+                    .source_literal
+                    .clone()
+                    .expect("Expected source_literal to present in build plan"),
+            );
+            if let Some(recorded) = self.images().iter().find(|i| i.is_built_for(&literal)) {
+                output.annotations = recorded.annotations.clone();
+            }
+        }
+    }
+}
+
+/// The recorded and rebuilt digests of an image, either of which is missing if the
+/// image is only in one of the builds.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageComparison {
+    pub literal: String,
+    /// The output node of the image in the rebuilt plan.
+    #[serde(skip)]
+    pub node: Option<NodeId>,
+    pub recorded: Option<String>,
+    pub rebuilt: Option<String>,
+}
+
+impl ImageComparison {
+    pub fn is_reproduced(&self) -> bool {
+        self.recorded.is_some() && self.recorded == self.rebuilt
+    }
+}
+
+/// Compares the recorded images with the ones rebuilt for the outputs of the plan.
+pub fn compare_images(
+    result: &BuildResult,
+    plan: &BuildPlan,
+    image_ids: &[String],
+) -> Vec<ImageComparison> {
+    let rebuilt = reporting::built_images(plan, image_ids);
+    let mut comparisons = result
+        .images()
+        .iter()
+        .map(|recorded| {
+            let i = rebuilt
+                .iter()
+                .position(|i| recorded.is_built_for(&i.source_literal));
+            ImageComparison {
+                literal: recorded.literal(),
+                node: i.map(|i| plan.outputs[i].node),
+                recorded: Some(recorded.digest.clone()),
+                rebuilt: i.map(|i| rebuilt[i].digest.clone()),
+            }
+        })
+        .collect::<Vec<_>>();
+    for (image, output) in rebuilt.iter().zip(plan.outputs.iter()) {
+        if !result
+            .images()
+            .iter()
+            .any(|r| r.is_built_for(&image.source_literal))
+        {
+            comparisons.push(ImageComparison {
+                literal: output.source_literal.as_ref().unwrap().to_string(),
+                node: Some(output.node),
+                recorded: None,
+                rebuilt: Some(image.digest.clone()),
+            });
+        }
+    }
+    comparisons
+}
+
+/// The nodes where the rebuild started to differ from the recorded build: the nodes whose
+/// cache key was not recorded, although the keys of the nodes they build on were.
+pub fn diverging_nodes(
+    plan: &BuildPlan,
+    nodes: &[NodeReport],
+    recorded: &[RecordedNode],
+) -> Vec<NodeReport> {
+    let recorded = recorded
+        .iter()
+        .map(|n| n.cache_key.as_str())
+        .collect::<BTreeSet<_>>();
+    let is_recorded = |node: NodeId| recorded.contains(nodes[node].cache_key.as_str());
+    plan.topological_order()
+        .into_iter()
+        .filter(|&node| {
+            !is_recorded(node) && plan.dependencies[node].iter().all(|&d| is_recorded(d))
+        })
+        .map(|node| nodes[node].clone())
+        .collect()
+}
+
+/// The layers of a local image, or `None` if it is not present.
+fn image_layers(image_id: &str) -> Option<Vec<String>> {
+    let output = Command::new("docker")
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{json .RootFS.Layers}}",
+            image_id,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+/// Where two builds of an image start to differ, according to their layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerDivergence {
+    /// The layers of the base image differ.
+    BaseImage,
+    /// The layer added by the node differs.
+    Node(NodeId),
+    /// The layers are the same, so only the config of the image differs, e.g. a label.
+    Config,
+}
+
+/// Finds the first layer that differs between the recorded and the rebuilt layers of the
+/// output `node`, and the node of the plan that added it, counting one layer for each
+/// node that adds one.
+pub fn first_different_layer(
+    plan: &BuildPlan,
+    node: NodeId,
+    recorded: &[String],
+    rebuilt: &[String],
+) -> Option<LayerDivergence> {
+    let mut layer_nodes = Vec::new();
+    let mut current = Some(node);
+    while let Some(n) = current {
+        if plan.nodes[n].adds_layer() {
+            layer_nodes.push(n);
+        }
+        current = plan.nodes[n].parent();
+    }
+    layer_nodes.reverse();
+    let base_layers = rebuilt.len().checked_sub(layer_nodes.len())?;
+    let first = recorded
+        .iter()
+        .zip(rebuilt.iter())
+        .position(|(a, b)| a != b)
+        .or_else(|| (recorded.len() != rebuilt.len()).then(|| recorded.len().min(rebuilt.len())));
+    Some(match first {
+        None => LayerDivergence::Config,
+        Some(i) if i < base_layers => LayerDivergence::BaseImage,
+        Some(i) => LayerDivergence::Node(*layer_nodes.get(i - base_layers)?),
+    })
+}
+
+/// Compares the layers of the recorded and rebuilt images of the output `node`, if the
+/// recorded image is present locally.
+pub fn compare_layers(
+    plan: &BuildPlan,
+    node: NodeId,
+    recorded_id: &str,
+    rebuilt_id: &str,
+) -> Option<LayerDivergence> {
+    let recorded = image_layers(recorded_id)?;
+    let rebuilt = image_layers(rebuilt_id)?;
+    first_different_layer(plan, node, &recorded, &rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use modus_lib::imagegen;
+    use std::collections::BTreeMap;
+
+    fn plan(command: &str) -> BuildPlan {
+        let mut plan = testing::plan(
+            &format!(
+                "app(V) :- (V = \"1\" ; V = \"2\"), (from(\"alpine\"), run(\"apk add make\"), \
+                     run(f\"{} ${{V}}\"))::set_env(\"V\", V).",
+                command
+            ),
+            "app(X)",
+        );
+        plan.outputs
+            .sort_by_key(|o| o.source_literal.as_ref().unwrap().to_string());
+        plan
+    }
+
+    #[test]
+    fn compare_with_recorded_result() {
+        let mut recorded_plan = plan("make");
+        reporting::annotate_outputs(
+            &mut recorded_plan,
+            &reporting::Provenance::new("", "app(X)"),
+        );
+        let ids = vec!["sha256:1".to_owned(), "sha256:2".to_owned()];
+        let nodes = reporting::node_reports(&recorded_plan, &BTreeMap::new());
+        let mut written = Vec::new();
+        let images = reporting::built_images(&recorded_plan, &ids);
+        reporting::write_build_result(&mut written, "test", &images, Some(&nodes)).unwrap();
+        let result: BuildResult = serde_json::from_slice(&written).unwrap();
+        assert_eq!(result.query(), Some("app(X)"));
+        assert_eq!(result.images()[0].literal(), "app(\"1\")");
+
+        let mut rebuilt_plan = plan("make");
+        result.restore_annotations(&mut rebuilt_plan);
+        assert_eq!(
+            rebuilt_plan.outputs[1].annotations,
+            recorded_plan.outputs[1].annotations
+        );
+        let rebuilt_ids = vec!["sha256:1".to_owned(), "sha256:3".to_owned()];
+        let comparisons = compare_images(&result, &rebuilt_plan, &rebuilt_ids);
+        assert_eq!(
+            comparisons
+                .iter()
+                .map(ImageComparison::is_reproduced)
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+        let rebuilt_nodes = reporting::node_reports(&rebuilt_plan, &BTreeMap::new());
+        assert!(diverging_nodes(&rebuilt_plan, &rebuilt_nodes, result.nodes().unwrap()).is_empty());
+
+        // Only the first node of each output that differs is reported.
+        let changed_plan = plan("make all");
+        let changed_nodes = reporting::node_reports(&changed_plan, &BTreeMap::new());
+        let diverging = diverging_nodes(&changed_plan, &changed_nodes, result.nodes().unwrap());
+        assert_eq!(diverging.len(), 2);
+        assert!(diverging.iter().all(|n| n.instruction == "run"));
+    }
+
+    #[test]
+    fn layers_map_to_nodes() {
+        let plan = plan("make");
+        let output = plan.outputs[0].node;
+        let layers = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let rebuilt = layers(&["base", "apk", "make"]);
+        assert_eq!(
+            first_different_layer(&plan, output, &rebuilt, &rebuilt),
+            Some(LayerDivergence::Config)
+        );
+        assert_eq!(
+            first_different_layer(&plan, output, &layers(&["other", "apk", "make"]), &rebuilt),
+            Some(LayerDivergence::BaseImage)
+        );
+        match first_different_layer(&plan, output, &layers(&["base", "apk", "x"]), &rebuilt) {
+            Some(LayerDivergence::Node(node)) => {
+                assert!(
+                    matches!(&plan.nodes[node], imagegen::BuildNode::Run { command, .. } if command == "make 1")
+                )
+            }
+            d => panic!("Expected the run of make to differ, got {:?}", d),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Conversion of diagnostics to SARIF 2.1.0, the format understood by code
+//! scanning tools such as GitHub's.
+
+use std::collections::BTreeSet;
+
+use codespan_reporting::{
+    diagnostic::{Diagnostic, LabelStyle, Severity},
+    files::Files,
+};
+use serde_json::{json, Value};
+
+/// Rule ID for diagnostics that do not have a code.
+const DEFAULT_RULE_ID: &str = "modus";
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Bug | Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note | Severity::Help => "note",
+    }
+}
+
+fn rule_id(diag: &Diagnostic<()>) -> &str {
+    diag.code.as_deref().unwrap_or(DEFAULT_RULE_ID)
+}
+
+fn region<'a, F: Files<'a, FileId = ()>>(file: &'a F, start: usize, end: usize) -> Option<Value> {
+    let start = file.location((), start).ok()?;
+    let end = file.location((), end).ok()?;
+    Some(json!({
+        "startLine": start.line_number,
+        "startColumn": start.column_number,
+        "endLine": end.line_number,
+        "endColumn": end.column_number,
+    }))
+}
+
+fn result<'a, F: Files<'a, FileId = ()>>(diag: &Diagnostic<()>, file: &'a F, uri: &str) -> Value {
+    let mut text = diag.message.clone();
+    for note in diag.notes.iter() {
+        text.push('\n');
+        text.push_str(note);
+    }
+
+    // SARIF results have one main location, so prefer the primary label.
+    let label = diag
+        .labels
+        .iter()
+        .find(|l| l.style == LabelStyle::Primary)
+        .or_else(|| diag.labels.first());
+    let mut physical_location = json!({ "artifactLocation": { "uri": uri } });
+    if let Some(region) = label.and_then(|l| region(file, l.range.start, l.range.end)) {
+        physical_location["region"] = region;
+    }
+
+    json!({
+        "ruleId": rule_id(diag),
+        "level": level(diag.severity),
+        "message": { "text": text },
+        "locations": [{ "physicalLocation": physical_location }],
+    })
+}
+
+/// Produces a SARIF log with a single run containing the given diagnostics,
+/// all of which refer to the file at `uri`.
+pub fn to_sarif<'a, F: Files<'a, FileId = ()>>(
+    diags: &[Diagnostic<()>],
+    file: &'a F,
+    uri: &str,
+) -> Value {
+    let rules = diags
+        .iter()
+        .map(rule_id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|id| json!({ "id": id }))
+        .collect::<Vec<_>>();
+    let results = diags
+        .iter()
+        .map(|d| result(d, file, uri))
+        .collect::<Vec<_>>();
+
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "modus",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://modus-continens.com",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use codespan_reporting::{diagnostic::Label, files::SimpleFile};
+
+    #[test]
+    fn diagnostics_to_sarif() {
+        let file = SimpleFile::new("Modusfile", "a :- b.\nc :- !d.\n");
+        let diags = vec![
+            Diagnostic::error()
+                .with_code("negated-non-logic")
+                .with_message("Negating a non-logical predicate is disallowed.")
+                .with_labels(vec![Label::primary((), 13..15)]),
+            Diagnostic::warning().with_message("something"),
+        ];
+        let sarif = to_sarif(&diags, &file, "Modusfile");
+
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 2);
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results[0]["ruleId"], "negated-non-logic");
+        assert_eq!(results[0]["level"], "error");
+        let region = &results[0]["locations"][0]["physicalLocation"]["region"];
+        assert_eq!(region["startLine"], 2);
+        assert_eq!(region["startColumn"], 6);
+        assert_eq!(results[1]["ruleId"], "modus");
+        assert_eq!(results[1]["level"], "warning");
+    }
+}
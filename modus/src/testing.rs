@@ -0,0 +1,31 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers shared by the unit tests of the modules of the binary.
+
+use modus_lib::{imagegen::BuildPlan, modusfile::Modusfile, sld};
+
+/// The build plan of `query` in the Modusfile, solved depth-first.
+pub fn plan(modusfile: &str, query: &str) -> BuildPlan {
+    let mf: Modusfile = modusfile.parse().unwrap();
+    modus_lib::imagegen::plan_from_modusfile(
+        mf,
+        query.parse().unwrap(),
+        sld::SearchStrategy::DepthFirst,
+        &mut sld::SolveProfile::default(),
+    )
+    .unwrap()
+}
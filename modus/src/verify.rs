@@ -0,0 +1,177 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `modus test` and `modus build --verify`, which check the images given
+//! `::assert_file` and `::assert_cmd_succeeds` after they are built, by running the
+//! checks in a container started from each image.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use modus_lib::imagegen::{Assertion, BuildPlan, NodeId, Output};
+
+use crate::buildkit::{self, BuildError, BuildOptions, DockerBuildOptions};
+use crate::reporting::Profiling;
+
+/// The code of the diagnostics reporting an assertion that does not hold.
+pub const ASSERTION_FAILED_CODE: &str = "assertion-failed";
+
+/// The most output of a failed check that is reported, from its end.
+const MAX_OUTPUT: usize = 2000;
+
+/// An assertion that did not hold for the image it was given to.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub node: NodeId,
+    pub assertion: Assertion,
+    /// The output of the check, e.g. the error of the command.
+    pub output: String,
+}
+
+/// Returns a plan whose outputs are the images that have assertions, and, in the same
+/// order, the nodes of these images in `plan` with their assertions.
+pub fn assertion_plan(plan: &BuildPlan) -> (BuildPlan, Vec<(NodeId, Vec<Assertion>)>) {
+    let mut outputs: Vec<Output> = Vec::new();
+    let mut assertions: Vec<(NodeId, Vec<Assertion>)> = Vec::new();
+    for (node, assertion) in plan.assertions.iter() {
+        match outputs.iter().position(|o| o.node == *node) {
+            Some(i) => assertions[i].1.push(assertion.clone()),
+            None => {
+                outputs.push(Output {
+                    node: *node,
+                    source_literal: plan.source(*node).cloned(),
+                    annotations: Default::default(),
+                    tags: Vec::new(),
+                });
+                assertions.push((*node, vec![assertion.clone()]));
+            }
+        }
+    }
+    let plan = BuildPlan {
+        outputs,
+        ..plan.clone()
+    }
+    .without_unreachable_nodes();
+    (plan, assertions)
+}
+
+/// Runs the check in a container started from the image, and returns its output if it
+/// fails.
+pub fn check(
+    image_id: &str,
+    platform: Option<&str>,
+    assertion: &Assertion,
+) -> std::io::Result<Option<String>> {
+    let mut cmd = Command::new("docker");
+    cmd.args(["run", "--rm", "--entrypoint", "sh"]);
+    if let Some(platform) = platform {
+        cmd.args(["--platform", platform]);
+    }
+    cmd.arg(image_id);
+    match assertion {
+        Assertion::File(path) => cmd.args(["-c", "test -e \"$1\"", "sh", path]),
+        Assertion::CommandSucceeds(command) => cmd.args(["-c", command]),
+    };
+    let output = cmd.stdin(Stdio::null()).output()?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    let mut out = String::from_utf8_lossy(&output.stdout).into_owned();
+    out.push_str(&String::from_utf8_lossy(&output.stderr));
+    let out = out.trim();
+    let start = out
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| out.len() - i <= MAX_OUTPUT)
+        .unwrap_or(out.len());
+    Ok(Some(match assertion {
+        Assertion::File(path) if out.is_empty() => format!("{} does not exist", path),
+        _ if out.is_empty() => format!("exited with {}", output.status),
+        _ => format!("exited with {}: {}", output.status, &out[start..]),
+    }))
+}
+
+/// Builds the images of the plan that have assertions, which were usually built just
+/// before and are cached, and checks them. Returns the assertions that do not hold.
+pub fn verify<P: AsRef<Path>>(
+    plan: &BuildPlan,
+    context: P,
+    options: &BuildOptions,
+) -> Result<Vec<Violation>, BuildError> {
+    if plan.assertions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (checked_plan, assertions) = assertion_plan(plan);
+    let options = BuildOptions {
+        docker_build_options: DockerBuildOptions {
+            quiet: true,
+            // The checks run in containers started by the local docker daemon.
+            load: true,
+            ..options.docker_build_options.clone()
+        },
+        ..options.clone()
+    };
+    let built = buildkit::build(
+        checked_plan,
+        context.as_ref(),
+        &options,
+        &mut Profiling::default(),
+    )?;
+    let mut violations = Vec::new();
+    for ((node, assertions), image_id) in assertions.into_iter().zip(built.image_ids.iter()) {
+        for assertion in assertions {
+            if let Some(output) = check(image_id, plan.platform.as_deref(), &assertion)? {
+                violations.push(Violation {
+                    node,
+                    assertion,
+                    output,
+                });
+            }
+        }
+    }
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use modus_lib::imagegen::BuildNode;
+
+    #[test]
+    fn assertions_are_grouped_by_image() {
+        let plan = testing::plan(
+            "base :- from(\"alpine\")::assert_file(\"/bin/sh\").\n\
+             app :- (base, run(\"make\"))::assert_file(\"/usr/bin/app\")\
+                 ::assert_cmd_succeeds(\"app --version\").",
+            "app",
+        );
+        let (checked, assertions) = assertion_plan(&plan);
+        assert_eq!(checked.outputs.len(), 2);
+        assert_eq!(assertions.iter().map(|(_, a)| a.len()).sum::<usize>(), 3);
+        for ((node, _), output) in assertions.iter().zip(checked.outputs.iter()) {
+            assert_eq!(plan.nodes[*node], checked.nodes[output.node]);
+        }
+        // The label recording the literal of the output is not needed for the checks.
+        assert!(checked.nodes.len() < plan.nodes.len());
+        assert!(plan
+            .nodes
+            .iter()
+            .any(|n| matches!(n, BuildNode::SetLabel { .. })));
+    }
+}